@@ -0,0 +1,142 @@
+//! A single store of the snapshots (and bookmarks) that each replication flow anchors its next
+//! incremental from.
+//!
+//! Local repl and remote repl anchor from different snapshot classes (repl_ and auto_), and there
+//! may be several destinations per source. Every flow registers its anchor here, and any cleanup
+//! consults the store so that it never destroys a precursor that another flow still relies on.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::PathBuf;
+use tracing::{debug, error, info};
+
+/// Default directory for znapper's persistent state, overridable with `ZNAPPER_STATE_DIR`.
+pub(crate) const DEFAULT_STATE_DIR: &str = "/var/lib/znapper";
+
+pub(crate) fn state_dir() -> PathBuf {
+    std::env::var_os("ZNAPPER_STATE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_DIR))
+}
+
+/// Identifies a replication flow - the kind of flow and where it replicates to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Owner {
+    pub flow: String,
+    pub destination: String,
+}
+
+impl Owner {
+    pub(crate) fn new(flow: &str, destination: &str) -> Self {
+        Owner {
+            flow: flow.to_string(),
+            destination: destination.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Anchor {
+    #[serde(flatten)]
+    owner: Owner,
+    /// The snapshot or bookmark on the source root that the next incremental sends from.
+    anchor: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct AnchorStore {
+    anchors: Vec<Anchor>,
+}
+
+fn split_name(name: &str) -> (&str, &str) {
+    match name.find(['@', '#']) {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    }
+}
+
+impl AnchorStore {
+    fn path() -> PathBuf {
+        state_dir().join("anchors.json")
+    }
+
+    /// Load the store, treating a missing file as empty.
+    pub(crate) fn load() -> Result<Self, ()> {
+        let path = Self::path();
+        match File::open(&path) {
+            Ok(f) => serde_json::from_reader(f).map_err(|e| {
+                error!("Failed to parse anchor store {:?} -> {:?}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AnchorStore::default()),
+            Err(e) => {
+                error!("Failed to open anchor store {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    pub(crate) fn save(&self, dry: bool) -> Result<(), ()> {
+        let path = Self::path();
+        if dry {
+            info!("dryrun: save anchors -> {:?}", path);
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                error!("Failed to create state dir {:?} -> {:?}", parent, e);
+            })?;
+        }
+        // Write then rename, so a crash can't leave a truncated store behind.
+        let tmp = path.with_extension("json.tmp");
+        let f = File::create(&tmp).map_err(|e| {
+            error!("Failed to create anchor store {:?} -> {:?}", tmp, e);
+        })?;
+        serde_json::to_writer_pretty(&f, self).map_err(|e| {
+            error!("Failed to write anchor store {:?} -> {:?}", tmp, e);
+        })?;
+        fs::rename(&tmp, &path).map_err(|e| {
+            error!("Failed to replace anchor store {:?} -> {:?}", path, e);
+        })
+    }
+
+    /// Record `anchor` as the current anchor of `owner`, replacing any previous one.
+    pub(crate) fn set(&mut self, owner: &Owner, anchor: &str) {
+        self.anchors.retain(|a| &a.owner != owner);
+        self.anchors.push(Anchor {
+            owner: owner.clone(),
+            anchor: anchor.to_string(),
+        });
+        debug!(?self.anchors);
+    }
+
+    /// Forget the anchor of `owner`, which no longer anchors anything.
+    pub(crate) fn remove(&mut self, owner: &Owner) {
+        self.anchors.retain(|a| &a.owner != owner);
+    }
+
+    /// The current anchor of `owner`, if it has registered one.
+    pub(crate) fn get(&self, owner: &Owner) -> Option<&str> {
+        self.anchors
+            .iter()
+            .find(|a| &a.owner == owner)
+            .map(|a| a.anchor.as_str())
+    }
+
+    /// Is `name` (or its recursive parent) the anchor of any flow other than `owner`? Pass `None`
+    /// from flows that own no anchors, such as auto snapshot cleanup.
+    pub(crate) fn is_protected(&self, name: &str, owner: Option<&Owner>) -> bool {
+        let (dataset, short) = split_name(name);
+        self.anchors
+            .iter()
+            .filter(|a| Some(&a.owner) != owner)
+            .any(|a| {
+                let (a_dataset, a_short) = split_name(&a.anchor);
+                a_short == short
+                    && (dataset == a_dataset
+                        || dataset
+                            .strip_prefix(a_dataset)
+                            .map(|rest| rest.starts_with('/'))
+                            .unwrap_or(false))
+            })
+    }
+}
@@ -0,0 +1,124 @@
+//! A ransomware tripwire - compare the size of each dataset's incremental with the trailing
+//! average of its previous incrementals to the same destination. Mass encryption or modification
+//! on the source shows up as an incremental that is many times larger than usual.
+//!
+//! The sizes are the estimates of `zfs send -n -P` from the precursor to the new repl snapshot
+//! (see estimate.rs), and their history is kept in `incrementals.json` in the state directory.
+
+use crate::anchors::state_dir;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use tracing::{error, info, warn};
+
+/// How many previous incrementals the average is taken over.
+const HISTORY: usize = 10;
+/// Until a dataset has this many previous incrementals it is never flagged.
+const MIN_SAMPLES: usize = 3;
+/// Incrementals smaller than this are never flagged, however small the average is.
+const MIN_ANOMALY_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct History {
+    /// "<destination> <source dataset>" -> sizes of its last incrementals, oldest first.
+    sizes: BTreeMap<String, Vec<u64>>,
+}
+
+fn path() -> PathBuf {
+    state_dir().join("incrementals.json")
+}
+
+fn key(destination: &str, dataset: &str) -> String {
+    format!("{} {}", destination, dataset)
+}
+
+impl History {
+    fn load() -> Result<Self, ()> {
+        let path = path();
+        match File::open(&path) {
+            Ok(f) => serde_json::from_reader(f).map_err(|e| {
+                error!("Failed to parse {:?} -> {:?}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(History::default()),
+            Err(e) => {
+                error!("Failed to open {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), ()> {
+        let path = path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                error!("Failed to create state dir {:?} -> {:?}", parent, e);
+            })?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        let f = File::create(&tmp).map_err(|e| {
+            error!("Failed to create {:?} -> {:?}", tmp, e);
+        })?;
+        serde_json::to_writer_pretty(&f, self).map_err(|e| {
+            error!("Failed to write {:?} -> {:?}", tmp, e);
+        })?;
+        fs::rename(&tmp, &path).map_err(|e| {
+            error!("Failed to replace {:?} -> {:?}", path, e);
+        })
+    }
+}
+
+/// Which datasets' incrementals to `destination` are more than `factor` times their trailing
+/// average. Each anomaly is logged.
+pub(crate) fn check(destination: &str, estimates: &[(String, u64)], factor: f64) -> Vec<String> {
+    let history = match History::load() {
+        Ok(h) => h,
+        Err(_) => return Vec::new(),
+    };
+
+    estimates
+        .iter()
+        .filter(|(dataset, size)| {
+            let previous = match history.sizes.get(&key(destination, dataset)) {
+                Some(previous) if previous.len() >= MIN_SAMPLES => previous,
+                _ => return false,
+            };
+            let average = previous.iter().sum::<u64>() as f64 / previous.len() as f64;
+            let anomalous = *size >= MIN_ANOMALY_BYTES && *size as f64 > average * factor;
+            if anomalous {
+                warn!(
+                    "Anomaly: incremental of {} to {} is {} bytes, the average of the last {} is {:.0} bytes",
+                    dataset,
+                    destination,
+                    size,
+                    previous.len(),
+                    average
+                );
+            }
+            anomalous
+        })
+        .map(|(dataset, _)| dataset.clone())
+        .collect()
+}
+
+/// Add the sizes of a successful replication to `destination` to the history.
+pub(crate) fn record(dry: bool, destination: &str, estimates: &[(String, u64)]) {
+    if dry {
+        info!("dryrun: record incremental sizes -> {:?}", path());
+        return;
+    }
+    let mut history = match History::load() {
+        Ok(h) => h,
+        Err(_) => return,
+    };
+    for (dataset, size) in estimates {
+        let sizes = history.sizes.entry(key(destination, dataset)).or_default();
+        sizes.push(*size);
+        if sizes.len() > HISTORY {
+            sizes.remove(0);
+        }
+    }
+    if history.save().is_err() {
+        warn!("Unable to record incremental sizes");
+    }
+}
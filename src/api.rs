@@ -0,0 +1,393 @@
+//! The library API - snapshot, replicate and prune from another Rust program, rather than
+//! running the znapper binary.
+//!
+//! These are the operations the command line runs, so they honour the anchors, approvals and
+//! immutable policies in the state directory and `znapper.toml`, and record what they did in the
+//! history as it does. They log with `tracing`, so install a subscriber to see why an operation
+//! failed. Unlike the command line they take no locks and have no timeouts - the caller decides
+//! what may run at once.
+//!
+//! ```no_run
+//! use znapper::{ReplicationJob, RetentionPolicy, Zfs};
+//!
+//! # fn main() -> Result<(), znapper::Error> {
+//! let zfs = Zfs::new();
+//! zfs.snapshot(&["nvme"])?;
+//! ReplicationJob::new("nvme", "tank/nvme")
+//!     .retention(RetentionPolicy::hours(48).daily(14))
+//!     .run()?;
+//! RetentionPolicy::hours(24).apply(&zfs, "nvme")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::buffer::BufferOpt;
+use crate::lock::LockOpt;
+use crate::model::{Dataset, Snapshot};
+use crate::stream::{RecvPropsOpt, StreamOpt};
+use crate::{audit, listing, CleanupOpt, Opt, ReplOpt};
+use crate::{auto_snap_list, dataset_list, do_init, do_repl, do_snap, do_snap_cleanup};
+use crate::{check_managed, destroy_snap, prune_auto, repl_bookmark_list, repl_destinations};
+use crate::{repl_precursor, repl_snap_list, retention_expired, snap_list};
+use std::fmt;
+use time::OffsetDateTime;
+
+/// An operation that failed. Why it did was logged as it failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    operation: String,
+}
+
+impl Error {
+    fn new(operation: String) -> Self {
+        Error { operation }
+    }
+
+    /// What was being done, ie "repl of nvme to tank/nvme".
+    pub fn operation(&self) -> &str {
+        &self.operation
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed", self.operation)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The datasets and snapshots of this machine.
+#[derive(Debug, Clone, Default)]
+pub struct Zfs {
+    dry_run: bool,
+    snapshot_jobs: usize,
+    force: bool,
+}
+
+impl Zfs {
+    pub fn new() -> Self {
+        Zfs::default()
+    }
+
+    /// Only log (and plan) what would be changed, as `-n` does.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Create this many snapshots at once in `snapshot`, as `--jobs` does.
+    pub fn snapshot_jobs(mut self, jobs: usize) -> Self {
+        self.snapshot_jobs = jobs;
+        self
+    }
+
+    /// Let `destroy` destroy snapshots that aren't znapper's, as `--force` does.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Every filesystem and volume under, and including, `root`.
+    pub fn datasets(&self, root: &str) -> Result<Vec<Dataset>, Error> {
+        let err = || Error::new(format!("listing the datasets of {}", root));
+        dataset_list(root)
+            .map_err(|_| err())?
+            .iter()
+            .map(|name| Dataset::parse(name))
+            .collect::<Result<_, _>>()
+            .map_err(|_| err())
+    }
+
+    /// The snapshots of `dataset` (not its descendants), with their guids, sorted by name.
+    pub fn snapshots(&self, dataset: &str) -> Result<Vec<Snapshot>, Error> {
+        snap_list(dataset, false)
+            .map_err(|_| Error::new(format!("listing the snapshots of {}", dataset)))
+    }
+
+    /// The auto_ snapshots of `root` and its descendants, sorted by name.
+    pub fn auto_snapshots(&self, root: &str) -> Result<Vec<Snapshot>, Error> {
+        auto_snap_list(root)
+            .map_err(|_| Error::new(format!("listing the auto snapshots of {}", root)))
+    }
+
+    /// Take an auto_ snapshot of every mounted filesystem under `pools`, as `znapper snapshot`
+    /// does. With no pools, of every pool.
+    pub fn snapshot(&self, pools: &[&str]) -> Result<(), Error> {
+        self.snapshot_of(pools, false)
+    }
+
+    /// Snapshot the datasets of the consistency `groups` of znapper.toml together, tagged with
+    /// their group, as `znapper snapshot --group` does.
+    pub fn snapshot_groups(&self, groups: &[&str]) -> Result<(), Error> {
+        self.snapshot_of(groups, true)
+    }
+
+    fn snapshot_of(&self, pools: &[&str], group: bool) -> Result<(), Error> {
+        let _cache = listing::cached();
+        do_snap(&Opt {
+            pools: pools.iter().map(|p| p.to_string()).collect(),
+            group,
+            jobs: self.snapshot_jobs,
+            dryrun: self.dry_run,
+            plan_format: None,
+            lock: LockOpt::default(),
+        })
+        .map(|_| ())
+        .map_err(|_| Error::new(format!("snapshot of {}", pools.join(", "))))
+    }
+
+    /// Destroy the auto snapshots of `pool` and its descendants older than `keep_hours`, other
+    /// than replication anchors, as `znapper snapshot_cleanup` does.
+    pub fn cleanup(&self, pool: &str, keep_hours: u32) -> Result<(), Error> {
+        self.snapshot_cleanup(pool, keep_hours, false, false)
+    }
+
+    /// As `cleanup`, also destroying the auto snapshots within `keep_hours` that are the same as
+    /// the snapshot before them, as `znapper snapshot_cleanup --empty` does.
+    pub fn cleanup_empty(&self, pool: &str, keep_hours: u32) -> Result<(), Error> {
+        self.snapshot_cleanup(pool, keep_hours, true, false)
+    }
+
+    /// As `cleanup`, of the datasets of the consistency `group` together - destroying a snapshot
+    /// name only once no dataset of the group keeps it, as `znapper snapshot_cleanup --group`
+    /// does.
+    pub fn cleanup_group(&self, group: &str, keep_hours: u32) -> Result<(), Error> {
+        self.snapshot_cleanup(group, keep_hours, false, true)
+    }
+
+    fn snapshot_cleanup(
+        &self,
+        pool: &str,
+        keep_hours: u32,
+        empty: bool,
+        group: bool,
+    ) -> Result<(), Error> {
+        let _cache = listing::cached();
+        do_snap_cleanup(&CleanupOpt {
+            pool: pool.to_string(),
+            keep_hours,
+            group,
+            empty,
+            defer: false,
+            grace: None,
+            dryrun: self.dry_run,
+            plan_format: None,
+            lock: LockOpt::default(),
+        })
+        .map_err(|_| Error::new(format!("snapshot cleanup of {}", pool)))
+    }
+
+    /// Destroy `snapshot`, and the snapshots of the same name of its descendants. Snapshots
+    /// under an immutable policy that are too young are refused, as are those that aren't auto_,
+    /// repl_ or redact_ unless `force`d.
+    pub fn destroy(&self, snapshot: &Snapshot) -> Result<(), Error> {
+        let err = || Error::new(format!("destroy of {}", snapshot));
+        if !self.force {
+            check_managed(snapshot.name()).map_err(|_| err())?;
+        }
+        destroy_snap(self.dry_run, snapshot.name(), audit::Reason::Requested).map_err(|_| err())
+    }
+}
+
+/// How long auto snapshots are kept - those of the last `keep_hours` hours, and the newest of
+/// each of the last `keep_daily` days. A snapshot either keeps is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub keep_hours: Option<u32>,
+    pub keep_daily: Option<u32>,
+}
+
+impl RetentionPolicy {
+    /// Keep the auto snapshots of the last `hours` hours.
+    pub fn hours(hours: u32) -> Self {
+        RetentionPolicy {
+            keep_hours: Some(hours),
+            keep_daily: None,
+        }
+    }
+
+    /// Also keep the newest auto snapshot of each of the last `days` days.
+    pub fn daily(mut self, days: u32) -> Self {
+        self.keep_daily = Some(days);
+        self
+    }
+
+    /// Which of the auto snapshots of `snapshots` the policy would destroy. Each dataset is
+    /// considered on its own, and snapshots that are not auto_ snapshots are always kept.
+    pub fn expired(&self, snapshots: &[Snapshot]) -> Result<Vec<Snapshot>, Error> {
+        let now = OffsetDateTime::try_now_local()
+            .map_err(|_| Error::new("determining the local time".to_string()))?;
+        Ok(retention_expired(
+            snapshots,
+            self.keep_hours,
+            self.keep_daily,
+            now,
+        ))
+    }
+
+    /// Destroy the auto snapshots of `root` and its descendants that the policy expires, other
+    /// than replication anchors, returning how many were destroyed. Without either kind of
+    /// policy nothing is kept, so nothing is destroyed.
+    pub fn apply(&self, zfs: &Zfs, root: &str) -> Result<usize, Error> {
+        if self.keep_hours.is_none() && self.keep_daily.is_none() {
+            return Ok(0);
+        }
+        let _cache = listing::cached();
+        prune_auto(zfs.dry_run, root, self.keep_hours, self.keep_daily)
+            .map_err(|_| Error::new(format!("retention of {}", root)))
+    }
+}
+
+/// A local replication of a dataset and its descendants to one or more destinations, as
+/// `znapper init_repl` and `znapper repl` run it.
+#[derive(Debug, Clone)]
+pub struct ReplicationJob {
+    opt: ReplOpt,
+}
+
+impl ReplicationJob {
+    /// Replicate `source` to `destination`, which may be a template with %hostname% and
+    /// %dataset%.
+    pub fn new(source: &str, destination: &str) -> Self {
+        ReplicationJob {
+            opt: ReplOpt {
+                from_pool: source.to_string(),
+                to_pool: destination.to_string(),
+                to: Vec::new(),
+                dryrun: false,
+                plan_format: None,
+                bookmarks: false,
+                keep_anchors: 0,
+                fallback_full: false,
+                force_rollback: false,
+                redact: false,
+                anomaly_factor: None,
+                pause_on_anomaly: false,
+                dest_keep_hours: None,
+                dest_keep_daily: None,
+                ignore_space: false,
+                skip_preflight: false,
+                require_healthy: false,
+                jobs: 1,
+                single_dataset: false,
+                stream: StreamOpt::default(),
+                recv: RecvPropsOpt::default(),
+                buffer: BufferOpt::default(),
+                lock: LockOpt::default(),
+            },
+        }
+    }
+
+    /// Also replicate to `destination`. Each destination keeps its own anchor, so a failure on
+    /// one does not affect the others.
+    pub fn also_to(mut self, destination: &str) -> Self {
+        self.opt.to.push(destination.to_string());
+        self
+    }
+
+    /// Only log (and plan) what would be changed, as `-n` does.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.opt.dryrun = dry_run;
+        self
+    }
+
+    /// Replace the source repl_ snapshot with a bookmark once it has been sent, as `--bookmarks`
+    /// does.
+    pub fn bookmarks(mut self, bookmarks: bool) -> Self {
+        self.opt.bookmarks = bookmarks;
+        self
+    }
+
+    /// Keep `keep` previous anchors on both sides as well as the new one, as `--keep-anchors`
+    /// does.
+    pub fn keep_anchors(mut self, keep: usize) -> Self {
+        self.opt.keep_anchors = keep;
+        self
+    }
+
+    /// Send plain streams rather than raw ones, received with -x encryption, as `--no-raw`
+    /// does.
+    pub fn raw(mut self, raw: bool) -> Self {
+        self.opt.stream = StreamOpt::new(raw, self.opt.stream.props());
+        self
+    }
+
+    /// Send each dataset as its own stream, `jobs` at a time, as `--jobs` does.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.opt.jobs = jobs;
+        self
+    }
+
+    /// Once replicated, prune the auto snapshots of each destination with `policy`.
+    pub fn retention(mut self, policy: RetentionPolicy) -> Self {
+        self.opt.dest_keep_hours = policy.keep_hours;
+        self.opt.dest_keep_daily = policy.keep_daily;
+        self
+    }
+
+    /// Replicate the source alone, not its descendants, as `--single-dataset` does.
+    pub fn single_dataset(mut self, single: bool) -> Self {
+        self.opt.single_dataset = single;
+        self
+    }
+
+    /// Run even if the pre-flight checks fail.
+    pub fn skip_preflight(mut self, skip: bool) -> Self {
+        self.opt.skip_preflight = skip;
+        self
+    }
+
+    /// Refuse to run when a pool is DEGRADED, resilvering or scrubbing, rather than run at a
+    /// lower priority.
+    pub fn require_healthy(mut self, require: bool) -> Self {
+        self.opt.require_healthy = require;
+        self
+    }
+
+    fn error(&self, what: &str) -> Error {
+        let mut dests = vec![self.opt.to_pool.as_str()];
+        dests.extend(self.opt.to.iter().map(String::as_str));
+        Error::new(format!(
+            "{} of {} to {}",
+            what,
+            self.opt.from_pool,
+            dests.join(", ")
+        ))
+    }
+
+    /// The snapshot - or bookmark, with `#` - of the source that the next run will send the
+    /// first destination incrementally from, which is the newest repl_ anchor both sides have.
+    /// None if there is no common anchor, when a run would fall back to a full send.
+    pub fn precursor(&self) -> Result<Option<Snapshot>, Error> {
+        let err = |_| self.error("finding the precursor");
+        let _cache = listing::cached();
+        let dests = repl_destinations(&self.opt).map_err(err)?;
+        let mut dest = match dests.into_iter().next() {
+            Some(dest) => dest,
+            None => return Ok(None),
+        };
+        // Only a question, so approve nothing.
+        dest.dryrun = true;
+        dest.fallback_full = true;
+        let from_snaps = repl_snap_list(&self.opt.from_pool).map_err(err)?;
+        let from_bookmarks = repl_bookmark_list(&self.opt.from_pool).map_err(err)?;
+        let (precursor, _) = repl_precursor(&dest, &from_snaps, &from_bookmarks).map_err(err)?;
+        Ok(from_snaps
+            .into_iter()
+            .chain(from_bookmarks)
+            .find(|snap| Some(snap.name()) == precursor.as_deref()))
+    }
+
+    /// The first, full, replication to the destinations, as `init_repl` does.
+    pub fn init(&self) -> Result<(), Error> {
+        let _cache = listing::cached();
+        do_init(&self.opt).map_err(|_| self.error("initial replication"))
+    }
+
+    /// Replicate what is new since the last replication to each destination, as `repl` does.
+    pub fn run(&self) -> Result<(), Error> {
+        let _cache = listing::cached();
+        do_repl(&self.opt).map_err(|_| self.error("repl"))
+    }
+}
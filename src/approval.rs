@@ -0,0 +1,448 @@
+//! Two-person approval of destructive operations.
+//!
+//! With `required = true` under `[approval]` in `znapper.toml`, a full resync, a forced rollback
+//! of a destination, or a destroy of more than `mass_destroy` snapshots at once is not carried
+//! out. Instead it is staged as a plan in `approvals.json` in the state directory, and the
+//! operation is skipped until the plan is approved with `znapper approve <plan-id>`. The next run
+//! that wants to do exactly the same operation then consumes the approval and does it - the same
+//! snapshots for a mass destroy, not whatever a later cleanup of the pool would destroy. An
+//! approval not used within `expiry` (a day by default) lapses, and the operation is staged again.
+//!
+//! With `distinct_approver = true` the approver must be a different user to the one that staged
+//! the plan, so a single compromised account can't approve its own destruction. The user is the
+//! real uid of the process, not anything the environment claims.
+
+use crate::anchors::state_dir;
+use crate::config::Config;
+use crate::parse_duration;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::os::raw::{c_char, c_uint};
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn};
+
+/// How long an approval stands unless `[approval]` gives an `expiry`.
+const EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+extern "C" {
+    fn getuid() -> c_uint;
+    fn getpwuid(uid: c_uint) -> *const Passwd;
+}
+
+/// The start of struct passwd, which is the name on every unix.
+#[repr(C)]
+struct Passwd {
+    pw_name: *const c_char,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct ApproveOpt {
+    /// The plan to approve. Without one, the staged plans are listed.
+    plan_id: Option<u64>,
+    /// Drop the plan instead of approving it.
+    #[structopt(long = "reject")]
+    reject: bool,
+}
+
+/// What a plan would destroy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Kind {
+    /// A full send that replaces the destination, setting the diverged one aside.
+    FullResync,
+    /// A receive with -F, rolling back changes on the destination.
+    Rollback,
+    /// A cleanup that destroys many snapshots at once.
+    MassDestroy,
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kind::FullResync => write!(f, "full resync"),
+            Kind::Rollback => write!(f, "rollback"),
+            Kind::MassDestroy => write!(f, "mass destroy"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Plan {
+    id: u64,
+    kind: Kind,
+    /// The dataset (or remote) the operation is done to.
+    target: String,
+    description: String,
+    /// The snapshots a mass destroy destroys - the approval is of these and no others.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    snapshots: Vec<String>,
+    staged_by: String,
+    staged_at: i64,
+    #[serde(default)]
+    approved_by: Option<String>,
+    #[serde(default)]
+    approved_at: Option<i64>,
+}
+
+impl Plan {
+    /// Is this the plan of exactly this operation?
+    fn is(&self, kind: Kind, target: &str, description: &str, snapshots: &[String]) -> bool {
+        self.kind == kind
+            && self.target == target
+            && self.description == description
+            && self.snapshots == snapshots
+    }
+
+    /// Does this plan, once approved, allow the operation - or of a mass destroy, the part of it
+    /// that destroys the snapshots that were approved?
+    fn covers(&self, kind: Kind, target: &str, description: &str, snapshots: &[String]) -> bool {
+        match kind {
+            Kind::MassDestroy => {
+                self.kind == kind
+                    && self.target == target
+                    && self.snapshots.iter().all(|s| snapshots.contains(s))
+            }
+            _ => self.is(kind, target, description, snapshots),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PlanStore {
+    next_id: u64,
+    plans: Vec<Plan>,
+}
+
+impl PlanStore {
+    fn path() -> PathBuf {
+        state_dir().join("approvals.json")
+    }
+
+    fn load() -> Result<Self, ()> {
+        let path = Self::path();
+        match File::open(&path) {
+            Ok(f) => serde_json::from_reader(f).map_err(|e| {
+                error!("Failed to parse {:?} -> {:?}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(PlanStore::default()),
+            Err(e) => {
+                error!("Failed to open {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), ()> {
+        let path = Self::path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                error!("Failed to create state dir {:?} -> {:?}", parent, e);
+            })?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        let f = File::create(&tmp).map_err(|e| {
+            error!("Failed to create {:?} -> {:?}", tmp, e);
+        })?;
+        serde_json::to_writer_pretty(&f, self).map_err(|e| {
+            error!("Failed to write {:?} -> {:?}", tmp, e);
+        })?;
+        fs::rename(&tmp, &path).map_err(|e| {
+            error!("Failed to replace {:?} -> {:?}", path, e);
+        })
+    }
+}
+
+/// The name of `uid`, or the uid itself if it has none.
+fn user_name(uid: c_uint) -> String {
+    let pw = unsafe { getpwuid(uid) };
+    if pw.is_null() {
+        return format!("uid {}", uid);
+    }
+    let name = unsafe { (*pw).pw_name };
+    if name.is_null() {
+        return format!("uid {}", uid);
+    }
+    unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The login of the real uid, as ssh logs in with.
+#[cfg(feature = "native-ssh")]
+pub(crate) fn login() -> String {
+    user_name(unsafe { getuid() })
+}
+
+/// Who is running znapper, by the real uid - anyone can set `USER`. Run as root, it is the user
+/// that invoked sudo or doas, which only root can claim to be, and root could edit the plans
+/// anyway.
+fn current_user() -> String {
+    let uid = unsafe { getuid() };
+    if uid != 0 {
+        return user_name(uid);
+    }
+    if let Some(uid) = std::env::var("SUDO_UID").ok().and_then(|u| u.parse().ok()) {
+        return user_name(uid);
+    }
+    std::env::var("DOAS_USER").unwrap_or_else(|_| user_name(uid))
+}
+
+/// How long an approval stands, from `[approval]` or the default.
+fn expiry(config: &Config) -> Duration {
+    match config.approval.expiry.as_deref().map(parse_duration) {
+        Some(Ok(expiry)) => expiry,
+        Some(Err(e)) => {
+            error!("Ignoring the approval expiry in znapper.toml -> {}", e);
+            EXPIRY
+        }
+        None => EXPIRY,
+    }
+}
+
+fn format_ts(ts: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(ts).format("%Y-%m-%dT%H:%M:%SZ")
+}
+
+/// Allow a `kind` operation on `target` to go ahead. When approval is required, this consumes a
+/// matching approved plan, or else stages one and refuses.
+pub(crate) fn gate(dry: bool, kind: Kind, target: &str, description: &str) -> Result<(), ()> {
+    gate_exactly(dry, kind, target, description, &[]).map(|_| ())
+}
+
+/// As `gate`, of an operation on `snapshots`, returning those it may go ahead with.
+fn gate_exactly(
+    dry: bool,
+    kind: Kind,
+    target: &str,
+    description: &str,
+    snapshots: &[String],
+) -> Result<Vec<String>, ()> {
+    let config = Config::load()?;
+    if !config.approval.required {
+        return Ok(snapshots.to_vec());
+    }
+
+    let mut store = PlanStore::load()?;
+    let now = OffsetDateTime::now_utc().timestamp();
+    let expiry = expiry(&config).as_secs() as i64;
+    let before = store.plans.len();
+    // A plan of the same kind for the target that isn't this one no longer describes what would
+    // run - unless it was approved and still covers part of it - and neither does an approval
+    // that has lapsed.
+    store.plans.retain(|p| {
+        let matches = if p.approved_by.is_some() {
+            p.covers(kind, target, description, snapshots)
+        } else {
+            p.is(kind, target, description, snapshots)
+        };
+        let superseded = p.kind == kind && p.target == target && !matches;
+        let lapsed = p
+            .approved_at
+            .is_some_and(|at| now.saturating_sub(at) > expiry);
+        if superseded || lapsed {
+            warn!(
+                "Dropping plan {} ({} of {}) -> {}",
+                p.id,
+                p.kind,
+                p.target,
+                if lapsed {
+                    "its approval has expired"
+                } else {
+                    "the operation has changed"
+                }
+            );
+        }
+        !(superseded || lapsed)
+    });
+    if store.plans.len() != before && !dry {
+        store.save()?;
+    }
+    let existing = store
+        .plans
+        .iter()
+        .position(|p| p.kind == kind && p.target == target);
+
+    if let Some(i) = existing {
+        let plan = &store.plans[i];
+        if let Some(approved_by) = plan.approved_by.as_deref() {
+            if dry {
+                info!(
+                    "dryrun: consume plan {} ({} of {}) approved by {}",
+                    plan.id, kind, target, approved_by
+                );
+                return Ok(approved(plan, snapshots));
+            }
+            info!(
+                "Running plan {} ({} of {}) approved by {}",
+                plan.id, kind, target, approved_by
+            );
+            let plan = store.plans.remove(i);
+            store.save()?;
+            return Ok(approved(&plan, snapshots));
+        }
+        error!(
+            "The {} of {} is awaiting approval - znapper approve {}",
+            kind, target, plan.id
+        );
+        return Err(());
+    }
+
+    if dry {
+        info!(
+            "dryrun: stage {} of {} for approval -> {}",
+            kind, target, description
+        );
+        crate::plan::approval(&kind.to_string(), target, description);
+        return Ok(snapshots.to_vec());
+    }
+
+    store.next_id += 1;
+    let plan = Plan {
+        id: store.next_id,
+        kind,
+        target: target.to_string(),
+        description: description.to_string(),
+        snapshots: snapshots.to_vec(),
+        staged_by: current_user(),
+        staged_at: now,
+        approved_by: None,
+        approved_at: None,
+    };
+    error!(
+        "The {} of {} requires approval - staged as plan {}, approve it with znapper approve {}",
+        kind, target, plan.id, plan.id
+    );
+    debug!(?plan);
+    store.plans.push(plan);
+    // Whether or not the plan could be saved, the operation does not go ahead.
+    let _ = store.save();
+    Err(())
+}
+
+/// What of `snapshots` the approval of `plan` allows - those it was approved for, less any that
+/// have gone since.
+fn approved(plan: &Plan, snapshots: &[String]) -> Vec<String> {
+    if plan.kind != Kind::MassDestroy {
+        return snapshots.to_vec();
+    }
+    if plan.snapshots.len() < snapshots.len() {
+        warn!(
+            "Plan {} approved {} of the {} snapshots - the rest wait for the next cleanup",
+            plan.id,
+            plan.snapshots.len(),
+            snapshots.len()
+        );
+    }
+    plan.snapshots.clone()
+}
+
+/// Refuse to destroy `snapshots` of `target` at once without approval, if that is a mass
+/// destroy, returning those that may be destroyed. An approval is of the snapshots it was staged
+/// with, so a later cleanup that would destroy more destroys only those.
+pub(crate) fn gate_destroy(
+    dry: bool,
+    target: &str,
+    snapshots: &[String],
+) -> Result<Vec<String>, ()> {
+    let config = Config::load()?;
+    match config.approval.mass_destroy {
+        Some(limit) if snapshots.len() > limit => {
+            let mut snapshots = snapshots.to_vec();
+            snapshots.sort();
+            gate_exactly(
+                dry,
+                Kind::MassDestroy,
+                target,
+                &format!("destroy {} snapshots", snapshots.len()),
+                &snapshots,
+            )
+        }
+        _ => Ok(snapshots.to_vec()),
+    }
+}
+
+pub(crate) fn do_approve(opt: &ApproveOpt) -> Result<(), ()> {
+    debug!("do_approve");
+
+    let config = Config::load()?;
+    let mut store = PlanStore::load()?;
+
+    let id = match opt.plan_id {
+        Some(id) => id,
+        None => {
+            for plan in store.plans.iter() {
+                println!(
+                    "{}\t{}\t{}\t{}\tstaged by {} at {}\t{}",
+                    plan.id,
+                    plan.kind,
+                    plan.target,
+                    plan.description,
+                    plan.staged_by,
+                    format_ts(plan.staged_at),
+                    match plan.approved_by.as_deref() {
+                        Some(by) => format!("approved by {}", by),
+                        None => "pending".to_string(),
+                    }
+                );
+            }
+            return Ok(());
+        }
+    };
+
+    let i = store.plans.iter().position(|p| p.id == id).ok_or_else(|| {
+        error!("No such plan {}", id);
+    })?;
+
+    if opt.reject {
+        let plan = store.plans.remove(i);
+        store.save()?;
+        info!(
+            "Rejected plan {} ({} of {})",
+            plan.id, plan.kind, plan.target
+        );
+        return Ok(());
+    }
+
+    let user = current_user();
+    let plan = &mut store.plans[i];
+    if config.approval.distinct_approver && plan.staged_by == user {
+        error!(
+            "Plan {} was staged by {} - it must be approved by someone else",
+            plan.id, user
+        );
+        return Err(());
+    }
+    if plan.approved_by.is_some() {
+        warn!("Plan {} was already approved", plan.id);
+    }
+    plan.approved_by = Some(user);
+    plan.approved_at = Some(OffsetDateTime::now_utc().timestamp());
+    let (kind, target) = (plan.kind, plan.target.clone());
+    store.save()?;
+    info!(
+        "Approved plan {} ({} of {}) - it runs with the next repl or cleanup, within {:?}",
+        id,
+        kind,
+        target,
+        expiry(&config)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_approver_is_the_real_user_not_the_environment() {
+        let user = current_user();
+        std::env::set_var("USER", "someoneelse");
+        std::env::set_var("SUDO_USER", "someoneelse");
+        assert_eq!(current_user(), user);
+        assert_ne!(user, "someoneelse");
+    }
+}
@@ -0,0 +1,324 @@
+//! An audit trail of destructive operations, appended to `audit.jsonl` in the state directory.
+//!
+//! Every snapshot destroy and rollback (`zfs rollback -r`, or a forced `zfs recv -F`) is one json
+//! line - with its time, the snapshot and its guid, why it was done, the sync job and command line
+//! that did it, who ran it, and whether it was a dry run. Unlike the history it holds nothing else,
+//! and also records what dry runs would have done. The file is only ever appended to, so it can be
+//! shipped elsewhere or made append-only with `chattr +a`. `znapper audit` queries it.
+
+use crate::anchors::state_dir;
+use crate::{parse_duration, OutputFormat};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{error, warn};
+
+static JOB: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct AuditOpt {
+    /// Only show the operations on this dataset (and its descendants).
+    dataset: Option<String>,
+    /// Only show the operations of the last this long, ie 12h or 30d.
+    #[structopt(long = "since", parse(try_from_str = parse_duration))]
+    since: Option<Duration>,
+    /// Only show the operations of this sync job.
+    #[structopt(long = "job")]
+    job: Option<String>,
+    /// Leave out what dry runs would have done.
+    #[structopt(long = "no-dryrun")]
+    no_dryrun: bool,
+    /// Only show the newest this many operations.
+    #[structopt(long = "limit")]
+    limit: Option<usize>,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Operation {
+    Destroy,
+    Rollback,
+}
+
+/// Why a snapshot was destroyed, or a destination rolled back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Reason {
+    /// An auto snapshot past its retention, or empty.
+    Retention,
+    /// A replication anchor replaced by a newer one.
+    Superseded,
+    /// A repl_ snapshot that no replication received.
+    Unsent,
+    /// A repl_ snapshot replaced by a bookmark of it.
+    Bookmarked,
+    /// repl_cleanup, down to the newest common anchor.
+    ReplCleanup,
+    /// A deferred cleanup past its grace period.
+    Purge,
+    /// Asked for by name, through the api or the tui.
+    Requested,
+    /// A dataset (or the datasets of a group) restored in place, to one of its snapshots.
+    Restore,
+    /// A dataset left out of replication, destroyed on a destination that held it.
+    Excluded,
+    /// A receive with --force-rollback.
+    ForcedRollback,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => write!(f, "{}", s),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub ts: i64,
+    pub operation: Operation,
+    /// The snapshot destroyed, or the dataset rolled back.
+    pub target: String,
+    /// The guid of the snapshot destroyed, or rolled back to.
+    pub guid: Option<String>,
+    /// The anchor a rollback went back to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    pub reason: Reason,
+    /// The sync job running, if it was one.
+    pub job: Option<String>,
+    pub command: String,
+    pub user: String,
+    pub dryrun: bool,
+    /// Whether the destroy or zfs rollback succeeded. A forced rollback is recorded as it is
+    /// decided on, and how its receive went is in the history.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub succeeded: Option<bool>,
+}
+
+impl Entry {
+    /// Is this about `dataset` or something beneath it?
+    fn concerns(&self, dataset: &str) -> bool {
+        self.target == dataset
+            || self
+                .target
+                .strip_prefix(dataset)
+                .map(|rest| rest.starts_with(['/', '@']))
+                .unwrap_or(false)
+    }
+}
+
+/// The operations from here on are of the sync job `job`.
+pub(crate) fn job(job: &str) {
+    if let Ok(mut current) = JOB.lock() {
+        *current = Some(job.to_string());
+    }
+}
+
+fn path() -> PathBuf {
+    state_dir().join("audit.jsonl")
+}
+
+/// Who is running znapper - the user that invoked sudo, if it was used.
+fn current_user() -> String {
+    std::env::var("SUDO_USER")
+        .or_else(|_| std::env::var("USER"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn append(entry: &Entry) {
+    let path = path();
+    let line = match serde_json::to_string(entry) {
+        Ok(mut line) => {
+            line.push('\n');
+            line
+        }
+        Err(e) => {
+            warn!("failed to serialise audit entry -> {:?}", e);
+            return;
+        }
+    };
+    let res = path
+        .parent()
+        .map(fs::create_dir_all)
+        .unwrap_or(Ok(()))
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut f| f.write_all(line.as_bytes()));
+    if let Err(e) = res {
+        warn!("Unable to append to the audit log {:?} -> {:?}", path, e);
+    }
+}
+
+fn entry(operation: Operation, target: &str, guid: Option<String>, reason: Reason) -> Entry {
+    Entry {
+        ts: OffsetDateTime::now_utc().timestamp(),
+        operation,
+        target: target.to_string(),
+        guid,
+        to: None,
+        reason,
+        job: JOB.lock().ok().and_then(|job| job.clone()),
+        command: std::env::args().collect::<Vec<_>>().join(" "),
+        user: current_user(),
+        dryrun: false,
+        succeeded: None,
+    }
+}
+
+/// Record the destroy of `snapshot` (with the snapshots of the same name beneath it).
+pub(crate) fn destroy(
+    dry: bool,
+    snapshot: &str,
+    guid: Option<String>,
+    reason: Reason,
+    succeeded: bool,
+) {
+    append(&Entry {
+        dryrun: dry,
+        succeeded: (!dry).then_some(succeeded),
+        ..entry(Operation::Destroy, snapshot, guid, reason)
+    })
+}
+
+/// Record the rollback of `dataset` to `to`, by a receive with -F.
+pub(crate) fn rollback(dry: bool, dataset: &str, to: &str, guid: Option<String>) {
+    append(&Entry {
+        dryrun: dry,
+        to: Some(to.to_string()),
+        ..entry(Operation::Rollback, dataset, guid, Reason::ForcedRollback)
+    })
+}
+
+/// Record the rollback of the dataset of `snapshot` to it, by zfs rollback -r, for `reason`.
+pub(crate) fn rollback_to(
+    dry: bool,
+    snapshot: &str,
+    guid: Option<String>,
+    reason: Reason,
+    succeeded: bool,
+) {
+    let dataset = snapshot
+        .split_once('@')
+        .map_or(snapshot, |(dataset, _)| dataset);
+    append(&Entry {
+        dryrun: dry,
+        to: Some(snapshot.to_string()),
+        succeeded: (!dry).then_some(succeeded),
+        ..entry(Operation::Rollback, dataset, guid, reason)
+    })
+}
+
+/// Every entry, oldest first. Lines that (no longer) parse are skipped.
+fn entries() -> Result<Vec<Entry>, ()> {
+    let path = path();
+    let f = match File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            error!("Failed to open {:?} -> {:?}", path, e);
+            return Err(());
+        }
+    };
+    Ok(BufReader::new(f)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect())
+}
+
+fn format_ts(ts: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(ts).format("%Y-%m-%dT%H:%M:%SZ")
+}
+
+pub(crate) fn do_audit(opt: &AuditOpt) {
+    let mut entries = match entries() {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    if let Some(dataset) = opt.dataset.as_deref() {
+        entries.retain(|e| e.concerns(dataset));
+    }
+    if let Some(since) = opt.since {
+        let after = OffsetDateTime::now_utc()
+            .timestamp()
+            .saturating_sub(since.as_secs() as i64);
+        entries.retain(|e| e.ts >= after);
+    }
+    if let Some(job) = opt.job.as_deref() {
+        entries.retain(|e| e.job.as_deref() == Some(job));
+    }
+    if opt.no_dryrun {
+        entries.retain(|e| !e.dryrun);
+    }
+    if let Some(limit) = opt.limit {
+        let skip = entries.len().saturating_sub(limit);
+        entries.drain(..skip);
+    }
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&entries) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise audit log -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for e in entries {
+                let result = match (e.dryrun, e.succeeded) {
+                    (true, _) => "dryrun",
+                    (false, Some(false)) => "failed",
+                    (false, _) => "ok",
+                };
+                let what = match (e.operation, e.to.as_deref()) {
+                    (Operation::Rollback, Some(to)) => format!("rollback\t{} to {}", e.target, to),
+                    (Operation::Rollback, None) => format!("rollback\t{}", e.target),
+                    (Operation::Destroy, _) => format!("destroy\t{}", e.target),
+                };
+                println!(
+                    "{}\t{}\t{}\tguid {}\t{}\t{}{}\t{}",
+                    format_ts(e.ts),
+                    result,
+                    what,
+                    e.guid.as_deref().unwrap_or("-"),
+                    e.reason,
+                    e.user,
+                    e.job.map(|j| format!(" (job {})", j)).unwrap_or_default(),
+                    e.command
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_round_trip_and_concern_their_dataset() {
+        let e = Entry {
+            dryrun: true,
+            ..entry(
+                Operation::Destroy,
+                "tank/home@auto_1",
+                Some("123".to_string()),
+                Reason::Retention,
+            )
+        };
+        let line = serde_json::to_string(&e).unwrap();
+        assert!(line.contains(r#""operation":"destroy""#));
+        assert!(line.contains(r#""reason":"retention""#));
+        let e: Entry = serde_json::from_str(&line).unwrap();
+        assert!(e.concerns("tank") && e.concerns("tank/home") && !e.concerns("tank/ho"));
+        assert_eq!(Reason::ForcedRollback.to_string(), "forced-rollback");
+    }
+}
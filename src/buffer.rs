@@ -0,0 +1,507 @@
+//! An in-memory buffer between `zfs send` and whatever receives the stream.
+//!
+//! zfs send produces data in bursts, as does zfs recv consume it, and without a buffer each side
+//! stalls the other. With `--buffer` the stream passes through a bounded buffer of that size,
+//! either built in (a reader and a writer thread with a queue of chunks between them), or with
+//! `--mbuffer`, the mbuffer tool when it is installed (falling back to the built in buffer when
+//! it is not).
+//!
+//! With `--proxy`, or `--rate-limit`, the stream passes through znapper even without `--buffer`
+//! (through a queue of a single chunk). The built in buffer is a proxy either way - as it writes
+//! the stream on to the receiver it counts the bytes, keeps an adler32 of them, holds them to the
+//! rate limit, and measures the throughput, all of which the progress checkpoint of the send
+//! shows while it runs. The rate limit can follow the time of day, ie `5M@08:00-20:00` to hold
+//! a send that runs into office hours to 5M a second until they end - the schedule is looked at
+//! again every second or so for as long as the stream runs.
+
+use serde::Deserialize;
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use time::{OffsetDateTime, UtcOffset};
+use tracing::{debug, error, info, warn};
+
+/// The stream is queued in chunks of this size.
+const CHUNK: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub(crate) struct BufferOpt {
+    /// Buffer this much of the send stream in memory, ie 256M or 1G, to smooth out bursts.
+    #[structopt(long = "buffer", parse(try_from_str = parse_size))]
+    pub size: Option<usize>,
+    /// With --buffer, use mbuffer as the buffer if it is installed.
+    #[structopt(long = "mbuffer")]
+    pub mbuffer: bool,
+    /// Copy the send stream through znapper, counting and checksumming it, without --buffer.
+    #[structopt(long = "proxy")]
+    pub proxy: bool,
+    /// Send no faster than this many bytes a second, ie 50M, or by the local time of day, ie
+    /// 5M@08:00-20:00,20M - the first rule whose hours cover the time applies, and the stream is
+    /// unlimited when none do. Implies --proxy.
+    #[structopt(long = "rate-limit")]
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Parse a size such as 512K, 256M or 1G. A bare number is in bytes.
+pub(crate) fn parse_size(s: &str) -> Result<usize, String> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let value: usize = value.parse().map_err(|_| format!("invalid size {}", s))?;
+    let scale = match unit {
+        "" => 1,
+        "K" | "k" => 1024,
+        "M" | "m" => 1024 * 1024,
+        "G" | "g" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size {} - use K, M or G", s)),
+    };
+    match value.checked_mul(scale) {
+        Some(size) if size > 0 => Ok(size),
+        _ => Err(format!("invalid size {}", s)),
+    }
+}
+
+/// A rate limit, or the rules of a schedule of them - `<rate>[@HH:MM-HH:MM]`, separated by commas.
+/// A rate is a size, or `unlimited`, and a rule without hours covers the whole day.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct RateLimit(Vec<RateRule>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RateRule {
+    /// Bytes a second, or None when unlimited.
+    rate: Option<u64>,
+    /// The minutes of the day from and until which the rule applies, which can wrap midnight.
+    hours: Option<(u16, u16)>,
+}
+
+/// The minute of the day of HH:MM.
+fn parse_minute(s: &str) -> Result<u16, String> {
+    s.split_once(':')
+        .and_then(|(h, m)| Some((h.parse::<u16>().ok()?, m.parse::<u16>().ok()?)))
+        .filter(|(h, m)| *h < 24 && *m < 60 && s.len() == 5)
+        .map(|(h, m)| h * 60 + m)
+        .ok_or_else(|| format!("invalid time {} - use HH:MM", s))
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|rule| {
+                let (rate, hours) = match rule.split_once('@') {
+                    Some((rate, hours)) => (rate, Some(hours)),
+                    None => (rule, None),
+                };
+                let rate = match rate {
+                    "unlimited" => None,
+                    rate => Some(parse_size(rate)? as u64),
+                };
+                let hours = match hours {
+                    Some(hours) => {
+                        let (from, until) = hours
+                            .split_once('-')
+                            .ok_or_else(|| format!("invalid hours {} - use HH:MM-HH:MM", hours))?;
+                        let (from, until) = (parse_minute(from)?, parse_minute(until)?);
+                        if from == until {
+                            return Err(format!("the hours {} are empty", hours));
+                        }
+                        Some((from, until))
+                    }
+                    None => None,
+                };
+                Ok(RateRule { rate, hours })
+            })
+            .collect::<Result<_, _>>()
+            .map(RateLimit)
+    }
+}
+
+impl RateLimit {
+    /// The limit at `minute` of the day, from the first rule that covers it.
+    fn at(&self, minute: u16) -> Option<u64> {
+        self.0
+            .iter()
+            .find(|rule| match rule.hours {
+                Some((from, until)) if from < until => (from..until).contains(&minute),
+                Some((from, until)) => minute >= from || minute < until,
+                None => true,
+            })
+            .and_then(|rule| rule.rate)
+    }
+
+    /// The limit now, by the local time.
+    fn now(&self) -> Option<u64> {
+        let now = OffsetDateTime::now_utc();
+        let now = now.to_offset(UtcOffset::try_local_offset_at(now).unwrap_or(UtcOffset::UTC));
+        self.at(u16::from(now.hour()) * 60 + u16::from(now.minute()))
+    }
+}
+
+/// The window throughput is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Adler-32, the rolling checksum of zlib, over the stream as it passes.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    const MOD: u32 = 65521;
+    /// The most bytes that can be summed before `b` could overflow.
+    const NMAX: usize = 5552;
+
+    pub(crate) fn new() -> Self {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for block in bytes.chunks(Self::NMAX) {
+            for byte in block {
+                self.a += u32::from(*byte);
+                self.b += self.a;
+            }
+            self.a %= Self::MOD;
+            self.b %= Self::MOD;
+        }
+    }
+
+    pub(crate) fn value(self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+/// What a proxy has passed on so far, shared with whatever reports on the send.
+#[derive(Debug)]
+pub(crate) struct Meter {
+    bytes: AtomicU64,
+    checksum: AtomicU32,
+    bytes_per_sec: AtomicU64,
+    /// The limit the stream is held to now, in bytes a second, or 0 when it is unlimited.
+    limit: AtomicU64,
+    schedule: Option<RateLimit>,
+}
+
+impl Meter {
+    fn new(schedule: Option<RateLimit>) -> Self {
+        Meter {
+            bytes: AtomicU64::new(0),
+            checksum: AtomicU32::new(Adler32::new().value()),
+            bytes_per_sec: AtomicU64::new(0),
+            limit: AtomicU64::new(0),
+            schedule,
+        }
+    }
+
+    /// The limit the stream is held to now, in bytes a second.
+    pub(crate) fn rate_limit(&self) -> Option<u64> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|l| *l > 0)
+    }
+
+    /// Look at the schedule again, returning the limit now.
+    fn reschedule(&self) -> Option<u64> {
+        let limit = self.schedule.as_ref().and_then(RateLimit::now);
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+        limit
+    }
+
+    /// Bytes written on to the receiver.
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// The adler32 of the bytes written so far.
+    pub(crate) fn checksum(&self) -> u32 {
+        self.checksum.load(Ordering::Relaxed)
+    }
+
+    /// Bytes a second over the last second or so.
+    pub(crate) fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec.load(Ordering::Relaxed)
+    }
+}
+
+/// How long to wait before writing more, with `sent` bytes written `elapsed` since the start,
+/// to keep to `rate` bytes a second.
+fn throttle(sent: u64, elapsed: Duration, rate: u64) -> Option<Duration> {
+    let due = Duration::from_secs_f64(sent as f64 / rate.max(1) as f64);
+    due.checked_sub(elapsed).filter(|wait| !wait.is_zero())
+}
+
+/// Write `rx`, the chunks of the stream, to `writer`, metering them into `meter`.
+fn drain(rx: mpsc::Receiver<Vec<u8>>, mut writer: impl Write, meter: &Meter) -> io::Result<()> {
+    let started = Instant::now();
+    let mut adler = Adler32::new();
+    let mut sent = 0u64;
+    let (mut window, mut window_bytes) = (started, 0u64);
+    let mut limit = meter.reschedule();
+    // The limit is kept to from when it last changed.
+    let (mut limited, mut limited_bytes) = (started, 0u64);
+    for chunk in rx {
+        // Write in smaller pieces when limited, so that the limit is kept to smoothly.
+        let piece = match limit {
+            Some(rate) => (rate as usize / 10).clamp(4096, CHUNK),
+            None => CHUNK,
+        };
+        for bytes in chunk.chunks(piece) {
+            if let Some(wait) =
+                limit.and_then(|rate| throttle(limited_bytes, limited.elapsed(), rate))
+            {
+                thread::sleep(wait);
+            }
+            writer.write_all(bytes)?;
+            adler.update(bytes);
+            sent += bytes.len() as u64;
+            window_bytes += bytes.len() as u64;
+            limited_bytes += bytes.len() as u64;
+            meter.bytes.store(sent, Ordering::Relaxed);
+            meter.checksum.store(adler.value(), Ordering::Relaxed);
+
+            let elapsed = window.elapsed();
+            if elapsed >= RATE_WINDOW {
+                let rate = window_bytes as f64 / elapsed.as_secs_f64();
+                meter.bytes_per_sec.store(rate as u64, Ordering::Relaxed);
+                (window, window_bytes) = (Instant::now(), 0);
+
+                let now = meter.reschedule();
+                if now != limit {
+                    match now {
+                        Some(rate) => info!("rate limit now {} B/s", rate),
+                        None => info!("rate limit lifted"),
+                    }
+                    limit = now;
+                    (limited, limited_bytes) = (Instant::now(), 0);
+                }
+            }
+        }
+    }
+    writer.flush()?;
+    // The average over the whole stream, once it is done.
+    let elapsed = started.elapsed().as_secs_f64();
+    if elapsed > 0.0 {
+        meter
+            .bytes_per_sec
+            .store((sent as f64 / elapsed) as u64, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Is `bin` in one of the PATH directories?
+fn installed(bin: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(bin).is_file()))
+        .unwrap_or(false)
+}
+
+/// The buffer a stream is passing through, to be finished once the receiver is done.
+pub(crate) enum Buffer {
+    Direct,
+    Mbuffer(Child),
+    Builtin(
+        JoinHandle<io::Result<()>>,
+        JoinHandle<io::Result<()>>,
+        Arc<Meter>,
+    ),
+}
+
+/// Pass `stream` through the buffer of `opt`, returning what the receiver should read from.
+pub(crate) fn buffered(opt: &BufferOpt, stream: ChildStdout) -> Result<(Stdio, Buffer), ()> {
+    let proxy = opt.proxy || opt.rate_limit.is_some();
+    let size = match (opt.size, proxy) {
+        (Some(size), _) => size,
+        (None, true) => CHUNK,
+        (None, false) => return Ok((Stdio::from(stream), Buffer::Direct)),
+    };
+
+    if opt.mbuffer && proxy {
+        warn!("the stream is proxied - using the built in buffer rather than mbuffer");
+    } else if opt.mbuffer && !installed("mbuffer") {
+        warn!("mbuffer is not installed - using the built in buffer");
+    } else if opt.mbuffer {
+        // mbuffer takes a size in bytes with a suffix.
+        let mbuffer = Command::new("mbuffer")
+            .arg("-q")
+            .arg("-m")
+            .arg(format!("{}k", size.div_ceil(1024)))
+            .stdin(stream)
+            .stdout(Stdio::piped())
+            .spawn();
+        match mbuffer {
+            Ok(mut child) => {
+                return match child.stdout.take() {
+                    Some(out) => Ok((Stdio::from(out), Buffer::Mbuffer(child))),
+                    None => {
+                        error!("Failed to connect to stdout of mbuffer process");
+                        let _ = child.kill();
+                        Err(())
+                    }
+                };
+            }
+            Err(e) => {
+                error!("mbuffer failed -> {:?}", e);
+                return Err(());
+            }
+        }
+    }
+
+    let (reader, writer) = io::pipe().map_err(|e| {
+        error!("Failed to create buffer pipe -> {:?}", e);
+    })?;
+    let (tx, rx) = mpsc::sync_channel::<Vec<u8>>((size / CHUNK).max(1));
+    debug!("buffering {} bytes in {} byte chunks", size, CHUNK);
+
+    let fill = thread::spawn(move || {
+        let mut stream = stream;
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK);
+            (&mut stream).take(CHUNK as u64).read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                return Ok(());
+            }
+            // If the writer is gone the receiver failed, and that is reported there.
+            if tx.send(chunk).is_err() {
+                return Ok(());
+            }
+        }
+    });
+    let meter = Arc::new(Meter::new(opt.rate_limit.clone()));
+    let drain = {
+        let meter = meter.clone();
+        thread::spawn(move || drain(rx, writer, &meter))
+    };
+
+    Ok((Stdio::from(reader), Buffer::Builtin(fill, drain, meter)))
+}
+
+impl Buffer {
+    /// The meter of the stream, when it passes through znapper.
+    pub(crate) fn meter(&self) -> Option<Arc<Meter>> {
+        match self {
+            Buffer::Builtin(_, _, meter) => Some(meter.clone()),
+            _ => None,
+        }
+    }
+
+    /// Wait for the buffer to drain, once the receiver has exited.
+    pub(crate) fn finish(self) -> Result<(), ()> {
+        match self {
+            Buffer::Direct => Ok(()),
+            Buffer::Mbuffer(mut child) => match child.wait() {
+                Ok(status) if status.success() => Ok(()),
+                Ok(status) => {
+                    error!("mbuffer failed -> {:?}", status.code());
+                    Err(())
+                }
+                Err(e) => {
+                    error!("mbuffer failed -> {:?}", e);
+                    Err(())
+                }
+            },
+            Buffer::Builtin(fill, drain, meter) => {
+                let mut ok = true;
+                for (side, handle) in [("read", fill), ("write", drain)] {
+                    match handle.join() {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => {
+                            warn!("buffer {} failed -> {:?}", side, e);
+                            ok = false;
+                        }
+                        Err(_) => {
+                            error!("buffer {} thread panicked", side);
+                            ok = false;
+                        }
+                    }
+                }
+                info!(
+                    "proxied {} bytes at {} B/s, adler32 {:08x}",
+                    meter.bytes(),
+                    meter.bytes_per_sec(),
+                    meter.checksum()
+                );
+                if ok {
+                    Ok(())
+                } else {
+                    Err(())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_proxy_checksums_and_limits_what_it_passes_on() {
+        let mut adler = Adler32::new();
+        adler.update(b"Wikipedia");
+        assert_eq!(adler.value(), 0x11e6_0398);
+        // Summed in one go, or piece by piece, across the blocks it reduces in.
+        let stream: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let (mut whole, mut pieces) = (Adler32::new(), Adler32::new());
+        whole.update(&stream);
+        stream.chunks(777).for_each(|p| pieces.update(p));
+        assert_eq!(whole.value(), pieces.value());
+
+        // 1000 bytes at 100 a second are due at 10s.
+        let rate = 100;
+        assert_eq!(
+            throttle(1000, Duration::from_secs(4), rate),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(throttle(1000, Duration::from_secs(12), rate), None);
+
+        let (tx, rx) = mpsc::sync_channel(4);
+        tx.send(stream.clone()).unwrap();
+        drop(tx);
+        let meter = Meter::new(None);
+        let mut out = Vec::new();
+        drain(rx, &mut out, &meter).unwrap();
+        assert_eq!(out, stream);
+        assert_eq!(meter.bytes(), stream.len() as u64);
+        assert_eq!(meter.checksum(), whole.value());
+    }
+
+    #[test]
+    fn rate_limits_follow_the_time_of_day() {
+        let flat: RateLimit = "50M".parse().unwrap();
+        assert_eq!(flat.at(0), Some(50 * 1024 * 1024));
+
+        // 5M in office hours, 20M in the evening, and unlimited overnight.
+        let office: RateLimit = "5M@08:00-20:00,20M@20:00-23:30".parse().unwrap();
+        assert_eq!(office.at(7 * 60 + 59), None);
+        assert_eq!(office.at(8 * 60), Some(5 * 1024 * 1024));
+        assert_eq!(office.at(20 * 60), Some(20 * 1024 * 1024));
+        assert_eq!(office.at(23 * 60 + 30), None);
+
+        // Hours can wrap midnight, and the first rule to cover the time wins.
+        let night: RateLimit = "unlimited@22:00-06:00,1M".parse().unwrap();
+        assert_eq!(night.at(23 * 60), None);
+        assert_eq!(night.at(5 * 60), None);
+        assert_eq!(night.at(12 * 60), Some(1024 * 1024));
+
+        assert!("5M@8-20".parse::<RateLimit>().is_err());
+        assert!("5M@08:00-08:00".parse::<RateLimit>().is_err());
+        assert!("5M@08:00-24:00".parse::<RateLimit>().is_err());
+        assert!("fast".parse::<RateLimit>().is_err());
+    }
+}
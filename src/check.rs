@@ -0,0 +1,584 @@
+//! Pre-flight checks, that the environment a job runs in is fit for it.
+//!
+//! `znapper check` reports each check as a pass or a fail, exiting 1 if any fail, and repl and
+//! remote_repl run the checks for their own datasets before they start, refusing to run (unless
+//! `--skip-preflight` is given) if any fail. The checks are that:
+//!
+//! * zfs is installed, and recent enough for the raw and resumable sends znapper uses,
+//! * the pools of the datasets are imported and healthy. A pool that is DEGRADED, resilvering
+//!   or scrubbing is only a warning - the run goes ahead at a lower priority, rather than add to
+//!   the load of a pool that is recovering - unless `--require-healthy` is given,
+//! * the running user has the `zfs allow` delegations the job needs, unless it is root,
+//! * remotes are reachable over ssh,
+//! * remote_repl metadata parses, and names a snapshot that exists and matches its anchor.
+
+use crate::anchors::{AnchorStore, Owner};
+use crate::delegation::Role;
+use crate::metadata::{self, MetadataFile, RemoteMetadata};
+use crate::privilege;
+use crate::process::{self, Kind, Timed};
+use crate::ssh::SshOpt;
+use crate::{dataset_exists, resolve_remote_ssh, OutputFormat};
+use serde::Serialize;
+use std::path::Path;
+use std::process::Stdio;
+use structopt::StructOpt;
+use tracing::{debug, error, warn};
+
+/// The oldest OpenZFS with `zfs send -w` and resumable receives.
+const MIN_VERSION: (u32, u32) = (0, 8);
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct CheckOpt {
+    /// A dataset that is snapshotted and sent from, may be repeated.
+    #[structopt(long = "source")]
+    sources: Vec<String>,
+    /// A dataset that is received into, may be repeated. It need not exist yet.
+    #[structopt(long = "destination")]
+    destinations: Vec<String>,
+    /// A remote to check ssh to - user@host, or the name of a target - may be repeated.
+    #[structopt(long = "remote")]
+    remotes: Vec<String>,
+    /// A remote_repl metadata file to check, may be repeated.
+    #[structopt(long = "metadata")]
+    metadata: Vec<String>,
+    #[structopt(flatten)]
+    ssh: SshOpt,
+    /// Fail a pool that is DEGRADED, resilvering or scrubbing, rather than warn.
+    #[structopt(long = "require-healthy")]
+    require_healthy: bool,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+/// What a job touches, and so what is checked before it runs.
+#[derive(Debug, Default)]
+pub(crate) struct Job {
+    pub sources: Vec<String>,
+    pub destinations: Vec<String>,
+    pub metadata: Vec<String>,
+    /// Refuse to run on a pool that is DEGRADED, resilvering or scrubbing.
+    pub require_healthy: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Outcome {
+    check: &'static str,
+    subject: String,
+    pass: bool,
+    /// Passed, but not as well as it might - ie a pool that is scrubbing.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    warning: bool,
+    detail: String,
+}
+
+#[derive(Serialize)]
+struct Summary {
+    passed: usize,
+    failed: usize,
+    warnings: usize,
+    checks: Vec<Outcome>,
+}
+
+fn outcome(check: &'static str, subject: &str, result: Result<String, String>) -> Outcome {
+    let (pass, detail) = match result {
+        Ok(detail) => (true, detail),
+        Err(detail) => (false, detail),
+    };
+    Outcome {
+        check,
+        subject: subject.to_string(),
+        pass,
+        warning: false,
+        detail,
+    }
+}
+
+/// Run `bin` with `args`, returning stdout if it succeeded, or why it didn't.
+pub(crate) fn run(bin: &str, args: &[&str]) -> Result<String, String> {
+    debug!("running -> {} {}", bin, args.join(" "));
+    let output = privilege::command(bin)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => format!("{} is not installed", bin),
+            _ => format!("{} failed -> {:?}", bin, e),
+        })?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(format!(
+            "{} {} -> {}",
+            bin,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// The major and minor version of `zfs-2.1.5-1` or `zfs-0.8.3-1ubuntu12`.
+fn parse_version(line: &str) -> Option<(u32, u32)> {
+    let mut parts = line.strip_prefix("zfs-")?.split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+fn check_zfs() -> Result<String, String> {
+    let output = privilege::zfs()
+        .arg("version")
+        .stdin(Stdio::null())
+        .run_output(Kind::Zfs)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => "zfs is not installed".to_string(),
+            _ => format!("zfs failed -> {:?}", e),
+        })?;
+    // zfs version only exists from 0.8, so its failing is reason enough to fail.
+    if !output.status.success() {
+        return Err(format!(
+            "zfs is older than {}.{}, which is required",
+            MIN_VERSION.0, MIN_VERSION.1
+        ));
+    }
+    let version = String::from_utf8_lossy(&output.stdout);
+    let line = version.lines().next().unwrap_or("").trim().to_string();
+    match parse_version(&line) {
+        Some(v) if v >= MIN_VERSION => Ok(line),
+        Some(_) => Err(format!(
+            "{} is older than {}.{}, which is required",
+            line, MIN_VERSION.0, MIN_VERSION.1
+        )),
+        None => Err(format!("unrecognised zfs version {}", line)),
+    }
+}
+
+/// What `zpool status` says of a pool - its state, and the scrub or resilver running on it.
+#[derive(Debug, PartialEq)]
+struct PoolState {
+    state: String,
+    /// scrubbing or resilvering.
+    scan: Option<&'static str>,
+}
+
+impl PoolState {
+    fn parse(status: &str) -> Self {
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.trim().strip_prefix(name))
+                .map(str::trim)
+        };
+        PoolState {
+            state: field("state:").unwrap_or("UNKNOWN").to_string(),
+            scan: match field("scan:") {
+                Some(scan) if scan.starts_with("scrub in progress") => Some("scrubbing"),
+                Some(scan) if scan.starts_with("resilver in progress") => Some("resilvering"),
+                _ => None,
+            },
+        }
+    }
+
+    /// Why sending from or to the pool now would add to its load, if it would.
+    fn strain(&self) -> Option<String> {
+        match (self.state.as_str(), self.scan) {
+            ("ONLINE", None) => None,
+            ("ONLINE", Some(scan)) => Some(scan.to_string()),
+            (state, Some(scan)) => Some(format!("{}, {}", state, scan)),
+            (state, None) => Some(state.to_string()),
+        }
+    }
+}
+
+fn check_pool(pool: &str, require_healthy: bool) -> Outcome {
+    let state = match run("zpool", &["status", pool]) {
+        Ok(status) => PoolState::parse(&status),
+        Err(e) => return outcome("pool", pool, Err(e)),
+    };
+    match state.strain() {
+        None => outcome("pool", pool, Ok("healthy".to_string())),
+        // Anything worse than DEGRADED, ie FAULTED or SUSPENDED, can't be sent from or to.
+        Some(strain) if state.state != "ONLINE" && state.state != "DEGRADED" => {
+            outcome("pool", pool, Err(strain))
+        }
+        Some(strain) if require_healthy => outcome(
+            "pool",
+            pool,
+            Err(format!("{} - refused with --require-healthy", strain)),
+        ),
+        Some(strain) => Outcome {
+            warning: true,
+            ..outcome(
+                "pool",
+                pool,
+                Ok(format!("{} - running at a lower priority", strain)),
+            )
+        },
+    }
+}
+
+/// Who is running znapper - their uid, name and groups.
+fn identity() -> Result<(String, String, Vec<String>), String> {
+    let uid = run("id", &["-u"])?.trim().to_string();
+    let name = run("id", &["-un"])?.trim().to_string();
+    let groups = run("id", &["-Gn"])?
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    Ok((uid, name, groups))
+}
+
+/// The permissions `zfs allow` output grants to `user`, a member of `groups`, directly or
+/// through everyone. Permissions granted at create time only apply to datasets the user creates,
+/// and permission sets are not expanded.
+pub(crate) fn granted(allow: &str, user: &str, groups: &[String]) -> Vec<String> {
+    let mut perms = Vec::new();
+    let mut create_time = false;
+    for line in allow.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            create_time = line.starts_with("Create time");
+            continue;
+        }
+        if create_time {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let list = match (words.next(), words.next(), words.next()) {
+            (Some("user"), Some(who), Some(list)) if who == user => list,
+            (Some("group"), Some(who), Some(list)) if groups.iter().any(|g| g == who) => list,
+            (Some("everyone"), Some(list), None) => list,
+            _ => continue,
+        };
+        perms.extend(list.split(',').map(str::to_string));
+    }
+    perms
+}
+
+fn check_delegation(dataset: &str, role: Role) -> Result<String, String> {
+    let required = role.permissions();
+    let (uid, user, groups) = identity()?;
+    if uid == "0" {
+        return Ok("running as root".to_string());
+    }
+
+    // A destination may not exist until the first replication, so look at its parents.
+    let mut existing = dataset;
+    while !dataset_exists(existing) {
+        existing = match existing.rsplit_once('/') {
+            Some((parent, _)) => parent,
+            None => return Err(format!("neither {} nor a parent exists", dataset)),
+        };
+    }
+
+    let allow = run("zfs", &["allow", existing])?;
+    let perms = granted(&allow, &user, &groups);
+    let missing: Vec<_> = required
+        .iter()
+        .filter(|perm| !perms.iter().any(|p| p == *perm))
+        .copied()
+        .collect();
+    if missing.is_empty() {
+        Ok(format!(
+            "{} has {} on {}",
+            user,
+            required.join(","),
+            existing
+        ))
+    } else {
+        Err(format!(
+            "{} is missing {} on {} - zfs allow {} {} {}",
+            user,
+            missing.join(","),
+            existing,
+            user,
+            missing.join(","),
+            existing
+        ))
+    }
+}
+
+/// Every destination of a metadata file.
+fn check_metadata(path: &str) -> Result<String, String> {
+    if !Path::new(path).exists() {
+        return Err("it does not exist".to_string());
+    }
+    let file = MetadataFile::load(path).map_err(|_| "unable to parse it".to_string())?;
+    let mut checked = Vec::new();
+    let mut problems = Vec::new();
+    for (identity, meta) in file.entries() {
+        let name = if identity.is_empty() {
+            "unclaimed"
+        } else {
+            identity
+        };
+        let res = if meta.precursor_snap.is_empty() && !meta.datasets.is_empty() {
+            check_dataset_metadata(path, identity, meta)
+        } else {
+            check_destination_metadata(path, identity, meta)
+        };
+        match res {
+            Ok(s) => checked.push(format!("{}: {}", name, s)),
+            Err(e) => problems.push(format!("{}: {}", name, e)),
+        }
+    }
+    if !problems.is_empty() {
+        Err(problems.join("; "))
+    } else if checked.is_empty() {
+        Err("it has no destinations".to_string())
+    } else {
+        Ok(checked.join("; "))
+    }
+}
+
+/// The owner of the anchors of `identity`, which is empty for unclaimed metadata.
+fn owner(path: &str, identity: &str, dataset: Option<&str>) -> Owner {
+    metadata::owner(path, Some(identity).filter(|i| !i.is_empty()), dataset)
+}
+
+fn check_destination_metadata(
+    path: &str,
+    identity: &str,
+    meta: &RemoteMetadata,
+) -> Result<String, String> {
+    let snap = meta.precursor_snap.as_str();
+    if !snap.contains('@') {
+        return Err(format!("{} is not a snapshot", snap));
+    }
+    if !dataset_exists(snap) {
+        return Err(format!("{} does not exist", snap));
+    }
+    let anchors = AnchorStore::load().map_err(|_| "unable to load the anchor store".to_string())?;
+    match anchors.get(&owner(path, identity, None)) {
+        Some(anchor) if anchor != snap => Err(format!(
+            "{} disagrees with the anchor store, which has {}",
+            snap, anchor
+        )),
+        Some(_) => Ok(format!("{}, anchored", snap)),
+        // Metadata made by init_archive is anchored by the first remote_repl.
+        None => Ok(format!("{}, not yet anchored", snap)),
+    }
+}
+
+/// Metadata of remote_repl --per-dataset - every precursor exists, and agrees with its anchor.
+fn check_dataset_metadata(
+    path: &str,
+    identity: &str,
+    meta: &RemoteMetadata,
+) -> Result<String, String> {
+    let anchors = AnchorStore::load().map_err(|_| "unable to load the anchor store".to_string())?;
+    let mut problems = Vec::new();
+    for (dataset, snap) in meta.datasets.iter() {
+        if !dataset_exists(snap) {
+            problems.push(format!("{} does not exist", snap));
+            continue;
+        }
+        match anchors.get(&owner(path, identity, Some(dataset))) {
+            Some(anchor) if anchor != snap => problems.push(format!(
+                "{} disagrees with the anchor store, which has {}",
+                snap, anchor
+            )),
+            _ => {}
+        }
+    }
+    if problems.is_empty() {
+        Ok(format!("{} datasets", meta.datasets.len()))
+    } else {
+        Err(problems.join(", "))
+    }
+}
+
+fn check_ssh(remote: &str, opt: &SshOpt) -> Result<String, String> {
+    // Fail rather than prompt for a password or an unknown host key.
+    let mut opt = opt.clone();
+    opt.options.push("BatchMode=yes".to_string());
+    let (ssh, _) = resolve_remote_ssh(remote, &opt)
+        .map_err(|_| "unable to set up the connection".to_string())?;
+
+    // A key with znapper recv as its forced command answers space, others run true.
+    let output = ssh
+        .command(&["space"])
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh)
+        .map_err(|e| format!("ssh failed -> {:?}", e))?;
+    if output.status.success()
+        && serde_json::from_slice::<crate::recv::Space>(&output.stdout).is_ok()
+    {
+        return Ok(format!("{}, znapper recv", ssh));
+    }
+    let output = ssh
+        .command(&["true"])
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh)
+        .map_err(|e| format!("ssh failed -> {:?}", e))?;
+    if output.status.success() {
+        Ok(ssh.to_string())
+    } else {
+        Err(format!(
+            "{} -> {}",
+            ssh.describe_failure(output.status),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+fn pool_of(dataset: &str) -> &str {
+    dataset.split(['/', '@']).next().unwrap_or(dataset)
+}
+
+fn check_job(job: &Job) -> Vec<Outcome> {
+    let mut outcomes = vec![outcome("zfs", "zfs", check_zfs())];
+
+    let mut pools: Vec<&str> = Vec::new();
+    for dataset in job.sources.iter().chain(job.destinations.iter()) {
+        let pool = pool_of(dataset);
+        if !pools.contains(&pool) {
+            pools.push(pool);
+        }
+    }
+    for pool in pools {
+        outcomes.push(check_pool(pool, job.require_healthy));
+    }
+
+    for source in job.sources.iter() {
+        outcomes.push(outcome(
+            "delegation",
+            source,
+            check_delegation(source, Role::Send),
+        ));
+    }
+    for dest in job.destinations.iter() {
+        outcomes.push(outcome(
+            "delegation",
+            dest,
+            check_delegation(dest, Role::Receive),
+        ));
+    }
+
+    for path in job.metadata.iter() {
+        outcomes.push(outcome("metadata", path, check_metadata(path)));
+    }
+
+    outcomes
+}
+
+/// Check `job` can run before starting it, logging why if not.
+pub(crate) fn preflight(job: &Job) -> Result<(), ()> {
+    let mut ok = true;
+    let mut strained = false;
+    for o in check_job(job) {
+        if o.warning {
+            warn!("pre-flight {} {} -> {}", o.check, o.subject, o.detail);
+            strained = true;
+        } else if o.pass {
+            debug!("pre-flight {} {} -> {}", o.check, o.subject, o.detail);
+        } else {
+            error!(
+                "pre-flight {} {} failed -> {}",
+                o.check, o.subject, o.detail
+            );
+            ok = false;
+        }
+    }
+    if ok {
+        if strained {
+            process::lower_priority();
+        }
+        Ok(())
+    } else {
+        error!("Pre-flight checks failed - fix them, or use --skip-preflight to run anyway");
+        Err(())
+    }
+}
+
+/// Run and report the checks of `opt`, failing if any check failed.
+pub(crate) fn do_check(opt: &CheckOpt) -> Result<(), ()> {
+    debug!("do_check");
+
+    let job = Job {
+        sources: opt.sources.clone(),
+        destinations: opt.destinations.clone(),
+        metadata: opt.metadata.clone(),
+        require_healthy: opt.require_healthy,
+    };
+    let mut checks = check_job(&job);
+    // With nothing to check against, check every imported pool.
+    if opt.sources.is_empty() && opt.destinations.is_empty() {
+        let pools = run("zpool", &["list", "-H", "-o", "name"]).unwrap_or_default();
+        for pool in pools.lines().filter(|l| !l.is_empty()) {
+            checks.push(check_pool(pool, opt.require_healthy));
+        }
+    }
+    for remote in opt.remotes.iter() {
+        checks.push(outcome("ssh", remote, check_ssh(remote, &opt.ssh)));
+    }
+
+    let passed = checks.iter().filter(|o| o.pass).count();
+    let summary = Summary {
+        passed,
+        failed: checks.len() - passed,
+        warnings: checks.iter().filter(|o| o.warning).count(),
+        checks,
+    };
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&summary) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise checks -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for o in summary.checks.iter() {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    match (o.pass, o.warning) {
+                        (true, false) => "PASS",
+                        (true, true) => "WARN",
+                        (false, _) => "FAIL",
+                    },
+                    o.check,
+                    o.subject,
+                    o.detail
+                );
+            }
+            if summary.warnings > 0 {
+                println!(
+                    "{} passed, {} with warnings, {} failed",
+                    summary.passed, summary.warnings, summary.failed
+                );
+            } else {
+                println!("{} passed, {} failed", summary.passed, summary.failed);
+            }
+        }
+    }
+    if summary.failed > 0 {
+        error!(
+            "{} of {} checks failed",
+            summary.failed,
+            summary.checks.len()
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_states_are_parsed() {
+        let status = "  pool: tank\n state: DEGRADED\nstatus: One or more devices is being \
+                      resilvered.\n  scan: resilver in progress since Mon Oct 12 10:00:00 2026\n";
+        let state = PoolState::parse(status);
+        assert_eq!(state.state, "DEGRADED");
+        assert_eq!(state.strain().as_deref(), Some("DEGRADED, resilvering"));
+        let status = "  pool: nvme\n state: ONLINE\n  scan: scrub repaired 0B in 00:10:00 with \
+                      0 errors on Sun Oct 11 00:34:01 2026\n";
+        assert_eq!(PoolState::parse(status).strain(), None);
+        let status = "  pool: nvme\n state: ONLINE\n  scan: scrub in progress since Sun Oct 11\n";
+        assert_eq!(
+            PoolState::parse(status).strain().as_deref(),
+            Some("scrubbing")
+        );
+    }
+}
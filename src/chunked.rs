@@ -0,0 +1,557 @@
+//! Chunked transfers, with `remote_repl --chunked <size>`, for links that drop too often for a
+//! stream to get through in one go.
+//!
+//! The stream is spooled locally, under `spool/` in the state directory, into chunks of the given
+//! size, each with its adler32. The receiver's znapper recv is then asked which chunks of the
+//! spool it holds (`chunks <id>`), sent each chunk it is missing on its own (`chunk <id> <n>
+//! <size> <adler32>`), which it checks and keeps under its own state directory, and finally asked
+//! to feed them, in order, into zfs recv (`assemble <id> <count>`). A drop only loses the chunk in
+//! flight - the next attempt (with `--retries`, or the next run that sends the same stream)
+//! carries on from the chunks the receiver already holds. zfs recv still checks the stream
+//! itself as it is received.
+//!
+//! The spool takes as much space as the stream, on both sides, until it is received. With
+//! `--transport-compress` the spool is compressed. The receiver reads no more of a chunk than the
+//! size it is sent with, and refuses chunks once the spools of the pool hold `--spool-limit`.
+
+use crate::anchors::state_dir;
+use crate::buffer::Adler32;
+use crate::compress::{self, Compression};
+use crate::process::{self, Kind, Timed};
+use crate::recv::{self, RecvResult};
+use crate::ssh::Ssh;
+use crate::{log_send_exit, privilege, progress, recv_confirmed, ReplFailure};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::thread::{self, JoinHandle};
+use tracing::{debug, error, info, warn};
+
+/// What is read from the stream, or a chunk, at a time.
+const READ: usize = 1024 * 1024;
+
+/// A chunk of a spool - its size, and the adler32 of its bytes, in hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Chunk {
+    pub index: usize,
+    pub size: u64,
+    pub adler32: String,
+}
+
+/// The chunks of a spool that the receiver holds.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ChunkList {
+    pub chunks: Vec<Chunk>,
+}
+
+/// The spool of one stream on the sender.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    send_args: Vec<String>,
+    compression: Option<String>,
+    chunks: Vec<Chunk>,
+}
+
+/// Is `id` safe to use as a directory name?
+pub(crate) fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-".contains(c))
+}
+
+/// `name`, with anything but letters and digits made an underscore.
+fn sanitise(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// The spool of the stream `send_args`, sent as `label` - the same stream always has the same
+/// spool, so that a later attempt finds it.
+fn spool_id(label: &str, send_args: &[&str]) -> String {
+    let mut adler = Adler32::new();
+    adler.update(send_args.join(" ").as_bytes());
+    format!("{}-{:08x}", sanitise(label), adler.value())
+}
+
+/// Where the sender keeps its spools.
+fn outgoing() -> PathBuf {
+    state_dir().join("spool").join("out")
+}
+
+/// Where the receiver keeps the chunks sent to `pool`.
+fn incoming(pool: &str) -> PathBuf {
+    state_dir().join("spool").join("in").join(sanitise(pool))
+}
+
+/// Copy at most `limit` bytes of `from` into a new file at `path`, returning the size and the
+/// adler32 of what was copied.
+fn copy_chunk(from: &mut impl Read, path: &Path, limit: u64) -> io::Result<(u64, u32)> {
+    let mut file = File::create(path)?;
+    let mut adler = Adler32::new();
+    let mut buf = vec![0; READ];
+    let mut size = 0;
+    let mut from = from.take(limit);
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        file.write_all(&buf[..n])?;
+        adler.update(&buf[..n]);
+        size += n as u64;
+    }
+    file.sync_all()?;
+    Ok((size, adler.value()))
+}
+
+/// Spool the stream of `send_args` into chunks of `chunk_size` in `dir`, unless it already is.
+fn spool(
+    dir: &Path,
+    send_args: &[&str],
+    label: &str,
+    chunk_size: u64,
+    compression: Option<Compression>,
+) -> Result<Manifest, ()> {
+    let manifest_path = dir.join("manifest.json");
+    let compression_name = compression.map(|c| format!("{:?}", c));
+    let spooled: Option<Manifest> = File::open(&manifest_path)
+        .ok()
+        .and_then(|f| serde_json::from_reader(f).ok());
+    if let Some(manifest) = spooled {
+        if manifest.send_args == send_args && manifest.compression == compression_name {
+            info!(
+                "{} is spooled already, in {} chunks",
+                label,
+                manifest.chunks.len()
+            );
+            return Ok(manifest);
+        }
+    }
+    if dir.exists() {
+        fs::remove_dir_all(dir).map_err(|e| {
+            error!("Unable to remove the old spool {:?} -> {:?}", dir, e);
+        })?;
+    }
+    fs::create_dir_all(dir).map_err(|e| {
+        error!("Unable to create the spool {:?} -> {:?}", dir, e);
+    })?;
+
+    debug!(
+        "running -> zfs send -v -P {} > {:?}",
+        send_args.join(" "),
+        dir
+    );
+    let mut send = privilege::zfs()
+        .arg("send")
+        .arg("-v")
+        .arg("-P")
+        .args(send_args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .run_spawn()
+        .map_err(|e| {
+            error!("send failed -> {:?}", e);
+        })?;
+    let send_guard = process::register(&send, "zfs", Kind::Transfer);
+    let watch = send
+        .stderr
+        .take()
+        .map(|stderr| progress::watch(label, stderr, None));
+    let stdout = match send.stdout.take() {
+        Some(s) => s,
+        None => {
+            error!("Failed to connect to stdout of zfs send process");
+            let _ = send.kill();
+            let _ = send.wait();
+            return Err(());
+        }
+    };
+    let (mut stream, mut compressor): (Box<dyn Read>, Option<Child>) = match compression {
+        Some(compression) => match compress::compress(compression, Stdio::from(stdout)) {
+            Ok((child, out)) => (Box::new(out), Some(child)),
+            Err(_) => {
+                let _ = send.kill();
+                let _ = send.wait();
+                return Err(());
+            }
+        },
+        None => (Box::new(stdout), None),
+    };
+
+    let mut chunks = Vec::new();
+    let spooled = loop {
+        let index = chunks.len();
+        let path = dir.join(index.to_string());
+        match copy_chunk(&mut stream, &path, chunk_size) {
+            // The stream ended on the last chunk.
+            Ok((0, _)) if index > 0 => {
+                let _ = fs::remove_file(&path);
+                break Ok(());
+            }
+            Ok((size, adler32)) => {
+                debug!("spooled chunk {} of {} bytes", index, size);
+                chunks.push(Chunk {
+                    index,
+                    size,
+                    adler32: format!("{:08x}", adler32),
+                });
+                if size < chunk_size {
+                    break Ok(());
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    drop(stream);
+
+    let send_status = process::wait(&mut send, &send_guard);
+    let send_ok = matches!(&send_status, Ok(status) if status.success());
+    let compress_ok = match compressor.as_mut().map(|child| child.wait()) {
+        None => true,
+        Some(Ok(status)) => status.success(),
+        Some(Err(_)) => false,
+    };
+    if let Err(e) = spooled.as_ref() {
+        error!("Unable to spool {} into {:?} -> {:?}", label, dir, e);
+    }
+    let ok = spooled.is_ok() && send_ok && compress_ok;
+    let stderr = watch.map(|watch| watch.finish(ok)).unwrap_or_default();
+    log_send_exit(label, &send_status, &stderr);
+    if !ok {
+        let _ = fs::remove_dir_all(dir);
+        return Err(());
+    }
+
+    let manifest = Manifest {
+        send_args: send_args.iter().map(|a| a.to_string()).collect(),
+        compression: compression_name,
+        chunks,
+    };
+    let written = File::create(&manifest_path)
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|f| serde_json::to_writer_pretty(f, &manifest).map_err(|e| format!("{:?}", e)));
+    if let Err(e) = written {
+        error!("Unable to write {:?} -> {}", manifest_path, e);
+        return Err(());
+    }
+    info!("spooled {} in {} chunks", label, manifest.chunks.len());
+    Ok(manifest)
+}
+
+/// Ask the receiver something, returning its reply.
+fn ask<T: serde::de::DeserializeOwned>(
+    ssh: &Ssh,
+    remote: &[&str],
+    stdin: Stdio,
+) -> Result<T, ReplFailure> {
+    debug!("running -> ssh {} {}", ssh, remote.join(" "));
+    let output = ssh
+        .command(remote)
+        .stdin(stdin)
+        .stderr(Stdio::piped())
+        .run_output(Kind::Transfer)
+        .map_err(|e| {
+            error!("ssh failed -> {:?}", e);
+            ReplFailure::Retry
+        })?;
+    match recv::parse_result::<T>(&String::from_utf8_lossy(&output.stdout)) {
+        Some(reply) => Ok(reply),
+        None if output.status.code() == Some(255) => {
+            error!(
+                "{} {} -> {}",
+                remote.join(" "),
+                ssh.describe_failure(output.status),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            Err(ReplFailure::Retry)
+        }
+        None => {
+            error!(
+                "{} did not answer {} - chunked transfers need the receiver to run znapper recv",
+                ssh,
+                remote.join(" ")
+            );
+            Err(ReplFailure::Fatal)
+        }
+    }
+}
+
+/// Send the stream of `send_args` to the znapper recv of `ssh` in chunks of `chunk_size`, into
+/// the dataset of `recv` (nothing, or `recv <dataset>`). With `expect`, the receiver must report
+/// that it received that snapshot.
+pub(crate) fn transfer(
+    ssh: &Ssh,
+    recv: &[&str],
+    send_args: &[&str],
+    expect: Option<(&str, Option<&str>)>,
+    label: &str,
+    chunk_size: u64,
+) -> Result<(), ReplFailure> {
+    let dataset = match recv {
+        [] => None,
+        ["recv", dataset] => Some(*dataset),
+        _ => {
+            error!("chunked transfers need the receiver to run znapper recv");
+            return Err(ReplFailure::Fatal);
+        }
+    };
+    let id = spool_id(label, send_args);
+    let dir = outgoing().join(&id);
+    let manifest = spool(&dir, send_args, label, chunk_size, ssh.compression())
+        .map_err(|_| ReplFailure::Fatal)?;
+
+    let held: ChunkList = ask(ssh, &["chunks", &id], Stdio::null())?;
+    let missing: Vec<&Chunk> = manifest
+        .chunks
+        .iter()
+        .filter(|chunk| !held.chunks.contains(chunk))
+        .collect();
+    info!(
+        "{} holds {} of the {} chunks of {}",
+        ssh,
+        manifest.chunks.len() - missing.len(),
+        manifest.chunks.len(),
+        label
+    );
+    for chunk in missing {
+        let index = chunk.index.to_string();
+        let file = File::open(dir.join(&index)).map_err(|e| {
+            error!("Unable to open chunk {} of {} -> {:?}", index, label, e);
+            ReplFailure::Fatal
+        })?;
+        let size = chunk.size.to_string();
+        let reply: RecvResult = ask(
+            ssh,
+            &["chunk", &id, &index, &size, &chunk.adler32],
+            Stdio::from(file),
+        )?;
+        if !reply.success {
+            for e in reply.errors.iter() {
+                error!("remote chunk {} -> {}", index, e);
+            }
+            return Err(ReplFailure::Retry);
+        }
+        debug!("sent chunk {} of {}", index, manifest.chunks.len());
+    }
+
+    let count = manifest.chunks.len().to_string();
+    let mut assemble = vec!["assemble", id.as_str(), count.as_str()];
+    assemble.extend(dataset);
+    let result: RecvResult = ask(ssh, &assemble, Stdio::null())?;
+    if !recv_confirmed(&result, expect) {
+        return Err(ReplFailure::Retry);
+    }
+    // The stream is received - this spool, and any older one of the same label, is done with.
+    let stale = format!("{}-", sanitise(label));
+    if let Ok(entries) = fs::read_dir(outgoing()) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            if entry.file_name().to_string_lossy().starts_with(&stale) {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The chunks of the spool `id` that the receiver holds for `pool`.
+pub(crate) fn held(pool: &str, id: &str) -> ChunkList {
+    let dir = incoming(pool).join(id);
+    let mut chunks: Vec<Chunk> = fs::read_dir(&dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    // Kept chunks are named <index>-<adler32>.
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    let (index, adler32) = name.split_once('-')?;
+                    Some(Chunk {
+                        index: index.parse().ok()?,
+                        size: entry.metadata().ok()?.len(),
+                        adler32: adler32.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    chunks.sort_by_key(|c| c.index);
+    ChunkList { chunks }
+}
+
+/// The bytes held in the spools under `spools`.
+fn spooled(spools: &Path) -> u64 {
+    let sizes = |dir: &Path| -> Vec<(PathBuf, fs::Metadata)> {
+        fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter_map(|entry| Some((entry.path(), entry.metadata().ok()?)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    sizes(spools)
+        .into_iter()
+        .filter(|(_, meta)| meta.is_dir())
+        .flat_map(|(dir, _)| sizes(&dir))
+        .map(|(_, meta)| meta.len())
+        .sum()
+}
+
+/// Keep chunk `index` of the spool `id` for `pool`, read from `from`, if it is `size` bytes with
+/// `adler32` and the spools of `pool` have room for it under `limit`.
+pub(crate) fn store(
+    pool: &str,
+    id: &str,
+    (index, size, adler32): (usize, u64, &str),
+    limit: u64,
+    from: impl Read,
+) -> RecvResult {
+    keep(&incoming(pool), id, (index, size, adler32), limit, from)
+}
+
+/// store, into the spools under `spools`.
+fn keep(
+    spools: &Path,
+    id: &str,
+    (index, size, adler32): (usize, u64, &str),
+    limit: u64,
+    mut from: impl Read,
+) -> RecvResult {
+    let mut result = RecvResult::default();
+    let used = spooled(spools);
+    if used.saturating_add(size) > limit {
+        result.errors.push(format!(
+            "the spools hold {} bytes, and chunk {} of {} bytes would take them over the \
+             --spool-limit of {}",
+            used, index, size, limit
+        ));
+        return result;
+    }
+    let dir = spools.join(id);
+    let part = dir.join(format!("{}.part", index));
+    let stored = fs::create_dir_all(&dir)
+        .and_then(|_| copy_chunk(&mut from, &part, size))
+        .map_err(|e| format!("Unable to store chunk {} -> {:?}", index, e));
+    match stored {
+        Ok((copied, sum)) if copied == size && format!("{:08x}", sum) == adler32 => {
+            let kept = dir.join(format!("{}-{}", index, adler32));
+            match fs::rename(&part, &kept) {
+                Ok(()) => result.success = true,
+                Err(e) => result
+                    .errors
+                    .push(format!("Unable to keep chunk {} -> {:?}", index, e)),
+            }
+        }
+        Ok((copied, sum)) => {
+            let _ = fs::remove_file(&part);
+            result.errors.push(format!(
+                "chunk {} of {} bytes has adler32 {:08x}, not {} bytes with {}",
+                index, copied, sum, size, adler32
+            ));
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&part);
+            result.errors.push(e);
+        }
+    }
+    result
+}
+
+/// The `count` chunks of the spool `id` for `pool`, in order, as a stream for zfs recv, and the
+/// thread writing it.
+pub(crate) fn reassemble(
+    pool: &str,
+    id: &str,
+    count: usize,
+) -> Result<(Stdio, JoinHandle<io::Result<()>>), String> {
+    let held = held(pool, id);
+    let paths: Vec<PathBuf> = (0..count)
+        .map(|index| {
+            held.chunks
+                .iter()
+                .find(|c| c.index == index)
+                .map(|c| {
+                    incoming(pool)
+                        .join(id)
+                        .join(format!("{}-{}", index, c.adler32))
+                })
+                .ok_or_else(|| format!("chunk {} of {} has not been sent", index, id))
+        })
+        .collect::<Result<_, _>>()?;
+    let (reader, mut writer) = io::pipe().map_err(|e| format!("{:?}", e))?;
+    let feed = thread::spawn(move || {
+        for path in paths {
+            io::copy(&mut File::open(path)?, &mut writer)?;
+        }
+        writer.flush()
+    });
+    Ok((Stdio::from(reader), feed))
+}
+
+/// Remove the spool `id` held for `pool`, once it is received.
+pub(crate) fn discard(pool: &str, id: &str) {
+    let dir = incoming(pool).join(id);
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        warn!("Unable to remove the spool {:?} -> {:?}", dir, e);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_are_spooled_into_checksummed_chunks() {
+        let dir = std::env::temp_dir().join(format!("znapper-chunked-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let stream: Vec<u8> = (0..2500u32).map(|i| (i % 253) as u8).collect();
+        let mut from = stream.as_slice();
+        let mut sizes = Vec::new();
+        for index in 0..4 {
+            sizes.push(copy_chunk(&mut from, &dir.join(index.to_string()), 1000).unwrap());
+        }
+        assert_eq!(
+            sizes.iter().map(|(size, _)| *size).collect::<Vec<_>>(),
+            vec![1000, 1000, 500, 0]
+        );
+        let mut adler = Adler32::new();
+        adler.update(&stream[1000..2000]);
+        assert_eq!(sizes[1].1, adler.value());
+        assert_eq!(fs::read(dir.join("2")).unwrap(), stream[2000..]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let id = spool_id("remote send to backup1", &["-w", "-I", "nvme@a", "nvme@b"]);
+        assert!(id.starts_with("remote_send_to_backup1-") && valid_id(&id));
+        assert_ne!(id, spool_id("remote send to backup1", &["-w", "nvme@b"]));
+        assert!(!valid_id("../etc") && !valid_id(""));
+    }
+
+    #[test]
+    fn chunks_are_kept_only_as_sent_and_within_the_limit() {
+        let spools = std::env::temp_dir().join(format!("znapper-spools-{}", std::process::id()));
+        let chunk: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let mut adler = Adler32::new();
+        adler.update(&chunk);
+        let sum = format!("{:08x}", adler.value());
+
+        // Only the size it was sent with is read, whatever follows.
+        let mut longer = chunk.clone();
+        longer.extend([0; 500]);
+        assert!(keep(&spools, "a-1", (0, 1000, &sum), 2500, longer.as_slice()).success);
+        assert_eq!(spooled(&spools), 1000);
+        assert!(!keep(&spools, "a-1", (1, 1000, &sum), 2500, &chunk[..900]).success);
+        assert!(keep(&spools, "b-2", (0, 1000, &sum), 2500, chunk.as_slice()).success);
+        // The limit is of every spool of the pool.
+        assert!(!keep(&spools, "c-3", (0, 1000, &sum), 2500, chunk.as_slice()).success);
+        assert_eq!(spooled(&spools), 2000);
+        fs::remove_dir_all(&spools).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+//! Shell completions and the man page, generated from the command line definition so they can't
+//! fall behind it.
+
+use crate::Cli;
+use std::io::{self, Write};
+use structopt::clap::{App, Shell};
+use structopt::StructOpt;
+use tracing::{debug, error};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct CompletionsOpt {
+    /// bash, zsh or fish (or powershell or elvish)
+    shell: Shell,
+}
+
+pub(crate) fn do_completions(opt: &CompletionsOpt) {
+    debug!("do_completions");
+    Cli::clap().gen_completions_to("znapper", opt.shell, &mut io::stdout());
+}
+
+/// Escape `text` so roff shows it as is - backslashes, and dots or quotes that start a line.
+fn roff(text: &str) -> String {
+    text.replace('\\', "\\e")
+        .lines()
+        .map(|line| {
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The full help of `app`, as `znapper <name> --help` prints it.
+fn long_help(app: &App, bin_name: &str) -> Result<String, ()> {
+    let mut help = Vec::new();
+    app.clone()
+        .bin_name(bin_name)
+        .set_term_width(80)
+        .write_long_help(&mut help)
+        .map_err(|e| error!("unable to render the help of {} -> {:?}", bin_name, e))?;
+    Ok(String::from_utf8_lossy(&help).into_owned())
+}
+
+/// A section for each subcommand of `app`, and each of theirs.
+fn commands(app: &App, bin_name: &str, page: &mut String) -> Result<(), ()> {
+    // clap 2 has no accessor for the subcommands, but the field is public.
+    for sub in app.p.subcommands.iter() {
+        let name = format!("{} {}", bin_name, sub.get_name());
+        page.push_str(&format!(".SS {}\n.nf\n", roff(&name)));
+        page.push_str(&roff(long_help(sub, &name)?.trim_end()));
+        page.push_str("\n.fi\n");
+        commands(sub, &name, page)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn do_manpage() {
+    debug!("do_manpage");
+    let app = Cli::clap();
+    let mut page = format!(
+        ".TH ZNAPPER 8 \"\" \"znapper {}\" \"System Administration\"\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    page.push_str(
+        ".SH NAME\nznapper \\- znap your snaps, ZFS snapshot and replication management\n",
+    );
+    page.push_str(".SH SYNOPSIS\n\\fBznapper\\fR [\\fIOPTIONS\\fR] \\fICOMMAND\\fR\n");
+    page.push_str(".SH DESCRIPTION\n");
+    page.push_str(
+        "znapper takes and prunes auto snapshots of ZFS pools, and replicates them to other pools, \
+         remote hosts and archives. The options below come before the command, and each command \
+         takes its own.\n",
+    );
+    page.push_str(".SH OPTIONS\n.nf\n");
+    let help = match long_help(&app, "znapper") {
+        Ok(help) => help,
+        Err(_) => return,
+    };
+    page.push_str(&roff(help.trim_end()));
+    page.push_str("\n.fi\n.SH COMMANDS\n");
+    if commands(&app, "znapper", &mut page).is_err() {
+        return;
+    }
+    page.push_str(
+        ".SH FILES\n.TP\n/etc/znapper/znapper.toml\nconfiguration, and /etc/znapper/targets.toml \
+         the registered remotes \\- see ZNAPPER_CONFIG_DIR\n.TP\n/var/lib/znapper\nthe anchors, \
+         history and other state \\- see ZNAPPER_STATE_DIR\n.TP\n/run/znapper\nthe pool locks \
+         \\- see ZNAPPER_LOCK_DIR\n",
+    );
+
+    if let Err(e) = io::stdout().write_all(page.as_bytes()) {
+        error!("unable to write the man page -> {:?}", e);
+    }
+}
@@ -0,0 +1,127 @@
+//! Compressing the send stream over ssh, with `remote_repl --transport-compress zstd[:level]`.
+//!
+//! zfs streams compress far better, and far faster, with zstd than with the zlib of ssh -C, which
+//! matters on a slow link. The stream is piped through `zstd` before ssh, and znapper recv on the
+//! receiver is asked to receive with `zstd` before its command, piping it through `zstd -d` into
+//! zfs recv. Both sides need the zstd tool installed. Streams that are already compressed (-c or
+//! raw encrypted) gain little.
+
+use crate::priority;
+use serde::Deserialize;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::str::FromStr;
+use tracing::{debug, error};
+
+/// The verb that asks znapper recv to decompress what it receives.
+pub(crate) const ZSTD: &str = "zstd";
+
+/// The level zstd compresses at unless told otherwise - fast, and most of the gain.
+const DEFAULT_LEVEL: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum Compression {
+    Zstd(u32),
+}
+
+impl TryFrom<String> for Compression {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, level) = match s.split_once(':') {
+            Some((algorithm, level)) => (algorithm, Some(level)),
+            None => (s, None),
+        };
+        if algorithm != ZSTD {
+            return Err(format!(
+                "unknown compression {} - use zstd[:level]",
+                algorithm
+            ));
+        }
+        let level = match level {
+            Some(level) => level
+                .parse()
+                .ok()
+                .filter(|l| (1..=19).contains(l))
+                .ok_or_else(|| format!("invalid zstd level {} - use 1 to 19", level))?,
+            None => DEFAULT_LEVEL,
+        };
+        Ok(Compression::Zstd(level))
+    }
+}
+
+/// zstd reading from `stream`, with the compressed stream on its stdout.
+pub(crate) fn compress(
+    compression: Compression,
+    stream: Stdio,
+) -> Result<(Child, ChildStdout), ()> {
+    let Compression::Zstd(level) = compression;
+    debug!("compressing the stream with zstd -{}", level);
+    let mut zstd = Command::new("zstd");
+    priority::apply(&mut zstd);
+    let mut child = zstd
+        .arg("-q")
+        .arg("-c")
+        .arg(format!("-{}", level))
+        .arg("-T0")
+        .stdin(stream)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            error!("zstd failed -> {:?}", e);
+        })?;
+    match child.stdout.take() {
+        Some(out) => Ok((child, out)),
+        None => {
+            error!("Failed to connect to stdout of zstd process");
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(())
+        }
+    }
+}
+
+/// zstd -d reading `stream`, with the decompressed stream on its stdout - for znapper recv.
+pub(crate) fn decompress(stream: Stdio) -> Result<(Child, ChildStdout), String> {
+    let mut zstd = Command::new("zstd");
+    priority::apply(&mut zstd);
+    let mut child = zstd
+        .arg("-q")
+        .arg("-d")
+        .arg("-c")
+        .stdin(stream)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("zstd -d failed -> {:?}", e))?;
+    match child.stdout.take() {
+        Some(out) => Ok((child, out)),
+        None => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err("Failed to connect to stdout of zstd process".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport_compression_is_zstd_at_a_level() {
+        assert_eq!("zstd".parse(), Ok(Compression::Zstd(3)));
+        assert_eq!("zstd:9".parse(), Ok(Compression::Zstd(9)));
+        assert!("zstd:0".parse::<Compression>().is_err());
+        assert!("zstd:fast".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+}
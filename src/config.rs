@@ -0,0 +1,401 @@
+//! The hand written configuration in `znapper.toml`.
+
+use crate::buffer::RateLimit;
+use crate::compress::Compression;
+use crate::priority::IoClass;
+use crate::stream::SendFlag;
+use crate::transport::{TlsOpt, Transport};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use tracing::error;
+
+/// Default directory for znapper's configuration, overridable with `ZNAPPER_CONFIG_DIR`.
+pub(crate) const DEFAULT_CONFIG_DIR: &str = "/etc/znapper";
+
+pub(crate) fn config_dir() -> PathBuf {
+    std::env::var_os("ZNAPPER_CONFIG_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_DIR))
+}
+
+/// A set of datasets that have to be restored together to be consistent, such as an
+/// application's data and its log.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Group {
+    pub datasets: Vec<String>,
+}
+
+/// Snapshots of the dataset (and its descendants) can not be destroyed or rolled back by znapper
+/// until they are this many days old.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Immutable {
+    pub days: u32,
+}
+
+/// Where load-keys finds the key of a replicated encryption root (and those below it), when it
+/// is not where the keylocation of the source says.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Key {
+    /// ie file:///root/keys/nvme.key, or prompt.
+    pub keylocation: String,
+}
+
+/// Which destructive operations have to be approved with `znapper approve` before they run.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Approval {
+    /// Stage full resyncs, forced rollbacks and mass destroys for approval.
+    #[serde(default)]
+    pub required: bool,
+    /// The approver must be a different user to the one that staged the plan.
+    #[serde(default)]
+    pub distinct_approver: bool,
+    /// Destroying more than this many snapshots in one cleanup is a mass destroy.
+    #[serde(default)]
+    pub mass_destroy: Option<usize>,
+    /// How long an approval stands before it lapses, ie "12h". Defaults to a day.
+    #[serde(default)]
+    pub expiry: Option<String>,
+    /// Refuse to destroy or roll back without --yes when there is no terminal to ask.
+    #[serde(default)]
+    pub require_confirmation: bool,
+}
+
+/// Where to export Prometheus metrics.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Metrics {
+    /// Rewrite this file for the node_exporter textfile collector after each run, ie
+    /// /var/lib/prometheus/node-exporter/znapper.prom
+    #[serde(default)]
+    pub textfile: Option<String>,
+}
+
+/// Where to send an email when a run fails.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Email {
+    /// The SMTP server, ie smtps://mail.example.com or smtp://localhost:25
+    pub server: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// How new auto_ and repl_ snapshots are named.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Naming {
+    /// The time in the name, as a strftime template. Defaults to %Y-%m-%dT%H%M%SZ, or in local
+    /// time to the %Y_%m_%d_%H_%M_%S of older znappers.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Name by the time in UTC rather than local time. Defaults to true.
+    #[serde(default)]
+    pub utc: Option<bool>,
+}
+
+/// How long the commands znapper runs may take before they are killed, ie "10m" or "2h". A
+/// command of a kind without a timeout may take as long as it likes.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Timeouts {
+    /// zfs commands, other than the send and receive of a stream.
+    #[serde(default)]
+    pub zfs: Option<String>,
+    /// ssh commands to a remote, other than a stream sent over it.
+    #[serde(default)]
+    pub ssh: Option<String>,
+    /// The send and receive of a stream - the initial seed of a large pool can take days.
+    #[serde(default)]
+    pub transfer: Option<String>,
+}
+
+/// The priority the send and receive of a stream run at, so that a nightly replication leaves the
+/// host usable - a niceness from -20 to 19, an io class of `idle` or `best-effort[:0-7]`, and a
+/// systemd slice (Linux only) to run in, ie "backup.slice".
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Priority {
+    #[serde(default)]
+    pub nice: Option<i32>,
+    #[serde(default)]
+    pub io: Option<IoClass>,
+    #[serde(default)]
+    pub slice: Option<String>,
+}
+
+/// A remote that a sync job replicates to with remote_repl.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JobRemote {
+    /// user@host, or the name of a target in targets.toml.
+    pub target: String,
+    /// The remote_repl metadata that tracks this remote.
+    pub metadata: String,
+    /// Retry a failed transfer this many times.
+    #[serde(default)]
+    pub retries: u32,
+    /// Send each dataset of the source on its own, as remote_repl --per-dataset.
+    #[serde(default)]
+    pub per_dataset: bool,
+    /// With per_dataset, do not replicate these datasets or their descendants.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Name this host to the receiver, as remote_repl --per-host.
+    #[serde(default)]
+    pub per_host: bool,
+    /// Hold the send to a rate, or a schedule of them, ie "5M@08:00-20:00", as remote_repl
+    /// --rate-limit.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Compress the stream over ssh, ie "zstd:6", as remote_repl --transport-compress.
+    #[serde(default)]
+    pub transport_compress: Option<Compression>,
+    /// Connect to a serve-recv at "tcp://host:port" or "tls://host:port", as remote_repl
+    /// --transport.
+    #[serde(default)]
+    pub transport: Option<Transport>,
+    /// The cert, key and ca of a tls transport, as `tls = { cert = "..", key = "..", ca = ".." }`.
+    #[serde(default)]
+    pub tls: TlsOpt,
+}
+
+/// Who to tell when a sync job finishes.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Notify {
+    /// A healthchecks.io (or compatible) check URL, pinged at the start, and on success or
+    /// failure.
+    #[serde(default)]
+    pub healthcheck: Option<String>,
+    /// POST a json summary of the run here.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// POST a one line summary here, as a Slack incoming webhook message.
+    #[serde(default)]
+    pub slack: Option<String>,
+    /// Only notify the webhook and Slack when the run fails.
+    #[serde(default)]
+    pub only_failures: bool,
+}
+
+/// A snapshot, replicate and prune cycle, run with `znapper sync <job>`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Job {
+    /// The dataset to snapshot (with its descendants) and replicate.
+    pub source: String,
+    /// Jobs that sync first, in the same run - this one is skipped unless they all synced.
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// Replicate the source alone, not its descendants, as repl --single-dataset does.
+    #[serde(default)]
+    pub single_dataset: bool,
+    /// Local destinations to repl to, which may be templates.
+    #[serde(default)]
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub remote: Vec<JobRemote>,
+    /// Create this many snapshots of the source at once, as snapshot --jobs does.
+    #[serde(default)]
+    pub snapshot_jobs: usize,
+    /// Keep this many hours of auto snapshots on the source.
+    #[serde(default)]
+    pub keep_hours: Option<u32>,
+    /// Also destroy the empty auto snapshots of the source within keep_hours, as
+    /// snapshot_cleanup --empty does.
+    #[serde(default)]
+    pub cleanup_empty: bool,
+    /// Keep this many hours of auto snapshots on the local destinations.
+    #[serde(default)]
+    pub dest_keep_hours: Option<u32>,
+    /// Also keep the newest auto snapshot of this many days on the local destinations.
+    #[serde(default)]
+    pub dest_keep_daily: Option<u32>,
+    /// Anchor local repl from bookmarks rather than snapshots.
+    #[serde(default)]
+    pub bookmarks: bool,
+    /// Keep this many previous anchors of local repl, as repl --keep-anchors does.
+    #[serde(default)]
+    pub keep_anchors: usize,
+    /// Send raw streams, the default. False sends plain streams, as repl --no-raw does.
+    #[serde(default)]
+    pub raw: Option<bool>,
+    /// Send the properties of the source, the default. False is as repl --no-props.
+    #[serde(default)]
+    pub props: Option<bool>,
+    /// The flags of zfs send besides -w, in place of large-block and props, as repl --send-flags.
+    #[serde(default)]
+    pub send_flags: Option<Vec<SendFlag>>,
+    /// Properties to set on the received datasets of the local destinations, as repl --recv-set.
+    #[serde(default)]
+    pub recv_set: BTreeMap<String, String>,
+    /// Properties the received datasets inherit from the destination, as repl --recv-inherit.
+    #[serde(default)]
+    pub recv_inherit: Vec<String>,
+    /// Properties kept as the source has them, as repl --recv-keep.
+    #[serde(default)]
+    pub recv_keep: Vec<String>,
+    /// Receive the local destinations as warm standbys, as repl --standby.
+    #[serde(default)]
+    pub standby: bool,
+    /// Refuse to send from or to a pool that is DEGRADED, resilvering or scrubbing, as repl
+    /// --require-healthy does.
+    #[serde(default)]
+    pub require_healthy: bool,
+    #[serde(default)]
+    pub usb: Option<Usb>,
+    #[serde(default)]
+    pub notify: Notify,
+    /// Run the job this often under znapper serve, as 30m or 6h.
+    #[serde(default)]
+    pub every: Option<String>,
+    /// What znapper serve does when the job is due while a run of it is still going.
+    #[serde(default)]
+    pub overlap: Overlap,
+}
+
+/// What to do with a run of a job that is due while the last is still going.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Overlap {
+    /// Leave the run going, and don't run again until the next time the job is due.
+    #[default]
+    Skip,
+    /// Run again once the run ends - however often the job is due meanwhile, only once.
+    Queue,
+    /// Stop the run, and start again once it has stopped.
+    Restart,
+}
+
+/// The rotated removable disks a job backs up to with usb-backup.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Usb {
+    /// The guids of the pools of the disks (zpool get guid), any of which may be plugged in.
+    pub guids: Vec<String>,
+}
+
+/// A libvirt guest, frozen while the datasets of its disks are snapshotted.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Vm {
+    /// The datasets holding the disks of the guest - its zvols, or the filesystems of its images.
+    pub datasets: Vec<String>,
+    /// The socket of the qemu-guest-agent of the guest, to freeze with directly rather than with
+    /// virsh - for guests that aren't run by libvirt.
+    #[serde(default)]
+    pub agent_socket: Option<PathBuf>,
+}
+
+/// The container runtime a container is run by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Runtime {
+    #[default]
+    Docker,
+    Podman,
+}
+
+impl Runtime {
+    pub(crate) fn bin(self) -> &'static str {
+        match self {
+            Runtime::Docker => "docker",
+            Runtime::Podman => "podman",
+        }
+    }
+}
+
+/// A container, paused (or told to quiesce itself) while the datasets of its volumes are
+/// snapshotted.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Container {
+    /// The datasets bind-mounted into the container.
+    pub datasets: Vec<String>,
+    #[serde(default)]
+    pub runtime: Runtime,
+    /// A command to exec in the container to quiesce it, rather than pausing it.
+    #[serde(default)]
+    pub quiesce: Option<Vec<String>>,
+    /// A command to exec in the container once the snapshots are taken, to undo quiesce.
+    #[serde(default)]
+    pub resume: Option<Vec<String>>,
+}
+
+/// The database server a database is run by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Engine {
+    Postgres,
+    Mysql,
+}
+
+/// A database, held in backup mode (or with its tables locked) while the datasets of its data
+/// directory are snapshotted.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Database {
+    /// The datasets holding the data directory of the database, and its WAL or binlogs.
+    pub datasets: Vec<String>,
+    pub engine: Engine,
+    /// How to connect - a libpq connection string for postgres, and for mysql a
+    /// `mysql://user@host:port` or the path of an option file.
+    pub connection: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    #[serde(default)]
+    pub group: BTreeMap<String, Group>,
+    #[serde(default)]
+    pub immutable: BTreeMap<String, Immutable>,
+    #[serde(default)]
+    pub key: BTreeMap<String, Key>,
+    #[serde(default)]
+    pub approval: Approval,
+    #[serde(default)]
+    pub job: BTreeMap<String, Job>,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub email: Option<Email>,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub naming: Naming,
+    #[serde(default)]
+    pub vm: BTreeMap<String, Vm>,
+    #[serde(default)]
+    pub container: BTreeMap<String, Container>,
+    #[serde(default)]
+    pub database: BTreeMap<String, Database>,
+}
+
+impl Config {
+    /// Load the configuration, treating a missing file as empty.
+    pub(crate) fn load() -> Result<Self, ()> {
+        let path = config_dir().join("znapper.toml");
+        match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).map_err(|e| {
+                error!("Failed to parse {:?} -> {}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => {
+                error!("Failed to read {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Confirmation of destructive operations, so a mistyped pool name can't wipe the wrong
+//! snapshot tree.
+//!
+//! Before a cleanup destroys snapshots, or a receive with -F rolls a destination back, znapper run
+//! from a terminal lists what would go and asks. `--yes` answers for it, for automation. Without
+//! a terminal to ask it goes ahead, as cron always has - unless `require_confirmation = true`
+//! under `[approval]` in `znapper.toml`, when it refuses without `--yes`. Dry runs destroy
+//! nothing, so aren't asked, and nor is a program using the api, which has already decided.
+
+use crate::config::Config;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::sync::OnceLock;
+use structopt::StructOpt;
+use tracing::{error, info};
+
+static CONFIRM: OnceLock<ConfirmOpt> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub(crate) struct ConfirmOpt {
+    /// Destroy and roll back without asking for confirmation, as an unattended run must.
+    #[structopt(long = "yes", global = true)]
+    pub yes: bool,
+    /// Destroy snapshots that aren't znapper's - not auto_, repl_ or redact_ - which are
+    /// otherwise refused.
+    #[structopt(long = "force", global = true)]
+    pub force: bool,
+}
+
+/// Ask before destroying, as `opt` says - only a run from the command line is asked.
+pub(crate) fn init(opt: &ConfirmOpt) {
+    let _ = CONFIRM.set(opt.clone());
+}
+
+/// Has the operation been confirmed ahead, with `--yes` (or by the api)?
+pub(crate) fn yes() -> bool {
+    CONFIRM.get().map(|opt| opt.yes).unwrap_or(true)
+}
+
+/// May snapshots that aren't znapper's be destroyed, with `--force`?
+pub(crate) fn forced() -> bool {
+    CONFIRM.get().map(|opt| opt.force).unwrap_or(false)
+}
+
+/// Ask on the terminal whether to go ahead.
+pub(crate) fn ask(question: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        error!(
+            "{} - pass --yes to confirm, as there is no terminal to ask",
+            question
+        );
+        return false;
+    }
+    print!("{} [y/N] ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Go ahead with `action` (ie "destroy 3 snapshots of tank") of `items`?
+pub(crate) fn gate(dry: bool, action: &str, items: &[String]) -> Result<(), ()> {
+    if dry || yes() {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        let config = Config::load()?;
+        if config.approval.require_confirmation {
+            error!(
+                "Not going ahead with {} - confirmation is required, pass --yes",
+                action
+            );
+            return Err(());
+        }
+        return Ok(());
+    }
+    for item in items.iter() {
+        println!("  {}", item);
+    }
+    if ask(&format!("{} - go ahead?", action)) {
+        Ok(())
+    } else {
+        info!("Not going ahead with {}", action);
+        Err(())
+    }
+}
+
+/// Go ahead with destroying `snaps` of `target`?
+pub(crate) fn destroy(dry: bool, target: &str, snaps: &[String]) -> Result<(), ()> {
+    if snaps.is_empty() {
+        return Ok(());
+    }
+    gate(
+        dry,
+        &format!("destroy {} snapshots of {}", snaps.len(), target),
+        snaps,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_api_and_dry_runs_are_not_asked() {
+        let snaps = vec!["tank@auto_1".to_string()];
+        assert!(yes());
+        assert_eq!(destroy(false, "tank", &snaps), Ok(()));
+        assert_eq!(gate(true, "zfs recv -F into tank", &[]), Ok(()));
+    }
+}
@@ -0,0 +1,306 @@
+//! Remote replication of each dataset on its own, with `remote_repl --per-dataset`.
+//!
+//! Rather than one -R stream from the root of the precursor, every dataset is sent as its own
+//! stream from its own precursor, which the metadata records per dataset. So datasets can be
+//! excluded, can live under different roots, and a failure only holds back the dataset that
+//! failed - the others advance, and the failed one carries on from where it was next time.
+//!
+//! Each dataset is received into `<pool>/<dataset>` of the receiver's `znapper recv --pool`, so
+//! the receiver must run znapper recv. Datasets are sent parents first, and a dataset the
+//! receiver doesn't hold yet is sent in full.
+
+use crate::anchors::AnchorStore;
+use crate::metadata::{self, MetadataFile};
+use crate::process::{Kind, Timed};
+use crate::ssh::Ssh;
+use crate::{
+    auto_snapshot_opted_out, dataset_list, get_auto_basesnap, get_property, query_partial_recv,
+    short_name,
+};
+use crate::{check, exclude, plan, process, recv};
+use crate::{remote_precursor, remote_transfer, Owner, ReplFailure, ReplRemoteOpt};
+use std::process::Stdio;
+use tracing::{debug, error, info, warn};
+
+/// The anchor of each dataset is registered on its own, so cleanup keeps each precursor.
+fn owner(opt: &ReplRemoteOpt, identity: &str, dataset: &str) -> Owner {
+    metadata::owner(&opt.auto_snap_metadata, Some(identity), Some(dataset))
+}
+
+/// Is `dataset` excluded, itself or as the descendant of an excluded dataset?
+fn excluded(opt: &ReplRemoteOpt, dataset: &str) -> bool {
+    opt.exclude.iter().any(|e| {
+        dataset == e
+            || dataset
+                .strip_prefix(e.as_str())
+                .map(|rest| rest.starts_with('/'))
+                .unwrap_or(false)
+    })
+}
+
+/// Every dataset to replicate, parents before their children. Those with
+/// com.sun:auto-snapshot=false are never snapshotted, so are left out like excluded ones, as are
+/// those with org.znapper:replicate=false.
+fn datasets(opt: &ReplRemoteOpt, roots: &[String]) -> Result<Vec<String>, ()> {
+    let mut datasets = Vec::new();
+    for root in roots {
+        let opted_out = auto_snapshot_opted_out(root)?;
+        let left_out = exclude::excluded(root)?;
+        datasets.extend(dataset_list(root)?.into_iter().filter(|dataset| {
+            if opted_out.contains(dataset) {
+                info!("Skipping {} - it has opted out of auto snapshots", dataset);
+                return false;
+            }
+            if left_out.contains(dataset) {
+                info!("Skipping {} - it is left out of replication", dataset);
+                return false;
+            }
+            !excluded(opt, dataset)
+        }));
+    }
+    datasets.sort_unstable();
+    datasets.dedup();
+    Ok(datasets)
+}
+
+/// The snapshots of `dataset` on the receiver, oldest first - none if it doesn't hold it yet.
+fn remote_snapshots(ssh: &Ssh, dataset: &str) -> Result<Vec<(String, String)>, ()> {
+    debug!("running -> ssh {} snapshots {}", ssh, dataset);
+    let output = ssh
+        .command(&["snapshots", dataset])
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh)
+        .map_err(|e| {
+            error!("ssh {} snapshots {} failed -> {:?}", ssh, dataset, e);
+        })?;
+    match recv::parse_result::<recv::SnapshotList>(&String::from_utf8_lossy(&output.stdout)) {
+        Some(list) => Ok(list
+            .snapshots
+            .into_iter()
+            .map(|snap| (snap.name, snap.guid))
+            .collect()),
+        None => {
+            error!("Unable to list the snapshots of {} on {}", dataset, ssh);
+            Err(())
+        }
+    }
+}
+
+/// Run a transfer, retrying it as remote_repl would.
+fn with_retries(
+    opt: &ReplRemoteOpt,
+    ssh: &Ssh,
+    mut transfer: impl FnMut() -> Result<(), ReplFailure>,
+) -> Result<(), ()> {
+    let mut attempt = 0;
+    loop {
+        match transfer() {
+            Ok(()) => return Ok(()),
+            Err(ReplFailure::Retry) if attempt < opt.retries && !process::cancelled() => {
+                let delay = opt.retry_delay * 2u32.saturating_pow(attempt);
+                attempt += 1;
+                warn!(
+                    "Transfer to {} failed - retry {} of {} in {:?}",
+                    ssh, attempt, opt.retries, delay
+                );
+                if !opt.dryrun {
+                    std::thread::sleep(delay);
+                }
+            }
+            Err(_) => return Err(()),
+        }
+    }
+}
+
+/// Bring `dataset` on the receiver up to its newest auto snapshot, returning that snapshot if
+/// it is there now.
+fn replicate_dataset(
+    opt: &ReplRemoteOpt,
+    ssh: &Ssh,
+    dataset: &str,
+    partial: Option<&recv::PartialState>,
+) -> Result<Option<String>, ()> {
+    let basesnap_name = match get_auto_basesnap(dataset) {
+        Some(b) => b,
+        None => {
+            warn!("No auto snapshots of {} - skipping it", dataset);
+            return Ok(None);
+        }
+    };
+    let recv = ["recv", dataset];
+    let label = format!("remote send of {} to {}", dataset, ssh);
+
+    let resume = partial.and_then(|state| {
+        let name = format!("{}/{}", state.dataset, dataset);
+        state.partial.iter().find(|p| p.name == name)
+    });
+    if let Some(partial) = resume {
+        info!("Resuming the interrupted receive into {}", partial.name);
+        with_retries(opt, ssh, || {
+            remote_transfer(
+                opt.dryrun,
+                &opt.buffer,
+                ssh,
+                &recv,
+                &["-t", partial.token.as_str()],
+                None,
+                &label,
+            )
+        })?;
+    }
+
+    // Anchor from what the receiver really has, which the metadata should agree with.
+    let remote_snaps = remote_snapshots(ssh, dataset)?;
+    let precursor_name = if remote_snaps.is_empty() {
+        info!("{} is not on {} yet - sending it in full", dataset, ssh);
+        None
+    } else {
+        Some(remote_precursor(dataset, &remote_snaps, false)?)
+    };
+
+    if precursor_name.as_deref() == Some(basesnap_name.as_str()) {
+        info!("{} is up to date at {}", dataset, basesnap_name);
+        return Ok(Some(basesnap_name));
+    }
+
+    let mut send_args = opt.stream.send_args(&["-L", "-w", "-p"]);
+    if let Some(precursor) = precursor_name.as_deref() {
+        send_args.extend(["-I", precursor]);
+    }
+    send_args.push(basesnap_name.as_str());
+    let basesnap_guid = get_property(&basesnap_name, "guid").ok();
+    with_retries(opt, ssh, || {
+        remote_transfer(
+            opt.dryrun,
+            &opt.buffer,
+            ssh,
+            &recv,
+            &send_args,
+            Some((short_name(&basesnap_name), basesnap_guid.as_deref())),
+            &label,
+        )
+    })?;
+    if opt.dryrun {
+        plan::transfer(plan::Transfer {
+            source: dataset.to_string(),
+            from: precursor_name,
+            to: basesnap_name.clone(),
+            destination: ssh.to_string(),
+            estimated_bytes: None,
+            resume_token: None,
+        });
+    }
+    Ok(Some(basesnap_name))
+}
+
+pub(crate) fn replicate(opt: &ReplRemoteOpt, ssh: &Ssh) -> Result<(), ()> {
+    if opt.force_rollback {
+        error!("--force-rollback can not be used with --per-dataset");
+        return Err(());
+    }
+    // Only znapper recv can receive each dataset into its place, and it tells which destination
+    // this is.
+    let space = ssh
+        .command(&["space"])
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh)
+        .ok()
+        .and_then(|output| {
+            recv::parse_result::<recv::Space>(&String::from_utf8_lossy(&output.stdout))
+        });
+    let identity = match space {
+        Some(space) => space.guid.unwrap_or_else(|| ssh.to_string()),
+        None => {
+            error!(
+                "{} does not run znapper recv, which --per-dataset needs to receive each dataset",
+                ssh
+            );
+            return Err(());
+        }
+    };
+
+    // A missing file, or none for this destination, is a fresh start.
+    let mut file = MetadataFile::load(&opt.auto_snap_metadata)?;
+    let mut meta = file
+        .claim(&opt.auto_snap_metadata, &identity, opt.dryrun)
+        .unwrap_or_default();
+
+    let roots: Vec<String> = if opt.datasets.is_empty() {
+        meta.precursor_snap
+            .split('@')
+            .next()
+            .filter(|pool| !pool.is_empty())
+            .map(str::to_string)
+            .into_iter()
+            .collect()
+    } else {
+        opt.datasets.clone()
+    };
+    if roots.is_empty() {
+        error!("--per-dataset needs --dataset, or metadata with a precursor to replicate from");
+        return Err(());
+    }
+
+    if !opt.skip_preflight {
+        let job = check::Job {
+            sources: roots.clone(),
+            require_healthy: opt.require_healthy,
+            ..Default::default()
+        };
+        if check::preflight(&job).is_err() {
+            return Err(());
+        }
+    }
+
+    let datasets = datasets(opt, &roots)?;
+    let partial = query_partial_recv(ssh, None);
+
+    let mut failed = Vec::new();
+    for dataset in datasets.iter() {
+        if process::cancelled() {
+            failed.push(dataset.as_str());
+            continue;
+        }
+        let snap = match replicate_dataset(opt, ssh, dataset, partial.as_ref()) {
+            Ok(Some(snap)) => snap,
+            Ok(None) => continue,
+            Err(_) => {
+                error!("Remote replication of {} to {} failed", dataset, ssh);
+                failed.push(dataset.as_str());
+                continue;
+            }
+        };
+        if meta.datasets.get(dataset) == Some(&snap) {
+            continue;
+        }
+        if opt.dryrun {
+            info!("dryrun: record {} in {}", snap, opt.auto_snap_metadata);
+            continue;
+        }
+        meta.datasets.insert(dataset.clone(), snap.clone());
+        file.set(Some(&identity), meta.clone());
+        if file.save(&opt.auto_snap_metadata).is_err() {
+            return Err(());
+        }
+        let mut anchors = AnchorStore::load()?;
+        anchors.set(&owner(opt, &identity, dataset), &snap);
+        anchors.save(false)?;
+    }
+
+    if failed.is_empty() {
+        info!(
+            "Per-dataset remote replication success - {} datasets",
+            datasets.len()
+        );
+        Ok(())
+    } else {
+        error!(
+            "{} of {} datasets failed to replicate to {} -> {}",
+            failed.len(),
+            datasets.len(),
+            ssh,
+            failed.join(", ")
+        );
+        Err(())
+    }
+}
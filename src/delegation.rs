@@ -0,0 +1,152 @@
+//! `znapper setup-delegation` - grant a user the `zfs allow` permissions a znapper role needs, so
+//! the role can run as that user rather than root.
+
+use crate::check::{granted, run};
+use crate::dataset_exists;
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use std::str::FromStr;
+use structopt::StructOpt;
+use tracing::{debug, error, info};
+
+/// One side of a replication, as the user running it sees the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    /// Snapshots, sends and prunes - snapshot, repl, snapshot_cleanup and remote_repl.
+    Send,
+    /// Is received into - the destination of repl and pull, and the forced recv of remote_repl.
+    Receive,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "send" => Ok(Role::Send),
+            "receive" | "recv" => Ok(Role::Receive),
+            _ => Err(format!("Invalid role {} - use send or receive", s)),
+        }
+    }
+}
+
+impl Role {
+    /// The permissions the role uses, which are what `check` expects the user to hold.
+    pub(crate) fn permissions(self) -> &'static [&'static str] {
+        match self {
+            Role::Send => &["snapshot", "send", "bookmark", "destroy", "mount"],
+            // readonly and mountpoint are set by recv -o, destroy prunes and cleans up the
+            // destination, and rollback is for --force-rollback.
+            Role::Receive => &[
+                "create",
+                "mount",
+                "receive",
+                "readonly",
+                "mountpoint",
+                "destroy",
+                "rollback",
+            ],
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct SetupDelegationOpt {
+    /// The user to delegate to.
+    #[structopt(long = "user")]
+    user: String,
+    /// The pool (or dataset) to delegate on. The permissions apply to its descendants too.
+    #[structopt(long = "pool")]
+    pool: String,
+    /// send or receive
+    #[structopt(long = "role")]
+    role: Role,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+}
+
+/// Which `required` permissions `user` is yet to hold on `pool`, directly, through a group or
+/// through everyone.
+fn missing_permissions(
+    user: &str,
+    pool: &str,
+    required: &[&'static str],
+) -> Result<Vec<&'static str>, ()> {
+    let groups: Vec<String> = run("id", &["-Gn", user])
+        .map_err(|e| error!("unable to find the groups of {} -> {}", user, e))?
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    let allow = run("zfs", &["allow", pool])
+        .map_err(|e| error!("unable to list the delegations of {} -> {}", pool, e))?;
+    let perms = granted(&allow, user, &groups);
+    Ok(required
+        .iter()
+        .filter(|perm| !perms.iter().any(|p| p == *perm))
+        .copied()
+        .collect())
+}
+
+pub(crate) fn do_setup_delegation(opt: &SetupDelegationOpt) {
+    debug!("do_setup_delegation");
+
+    if !dataset_exists(&opt.pool) {
+        error!("{} does not exist", opt.pool);
+        return;
+    }
+
+    let required = opt.role.permissions();
+    let missing = match missing_permissions(&opt.user, &opt.pool, required) {
+        Ok(missing) => missing,
+        Err(_) => return,
+    };
+    if missing.is_empty() {
+        info!(
+            "{} already has {} on {}",
+            opt.user,
+            required.join(","),
+            opt.pool
+        );
+        return;
+    }
+
+    let perms = missing.join(",");
+    if opt.dryrun {
+        info!("dryrun: zfs allow -u {} {} {}", opt.user, perms, opt.pool);
+        return;
+    }
+
+    info!("zfs allow -u {} {} {}", opt.user, perms, opt.pool);
+    let status = privilege::zfs()
+        .arg("allow")
+        .arg("-u")
+        .arg(&opt.user)
+        .arg(&perms)
+        .arg(&opt.pool)
+        .run_status(Kind::Zfs);
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            error!("zfs allow failed -> {:?}", status);
+            return;
+        }
+        Err(e) => {
+            error!("zfs allow failed -> {:?}", e);
+            return;
+        }
+    }
+
+    // Check what zfs now reports, rather than trusting it took.
+    match missing_permissions(&opt.user, &opt.pool, required) {
+        Ok(still) if still.is_empty() => {
+            info!("{} has {} on {}", opt.user, required.join(","), opt.pool)
+        }
+        Ok(still) => error!(
+            "{} is still missing {} on {}",
+            opt.user,
+            still.join(","),
+            opt.pool
+        ),
+        Err(_) => {}
+    }
+}
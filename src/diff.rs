@@ -0,0 +1,265 @@
+//! `znapper diff` - what changed in a dataset between two of its snapshots, or since one of them,
+//! as `zfs diff -FH` reports it.
+
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use crate::OutputFormat;
+use serde::Serialize;
+use structopt::StructOpt;
+use tracing::{debug, error};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct DiffOpt {
+    dataset: String,
+    /// The snapshot to diff from, ie auto_2024-05-01T030000Z.
+    from: String,
+    /// The snapshot to diff to. Without, to the dataset as it is now.
+    to: Option<String>,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Change {
+    Added,
+    Removed,
+    Modified,
+    Renamed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum FileType {
+    File,
+    Directory,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Pipe,
+    Socket,
+    Door,
+    EventPort,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Record {
+    change: Change,
+    file_type: FileType,
+    path: String,
+    /// Where a renamed path was renamed to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    renamed_to: Option<String>,
+}
+
+impl Change {
+    fn as_str(self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Modified => "modified",
+            Change::Renamed => "renamed",
+        }
+    }
+}
+
+impl FileType {
+    fn as_str(self) -> &'static str {
+        match self {
+            FileType::File => "file",
+            FileType::Directory => "directory",
+            FileType::Symlink => "symlink",
+            FileType::BlockDevice => "block-device",
+            FileType::CharDevice => "char-device",
+            FileType::Pipe => "pipe",
+            FileType::Socket => "socket",
+            FileType::Door => "door",
+            FileType::EventPort => "event-port",
+        }
+    }
+}
+
+impl Record {
+    /// The record as a line of text, ie `renamed\tfile\t/tank/data/a -> /tank/data/b`.
+    pub(crate) fn line(&self) -> String {
+        match self.renamed_to.as_deref() {
+            Some(to) => format!(
+                "{}\t{}\t{} -> {}",
+                self.change.as_str(),
+                self.file_type.as_str(),
+                self.path,
+                to
+            ),
+            None => format!(
+                "{}\t{}\t{}",
+                self.change.as_str(),
+                self.file_type.as_str(),
+                self.path
+            ),
+        }
+    }
+}
+
+/// zfs diff escapes the unprintable bytes of a path, and spaces, as `\ooo` octal.
+fn unescape(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|d| u8::from_str_radix(d, 8).ok())
+        });
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// A line of `zfs diff -FH` - the change, the type and the path, and for a rename the new path.
+fn parse_line(line: &str) -> Option<Record> {
+    let mut fields = line.split('\t');
+    let change = match fields.next()? {
+        "+" => Change::Added,
+        "-" => Change::Removed,
+        "M" => Change::Modified,
+        "R" => Change::Renamed,
+        _ => return None,
+    };
+    let file_type = match fields.next()? {
+        "F" => FileType::File,
+        "/" => FileType::Directory,
+        "@" => FileType::Symlink,
+        "B" => FileType::BlockDevice,
+        "C" => FileType::CharDevice,
+        "|" => FileType::Pipe,
+        "=" => FileType::Socket,
+        ">" => FileType::Door,
+        "P" => FileType::EventPort,
+        _ => return None,
+    };
+    let path = unescape(fields.next()?);
+    let renamed_to = match change {
+        Change::Renamed => Some(unescape(fields.next()?)),
+        _ => None,
+    };
+    Some(Record {
+        change,
+        file_type,
+        path,
+        renamed_to,
+    })
+}
+
+/// `name` as a snapshot of `dataset`, unless it already names one.
+fn snapshot(dataset: &str, name: &str) -> String {
+    if name.contains('@') {
+        name.to_string()
+    } else {
+        format!("{}@{}", dataset, name)
+    }
+}
+
+/// What changed from the snapshot `from` to `to`, a later snapshot or the dataset itself, or
+/// why zfs diff failed.
+pub(crate) fn changes(from: &str, to: &str) -> Result<Vec<Record>, String> {
+    debug!("zfs diff -FH {} {}", from, to);
+    let output = privilege::zfs()
+        .arg("diff")
+        .arg("-F")
+        .arg("-H")
+        .arg(from)
+        .arg(to)
+        .run_output(Kind::Zfs)
+        .map_err(|e| format!("zfs diff failed -> {:?}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "zfs diff {} {} failed -> {}",
+            from,
+            to,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let record = parse_line(line);
+            if record.is_none() {
+                debug!("Ignoring zfs diff line {}", line);
+            }
+            record
+        })
+        .collect())
+}
+
+fn diff(opt: &DiffOpt) -> Result<Vec<Record>, ()> {
+    let from = snapshot(&opt.dataset, &opt.from);
+    let to = opt
+        .to
+        .as_deref()
+        .map(|to| snapshot(&opt.dataset, to))
+        .unwrap_or_else(|| opt.dataset.clone());
+    changes(&from, &to).map_err(|e| {
+        error!("{}", e);
+    })
+}
+
+pub(crate) fn do_diff(opt: &DiffOpt) {
+    let records = match diff(opt) {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&records) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise diff -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for r in records {
+                println!("{}", r.line());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_lines_are_parsed() {
+        assert_eq!(
+            parse_line("M\t/\t/tank/data/etc"),
+            Some(Record {
+                change: Change::Modified,
+                file_type: FileType::Directory,
+                path: "/tank/data/etc".to_string(),
+                renamed_to: None,
+            })
+        );
+        assert_eq!(
+            parse_line("R\tF\t/tank/data/a\\040b\t/tank/data/c"),
+            Some(Record {
+                change: Change::Renamed,
+                file_type: FileType::File,
+                path: "/tank/data/a b".to_string(),
+                renamed_to: Some("/tank/data/c".to_string()),
+            })
+        );
+        assert_eq!(parse_line("R\tF\t/tank/data/a"), None);
+        assert_eq!(parse_line("?\tF\t/tank/data/a"), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! An email to the administrator when a run fails, for those without a monitoring stack.
+//!
+//! Every error logged during a run is also kept here - with the stderr of the zfs and ssh
+//! commands that failed, where the error carries it. A run that logged errors failed, and with
+//! `[email]` in `znapper.toml` they are mailed once it is over. The mail is sent over SMTP with
+//! curl, and the credentials are given to curl on stdin rather than on its command line. The
+//! message itself is uploaded from a file of the state directory that only we can read.
+
+use crate::anchors::state_dir;
+use crate::config::{Config, Email};
+use crate::hostname;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+use tracing::field::{Field, Visit};
+use tracing::{debug, error, info, Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// Give up on sending the mail after this many seconds.
+const TIMEOUT_SECS: &str = "30";
+
+/// The errors logged so far.
+static ERRORS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Keeps the errors logged during the run, for `send_failure`.
+pub(crate) struct ErrorLog;
+
+/// The message of an event, followed by its other fields.
+#[derive(Default)]
+pub(crate) struct Message(pub(crate) String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}{}", value, self.0);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for ErrorLog {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        let mut message = Message::default();
+        event.record(&mut message);
+        if let Ok(mut errors) = ERRORS.lock() {
+            errors.push(message.0);
+        }
+    }
+}
+
+/// The errors logged so far in this run.
+pub(crate) fn logged() -> Vec<String> {
+    ERRORS
+        .lock()
+        .map(|errors| errors.clone())
+        .unwrap_or_default()
+}
+
+/// Quote `value` as a curl config string.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn message(email: &Email, host: &str, errors: &[String]) -> String {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let action = args.first().map(String::as_str).unwrap_or("znapper");
+    let now = OffsetDateTime::try_now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+
+    let mut out = String::new();
+    let _ = writeln!(out, "From: {}", email.from);
+    let _ = writeln!(out, "To: {}", email.to.join(", "));
+    let _ = writeln!(out, "Subject: znapper {} failed on {}", action, host);
+    let _ = writeln!(out, "Date: {}", now.format("%a, %d %b %Y %H:%M:%S %z"));
+    let _ = writeln!(out, "Content-Type: text/plain; charset=utf-8");
+    let _ = writeln!(out);
+    let _ = writeln!(
+        out,
+        "znapper {} failed on {} at {}.",
+        args.join(" "),
+        host,
+        now.format("%Y-%m-%d %H:%M:%S %z")
+    );
+    let _ = writeln!(out);
+    let _ = writeln!(out, "Errors:");
+    for e in errors {
+        let _ = writeln!(out);
+        let _ = writeln!(out, "{}", e);
+    }
+    out
+}
+
+/// Write `message` for curl to upload, to a file of the state directory only we can read. It is
+/// created afresh rather than opened, so nothing already at its path - such as a symlink - is
+/// written through.
+fn write_message(message: &str) -> Result<PathBuf, ()> {
+    let dir = state_dir();
+    let path = dir.join(format!("email-{}.eml", std::process::id()));
+    fs::create_dir_all(&dir)
+        .and_then(|()| match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        })
+        .and_then(|()| {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+        })
+        .and_then(|mut f| f.write_all(message.as_bytes()))
+        .map_err(|e| {
+            error!("Failed to write {:?} -> {:?}", path, e);
+        })?;
+    Ok(path)
+}
+
+fn send(email: &Email, message: &str) -> Result<(), ()> {
+    let path = write_message(message)?;
+
+    let mut cmd = Command::new("curl");
+    cmd.args(["-sS", "-m", TIMEOUT_SECS, "--crlf", "--url", &email.server])
+        .args(["--mail-from", &email.from]);
+    for to in email.to.iter() {
+        cmd.args(["--mail-rcpt", to]);
+    }
+    cmd.arg("--upload-file")
+        .arg(&path)
+        .args(["-K", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null());
+
+    let res = cmd
+        .spawn()
+        .map_err(|e| {
+            error!("Unable to run curl to send the email -> {:?}", e);
+        })
+        .and_then(|mut child| {
+            if let (Some(username), Some(mut stdin)) =
+                (email.username.as_deref(), child.stdin.take())
+            {
+                let password = email.password.as_deref().unwrap_or("");
+                let config = format!(
+                    "user = {}\nssl-reqd\n",
+                    quote(&format!("{}:{}", username, password))
+                );
+                if let Err(e) = stdin.write_all(config.as_bytes()) {
+                    error!("Failed to give curl the credentials -> {:?}", e);
+                }
+            }
+            child.wait().map_err(|e| {
+                error!("Sending the email failed -> {:?}", e);
+            })
+        })
+        .and_then(|status| {
+            if status.success() {
+                Ok(())
+            } else {
+                error!("Sending the email failed -> {:?}", status.code());
+                Err(())
+            }
+        });
+    let _ = fs::remove_file(&path);
+    res
+}
+
+/// Mail the errors logged during the run, if there were any and `[email]` is configured.
+pub(crate) fn send_failure(dry: bool) {
+    let errors = match ERRORS.lock() {
+        Ok(mut errors) => std::mem::take(&mut *errors),
+        Err(_) => return,
+    };
+    if errors.is_empty() {
+        return;
+    }
+    let email = match Config::load().ok().and_then(|c| c.email) {
+        Some(email) => email,
+        None => return,
+    };
+    if dry {
+        info!(
+            "dryrun: email {} errors to {}",
+            errors.len(),
+            email.to.join(", ")
+        );
+        return;
+    }
+    let host = hostname().unwrap_or_else(|_| "unknown host".to_string());
+    if send(&email, &message(&email, &host, &errors)).is_ok() {
+        debug!("emailed {} errors to {}", errors.len(), email.to.join(", "));
+    }
+}
@@ -0,0 +1,252 @@
+//! How large a replication stream will be, from `zfs send -n -P`, and whether the destination
+//! pool has the free space to receive it.
+//!
+//! repl and remote_repl check this before they send, and `znapper estimate` reports it for the
+//! repl that would run with the same arguments.
+
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use crate::{dataset_list, repl_bookmark_list, repl_destinations, repl_precursor, repl_snap_list};
+use crate::{short_name, snapshot_guid_list, OutputFormat, ReplOpt};
+use serde::Serialize;
+use std::process::Command;
+use structopt::StructOpt;
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct EstimateOpt {
+    #[structopt(flatten)]
+    repl: ReplOpt,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+/// The estimated size of the stream from `precursor_name` (a snapshot or bookmark of the root) to
+/// `snap_name` for each dataset of `from_pool`, or of a full stream of `snap_name` if there is no
+/// precursor. Datasets that don't have the snapshots are skipped. Unless `recursive`, only
+/// from_pool itself is estimated, as --single-dataset sends it.
+pub(crate) fn stream(
+    from_pool: &str,
+    recursive: bool,
+    precursor_name: Option<&str>,
+    snap_name: &str,
+) -> Result<Vec<(String, u64)>, ()> {
+    let snap_short = short_name(snap_name);
+    let datasets = if recursive {
+        dataset_list(from_pool)?
+    } else {
+        vec![from_pool.to_string()]
+    };
+
+    let mut estimates = Vec::new();
+    for fs in datasets {
+        let to = format!("{}@{}", fs, snap_short);
+        let mut args: Vec<String> = ["send", "-n", "-P", "-w"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        if let Some(precursor_name) = precursor_name {
+            let sep = if precursor_name.contains('#') {
+                '#'
+            } else {
+                '@'
+            };
+            args.push("-i".to_string());
+            args.push(format!("{}{}{}", fs, sep, short_name(precursor_name)));
+        }
+        args.push(to.clone());
+
+        let output = privilege::zfs()
+            .args(&args)
+            .run_output(Kind::Zfs)
+            .map_err(|e| {
+                error!("send estimate failed -> {:?}", e);
+            })?;
+        if !output.status.success() {
+            // Datasets created since the precursor have nothing to compare against.
+            debug!("No estimate for {}", args.join(" "));
+            continue;
+        }
+        let size = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .chain(String::from_utf8_lossy(&output.stderr).lines())
+            .find_map(|line| {
+                line.strip_prefix("size\t")
+                    .and_then(|size| size.trim().parse::<u64>().ok())
+            });
+        match size {
+            Some(size) => estimates.push((fs, size)),
+            None => debug!("No size in estimate for {}", to),
+        }
+    }
+    debug!(?estimates);
+    Ok(estimates)
+}
+
+/// repl sends up to a snapshot it has yet to take, so estimates (without taking it) are up to
+/// the newest auto or repl snapshot there is now.
+pub(crate) fn newest_snapshot(from_pool: &str) -> Result<Option<String>, ()> {
+    Ok(snapshot_guid_list(from_pool)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .rfind(|name| {
+            let short = short_name(name);
+            short.starts_with("auto_") || short.starts_with("repl_")
+        }))
+}
+
+/// The free space of the pool holding `dataset`, which need not exist yet.
+pub(crate) fn pool_free(dataset: &str) -> Result<u64, ()> {
+    let pool = dataset.split('/').next().unwrap_or(dataset);
+    let output = Command::new("zpool")
+        .args(["get", "-H", "-p", "-o", "value", "free", pool])
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zpool get failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!("Unable to get the free space of {}", pool);
+        return Err(());
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .map_err(|e| {
+            error!("Invalid free space of {} -> {:?}", pool, e);
+        })
+}
+
+/// Refuse (or with `ignore`, only warn) when `estimates` don't fit in `free` bytes on `dest`.
+pub(crate) fn check_space(
+    dest: &str,
+    estimates: &[(String, u64)],
+    free: u64,
+    ignore: bool,
+) -> Result<(), ()> {
+    let total: u64 = estimates.iter().map(|(_, size)| size).sum();
+    if total <= free {
+        info!("Estimated {} bytes to {}, with {} free", total, dest, free);
+        return Ok(());
+    }
+    if ignore {
+        warn!(
+            "Estimated {} bytes to {}, but only {} are free - sending anyway",
+            total, dest, free
+        );
+        Ok(())
+    } else {
+        error!(
+            "Estimated {} bytes to {}, but only {} are free - use --ignore-space to send anyway",
+            total, dest, free
+        );
+        Err(())
+    }
+}
+
+#[derive(Serialize)]
+struct DatasetEstimate {
+    dataset: String,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct DestEstimate {
+    destination: String,
+    /// The precursor the stream is sent from, none for a full send.
+    from: Option<String>,
+    to: String,
+    datasets: Vec<DatasetEstimate>,
+    total: u64,
+    free: Option<u64>,
+}
+
+pub(crate) fn do_estimate(opt: &EstimateOpt) {
+    debug!("do_estimate");
+
+    let from_snaps = match repl_snap_list(opt.repl.from_pool.as_str()) {
+        Ok(snaps) => snaps,
+        Err(_) => return,
+    };
+    let from_bookmarks = match repl_bookmark_list(opt.repl.from_pool.as_str()) {
+        Ok(bookmarks) => bookmarks,
+        Err(_) => return,
+    };
+
+    let snap_name = match newest_snapshot(opt.repl.from_pool.as_str()) {
+        Ok(Some(s)) => s,
+        Ok(None) => {
+            error!(
+                "No auto or repl snapshots of {} to estimate from",
+                opt.repl.from_pool
+            );
+            return;
+        }
+        Err(_) => return,
+    };
+    debug!("Estimating up to {}", snap_name);
+
+    let dests = match repl_destinations(&opt.repl) {
+        Ok(dests) => dests,
+        Err(_) => return,
+    };
+
+    let mut report = Vec::new();
+    for mut dest in dests {
+        // Only plan - never stage or consume approvals.
+        dest.dryrun = true;
+        let precursor = match repl_precursor(&dest, &from_snaps, &from_bookmarks) {
+            Ok((precursor, _)) => precursor,
+            Err(_) => continue,
+        };
+        let estimates = match stream(
+            opt.repl.from_pool.as_str(),
+            !opt.repl.single_dataset,
+            precursor.as_deref(),
+            snap_name.as_str(),
+        ) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        report.push(DestEstimate {
+            free: pool_free(dest.to_pool.as_str()).ok(),
+            total: estimates.iter().map(|(_, size)| size).sum(),
+            destination: dest.to_pool,
+            from: precursor,
+            to: snap_name.clone(),
+            datasets: estimates
+                .into_iter()
+                .map(|(dataset, bytes)| DatasetEstimate { dataset, bytes })
+                .collect(),
+        });
+    }
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&report) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise estimate -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for dest in report {
+                println!(
+                    "{}\t{} -> {}\ttotal={} free={}\t{}",
+                    dest.destination,
+                    dest.from.as_deref().unwrap_or("full"),
+                    dest.to,
+                    dest.total,
+                    dest.free
+                        .map(|f| f.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    match dest.free {
+                        Some(free) if free >= dest.total => "ok",
+                        Some(_) => "insufficient",
+                        None => "unknown",
+                    }
+                );
+                for ds in dest.datasets {
+                    println!("  {}\t{}", ds.dataset, ds.bytes);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Datasets left out of replication, with `org.znapper:replicate=false`.
+//!
+//! The property is inherited, so setting it on a dataset leaves out its descendants too, unless
+//! one of them sets it back to true. When anything under the source is left out, local repl no
+//! longer sends one -R stream of the whole source, but splits it - one -R stream for each subtree
+//! with nothing left out beneath it, and a stream of its own for each dataset that is kept but
+//! has something left out beneath it. remote_repl leaves them out with `--per-dataset`, and
+//! refuses to send them in a -R stream.
+//!
+//! A left out dataset that a local destination already holds (sent before it was left out) is
+//! destroyed there, with its descendants, once the destination has been replicated to - asked
+//! for as the destroys of a cleanup are.
+
+use crate::audit;
+use crate::process::{Kind, Timed};
+use crate::{confirm, dataset_exists, immutable, privilege};
+use tracing::{debug, error, info};
+
+/// The property that, set to false, leaves a dataset out of replication.
+pub(crate) const REPLICATE_PROPERTY: &str = "org.znapper:replicate";
+
+/// Does `value` of org.znapper:replicate leave the dataset out?
+fn off(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "false" | "off" | "no"
+    )
+}
+
+/// Is the dataset `name` `dataset`, or under it?
+fn within(name: &str, dataset: &str) -> bool {
+    name == dataset
+        || name
+            .strip_prefix(dataset)
+            .map(|rest| rest.starts_with('/'))
+            .unwrap_or(false)
+}
+
+/// The datasets under (and including) `pool` that are left out of replication.
+pub(crate) fn excluded(pool: &str) -> Result<Vec<String>, ()> {
+    let output = privilege::zfs()
+        .arg("list")
+        .arg("-H")
+        .arg("-r")
+        .arg("-t")
+        .arg("filesystem,volume")
+        .arg("-o")
+        .arg(format!("name,{}", REPLICATE_PROPERTY))
+        .arg(pool)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("dataset list failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!("Unable to list the {} of {}", REPLICATE_PROPERTY, pool);
+        return Err(());
+    }
+    let excluded: Vec<_> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(_, value)| off(value))
+        .map(|(name, _)| name.to_string())
+        .collect();
+    debug!("left out of replication -> {:?}", excluded);
+    Ok(excluded)
+}
+
+/// The streams `datasets` are sent in, leaving out `excluded` - each the dataset it is sent from,
+/// and whether it is sent -R, with its descendants. `datasets` are parents first.
+pub(crate) fn streams(datasets: &[String], excluded: &[String]) -> Vec<(String, bool)> {
+    let mut streams: Vec<(String, bool)> = Vec::new();
+    for dataset in datasets.iter().filter(|d| !excluded.contains(d)) {
+        let whole = !excluded.iter().any(|e| within(e, dataset));
+        // Within a -R stream already.
+        if streams
+            .iter()
+            .any(|(root, recursive)| *recursive && within(dataset, root))
+        {
+            continue;
+        }
+        streams.push((dataset.clone(), whole));
+    }
+    streams
+}
+
+/// The left out datasets whose parent is kept - destroying them destroys the rest.
+fn tops(excluded: &[String]) -> impl Iterator<Item = &String> {
+    excluded
+        .iter()
+        .filter(|e| !excluded.iter().any(|other| other != *e && within(e, other)))
+}
+
+/// Destroy on `to_pool` what it holds of the left out datasets of `from_pool`.
+pub(crate) fn prune(
+    dry: bool,
+    from_pool: &str,
+    to_pool: &str,
+    excluded: &[String],
+) -> Result<(), ()> {
+    let held: Vec<String> = tops(excluded)
+        .filter_map(|e| {
+            let relative = e.strip_prefix(from_pool)?;
+            Some(format!("{}{}", to_pool, relative))
+        })
+        .filter(|dest| dest != to_pool && dataset_exists(dest))
+        .collect();
+    if held.is_empty() {
+        return Ok(());
+    }
+    confirm::gate(
+        dry,
+        &format!(
+            "destroy {} datasets of {} left out of replication",
+            held.len(),
+            to_pool
+        ),
+        &held,
+    )?;
+
+    let mut res = Ok(());
+    for dataset in held.iter() {
+        if immutable::check_destroy_dataset(dataset).is_err() {
+            res = Err(());
+            continue;
+        }
+        if dry {
+            info!(
+                "dryrun: destroy -r {} - it is left out of replication",
+                dataset
+            );
+            audit::destroy(true, dataset, None, audit::Reason::Excluded, true);
+            continue;
+        }
+        info!("destroy -r {} - it is left out of replication", dataset);
+        let succeeded = privilege::zfs()
+            .arg("destroy")
+            .arg("-r")
+            .arg(dataset)
+            .run_status(Kind::Zfs)
+            .map(|status| status.success())
+            .unwrap_or(false);
+        audit::destroy(false, dataset, None, audit::Reason::Excluded, succeeded);
+        if !succeeded {
+            error!("Unable to destroy {}", dataset);
+            res = Err(());
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn sends_are_split_around_what_is_left_out() {
+        let datasets = names(&[
+            "nvme",
+            "nvme/home",
+            "nvme/home/william",
+            "nvme/scratch",
+            "nvme/scratch/build",
+            "nvme/vm",
+            "nvme/vm/cache",
+            "nvme/vm/disk",
+        ]);
+        let excluded = names(&["nvme/scratch", "nvme/scratch/build", "nvme/vm/cache"]);
+        assert_eq!(
+            streams(&datasets, &excluded),
+            vec![
+                ("nvme".to_string(), false),
+                ("nvme/home".to_string(), true),
+                ("nvme/vm".to_string(), false),
+                ("nvme/vm/disk".to_string(), true),
+            ]
+        );
+        assert_eq!(
+            tops(&excluded).collect::<Vec<_>>(),
+            vec!["nvme/scratch", "nvme/vm/cache"]
+        );
+        assert_eq!(streams(&datasets, &[]), vec![("nvme".to_string(), true)]);
+        assert!(off("false") && off("OFF") && !off("-") && !off("true"));
+    }
+}
@@ -0,0 +1,196 @@
+//! `znapper failover` - take over a replica, so that this machine can serve it in place of the
+//! source.
+//!
+//! The replica is made writable, each of its filesystems that was received to not mount
+//! (canmount=noauto, as `--standby` receives them) is set to mount as any other would, and they
+//! are mounted. A warm standby keeps the mountpoints of the source, so comes up where the source
+//! had it. Once written to, the replica no longer matches its source, and replication into it
+//! stops until it is rolled back (`repl --force-rollback`) or replicated back the other way.
+
+use crate::dataset_exists;
+use crate::keys;
+use crate::lock::LockOpt;
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use crate::restore::zfs;
+use structopt::StructOpt;
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct FailoverOpt {
+    /// The replica to take over, with its descendants.
+    pub pool: String,
+    /// Load the keys of its encryption roots first, as znapper load-keys does, so that its
+    /// encrypted filesystems can mount.
+    #[structopt(long = "load-keys")]
+    load_keys: bool,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+    #[structopt(flatten)]
+    pub lock: LockOpt,
+}
+
+/// A filesystem of the replica, as zfs list gives it.
+#[derive(Debug)]
+struct Filesystem {
+    name: String,
+    canmount: String,
+    mountpoint: String,
+    mounted: bool,
+}
+
+fn filesystems(pool: &str) -> Result<Vec<Filesystem>, ()> {
+    let output = privilege::zfs()
+        .args([
+            "list",
+            "-H",
+            "-r",
+            "-t",
+            "filesystem",
+            "-o",
+            "name,canmount,mountpoint,mounted",
+            pool,
+        ])
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("filesystem list failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!(
+            "filesystem list of {} failed -> {}",
+            pool,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(());
+    }
+    let filesystems = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let f: Vec<_> = line.split('\t').collect();
+            match f.as_slice() {
+                [name, canmount, mountpoint, mounted] => Some(Filesystem {
+                    name: name.to_string(),
+                    canmount: canmount.to_string(),
+                    mountpoint: mountpoint.to_string(),
+                    mounted: *mounted == "yes",
+                }),
+                _ => None,
+            }
+        })
+        .collect();
+    debug!(?filesystems);
+    Ok(filesystems)
+}
+
+/// The zfs commands that take over `pool`, parents first so that children mount within them, and
+/// the filesystems that have nowhere to mount.
+fn plan(pool: &str, filesystems: &[Filesystem]) -> (Vec<Vec<String>>, Vec<String>) {
+    // readonly=on is set on received datasets, not inherited, so clear it throughout.
+    let mut steps = vec![
+        vec![
+            "inherit".into(),
+            "-r".into(),
+            "readonly".into(),
+            pool.into(),
+        ],
+        vec!["set".into(), "readonly=off".into(), pool.into()],
+    ];
+    let mut unmountable = Vec::new();
+    for fs in filesystems.iter() {
+        if fs.canmount == "noauto" {
+            steps.push(vec!["set".into(), "canmount=on".into(), fs.name.clone()]);
+        } else if fs.canmount != "on" {
+            continue;
+        }
+        if fs.mountpoint == "none" || fs.mountpoint == "legacy" {
+            unmountable.push(fs.name.clone());
+        } else if !fs.mounted {
+            steps.push(vec!["mount".into(), fs.name.clone()]);
+        }
+    }
+    (steps, unmountable)
+}
+
+pub(crate) fn do_failover(opt: &FailoverOpt) {
+    debug!("do_failover");
+    if !dataset_exists(&opt.pool) {
+        error!("{} does not exist", opt.pool);
+        return;
+    }
+    let filesystems = match filesystems(&opt.pool) {
+        Ok(f) => f,
+        Err(_) => return,
+    };
+
+    if opt.load_keys && keys::load_keys(&opt.pool, None, opt.dryrun).is_err() {
+        error!(
+            "Failover of {} stopped - not every key could be loaded",
+            opt.pool
+        );
+        return;
+    }
+
+    let (steps, unmountable) = plan(&opt.pool, &filesystems);
+    for step in steps.iter() {
+        let args: Vec<&str> = step.iter().map(String::as_str).collect();
+        if zfs(opt.dryrun, &args).is_err() {
+            error!("Failover of {} stopped at zfs {}", opt.pool, args.join(" "));
+            return;
+        }
+    }
+    for name in unmountable.iter() {
+        warn!(
+            "{} has no mountpoint (it was not received as a --standby) - zfs set \
+             mountpoint=<path> {} to mount it",
+            name, name
+        );
+    }
+    if !opt.dryrun {
+        info!(
+            "{} is writable and mounted - replication into it stops until it is rolled back, or \
+             replicated back",
+            opt.pool
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fs(name: &str, canmount: &str, mountpoint: &str, mounted: bool) -> Filesystem {
+        Filesystem {
+            name: name.to_string(),
+            canmount: canmount.to_string(),
+            mountpoint: mountpoint.to_string(),
+            mounted,
+        }
+    }
+
+    #[test]
+    fn failover_makes_the_replica_writable_and_mounts_it() {
+        let (steps, unmountable) = plan(
+            "standby/nvme",
+            &[
+                fs("standby/nvme", "noauto", "/srv", false),
+                fs("standby/nvme/home", "noauto", "/home", false),
+                fs("standby/nvme/scratch", "off", "/scratch", false),
+                fs("standby/nvme/db", "on", "/var/db", true),
+                fs("standby/nvme/old", "on", "none", false),
+            ],
+        );
+        let steps: Vec<_> = steps.iter().map(|step| step.join(" ")).collect();
+        assert_eq!(
+            steps,
+            [
+                "inherit -r readonly standby/nvme",
+                "set readonly=off standby/nvme",
+                "set canmount=on standby/nvme",
+                "mount standby/nvme",
+                "set canmount=on standby/nvme/home",
+                "mount standby/nvme/home",
+            ]
+        );
+        assert_eq!(unmountable, ["standby/nvme/old"]);
+    }
+}
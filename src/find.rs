@@ -0,0 +1,202 @@
+//! `znapper find` - which snapshots of a dataset hold a file, and in which it last changed, by
+//! looking for it in each of the dataset's `.zfs/snapshot` directories, oldest first.
+
+use crate::model::Snapshot;
+use crate::{get_property, snap_list, OutputFormat};
+use serde::Serialize;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, info};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct FindOpt {
+    dataset: String,
+    /// The file, relative to the root of the dataset, or as an absolute path under its
+    /// mountpoint.
+    path: String,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+/// The file as one snapshot (or the dataset as it is now) has it.
+#[derive(Debug, Clone, Serialize)]
+struct Sighting {
+    /// Empty for the dataset as it is now.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    snapshot: String,
+    present: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<String>,
+    /// It differs from the snapshot before - it was created, changed or removed since.
+    changed: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Found {
+    dataset: String,
+    path: String,
+    snapshots: Vec<Sighting>,
+    /// The newest snapshot it was created or changed in.
+    last_changed: Option<String>,
+    live: Sighting,
+}
+
+/// `path` relative to the root of the dataset mounted at `mountpoint`, refusing any that would
+/// leave it.
+fn relative(mountpoint: &str, path: &str) -> Result<PathBuf, ()> {
+    let path = Path::new(path);
+    let relative = path
+        .strip_prefix(mountpoint)
+        .unwrap_or_else(|_| path.strip_prefix("/").unwrap_or(path));
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+        || relative.as_os_str().is_empty()
+    {
+        error!("{} is not a path within the dataset", path.display());
+        return Err(());
+    }
+    Ok(relative.to_path_buf())
+}
+
+/// (size, modified) of `path`, if it is there. Links are not followed, so a link can't lead out
+/// of the snapshot.
+fn look(path: &Path) -> Option<(u64, i64)> {
+    let meta = fs::symlink_metadata(path).ok()?;
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    Some((meta.len(), modified))
+}
+
+fn format_ts(ts: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(ts).format("%Y-%m-%dT%H:%M:%SZ")
+}
+
+fn sighting(snapshot: &str, seen: Option<(u64, i64)>, before: Option<(u64, i64)>) -> Sighting {
+    Sighting {
+        snapshot: snapshot.to_string(),
+        present: seen.is_some(),
+        size: seen.map(|(size, _)| size),
+        modified: seen.map(|(_, modified)| format_ts(modified)),
+        changed: seen != before,
+    }
+}
+
+fn find(opt: &FindOpt) -> Result<Found, ()> {
+    if get_property(&opt.dataset, "mounted")? != "yes" {
+        error!(
+            "{} is not mounted, so has no .zfs/snapshot to look in - znapper mount a snapshot of \
+             it to look there",
+            opt.dataset
+        );
+        return Err(());
+    }
+    let mountpoint = get_property(&opt.dataset, "mountpoint")?;
+    let relative = relative(&mountpoint, &opt.path)?;
+    let root = Path::new(&mountpoint);
+
+    let mut snaps: Vec<Snapshot> = snap_list(&opt.dataset, false)?;
+    snaps.sort_by_key(|snap| snap.createtxg());
+
+    let mut sightings = Vec::new();
+    let mut before = None;
+    for snap in snaps.iter() {
+        let path = root
+            .join(".zfs/snapshot")
+            .join(snap.short_name())
+            .join(&relative);
+        debug!("looking for {:?}", path);
+        let seen = look(&path);
+        sightings.push(sighting(snap.short_name(), seen, before));
+        before = seen;
+    }
+    let live = sighting("", look(&root.join(&relative)), before);
+
+    let last_changed = sightings
+        .iter()
+        .rev()
+        .find(|s| s.changed && s.present)
+        .map(|s| s.snapshot.clone());
+    Ok(Found {
+        dataset: opt.dataset.clone(),
+        path: relative.display().to_string(),
+        snapshots: sightings,
+        last_changed,
+        live,
+    })
+}
+
+pub(crate) fn do_find(opt: &FindOpt) {
+    let found = match find(opt) {
+        Ok(f) => f,
+        Err(_) => return,
+    };
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&found) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise find -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            let describe = |s: &Sighting| match (s.size, s.modified.as_deref()) {
+                (Some(size), Some(modified)) => format!(
+                    "{} bytes\tmodified {}{}",
+                    size,
+                    modified,
+                    if s.changed { "\tchanged" } else { "" }
+                ),
+                _ if s.changed => "absent\tremoved".to_string(),
+                _ => "absent".to_string(),
+            };
+            for s in found.snapshots.iter() {
+                println!("{}\t{}", s.snapshot, describe(s));
+            }
+            println!("now\t{}", describe(&found.live));
+            let present = found.snapshots.iter().filter(|s| s.present).count();
+            match found.last_changed {
+                Some(snapshot) => info!(
+                    "{} is in {} of {} snapshots, and last changed in {}",
+                    found.path,
+                    present,
+                    found.snapshots.len(),
+                    snapshot
+                ),
+                None => info!("{} is in no snapshot of {}", found.path, found.dataset),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_relative_to_the_dataset() {
+        assert_eq!(
+            relative("/srv/web", "etc/fstab").unwrap(),
+            Path::new("etc/fstab")
+        );
+        assert_eq!(
+            relative("/srv/web", "/srv/web/etc/fstab").unwrap(),
+            Path::new("etc/fstab")
+        );
+        assert_eq!(
+            relative("/srv/web", "/etc/fstab").unwrap(),
+            Path::new("etc/fstab")
+        );
+        assert!(relative("/srv/web", "etc/../../shadow").is_err());
+        assert!(relative("/srv/web", "/srv/web").is_err());
+    }
+}
@@ -0,0 +1,470 @@
+//! Fleet mode - one backup server pulling from many hosts.
+//!
+//! The hosts, and the datasets to pull from each, are kept in `fleet.toml` in the configuration
+//! directory:
+//!
+//! ```toml
+//! jobs = 4
+//! to = "backups/%hostname%/%dataset%"
+//!
+//! [host.web1]
+//! ssh = "backup@web1.example.com"
+//! datasets = ["tank/www", "tank/db"]
+//! ```
+//!
+//! `znapper fleet run` pulls every dataset of every host (or of the hosts named), `jobs` hosts at
+//! a time and the datasets of one host in turn, so that a slow or unreachable host doesn't hold
+//! the others back. How each host's last run went is kept in `fleet.json` in the state directory,
+//! and `znapper fleet status` reports it for the whole fleet.
+
+use crate::anchors::state_dir;
+use crate::buffer::BufferOpt;
+use crate::config::config_dir;
+use crate::lock::{self, LockOpt};
+use crate::pull::{self, PullOpt};
+use crate::ssh::SshOpt;
+use crate::status::format_lag;
+use crate::stream::RecvPropsOpt;
+use crate::{process, OutputFormat};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn};
+
+/// How many hosts are pulled from at once, unless fleet.toml or --jobs say otherwise.
+const JOBS: usize = 4;
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct FleetRunOpt {
+    /// Only pull from these hosts of fleet.toml.
+    hosts: Vec<String>,
+    /// Pull from this many hosts at once.
+    #[structopt(long = "jobs")]
+    jobs: Option<usize>,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+    #[structopt(flatten)]
+    pub lock: LockOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct FleetStatusOpt {
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) enum FleetAction {
+    /// Pull from each host of fleet.toml.
+    #[structopt(name = "run")]
+    Run(FleetRunOpt),
+    /// Show how the last run of each host of fleet.toml went.
+    #[structopt(name = "status")]
+    Status(FleetStatusOpt),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Host {
+    /// user@host, or the name of a target in targets.toml.
+    ssh: String,
+    /// The datasets of the host to pull.
+    datasets: Vec<String>,
+    /// Where to receive them, instead of the fleet's `to`.
+    #[serde(default)]
+    to: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fleet {
+    #[serde(default)]
+    jobs: Option<usize>,
+    /// Where to receive the datasets of each host - %hostname% is replaced with the name of the
+    /// host in fleet.toml, and %dataset% with the dataset.
+    #[serde(default)]
+    to: Option<String>,
+    #[serde(default)]
+    host: BTreeMap<String, Host>,
+}
+
+impl Fleet {
+    fn load() -> Result<Self, ()> {
+        let path = config_dir().join("fleet.toml");
+        match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).map_err(|e| {
+                error!("Failed to parse {:?} -> {}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Fleet::default()),
+            Err(e) => {
+                error!("Failed to read {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    /// The local dataset the `dataset` of `name` is received into.
+    fn destination(&self, name: &str, host: &Host, dataset: &str) -> Result<String, ()> {
+        let template = match host.to.as_deref().or(self.to.as_deref()) {
+            Some(t) => t,
+            None => {
+                error!(
+                    "No to for {} in fleet.toml, to receive {} into",
+                    name, dataset
+                );
+                return Err(());
+            }
+        };
+        let to = template
+            .replace("%hostname%", name)
+            .replace("%dataset%", dataset);
+        if to.contains('%') {
+            error!("Unknown template in destination {}", template);
+            return Err(());
+        }
+        Ok(to)
+    }
+
+    /// The hosts of `names`, or every host.
+    fn hosts<'a>(&'a self, names: &[String]) -> Result<Vec<(&'a str, &'a Host)>, ()> {
+        if let Some(unknown) = names.iter().find(|n| !self.host.contains_key(n.as_str())) {
+            error!("No host {} in fleet.toml", unknown);
+            return Err(());
+        }
+        Ok(self
+            .host
+            .iter()
+            .filter(|(name, _)| names.is_empty() || names.contains(name))
+            .map(|(name, host)| (name.as_str(), host))
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HostState {
+    last_run: i64,
+    #[serde(default)]
+    last_success: Option<i64>,
+    /// How many runs have failed, ever.
+    #[serde(default)]
+    failures: u64,
+    /// The datasets whose last pull failed.
+    #[serde(default)]
+    failed: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FleetStore {
+    hosts: BTreeMap<String, HostState>,
+}
+
+impl FleetStore {
+    fn path() -> PathBuf {
+        state_dir().join("fleet.json")
+    }
+
+    fn load() -> Result<Self, ()> {
+        let path = Self::path();
+        match File::open(&path) {
+            Ok(f) => serde_json::from_reader(f).map_err(|e| {
+                error!("Failed to parse {:?} -> {:?}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(FleetStore::default()),
+            Err(e) => {
+                error!("Failed to open {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), ()> {
+        let path = Self::path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                error!("Failed to create state dir {:?} -> {:?}", parent, e);
+            })?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        let f = File::create(&tmp).map_err(|e| {
+            error!("Failed to create {:?} -> {:?}", tmp, e);
+        })?;
+        serde_json::to_writer_pretty(&f, self).map_err(|e| {
+            error!("Failed to write {:?} -> {:?}", tmp, e);
+        })?;
+        fs::rename(&tmp, &path).map_err(|e| {
+            error!("Failed to replace {:?} -> {:?}", path, e);
+        })
+    }
+
+    /// Record that the run of `host` at `now` failed to pull `failed`.
+    fn record(&mut self, host: &str, now: i64, failed: Vec<String>) {
+        let previous = self.hosts.get(host);
+        let last_success = if failed.is_empty() {
+            Some(now)
+        } else {
+            previous.and_then(|h| h.last_success)
+        };
+        let failures = previous.map(|h| h.failures).unwrap_or(0) + u64::from(!failed.is_empty());
+        self.hosts.insert(
+            host.to_string(),
+            HostState {
+                last_run: now,
+                last_success,
+                failures,
+                failed,
+            },
+        );
+    }
+}
+
+/// The locks of a fleet run - the fleet, and the pool of every destination.
+pub(crate) fn locks(opt: &FleetRunOpt) -> Vec<String> {
+    let mut locks = vec![lock::job("fleet")];
+    if let Ok(fleet) = Fleet::load() {
+        for (name, host) in fleet.hosts(&opt.hosts).unwrap_or_default() {
+            for dataset in host.datasets.iter() {
+                if let Ok(to) = fleet.destination(name, host, dataset) {
+                    let pool = lock::pool(&to);
+                    if !locks.contains(&pool) {
+                        locks.push(pool);
+                    }
+                }
+            }
+        }
+    }
+    locks
+}
+
+/// Pull each dataset of `host` in turn, returning those that failed.
+fn pull_host(dry: bool, fleet: &Fleet, name: &str, host: &Host) -> Vec<String> {
+    info!("Pulling {} datasets from {}", host.datasets.len(), name);
+    let mut failed = Vec::new();
+    for dataset in host.datasets.iter() {
+        if process::cancelled() {
+            failed.push(dataset.clone());
+            continue;
+        }
+        let res = fleet.destination(name, host, dataset).and_then(|to_pool| {
+            pull::do_pull(&PullOpt {
+                remote_ssh: host.ssh.clone(),
+                ssh: SshOpt::default(),
+                buffer: BufferOpt::default(),
+                from_pool: dataset.clone(),
+                to_pool,
+                recv: RecvPropsOpt::default(),
+                dryrun: dry,
+                lock: LockOpt::default(),
+            })
+        });
+        if res.is_err() {
+            failed.push(dataset.clone());
+        }
+    }
+    failed
+}
+
+pub(crate) fn do_fleet_run(opt: &FleetRunOpt) {
+    debug!("do_fleet_run");
+
+    let fleet = match Fleet::load() {
+        Ok(f) => f,
+        Err(_) => return,
+    };
+    let hosts = match fleet.hosts(&opt.hosts) {
+        Ok(h) => h,
+        Err(_) => return,
+    };
+    if hosts.is_empty() {
+        warn!("No hosts in fleet.toml to pull from");
+        return;
+    }
+    let jobs = opt.jobs.or(fleet.jobs).unwrap_or(JOBS);
+    info!("Pulling from {} hosts, {} at a time", hosts.len(), jobs);
+
+    let now = OffsetDateTime::now_utc().timestamp();
+    let next = AtomicUsize::new(0);
+    let done: Vec<(&str, Vec<String>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, hosts.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    while let Some((name, host)) = hosts.get(next.fetch_add(1, Ordering::SeqCst)) {
+                        done.push((*name, pull_host(opt.dryrun, &fleet, name, host)));
+                    }
+                    done
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_default())
+            .collect()
+    });
+
+    let failed = done.iter().filter(|(_, failed)| !failed.is_empty()).count();
+    // A worker that panicked leaves its hosts unrecorded, and they count as failed.
+    let missing = hosts.len() - done.len();
+    if failed + missing > 0 {
+        error!(
+            "Pulls from {} of {} hosts failed",
+            failed + missing,
+            hosts.len()
+        );
+    } else {
+        info!("Pulled from all {} hosts", hosts.len());
+    }
+
+    if opt.dryrun {
+        return;
+    }
+    let mut store = match FleetStore::load() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    for (name, failed) in done {
+        store.record(name, now, failed);
+    }
+    let _ = store.save();
+}
+
+#[derive(Debug, Serialize)]
+struct HostStatus {
+    host: String,
+    ssh: String,
+    datasets: usize,
+    /// The datasets whose last pull failed.
+    failed: Vec<String>,
+    last_run: Option<String>,
+    /// Seconds since the last run that pulled every dataset.
+    lag: Option<i64>,
+    failures: u64,
+}
+
+fn format_ts(ts: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(ts).format("%Y-%m-%dT%H:%M:%SZ")
+}
+
+fn host_status(name: &str, host: &Host, state: Option<&HostState>, now: i64) -> HostStatus {
+    HostStatus {
+        host: name.to_string(),
+        ssh: host.ssh.clone(),
+        datasets: host.datasets.len(),
+        failed: state.map(|s| s.failed.clone()).unwrap_or_default(),
+        last_run: state.map(|s| format_ts(s.last_run)),
+        lag: state.and_then(|s| s.last_success).map(|ts| now - ts),
+        failures: state.map(|s| s.failures).unwrap_or(0),
+    }
+}
+
+pub(crate) fn do_fleet_status(opt: &FleetStatusOpt) {
+    debug!("do_fleet_status");
+
+    let (fleet, store) = match (Fleet::load(), FleetStore::load()) {
+        (Ok(f), Ok(s)) => (f, s),
+        _ => return,
+    };
+    let now = OffsetDateTime::now_utc().timestamp();
+    let report: Vec<_> = fleet
+        .host
+        .iter()
+        .map(|(name, host)| host_status(name, host, store.hosts.get(name), now))
+        .collect();
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&report) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise fleet status -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for host in report.iter() {
+                println!(
+                    "{}\t{}\t{}\tlag={}\tlast={}",
+                    host.host,
+                    host.ssh,
+                    match (host.last_run.as_ref(), host.failed.len()) {
+                        (None, _) => "never".to_string(),
+                        (Some(_), 0) => format!("ok {0}/{0}", host.datasets),
+                        (Some(_), failed) => format!(
+                            "failed {}/{} ({})",
+                            host.datasets - failed.min(host.datasets),
+                            host.datasets,
+                            host.failed.join(", ")
+                        ),
+                    },
+                    host.lag
+                        .map(format_lag)
+                        .unwrap_or_else(|| "never".to_string()),
+                    host.last_run.as_deref().unwrap_or("never")
+                );
+            }
+            let behind = report
+                .iter()
+                .filter(|h| h.last_run.is_none() || !h.failed.is_empty())
+                .count();
+            println!(
+                "{} hosts, {} ok, {} failed or never run",
+                report.len(),
+                report.len() - behind,
+                behind
+            );
+        }
+    }
+}
+
+pub(crate) fn do_fleet(action: &FleetAction) {
+    match action {
+        FleetAction::Run(opt) => do_fleet_run(opt),
+        FleetAction::Status(opt) => do_fleet_status(opt),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fleet_destinations_and_state() {
+        let fleet: Fleet = toml::from_str(
+            r#"
+            to = "backups/%hostname%/%dataset%"
+
+            [host.web1]
+            ssh = "backup@web1"
+            datasets = ["tank/www"]
+
+            [host.db1]
+            ssh = "db1"
+            datasets = ["nvme/pg"]
+            to = "fast/%dataset%"
+            "#,
+        )
+        .unwrap();
+        let web1 = &fleet.host["web1"];
+        assert_eq!(
+            fleet.destination("web1", web1, "tank/www").unwrap(),
+            "backups/web1/tank/www"
+        );
+        let db1 = &fleet.host["db1"];
+        assert_eq!(
+            fleet.destination("db1", db1, "nvme/pg").unwrap(),
+            "fast/nvme/pg"
+        );
+        assert!(fleet.hosts(&["web2".to_string()]).is_err());
+        assert_eq!(fleet.hosts(&[]).unwrap().len(), 2);
+
+        let mut store = FleetStore::default();
+        store.record("web1", 100, Vec::new());
+        store.record("web1", 200, vec!["tank/www".to_string()]);
+        let state = &store.hosts["web1"];
+        assert_eq!((state.last_success, state.failures), (Some(100), 1));
+        let status = host_status("web1", web1, Some(state), 300);
+        assert_eq!(status.lag, Some(200));
+        assert_eq!(status.failed, vec!["tank/www".to_string()]);
+    }
+}
@@ -0,0 +1,202 @@
+//! Consistency groups - sets of datasets, perhaps of different pools, that must be snapshotted,
+//! cleaned up and restored together to be coherent.
+//!
+//! `snapshot --group` snapshots the datasets of a group in one run, with the same name, tagged
+//! with the group. `snapshot_cleanup --group` cleans them up together, so that a snapshot name
+//! is destroyed from every dataset of the group or from none. `restore-group` restores them all
+//! to the same run.
+
+use crate::audit;
+use crate::config::Config;
+use crate::lock::{self, LockOpt};
+use crate::model::{Class, Snapshot};
+use crate::naming;
+use crate::{auto_snap_list, cleanup_expired};
+use crate::{clone_snap, filter_snap_list, get_property, rollback_snap, RUN_PROPERTY};
+use std::collections::BTreeSet;
+use structopt::StructOpt;
+use time::{OffsetDateTime, PrimitiveDateTime};
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct RestoreGroupOpt {
+    /// The name of a [group.<name>] in znapper.toml
+    group: String,
+    /// The local time to restore to, as YYYY-MM-DDTHH:MM[:SS]. The snapshot run closest to this
+    /// time that exists on every dataset of the group is used.
+    #[structopt(long = "at")]
+    at: String,
+    /// Roll every dataset back to the snapshot, destroying newer snapshots, instead of cloning.
+    #[structopt(long = "rollback")]
+    rollback: bool,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+    #[structopt(flatten)]
+    pub lock: LockOpt,
+}
+
+fn parse_at(at: &str) -> Result<PrimitiveDateTime, ()> {
+    PrimitiveDateTime::parse(at, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| PrimitiveDateTime::parse(format!("{}:00", at), "%Y-%m-%dT%H:%M:%S"))
+        .map_err(|e| {
+            error!("Invalid --at {} -> {:?}", at, e);
+        })
+}
+
+/// The datasets of the group `name`.
+pub(crate) fn datasets(name: &str) -> Result<Vec<String>, ()> {
+    let config = Config::load()?;
+    match config.group.get(name) {
+        Some(g) if !g.datasets.is_empty() => Ok(g.datasets.clone()),
+        _ => {
+            error!("No group {} (or it has no datasets) in znapper.toml", name);
+            Err(())
+        }
+    }
+}
+
+/// The locks of restore-group - of the pool of each dataset of the group.
+pub(crate) fn locks(opt: &RestoreGroupOpt) -> Vec<String> {
+    datasets(&opt.group)
+        .unwrap_or_default()
+        .iter()
+        .map(|ds| lock::pool(ds))
+        .collect()
+}
+
+/// The expired auto snapshots of each dataset of the group `name`, as snapshot_cleanup would
+/// destroy them, less those whose name another dataset of the group keeps.
+pub(crate) fn expired(
+    name: &str,
+    keep_hours: u32,
+    empty: bool,
+    now: OffsetDateTime,
+) -> Result<Vec<(String, Vec<Snapshot>)>, ()> {
+    let mut expired = Vec::new();
+    let mut kept = BTreeSet::new();
+    for dataset in datasets(name)? {
+        let remove = cleanup_expired(&dataset, keep_hours, empty, now)?;
+        kept.extend(
+            auto_snap_list(&dataset)?
+                .iter()
+                .filter(|snap| !remove.iter().any(|r| r.name() == snap.name()))
+                .map(|snap| snap.short_name().to_string()),
+        );
+        expired.push((dataset, remove));
+    }
+    for (_, remove) in expired.iter_mut() {
+        remove.retain(|snap| {
+            let keep = kept.contains(snap.short_name());
+            if keep {
+                debug!(
+                    "Keeping {} - the group {} keeps it elsewhere",
+                    snap.name(),
+                    name
+                );
+            }
+            !keep
+        });
+    }
+    Ok(expired)
+}
+
+pub(crate) fn do_restore_group(opt: &RestoreGroupOpt) {
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+    let group = match config.group.get(&opt.group) {
+        Some(g) if !g.datasets.is_empty() => g,
+        _ => {
+            error!(
+                "No group {} (or it has no datasets) in znapper.toml",
+                opt.group
+            );
+            return;
+        }
+    };
+    let at = match parse_at(&opt.at) {
+        Ok(a) => naming::assume_local(a),
+        Err(_) => return,
+    };
+
+    // The auto snapshots of the first dataset whose names are present on every dataset of the
+    // group.
+    let mut common: Option<Vec<Snapshot>> = None;
+    for dataset in group.datasets.iter() {
+        let snaps = match filter_snap_list(Class::Auto, dataset, false) {
+            Ok(snaps) => snaps,
+            Err(_) => return,
+        };
+        common = Some(match common {
+            None => snaps,
+            Some(c) => c
+                .into_iter()
+                .filter(|s| snaps.iter().any(|o| o.short_name() == s.short_name()))
+                .collect(),
+        });
+    }
+
+    let chosen = common.unwrap_or_default().into_iter().min_by_key(|snap| {
+        snap.timestamp()
+            .map(|t| (t - at).whole_seconds().abs())
+            .unwrap_or(i64::MAX)
+    });
+    let chosen = match chosen {
+        Some(c) => c.short_name().to_string(),
+        None => {
+            error!(
+                "No auto snapshot exists on every dataset of group {}",
+                opt.group
+            );
+            return;
+        }
+    };
+    info!("Closest common snapshot to {} -> {}", opt.at, chosen);
+
+    // Same name is not enough - they must all have been taken by the same run.
+    let mut run_ids = Vec::new();
+    for dataset in group.datasets.iter() {
+        match get_property(&format!("{}@{}", dataset, chosen), RUN_PROPERTY) {
+            Ok(id) => run_ids.push(id),
+            Err(_) => return,
+        }
+    }
+    run_ids.dedup();
+    match run_ids.as_slice() {
+        [id] if id == "-" => warn!(
+            "{} predates run ids - unable to verify the group was snapshot together",
+            chosen
+        ),
+        [id] => info!("All snapshots share run id {}", id),
+        _ => {
+            error!(
+                "Snapshots named {} were taken by different runs ({}) - refusing to restore",
+                chosen,
+                run_ids.join(", ")
+            );
+            return;
+        }
+    }
+
+    for dataset in group.datasets.iter() {
+        let snap = format!("{}@{}", dataset, chosen);
+        let res = if opt.rollback {
+            rollback_snap(opt.dryrun, &snap, audit::Reason::Restore)
+        } else {
+            clone_snap(
+                opt.dryrun,
+                &snap,
+                &format!("{}_restore_{}", dataset, chosen.trim_start_matches("auto_")),
+            )
+        };
+        if res.is_err() {
+            error!(
+                "Restore of group {} is incomplete - {} failed, the datasets before it were restored",
+                opt.group, dataset
+            );
+            return;
+        }
+    }
+    info!("Restored group {} to {}", opt.group, chosen);
+}
@@ -0,0 +1,190 @@
+//! A record of what znapper has done, appended to `history.jsonl` in the state directory.
+//!
+//! Every snapshot created and destroyed, and every send with its anchors, size, duration and
+//! outcome, is one json line. Unlike the other state files nothing is ever rewritten, so the record
+//! is a history rather than a current state - `znapper history` shows it, and it can be reasoned
+//! from rather than guessing from the names of the snapshots that are left.
+
+use crate::anchors::state_dir;
+use crate::OutputFormat;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{error, warn};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct HistoryOpt {
+    /// Only show what happened to this dataset (and its descendants), or sends to this
+    /// destination.
+    dataset: Option<String>,
+    /// Only show the newest this many events.
+    #[structopt(long = "limit")]
+    limit: Option<usize>,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum Kind {
+    SnapshotCreate {
+        snapshot: String,
+        recursive: bool,
+    },
+    SnapshotDestroy {
+        snapshot: String,
+    },
+    Send {
+        /// What the send was to, as its progress is labelled.
+        label: String,
+        /// The anchor of an incremental, none for a full send.
+        from: Option<String>,
+        /// The snapshot the root of the stream was sent up to.
+        to: Option<String>,
+        bytes: u64,
+        duration_seconds: i64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Event {
+    pub ts: i64,
+    #[serde(flatten)]
+    pub kind: Kind,
+    pub succeeded: bool,
+}
+
+impl Event {
+    /// Is this about `dataset` or something beneath it?
+    fn concerns(&self, dataset: &str) -> bool {
+        let within = |name: &str| {
+            name == dataset
+                || name
+                    .strip_prefix(dataset)
+                    .map(|rest| rest.starts_with(['/', '@', '#']))
+                    .unwrap_or(false)
+        };
+        match &self.kind {
+            Kind::SnapshotCreate { snapshot, .. } | Kind::SnapshotDestroy { snapshot } => {
+                within(snapshot)
+            }
+            Kind::Send { label, to, .. } => {
+                label.ends_with(&format!(" {}", dataset))
+                    || to.as_deref().map(within).unwrap_or(false)
+            }
+        }
+    }
+}
+
+fn path() -> PathBuf {
+    state_dir().join("history.jsonl")
+}
+
+/// Append an event to the history. Failing to is only a warning - the history is a record of
+/// what happened, and must not stop it happening.
+pub(crate) fn record(kind: Kind, succeeded: bool) {
+    crate::summary::record(&kind, succeeded);
+    let event = Event {
+        ts: OffsetDateTime::now_utc().timestamp(),
+        kind,
+        succeeded,
+    };
+    let path = path();
+    let line = match serde_json::to_string(&event) {
+        Ok(mut line) => {
+            line.push('\n');
+            line
+        }
+        Err(e) => {
+            warn!("failed to serialise history event -> {:?}", e);
+            return;
+        }
+    };
+    let res = path
+        .parent()
+        .map(fs::create_dir_all)
+        .unwrap_or(Ok(()))
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut f| f.write_all(line.as_bytes()));
+    if let Err(e) = res {
+        warn!("Unable to append to history {:?} -> {:?}", path, e);
+    }
+}
+
+/// Every event, oldest first. Lines that (no longer) parse are skipped.
+pub(crate) fn events() -> Result<Vec<Event>, ()> {
+    let path = path();
+    let f = match File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            error!("Failed to open {:?} -> {:?}", path, e);
+            return Err(());
+        }
+    };
+    Ok(BufReader::new(f)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect())
+}
+
+fn format_ts(ts: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(ts).format("%Y-%m-%dT%H:%M:%SZ")
+}
+
+pub(crate) fn do_history(opt: &HistoryOpt) {
+    let mut events = match events() {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    if let Some(dataset) = opt.dataset.as_deref() {
+        events.retain(|e| e.concerns(dataset));
+    }
+    if let Some(limit) = opt.limit {
+        let skip = events.len().saturating_sub(limit);
+        events.drain(..skip);
+    }
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&events) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise history -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for e in events {
+                let result = if e.succeeded { "ok" } else { "failed" };
+                let what = match &e.kind {
+                    Kind::SnapshotCreate {
+                        snapshot,
+                        recursive,
+                    } => format!(
+                        "create{}\t{}",
+                        if *recursive { " -r" } else { "" },
+                        snapshot
+                    ),
+                    Kind::SnapshotDestroy { snapshot } => format!("destroy\t{}", snapshot),
+                    Kind::Send {
+                        label,
+                        from,
+                        to,
+                        bytes,
+                        duration_seconds,
+                    } => format!(
+                        "{}\t{} -> {}\t{} bytes in {}s",
+                        label,
+                        from.as_deref().unwrap_or("full"),
+                        to.as_deref().unwrap_or("?"),
+                        bytes,
+                        duration_seconds
+                    ),
+                };
+                println!("{}\t{}\t{}", format_ts(e.ts), result, what);
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Immutability windows for backup destinations.
+//!
+//! A dataset listed under `[immutable]` in `znapper.toml` has its snapshots (and those of its
+//! descendants) protected from destroy and rollback until they are older than the window. Every
+//! destroy and rollback znapper does is checked here first, so a compromised source, or a
+//! mistaken retention policy, can't be used to wipe recent backups.
+
+use crate::config::Config;
+use crate::get_property;
+use crate::plan;
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use std::sync::OnceLock;
+use time::OffsetDateTime;
+use tracing::{debug, error};
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// The configured windows, loaded once. If the config can't be read this is an error, and every
+/// check fails closed.
+fn windows() -> Result<&'static [(String, u32)], ()> {
+    static WINDOWS: OnceLock<Result<Vec<(String, u32)>, ()>> = OnceLock::new();
+    WINDOWS
+        .get_or_init(|| {
+            Config::load().map(|config| {
+                config
+                    .immutable
+                    .into_iter()
+                    .map(|(dataset, immutable)| (dataset, immutable.days))
+                    .collect()
+            })
+        })
+        .as_ref()
+        .map(|w| w.as_slice())
+        .map_err(|_| error!("Unable to load immutability windows"))
+}
+
+/// The longest window that covers `dataset` - set on it, an ancestor, or (as -r operations
+/// reach them) a descendant.
+pub(crate) fn window_for(dataset: &str) -> Result<Option<u32>, ()> {
+    Ok(windows()?
+        .iter()
+        .filter(|(protected, _)| {
+            dataset == protected
+                || dataset
+                    .strip_prefix(protected.as_str())
+                    .map(|rest| rest.starts_with('/'))
+                    .unwrap_or(false)
+                || protected
+                    .strip_prefix(dataset)
+                    .map(|rest| rest.starts_with('/'))
+                    .unwrap_or(false)
+        })
+        .map(|(_, days)| *days)
+        .max())
+}
+
+pub(crate) fn format_ts(ts: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(ts).format("%Y-%m-%dT%H:%M:%SZ")
+}
+
+/// When `snap_name` leaves its immutability window, if it is still in one.
+pub(crate) fn locked_until(snap_name: &str) -> Result<Option<i64>, ()> {
+    let dataset = match snap_name.split_once('@') {
+        Some((dataset, _)) => dataset,
+        // Bookmarks hold no data.
+        None => return Ok(None),
+    };
+    let days = match window_for(dataset)? {
+        Some(days) => days,
+        None => return Ok(None),
+    };
+    let creation: i64 = get_property(snap_name, "creation")?.parse().map_err(|e| {
+        error!("Invalid creation of {} -> {:?}", snap_name, e);
+    })?;
+    let until = creation + days as i64 * SECS_PER_DAY;
+    debug!("{} is immutable until {}", snap_name, format_ts(until));
+    if OffsetDateTime::now_utc().timestamp() < until {
+        Ok(Some(until))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Refuse to destroy `snap_name` while it is in an immutability window, and list it in the
+/// plan with when the window ends.
+pub(crate) fn check_destroy(snap_name: &str) -> Result<(), ()> {
+    match locked_until(snap_name)? {
+        Some(until) => {
+            error!(
+                "Refusing to destroy {} - it is immutable until {}",
+                snap_name,
+                format_ts(until)
+            );
+            plan::immutable(snap_name, &format_ts(until));
+            Err(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Refuse to roll back to `snap_name` if any of the newer snapshots that would be destroyed
+/// are in an immutability window.
+pub(crate) fn check_rollback(snap_name: &str) -> Result<(), ()> {
+    let dataset = match snap_name.split_once('@') {
+        Some((dataset, _)) => dataset,
+        None => return Ok(()),
+    };
+    if window_for(dataset)?.is_none() {
+        return Ok(());
+    }
+
+    let output = privilege::zfs()
+        .arg("list")
+        .arg("-H")
+        .arg("-p")
+        .arg("-t")
+        .arg("snapshot")
+        .arg("-o")
+        .arg("name,createtxg")
+        .arg("-d")
+        .arg("1")
+        .arg(dataset)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("snapshot list failed -> {:?}", e);
+        })?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let snaps: Vec<(&str, u64)> = stdout
+        .lines()
+        .filter_map(|line| {
+            let (name, txg) = line.split_once('\t')?;
+            Some((name, txg.parse().ok()?))
+        })
+        .collect();
+    let target_txg = match snaps.iter().find(|(name, _)| *name == snap_name) {
+        Some((_, txg)) => *txg,
+        None => {
+            error!("No such snapshot {}", snap_name);
+            return Err(());
+        }
+    };
+
+    for (name, _) in snaps.iter().filter(|(_, txg)| *txg > target_txg) {
+        if let Some(until) = locked_until(name)? {
+            error!(
+                "Refusing to roll back to {} - {} is immutable until {}",
+                snap_name,
+                name,
+                format_ts(until)
+            );
+            plan::immutable(name, &format_ts(until));
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
+/// Destroying a dataset destroys its snapshots, so it is never allowed within an immutability
+/// window.
+pub(crate) fn check_destroy_dataset(dataset: &str) -> Result<(), ()> {
+    match window_for(dataset)? {
+        Some(days) => {
+            error!(
+                "Refusing to destroy {} - it has a {} day immutability window",
+                dataset, days
+            );
+            Err(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// A forced receive can destroy snapshots that are no longer on the source, so it is never
+/// allowed into a dataset with an immutability window.
+pub(crate) fn check_force_recv(dataset: &str) -> Result<(), ()> {
+    match window_for(dataset)? {
+        Some(days) => {
+            error!(
+                "Refusing to force receive into {} - it has a {} day immutability window",
+                dataset, days
+            );
+            Err(())
+        }
+        None => Ok(()),
+    }
+}
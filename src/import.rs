@@ -0,0 +1,556 @@
+//! `znapper import-config` - translate a sanoid.conf, and the syncoid lines of a crontab, into
+//! sync jobs for `znapper.toml`.
+//!
+//! sanoid keeps hourly, daily, weekly, monthly and yearly tiers where znapper keeps hours and
+//! days, so the longer tiers become the days they cover - more snapshots are kept than sanoid
+//! kept, never fewer. What a job can't say, such as the retention of a child or a child left out
+//! of snapshots, comes out as the `zfs set` of the property that says it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use structopt::StructOpt;
+use tracing::{debug, error, info};
+
+/// What the configuration is imported from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ImportFrom {
+    Sanoid,
+}
+
+impl FromStr for ImportFrom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sanoid" => Ok(ImportFrom::Sanoid),
+            _ => Err(format!("Can not import from {} - only from sanoid", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct ImportConfigOpt {
+    /// sanoid
+    #[structopt(long = "from")]
+    from: ImportFrom,
+    /// The sanoid.conf to import, ie /etc/sanoid/sanoid.conf
+    path: String,
+    /// A file of syncoid command lines to import as the destinations of the jobs, such as a
+    /// crontab or /etc/cron.d/syncoid. May be repeated.
+    #[structopt(long = "syncoid")]
+    syncoid: Vec<String>,
+    /// Write the configuration to this file, rather than print it. An existing file is left
+    /// alone.
+    #[structopt(short = "o", long = "output")]
+    output: Option<String>,
+}
+
+/// The sections of an ini file, each of its keys and values.
+type Sections = BTreeMap<String, BTreeMap<String, String>>;
+
+fn parse_ini(text: &str) -> Sections {
+    let mut sections = Sections::new();
+    let mut current: Option<String> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = name.trim().to_string();
+            sections.entry(name.clone()).or_default();
+            current = Some(name);
+        } else if let (Some(section), Some((key, value))) = (current.as_ref(), line.split_once('='))
+        {
+            sections
+                .entry(section.clone())
+                .or_default()
+                .insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    sections
+}
+
+/// The settings of `dataset` - those of template_default, then of each of its templates in turn,
+/// then its own, each overriding the last.
+fn resolve(sections: &Sections, dataset: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    let mut settings = BTreeMap::new();
+    let templates = dataset
+        .get("use_template")
+        .map(|t| t.split(',').map(str::trim).collect::<Vec<_>>())
+        .unwrap_or_default();
+    for template in std::iter::once("default").chain(templates) {
+        if let Some(values) = sections.get(&format!("template_{}", template)) {
+            settings.extend(values.clone());
+        }
+    }
+    settings.extend(dataset.clone());
+    settings.remove("use_template");
+    settings
+}
+
+fn yes(settings: &BTreeMap<String, String>, key: &str, default: bool) -> bool {
+    match settings.get(key).map(|v| v.to_ascii_lowercase()) {
+        Some(v) => v == "yes" || v == "1" || v == "true" || v == "zfs",
+        None => default,
+    }
+}
+
+fn count(settings: &BTreeMap<String, String>, key: &str) -> u32 {
+    settings.get(key).and_then(|v| v.parse().ok()).unwrap_or(0)
+}
+
+/// sanoid's tiers as hours and days to keep, with a note of the tiers that were stretched into
+/// days.
+fn retention(settings: &BTreeMap<String, String>) -> (u32, Option<u32>, Option<String>) {
+    let hours = count(settings, "hourly");
+    let tiers = [
+        ("daily", count(settings, "daily"), 1),
+        ("weekly", count(settings, "weekly"), 7),
+        ("monthly", count(settings, "monthly"), 31),
+        ("yearly", count(settings, "yearly"), 366),
+    ];
+    let days = tiers.iter().map(|(_, n, d)| n * d).max().unwrap_or(0);
+    let stretched: Vec<_> = tiers
+        .iter()
+        .skip(1)
+        .filter(|(_, n, _)| *n > 0)
+        .map(|(tier, n, _)| format!("{}={}", tier, n))
+        .collect();
+    let note = if stretched.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "sanoid kept {}, which znapper keeps as {} daily snapshots",
+            stretched.join(" and "),
+            days
+        ))
+    };
+    (hours, Some(days).filter(|d| *d > 0), note)
+}
+
+#[derive(Debug, Default)]
+struct Job {
+    source: String,
+    to: Vec<String>,
+    /// (target, metadata)
+    remotes: Vec<(String, String)>,
+    keep_hours: Option<u32>,
+    comments: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Imported {
+    jobs: BTreeMap<String, Job>,
+    /// The commands to run before the jobs - registering targets, setting properties and seeding
+    /// the destinations.
+    commands: Vec<String>,
+    /// What could not be imported.
+    skipped: Vec<String>,
+}
+
+/// A job name from a dataset name, as a bare toml key.
+fn job_name(dataset: &str) -> String {
+    dataset
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// The job whose source is `dataset` or one of its ancestors.
+fn covering<'a>(jobs: &'a BTreeMap<String, Job>, dataset: &str) -> Option<&'a Job> {
+    jobs.values().find(|job| {
+        dataset == job.source
+            || dataset
+                .strip_prefix(job.source.as_str())
+                .map(|rest| rest.starts_with('/'))
+                .unwrap_or(false)
+    })
+}
+
+fn import_sanoid(sections: &Sections, imported: &mut Imported) {
+    for (dataset, values) in sections.iter() {
+        if dataset.starts_with("template_") || dataset == "version" {
+            continue;
+        }
+        let settings = resolve(sections, values);
+        let autosnap = yes(&settings, "autosnap", true);
+        let autoprune = yes(&settings, "autoprune", true);
+        let (hours, days, note) = retention(&settings);
+
+        // A child of a job is snapshotted and pruned by it, so only its own retention is left.
+        if covering(&imported.jobs, dataset).is_some() {
+            if !autosnap {
+                imported
+                    .commands
+                    .push(format!("zfs set com.sun:auto-snapshot=false {}", dataset));
+            } else if autoprune {
+                // Both, as whichever is left unset would be inherited from the parent.
+                imported.commands.push(format!(
+                    "zfs set org.znapper:keep={}h org.znapper:keep-daily={} {}",
+                    hours,
+                    days.unwrap_or(0),
+                    dataset
+                ));
+            } else {
+                imported.skipped.push(format!(
+                    "autoprune=no of {} - it is pruned with its parent",
+                    dataset
+                ));
+            }
+            continue;
+        }
+        if !autosnap {
+            imported
+                .skipped
+                .push(format!("{} - it has autosnap=no", dataset));
+            continue;
+        }
+
+        let mut job = Job {
+            source: dataset.clone(),
+            ..Default::default()
+        };
+        if autoprune {
+            job.keep_hours = Some(hours);
+            if let Some(days) = days {
+                imported.commands.push(format!(
+                    "zfs set org.znapper:keep-daily={} {}",
+                    days, dataset
+                ));
+            }
+            job.comments.extend(note);
+        } else {
+            job.comments
+                .push("sanoid had autoprune=no, so nothing is pruned".to_string());
+        }
+        if yes(&settings, "process_children_only", false) {
+            job.comments.push(format!(
+                "sanoid left {} itself out - znapper snapshots it with its children",
+                dataset
+            ));
+        } else if !yes(&settings, "recursive", false) {
+            job.comments.push(format!(
+                "sanoid only snapshotted {} itself - znapper snapshots its children too, less \
+                 those set com.sun:auto-snapshot=false",
+                dataset
+            ));
+        }
+        imported.jobs.insert(job_name(dataset), job);
+    }
+}
+
+/// syncoid options that take a value.
+const SYNCOID_VALUED: &[&str] = &[
+    "--sshport",
+    "--sshkey",
+    "--sshcipher",
+    "-c",
+    "--sshoption",
+    "-o",
+    "--source-bwlimit",
+    "--target-bwlimit",
+    "--compress",
+    "--identifier",
+    "--exclude",
+    "--exclude-datasets",
+    "--exclude-snaps",
+    "--include-snaps",
+    "--mbuffer-size",
+    "--pv-options",
+    "--sendoptions",
+    "--recvoptions",
+    "--insecure-direct-connection",
+];
+
+/// A syncoid command line.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Syncoid {
+    source: String,
+    target: String,
+    recursive: bool,
+    port: Option<String>,
+    key: Option<String>,
+}
+
+/// The syncoid command of a line - a crontab entry, or a command as is - if it has one.
+fn parse_syncoid(line: &str) -> Option<Syncoid> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    let mut words = line.split_whitespace();
+    words.find(|w| {
+        Path::new(w)
+            .file_name()
+            .map(|f| f == "syncoid")
+            .unwrap_or(false)
+    })?;
+
+    let mut syncoid = Syncoid::default();
+    let mut positional = Vec::new();
+    while let Some(word) = words.next() {
+        // The redirections and further commands of a cron line.
+        if word.contains('>') || word.starts_with('|') || word == "&&" || word == ";" {
+            break;
+        }
+        if !word.starts_with('-') {
+            positional.push(word.to_string());
+            continue;
+        }
+        let (flag, inline) = match word.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (word, None),
+        };
+        let value = if SYNCOID_VALUED.contains(&flag) {
+            inline.or_else(|| words.next().map(str::to_string))
+        } else {
+            None
+        };
+        match flag {
+            "-r" | "--recursive" => syncoid.recursive = true,
+            "--sshport" => syncoid.port = value,
+            "--sshkey" => syncoid.key = value,
+            _ => {}
+        }
+    }
+    let mut positional = positional.into_iter();
+    syncoid.source = positional.next()?;
+    syncoid.target = positional.next()?;
+    Some(syncoid)
+}
+
+fn import_syncoid(syncoid: &Syncoid, imported: &mut Imported) {
+    if syncoid.source.contains(':') {
+        imported.skipped.push(format!(
+            "the pull of {} into {} - run znapper pull on this host instead",
+            syncoid.source, syncoid.target
+        ));
+        return;
+    }
+
+    let name = job_name(&syncoid.source);
+    let job = imported.jobs.entry(name.clone()).or_insert_with(|| Job {
+        source: syncoid.source.clone(),
+        comments: vec![format!(
+            "{} was not in sanoid.conf, so is snapshotted but not pruned",
+            syncoid.source
+        )],
+        ..Default::default()
+    });
+    if !syncoid.recursive {
+        job.comments.push(format!(
+            "syncoid sent only {} itself to {} - znapper replicates its children too",
+            syncoid.source, syncoid.target
+        ));
+    }
+
+    match syncoid.target.split_once(':') {
+        Some((ssh, dataset)) => {
+            let host = ssh.rsplit('@').next().unwrap_or(ssh);
+            let metadata = format!("/var/lib/znapper/{}-{}.json", name, job_name(host));
+            let mut add = format!(
+                "znapper target add {} --ssh {} --dataset {}",
+                host, ssh, dataset
+            );
+            if let Some(port) = syncoid.port.as_ref() {
+                add.push_str(&format!(" --ssh-port {}", port));
+            }
+            if let Some(key) = syncoid.key.as_ref() {
+                add.push_str(&format!(" --ssh-identity {}", key));
+            }
+            imported.commands.push(add);
+            imported.commands.push(format!(
+                "znapper init_remote {} {} --remote {}",
+                syncoid.source, metadata, host
+            ));
+            job.remotes.push((host.to_string(), metadata));
+        }
+        None => {
+            imported.commands.push(format!(
+                "znapper init_repl {} {}",
+                syncoid.source, syncoid.target
+            ));
+            job.to.push(syncoid.target.clone());
+        }
+    }
+}
+
+fn quote(s: &str) -> String {
+    toml::Value::String(s.to_string()).to_string()
+}
+
+fn render(imported: &Imported, path: &str) -> String {
+    let mut out = format!("# Imported from {} by znapper import-config.\n", path);
+    if !imported.commands.is_empty() {
+        out.push_str("#\n# Before the first sync, register the remotes, set the properties and seed the destinations:\n");
+        for command in imported.commands.iter() {
+            out.push_str(&format!("#   {}\n", command));
+        }
+    }
+    if !imported.skipped.is_empty() {
+        out.push_str("#\n# Not imported:\n");
+        for skipped in imported.skipped.iter() {
+            out.push_str(&format!("#   {}\n", skipped));
+        }
+    }
+    if let Some(name) = imported.jobs.keys().next() {
+        out.push_str(&format!(
+            "#\n# Then run each job from cron in place of sanoid and syncoid, ie\n#   0 * * * * root znapper sync {}\n",
+            name
+        ));
+    }
+
+    for (name, job) in imported.jobs.iter() {
+        out.push_str(&format!("\n[job.{}]\n", name));
+        for comment in job.comments.iter() {
+            out.push_str(&format!("# {}\n", comment));
+        }
+        out.push_str(&format!("source = {}\n", quote(&job.source)));
+        if !job.to.is_empty() {
+            let to: Vec<_> = job.to.iter().map(|t| quote(t)).collect();
+            out.push_str(&format!("to = [{}]\n", to.join(", ")));
+        }
+        if let Some(hours) = job.keep_hours {
+            out.push_str(&format!("keep_hours = {}\n", hours));
+        }
+        for (target, metadata) in job.remotes.iter() {
+            out.push_str(&format!("\n[[job.{}.remote]]\n", name));
+            out.push_str(&format!("target = {}\n", quote(target)));
+            out.push_str(&format!("metadata = {}\n", quote(metadata)));
+        }
+    }
+    out
+}
+
+fn import(sanoid: &str, syncoid: &[String], path: &str) -> String {
+    let mut imported = Imported::default();
+    import_sanoid(&parse_ini(sanoid), &mut imported);
+    for line in syncoid.iter() {
+        if let Some(syncoid) = parse_syncoid(line) {
+            debug!(?syncoid);
+            import_syncoid(&syncoid, &mut imported);
+        }
+    }
+    render(&imported, path)
+}
+
+pub(crate) fn do_import_config(opt: &ImportConfigOpt) {
+    debug!("do_import_config from {:?}", opt.from);
+
+    let sanoid = match fs::read_to_string(&opt.path) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to read {} -> {:?}", opt.path, e);
+            return;
+        }
+    };
+    let mut syncoid = Vec::new();
+    for path in opt.syncoid.iter() {
+        match fs::read_to_string(path) {
+            Ok(s) => syncoid.extend(s.lines().map(str::to_string)),
+            Err(e) => {
+                error!("Failed to read {} -> {:?}", path, e);
+                return;
+            }
+        }
+    }
+    let config = import(&sanoid, &syncoid, &opt.path);
+
+    match opt.output.as_ref() {
+        Some(output) => {
+            let written = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(output)
+                .and_then(|mut f| f.write_all(config.as_bytes()));
+            match written {
+                Ok(()) => info!("Wrote {} - merge its jobs into znapper.toml", output),
+                Err(e) => error!("Failed to write {} -> {:?}", output, e),
+            }
+        }
+        None => {
+            if let Err(e) = io::stdout().write_all(config.as_bytes()) {
+                error!("unable to write the configuration -> {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const SANOID: &str = "
+[tank/data]
+    use_template = production
+    recursive = yes
+
+[tank/data/scratch]
+    use_template = scratch
+
+[tank/data/tmp]
+    autosnap = no
+
+[template_production]
+    hourly = 36
+    daily = 30
+    monthly = 3
+    autoprune = yes
+
+[template_scratch]
+    hourly = 6
+";
+
+    #[test]
+    fn sanoid_and_syncoid_become_jobs() {
+        let syncoid = [
+            "0 * * * * root /usr/sbin/syncoid -r --sshport 2222 tank/data root@backup:pool/data > /dev/null 2>&1".to_string(),
+            "30 * * * * root /usr/sbin/syncoid --no-sync-snap -r tank/data backup/data".to_string(),
+        ];
+        let text = import(SANOID, &syncoid, "sanoid.conf");
+        let config: Config = toml::from_str(&text).unwrap();
+
+        assert_eq!(config.job.len(), 1);
+        let job = &config.job["tank_data"];
+        assert_eq!(job.source, "tank/data");
+        assert_eq!(job.keep_hours, Some(36));
+        assert_eq!(job.to, vec!["backup/data"]);
+        assert_eq!(job.remote[0].target, "backup");
+
+        assert!(text.contains("zfs set org.znapper:keep-daily=93 tank/data\n"));
+        assert!(text
+            .contains("zfs set org.znapper:keep=6h org.znapper:keep-daily=0 tank/data/scratch\n"));
+        assert!(text.contains("zfs set com.sun:auto-snapshot=false tank/data/tmp\n"));
+        assert!(text.contains(
+            "znapper target add backup --ssh root@backup --dataset pool/data --ssh-port 2222\n"
+        ));
+    }
+
+    #[test]
+    fn syncoid_lines_are_parsed() {
+        assert_eq!(
+            parse_syncoid("syncoid --compress=lz4 --sshkey /root/.ssh/id tank/a tank/b"),
+            Some(Syncoid {
+                source: "tank/a".to_string(),
+                target: "tank/b".to_string(),
+                recursive: false,
+                port: None,
+                key: Some("/root/.ssh/id".to_string()),
+            })
+        );
+        assert_eq!(parse_syncoid("# 0 * * * * syncoid tank/a tank/b"), None);
+        assert_eq!(parse_syncoid("0 * * * * root zfs-prune-snapshots"), None);
+    }
+}
@@ -0,0 +1,243 @@
+//! Machine wide inventory of pools and datasets, for feeding asset / CMDB systems.
+
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use crate::OutputFormat;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct InventoryOpt {
+    /// If pools are listed, only these are reported, otherwise every imported pool is.
+    pools: Vec<String>,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+#[derive(Serialize)]
+struct Inventory {
+    generated: String,
+    pools: Vec<PoolInventory>,
+}
+
+#[derive(Serialize)]
+struct PoolInventory {
+    name: String,
+    health: String,
+    size: u64,
+    allocated: u64,
+    free: u64,
+    datasets: Vec<DatasetInventory>,
+}
+
+#[derive(Default, Serialize)]
+struct SnapshotCounts {
+    auto: usize,
+    repl: usize,
+    other: usize,
+}
+
+#[derive(Serialize)]
+struct DatasetInventory {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    used: u64,
+    available: u64,
+    encryption: String,
+    readonly: bool,
+    mountpoint: String,
+    snapshots: SnapshotCounts,
+    /// "source" or "destination" when the dataset carries repl_ anchors.
+    replication: Option<String>,
+    /// The creation time of the newest repl_ anchor on this dataset.
+    last_backup: Option<String>,
+}
+
+fn zfs_lines(bin: &str, args: &[&str]) -> Result<Vec<String>, ()> {
+    let stdout = privilege::command(bin)
+        .args(args)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("{} {} failed -> {:?}", bin, args.join(" "), e);
+        })
+        .and_then(|output| {
+            String::from_utf8(output.stdout).map_err(|e| {
+                error!("{} output contains invalid utf8 -> {:?}", bin, e);
+            })
+        })?;
+
+    let lines: Vec<_> = stdout
+        .split('\n')
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect();
+    debug!("{:?}", lines);
+    Ok(lines)
+}
+
+fn format_ts(ts: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(ts).format("%Y-%m-%dT%H:%M:%SZ")
+}
+
+fn gather(pools: &[String]) -> Result<Inventory, ()> {
+    let mut args = vec!["list", "-H", "-p", "-o", "name,health,size,alloc,free"];
+    args.extend(pools.iter().map(String::as_str));
+    let pool_lines = zfs_lines("zpool", &args)?;
+
+    let mut inventory = Inventory {
+        generated: format_ts(OffsetDateTime::now_utc().timestamp()),
+        pools: Vec::new(),
+    };
+
+    for line in pool_lines {
+        let f: Vec<_> = line.split('\t').collect();
+        if f.len() < 5 {
+            continue;
+        }
+        let pool_name = f[0];
+
+        let dataset_lines = zfs_lines(
+            "zfs",
+            &[
+                "list",
+                "-H",
+                "-p",
+                "-r",
+                "-t",
+                "filesystem,volume",
+                "-o",
+                "name,type,used,avail,encryption,readonly,mountpoint",
+                pool_name,
+            ],
+        )?;
+
+        // Snapshots and bookmarks in one pass, keyed by the dataset they belong to.
+        let mut counts: BTreeMap<String, SnapshotCounts> = BTreeMap::new();
+        let mut last_repl: BTreeMap<String, i64> = BTreeMap::new();
+        for snap_line in zfs_lines(
+            "zfs",
+            &[
+                "list",
+                "-H",
+                "-p",
+                "-r",
+                "-t",
+                "snapshot,bookmark",
+                "-o",
+                "name,creation",
+                pool_name,
+            ],
+        )? {
+            let mut lsplit = snap_line.split('\t');
+            let (name, creation) = match (lsplit.next(), lsplit.next()) {
+                (Some(n), Some(c)) => (n, c.parse::<i64>().unwrap_or(0)),
+                _ => continue,
+            };
+            let (dataset, short, is_snap) = match name.split_once('@') {
+                Some((d, s)) => (d, s, true),
+                None => match name.split_once('#') {
+                    Some((d, s)) => (d, s, false),
+                    None => continue,
+                },
+            };
+
+            if short.starts_with("repl_") {
+                let last = last_repl.entry(dataset.to_string()).or_insert(creation);
+                *last = (*last).max(creation);
+            }
+            if is_snap {
+                let c = counts.entry(dataset.to_string()).or_default();
+                if short.starts_with("auto_") {
+                    c.auto += 1;
+                } else if short.starts_with("repl_") {
+                    c.repl += 1;
+                } else {
+                    c.other += 1;
+                }
+            }
+        }
+
+        let datasets = dataset_lines
+            .iter()
+            .filter_map(|dline| {
+                let d: Vec<_> = dline.split('\t').collect();
+                if d.len() < 7 {
+                    return None;
+                }
+                let readonly = d[5] == "on";
+                let last_backup = last_repl.get(d[0]).copied();
+                Some(DatasetInventory {
+                    name: d[0].to_string(),
+                    kind: d[1].to_string(),
+                    used: d[2].parse().unwrap_or(0),
+                    available: d[3].parse().unwrap_or(0),
+                    encryption: d[4].to_string(),
+                    readonly,
+                    mountpoint: d[6].to_string(),
+                    snapshots: counts.remove(d[0]).unwrap_or_default(),
+                    // Replicas are always received readonly and unmounted.
+                    replication: last_backup.map(|_| {
+                        if readonly && d[6] == "none" {
+                            "destination".to_string()
+                        } else {
+                            "source".to_string()
+                        }
+                    }),
+                    last_backup: last_backup.map(format_ts),
+                })
+            })
+            .collect();
+
+        inventory.pools.push(PoolInventory {
+            name: pool_name.to_string(),
+            health: f[1].to_string(),
+            size: f[2].parse().unwrap_or(0),
+            allocated: f[3].parse().unwrap_or(0),
+            free: f[4].parse().unwrap_or(0),
+            datasets,
+        });
+    }
+
+    Ok(inventory)
+}
+
+pub(crate) fn do_inventory(opt: &InventoryOpt) {
+    let inventory = match gather(&opt.pools) {
+        Ok(i) => i,
+        Err(_) => return,
+    };
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&inventory) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise inventory -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for pool in inventory.pools {
+                println!(
+                    "{}\t{}\tsize={} alloc={} free={}",
+                    pool.name, pool.health, pool.size, pool.allocated, pool.free
+                );
+                for ds in pool.datasets {
+                    println!(
+                        "  {}\t{}\tused={} encryption={} auto={} repl={} other={} replication={} last_backup={}",
+                        ds.name,
+                        ds.kind,
+                        ds.used,
+                        ds.encryption,
+                        ds.snapshots.auto,
+                        ds.snapshots.repl,
+                        ds.snapshots.other,
+                        ds.replication.as_deref().unwrap_or("-"),
+                        ds.last_backup.as_deref().unwrap_or("-"),
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! `znapper load-keys` and `znapper key-status` - the keys of replicated encrypted datasets.
+//!
+//! Raw streams carry encrypted datasets still encrypted, so a replica can't be mounted (or read)
+//! until the keys of its encryption roots are loaded. load-keys loads each that is unavailable,
+//! from `--keylocation`, the `[key."<dataset>"]` of znapper.toml that covers it, or its own
+//! keylocation property - prompting on the terminal when that is `prompt`.
+
+use crate::config::{Config, Key};
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use crate::OutputFormat;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use structopt::StructOpt;
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct LoadKeysOpt {
+    /// The replica, whose encryption roots (and those of its descendants) to load the keys of.
+    pool: String,
+    /// Load every key from here, ie file:///root/keys/nvme.key, or prompt.
+    #[structopt(long = "keylocation")]
+    keylocation: Option<String>,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct KeyStatusOpt {
+    pool: String,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Serialize)]
+struct KeyStatus {
+    name: String,
+    encryption: String,
+    encryptionroot: String,
+    /// available or unavailable.
+    keystatus: String,
+    keylocation: String,
+}
+
+/// The encrypted datasets of `pool` and its descendants.
+fn key_status(pool: &str) -> Result<Vec<KeyStatus>, ()> {
+    let output = privilege::zfs()
+        .args([
+            "list",
+            "-H",
+            "-r",
+            "-t",
+            "filesystem,volume",
+            "-o",
+            "name,encryption,encryptionroot,keystatus,keylocation",
+            pool,
+        ])
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zfs list failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!(
+            "key status of {} failed -> {}",
+            pool,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(());
+    }
+    let status = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let f: Vec<_> = line.split('\t').collect();
+            match f.as_slice() {
+                [name, encryption, encryptionroot, keystatus, keylocation]
+                    if *encryption != "off" =>
+                {
+                    Some(KeyStatus {
+                        name: name.to_string(),
+                        encryption: encryption.to_string(),
+                        encryptionroot: encryptionroot.to_string(),
+                        keystatus: keystatus.to_string(),
+                        keylocation: keylocation.to_string(),
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect();
+    debug!(?status);
+    Ok(status)
+}
+
+/// Where to load the key of `root` from - `keylocation`, else the configured location of the
+/// nearest dataset at or above it, else its own.
+fn location(keylocation: Option<&str>, keys: &BTreeMap<String, Key>, root: &KeyStatus) -> String {
+    if let Some(keylocation) = keylocation {
+        return keylocation.to_string();
+    }
+    keys.iter()
+        .filter(|(dataset, _)| {
+            root.name == **dataset
+                || root
+                    .name
+                    .strip_prefix(dataset.as_str())
+                    .map(|rest| rest.starts_with('/'))
+                    .unwrap_or(false)
+        })
+        .max_by_key(|(dataset, _)| dataset.len())
+        .map(|(_, key)| key.keylocation.clone())
+        .unwrap_or_else(|| root.keylocation.clone())
+}
+
+/// Load the keys of the encryption roots of `pool` that aren't loaded. Those of a raw replica
+/// are as the source had them, so --keylocation or the config may be needed to find them here.
+pub(crate) fn load_keys(pool: &str, keylocation: Option<&str>, dry: bool) -> Result<(), ()> {
+    let keys = Config::load()?.key;
+    let mut failed = false;
+    for root in key_status(pool)?
+        .iter()
+        .filter(|ds| ds.name == ds.encryptionroot && ds.keystatus != "available")
+    {
+        let location = location(keylocation, &keys, root);
+        let mut args = vec!["load-key"];
+        if location != "prompt" {
+            args.extend(["-L", location.as_str()]);
+        }
+        args.push(root.name.as_str());
+        if dry {
+            info!("dryrun: zfs {}", args.join(" "));
+            continue;
+        }
+        info!("zfs {}", args.join(" "));
+        // Left with the terminal, so that it can prompt.
+        match privilege::zfs_escalated().args(&args).run_status(Kind::Zfs) {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                error!("zfs {} failed -> {}", args.join(" "), status);
+                failed = true;
+            }
+            Err(e) => {
+                error!("zfs {} failed -> {:?}", args.join(" "), e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn do_load_keys(opt: &LoadKeysOpt) {
+    debug!("do_load_keys");
+    if load_keys(&opt.pool, opt.keylocation.as_deref(), opt.dryrun).is_ok() && !opt.dryrun {
+        info!("The keys of {} are loaded", opt.pool);
+    }
+}
+
+pub(crate) fn do_key_status(opt: &KeyStatusOpt) {
+    debug!("do_key_status");
+    let status = match key_status(&opt.pool) {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&status) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise key status -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for ds in status.iter() {
+                println!(
+                    "{}\t{}\t{}\troot={}\t{}",
+                    ds.name, ds.keystatus, ds.encryption, ds.encryptionroot, ds.keylocation
+                );
+            }
+            if status.is_empty() {
+                info!("{} has no encrypted datasets", opt.pool);
+            }
+        }
+    }
+    let unavailable = status
+        .iter()
+        .filter(|ds| ds.keystatus != "available")
+        .count();
+    if unavailable > 0 {
+        warn!(
+            "{} of the datasets of {} have no key loaded - znapper load-keys {}",
+            unavailable, opt.pool, opt.pool
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(name: &str, keylocation: &str) -> KeyStatus {
+        KeyStatus {
+            name: name.to_string(),
+            encryption: "aes-256-gcm".to_string(),
+            encryptionroot: name.to_string(),
+            keystatus: "unavailable".to_string(),
+            keylocation: keylocation.to_string(),
+        }
+    }
+
+    #[test]
+    fn keys_load_from_the_nearest_configured_location() {
+        let mut keys = BTreeMap::new();
+        for (dataset, keylocation) in [
+            ("standby", "file:///keys/standby.key"),
+            ("standby/nvme", "file:///keys/nvme.key"),
+        ] {
+            keys.insert(
+                dataset.to_string(),
+                Key {
+                    keylocation: keylocation.to_string(),
+                },
+            );
+        }
+        let home = root("standby/nvme/home", "prompt");
+        assert_eq!(location(None, &keys, &home), "file:///keys/nvme.key");
+        assert_eq!(location(Some("prompt"), &keys, &home), "prompt");
+        let other = root("standbyx/nvme", "file:///etc/nvme.key");
+        assert_eq!(location(None, &keys, &other), "file:///etc/nvme.key");
+    }
+}
@@ -0,0 +1,4912 @@
+#![deny(warnings)]
+#![warn(unused_extern_crates)]
+#![deny(clippy::unwrap_used)]
+#![deny(clippy::expect_used)]
+#![deny(clippy::panic)]
+#![deny(clippy::unreachable)]
+#![deny(clippy::await_holding_lock)]
+#![deny(clippy::needless_pass_by_value)]
+#![deny(clippy::trivially_copy_pass_by_ref)]
+
+//! znapper - snapshot, replicate and prune ZFS datasets.
+//!
+//! The znapper binary is a thin wrapper of `run_cli`. Other programs can embed the same
+//! operations through [`Zfs`], [`Snapshot`], [`ReplicationJob`] and [`RetentionPolicy`], rather
+//! than running the binary.
+
+use std::fs::File;
+use std::process::{Command, ExitStatus, Stdio};
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, info, info_span, warn};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+mod anchors;
+mod anomaly;
+mod api;
+mod approval;
+mod audit;
+mod buffer;
+mod check;
+mod chunked;
+mod completions;
+mod compress;
+mod config;
+mod confirm;
+mod datasets;
+mod delegation;
+mod diff;
+mod email;
+mod estimate;
+mod exclude;
+mod failover;
+mod find;
+mod fleet;
+mod groups;
+mod history;
+mod immutable;
+mod import;
+mod inventory;
+mod keys;
+mod listing;
+mod lock;
+mod logging;
+mod lzc;
+mod metadata;
+mod metrics;
+mod model;
+mod mount;
+mod naming;
+mod notify;
+mod plan;
+mod priority;
+mod privilege;
+mod process;
+mod progress;
+mod pull;
+mod quiesce;
+mod recv;
+mod redact;
+mod restore;
+mod retention;
+pub mod runner;
+mod serve;
+mod ssh;
+mod sshclient;
+mod status;
+mod stream;
+mod summary;
+mod sync;
+mod targets;
+mod telemetry;
+mod transport;
+mod trash;
+mod units;
+mod usage;
+mod usb;
+mod verify;
+mod zed;
+
+use anchors::{AnchorStore, Owner};
+pub use api::{Error, ReplicationJob, RetentionPolicy, Zfs};
+use buffer::BufferOpt;
+use lock::LockOpt;
+use metadata::{MetadataFile, RemoteMetadata};
+pub use model::{Class, Dataset, ParseError, Snapshot};
+use process::{Kind, Timed};
+use ssh::{Ssh, SshOpt};
+use stream::{RecvPropsOpt, StreamOpt};
+use targets::Targets;
+#[cfg(feature = "tui")]
+mod tui;
+
+/// User property recording which znapper snapshot run created a snapshot.
+const RUN_PROPERTY: &str = "org.znapper:run";
+
+/// The consistency group that snapshot --group took a snapshot for.
+const GROUP_PROPERTY: &str = "org.znapper:group";
+
+/// zfs-auto-snapshot's opt out, honoured so datasets tagged for it needn't be tagged again. Being
+/// inherited, a child can opt back in with `true`.
+const AUTO_SNAPSHOT_PROPERTY: &str = "com.sun:auto-snapshot";
+
+/// Has a dataset opted out of auto snapshots with this value of com.sun:auto-snapshot? Unset
+/// (`-`) is the same as true.
+fn auto_snapshot_off(value: &str) -> bool {
+    value.trim().eq_ignore_ascii_case("false")
+}
+
+/// How commands that report data (rather than perform actions) print it.
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown format {} - expected text or json", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct Opt {
+    /// If filesystems/pools are listed, only these will be recursively snapshotted.
+    ///
+    /// Else if not specified all pools will be recursively snapshotted
+    pools: Vec<String>,
+    /// The pools are the names of [group.<name>]s in znapper.toml, whose datasets are snapshotted
+    /// in one run, with the same name, and tagged with their group as org.znapper:group.
+    #[structopt(long = "group")]
+    group: bool,
+    /// Create this many snapshots at once - on a machine with many datasets, one at a time can
+    /// take minutes.
+    #[structopt(long = "jobs", default_value = "1")]
+    jobs: usize,
+    #[structopt(short = "n")]
+    dryrun: bool,
+    /// With -n, print the plan as text (the log, the default) or json.
+    #[structopt(long = "plan-format", requires = "dryrun")]
+    plan_format: Option<OutputFormat>,
+    #[structopt(flatten)]
+    lock: LockOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ListOpt {
+    pool: String,
+    // #[structopt(short = "n")]
+    // dryrun: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct CleanupOpt {
+    pool: String,
+    keep_hours: u32,
+    /// The pool is the name of a [group.<name>] in znapper.toml, whose datasets are cleaned up
+    /// together - a snapshot name is only destroyed once no dataset of the group keeps it.
+    #[structopt(long = "group")]
+    group: bool,
+    /// Also destroy the auto snapshots within retention that hold nothing - a used and written of
+    /// 0, so the same as the snapshot before them - other than the daily ones retention keeps.
+    #[structopt(long = "empty")]
+    empty: bool,
+    /// Rather than destroying them, set the snapshots aside as trash_<name> until the grace
+    /// period has passed, so that undo-cleanup can put them back.
+    #[structopt(long = "defer")]
+    defer: bool,
+    /// How long deferred snapshots are kept before a cleanup destroys them, ie 12h or 7d.
+    #[structopt(long = "grace", requires = "defer", parse(try_from_str = parse_duration))]
+    grace: Option<Duration>,
+    #[structopt(short = "n")]
+    dryrun: bool,
+    /// With -n, print the plan as text (the log, the default) or json.
+    #[structopt(long = "plan-format", requires = "dryrun")]
+    plan_format: Option<OutputFormat>,
+    #[structopt(flatten)]
+    lock: LockOpt,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct ReplOpt {
+    from_pool: String,
+    /// The dataset to receive into. %hostname% and %dataset% are replaced with the hostname of
+    /// this machine and from_pool, ie tank/backups/%hostname%/%dataset%
+    to_pool: String,
+    /// Also replicate to this destination, may be repeated. Each destination keeps its own
+    /// anchor, so a failure on one does not affect the others.
+    #[structopt(long = "to")]
+    to: Vec<String>,
+    #[structopt(short = "n")]
+    dryrun: bool,
+    /// With -n, print the plan as text (the log, the default) or json.
+    #[structopt(long = "plan-format", requires = "dryrun")]
+    plan_format: Option<OutputFormat>,
+    /// Once replicated, replace the source repl_ snapshot with a bookmark and anchor the next
+    /// incremental from that bookmark, freeing the space the snapshot would hold on the source.
+    #[structopt(long = "bookmarks")]
+    bookmarks: bool,
+    /// Keep this many of the previous anchors on the source and destination as well as the new
+    /// one, so that a later repl can still send from one of them if the newest is lost.
+    #[structopt(long = "keep-anchors", default_value = "0")]
+    keep_anchors: usize,
+    /// If repl finds no common anchor, do a full send into a fresh dataset and swap it in place
+    /// of the destination. The diverged destination is kept, renamed with a _stale_ suffix.
+    #[structopt(long = "fallback-full")]
+    fallback_full: bool,
+    /// Pass -F to zfs recv, rolling back changes made on the destination since the last repl.
+    /// Only allowed when the destination is readonly and shares a repl_ anchor with the source.
+    #[structopt(long = "force-rollback")]
+    force_rollback: bool,
+    /// Exclude the paths in each dataset's org.znapper:redact property from the stream, with a
+    /// redaction bookmark. Redacted datasets are not sent raw, so they must not be encrypted.
+    #[structopt(long = "redact")]
+    redact: bool,
+    /// Warn when a dataset's incremental is more than this many times the average of its
+    /// previous incrementals to the same destination, ie 10. A cheap sign of mass encryption.
+    #[structopt(long = "anomaly-factor")]
+    anomaly_factor: Option<f64>,
+    /// With --anomaly-factor, do not replicate to a destination when an anomaly is found, so
+    /// that it keeps the state from before the change.
+    #[structopt(long = "pause-on-anomaly")]
+    pause_on_anomaly: bool,
+    /// After a successful repl, keep only this many hours of auto snapshots on the destination.
+    #[structopt(long = "dest-keep-hours")]
+    dest_keep_hours: Option<u32>,
+    /// After a successful repl, also keep the newest auto snapshot of each of this many days on
+    /// the destination. Combined with --dest-keep-hours, a snapshot is kept if either keeps it.
+    #[structopt(long = "dest-keep-daily")]
+    dest_keep_daily: Option<u32>,
+    /// Send even when the estimated stream is larger than the free space of the destination pool.
+    #[structopt(long = "ignore-space")]
+    ignore_space: bool,
+    /// Run even if the pre-flight checks fail.
+    #[structopt(long = "skip-preflight")]
+    skip_preflight: bool,
+    /// Refuse to send from or to a pool that is DEGRADED, resilvering or scrubbing, rather than
+    /// send at a lower priority.
+    #[structopt(long = "require-healthy")]
+    require_healthy: bool,
+    /// Send each dataset as its own stream, this many at once, rather than one -R stream of the
+    /// whole hierarchy. Faster over high-latency links, but renames and destroys of datasets on
+    /// the source are not replicated.
+    #[structopt(long = "jobs", default_value = "1")]
+    jobs: usize,
+    /// Replicate from_pool alone, not its descendants - send it with -p rather than -R, from a
+    /// repl snapshot of its own, ie just the volume of a vm.
+    #[structopt(long = "single-dataset", conflicts_with = "redact")]
+    single_dataset: bool,
+    #[structopt(flatten)]
+    stream: StreamOpt,
+    #[structopt(flatten)]
+    recv: RecvPropsOpt,
+    #[structopt(flatten)]
+    buffer: BufferOpt,
+    #[structopt(flatten)]
+    lock: LockOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ReplCleanupOpt {
+    from_pool: String,
+    /// The same destination (or template) as given to repl.
+    to_pool: String,
+    #[structopt(short = "n")]
+    dryrun: bool,
+    /// With -n, print the plan as text (the log, the default) or json.
+    #[structopt(long = "plan-format", requires = "dryrun")]
+    plan_format: Option<OutputFormat>,
+    #[structopt(flatten)]
+    lock: LockOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct InitArchiveOpt {
+    pool: String,
+    file: String,
+    /// Path to a json metadata to track which autosnaps we are anchoring from
+    auto_snap_metadata: String,
+    #[structopt(short = "n")]
+    dryrun: bool,
+    #[structopt(flatten)]
+    stream: StreamOpt,
+    #[structopt(flatten)]
+    lock: LockOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ArchiveOpt {
+    pool: String,
+    file: String,
+    /// The archive was made with --no-raw - receive it with -x encryption, so that it takes the
+    /// encryption of the dataset it is received under.
+    #[structopt(long = "no-raw")]
+    no_raw: bool,
+    #[structopt(flatten)]
+    recv: RecvPropsOpt,
+    #[structopt(short = "n")]
+    dryrun: bool,
+    #[structopt(flatten)]
+    lock: LockOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct InitRemoteOpt {
+    /// The dataset to replicate, with its descendants unless --single-dataset.
+    pool: String,
+    /// Path to a json metadata to track which autosnaps we are anchoring from
+    auto_snap_metadata: String,
+    /// user@host, or the name of a target in targets.toml. The key must allow running commands
+    /// (not a forced command).
+    #[structopt(long = "remote")]
+    remote: String,
+    /// The dataset to receive into on the remote, which must not exist yet. Defaults to the
+    /// dataset of the target.
+    #[structopt(long = "remote-pool")]
+    remote_pool: Option<String>,
+    #[structopt(short = "n")]
+    dryrun: bool,
+    /// Send even when the estimated stream is larger than the free space on the remote.
+    #[structopt(long = "ignore-space")]
+    ignore_space: bool,
+    /// Replicate pool alone, not its descendants - send it with -p rather than -R.
+    #[structopt(long = "single-dataset")]
+    single_dataset: bool,
+    #[structopt(flatten)]
+    stream: StreamOpt,
+    #[structopt(flatten)]
+    recv: RecvPropsOpt,
+    #[structopt(flatten)]
+    ssh: SshOpt,
+    #[structopt(flatten)]
+    buffer: BufferOpt,
+    #[structopt(flatten)]
+    lock: LockOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ReplRemoteOpt {
+    /// user@host, or the name of a target in targets.toml
+    remote_ssh: String,
+    /// Path to a json metadata to track which autosnaps we are anchoring from
+    auto_snap_metadata: String,
+    #[structopt(short = "n")]
+    dryrun: bool,
+    /// With -n, print the plan as text (the log, the default) or json.
+    #[structopt(long = "plan-format", requires = "dryrun")]
+    plan_format: Option<OutputFormat>,
+    /// Run zfs recv -F on the remote, rolling back changes made there since the last repl. The
+    /// remote must be a registered target, and its key must allow running commands (not a
+    /// forced command). Only allowed when the dataset is readonly and holds the precursor.
+    #[structopt(long = "force-rollback")]
+    force_rollback: bool,
+    /// Retry a failed transfer this many times, resuming an interrupted receive where the
+    /// receiver allows it.
+    #[structopt(long = "retries", default_value = "0")]
+    retries: u32,
+    /// How long to wait before the first retry, ie 30s, 5m. Doubles with each retry.
+    #[structopt(long = "retry-delay", default_value = "30s", parse(try_from_str = parse_duration))]
+    retry_delay: Duration,
+    /// Send even when the estimated stream is larger than the free space on the remote.
+    #[structopt(long = "ignore-space")]
+    ignore_space: bool,
+    /// Run even if the pre-flight checks fail.
+    #[structopt(long = "skip-preflight")]
+    skip_preflight: bool,
+    /// Refuse to send from or to a pool that is DEGRADED, resilvering or scrubbing, rather than
+    /// send at a lower priority.
+    #[structopt(long = "require-healthy")]
+    require_healthy: bool,
+    /// Send each dataset as its own stream from its own precursor, kept in the metadata, rather
+    /// than one -R stream. The receiver must run znapper recv.
+    #[structopt(long = "per-dataset")]
+    per_dataset: bool,
+    /// Replicate the dataset of the metadata's precursor alone, not its descendants - send it
+    /// with -p rather than -R, as init_remote --single-dataset seeded it.
+    #[structopt(long = "single-dataset", conflicts_with = "per-dataset")]
+    single_dataset: bool,
+    /// With --per-dataset, replicate this dataset and its descendants, may be repeated. Defaults
+    /// to the dataset of the metadata's precursor.
+    #[structopt(long = "dataset", number_of_values = 1)]
+    datasets: Vec<String>,
+    /// With --per-dataset, do not replicate this dataset or its descendants, may be repeated.
+    #[structopt(long = "exclude", number_of_values = 1)]
+    exclude: Vec<String>,
+    /// Compress the stream over ssh, as zstd[:level], for the receiver's znapper recv to
+    /// decompress. Both hosts need zstd installed.
+    #[structopt(long = "transport-compress")]
+    transport_compress: Option<compress::Compression>,
+    /// Spool the stream into chunks of this size, ie 1G, sending each on its own so that an
+    /// interrupted transfer carries on from the last chunk. The receiver must run znapper recv.
+    #[structopt(long = "chunked", parse(try_from_str = buffer::parse_size))]
+    chunked: Option<usize>,
+    /// Connect to a znapper serve-recv instead of ssh, at tcp://host:port, or tls://host:port
+    /// with --tls-cert, --tls-key and --tls-ca.
+    #[structopt(long = "transport")]
+    transport: Option<transport::Transport>,
+    #[structopt(flatten)]
+    tls: transport::TlsOpt,
+    /// Name this host to the receiver, so that a znapper recv --pool backups/%hostname% keeps
+    /// its streams apart from those of other hosts, in backups/<hostname>/<pool>.
+    #[structopt(long = "per-host")]
+    per_host: bool,
+    #[structopt(flatten)]
+    stream: StreamOpt,
+    /// With --force-rollback, which runs zfs recv itself. Otherwise the receiver's command
+    /// decides.
+    #[structopt(flatten)]
+    recv: RecvPropsOpt,
+    #[structopt(flatten)]
+    ssh: SshOpt,
+    #[structopt(flatten)]
+    buffer: BufferOpt,
+    #[structopt(flatten)]
+    lock: LockOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct Cli {
+    #[structopt(flatten)]
+    zfs: privilege::ZfsOpt,
+    #[structopt(flatten)]
+    naming: naming::NamingOpt,
+    #[structopt(flatten)]
+    log: logging::LogOpt,
+    #[structopt(flatten)]
+    telemetry: telemetry::TelemetryOpt,
+    #[structopt(flatten)]
+    confirm: confirm::ConfirmOpt,
+    #[structopt(flatten)]
+    summary: summary::SummaryOpt,
+    #[structopt(subcommand)]
+    action: Action,
+}
+
+#[derive(Debug, StructOpt)]
+enum Action {
+    #[structopt(name = "list_snapshots")]
+    List(ListOpt),
+    #[structopt(name = "init_repl")]
+    Init(ReplOpt),
+    #[structopt(name = "repl")]
+    Repl(ReplOpt),
+    #[structopt(name = "repl_cleanup")]
+    ReplCleanup(ReplCleanupOpt),
+
+    #[structopt(name = "remote_init_archive")]
+    InitArchive(InitArchiveOpt),
+    #[structopt(name = "remote_load_archive")]
+    LoadArchive(ArchiveOpt),
+    /// Send the first full replication straight to a remote over ssh, rather than by archive.
+    #[structopt(name = "init_remote")]
+    InitRemote(InitRemoteOpt),
+    #[structopt(name = "remote_repl")]
+    ReplRemote(ReplRemoteOpt),
+    /// Run on the backup host - receive the auto snapshots of a remote dataset over ssh.
+    #[structopt(name = "pull")]
+    Pull(pull::PullOpt),
+    /// Run on the backup host - pull from each host of fleet.toml, or show how they went.
+    #[structopt(name = "fleet")]
+    Fleet(fleet::FleetAction),
+    /// Receive a remote_repl stream - for use as the forced command of the replication key.
+    #[structopt(name = "recv")]
+    Recv(recv::RecvOpt),
+    /// Receive remote_repl streams sent with --transport, over tcp or TLS rather than ssh.
+    #[structopt(name = "serve-recv")]
+    ServeRecv(transport::ServeRecvOpt),
+    #[structopt(name = "transport-connect", setting = structopt::clap::AppSettings::Hidden)]
+    Connect(transport::ConnectOpt),
+    #[structopt(name = "ssh-connect", setting = structopt::clap::AppSettings::Hidden)]
+    SshConnect(sshclient::SshConnectOpt),
+    /// Run as a daemon, serving an http api to list and run jobs, and follow their status.
+    #[structopt(name = "serve")]
+    Serve(serve::ServeOpt),
+    /// Pause replication from a degraded pool, and sync a resilvered one - run as a zedlet by
+    /// ZED, or following zpool events with --follow.
+    #[structopt(name = "zedlet")]
+    Zedlet(zed::ZedletOpt),
+
+    #[structopt(name = "snapshot")]
+    Snapshot(Opt),
+    #[structopt(name = "snapshot_cleanup")]
+    SnapshotCleanup(CleanupOpt),
+
+    /// Show the replication lag and last run of each job.
+    #[structopt(name = "status")]
+    Status(status::StatusOpt),
+    /// Check the replication lag of a job as a Nagios plugin would, exiting 0, 1, 2 or 3.
+    #[structopt(name = "check-lag")]
+    CheckLag(status::CheckLagOpt),
+    /// Show the snapshots created and destroyed, and the sends run, as recorded in the history.
+    #[structopt(name = "history")]
+    History(history::HistoryOpt),
+    /// Show the audit log of snapshot destroys and forced rollbacks.
+    #[structopt(name = "audit")]
+    Audit(audit::AuditOpt),
+    /// Show what changed in a dataset between two snapshots, or since one.
+    #[structopt(name = "diff")]
+    Diff(diff::DiffOpt),
+    /// Snapshot, replicate and prune a job from znapper.toml in one run.
+    #[structopt(name = "sync")]
+    Sync(sync::SyncOpt),
+    /// Wait for one of the removable disks of a job, back the job up to it, and export it.
+    #[structopt(name = "usb-backup")]
+    UsbBackup(usb::UsbBackupOpt),
+    /// Print (or install) the systemd service and timer that run a job on a schedule.
+    #[structopt(name = "generate-units")]
+    GenerateUnits(units::GenerateUnitsOpt),
+
+    #[structopt(name = "inventory")]
+    Inventory(inventory::InventoryOpt),
+    /// Show the space of a pool held by each dataset and class of snapshot, and what cleanup
+    /// would reclaim.
+    #[structopt(name = "usage")]
+    Usage(usage::UsageOpt),
+    #[structopt(name = "target")]
+    Target(targets::TargetAction),
+    /// Find which snapshots of a dataset hold a file, and in which it last changed.
+    #[structopt(name = "find")]
+    Find(find::FindOpt),
+    /// Mount a snapshot read-only at a path, to copy files out of.
+    #[structopt(name = "mount")]
+    Mount(mount::MountOpt),
+    /// Unmount a snapshot mounted with znapper mount.
+    #[structopt(name = "unmount")]
+    Unmount(mount::UnmountOpt),
+    /// Redact a snapshot with clones of it that sensitive files were removed from, for sharing.
+    #[structopt(name = "redact")]
+    Redact(redact::RedactOpt),
+    /// Restore a dataset to one of its snapshots, as a clone or in its place.
+    #[structopt(name = "restore")]
+    Restore(restore::RestoreOpt),
+    #[structopt(name = "restore-group")]
+    RestoreGroup(groups::RestoreGroupOpt),
+    /// Estimate the size of the streams repl would send, and check they fit the destinations.
+    #[structopt(name = "estimate")]
+    Estimate(estimate::EstimateOpt),
+    /// Check that zfs, the pools, delegations, remotes and metadata are fit for replication.
+    #[structopt(name = "check")]
+    Check(check::CheckOpt),
+    /// Check that a replica still matches its source, comparing their snapshots by guid.
+    #[structopt(name = "verify")]
+    Verify(verify::VerifyOpt),
+    /// Take over a replica - make it writable and mount it, to serve in place of its source.
+    #[structopt(name = "failover")]
+    Failover(failover::FailoverOpt),
+    /// Load the keys of the encryption roots of a replica.
+    #[structopt(name = "load-keys")]
+    LoadKeys(keys::LoadKeysOpt),
+    /// Show whether the keys of the encrypted datasets of a replica are loaded.
+    #[structopt(name = "key-status")]
+    KeyStatus(keys::KeyStatusOpt),
+    /// Grant a user the zfs allow delegations a send or receive role needs.
+    #[structopt(name = "setup-delegation")]
+    SetupDelegation(delegation::SetupDelegationOpt),
+    /// Put back the snapshots the most recent cleanup --defer of a pool set aside.
+    #[structopt(name = "undo-cleanup")]
+    UndoCleanup(trash::UndoCleanupOpt),
+    /// Approve (or list) the destructive plans staged for approval.
+    #[structopt(name = "approve")]
+    Approve(approval::ApproveOpt),
+    /// Print the Prometheus metrics, as written to the [metrics] textfile.
+    #[structopt(name = "metrics")]
+    Metrics,
+    /// Show the progress of running (and the result of finished) sends.
+    #[structopt(name = "progress")]
+    Progress(progress::ProgressOpt),
+    /// Translate a sanoid.conf, and the syncoid lines of a crontab, into znapper.toml jobs.
+    #[structopt(name = "import-config")]
+    ImportConfig(import::ImportConfigOpt),
+    /// Print the completions of a shell.
+    #[structopt(name = "completions")]
+    Completions(completions::CompletionsOpt),
+    /// Print the man page.
+    #[structopt(name = "manpage")]
+    Manpage,
+
+    #[cfg(feature = "tui")]
+    #[structopt(name = "tui")]
+    Tui(tui::TuiOpt),
+}
+
+impl Action {
+    /// How the plan of a dry run is printed, for the actions that have one.
+    fn plan_format(&self) -> OutputFormat {
+        let plan_format = match self {
+            Action::Snapshot(opt) => opt.plan_format,
+            Action::SnapshotCleanup(opt) => opt.plan_format,
+            Action::Init(opt) | Action::Repl(opt) => opt.plan_format,
+            Action::ReplCleanup(opt) => opt.plan_format,
+            Action::ReplRemote(opt) => opt.plan_format,
+            Action::Sync(opt) => opt.plan_format,
+            Action::UsbBackup(opt) => opt.plan_format,
+            _ => None,
+        };
+        plan_format.unwrap_or(OutputFormat::Text)
+    }
+
+    /// Is this a dry run?
+    fn dryrun(&self) -> bool {
+        match self {
+            Action::Snapshot(opt) => opt.dryrun,
+            Action::SnapshotCleanup(opt) => opt.dryrun,
+            Action::Init(opt) | Action::Repl(opt) => opt.dryrun,
+            Action::ReplCleanup(opt) => opt.dryrun,
+            Action::InitArchive(opt) => opt.dryrun,
+            Action::LoadArchive(opt) => opt.dryrun,
+            Action::InitRemote(opt) => opt.dryrun,
+            Action::ReplRemote(opt) => opt.dryrun,
+            Action::Pull(opt) => opt.dryrun,
+            Action::Fleet(fleet::FleetAction::Run(opt)) => opt.dryrun,
+            Action::Sync(opt) => opt.dryrun,
+            Action::UsbBackup(opt) => opt.dryrun,
+            Action::GenerateUnits(opt) => opt.dryrun,
+            Action::SetupDelegation(opt) => opt.dryrun,
+            Action::Restore(opt) => opt.dryrun,
+            Action::RestoreGroup(opt) => opt.dryrun,
+            Action::Redact(opt) => opt.dryrun,
+            Action::Mount(opt) => opt.dryrun,
+            Action::Unmount(opt) => opt.dryrun,
+            Action::Failover(opt) => opt.dryrun,
+            Action::LoadKeys(opt) => opt.dryrun,
+            Action::UndoCleanup(opt) => opt.dryrun,
+            Action::Zedlet(opt) => opt.dryrun,
+            _ => false,
+        }
+    }
+
+    /// The locks this action takes, and whether to wait for them. Dry runs change nothing, so
+    /// take none.
+    fn locks(&self) -> Option<(Vec<String>, &LockOpt)> {
+        if self.dryrun() {
+            return None;
+        }
+        match self {
+            Action::Snapshot(opt) => {
+                // Without pools every pool is snapshotted.
+                let filesystems = snapshot_targets(opt).ok()?;
+                Some((
+                    filesystems.iter().map(|(fs, _)| lock::pool(fs)).collect(),
+                    &opt.lock,
+                ))
+            }
+            Action::SnapshotCleanup(opt) if opt.group => Some((
+                groups::datasets(&opt.pool)
+                    .ok()?
+                    .iter()
+                    .map(|ds| lock::pool(ds))
+                    .collect(),
+                &opt.lock,
+            )),
+            Action::SnapshotCleanup(opt) => Some((vec![lock::pool(&opt.pool)], &opt.lock)),
+            Action::Init(opt) | Action::Repl(opt) => Some((repl_locks(opt), &opt.lock)),
+            Action::ReplCleanup(opt) => Some((
+                vec![
+                    lock::pool(&opt.from_pool),
+                    lock::pool(&dest_lock_path(&opt.to_pool, &opt.from_pool)),
+                ],
+                &opt.lock,
+            )),
+            Action::InitArchive(opt) => Some((vec![lock::pool(&opt.pool)], &opt.lock)),
+            Action::LoadArchive(opt) => Some((vec![lock::pool(&opt.pool)], &opt.lock)),
+            Action::InitRemote(opt) => Some((vec![lock::pool(&opt.pool)], &opt.lock)),
+            Action::ReplRemote(opt) => Some((remote_locks(opt), &opt.lock)),
+            Action::Pull(opt) => Some((vec![lock::pool(&opt.to_pool)], &opt.lock)),
+            Action::Fleet(fleet::FleetAction::Run(opt)) => Some((fleet::locks(opt), &opt.lock)),
+            Action::Sync(opt) => Some((sync::locks(opt), &opt.lock)),
+            Action::UsbBackup(opt) => Some((usb::locks(opt), &opt.lock)),
+            Action::Restore(opt) => Some((vec![lock::pool(&opt.snapshot)], &opt.lock)),
+            Action::Redact(opt) => Some((vec![lock::pool(&opt.snapshot)], &opt.lock)),
+            Action::Failover(opt) => Some((vec![lock::pool(&opt.pool)], &opt.lock)),
+            Action::UndoCleanup(opt) => Some((vec![lock::pool(&opt.pool)], &opt.lock)),
+            Action::RestoreGroup(opt) => Some((groups::locks(opt), &opt.lock)),
+            _ => None,
+        }
+    }
+
+    /// Does this action change something (or would it, in a dry run), and so end with a
+    /// summary?
+    fn summarised(&self) -> bool {
+        matches!(
+            self,
+            Action::Snapshot(_)
+                | Action::SnapshotCleanup(_)
+                | Action::Init(_)
+                | Action::Repl(_)
+                | Action::ReplCleanup(_)
+                | Action::InitArchive(_)
+                | Action::LoadArchive(_)
+                | Action::InitRemote(_)
+                | Action::ReplRemote(_)
+                | Action::Pull(_)
+                | Action::Fleet(fleet::FleetAction::Run(_))
+                | Action::Sync(_)
+                | Action::UsbBackup(_)
+                | Action::Restore(_)
+                | Action::Redact(_)
+                | Action::RestoreGroup(_)
+                | Action::Failover(_)
+                | Action::UndoCleanup(_)
+        )
+    }
+
+    /// Does this action change what the metrics report?
+    fn updates_metrics(&self) -> bool {
+        match self {
+            Action::Snapshot(opt) => !opt.dryrun,
+            Action::SnapshotCleanup(opt) => !opt.dryrun,
+            Action::Init(opt) | Action::Repl(opt) => !opt.dryrun,
+            Action::ReplCleanup(opt) => !opt.dryrun,
+            Action::ReplRemote(opt) => !opt.dryrun,
+            Action::Sync(opt) => !opt.dryrun,
+            Action::UsbBackup(opt) => !opt.dryrun,
+            _ => false,
+        }
+    }
+}
+
+fn mounted_list(pools: &[String]) -> Result<Vec<String>, ()> {
+    let mut cmd = privilege::zfs();
+
+    cmd.arg("list")
+        .arg("-H")
+        .arg("-r")
+        .arg("-t")
+        .arg("filesystem")
+        .arg("-o")
+        .arg(format!("name,mountpoint,{}", AUTO_SNAPSHOT_PROPERTY));
+
+    for pool in pools {
+        cmd.arg(pool.as_str());
+    }
+
+    let stdout = cmd
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("mounted list failed -> {:?}", e);
+        })
+        .and_then(|output| {
+            String::from_utf8(output.stdout).map_err(|e| {
+                error!("mounted list contains invalid utf8 -> {:?}", e);
+            })
+        })?;
+
+    let lines: Vec<_> = stdout.split("\n").collect();
+    debug!("{:?}", lines);
+
+    Ok(lines
+        .iter()
+        .filter_map(|line| {
+            let mut lsplit = line.split('\t');
+            match (lsplit.next(), lsplit.next(), lsplit.next()) {
+                (Some(_), Some("none"), _) => None,
+                (Some(name), Some(_), Some(auto)) if auto_snapshot_off(auto) => {
+                    debug!("Skipping {} - {}={}", name, AUTO_SNAPSHOT_PROPERTY, auto);
+                    None
+                }
+                (Some(name), Some(_), _) => Some(name),
+                _ => None,
+            }
+        })
+        .map(str::to_string)
+        .collect())
+}
+
+/// The filesystems and volumes under (and including) `pool_name` that have opted out of auto
+/// snapshots, and so have none to replicate.
+fn auto_snapshot_opted_out(pool_name: &str) -> Result<Vec<String>, ()> {
+    let stdout = privilege::zfs()
+        .arg("list")
+        .arg("-H")
+        .arg("-r")
+        .arg("-t")
+        .arg("filesystem,volume")
+        .arg("-o")
+        .arg(format!("name,{}", AUTO_SNAPSHOT_PROPERTY))
+        .arg(pool_name)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("dataset list failed -> {:?}", e);
+        })
+        .and_then(|output| {
+            String::from_utf8(output.stdout).map_err(|e| {
+                error!("dataset list contains invalid utf8 -> {:?}", e);
+            })
+        })?;
+
+    Ok(stdout
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(_, auto)| auto_snapshot_off(auto))
+        .map(|(name, _)| name.to_string())
+        .collect())
+}
+
+/// The snapshots (or bookmarks, by `kind`) of `pool_name`, and with `recurse` of its descendants.
+fn zfs_list(kind: &str, pool_name: &str, recurse: bool) -> Result<Vec<Snapshot>, ()> {
+    let stdout: String = listing::list(pool_name)?
+        .iter()
+        .filter(|l| match kind {
+            "bookmark" => l.is_bookmark(),
+            _ => l.is_snapshot(),
+        })
+        .filter(|l| recurse || l.of(pool_name))
+        .map(|l| format!("{}\n", l.line))
+        .collect();
+
+    let mut snaps = model::parse_list(&stdout).map_err(|e| {
+        error!("{} list of {} -> {}", kind, pool_name, e);
+    })?;
+    snaps.sort_unstable();
+    debug!("{:?}", snaps);
+    Ok(snaps)
+}
+
+fn snap_list(pool_name: &str, recurse: bool) -> Result<Vec<Snapshot>, ()> {
+    zfs_list("snapshot", pool_name, recurse)
+}
+
+fn filter_snap_list(class: Class, pool_name: &str, recurse: bool) -> Result<Vec<Snapshot>, ()> {
+    Ok(snap_list(pool_name, recurse)?
+        .into_iter()
+        .filter(|snap| snap.class() == class)
+        .collect())
+}
+
+fn repl_snap_list(pool_name: &str) -> Result<Vec<Snapshot>, ()> {
+    filter_snap_list(Class::Repl, pool_name, true)
+}
+
+fn auto_snap_list(pool_name: &str) -> Result<Vec<Snapshot>, ()> {
+    filter_snap_list(Class::Auto, pool_name, true)
+}
+
+fn bookmark_list(pool_name: &str) -> Result<Vec<Snapshot>, ()> {
+    zfs_list("bookmark", pool_name, true)
+}
+
+fn repl_bookmark_list(pool_name: &str) -> Result<Vec<Snapshot>, ()> {
+    Ok(bookmark_list(pool_name)?
+        .into_iter()
+        .filter(Snapshot::is_repl)
+        .collect())
+}
+
+/// (name, guid) of every repl_ snapshot or bookmark (by `kind`) under `pool_name`.
+fn repl_guid_list(pool_name: &str, kind: &str) -> Result<Vec<(String, String)>, ()> {
+    let mut names: Vec<_> = listing::list(pool_name)?
+        .iter()
+        .filter(|l| match kind {
+            "bookmark" => l.is_bookmark(),
+            _ => l.is_snapshot(),
+        })
+        .filter_map(|l| {
+            let mut lsplit = l.line.split_whitespace();
+            match (lsplit.next(), lsplit.next()) {
+                (Some(name), Some(guid)) if short_name(name).starts_with("repl_") => {
+                    Some((name.to_string(), guid.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .collect();
+    names.sort_unstable();
+    Ok(names)
+}
+
+/// The (parsable) value of a single property of a dataset, snapshot or bookmark.
+/// Parse name<tab>guid lines.
+fn parse_guids(stdout: &str) -> Vec<(String, String)> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (name, guid) = line.split_once('\t')?;
+            Some((name.to_string(), guid.to_string()))
+        })
+        .collect()
+}
+
+/// The snapshots of `dataset` (not its children) as (name, guid), oldest first.
+fn snapshot_guid_list(dataset: &str) -> Result<Vec<(String, String)>, ()> {
+    let output = privilege::zfs()
+        .arg("list")
+        .arg("-H")
+        .arg("-p")
+        .arg("-t")
+        .arg("snapshot")
+        .arg("-o")
+        .arg("name,guid")
+        .arg("-s")
+        .arg("createtxg")
+        .arg("-d")
+        .arg("1")
+        .arg(dataset)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("snapshot list failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!("snapshot list of {} failed", dataset);
+        return Err(());
+    }
+    Ok(parse_guids(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn get_property(name: &str, property: &str) -> Result<String, ()> {
+    let output = privilege::zfs()
+        .arg("get")
+        .arg("-H")
+        .arg("-p")
+        .arg("-o")
+        .arg("value")
+        .arg(property)
+        .arg(name)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zfs get failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!("zfs get {} {} failed", property, name);
+        return Err(());
+    }
+    String::from_utf8(output.stdout)
+        .map(|s| s.trim().to_string())
+        .map_err(|e| {
+            error!("zfs get contains invalid utf8 -> {:?}", e);
+        })
+}
+
+/// All filesystems and volumes under (and including) `pool_name`.
+fn dataset_list(pool_name: &str) -> Result<Vec<String>, ()> {
+    Ok(listing::list(pool_name)?
+        .into_iter()
+        .filter(|l| l.is_dataset())
+        .map(|l| l.name)
+        .collect())
+}
+
+/// The part of a snapshot or bookmark name after the `@` or `#`.
+fn short_name(name: &str) -> &str {
+    name.rsplit(['@', '#']).next().unwrap_or(name)
+}
+
+fn do_list(opt: &ListOpt) {
+    if let Ok(names) = snap_list(opt.pool.as_str(), true) {
+        for name in names {
+            println!("{}", name);
+        }
+    }
+}
+
+/// Refuse to destroy a snapshot znapper didn't name - one that isn't auto_, repl_ or redact_ (the
+/// name template only changes the time after the prefix) - so that a cleanup bug can't take a
+/// hand made snapshot, or another tool's.
+fn check_managed(snap_name: &str) -> Result<(), ()> {
+    let snap = Snapshot::parse(snap_name).map_err(|e| {
+        error!("Invalid snapshot name {} -> {:?}", snap_name, e);
+    })?;
+    // What a deferred cleanup set aside is still znapper's.
+    let snap = match snap.short_name().strip_prefix(trash::PREFIX) {
+        Some(short) => {
+            Snapshot::parse(&format!("{}@{}", snap.dataset_name(), short)).map_err(|e| {
+                error!("Invalid snapshot name {} -> {:?}", snap_name, e);
+            })?
+        }
+        None => snap,
+    };
+    if snap.class() == Class::Other {
+        error!(
+            "Refusing to destroy {} - it isn't an auto_, repl_ or redact_ snapshot of znapper, pass --force to destroy it anyway",
+            snap_name
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
+fn remove_snap(dry: bool, snap_name: &str, reason: audit::Reason) -> Result<(), ()> {
+    if !confirm::forced() {
+        check_managed(snap_name)?;
+    }
+    destroy_snap(dry, snap_name, reason)
+}
+
+/// As remove_snap, but of `snap_name` alone and not the snapshots of the same name of its
+/// descendants - for the lists of snapshots that already name those of each dataset.
+fn remove_snap_only(dry: bool, snap_name: &str, reason: audit::Reason) -> Result<(), ()> {
+    if !confirm::forced() {
+        check_managed(snap_name)?;
+    }
+    destroy(dry, snap_name, reason, false)
+}
+
+/// Destroy `snap_name` and those of the same name of its descendants whatever they are named,
+/// for `reason`.
+fn destroy_snap(dry: bool, snap_name: &str, reason: audit::Reason) -> Result<(), ()> {
+    destroy(dry, snap_name, reason, true)
+}
+
+/// Destroy `snap_name`, and if `recursive` those of the same name of its descendants.
+fn destroy(dry: bool, snap_name: &str, reason: audit::Reason, recursive: bool) -> Result<(), ()> {
+    immutable::check_destroy(snap_name)?;
+    let guid = snapshot_guid(snap_name);
+    if dry {
+        info!("dryrun: remove_snap -> {}", snap_name);
+        plan::destroy(snap_name);
+        audit::destroy(true, snap_name, guid, reason, true);
+        Ok(())
+    } else {
+        info!("remove_snap -> {}", snap_name);
+        if lzc::active() {
+            let res = remove_snap_native(snap_name, recursive);
+            audit::destroy(false, snap_name, guid, reason, res.is_ok());
+            return res;
+        }
+        let mut cmd = privilege::zfs();
+        cmd.arg("destroy");
+        if recursive {
+            cmd.arg("-r");
+        }
+        cmd.arg(snap_name)
+            .run_status(Kind::Zfs)
+            .map_err(|e| {
+                error!("snapshot remove failed -> {:?}", e);
+            })
+            .and_then(|status| {
+                debug!(?status);
+                history::record(
+                    history::Kind::SnapshotDestroy {
+                        snapshot: snap_name.to_string(),
+                    },
+                    status.success(),
+                );
+                audit::destroy(false, snap_name, guid, reason, status.success());
+                if status.success() {
+                    Ok(())
+                } else {
+                    error!("snapshot remove failed -> {}", snap_name);
+                    Err(())
+                }
+            })
+    }
+}
+
+/// As zfs destroy, and if `recursive` as zfs destroy -r - the snapshots of the same name of the
+/// dataset and its descendants.
+fn remove_snap_native(snap_name: &str, recursive: bool) -> Result<(), ()> {
+    let (dataset, short) = snap_name.split_once('@').ok_or_else(|| {
+        error!("{} is not a snapshot", snap_name);
+    })?;
+    let snaps: Vec<_> = if recursive {
+        listing::list(dataset)?
+            .into_iter()
+            .filter(|l| l.is_snapshot() && short_name(&l.name) == short)
+            .map(|l| l.name)
+            .collect()
+    } else {
+        vec![snap_name.to_string()]
+    };
+    let res = lzc::destroy(&snaps);
+    history::record(
+        history::Kind::SnapshotDestroy {
+            snapshot: snap_name.to_string(),
+        },
+        res.is_ok(),
+    );
+    res.map_err(|failures| {
+        error!(
+            "snapshot remove of {} failed -> {}",
+            snap_name,
+            lzc::describe(&failures)
+        );
+    })
+}
+
+fn remove_bookmark(dry: bool, bookmark_name: &str) -> Result<(), ()> {
+    if dry {
+        info!("dryrun: remove_bookmark -> {}", bookmark_name);
+        plan::destroy(bookmark_name);
+        Ok(())
+    } else {
+        info!("remove_bookmark -> {}", bookmark_name);
+        privilege::zfs()
+            .arg("destroy")
+            .arg(bookmark_name)
+            .run_status(Kind::Zfs)
+            .map_err(|e| {
+                error!("bookmark remove failed -> {:?}", e);
+            })
+            .and_then(|status| {
+                debug!(?status);
+                if status.success() {
+                    Ok(())
+                } else {
+                    error!("bookmark remove failed -> {}", bookmark_name);
+                    Err(())
+                }
+            })
+    }
+}
+
+fn create_bookmark(dry: bool, snap_name: &str, bookmark_name: &str) -> Result<(), ()> {
+    if dry {
+        info!("dryrun: create_bookmark -> {} {}", snap_name, bookmark_name);
+        plan::bookmark(snap_name, bookmark_name);
+        Ok(())
+    } else {
+        info!("create_bookmark -> {} {}", snap_name, bookmark_name);
+        let status = privilege::zfs()
+            .arg("bookmark")
+            .arg(snap_name)
+            .arg(bookmark_name)
+            .run_status(Kind::Zfs)
+            .map_err(|e| {
+                error!("bookmark create failed -> {:?}", e);
+            })?;
+        debug!(?status);
+        if status.success() {
+            Ok(())
+        } else {
+            error!("bookmark create failed -> {}", bookmark_name);
+            Err(())
+        }
+    }
+}
+
+fn rename_dataset(dry: bool, from_name: &str, to_name: &str) -> Result<(), ()> {
+    if dry {
+        info!("dryrun: rename_dataset -> {} {}", from_name, to_name);
+        plan::dataset(plan::DatasetChange::Rename {
+            from: from_name.to_string(),
+            to: to_name.to_string(),
+        });
+        Ok(())
+    } else {
+        info!("rename_dataset -> {} {}", from_name, to_name);
+        let status = privilege::zfs_escalated()
+            .arg("rename")
+            .arg(from_name)
+            .arg(to_name)
+            .run_status(Kind::Zfs)
+            .map_err(|e| {
+                error!("dataset rename failed -> {:?}", e);
+            })?;
+        debug!(?status);
+        if status.success() {
+            Ok(())
+        } else {
+            error!("dataset rename failed -> {} {}", from_name, to_name);
+            Err(())
+        }
+    }
+}
+
+fn clone_snap(dry: bool, snap_name: &str, clone_name: &str) -> Result<(), ()> {
+    if dry {
+        info!("dryrun: clone_snap -> {} {}", snap_name, clone_name);
+        plan::dataset(plan::DatasetChange::Clone {
+            snapshot: snap_name.to_string(),
+            clone: clone_name.to_string(),
+        });
+        Ok(())
+    } else {
+        info!("clone_snap -> {} {}", snap_name, clone_name);
+        let status = privilege::zfs_escalated()
+            .arg("clone")
+            .arg(snap_name)
+            .arg(clone_name)
+            .run_status(Kind::Zfs)
+            .map_err(|e| {
+                error!("snapshot clone failed -> {:?}", e);
+            })?;
+        debug!(?status);
+        if status.success() {
+            Ok(())
+        } else {
+            error!("snapshot clone failed -> {} {}", snap_name, clone_name);
+            Err(())
+        }
+    }
+}
+
+/// Roll the dataset back to `snap_name` for `reason`, destroying any newer snapshots.
+fn rollback_snap(dry: bool, snap_name: &str, reason: audit::Reason) -> Result<(), ()> {
+    immutable::check_rollback(snap_name)?;
+    let guid = snapshot_guid(snap_name);
+    if dry {
+        info!("dryrun: rollback_snap -> {}", snap_name);
+        plan::dataset(plan::DatasetChange::Rollback {
+            snapshot: snap_name.to_string(),
+        });
+        audit::rollback_to(true, snap_name, guid, reason, true);
+        Ok(())
+    } else {
+        info!("rollback_snap -> {}", snap_name);
+        let status = privilege::zfs()
+            .arg("rollback")
+            .arg("-r")
+            .arg(snap_name)
+            .run_status(Kind::Zfs)
+            .map_err(|e| {
+                error!("snapshot rollback failed -> {:?}", e);
+            })?;
+        debug!(?status);
+        audit::rollback_to(false, snap_name, guid, reason, status.success());
+        if status.success() {
+            Ok(())
+        } else {
+            error!("snapshot rollback failed -> {}", snap_name);
+            Err(())
+        }
+    }
+}
+
+/// What became of a snapshot that didn't fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Snapped {
+    Created,
+    /// The name was taken by the time it was snapshotted - by another run in the same second -
+    /// so the snapshot that has it stands in for this one.
+    AlreadyExists,
+}
+
+fn create_snap(dry: bool, snap_name: &str, run_id: &str) -> Result<Snapped, ()> {
+    snapshot(dry, snap_name, run_id, None).map_err(|e| error!("{}", e))
+}
+
+/// Create `snap_name`, tagged with `run_id` (and `group`, if it is of one), returning why it
+/// couldn't be.
+fn snapshot(
+    dry: bool,
+    snap_name: &str,
+    run_id: &str,
+    group: Option<&str>,
+) -> Result<Snapped, String> {
+    if dry {
+        info!("dryrun: create_snap -> {}", snap_name);
+        plan::create(snap_name, false);
+        return Ok(Snapped::Created);
+    }
+    info!("create_snap -> {}", snap_name);
+    if lzc::active() {
+        let mut props = vec![(RUN_PROPERTY, run_id)];
+        props.extend(group.map(|group| (GROUP_PROPERTY, group)));
+        let res = lzc::snapshot(&[snap_name.to_string()], &props);
+        history::record(
+            history::Kind::SnapshotCreate {
+                snapshot: snap_name.to_string(),
+                recursive: false,
+            },
+            res.is_ok(),
+        );
+        return match res {
+            Ok(()) => Ok(Snapped::Created),
+            Err(failures)
+                if failures
+                    .iter()
+                    .all(|(_, e)| e.kind() == io::ErrorKind::AlreadyExists) =>
+            {
+                warn!("{} already exists", snap_name);
+                Ok(Snapped::AlreadyExists)
+            }
+            Err(failures) => Err(format!(
+                "snapshot create of {} failed -> {}",
+                snap_name,
+                lzc::describe(&failures)
+            )),
+        };
+    }
+    let mut cmd = privilege::zfs();
+    cmd.arg("snapshot")
+        .arg("-o")
+        .arg(format!("{}={}", RUN_PROPERTY, run_id));
+    if let Some(group) = group {
+        cmd.arg("-o").arg(format!("{}={}", GROUP_PROPERTY, group));
+    }
+    let output = cmd
+        .arg(snap_name)
+        .run_output(Kind::Zfs)
+        .map_err(|e| format!("snapshot create of {} failed -> {:?}", snap_name, e))?;
+    debug!(?output.status);
+    history::record(
+        history::Kind::SnapshotCreate {
+            snapshot: snap_name.to_string(),
+            recursive: false,
+        },
+        output.status.success(),
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() {
+        Ok(Snapped::Created)
+    } else if stderr.contains("already exists") {
+        warn!("{} already exists", snap_name);
+        Ok(Snapped::AlreadyExists)
+    } else {
+        Err(format!(
+            "snapshot create of {} failed -> {}",
+            snap_name,
+            stderr.trim()
+        ))
+    }
+}
+
+/// Create `snap_names`, `jobs` at a time, returning the result of each in order - each tagged
+/// with the group of the same index in `groups`. Those not started before a cancel fail as such.
+fn create_snaps(
+    dry: bool,
+    snap_names: &[String],
+    groups: &[Option<String>],
+    run_id: &str,
+    jobs: usize,
+) -> Vec<Result<Snapped, String>> {
+    let next = AtomicUsize::new(0);
+    let done: Vec<_> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, snap_names.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        let snap_name = match snap_names.get(i) {
+                            Some(s) => s,
+                            None => break,
+                        };
+                        let res = if process::cancelled() {
+                            Err(format!("snapshot create of {} cancelled", snap_name))
+                        } else {
+                            let group = groups.get(i).and_then(Option::as_deref);
+                            snapshot(dry, snap_name, run_id, group)
+                        };
+                        done.push((i, res));
+                    }
+                    done
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_default())
+            .collect()
+    });
+    let mut results: Vec<Option<Result<Snapped, String>>> =
+        snap_names.iter().map(|_| None).collect();
+    for (i, res) in done {
+        if let Some(slot) = results.get_mut(i) {
+            *slot = Some(res);
+        }
+    }
+    results
+        .into_iter()
+        .zip(snap_names)
+        .map(|(res, snap_name)| {
+            res.unwrap_or_else(|| {
+                Err(format!(
+                    "snapshot create of {} failed - its worker panicked",
+                    snap_name
+                ))
+            })
+        })
+        .collect()
+}
+
+/// The flag that sends the snapshot of a dataset - -R, with its descendants, or with
+/// --single-dataset -p, the dataset alone with its properties.
+fn recursion_flag(single_dataset: bool) -> &'static str {
+    if single_dataset {
+        "-p"
+    } else {
+        "-R"
+    }
+}
+
+/// Take the repl snapshot `snap_name` - of from_pool alone with --single-dataset, otherwise with
+/// its descendants - with the guests that have disks in it frozen.
+fn create_repl_snap(opt: &ReplOpt, snap_name: &str, now_ts: &str) -> Result<(), ()> {
+    let _frozen = quiesce::freeze(
+        opt.dryrun,
+        std::slice::from_ref(&opt.from_pool),
+        !opt.single_dataset,
+    );
+    if opt.single_dataset {
+        let run_id = format!("{}_{}", now_ts, std::process::id());
+        create_snap(opt.dryrun, snap_name, &run_id).map(|_| ())
+    } else {
+        create_recurse_snap(opt.dryrun, snap_name)
+    }
+}
+
+/// The datasets of from_pool that repl sends - only from_pool itself with --single-dataset.
+fn repl_datasets(opt: &ReplOpt) -> Result<Vec<String>, ()> {
+    if opt.single_dataset {
+        Ok(vec![opt.from_pool.clone()])
+    } else {
+        dataset_list(opt.from_pool.as_str())
+    }
+}
+
+/// The repl_ snapshots and bookmarks that repl anchors from and cleans up - with
+/// --single-dataset only those of from_pool itself, as its descendants' belong to other flows.
+fn repl_anchors(opt: &ReplOpt) -> Result<(Vec<Snapshot>, Vec<Snapshot>), ()> {
+    let own = |anchor: &Snapshot| !opt.single_dataset || anchor.dataset_name() == opt.from_pool;
+    let snaps = repl_snap_list(opt.from_pool.as_str())?;
+    let bookmarks = repl_bookmark_list(opt.from_pool.as_str())?;
+    Ok((
+        snaps.into_iter().filter(own).collect(),
+        bookmarks.into_iter().filter(own).collect(),
+    ))
+}
+
+/// The datasets left out of repl - none with --single-dataset, which sends only from_pool, and
+/// with --redact also those that redact the whole of themselves.
+fn repl_excluded(opt: &ReplOpt) -> Result<Vec<String>, ()> {
+    if opt.single_dataset {
+        return Ok(Vec::new());
+    }
+    let mut excluded = exclude::excluded(opt.from_pool.as_str())?;
+    if opt.redact {
+        for fs in redact::left_out(opt.from_pool.as_str())? {
+            if !excluded.contains(&fs) {
+                excluded.push(fs);
+            }
+        }
+    }
+    Ok(excluded)
+}
+
+fn create_recurse_snap(dry: bool, snap_name: &str) -> Result<(), ()> {
+    let _span =
+        info_span!(target: telemetry::SPANS, "snapshot", snap = snap_name, recursive = true)
+            .entered();
+    if dry {
+        info!("dryrun: create_recurse_snap -> {}", snap_name);
+        plan::create(snap_name, true);
+        Ok(())
+    } else {
+        info!("create_recurse_snap -> {}", snap_name);
+        if lzc::active() {
+            return create_recurse_snap_native(snap_name);
+        }
+        privilege::zfs()
+            .arg("snapshot")
+            .arg("-r")
+            .arg(snap_name)
+            .run_status(Kind::Zfs)
+            .map_err(|e| {
+                error!("snapshot create failed -> {:?}", e);
+            })
+            .map(|status| {
+                debug!(?status);
+                history::record(
+                    history::Kind::SnapshotCreate {
+                        snapshot: snap_name.to_string(),
+                        recursive: true,
+                    },
+                    status.success(),
+                );
+            })
+    }
+}
+
+/// As zfs snapshot -r, through libzfs_core - the dataset and each of its descendants, at once.
+fn create_recurse_snap_native(snap_name: &str) -> Result<(), ()> {
+    let (dataset, short) = snap_name.split_once('@').ok_or_else(|| {
+        error!("{} is not a snapshot", snap_name);
+    })?;
+    let snaps: Vec<_> = dataset_list(dataset)?
+        .iter()
+        .map(|ds| format!("{}@{}", ds, short))
+        .collect();
+    let res = lzc::snapshot(&snaps, &[]);
+    history::record(
+        history::Kind::SnapshotCreate {
+            snapshot: snap_name.to_string(),
+            recursive: true,
+        },
+        res.is_ok(),
+    );
+    res.map_err(|failures| {
+        error!(
+            "snapshot create of {} failed -> {}",
+            snap_name,
+            lzc::describe(&failures)
+        );
+    })
+}
+
+/// Which of the snapshots `names` exist. zfs lists the ones that do, and complains of the rest.
+fn existing_snapshots(names: &[String]) -> Result<Vec<String>, ()> {
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let output = privilege::zfs()
+        .arg("list")
+        .arg("-H")
+        .arg("-o")
+        .arg("name")
+        .arg("-t")
+        .arg("snapshot")
+        .args(names)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zfs list failed -> {:?}", e);
+        })?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| names.iter().any(|name| name == line))
+        .map(str::to_string)
+        .collect())
+}
+
+/// The most runs in one second that can each have their own name.
+const MAX_SNAP_COUNTER: usize = 100;
+
+/// The name for the auto snapshots of `filesystems` taken at `ts` - `auto_<ts>`, or when a run of
+/// the same second already took that for any of them, `auto_<ts>_1`, `auto_<ts>_2` and so on, so
+/// the snapshots of a run still share a name. The counter comes after the time, so the name
+/// still parses to it.
+fn free_snap_name(filesystems: &[String], ts: &str) -> Result<String, ()> {
+    for n in 0..MAX_SNAP_COUNTER {
+        let short = match n {
+            0 => format!("auto_{}", ts),
+            n => format!("auto_{}_{}", ts, n),
+        };
+        let names: Vec<_> = filesystems
+            .iter()
+            .map(|fs| format!("{}@{}", fs, short))
+            .collect();
+        let existing = existing_snapshots(&names)?;
+        if existing.is_empty() {
+            return Ok(short);
+        }
+        info!("{} already exists, counting up", existing.join(", "));
+    }
+    error!(
+        "auto_{} to auto_{}_{} all already exist",
+        ts,
+        ts,
+        MAX_SNAP_COUNTER - 1
+    );
+    Err(())
+}
+
+/// The filesystems snapshot takes, each with the group it is taken for - every mounted
+/// filesystem under the pools, or with --group, under the datasets of the groups.
+fn snapshot_targets(opt: &Opt) -> Result<Vec<(String, Option<String>)>, ()> {
+    if !opt.group {
+        return Ok(mounted_list(&opt.pools)?
+            .into_iter()
+            .map(|fs| (fs, None))
+            .collect());
+    }
+    let mut targets: Vec<(String, Option<String>)> = Vec::new();
+    for group in opt.pools.iter() {
+        for fs in mounted_list(&groups::datasets(group)?)? {
+            // A dataset of two groups is only snapshotted once, for the first.
+            if !targets.iter().any(|(taken, _)| *taken == fs) {
+                targets.push((fs, Some(group.clone())));
+            }
+        }
+    }
+    Ok(targets)
+}
+
+fn do_snap(opt: &Opt) -> Result<Snapped, ()> {
+    let _span =
+        info_span!(target: telemetry::SPANS, "snapshot", pools = %opt.pools.join(" ")).entered();
+    let (mounted, groups): (Vec<_>, Vec<_>) = match snapshot_targets(opt) {
+        Ok(targets) => targets.into_iter().unzip(),
+        Err(_) => {
+            return Err(());
+        }
+    };
+
+    let now_ts = naming::now()?;
+    let short = free_snap_name(&mounted, &now_ts)?;
+
+    // Every snapshot of this run is tagged with the same id, so that sets of snapshots that were
+    // taken together can be told apart from ones that merely share a name.
+    let run_id = format!("{}_{}", now_ts, std::process::id());
+
+    let snap_names: Vec<_> = mounted
+        .iter()
+        .map(|fs| format!("{}@{}", fs, short))
+        .collect();
+    // A dry run creates nothing, so plan it in order.
+    let jobs = if opt.dryrun { 1 } else { opt.jobs };
+    let frozen = quiesce::freeze(opt.dryrun, &mounted, false);
+    let results = create_snaps(opt.dryrun, &snap_names, &groups, &run_id, jobs);
+    drop(frozen);
+
+    // Reported together, rather than among the snapshots of the datasets that succeeded.
+    let failed: Vec<_> = results.iter().filter_map(|r| r.as_ref().err()).collect();
+    if !failed.is_empty() {
+        error!("{} of {} snapshots failed:", failed.len(), snap_names.len());
+        for e in failed.iter() {
+            error!("  {}", e);
+        }
+        return Err(());
+    }
+    if results
+        .iter()
+        .any(|r| matches!(r, Ok(Snapped::AlreadyExists)))
+    {
+        Ok(Snapped::AlreadyExists)
+    } else {
+        Ok(Snapped::Created)
+    }
+}
+
+/// The auto snapshots under `pool` that snapshot_cleanup would destroy, keeping `keep_hours`
+/// (or what each dataset declares of its own retention) and any replication anchors. With
+/// `empty`, those within retention that are the same as the snapshot before them go too.
+fn cleanup_expired(
+    pool: &str,
+    keep_hours: u32,
+    empty: bool,
+    now: OffsetDateTime,
+) -> Result<Vec<Snapshot>, ()> {
+    let snaps: Vec<_> = match auto_snap_list(pool) {
+        Ok(snaps) => snaps,
+        Err(_) => {
+            return Err(());
+        }
+    };
+
+    // A dataset's own retention wins over keep_hours, as far as it goes.
+    let overrides = retention::overrides(pool)?;
+    let mut by_dataset: BTreeMap<&str, Vec<Snapshot>> = Default::default();
+    for snap in snaps.iter() {
+        by_dataset
+            .entry(snap.dataset_name())
+            .or_default()
+            .push(snap.clone());
+    }
+    let mut expired = Vec::new();
+    let mut daily = Vec::new();
+    for (dataset, snaps) in by_dataset {
+        let own = overrides.get(dataset).copied().unwrap_or_default();
+        let keep_hours = own.keep_hours.unwrap_or(keep_hours);
+        if own != Default::default() {
+            info!(
+                "{} keeps {}h and {} daily, by its own retention",
+                dataset,
+                keep_hours,
+                own.keep_daily.unwrap_or(0)
+            );
+        }
+        expired.extend(retention_expired(
+            &snaps,
+            Some(keep_hours),
+            own.keep_daily,
+            now,
+        ));
+        if empty {
+            let undaily = retention_expired(&snaps, None, own.keep_daily, now);
+            daily.extend(snaps.into_iter().filter(|snap| !undaily.contains(snap)));
+        }
+    }
+    if empty {
+        let empties = empty_snapshots(pool, &snaps, &expired, &daily)?;
+        expired.extend(empties);
+    }
+
+    // Snapshots without a time in their name were not taken by znapper, so are kept.
+    let remove_snaps: Vec<_> = snaps
+        .into_iter()
+        .filter(|snap| expired.contains(snap))
+        .collect();
+
+    debug!("would remove -> {:?}", remove_snaps);
+
+    let anchors = match AnchorStore::load() {
+        Ok(a) => a,
+        Err(_) => return Err(()),
+    };
+
+    Ok(remove_snaps
+        .into_iter()
+        .filter(|snap| {
+            let protected = anchors.is_protected(snap.name(), None);
+            if protected {
+                info!("Keeping {} - it is a replication anchor", snap);
+            }
+            !protected
+        })
+        .collect())
+}
+
+/// Of the auto snapshots `snaps`, those that hold nothing the snapshot before them doesn't - a
+/// used and written of 0 - other than the ones in `daily`. One of each run of snapshots that are
+/// all the same is always kept, so if the first of a run is `expired` the next is kept in its
+/// place.
+fn empty_snapshots(
+    pool: &str,
+    snaps: &[Snapshot],
+    expired: &[Snapshot],
+    daily: &[Snapshot],
+) -> Result<Vec<Snapshot>, ()> {
+    let output = privilege::zfs()
+        .arg("list")
+        .arg("-H")
+        .arg("-p")
+        .arg("-r")
+        .arg("-t")
+        .arg("snapshot")
+        .arg("-o")
+        .arg("name,createtxg,used,written")
+        .arg(pool)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zfs list failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!(
+            "zfs list -o used,written of {} failed -> {}",
+            pool,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(());
+    }
+
+    // Every snapshot counts for written, whatever its class.
+    let mut by_dataset: BTreeMap<String, Vec<(u64, String, bool)>> = Default::default();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let f: Vec<_> = line.split('\t').collect();
+        if f.len() < 4 {
+            continue;
+        }
+        let (dataset, _) = match f[0].split_once('@') {
+            Some(split) => split,
+            None => continue,
+        };
+        let same = f[2] == "0" && f[3] == "0";
+        by_dataset.entry(dataset.to_string()).or_default().push((
+            f[1].parse().unwrap_or(0),
+            f[0].to_string(),
+            same,
+        ));
+    }
+
+    let mut empties = Vec::new();
+    for (_, mut listed) in by_dataset {
+        listed.sort_unstable();
+        // Whether a snapshot of the run the walk is in - the same as each other - is kept.
+        let mut run_kept = false;
+        for (_, name, same) in listed {
+            let snap = snaps.iter().find(|snap| snap.name() == name);
+            let destroyed = snap.map(|snap| expired.contains(snap)).unwrap_or(false);
+            if !same {
+                run_kept = !destroyed;
+                continue;
+            }
+            match snap {
+                Some(snap) if run_kept && !destroyed && !daily.contains(snap) => {
+                    info!("{} is empty - the same as the snapshot before it", snap);
+                    empties.push(snap.clone());
+                }
+                _ => run_kept |= !destroyed,
+            }
+        }
+    }
+    Ok(empties)
+}
+
+fn do_snap_cleanup(opt: &CleanupOpt) -> Result<(), ()> {
+    let _span = info_span!(target: telemetry::SPANS, "cleanup", pool = %opt.pool).entered();
+    let now = OffsetDateTime::try_now_local().map_err(|_| {
+        error!("Unable to determine time");
+    })?;
+    if opt.group {
+        let mut res = Ok(());
+        for (dataset, remove_snaps) in groups::expired(&opt.pool, opt.keep_hours, opt.empty, now)? {
+            if cleanup_snaps(opt, &dataset, remove_snaps).is_err() {
+                res = Err(());
+            }
+        }
+        return res;
+    }
+    let remove_snaps = cleanup_expired(opt.pool.as_str(), opt.keep_hours, opt.empty, now)?;
+    cleanup_snaps(opt, &opt.pool, remove_snaps)
+}
+
+/// Destroy `remove_snaps`, the expired snapshots of `pool` - or set them aside with --defer -
+/// once approved and confirmed.
+fn cleanup_snaps(opt: &CleanupOpt, pool: &str, remove_snaps: Vec<Snapshot>) -> Result<(), ()> {
+    let purged = trash::purge(opt.dryrun, pool);
+    let names: Vec<_> = remove_snaps.iter().map(|s| s.name().to_string()).collect();
+    let names = approval::gate_destroy(opt.dryrun, pool, &names)?;
+    let remove_snaps: Vec<_> = remove_snaps
+        .into_iter()
+        .filter(|s| names.iter().any(|n| n == s.name()))
+        .collect();
+    confirm::destroy(opt.dryrun, pool, &names)?;
+
+    if opt.defer {
+        let grace = opt.grace.unwrap_or(trash::GRACE);
+        return trash::defer(opt.dryrun, pool, &names, grace).and(purged);
+    }
+    let mut res = purged;
+    for snap in remove_snaps {
+        if process::cancelled() {
+            res = Err(());
+            break;
+        }
+        if remove_snap_only(opt.dryrun, snap.name(), audit::Reason::Retention).is_err() {
+            res = Err(());
+        }
+    }
+    res
+}
+
+fn do_init(opt: &ReplOpt) -> Result<(), ()> {
+    debug!("do_init");
+
+    let now_ts = naming::now()?;
+
+    let mut anchors = match AnchorStore::load() {
+        Ok(a) => a,
+        Err(_) => return Err(()),
+    };
+
+    debug!("{:?}", now_ts);
+
+    let (snaps, bookmarks) = repl_anchors(opt)?;
+
+    /*
+     * Init a base snap
+     * Set the hold on the basesnap
+     */
+    let basesnap_name = format!("{}@repl_{}", opt.from_pool, now_ts);
+
+    let excluded = repl_excluded(opt)?;
+
+    let dests = match repl_destinations(opt) {
+        Ok(dests) => dests,
+        Err(_) => return Err(()),
+    };
+
+    if create_repl_snap(opt, basesnap_name.as_str(), &now_ts).is_err() {
+        return Err(());
+    }
+
+    /*
+     * do the send/recv
+     * -w for encyrption to stay raw. Is that needed locally?
+     */
+    let dest_count = dests.len();
+    let mut replicated = Vec::new();
+    let mut unconfirmed = false;
+    for dest in dests {
+        if create_parents(opt.dryrun, dest.to_pool.as_str()).is_err() {
+            error!("Initial replication to {} failed", dest.to_pool);
+            continue;
+        }
+        let res = if opt.redact {
+            do_repl_redact_inner(&dest, None, &basesnap_name, &excluded)
+        } else if (opt.jobs > 1 && !opt.single_dataset) || !excluded.is_empty() {
+            do_repl_split_inner(&dest, None, &basesnap_name, &excluded)
+        } else {
+            local_send_recv(
+                opt,
+                &[
+                    "-v",
+                    "-P",
+                    recursion_flag(opt.single_dataset),
+                    "-w",
+                    "-L",
+                    basesnap_name.as_str(),
+                ],
+                &[],
+                dest.to_pool.as_str(),
+            )
+        };
+        // The previous anchors only go once the destination is known to hold the new one.
+        if res.is_ok()
+            && !opt.dryrun
+            && validate_received(&dest, &basesnap_name, &excluded).is_err()
+        {
+            error!("Initial replication to {} did not validate", dest.to_pool);
+            unconfirmed = true;
+            continue;
+        }
+        if res.is_ok() && opt.dryrun {
+            let estimates = estimate::stream(
+                opt.from_pool.as_str(),
+                !opt.single_dataset,
+                None,
+                &dry_estimate_to(opt, &basesnap_name),
+            )
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(fs, _)| !excluded.contains(fs))
+            .collect::<Vec<_>>();
+            plan::transfer(plan::Transfer {
+                source: opt.from_pool.clone(),
+                from: None,
+                to: basesnap_name.clone(),
+                destination: dest.to_pool.clone(),
+                estimated_bytes: estimated_total(&estimates),
+                resume_token: None,
+            });
+        }
+        if res.is_ok() {
+            info!("Initial replication to {} success", dest.to_pool);
+            replicated.push(dest.to_pool);
+        } else {
+            error!("Initial replication to {} failed", dest.to_pool);
+        }
+    }
+
+    /*
+     * Remove any holds/previous snaps from previous repls
+     */
+    let kept = kept_anchors(opt, &snaps, &bookmarks);
+    if replicated.is_empty() && unconfirmed {
+        warn!("Keeping {} - a destination may hold it", basesnap_name);
+    } else {
+        finish_repl(
+            opt,
+            &mut anchors,
+            &basesnap_name,
+            &replicated,
+            &snaps,
+            &bookmarks,
+            &kept,
+        );
+    }
+    if replicated.len() == dest_count {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// The to_pool and every --to destination of `opt` with their templates expanded, each as its
+/// own single destination opt.
+fn repl_destinations(opt: &ReplOpt) -> Result<Vec<ReplOpt>, ()> {
+    let mut to_pools = vec![expand_dest_path(
+        opt.to_pool.as_str(),
+        opt.from_pool.as_str(),
+    )?];
+    for to in opt.to.iter() {
+        let to = expand_dest_path(to.as_str(), opt.from_pool.as_str())?;
+        if !to_pools.contains(&to) {
+            to_pools.push(to);
+        }
+    }
+    Ok(to_pools
+        .into_iter()
+        .map(|to_pool| ReplOpt {
+            to_pool,
+            to: Vec::new(),
+            ..opt.clone()
+        })
+        .collect())
+}
+
+/// The locks of a repl - of the pool it sends from, and of each it receives into.
+fn repl_locks(opt: &ReplOpt) -> Vec<String> {
+    std::iter::once(lock::pool(&opt.from_pool))
+        .chain(
+            std::iter::once(&opt.to_pool)
+                .chain(opt.to.iter())
+                .map(|to| lock::pool(&dest_lock_path(to, &opt.from_pool))),
+        )
+        .collect()
+}
+
+/// The destination `template` to lock, as it is replaced, or as it is if it can't be.
+fn dest_lock_path(template: &str, from_pool: &str) -> String {
+    expand_dest_path(template, from_pool).unwrap_or_else(|()| template.to_string())
+}
+
+/// Replace %hostname% and %dataset% in a destination `template`.
+fn expand_dest_path(template: &str, from_pool: &str) -> Result<String, ()> {
+    let mut dest = template.replace("%dataset%", from_pool);
+    if dest.contains("%hostname%") {
+        dest = dest.replace("%hostname%", hostname()?.as_str());
+    }
+    if dest.contains('%') {
+        error!("Unknown template in destination {}", template);
+        return Err(());
+    }
+    debug!("destination {} -> {}", template, dest);
+    Ok(dest)
+}
+
+fn hostname() -> Result<String, ()> {
+    let output = Command::new("hostname").output().map_err(|e| {
+        error!("hostname failed -> {:?}", e);
+    })?;
+    let hostname = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || hostname.is_empty() {
+        error!("Unable to determine hostname");
+        return Err(());
+    }
+    Ok(hostname)
+}
+
+fn dataset_exists(name: &str) -> bool {
+    privilege::zfs()
+        .arg("list")
+        .arg("-H")
+        .arg("-o")
+        .arg("name")
+        .arg(name)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .run_status(Kind::Zfs)
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// zfs recv only creates the last component of the destination, so create any missing parents.
+/// They are not mounted, as they only exist to hold the replicas.
+fn create_parents(dry: bool, dataset: &str) -> Result<(), ()> {
+    let parent = match dataset.rsplit_once('/') {
+        Some((parent, _)) => parent,
+        None => return Ok(()),
+    };
+    if dataset_exists(parent) {
+        return Ok(());
+    }
+    if dry {
+        info!("dryrun: create_parents -> {}", parent);
+        plan::dataset(plan::DatasetChange::Create {
+            name: parent.to_string(),
+        });
+        Ok(())
+    } else {
+        info!("create_parents -> {}", parent);
+        let status = privilege::zfs()
+            .arg("create")
+            .arg("-p")
+            .arg("-o")
+            .arg("canmount=off")
+            .arg(parent)
+            .run_status(Kind::Zfs)
+            .map_err(|e| {
+                error!("dataset create failed -> {:?}", e);
+            })?;
+        debug!(?status);
+        if status.success() {
+            Ok(())
+        } else {
+            error!("dataset create failed -> {}", parent);
+            Err(())
+        }
+    }
+}
+
+fn do_repl(opt: &ReplOpt) -> Result<(), ()> {
+    debug!("do_repl");
+
+    let dests = repl_destinations(opt)?;
+    let to_pools: Vec<_> = dests.iter().map(|dest| dest.to_pool.clone()).collect();
+    let replicated = repl_to(opt, dests);
+    for to_pool in to_pools.iter() {
+        status::record(
+            opt.dryrun,
+            &Owner::new("repl", to_pool),
+            &opt.from_pool,
+            replicated.contains(to_pool),
+        );
+    }
+    if replicated.len() == to_pools.len() {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Replicate `opt.from_pool` to each of `dests`, returning the destinations that succeeded.
+fn repl_to(opt: &ReplOpt, dests: Vec<ReplOpt>) -> Vec<String> {
+    let now_ts = match naming::now() {
+        Ok(t) => t,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut anchors = match AnchorStore::load() {
+        Ok(a) => a,
+        Err(_) => return Vec::new(),
+    };
+
+    let (from_snaps, from_bookmarks) = match repl_anchors(opt) {
+        Ok(anchors) => anchors,
+        Err(_) => return Vec::new(),
+    };
+
+    if zed::check(&opt.from_pool).is_err() {
+        return Vec::new();
+    }
+    let excluded = match repl_excluded(opt) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    if !opt.skip_preflight {
+        let job = check::Job {
+            sources: vec![opt.from_pool.clone()],
+            destinations: dests.iter().map(|d| d.to_pool.clone()).collect(),
+            require_healthy: opt.require_healthy,
+            ..Default::default()
+        };
+        if check::preflight(&job).is_err() {
+            return Vec::new();
+        }
+    }
+
+    // Work out the precursor of every destination before creating the new repl snap.
+    let mut plans = Vec::new();
+    for dest in dests {
+        match repl_precursor(&dest, &from_snaps, &from_bookmarks) {
+            Ok((precursor, to_snaps)) => plans.push((dest, precursor, to_snaps)),
+            Err(_) => error!("Skipping replication to {}", dest.to_pool),
+        }
+    }
+    if plans.is_empty() {
+        return Vec::new();
+    }
+
+    /*
+     * Init a new repl snap
+     */
+    let basesnap_name = format!("{}@repl_{}", opt.from_pool, now_ts);
+    if create_repl_snap(opt, basesnap_name.as_str(), &now_ts).is_err() {
+        return Vec::new();
+    }
+
+    /*
+     * do the send/recv
+     */
+    // zfs send -R -h -L nvme@snap1 | zfs recv -o mountpoint=none -o readonly=on tank/nvme
+    let mut replicated = Vec::new();
+    let mut unconfirmed = false;
+    let mut dest_cleanups = Vec::new();
+    let estimate_to = dry_estimate_to(opt, &basesnap_name);
+    for (dest, precursor, to_snaps) in plans {
+        if process::cancelled() {
+            break;
+        }
+        let estimates = match estimate::stream(
+            opt.from_pool.as_str(),
+            !opt.single_dataset,
+            precursor.as_deref(),
+            &estimate_to,
+        ) {
+            Ok(estimates) => estimates
+                .into_iter()
+                .filter(|(fs, _)| !excluded.contains(fs))
+                .collect(),
+            Err(_) => {
+                warn!("Unable to estimate the stream to {}", dest.to_pool);
+                Vec::new()
+            }
+        };
+
+        if !estimates.is_empty() {
+            let fits = match estimate::pool_free(dest.to_pool.as_str()) {
+                Ok(free) => {
+                    estimate::check_space(&dest.to_pool, &estimates, free, opt.ignore_space)
+                }
+                Err(_) => {
+                    warn!("Unable to check the free space for {}", dest.to_pool);
+                    Ok(())
+                }
+            };
+            if fits.is_err() {
+                error!("Skipping replication to {}", dest.to_pool);
+                continue;
+            }
+        }
+
+        if let (Some(factor), Some(_)) = (opt.anomaly_factor, precursor.as_deref()) {
+            let anomalies = anomaly::check(&dest.to_pool, &estimates, factor);
+            if !anomalies.is_empty() && opt.pause_on_anomaly {
+                error!(
+                    "Pausing replication to {} - anomalous incrementals of {}",
+                    dest.to_pool,
+                    anomalies.join(", ")
+                );
+                continue;
+            }
+        }
+
+        let res = match precursor.as_deref() {
+            Some(precursor) if opt.redact => {
+                do_repl_redact_inner(&dest, Some(precursor), &basesnap_name, &excluded)
+            }
+            Some(precursor) if (opt.jobs > 1 && !opt.single_dataset) || !excluded.is_empty() => {
+                do_repl_split_inner(&dest, Some(precursor), &basesnap_name, &excluded)
+            }
+            Some(precursor) if precursor.contains('#') => {
+                do_repl_bookmark_inner(&dest, precursor, &basesnap_name)
+            }
+            Some(precursor) => do_repl_inner(&dest, precursor, &basesnap_name),
+            None => do_repl_fallback_full(&dest, &now_ts, &basesnap_name, &excluded),
+        };
+        match res {
+            Ok(())
+                if !opt.dryrun && validate_received(&dest, &basesnap_name, &excluded).is_err() =>
+            {
+                error!(
+                    "Replication to {} did not validate - its previous anchor is kept",
+                    dest.to_pool
+                );
+                unconfirmed = true;
+            }
+            Ok(()) => {
+                if opt.dryrun {
+                    plan::transfer(plan::Transfer {
+                        source: opt.from_pool.clone(),
+                        from: precursor.clone(),
+                        to: basesnap_name.clone(),
+                        destination: dest.to_pool.clone(),
+                        estimated_bytes: estimated_total(&estimates),
+                        resume_token: None,
+                    });
+                }
+                if opt.anomaly_factor.is_some() && precursor.is_some() && !estimates.is_empty() {
+                    anomaly::record(opt.dryrun, &dest.to_pool, &estimates);
+                }
+                if !opt.dryrun {
+                    info!("Replication to {} success", dest.to_pool);
+                }
+                replicated.push(dest.to_pool.clone());
+                // After a full fallback the old repl snaps went aside with the stale dataset.
+                let leftover_snaps = if precursor.is_some() {
+                    to_snaps
+                } else {
+                    Vec::new()
+                };
+                dest_cleanups.push((dest, leftover_snaps));
+            }
+            Err(_) => error!("Replication to {} failed", dest.to_pool),
+        }
+    }
+
+    /*
+     * Remove any holds/previous snaps from previous repls on source and dest
+     */
+    let kept = kept_anchors(opt, &from_snaps, &from_bookmarks);
+    if replicated.is_empty() && unconfirmed {
+        // Not knowing whether it was received, the next run can still send from it if it was.
+        warn!("Keeping {} - a destination may hold it", basesnap_name);
+    } else {
+        finish_repl(
+            opt,
+            &mut anchors,
+            &basesnap_name,
+            &replicated,
+            &from_snaps,
+            &from_bookmarks,
+            &kept,
+        );
+    }
+
+    for (dest, to_snaps) in dest_cleanups {
+        debug!("Available Repl Snaps -> {:?}", to_snaps);
+        for leftover_snap in to_snaps {
+            if kept.iter().any(|k| k == leftover_snap.short_name()) {
+                info!("Keeping {} - --keep-anchors", leftover_snap);
+            } else {
+                let _ = remove_snap(opt.dryrun, leftover_snap.name(), audit::Reason::Superseded);
+            }
+        }
+
+        apply_dest_retention(&dest);
+        let _ = exclude::prune(
+            opt.dryrun,
+            opt.from_pool.as_str(),
+            dest.to_pool.as_str(),
+            &excluded,
+        );
+    }
+
+    replicated
+}
+
+/// The guid of `name`, if it exists.
+fn snapshot_guid(name: &str) -> Option<String> {
+    let output = privilege::zfs()
+        .args(["get", "-H", "-p", "-o", "value", "guid", name])
+        .run_output(Kind::Zfs)
+        .ok()?;
+    let guid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.status.success() && !guid.is_empty() {
+        Some(guid)
+    } else {
+        None
+    }
+}
+
+/// Check that every dataset of `opt.from_pool` holding `basesnap_name` has it received in its
+/// place under `opt.to_pool`, with the same guid, returning how many do. A recv that exited
+/// cleanly may still have applied partially, or to the wrong dataset, so until this passes the
+/// replication hasn't succeeded and the previous anchor is kept.
+fn validate_received(opt: &ReplOpt, basesnap_name: &str, excluded: &[String]) -> Result<usize, ()> {
+    let basesnap_short = short_name(basesnap_name);
+    let redact_clone = redact::clone_name(opt.from_pool.as_str());
+    let mut datasets = repl_datasets(opt)?;
+    if !datasets.contains(&opt.from_pool) {
+        datasets.insert(0, opt.from_pool.clone());
+    }
+
+    let mut validated = 0;
+    let mut problems = Vec::new();
+    for fs in datasets
+        .iter()
+        .filter(|fs| **fs != redact_clone && !excluded.contains(fs))
+    {
+        // A dataset created since the snapshot was taken isn't part of the stream.
+        let guid = match snapshot_guid(&format!("{}@{}", fs, basesnap_short)) {
+            Some(guid) => guid,
+            None if *fs == opt.from_pool => {
+                problems.push(format!("{} has no guid", basesnap_name));
+                continue;
+            }
+            None => continue,
+        };
+        let received = format!(
+            "{}{}@{}",
+            opt.to_pool,
+            fs.strip_prefix(opt.from_pool.as_str()).unwrap_or(""),
+            basesnap_short
+        );
+        match snapshot_guid(&received) {
+            Some(received_guid) if received_guid == guid => validated += 1,
+            Some(received_guid) => problems.push(format!(
+                "{} has guid {}, not the {} of the source",
+                received, received_guid, guid
+            )),
+            None => problems.push(format!("{} was not received", received)),
+        }
+    }
+
+    if problems.is_empty() {
+        debug!(
+            "{} validated on {} datasets of {}",
+            basesnap_short, validated, opt.to_pool
+        );
+        Ok(validated)
+    } else {
+        for problem in problems.iter() {
+            error!("{}", problem);
+        }
+        Err(())
+    }
+}
+
+/// The short names of the newest --keep-anchors previous anchors of the source root, snapshots
+/// or bookmarks, which cleanup leaves on both sides.
+fn kept_anchors(
+    opt: &ReplOpt,
+    from_snaps: &[Snapshot],
+    from_bookmarks: &[Snapshot],
+) -> Vec<String> {
+    let mut anchors: Vec<_> = from_snaps
+        .iter()
+        .chain(from_bookmarks.iter())
+        .filter(|anchor| anchor.dataset_name() == opt.from_pool)
+        .collect();
+    anchors.sort_by_key(|anchor| std::cmp::Reverse((anchor.timestamp(), anchor.createtxg())));
+    let mut kept: Vec<String> = Vec::new();
+    for anchor in anchors {
+        if kept.len() >= opt.keep_anchors {
+            break;
+        }
+        if !kept.iter().any(|k| k == anchor.short_name()) {
+            kept.push(anchor.short_name().to_string());
+        }
+    }
+    kept
+}
+
+/// The snapshot to estimate a send of `basesnap_name` up to - in a dry run basesnap was never
+/// taken, so the newest snapshot there is now stands in for it.
+fn dry_estimate_to(opt: &ReplOpt, basesnap_name: &str) -> String {
+    if !opt.dryrun {
+        return basesnap_name.to_string();
+    }
+    match estimate::newest_snapshot(opt.from_pool.as_str()) {
+        Ok(Some(newest)) => {
+            debug!("dryrun: estimating up to {}", newest);
+            newest
+        }
+        _ => basesnap_name.to_string(),
+    }
+}
+
+/// The total of `estimates`, none if there are none.
+fn estimated_total(estimates: &[(String, u64)]) -> Option<u64> {
+    if estimates.is_empty() {
+        None
+    } else {
+        Some(estimates.iter().map(|(_, size)| size).sum())
+    }
+}
+
+/// Find the anchor that `opt.to_pool` shares with the source, and the repl snaps it has. A
+/// precursor of None means there is no anchor, and a full send should be used instead.
+fn repl_precursor(
+    opt: &ReplOpt,
+    from_snaps: &[Snapshot],
+    from_bookmarks: &[Snapshot],
+) -> Result<(Option<String>, Vec<Snapshot>), ()> {
+    let to_snaps: Vec<_> = repl_snap_list(opt.to_pool.as_str())?;
+
+    // A -R stream is sent from an anchor of the root, which the destination holds as the
+    // snapshot of the same name (and guid) on its root. Children are not compared - their
+    // snapshots of the same name are part of the same anchor.
+    let to_root: Vec<_> = to_snaps
+        .iter()
+        .filter(|to_snap| to_snap.dataset_name() == opt.to_pool)
+        .collect();
+    let on_dest = |anchor: &&Snapshot| {
+        anchor.dataset_name() == opt.from_pool
+            && to_root.iter().any(|to_snap| {
+                debug!("{} == {}", to_snap, anchor);
+                to_snap.same_as(anchor)
+            })
+    };
+
+    // The newest by the time in their names, as names of older and newer templates don't sort
+    // in the order they were taken.
+    let newest = |anchor: &&Snapshot| (anchor.timestamp(), anchor.createtxg());
+
+    // Was a previous run anchored on a bookmark that the destination still has as a snapshot?
+    let precursor_bookmark = from_bookmarks.iter().filter(on_dest).max_by_key(newest);
+
+    // What is the precursor snap? We remove it from the set of cleanup snaps.
+    let precursor_snap = from_snaps.iter().filter(on_dest).max_by_key(newest);
+
+    // Prefer whichever anchor is the most recent.
+    let precursor_name = match (precursor_snap, precursor_bookmark) {
+        (Some(s), Some(b)) if b.timestamp() > s.timestamp() => b.name().to_string(),
+        (Some(s), _) => s.name().to_string(),
+        (None, Some(b)) => b.name().to_string(),
+        (None, None) => {
+            if opt.fallback_full {
+                warn!(
+                    "No previous matching snaps available for {} - falling back to full replication",
+                    opt.to_pool
+                );
+                approval::gate(
+                    opt.dryrun,
+                    approval::Kind::FullResync,
+                    opt.to_pool.as_str(),
+                    &format!(
+                        "full send of {} replacing {}, which is kept as _stale_",
+                        opt.from_pool, opt.to_pool
+                    ),
+                )?;
+                return Ok((None, to_snaps));
+            } else {
+                error!(
+                    "No previous matching snaps available for {} - you may need to restart repl, or use --fallback-full",
+                    opt.to_pool
+                );
+                return Err(());
+            }
+        }
+    };
+
+    if opt.force_rollback {
+        check_rollback_destination(opt)?;
+        let rollback = format!(
+            "zfs recv -F into {}, rolling back to {}",
+            opt.to_pool, precursor_name
+        );
+        approval::gate(
+            opt.dryrun,
+            approval::Kind::Rollback,
+            opt.to_pool.as_str(),
+            &rollback,
+        )?;
+        confirm::gate(opt.dryrun, &rollback, &[])?;
+        audit::rollback(
+            opt.dryrun,
+            opt.to_pool.as_str(),
+            &precursor_name,
+            snapshot_guid(&precursor_name),
+        );
+    }
+
+    Ok((Some(precursor_name), to_snaps))
+}
+
+/// Prune auto snapshots on the destination according to the --dest-keep-* policy.
+fn apply_dest_retention(opt: &ReplOpt) {
+    if opt.dest_keep_hours.is_none() && opt.dest_keep_daily.is_none() {
+        return;
+    }
+    let _ = prune_auto(
+        opt.dryrun,
+        opt.to_pool.as_str(),
+        opt.dest_keep_hours,
+        opt.dest_keep_daily,
+    );
+}
+
+/// Destroy the auto snapshots under `pool` that fall outside of the hourly and daily policies,
+/// other than replication anchors, returning how many were.
+fn prune_auto(
+    dry: bool,
+    pool: &str,
+    keep_hours: Option<u32>,
+    keep_daily: Option<u32>,
+) -> Result<usize, ()> {
+    let now = OffsetDateTime::try_now_local().map_err(|_| {
+        error!("Unable to determine time");
+    })?;
+
+    let snaps = auto_snap_list(pool)?;
+    let anchors = AnchorStore::load()?;
+
+    let remove_snaps = retention_expired(&snaps, keep_hours, keep_daily, now);
+    debug!("retention would remove -> {:?}", remove_snaps);
+
+    // The destination may itself be the source of another replication.
+    let remove_snaps: Vec<_> = remove_snaps
+        .into_iter()
+        .filter(|snap| {
+            let protected = anchors.is_protected(snap.name(), None);
+            if protected {
+                info!("Keeping {} - it is a replication anchor", snap);
+            }
+            !protected
+        })
+        .collect();
+
+    let names: Vec<_> = remove_snaps.iter().map(|s| s.name().to_string()).collect();
+    let names = approval::gate_destroy(dry, pool, &names)?;
+    let remove_snaps: Vec<_> = remove_snaps
+        .into_iter()
+        .filter(|s| names.iter().any(|n| n == s.name()))
+        .collect();
+    confirm::destroy(dry, pool, &names)?;
+
+    let mut removed = 0;
+    for snap in remove_snaps {
+        if remove_snap_only(dry, snap.name(), audit::Reason::Retention).is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Which of the auto snapshots `snaps` fall outside of both the hourly and daily policies. Each
+/// dataset is considered separately, and snapshots without a time in their name are kept.
+fn retention_expired(
+    snaps: &[Snapshot],
+    keep_hours: Option<u32>,
+    keep_daily: Option<u32>,
+    now: OffsetDateTime,
+) -> Vec<Snapshot> {
+    let hourly_from = keep_hours.map(|h| now - time::Duration::hours(h as i64));
+
+    let mut by_dataset: BTreeMap<&str, Vec<(OffsetDateTime, &Snapshot)>> = Default::default();
+    for snap in snaps.iter().filter(|snap| snap.is_auto()) {
+        if let Some(t) = snap.timestamp() {
+            by_dataset
+                .entry(snap.dataset_name())
+                .or_default()
+                .push((t, snap));
+        }
+    }
+
+    let mut expired = Vec::new();
+    for (_, mut timed) in by_dataset {
+        timed.sort_unstable();
+
+        // Newest first, keep the first snapshot we see of each of the latest keep_daily days -
+        // the days of the local calendar, however the snapshots are named.
+        let mut days_kept = Vec::new();
+        let mut daily = Vec::new();
+        for (t, snap) in timed.iter().rev() {
+            let day = t.to_offset(now.offset()).date();
+            if keep_daily
+                .map(|d| days_kept.len() < d as usize)
+                .unwrap_or(false)
+                && !days_kept.contains(&day)
+            {
+                days_kept.push(day);
+                daily.push(*snap);
+            }
+        }
+
+        for (t, snap) in timed {
+            let hourly = hourly_from.map(|from| t >= from).unwrap_or(false);
+            if !hourly && !daily.contains(&snap) {
+                expired.push(snap.clone());
+            }
+        }
+    }
+    expired
+}
+
+/// Full send of the new repl snapshot into `<to_pool>_resync`, then rename the old destination
+/// aside and the resync into its place so that the next repl has a common anchor again.
+fn do_repl_fallback_full(
+    opt: &ReplOpt,
+    now_ts: &str,
+    basesnap_name: &str,
+    excluded: &[String],
+) -> Result<(), ()> {
+    if !opt.to_pool.contains('/') {
+        error!(
+            "Can not fall back to full replication into the pool root {}",
+            opt.to_pool
+        );
+        return Err(());
+    }
+
+    let resync_name = format!("{}_resync", opt.to_pool);
+    let resync_opt = ReplOpt {
+        to_pool: resync_name.clone(),
+        ..opt.clone()
+    };
+    if opt.redact {
+        do_repl_redact_inner(&resync_opt, None, basesnap_name, excluded)?;
+    } else if !excluded.is_empty() {
+        do_repl_split_inner(&resync_opt, None, basesnap_name, excluded)?;
+    } else {
+        local_send_recv(
+            opt,
+            &[
+                "-v",
+                "-P",
+                recursion_flag(opt.single_dataset),
+                "-w",
+                "-L",
+                basesnap_name,
+            ],
+            &[],
+            resync_name.as_str(),
+        )?;
+    }
+
+    let stale_name = format!("{}_stale_{}", opt.to_pool, now_ts);
+    if rename_dataset(opt.dryrun, opt.to_pool.as_str(), stale_name.as_str()).is_err() {
+        error!(
+            "Full replication is in {} but {} could not be moved aside",
+            resync_name, opt.to_pool
+        );
+        return Err(());
+    }
+    if rename_dataset(opt.dryrun, resync_name.as_str(), opt.to_pool.as_str()).is_err() {
+        error!(
+            "Full replication is in {} but could not be renamed to {}",
+            resync_name, opt.to_pool
+        );
+        return Err(());
+    }
+    info!("Full replication fallback received");
+    warn!(
+        "The previous destination has been kept as {} - destroy it once you no longer need it",
+        stale_name
+    );
+    Ok(())
+}
+
+/// After a local replication to the `replicated` destinations - optionally convert the new
+/// anchor to bookmarks, register it in the anchor store for each, and remove their previous
+/// anchors from the source, less the `kept` of --keep-anchors. Anchors still registered by other
+/// flows (including destinations that failed this time) are left alone. If nothing was
+/// replicated the new snapshot is removed.
+fn finish_repl(
+    opt: &ReplOpt,
+    anchors: &mut AnchorStore,
+    basesnap_name: &str,
+    replicated: &[String],
+    leftover_snaps: &[Snapshot],
+    leftover_bookmarks: &[Snapshot],
+    kept: &[String],
+) {
+    let _span = info_span!(target: telemetry::SPANS, "cleanup", snap = basesnap_name).entered();
+    if replicated.is_empty() {
+        info!("Removing potentially un-sent snapshot");
+        let _ = remove_snap(opt.dryrun, basesnap_name, audit::Reason::Unsent);
+        return;
+    }
+
+    let anchor = if opt.bookmarks && convert_to_bookmarks(opt, basesnap_name).is_ok() {
+        basesnap_name.replacen('@', "#", 1)
+    } else {
+        if opt.bookmarks {
+            warn!("Unable to convert {} to bookmarks", basesnap_name);
+        }
+        basesnap_name.to_string()
+    };
+    for to_pool in replicated {
+        anchors.set(&Owner::new("repl", to_pool.as_str()), anchor.as_str());
+    }
+    if anchors.save(opt.dryrun).is_err() {
+        // Without the record other flows could remove our anchor, so keep the old ones too.
+        warn!(
+            "Unable to record anchor {} - previous anchors are kept",
+            anchor
+        );
+        return;
+    }
+
+    debug!("Available Repl Snaps -> {:?}", leftover_snaps);
+    for leftover_snap in leftover_snaps {
+        if kept.iter().any(|k| k == leftover_snap.short_name()) {
+            info!("Keeping {} - --keep-anchors", leftover_snap);
+        } else if anchors.is_protected(leftover_snap.name(), None) {
+            info!(
+                "Keeping {} - it is the anchor of another flow",
+                leftover_snap
+            );
+        } else {
+            let _ = remove_snap(opt.dryrun, leftover_snap.name(), audit::Reason::Superseded);
+        }
+    }
+    debug!("Available Repl Bookmarks -> {:?}", leftover_bookmarks);
+    for leftover_bookmark in leftover_bookmarks {
+        if kept.iter().any(|k| k == leftover_bookmark.short_name()) {
+            info!("Keeping {} - --keep-anchors", leftover_bookmark);
+        } else if anchors.is_protected(leftover_bookmark.name(), None) {
+            info!(
+                "Keeping {} - it is the anchor of another flow",
+                leftover_bookmark
+            );
+        } else {
+            let _ = remove_bookmark(opt.dryrun, leftover_bookmark.name());
+        }
+    }
+}
+
+fn recv_args(opt: &ReplOpt) -> &'static [&'static str] {
+    if opt.force_rollback {
+        &["-F"]
+    } else {
+        &[]
+    }
+}
+
+/// Only roll back destinations that look like znapper made them - readonly, and holding a repl_
+/// anchor with the same guid as one on the source.
+fn check_rollback_destination(opt: &ReplOpt) -> Result<(), ()> {
+    immutable::check_force_recv(opt.to_pool.as_str())?;
+    let readonly = get_property(opt.to_pool.as_str(), "readonly")?;
+    if readonly != "on" {
+        error!(
+            "Refusing to force rollback {} - it is not readonly, so znapper did not create it",
+            opt.to_pool
+        );
+        return Err(());
+    }
+
+    let from_guids: Vec<_> = repl_guid_list(opt.from_pool.as_str(), "snapshot")?
+        .into_iter()
+        .chain(repl_guid_list(opt.from_pool.as_str(), "bookmark")?)
+        .map(|(_, guid)| guid)
+        .collect();
+    let shared = repl_guid_list(opt.to_pool.as_str(), "snapshot")?
+        .iter()
+        .any(|(_, guid)| from_guids.contains(guid));
+    if shared {
+        Ok(())
+    } else {
+        error!(
+            "Refusing to force rollback {} - it shares no repl_ anchors with {}",
+            opt.to_pool, opt.from_pool
+        );
+        Err(())
+    }
+}
+
+fn do_repl_inner(opt: &ReplOpt, precursor_name: &str, basesnap_name: &str) -> Result<(), ()> {
+    local_send_recv(
+        opt,
+        &[
+            "-v",
+            "-P",
+            recursion_flag(opt.single_dataset),
+            "-w",
+            "-L",
+            "-I",
+            precursor_name,
+            basesnap_name,
+        ],
+        recv_args(opt),
+        opt.to_pool.as_str(),
+    )?;
+    info!("Incremental replication received");
+    Ok(())
+}
+
+/// Per-dataset incremental from the bookmarks of the precursor. Bookmarks can not be the source
+/// of a replication (-R) stream, so each dataset is sent on its own. Datasets that have no
+/// matching bookmark (ie they were created since the last repl) are sent in full.
+fn do_repl_bookmark_inner(
+    opt: &ReplOpt,
+    precursor_name: &str,
+    basesnap_name: &str,
+) -> Result<(), ()> {
+    let precursor_short = short_name(precursor_name);
+    let basesnap_short = short_name(basesnap_name);
+
+    let bookmarks = repl_bookmark_list(opt.from_pool.as_str())?;
+
+    for fs in repl_datasets(opt)? {
+        let dest = format!(
+            "{}{}",
+            opt.to_pool,
+            fs.strip_prefix(opt.from_pool.as_str()).unwrap_or("")
+        );
+        let snap = format!("{}@{}", fs, basesnap_short);
+        let bookmark = format!("{}#{}", fs, precursor_short);
+
+        if bookmarks.iter().any(|b| b.name() == bookmark) {
+            local_send_recv(
+                opt,
+                &[
+                    "-v",
+                    "-P",
+                    "-w",
+                    "-L",
+                    "-i",
+                    bookmark.as_str(),
+                    snap.as_str(),
+                ],
+                recv_args(opt),
+                dest.as_str(),
+            )?;
+        } else {
+            warn!("No bookmark {} - sending {} in full", bookmark, snap);
+            local_send_recv(
+                opt,
+                &["-v", "-P", "-w", "-L", snap.as_str()],
+                &[],
+                dest.as_str(),
+            )?;
+        }
+    }
+
+    info!("Incremental bookmark replication received");
+    Ok(())
+}
+
+/// One dataset's stream of a split replication.
+struct Stream {
+    send_args: Vec<String>,
+    incremental: bool,
+    dest: String,
+}
+
+/// Per-dataset replication, running `opt.jobs` streams at once. Each dataset is sent as an
+/// incremental from its own copy of the precursor (snapshot or bookmark) if it has one, otherwise
+/// in full. Datasets are sent a depth at a time, so that a new dataset's parent has always been
+/// received before it, and if any stream fails the rest are not started.
+///
+/// The `excluded` datasets are left out. One stream at a time from a snapshot (or in full), the
+/// subtrees with nothing left out are each sent as one -R stream rather than a stream per dataset.
+fn do_repl_split_inner(
+    opt: &ReplOpt,
+    precursor_name: Option<&str>,
+    basesnap_name: &str,
+    excluded: &[String],
+) -> Result<(), ()> {
+    let basesnap_short = short_name(basesnap_name);
+    let (sources, sep, flag) = match precursor_name {
+        Some(p) if p.contains('#') => (repl_bookmark_list(opt.from_pool.as_str())?, '#', "-i"),
+        Some(_) => (repl_snap_list(opt.from_pool.as_str())?, '@', "-I"),
+        None => (Vec::new(), '@', "-I"),
+    };
+
+    let datasets = dataset_list(opt.from_pool.as_str())?;
+    let subtrees = opt.jobs <= 1 && sep == '@';
+    let roots = if subtrees {
+        exclude::streams(&datasets, excluded)
+    } else {
+        datasets
+            .into_iter()
+            .filter(|fs| !excluded.contains(fs))
+            .map(|fs| (fs, false))
+            .collect()
+    };
+
+    let mut levels: BTreeMap<usize, Vec<Stream>> = BTreeMap::new();
+    for (fs, recursive) in roots {
+        let relative = fs.strip_prefix(opt.from_pool.as_str()).unwrap_or("");
+        let snap = format!("{}@{}", fs, basesnap_short);
+        let incremental = precursor_name
+            .map(|p| format!("{}{}{}", fs, sep, short_name(p)))
+            .filter(|source| sources.iter().any(|s| s.name() == source));
+
+        let mut send_args = ["-v", "-P", if recursive { "-R" } else { "-p" }, "-w", "-L"]
+            .map(str::to_string)
+            .to_vec();
+        if let Some(incremental) = incremental.as_ref() {
+            send_args.extend([flag.to_string(), incremental.clone()]);
+        } else if precursor_name.is_some() {
+            warn!("No precursor for {} - sending {} in full", fs, snap);
+        }
+        send_args.push(snap);
+
+        levels
+            .entry(relative.matches('/').count())
+            .or_default()
+            .push(Stream {
+                send_args,
+                incremental: incremental.is_some(),
+                dest: format!("{}{}", opt.to_pool, relative),
+            });
+    }
+
+    let total: usize = levels.values().map(Vec::len).sum();
+    for streams in levels.values() {
+        let failed = run_streams(opt, streams);
+        if failed > 0 {
+            error!(
+                "{} of {} streams to {} failed - not sending the rest",
+                failed, total, opt.to_pool
+            );
+            return Err(());
+        }
+    }
+
+    info!(
+        "Split replication received - {} streams, {} at a time",
+        total, opt.jobs
+    );
+    Ok(())
+}
+
+/// Send and receive `streams`, `opt.jobs` at a time, returning how many failed.
+fn run_streams(opt: &ReplOpt, streams: &[Stream]) -> usize {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..opt.jobs.clamp(1, streams.len().max(1)) {
+            scope.spawn(|| {
+                while let Some(stream) = streams.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    if process::cancelled() {
+                        failed.fetch_add(1, Ordering::SeqCst);
+                        break;
+                    }
+                    let send_args: Vec<_> = stream.send_args.iter().map(String::as_str).collect();
+                    let recv = if stream.incremental {
+                        recv_args(opt)
+                    } else {
+                        &[]
+                    };
+                    if local_send_recv(opt, &send_args, recv, stream.dest.as_str()).is_err() {
+                        error!("Stream to {} failed", stream.dest);
+                        failed.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+        }
+    });
+    failed.into_inner()
+}
+
+/// Per-dataset replication where datasets with redact paths are sent with `--redact`, from the
+/// precursor snapshot or bookmark if the dataset has it, otherwise in full. Once a dataset is
+/// sent, its older redaction bookmarks are removed.
+fn do_repl_redact_inner(
+    opt: &ReplOpt,
+    precursor_name: Option<&str>,
+    basesnap_name: &str,
+    excluded: &[String],
+) -> Result<(), ()> {
+    let basesnap_short = short_name(basesnap_name);
+    let (sources, sep) = match precursor_name {
+        Some(p) if p.contains('#') => (repl_bookmark_list(opt.from_pool.as_str())?, '#'),
+        Some(_) => (repl_snap_list(opt.from_pool.as_str())?, '@'),
+        None => (Vec::new(), '@'),
+    };
+    let redact_clone = redact::clone_name(opt.from_pool.as_str());
+
+    for fs in dataset_list(opt.from_pool.as_str())? {
+        if fs == redact_clone || excluded.contains(&fs) {
+            continue;
+        }
+        let dest = format!(
+            "{}{}",
+            opt.to_pool,
+            fs.strip_prefix(opt.from_pool.as_str()).unwrap_or("")
+        );
+        let snap = format!("{}@{}", fs, basesnap_short);
+        let incremental = precursor_name
+            .map(|p| format!("{}{}{}", fs, sep, short_name(p)))
+            .filter(|source| sources.iter().any(|s| s.name() == source));
+        let recv = if incremental.is_some() {
+            recv_args(opt)
+        } else {
+            &[]
+        };
+
+        let paths = redact::redact_paths(&fs)?;
+        let redaction = if paths.is_empty() {
+            None
+        } else {
+            Some(redact::create_redaction(opt.dryrun, &snap, &paths)?)
+        };
+
+        let mut send_args = vec!["-v", "-P", "-L"];
+        match redaction.as_deref() {
+            Some(bookmark) => send_args.extend(["--redact", bookmark]),
+            None => send_args.push("-w"),
+        }
+        if let Some(incremental) = incremental.as_deref() {
+            send_args.extend(["-i", incremental]);
+        }
+        send_args.push(snap.as_str());
+
+        local_send_recv(opt, &send_args, recv, dest.as_str())?;
+
+        if let Some(bookmark) = redaction.as_deref() {
+            let _ = redact::cleanup_redactions(opt.dryrun, &fs, bookmark);
+        }
+    }
+
+    info!("Redacted replication received");
+    Ok(())
+}
+
+/// zfs send `send_args` | zfs recv `recv_args` `to_fs`, with the replica properties of `opt.recv`
+fn local_send_recv(
+    opt: &ReplOpt,
+    send_args: &[&str],
+    recv_args: &[&str],
+    to_fs: &str,
+) -> Result<(), ()> {
+    let mut send_cmd = vec!["zfs", "send"];
+    send_cmd.extend(opt.stream.send_args(send_args));
+    let props = opt.recv.args(stream::REPLICA);
+    let mut recv_args = recv_args.to_vec();
+    recv_args.extend_from_slice(opt.stream.recv_args());
+    recv_args.extend(props.iter().map(String::as_str));
+    pipe_send_recv(
+        opt.dryrun,
+        &opt.buffer,
+        &send_cmd,
+        &recv_args,
+        to_fs,
+        &format!("send to {}", to_fs),
+    )
+}
+
+/// Log how a zfs send exited, with what it wrote to stderr besides its progress - its warnings
+/// if it succeeded, and why it didn't if it failed.
+fn log_send_exit(label: &str, status: &io::Result<ExitStatus>, stderr: &[String]) {
+    match status {
+        Ok(status) if status.success() => {
+            for line in stderr.iter() {
+                warn!("{} -> {}", label, line);
+            }
+        }
+        Ok(status) => error!(
+            "{} failed with code {} -> {}",
+            label,
+            status.code().unwrap_or(255),
+            stderr.join("; ")
+        ),
+        Err(e) => error!("{} failed -> {:?}", label, e),
+    }
+}
+
+/// `send_cmd` | zfs recv `recv_args` `to_fs`, where send_cmd writes a send stream to stdout and
+/// its -P progress to stderr, which is checkpointed as `label`. `recv_args` carries the
+/// properties of the replica, as `RecvPropsOpt::args` gives them.
+fn pipe_send_recv(
+    dry: bool,
+    buffer: &BufferOpt,
+    send_cmd: &[&str],
+    recv_args: &[&str],
+    to_fs: &str,
+    label: &str,
+) -> Result<(), ()> {
+    let _span = info_span!(target: telemetry::SPANS, "send", label, to = to_fs, bytes = tracing::field::Empty).entered();
+    let (send_bin, send_args) = match send_cmd.split_first() {
+        Some(split) => split,
+        None => {
+            error!("No send command");
+            return Err(());
+        }
+    };
+    let recv_flags = recv_args
+        .iter()
+        .map(|a| format!("{} ", a))
+        .collect::<String>();
+    if dry {
+        info!(
+            "dryrun -> {} | zfs recv {}{}",
+            send_cmd.join(" "),
+            recv_flags,
+            to_fs
+        );
+        return Ok(());
+    }
+
+    debug!(
+        "running -> {} | zfs recv {}{}",
+        send_cmd.join(" "),
+        recv_flags,
+        to_fs
+    );
+    let send = privilege::command(send_bin)
+        .args(send_args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .run_spawn();
+
+    let mut send = match send {
+        Ok(send) => send,
+        Err(e) => {
+            error!("send failed -> {:?}", e);
+            return Err(());
+        }
+    };
+    let send_guard = process::register(&send, send_bin, Kind::Transfer);
+
+    let stdout = match send.stdout.take() {
+        Some(s) => s,
+        None => {
+            error!("Failed to connect to stdout of zfs send process");
+            return Err(());
+        }
+    };
+    let (stdin, buffer) = match buffer::buffered(buffer, stdout) {
+        Ok(b) => b,
+        Err(_) => {
+            let _ = send.kill();
+            let _ = send.wait();
+            return Err(());
+        }
+    };
+    let watch = send
+        .stderr
+        .take()
+        .map(|stderr| progress::watch(label, stderr, buffer.meter()));
+
+    let recv_span = info_span!(target: telemetry::SPANS, "recv", to = to_fs).entered();
+    let recv = privilege::zfs()
+        .arg("recv")
+        .args(recv_args)
+        .arg(to_fs)
+        .stdin(stdin)
+        .stderr(Stdio::piped())
+        .run_output(Kind::Transfer);
+
+    let recv_ok = match recv {
+        Ok(output) => {
+            let code = output.status.code().unwrap_or(255);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if code == 0 {
+                warn!("success recv code {}", code);
+                for line in stderr.lines().filter(|l| !l.trim().is_empty()) {
+                    warn!("recv into {} -> {}", to_fs, line);
+                }
+                // Happy path.
+                true
+            } else {
+                error!(
+                    "recv into {} failed with code {} -> {}",
+                    to_fs,
+                    code,
+                    stderr.trim()
+                );
+                false
+            }
+        }
+        Err(e) => {
+            error!("recv failed -> {:?}", e);
+            false
+        }
+    };
+    drop(recv_span);
+
+    let send_status = process::wait(&mut send, &send_guard);
+    let send_ok = matches!(&send_status, Ok(status) if status.success());
+    let buffer_ok = buffer.finish().is_ok();
+
+    let stderr = watch
+        .map(|watch| watch.finish(recv_ok && send_ok && buffer_ok))
+        .unwrap_or_default();
+    log_send_exit(label, &send_status, &stderr);
+
+    if recv_ok && send_ok && buffer_ok {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Replace every dataset's snapshot named `snap_name` (recursively from the pool root) with a
+/// bookmark of the same name, then destroy the snapshots. If any bookmark can not be created the
+/// snapshots are left in place so the next repl can still anchor from them.
+fn convert_to_bookmarks(opt: &ReplOpt, snap_name: &str) -> Result<(), ()> {
+    let short = short_name(snap_name);
+    for fs in repl_datasets(opt)? {
+        let snap = format!("{}@{}", fs, short);
+        let bookmark = format!("{}#{}", fs, short);
+        create_bookmark(opt.dryrun, &snap, &bookmark)?;
+    }
+
+    remove_snap(opt.dryrun, snap_name, audit::Reason::Bookmarked)
+}
+
+fn do_repl_cleanup(opt: &ReplCleanupOpt) {
+    debug!("do_repl_cleanup");
+    let _span =
+        info_span!(target: telemetry::SPANS, "cleanup", from = %opt.from_pool, to = %opt.to_pool)
+            .entered();
+
+    let to_pool = match expand_dest_path(opt.to_pool.as_str(), opt.from_pool.as_str()) {
+        Ok(to_pool) => to_pool,
+        Err(_) => return,
+    };
+
+    let lists = repl_guid_list(opt.from_pool.as_str(), "snapshot").and_then(|from_snaps| {
+        let from_bookmarks = repl_guid_list(opt.from_pool.as_str(), "bookmark")?;
+        let to_snaps = repl_guid_list(to_pool.as_str(), "snapshot")?;
+        Ok((from_snaps, from_bookmarks, to_snaps))
+    });
+    let (from_snaps, from_bookmarks, to_snaps) = match lists {
+        Ok(l) => l,
+        Err(_) => return,
+    };
+
+    // The newest anchor on the source root that the destination root holds with the same guid.
+    let to_root: Vec<_> = to_snaps
+        .iter()
+        .filter(|(name, _)| name.split('@').next() == Some(to_pool.as_str()))
+        .collect();
+
+    let anchor = from_snaps
+        .iter()
+        .chain(from_bookmarks.iter())
+        .filter(|(name, _)| name.split(['@', '#']).next() == Some(opt.from_pool.as_str()))
+        .filter(|(name, guid)| {
+            to_root.iter().any(|(to_name, to_guid)| {
+                to_guid == guid && short_name(to_name) == short_name(name)
+            })
+        })
+        .map(|(name, _)| name)
+        .max_by_key(|name| {
+            let ts = Snapshot::parse(name).ok().and_then(|snap| snap.timestamp());
+            (ts, short_name(name))
+        })
+        .map(|name| short_name(name));
+
+    let anchor = match anchor {
+        Some(a) => a.to_string(),
+        None => {
+            error!(
+                "No common repl anchor between {} and {} - refusing to clean up",
+                opt.from_pool, to_pool
+            );
+            return;
+        }
+    };
+    info!("Newest common anchor -> {}", anchor);
+
+    let mut anchors = match AnchorStore::load() {
+        Ok(a) => a,
+        Err(_) => return,
+    };
+    let owner = Owner::new("repl", to_pool.as_str());
+    let anchor_name = match (from_snaps.iter())
+        .chain(from_bookmarks.iter())
+        .map(|(name, _)| name)
+        .find(|name| {
+            short_name(name) == anchor
+                && name.split(['@', '#']).next() == Some(opt.from_pool.as_str())
+        }) {
+        Some(n) => n.clone(),
+        None => return,
+    };
+    let names: Vec<_> = from_snaps
+        .iter()
+        .filter(|(name, _)| short_name(name) != anchor && !anchors.is_protected(name, Some(&owner)))
+        .chain(
+            to_snaps
+                .iter()
+                .filter(|(name, _)| short_name(name) != anchor),
+        )
+        .map(|(name, _)| name.clone())
+        .collect();
+    let names = match approval::gate_destroy(opt.dryrun, to_pool.as_str(), &names) {
+        Ok(names) => names,
+        Err(()) => return,
+    };
+    if confirm::destroy(opt.dryrun, to_pool.as_str(), &names).is_err() {
+        return;
+    }
+
+    anchors.set(&owner, anchor_name.as_str());
+    if anchors.save(opt.dryrun).is_err() {
+        return;
+    }
+
+    for name in names.iter() {
+        let _ = remove_snap(opt.dryrun, name.as_str(), audit::Reason::ReplCleanup);
+    }
+    for (name, _) in from_bookmarks.iter() {
+        if short_name(name) != anchor && !anchors.is_protected(name, Some(&owner)) {
+            let _ = remove_bookmark(opt.dryrun, name.as_str());
+        }
+    }
+}
+
+/// A registered target name resolves to its ssh destination, connection options and dataset,
+/// anything else is used as the ssh destination as is. Options given as flags take precedence
+/// over those of the target.
+fn resolve_remote_ssh(remote: &str, opt: &SshOpt) -> Result<(Ssh, Option<String>), ()> {
+    let targets = Targets::load()?;
+    Ok(match targets.get(remote) {
+        Some(target) => (
+            Ssh::new(&target.ssh, &opt.or(&target.connection))?,
+            Some(target.dataset.clone()),
+        ),
+        None => (Ssh::new(remote, opt)?, None),
+    })
+}
+
+fn ssh_output(remote_ssh: &Ssh, args: &[&str]) -> Result<String, ()> {
+    debug!("running -> ssh {} {}", remote_ssh, args.join(" "));
+    let output = remote_ssh
+        .command(args)
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh)
+        .map_err(|e| {
+            error!("ssh failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!(
+            "{} {} -> {}",
+            args.join(" "),
+            remote_ssh.describe_failure(output.status),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(());
+    }
+    String::from_utf8(output.stdout).map_err(|e| {
+        error!("ssh output contains invalid utf8 -> {:?}", e);
+    })
+}
+
+/// The remote equivalent of check_rollback_destination - the remote dataset must be readonly and
+/// hold a snapshot with the guid of our precursor.
+fn check_remote_rollback_destination(
+    remote_ssh: &Ssh,
+    dataset: &str,
+    precursor_name: &str,
+) -> Result<(), ()> {
+    let readonly = ssh_output(
+        remote_ssh,
+        &["zfs", "get", "-H", "-o", "value", "readonly", dataset],
+    )?;
+    if readonly.trim() != "on" {
+        error!(
+            "Refusing to force rollback {} - it is not readonly, so znapper did not create it",
+            dataset
+        );
+        return Err(());
+    }
+
+    let guid = get_property(precursor_name, "guid")?;
+    let remote_snaps = ssh_output(
+        remote_ssh,
+        &[
+            "zfs", "list", "-H", "-p", "-t", "snapshot", "-o", "guid", "-d", "1", dataset,
+        ],
+    )?;
+    if remote_snaps.lines().any(|g| g.trim() == guid) {
+        Ok(())
+    } else {
+        error!(
+            "Refusing to force rollback {} - it does not hold the precursor {}",
+            dataset, precursor_name
+        );
+        Err(())
+    }
+}
+
+/// The snapshots of the remote dataset as (name, guid), oldest first. A receiver running znapper
+/// recv lists them itself, otherwise (if the dataset is known) we ask zfs list over ssh.
+fn query_remote_snapshots(
+    remote_ssh: &Ssh,
+    dataset: Option<&str>,
+) -> Result<Vec<(String, String)>, ()> {
+    debug!("running -> ssh {} snapshots", remote_ssh);
+    let output = remote_ssh
+        .command(&["snapshots"])
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh);
+    if let Ok(output) = output {
+        if let Some(list) =
+            recv::parse_result::<recv::SnapshotList>(&String::from_utf8_lossy(&output.stdout))
+        {
+            return Ok(list
+                .snapshots
+                .into_iter()
+                .map(|snap| (snap.name, snap.guid))
+                .collect());
+        }
+    }
+
+    match dataset {
+        Some(dataset) => ssh_output(
+            remote_ssh,
+            &[
+                "zfs",
+                "list",
+                "-H",
+                "-p",
+                "-t",
+                "snapshot",
+                "-o",
+                "name,guid",
+                "-s",
+                "createtxg",
+                "-d",
+                "1",
+                dataset,
+            ],
+        )
+        .map(|stdout| parse_guids(&stdout)),
+        None => Err(()),
+    }
+}
+
+/// The snapshot of `pool` to send the next incremental from. Without a rollback the remote can
+/// only receive onto its latest snapshot, so that must be on the source. With a rollback, the
+/// newest snapshot both sides share is used.
+fn remote_precursor(
+    pool: &str,
+    remote_snaps: &[(String, String)],
+    force_rollback: bool,
+) -> Result<String, ()> {
+    let local_snaps = snapshot_guid_list(pool)?;
+
+    let latest_guid = match remote_snaps.last() {
+        Some((_, guid)) => guid,
+        None => {
+            error!("The remote has no snapshots - use remote_init_archive first");
+            return Err(());
+        }
+    };
+    if let Some((name, _)) = local_snaps.iter().find(|(_, guid)| guid == latest_guid) {
+        return Ok(name.clone());
+    }
+
+    let common = local_snaps.iter().rev().find(|(_, guid)| {
+        remote_snaps
+            .iter()
+            .any(|(_, remote_guid)| remote_guid == guid)
+    });
+    match common {
+        Some((name, _)) if force_rollback => Ok(name.clone()),
+        Some((name, _)) => {
+            error!(
+                "The latest snapshot on the remote is not on {} - use --force-rollback to roll the remote back to {}",
+                pool, name
+            );
+            Err(())
+        }
+        None => {
+            error!("The remote shares no snapshots with {}", pool);
+            Err(())
+        }
+    }
+}
+
+/// Remote flows are identified by their metadata file, as that is the one thing both the archive
+/// and the incremental steps know about, and the identity of their destination within it.
+fn register_remote_anchor(
+    auto_snap_metadata: &str,
+    identity: Option<&str>,
+    anchor: &str,
+) -> Result<(), ()> {
+    let mut anchors = AnchorStore::load()?;
+    anchors.set(&metadata::owner(auto_snap_metadata, identity, None), anchor);
+    anchors.save(false)
+}
+
+fn get_auto_basesnap(pool_name: &str) -> Option<String> {
+    let snaps: Vec<_> = filter_snap_list(Class::Auto, pool_name, false).ok()?;
+
+    // Find the "latest" autosnap.
+    snaps
+        .into_iter()
+        .max_by_key(Snapshot::timestamp)
+        .map(|snap| snap.name().to_string())
+}
+
+fn do_init_archive(opt: &InitArchiveOpt) {
+    debug!("do_init_archive");
+
+    let basesnap_name = match get_auto_basesnap(&opt.pool) {
+        Some(b) => b,
+        None => {
+            error!("No auto-snaps available");
+            return;
+        }
+    };
+
+    /*
+     * do the send/recv
+     * -w for encyrption to stay raw, unless --no-raw
+     */
+    if opt.dryrun {
+        info!(
+            "dryrun -> zfs send {} {} > {}",
+            opt.stream
+                .send_args(&["-v", "-P", "-R", "-L", "-w"])
+                .join(" "),
+            basesnap_name,
+            opt.file
+        );
+    } else {
+        // Where the archive will be loaded isn't known, so it is claimed by the first remote_repl.
+        let mut meta = match MetadataFile::load(&opt.auto_snap_metadata) {
+            Ok(m) => m,
+            Err(_) => return,
+        };
+        meta.set(
+            None,
+            RemoteMetadata {
+                precursor_snap: basesnap_name.clone(),
+                ..Default::default()
+            },
+        );
+        if meta.save(&opt.auto_snap_metadata).is_err() {
+            return;
+        }
+
+        if register_remote_anchor(&opt.auto_snap_metadata, None, &basesnap_name).is_err() {
+            return;
+        }
+
+        let mut file = match File::create(&opt.file) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("failed to open file -> {:?}", e);
+                return;
+            }
+        };
+
+        let label = format!("archive to {}", opt.file);
+        let _span = info_span!(target: telemetry::SPANS, "send", label = %label, bytes = tracing::field::Empty).entered();
+        let send = privilege::zfs()
+            .arg("send")
+            .args(opt.stream.send_args(&["-v", "-P", "-R", "-L", "-w"]))
+            .arg(basesnap_name.as_str())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .run_spawn();
+
+        let mut send = match send {
+            Ok(send) => send,
+            Err(e) => {
+                error!("send failed -> {:?}", e);
+                return;
+            }
+        };
+        let send_guard = process::register(&send, "zfs", Kind::Transfer);
+
+        let mut stdout = match send.stdout.take() {
+            Some(s) => s,
+            None => {
+                error!("Failed to connect to stdout of zfs send process");
+                return;
+            }
+        };
+        let watch = send
+            .stderr
+            .take()
+            .map(|stderr| progress::watch(&label, stderr, None));
+
+        let copied = match io::copy(&mut stdout, &mut file) {
+            Ok(b) => {
+                debug!("wrote {} bytes", b);
+                true
+            }
+            Err(e) => {
+                error!("Failed to write to file -> {:?}", e);
+                false
+            }
+        };
+
+        let send_status = process::wait(&mut send, &send_guard);
+        let sent = matches!(&send_status, Ok(status) if status.success());
+        let stderr = watch
+            .map(|watch| watch.finish(copied && sent))
+            .unwrap_or_default();
+        log_send_exit(&label, &send_status, &stderr);
+        if copied && sent {
+            info!("Initial replication archive success")
+        }
+    }
+}
+
+fn do_load_archive(opt: &ArchiveOpt) {
+    debug!("do_load_archive");
+    let mut recv_args: Vec<String> = Vec::new();
+    if opt.no_raw {
+        recv_args.extend(["-x".to_string(), "encryption".to_string()]);
+    }
+    recv_args.extend(opt.recv.args(stream::REPLICA));
+
+    if opt.dryrun {
+        info!(
+            "dryrun -> cat {} | zfs recv {} {}",
+            opt.file,
+            recv_args.join(" "),
+            opt.pool
+        );
+    } else {
+        let mut file = match File::open(&opt.file) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("failed to open file -> {:?}", e);
+                return;
+            }
+        };
+
+        let recv = privilege::zfs()
+            .arg("recv")
+            .args(&recv_args)
+            .arg(opt.pool.as_str())
+            .stdin(Stdio::piped())
+            .run_spawn();
+
+        let mut recv = match recv {
+            Ok(recv) => recv,
+            Err(e) => {
+                error!("recv failed -> {:?}", e);
+                return;
+            }
+        };
+        let recv_guard = process::register(&recv, "zfs", Kind::Transfer);
+
+        let mut stdin = match recv.stdin.take() {
+            Some(s) => s,
+            None => {
+                error!("Failed to connect to stdin of zfs recv process");
+                return;
+            }
+        };
+
+        match io::copy(&mut file, &mut stdin) {
+            Ok(b) => debug!("wrote {} bytes", b),
+            Err(e) => {
+                error!("Failed to write to zfs recv -> {:?}", e);
+            }
+        };
+
+        if let Err(e) = process::wait(&mut recv, &recv_guard) {
+            error!("recv failed -> {:?}", e);
+        } else {
+            info!("Initial replication archive load success");
+            warn!("You should now setup a remote backup user. For that user in .ssh/authorized_keys set:");
+            warn!(
+                r#"  command="/usr/sbin/zfs recv -x mountpoint -x readonly {}{}",no-port-forwarding,no-X11-forwarding,no-agent-forwarding,no-pty [ssh-key]"#,
+                if opt.no_raw { "-x encryption " } else { "" },
+                opt.pool
+            );
+            warn!("You must also delegate that user the permissions to recv replication snapshots");
+            warn!(
+                "  znapper setup-delegation --user [user] --pool {} --role receive",
+                opt.pool
+            );
+        }
+    }
+}
+
+/// Does `dataset` exist on the remote? Err if we couldn't tell.
+fn remote_dataset_exists(remote_ssh: &Ssh, dataset: &str) -> Result<bool, ()> {
+    let output = remote_ssh
+        .command(&["zfs", "list", "-H", "-o", "name", dataset])
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh)
+        .map_err(|e| {
+            error!("ssh failed -> {:?}", e);
+        })?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() {
+        Ok(true)
+    } else if stderr.contains("does not exist") {
+        Ok(false)
+    } else {
+        error!(
+            "zfs list {} {} -> {}",
+            dataset,
+            remote_ssh.describe_failure(output.status),
+            stderr.trim()
+        );
+        Err(())
+    }
+}
+
+fn do_init_remote(opt: &InitRemoteOpt) -> Result<(), ()> {
+    let res = init_remote(opt);
+    status::record(
+        opt.dryrun,
+        &Owner::new("remote_repl", &opt.auto_snap_metadata),
+        &opt.pool,
+        res.is_ok(),
+    );
+    res
+}
+
+/// The init_archive and load_archive of a remote in one step - a full zfs send | ssh zfs recv of
+/// the latest auto snapshot, checked on the remote before the metadata is written.
+fn init_remote(opt: &InitRemoteOpt) -> Result<(), ()> {
+    debug!("do_init_remote");
+
+    let (remote_ssh, target_dataset) = resolve_remote_ssh(&opt.remote, &opt.ssh)?;
+    let remote_pool = match opt.remote_pool.clone().or(target_dataset) {
+        Some(p) => p,
+        None => {
+            error!(
+                "--remote-pool is required unless {} is a registered target",
+                opt.remote
+            );
+            return Err(());
+        }
+    };
+
+    let basesnap_name = match get_auto_basesnap(&opt.pool) {
+        Some(b) => b,
+        None => {
+            error!("No auto-snaps available");
+            return Err(());
+        }
+    };
+
+    // A full stream can only create the dataset, so anything there already must be removed (or
+    // replicated to with remote_repl) first.
+    if remote_dataset_exists(&remote_ssh, &remote_pool)? {
+        error!(
+            "{} already exists on {} - use remote_repl to update it",
+            remote_pool, remote_ssh
+        );
+        return Err(());
+    }
+
+    let estimates = estimate::stream(&opt.pool, !opt.single_dataset, None, &basesnap_name)
+        .unwrap_or_else(|_| {
+            warn!("Unable to estimate the stream to {}", remote_ssh);
+            Vec::new()
+        });
+    match query_remote_free(&remote_ssh, Some(&remote_pool)) {
+        Some(free) if !estimates.is_empty() => {
+            estimate::check_space(&remote_ssh.to_string(), &estimates, free, opt.ignore_space)?
+        }
+        Some(_) => {}
+        None => warn!("Unable to check the free space on {}", remote_ssh),
+    }
+
+    // The received datasets must not mount over the remote's own, so the mountpoints of the
+    // stream are dropped, and the top is not mounted anywhere.
+    let props = opt
+        .recv
+        .args(&[("mountpoint", None), ("readonly", Some("on"))]);
+    let mut recv = vec!["zfs", "recv", "-s", "-u"];
+    recv.extend_from_slice(opt.stream.recv_args());
+    recv.extend(props.iter().map(String::as_str));
+    recv.push(remote_pool.as_str());
+    let mut send_args = opt
+        .stream
+        .send_args(&[recursion_flag(opt.single_dataset), "-L", "-w"]);
+    send_args.push(basesnap_name.as_str());
+    remote_transfer(
+        opt.dryrun,
+        &opt.buffer,
+        &remote_ssh,
+        &recv,
+        &send_args,
+        None,
+        &format!("remote send to {}", remote_ssh),
+    )
+    .map_err(|_| {
+        error!("Initial remote replication to {} failed", remote_ssh);
+    })?;
+    // Unless told otherwise, which the checks below allow for.
+    let set_mountpoint = !opt.recv.overrides("mountpoint");
+    let check_readonly = !opt.recv.overrides("readonly");
+    if opt.dryrun {
+        if set_mountpoint {
+            info!(
+                "dryrun: ssh {} zfs set mountpoint=none {}",
+                remote_ssh, remote_pool
+            );
+        }
+        plan::transfer(plan::Transfer {
+            source: opt.pool.clone(),
+            from: None,
+            to: basesnap_name.clone(),
+            destination: remote_ssh.to_string(),
+            estimated_bytes: estimated_total(&estimates),
+            resume_token: None,
+        });
+        return Ok(());
+    }
+
+    if set_mountpoint {
+        ssh_output(
+            &remote_ssh,
+            &["zfs", "set", "mountpoint=none", remote_pool.as_str()],
+        )?;
+    }
+
+    // Only anchor on what the remote really received.
+    let guid = get_property(&basesnap_name, "guid")?;
+    let remote_snaps = query_remote_snapshots(&remote_ssh, Some(&remote_pool))?;
+    if !remote_snaps.iter().any(|(_, g)| *g == guid) {
+        error!(
+            "{} does not hold {} after the send - not writing the metadata",
+            remote_pool, basesnap_name
+        );
+        return Err(());
+    }
+    let props = ssh_output(
+        &remote_ssh,
+        &[
+            "zfs",
+            "get",
+            "-H",
+            "-o",
+            "value",
+            "readonly,mountpoint",
+            remote_pool.as_str(),
+        ],
+    )?;
+    let props: Vec<_> = props.split_whitespace().collect();
+    if (check_readonly && props.first() != Some(&"on"))
+        || (set_mountpoint && props.get(1) != Some(&"none"))
+    {
+        error!(
+            "{} is not readonly with no mountpoint -> {}",
+            remote_pool,
+            props.join(" ")
+        );
+        return Err(());
+    }
+
+    let identity = query_remote_identity(&remote_ssh, Some(&remote_pool));
+    let mut meta = MetadataFile::load(&opt.auto_snap_metadata)?;
+    meta.set(
+        Some(&identity),
+        RemoteMetadata {
+            precursor_snap: basesnap_name.clone(),
+            ..Default::default()
+        },
+    );
+    meta.save(&opt.auto_snap_metadata)?;
+    register_remote_anchor(&opt.auto_snap_metadata, Some(&identity), &basesnap_name)?;
+
+    info!(
+        "Initial remote replication of {} to {} on {} success",
+        basesnap_name, remote_pool, remote_ssh
+    );
+    Ok(())
+}
+
+/// The locks of remote_repl - the pools it replicates from, as --dataset or the metadata has them.
+fn remote_locks(opt: &ReplRemoteOpt) -> Vec<String> {
+    let mut sources = opt.datasets.clone();
+    if sources.is_empty() {
+        if let Ok(file) = MetadataFile::load(&opt.auto_snap_metadata) {
+            for (_, meta) in file.entries() {
+                sources.push(meta.precursor_snap.clone());
+                sources.extend(meta.datasets.keys().cloned());
+            }
+        }
+    }
+    sources
+        .iter()
+        .filter(|s| !s.is_empty())
+        .map(|s| lock::pool(s))
+        .collect()
+}
+
+fn do_repl_remote(opt: &ReplRemoteOpt) -> Result<(), ()> {
+    let res = repl_remote(opt);
+    let source = MetadataFile::load(&opt.auto_snap_metadata)
+        .ok()
+        .and_then(|file| {
+            file.entries()
+                .find_map(|(_, meta)| meta.source().map(str::to_string))
+        })
+        .or_else(|| opt.datasets.first().cloned())
+        .unwrap_or_default();
+    status::record(
+        opt.dryrun,
+        &Owner::new("remote_repl", &opt.auto_snap_metadata),
+        &source,
+        res.is_ok(),
+    );
+    res
+}
+
+fn repl_remote(opt: &ReplRemoteOpt) -> Result<(), ()> {
+    debug!("do_repl_remote");
+
+    /*
+     * If you get:
+     *  cannot receive incremental stream: most recent snapshot of tank/remote does not
+     *  match incremental source
+     *
+     * ZFS wants to send from the 'latest' the remote knows about. You can't repeat-send
+     * snapshot. So let say the source has:
+     *
+     * NAME                                  USED  AVAIL     REFER  MOUNTPOINT
+     * tank@remote_2022_05_22_12_09_46         0B      -      100K  -
+     * tank@remote_2022_05_22_12_11_24         0B      -      100K  -
+     * tank@remote_2022_05_22_12_11_51         0B      -      100K  -
+     * tank@remote_2022_05_22_12_12_59         0B      -      100K  -
+     *
+     * And the remote has:
+     *
+     * NAME                                  USED  AVAIL     REFER  MOUNTPOINT
+     * tank/remote@remote_2022_05_22_12_09_46         0B      -      100K  -
+     * tank/remote@remote_2022_05_22_12_11_24         0B      -      100K  -
+     *
+     * And we issued:
+     *
+     * zfs send -R -L -w -I tank@remote_2022_05_22_12_09_46 tank@remote_2022_05_22_12_12_59
+     * Because 09_46 is not the "latest" on remote, that's why it won't apply. We needed to anchor
+     * from 11_24 instead.
+     *
+     */
+
+    /*
+     * With a bare zfs recv as the forced command we can't tell the difference between a success
+     * and failure, so it can be fragile to work this out :(
+     *
+     * That's why we only "keep" a snapshot for repl_ IF it appears everything succeedd, but it's
+     * still not perfect, and will need monitoring :( If the receiver runs znapper recv instead, it
+     * reports what was received, and we only advance once our basesnap is there.
+     */
+
+    let (mut remote_ssh, remote_dataset) = match resolve_remote_ssh(&opt.remote_ssh, &opt.ssh) {
+        Ok(r) => r,
+        Err(_) => return Err(()),
+    };
+    if let Some(transport) = opt.transport.clone() {
+        if opt.force_rollback {
+            error!("--force-rollback can not be used with --transport");
+            return Err(());
+        }
+        remote_ssh.over(transport, opt.tls.clone());
+    }
+    if let Some(size) = opt.chunked {
+        if opt.force_rollback {
+            error!("--force-rollback can not be used with --chunked");
+            return Err(());
+        }
+        remote_ssh.chunked(size as u64);
+    }
+    if let Some(compression) = opt.transport_compress {
+        if opt.force_rollback {
+            error!("--force-rollback can not be used with --transport-compress");
+            return Err(());
+        }
+        remote_ssh.compress(compression);
+    }
+    let host = if opt.per_host {
+        if opt.force_rollback {
+            error!("--force-rollback can not be used with --per-host");
+            return Err(());
+        }
+        let host = hostname()?;
+        remote_ssh.per_host(&host, None);
+        Some(host)
+    } else {
+        None
+    };
+    if opt.per_dataset {
+        return datasets::replicate(opt, &remote_ssh);
+    } else if !opt.datasets.is_empty() || !opt.exclude.is_empty() {
+        error!("--dataset and --exclude are only used with --per-dataset");
+        return Err(());
+    }
+    // With a forced rollback we must choose the recv command ourselves, so need the dataset.
+    let recv_props = opt.recv.args(&[("mountpoint", None), ("readonly", None)]);
+    let remote_recv: Vec<&str> = match (opt.force_rollback, remote_dataset.as_deref()) {
+        (false, _) => {
+            if !opt.stream.raw() {
+                info!(
+                    "Sending plain streams - the receiver must receive them with -x encryption, \
+                     as znapper recv --no-raw does"
+                );
+            }
+            Vec::new()
+        }
+        (true, Some(dataset)) => {
+            let mut recv = vec!["zfs", "recv", "-s", "-F"];
+            recv.extend_from_slice(opt.stream.recv_args());
+            recv.extend(recv_props.iter().map(String::as_str));
+            recv.push(dataset);
+            recv
+        }
+        (true, None) => {
+            error!(
+                "--force-rollback requires {} to be a registered target",
+                opt.remote_ssh
+            );
+            return Err(());
+        }
+    };
+
+    // Get the precursor snap from the metadata of this destination.
+    let identity = query_remote_identity(&remote_ssh, remote_dataset.as_deref());
+    let mut meta_file = MetadataFile::load(&opt.auto_snap_metadata)?;
+    let meta = match meta_file.claim(&opt.auto_snap_metadata, &identity, opt.dryrun) {
+        Some(meta) => meta,
+        None => {
+            error!(
+                "{} has no metadata for {} ({}) - seed it with init_remote or remote_init_archive",
+                opt.auto_snap_metadata, remote_ssh, identity
+            );
+            return Err(());
+        }
+    };
+    // Keep the claim (and a migration), whatever becomes of the replication.
+    if !opt.dryrun {
+        meta_file.save(&opt.auto_snap_metadata)?;
+    }
+
+    let pool = match meta.precursor_snap.split('@').next() {
+        Some(p) => p,
+        None => {
+            error!("Invalid precursor snapshot name -> {}", meta.precursor_snap);
+            return Err(());
+        }
+    };
+    if let Some(host) = host.as_deref() {
+        remote_ssh.per_host(host, Some(pool));
+    }
+
+    zed::check(pool)?;
+    let excluded = if opt.single_dataset {
+        Vec::new()
+    } else {
+        exclude::excluded(pool)?
+    };
+    if !excluded.is_empty() {
+        error!(
+            "{} are left out of replication with {}=false, which a -R stream can't leave out - \
+             use --per-dataset",
+            excluded.join(", "),
+            exclude::REPLICATE_PROPERTY
+        );
+        return Err(());
+    }
+    // The remote is checked by the attempts, so that it is retried.
+    if !opt.skip_preflight {
+        let job = check::Job {
+            sources: vec![pool.to_string()],
+            metadata: vec![opt.auto_snap_metadata.clone()],
+            require_healthy: opt.require_healthy,
+            ..Default::default()
+        };
+        if check::preflight(&job).is_err() {
+            return Err(());
+        }
+    }
+
+    // get the new base snap from the latest auto.
+    let basesnap_name = match get_auto_basesnap(pool) {
+        Some(b) => b,
+        None => {
+            error!("No auto-snaps available");
+            return Err(());
+        }
+    };
+
+    let remote = RemoteRepl {
+        opt,
+        ssh: &remote_ssh,
+        dataset: remote_dataset.as_deref(),
+        recv: &remote_recv,
+        meta: &meta,
+        pool,
+        basesnap_name: &basesnap_name,
+    };
+
+    let mut attempt = 0;
+    loop {
+        match remote.attempt(attempt == 0) {
+            Ok(()) => break,
+            Err(ReplFailure::Retry) if attempt < opt.retries && !process::cancelled() => {
+                let delay = opt.retry_delay * 2u32.saturating_pow(attempt);
+                attempt += 1;
+                warn!(
+                    "Remote replication to {} failed - retry {} of {} in {:?}",
+                    remote_ssh, attempt, opt.retries, delay
+                );
+                if !opt.dryrun {
+                    std::thread::sleep(delay);
+                }
+            }
+            Err(_) => {
+                error!("Remote replication to {} failed", remote_ssh);
+                return Err(());
+            }
+        }
+    }
+
+    if opt.dryrun {
+        return Ok(());
+    }
+
+    // The exit code of a bare zfs recv is no proof, so only move on once the remote lists it.
+    if confirm_remote_received(&remote_ssh, remote_dataset.as_deref(), &basesnap_name).is_err() {
+        error!(
+            "Remote replication to {} could not be confirmed - the metadata and anchor stay at {}",
+            remote_ssh, meta.precursor_snap
+        );
+        return Err(());
+    }
+
+    meta_file.set(
+        Some(&identity),
+        RemoteMetadata {
+            precursor_snap: basesnap_name.clone(),
+            ..Default::default()
+        },
+    );
+    if meta_file.save(&opt.auto_snap_metadata).is_err() {
+        return Err(());
+    }
+
+    if register_remote_anchor(&opt.auto_snap_metadata, Some(&identity), &basesnap_name).is_err() {
+        return Err(());
+    }
+
+    info!("Incremental remote replication success");
+    Ok(())
+}
+
+/// Does the remote list `basesnap_name`, with the guid it has here?
+fn confirm_remote_received(
+    remote_ssh: &Ssh,
+    dataset: Option<&str>,
+    basesnap_name: &str,
+) -> Result<(), ()> {
+    let guid = get_property(basesnap_name, "guid")?;
+    let remote_snaps = query_remote_snapshots(remote_ssh, dataset).map_err(|_| {
+        error!(
+            "Unable to list the snapshots on {} - the receiver must run znapper recv, or be a \
+             registered target",
+            remote_ssh
+        );
+    })?;
+    if remote_snaps.iter().any(|(name, remote_guid)| {
+        short_name(name) == short_name(basesnap_name) && *remote_guid == guid
+    }) {
+        debug!("{} holds {} ({})", remote_ssh, basesnap_name, guid);
+        Ok(())
+    } else {
+        error!(
+            "{} does not hold {} (guid {})",
+            remote_ssh, basesnap_name, guid
+        );
+        Err(())
+    }
+}
+
+/// Why an attempt at remote replication failed - is it worth trying again?
+enum ReplFailure {
+    /// Trying again won't help, ie the remote is not a valid destination.
+    Fatal,
+    /// The transfer failed, ie the connection dropped.
+    Retry,
+}
+
+/// Everything an attempt at remote replication needs.
+struct RemoteRepl<'a> {
+    opt: &'a ReplRemoteOpt,
+    ssh: &'a Ssh,
+    dataset: Option<&'a str>,
+    recv: &'a [&'a str],
+    meta: &'a RemoteMetadata,
+    pool: &'a str,
+    basesnap_name: &'a str,
+}
+
+impl RemoteRepl<'_> {
+    /// Bring the remote up to basesnap - resume any interrupted receive, then send the rest as an
+    /// incremental from the remote's latest snapshot. The destination checks are done on the
+    /// first attempt only.
+    fn attempt(&self, first: bool) -> Result<(), ReplFailure> {
+        let mut resumed = false;
+        match query_partial_recv(self.ssh, self.dataset) {
+            Some(state) => {
+                for partial in state.partial.iter().filter(|p| p.name != state.dataset) {
+                    error!(
+                        "{} on {} has a partial receive that can't be resumed alone - abort it with zfs recv -A {}",
+                        partial.name, self.ssh, partial.name
+                    );
+                }
+                if state.partial.iter().any(|p| p.name != state.dataset) {
+                    return Err(ReplFailure::Fatal);
+                }
+                if let Some(partial) = state.partial.first() {
+                    info!("Resuming the interrupted receive into {}", partial.name);
+                    self.transfer(&["-t", partial.token.as_str()], None)?;
+                    if self.opt.dryrun {
+                        plan::transfer(plan::Transfer {
+                            source: self.pool.to_string(),
+                            from: None,
+                            to: partial.name.clone(),
+                            destination: self.ssh.to_string(),
+                            estimated_bytes: None,
+                            resume_token: Some(partial.token.clone()),
+                        });
+                    }
+                    resumed = true;
+                }
+            }
+            None => debug!("Unable to check {} for a partial receive", self.ssh),
+        }
+
+        // The metadata can drift after partial failures, so anchor from what the remote really has.
+        let precursor_name = match query_remote_snapshots(self.ssh, self.dataset) {
+            Ok(remote_snaps) => {
+                match remote_precursor(self.pool, &remote_snaps, self.opt.force_rollback) {
+                    Ok(p) => {
+                        if p != self.meta.precursor_snap {
+                            warn!(
+                                "Metadata precursor is {}, but the remote is at {} - sending from {}",
+                                self.meta.precursor_snap, p, p
+                            );
+                        }
+                        p
+                    }
+                    Err(_) => return Err(ReplFailure::Fatal),
+                }
+            }
+            Err(_) => {
+                warn!(
+                    "Unable to list the snapshots on {} - trusting the metadata precursor {}",
+                    self.ssh, self.meta.precursor_snap
+                );
+                self.meta.precursor_snap.clone()
+            }
+        };
+
+        if precursor_name == self.basesnap_name {
+            if resumed {
+                // The rest of a recursive stream is not part of the resumed top dataset.
+                warn!(
+                    "Resumed {} on {} - if it has descendants, check they also hold it",
+                    self.basesnap_name, self.ssh
+                );
+            } else {
+                warn!("No action required - snapshots are in the same state!");
+            }
+            return Ok(());
+        }
+
+        let estimated = if first {
+            self.check_space(&precursor_name)?
+        } else {
+            None
+        };
+
+        if let Some(dataset) = self.dataset.filter(|_| first && self.opt.force_rollback) {
+            check_remote_rollback_destination(self.ssh, dataset, &precursor_name)
+                .map_err(|_| ReplFailure::Fatal)?;
+            let rollback = format!(
+                "zfs recv -F into {}, rolling back to {}",
+                dataset, precursor_name
+            );
+            approval::gate(
+                self.opt.dryrun,
+                approval::Kind::Rollback,
+                &format!("{}:{}", self.ssh, dataset),
+                &rollback,
+            )
+            .and_then(|()| confirm::gate(self.opt.dryrun, &rollback, &[]))
+            .map_err(|_| ReplFailure::Fatal)?;
+            audit::rollback(
+                self.opt.dryrun,
+                dataset,
+                &precursor_name,
+                snapshot_guid(&precursor_name),
+            );
+        }
+
+        let basesnap_guid = get_property(self.basesnap_name, "guid").ok();
+        self.transfer(
+            &[
+                recursion_flag(self.opt.single_dataset),
+                "-L",
+                "-w",
+                "-I",
+                precursor_name.as_str(),
+                self.basesnap_name,
+            ],
+            Some((short_name(self.basesnap_name), basesnap_guid.as_deref())),
+        )?;
+        if self.opt.dryrun {
+            plan::transfer(plan::Transfer {
+                source: self.pool.to_string(),
+                from: Some(precursor_name),
+                to: self.basesnap_name.to_string(),
+                destination: self.ssh.to_string(),
+                estimated_bytes: estimated,
+                resume_token: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Refuse to send if the estimated stream from `precursor_name` won't fit on the remote,
+    /// returning the estimate.
+    fn check_space(&self, precursor_name: &str) -> Result<Option<u64>, ReplFailure> {
+        let estimates = match estimate::stream(
+            self.pool,
+            !self.opt.single_dataset,
+            Some(precursor_name),
+            self.basesnap_name,
+        ) {
+            Ok(e) => e,
+            Err(_) => {
+                warn!("Unable to estimate the stream to {}", self.ssh);
+                return Ok(None);
+            }
+        };
+        match query_remote_free(self.ssh, self.dataset) {
+            Some(free) => estimate::check_space(
+                &self.ssh.to_string(),
+                &estimates,
+                free,
+                self.opt.ignore_space,
+            )
+            .map(|_| estimated_total(&estimates))
+            .map_err(|_| ReplFailure::Fatal),
+            None => {
+                warn!("Unable to check the free space on {}", self.ssh);
+                Ok(estimated_total(&estimates))
+            }
+        }
+    }
+
+    /// zfs send -v -P `send_args` | ssh remote. With `expect`, znapper recv must report that it
+    /// received that snapshot (short name and guid).
+    fn transfer(
+        &self,
+        send_args: &[&str],
+        expect: Option<(&str, Option<&str>)>,
+    ) -> Result<(), ReplFailure> {
+        remote_transfer(
+            self.opt.dryrun,
+            &self.opt.buffer,
+            self.ssh,
+            self.recv,
+            &self.opt.stream.send_args(send_args),
+            expect,
+            &format!("remote send to {}", self.ssh),
+        )
+    }
+}
+
+/// Did znapper recv report a success, and (with `expect`) receiving that snapshot?
+fn recv_confirmed(result: &recv::RecvResult, expect: Option<(&str, Option<&str>)>) -> bool {
+    for w in result.warnings.iter() {
+        warn!("remote recv -> {}", w);
+    }
+    for e in result.errors.iter() {
+        error!("remote recv -> {}", e);
+    }
+    let received = match expect {
+        Some((sent, guid)) => result
+            .received
+            .iter()
+            .any(|r| short_name(&r.name) == sent && Some(r.guid.as_str()) == guid),
+        None => true,
+    };
+    if let (true, false, Some((sent, _))) = (result.success, received, expect) {
+        error!("remote recv succeeded, but did not receive {}", sent);
+    }
+    result.success && received
+}
+
+/// zfs send -v -P `send_args` | ssh remote `recv`, checkpointed as `label`. With `expect`, znapper
+/// recv must report that it received that snapshot (short name and guid).
+fn remote_transfer(
+    dry: bool,
+    buffer: &BufferOpt,
+    ssh: &Ssh,
+    recv: &[&str],
+    send_args: &[&str],
+    expect: Option<(&str, Option<&str>)>,
+    label: &str,
+) -> Result<(), ReplFailure> {
+    let _span = info_span!(target: telemetry::SPANS, "send", label, remote = %ssh, bytes = tracing::field::Empty).entered();
+    if dry {
+        info!(
+            "dryrun -> zfs send -v -P {} | ssh {} {}",
+            send_args.join(" "),
+            ssh,
+            recv.join(" ")
+        );
+        return Ok(());
+    }
+    if let Some(chunk_size) = ssh.chunk_size() {
+        return chunked::transfer(ssh, recv, send_args, expect, label, chunk_size);
+    }
+    debug!(
+        "running -> zfs send -v -P {} | ssh {} {}",
+        send_args.join(" "),
+        ssh,
+        recv.join(" ")
+    );
+
+    let send = privilege::zfs()
+        .arg("send")
+        .arg("-v")
+        .arg("-P")
+        .args(send_args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .run_spawn();
+
+    let mut send = match send {
+        Ok(send) => send,
+        Err(e) => {
+            error!("send failed -> {:?}", e);
+            return Err(ReplFailure::Fatal);
+        }
+    };
+    let send_guard = process::register(&send, "zfs", Kind::Transfer);
+
+    let stdout = match send.stdout.take() {
+        Some(s) => s,
+        None => {
+            error!("Failed to connect to stdout of zfs send process");
+            return Err(ReplFailure::Fatal);
+        }
+    };
+    let (stdin, buffer) = match buffer::buffered(buffer, stdout) {
+        Ok(b) => b,
+        Err(_) => {
+            let _ = send.kill();
+            let _ = send.wait();
+            return Err(ReplFailure::Fatal);
+        }
+    };
+    let watch = send
+        .stderr
+        .take()
+        .map(|stderr| progress::watch(label, stderr, buffer.meter()));
+    let (stdin, mut compressor) = match ssh.compression() {
+        Some(compression) => match compress::compress(compression, stdin) {
+            Ok((child, out)) => (Stdio::from(out), Some(child)),
+            Err(_) => {
+                let _ = send.kill();
+                let _ = send.wait();
+                return Err(ReplFailure::Fatal);
+            }
+        },
+        None => (stdin, None),
+    };
+
+    let recv_span = info_span!(target: telemetry::SPANS, "recv", remote = %ssh).entered();
+    let recv = ssh
+        .command(recv)
+        .stdin(stdin)
+        .stderr(Stdio::piped())
+        .run_output(Kind::Transfer);
+
+    let recv_ok = match recv {
+        Ok(output) => {
+            // What ssh, or the forced command it ran, had to say.
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            match recv::parse_result::<recv::RecvResult>(&String::from_utf8_lossy(&output.stdout)) {
+                // The receiver runs znapper recv, so we know exactly what happened.
+                Some(result) => {
+                    for line in stderr.lines().filter(|l| !l.trim().is_empty()) {
+                        warn!("ssh {} -> {}", ssh, line);
+                    }
+                    recv_confirmed(&result, expect)
+                }
+                // A bare zfs recv forced command can't tell us, so go by the exit code until the
+                // received snapshot is confirmed.
+                None if ssh.compression().is_some() => {
+                    error!(
+                        "recv {} did not report what it received -> {} - the receiver must run \
+                         znapper recv to decompress the stream",
+                        ssh,
+                        stderr.trim()
+                    );
+                    false
+                }
+                None => {
+                    let code = output.status.code().unwrap_or(255);
+                    if code == 1 || code == 0 {
+                        warn!("success recv code {}", code);
+                        for line in stderr.lines().filter(|l| !l.trim().is_empty()) {
+                            warn!("ssh {} -> {}", ssh, line);
+                        }
+                        // Happy path.
+                        true
+                    } else {
+                        error!(
+                            "recv {} -> {}",
+                            ssh.describe_failure(output.status),
+                            stderr.trim()
+                        );
+                        false
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            error!("ssh recv failed -> {:?}", e);
+            false
+        }
+    };
+    drop(recv_span);
+
+    let send_status = process::wait(&mut send, &send_guard);
+    let send_ok = matches!(&send_status, Ok(status) if status.success());
+    let buffer_ok = buffer.finish().is_ok();
+    let compress_ok = match compressor.as_mut().map(|child| child.wait()) {
+        None => true,
+        Some(Ok(status)) if status.success() => true,
+        // With the receiver gone zstd fails to write to it, which is reported as the recv.
+        Some(_) if !recv_ok => false,
+        Some(status) => {
+            error!("zstd failed -> {:?}", status);
+            false
+        }
+    };
+    let ok = recv_ok && send_ok && buffer_ok && compress_ok;
+
+    let stderr = watch.map(|watch| watch.finish(ok)).unwrap_or_default();
+    log_send_exit(label, &send_status, &stderr);
+    if ok {
+        Ok(())
+    } else {
+        Err(ReplFailure::Retry)
+    }
+}
+
+/// The interrupted receives on the remote that can be resumed. A receiver running znapper recv
+/// reports them itself, otherwise (if the dataset is known) we ask zfs get over ssh.
+fn query_partial_recv(remote_ssh: &Ssh, dataset: Option<&str>) -> Option<recv::PartialState> {
+    debug!("running -> ssh {} partial", remote_ssh);
+    let output = remote_ssh
+        .command(&["partial"])
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh);
+    if let Ok(output) = output {
+        if let Some(state) =
+            recv::parse_result::<recv::PartialState>(&String::from_utf8_lossy(&output.stdout))
+        {
+            return Some(state);
+        }
+    }
+
+    let dataset = dataset?;
+    let stdout = ssh_output(
+        remote_ssh,
+        &[
+            "zfs",
+            "get",
+            "-H",
+            "-r",
+            "-o",
+            "name,value",
+            "receive_resume_token",
+            dataset,
+        ],
+    )
+    .ok()?;
+    Some(recv::PartialState {
+        dataset: dataset.to_string(),
+        partial: recv::parse_partial(&stdout),
+    })
+}
+
+/// The free space of the remote pool. A receiver running znapper recv reports it itself,
+/// otherwise (if the dataset is known) we ask zpool get over ssh.
+fn query_remote_free(remote_ssh: &Ssh, dataset: Option<&str>) -> Option<u64> {
+    debug!("running -> ssh {} space", remote_ssh);
+    let output = remote_ssh
+        .command(&["space"])
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh);
+    if let Ok(output) = output {
+        if let Some(space) =
+            recv::parse_result::<recv::Space>(&String::from_utf8_lossy(&output.stdout))
+        {
+            return space.free;
+        }
+    }
+
+    let dataset = dataset?;
+    let pool = dataset.split('/').next().unwrap_or(dataset);
+    ssh_output(
+        remote_ssh,
+        &["zpool", "get", "-H", "-p", "-o", "value", "free", pool],
+    )
+    .ok()?
+    .trim()
+    .parse()
+    .ok()
+}
+
+/// Who the remote is, to keep its metadata apart from that of the other destinations of the same
+/// file - the guid of the pool it receives into, as znapper recv or zpool get reports it, or
+/// failing that its host.
+fn query_remote_identity(remote_ssh: &Ssh, dataset: Option<&str>) -> String {
+    debug!("running -> ssh {} space", remote_ssh);
+    let output = remote_ssh
+        .command(&["space"])
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh);
+    let guid = output
+        .ok()
+        .and_then(|output| {
+            recv::parse_result::<recv::Space>(&String::from_utf8_lossy(&output.stdout))
+        })
+        .and_then(|space| space.guid)
+        .or_else(|| {
+            let dataset = dataset?;
+            let pool = dataset.split('/').next().unwrap_or(dataset);
+            ssh_output(
+                remote_ssh,
+                &["zpool", "get", "-H", "-p", "-o", "value", "guid", pool],
+            )
+            .ok()
+        })
+        .map(|guid| guid.trim().to_string())
+        .filter(|guid| !guid.is_empty() && guid.chars().all(|c| c.is_ascii_digit()));
+    guid.unwrap_or_else(|| remote_ssh.to_string())
+}
+
+/// Parse a duration such as 30s, 5m, 1h or 2d. A bare number is in seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration {}", s))?;
+    match unit {
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 60 * 60)),
+        "d" => Ok(Duration::from_secs(value * 60 * 60 * 24)),
+        _ => Err(format!("invalid duration {} - use s, m, h or d", s)),
+    }
+}
+
+// https://doc.rust-lang.org/std/process/struct.Stdio.html#impl-From%3CChildStdout%3E
+
+/// The znapper command line - parse the arguments, and run the action they give.
+pub fn run_cli() {
+    let matches = Cli::clap().get_matches();
+    let action = matches.subcommand_name().unwrap_or("znapper").to_string();
+    let cli = Cli::from_clap(&matches);
+    privilege::init(&cli.zfs);
+    confirm::init(&cli.confirm);
+    let opt = cli.action;
+
+    let plan_json = matches!(opt.plan_format(), OutputFormat::Json);
+    logging::init(&cli.log, telemetry::layer(&cli.telemetry));
+    process::init();
+    naming::init(&cli.naming);
+
+    debug!(?opt);
+
+    if plan_json {
+        plan::start();
+    }
+    let update_metrics = opt.updates_metrics();
+    let dry = opt.dryrun();
+    let summarised = opt.summarised();
+    if summarised {
+        summary::start(&action, dry);
+    }
+
+    // Held until we exit.
+    let _locks = match opt.locks() {
+        Some((scopes, lock_opt)) => match lock::acquire(scopes, lock_opt) {
+            Ok(locks) => locks,
+            Err(_) => {
+                email::send_failure(dry);
+                std::process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+
+    // Each pool is listed once for the run, until something changes it.
+    let _cache = listing::cached();
+    let run = info_span!(target: telemetry::SPANS, "znapper", dryrun = dry);
+    let entered = run.enter();
+    let result = match opt {
+        Action::List(opt) => {
+            do_list(&opt);
+            Ok(())
+        }
+        Action::Init(opt) => do_init(&opt),
+        Action::Repl(opt) => do_repl(&opt),
+        Action::ReplCleanup(opt) => {
+            do_repl_cleanup(&opt);
+            Ok(())
+        }
+        Action::InitArchive(opt) => {
+            do_init_archive(&opt);
+            Ok(())
+        }
+        Action::LoadArchive(opt) => {
+            do_load_archive(&opt);
+            Ok(())
+        }
+        Action::InitRemote(opt) => do_init_remote(&opt),
+        Action::ReplRemote(opt) => do_repl_remote(&opt),
+        Action::Pull(opt) => pull::do_pull(&opt),
+        Action::Fleet(action) => {
+            fleet::do_fleet(&action);
+            Ok(())
+        }
+        Action::Recv(opt) => {
+            recv::do_recv(&opt);
+            Ok(())
+        }
+        Action::ServeRecv(opt) => {
+            transport::do_serve_recv(&opt);
+            Ok(())
+        }
+        Action::Connect(opt) => std::process::exit(transport::do_connect(&opt)),
+        Action::SshConnect(opt) => std::process::exit(sshclient::do_connect(&opt)),
+        Action::Serve(opt) => {
+            serve::do_serve(&opt);
+            Ok(())
+        }
+        Action::Zedlet(opt) => {
+            zed::do_zedlet(&opt);
+            Ok(())
+        }
+        Action::Snapshot(opt) => do_snap(&opt).map(|_| ()),
+        Action::SnapshotCleanup(opt) => do_snap_cleanup(&opt),
+        Action::Status(opt) => {
+            status::do_status(&opt);
+            Ok(())
+        }
+        Action::CheckLag(opt) => std::process::exit(status::do_check_lag(&opt)),
+        Action::History(opt) => {
+            history::do_history(&opt);
+            Ok(())
+        }
+        Action::Audit(opt) => {
+            audit::do_audit(&opt);
+            Ok(())
+        }
+        Action::Diff(opt) => {
+            diff::do_diff(&opt);
+            Ok(())
+        }
+        Action::Sync(opt) => sync::do_sync(&opt).and_then(|outcomes| {
+            if sync::synced(&outcomes) {
+                Ok(())
+            } else {
+                Err(())
+            }
+        }),
+        Action::UsbBackup(opt) => {
+            usb::do_usb_backup(&opt);
+            Ok(())
+        }
+        Action::GenerateUnits(opt) => {
+            units::do_generate_units(&opt);
+            Ok(())
+        }
+        Action::Inventory(opt) => {
+            inventory::do_inventory(&opt);
+            Ok(())
+        }
+        Action::Usage(opt) => {
+            usage::do_usage(&opt);
+            Ok(())
+        }
+        Action::Target(action) => {
+            targets::do_target(&action);
+            Ok(())
+        }
+        Action::Find(opt) => {
+            find::do_find(&opt);
+            Ok(())
+        }
+        Action::Mount(opt) => {
+            mount::do_mount(&opt);
+            Ok(())
+        }
+        Action::Unmount(opt) => {
+            mount::do_unmount(&opt);
+            Ok(())
+        }
+        Action::Redact(opt) => {
+            redact::do_redact(&opt);
+            Ok(())
+        }
+        Action::Restore(opt) => {
+            restore::do_restore(&opt);
+            Ok(())
+        }
+        Action::RestoreGroup(opt) => {
+            groups::do_restore_group(&opt);
+            Ok(())
+        }
+        Action::Estimate(opt) => {
+            estimate::do_estimate(&opt);
+            Ok(())
+        }
+        Action::Check(opt) => check::do_check(&opt),
+        Action::Verify(opt) => verify::do_verify(&opt),
+        Action::Failover(opt) => {
+            failover::do_failover(&opt);
+            Ok(())
+        }
+        Action::LoadKeys(opt) => {
+            keys::do_load_keys(&opt);
+            Ok(())
+        }
+        Action::KeyStatus(opt) => {
+            keys::do_key_status(&opt);
+            Ok(())
+        }
+        Action::SetupDelegation(opt) => {
+            delegation::do_setup_delegation(&opt);
+            Ok(())
+        }
+        Action::UndoCleanup(opt) => {
+            trash::do_undo_cleanup(&opt);
+            Ok(())
+        }
+        Action::Approve(opt) => approval::do_approve(&opt),
+        Action::Metrics => {
+            metrics::do_metrics();
+            Ok(())
+        }
+        Action::Progress(opt) => {
+            progress::do_progress(&opt);
+            Ok(())
+        }
+        Action::ImportConfig(opt) => {
+            import::do_import_config(&opt);
+            Ok(())
+        }
+        Action::Completions(opt) => {
+            completions::do_completions(&opt);
+            Ok(())
+        }
+        Action::Manpage => {
+            completions::do_manpage();
+            Ok(())
+        }
+        #[cfg(feature = "tui")]
+        Action::Tui(opt) => {
+            tui::do_tui(&opt);
+            Ok(())
+        }
+    };
+    drop(entered);
+    drop(run);
+
+    if plan_json {
+        plan::print();
+    }
+    // A run that logged errors failed, as its summary and email say, even where the action
+    // carried on past them - whatever the action, summarised or not.
+    let failed = result.is_err() || process::timed_out() || !email::logged().is_empty();
+    summary::print(&cli.summary, process::exit_code().is_some(), !plan_json);
+    if update_metrics {
+        metrics::write();
+    }
+    telemetry::export(&cli.telemetry);
+    email::send_failure(dry);
+    if let Some(code) = process::exit_code() {
+        std::process::exit(code);
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,222 @@
+//! One `zfs list` of each pool per run, rather than one for each question asked of it.
+//!
+//! Cleanup and replication list the snapshots, bookmarks, guids and datasets of the same pools
+//! many times over, which on a pool with thousands of snapshots is most of the run. Within a run
+//! (`cached`), the first list of a dataset lists everything under it - `zfs list -t all` - and
+//! the lists of it and of its descendants are answered from that until a zfs command that isn't
+//! a list or get is run, which could have changed them.
+
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error};
+
+#[derive(Debug, Default)]
+struct Cache {
+    /// How many `cached` guards are held - listings are only kept while there is one.
+    depth: usize,
+    /// name, guid and createtxg of everything under each dataset listed.
+    listed: BTreeMap<String, Arc<Vec<Listed>>>,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    depth: 0,
+    listed: BTreeMap::new(),
+});
+
+/// A dataset, snapshot or bookmark, as the list gives it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Listed {
+    /// The name, tab separated from its guid and createtxg as `model::parse_list` reads them.
+    pub line: String,
+    pub name: String,
+}
+
+impl Listed {
+    fn parse(line: &str) -> Option<Self> {
+        let name = line.split('\t').next()?.trim();
+        if name.is_empty() {
+            return None;
+        }
+        Some(Listed {
+            line: line.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    pub(crate) fn is_snapshot(&self) -> bool {
+        self.name.contains('@')
+    }
+
+    pub(crate) fn is_bookmark(&self) -> bool {
+        self.name.contains('#')
+    }
+
+    pub(crate) fn is_dataset(&self) -> bool {
+        !self.is_snapshot() && !self.is_bookmark()
+    }
+
+    /// The dataset itself, or the snapshot or bookmark's.
+    fn dataset(&self) -> &str {
+        self.name.split(['@', '#']).next().unwrap_or(&self.name)
+    }
+
+    /// Is it `dataset`, or a snapshot or bookmark of it?
+    pub(crate) fn of(&self, dataset: &str) -> bool {
+        self.dataset() == dataset
+    }
+
+    /// Is it `dataset`, under it, or a snapshot or bookmark of either?
+    fn under(&self, dataset: &str) -> bool {
+        within(self.dataset(), dataset)
+    }
+}
+
+/// Is the dataset `name` `dataset`, or under it?
+fn within(name: &str, dataset: &str) -> bool {
+    name == dataset
+        || name
+            .strip_prefix(dataset)
+            .map(|rest| rest.starts_with('/'))
+            .unwrap_or(false)
+}
+
+/// Keeps listings for as long as it is held.
+pub(crate) struct Cached;
+
+/// Keep the listings until the guard is dropped - for the length of a run.
+pub(crate) fn cached() -> Cached {
+    if let Ok(mut cache) = CACHE.lock() {
+        cache.depth += 1;
+    }
+    Cached
+}
+
+impl Drop for Cached {
+    fn drop(&mut self) {
+        if let Ok(mut cache) = CACHE.lock() {
+            cache.depth = cache.depth.saturating_sub(1);
+            if cache.depth == 0 {
+                cache.listed.clear();
+            }
+        }
+    }
+}
+
+/// Something may have changed - forget the listings.
+pub(crate) fn changed() {
+    if let Ok(mut cache) = CACHE.lock() {
+        if !cache.listed.is_empty() {
+            debug!("listings cleared");
+            cache.listed.clear();
+        }
+    }
+}
+
+/// Does `cmd` only read - a zfs (or zpool) list or get?
+fn reads_only(cmd: &Command) -> bool {
+    let zfs = privilege::zfs_path();
+    let binary = |arg: &str| {
+        arg == zfs
+            || matches!(
+                Path::new(arg).file_name().and_then(|n| n.to_str()),
+                Some("zfs") | Some("zpool")
+            )
+    };
+    let args: Vec<_> = cmd
+        .get_args()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect();
+    matches!(
+        args.iter()
+            .find(|a| !a.starts_with('-') && !binary(a))
+            .map(String::as_str),
+        Some("list") | Some("get")
+    )
+}
+
+/// `cmd` is about to be run - so forget the listings, unless it can't change them.
+pub(crate) fn running(cmd: &Command, kind: Kind) {
+    if !matches!(kind, Kind::Ssh) && !reads_only(cmd) {
+        changed();
+    }
+}
+
+/// Everything under (and including) `dataset` - from the listing of it, or of a dataset above
+/// it, if this run has one.
+pub(crate) fn list(dataset: &str) -> Result<Vec<Listed>, ()> {
+    let hit = CACHE.lock().ok().and_then(|cache| {
+        cache
+            .listed
+            .iter()
+            .find(|(root, _)| within(dataset, root))
+            .map(|(_, listed)| listed.clone())
+    });
+    if let Some(listed) = hit {
+        return Ok(listed
+            .iter()
+            .filter(|l| l.under(dataset))
+            .cloned()
+            .collect());
+    }
+
+    let mut cmd = privilege::zfs();
+    cmd.args([
+        "list",
+        "-H",
+        "-p",
+        "-t",
+        "all",
+        "-o",
+        "name,guid,createtxg",
+        "-r",
+        dataset,
+    ]);
+    let output = cmd.run_output(Kind::Zfs).map_err(|e| {
+        error!("list of {} failed -> {:?}", dataset, e);
+    })?;
+    let stdout = String::from_utf8(output.stdout).map_err(|e| {
+        error!("list of {} contains invalid utf8 -> {:?}", dataset, e);
+    })?;
+    let listed: Vec<_> = stdout.lines().filter_map(Listed::parse).collect();
+
+    // What doesn't exist (yet) is listed afresh each time.
+    if output.status.success() {
+        if let Ok(mut cache) = CACHE.lock() {
+            if cache.depth > 0 {
+                cache
+                    .listed
+                    .insert(dataset.to_string(), Arc::new(listed.clone()));
+            }
+        }
+    }
+    Ok(listed)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listings_are_of_the_dataset_and_below() {
+        let home = Listed::parse("nvme/home@repl_1\t1234\t56").unwrap();
+        assert!(home.is_snapshot() && !home.is_dataset());
+        assert!(home.under("nvme") && home.under("nvme/home") && home.of("nvme/home"));
+        assert!(!home.under("nvme/ho") && !home.of("nvme"));
+        assert!(within("nvme/home", "nvme") && !within("nvmex", "nvme"));
+        assert!(Listed::parse("nvme#repl_1\t1234\t56")
+            .unwrap()
+            .is_bookmark());
+
+        let mut cmd = Command::new("sudo");
+        cmd.args(["-n", "zfs", "list", "-H", "nvme"]);
+        assert!(reads_only(&cmd));
+        let mut cmd = Command::new("zfs");
+        cmd.args(["destroy", "nvme@auto_1"]);
+        assert!(!reads_only(&cmd));
+    }
+}
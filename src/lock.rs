@@ -0,0 +1,127 @@
+//! One znapper at a time for each pool, and each sync job.
+//!
+//! Two overlapping runs on the same pool - a slow repl and the next one from cron - race for the
+//! same anchors, and can destroy each other's. So every action that changes a pool first takes an
+//! exclusive flock on `/run/znapper/pool-<pool>.lock` (and sync on `job-<name>.lock` too), which
+//! is held until znapper exits. A run that finds a lock held fails straight away, or with
+//! `--wait` queues until it is released.
+
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+use tracing::{debug, error, info};
+
+/// Default directory for the lock files, overridable with `ZNAPPER_LOCK_DIR`.
+pub(crate) const DEFAULT_LOCK_DIR: &str = "/run/znapper";
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub(crate) struct LockOpt {
+    /// If another znapper is running on the same pool (or job), wait for it to finish rather
+    /// than failing.
+    #[structopt(long = "wait")]
+    pub wait: bool,
+}
+
+/// A lock that is held until it is dropped, or znapper exits.
+pub(crate) struct Lock {
+    _file: File,
+}
+
+fn lock_dir() -> PathBuf {
+    std::env::var_os("ZNAPPER_LOCK_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_LOCK_DIR))
+}
+
+/// The lock of the pool holding `dataset`.
+pub(crate) fn pool(dataset: &str) -> String {
+    let pool = dataset.split(['/', '@', '#']).next().unwrap_or(dataset);
+    format!("pool-{}", pool)
+}
+
+/// The lock of a sync job.
+pub(crate) fn job(name: &str) -> String {
+    format!("job-{}", name)
+}
+
+fn path(scope: &str) -> PathBuf {
+    let name: String = scope
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    lock_dir().join(format!("{}.lock", name))
+}
+
+fn lock(scope: &str, opt: &LockOpt) -> Result<Lock, ()> {
+    let path = path(scope);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| {
+            error!(
+                "Unable to open lock {:?} -> {:?} - set ZNAPPER_LOCK_DIR to a writable directory",
+                path, e
+            );
+        })?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            // The holder wrote who it is into the lock.
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            let holder = holder.trim();
+            if !opt.wait {
+                error!(
+                    "Another znapper holds {} ({}) - use --wait to wait for it",
+                    scope, holder
+                );
+                return Err(());
+            }
+            info!("Waiting for {} held by {}", scope, holder);
+            file.lock().map_err(|e| {
+                error!("Unable to lock {:?} -> {:?}", path, e);
+            })?;
+        }
+        Err(TryLockError::Error(e)) => {
+            error!("Unable to lock {:?} -> {:?}", path, e);
+            return Err(());
+        }
+    }
+
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let holder = format!("pid {}: znapper {}\n", std::process::id(), args.join(" "));
+    let res = file
+        .set_len(0)
+        .and_then(|_| file.rewind())
+        .and_then(|_| file.write_all(holder.as_bytes()));
+    if let Err(e) = res {
+        debug!("Unable to record the holder of {:?} -> {:?}", path, e);
+    }
+    debug!("locked {}", scope);
+    Ok(Lock { _file: file })
+}
+
+/// Take the locks of `scopes`, in order so that two runs can never wait on each other.
+pub(crate) fn acquire(mut scopes: Vec<String>, opt: &LockOpt) -> Result<Vec<Lock>, ()> {
+    scopes.sort_unstable();
+    scopes.dedup();
+    let dir = lock_dir();
+    fs::create_dir_all(&dir).map_err(|e| {
+        error!(
+            "Unable to create lock dir {:?} -> {:?} - set ZNAPPER_LOCK_DIR to a writable directory",
+            dir, e
+        );
+    })?;
+    scopes.iter().map(|scope| lock(scope, opt)).collect()
+}
@@ -0,0 +1,398 @@
+//! Where the log of a run goes - stderr, the journal, syslog, or a file of its own - and how much
+//! of it.
+//!
+//! The log never goes to stdout, which is kept for what an action reports - a list, a status, a
+//! plan - so that it can be piped on.
+//!
+//! The journal and syslog are written to over their sockets, with the priority of each event, so
+//! cron runs and timers leave a log that can be queried with journalctl or the syslog daemon. A
+//! file is rotated once it grows past `--log-max-size`, keeping `--log-keep` old logs beside it
+//! as `<path>.1` (the newest) up. stderr and files can log json instead, one object per line.
+
+use crate::buffer::parse_size;
+use crate::telemetry;
+use crate::OutputFormat;
+use crate::{email, summary};
+use std::fmt::{self as stdfmt, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use structopt::StructOpt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriter};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter, Registry};
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum LogTarget {
+    Stderr,
+    Journald,
+    Syslog,
+    File(PathBuf),
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(LogTarget::Stderr),
+            "journald" => Ok(LogTarget::Journald),
+            "syslog" => Ok(LogTarget::Syslog),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(LogTarget::File(PathBuf::from(path))),
+                _ => Err(format!(
+                    "unknown log target {} - use stderr, journald, syslog or file:<path>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub(crate) struct LogOpt {
+    /// Where to log - stderr (the default), journald, syslog or file:<path>.
+    #[structopt(long = "log-target")]
+    log_target: Option<LogTarget>,
+    /// text or json, one object per line. The journal and syslog always get text.
+    #[structopt(long = "log-format", default_value = "text")]
+    log_format: OutputFormat,
+    /// Rotate a file:<path> log once it grows past this size, ie 10M.
+    #[structopt(long = "log-max-size", default_value = "10M", parse(try_from_str = parse_size))]
+    log_max_size: usize,
+    /// How many rotated file logs to keep.
+    #[structopt(long = "log-keep", default_value = "5")]
+    log_keep: usize,
+    /// Log more - debug, or trace with -vv - rather than RUST_LOG (info by default).
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: u8,
+    /// Log less - only warnings, or only errors with -qq.
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        parse(from_occurrences),
+        conflicts_with = "verbose"
+    )]
+    quiet: u8,
+}
+
+impl LogOpt {
+    /// The level -v and -q ask for, if either was given.
+    fn level(&self) -> Option<&'static str> {
+        match (self.verbose, self.quiet) {
+            (0, 0) => None,
+            (1, _) => Some("debug"),
+            (v, _) if v > 1 => Some("trace"),
+            (_, 1) => Some("warn"),
+            _ => Some("error"),
+        }
+    }
+}
+
+/// The message of an event, followed by its other fields.
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn stdfmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}{}", value, self.0);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// The syslog severity of `level`.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/// A journal entry in the native protocol - each field as NAME=value, or for a value with a
+/// newline in it, the name then the length and the value.
+fn journal_entry(level: Level, message: &str) -> Vec<u8> {
+    let mut entry = Vec::new();
+    let priority = severity(level).to_string();
+    for (name, value) in [
+        ("PRIORITY", priority.as_str()),
+        ("SYSLOG_IDENTIFIER", "znapper"),
+        ("MESSAGE", message),
+    ] {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
+
+/// A syslog message, from the daemon facility.
+fn syslog_message(level: Level, message: &str) -> String {
+    format!(
+        "<{}>znapper[{}]: {}",
+        3 * 8 + severity(level),
+        std::process::id(),
+        message
+    )
+}
+
+/// Sends each event to the journal or syslog, over its socket.
+struct SocketLog {
+    socket: UnixDatagram,
+    journald: bool,
+}
+
+impl<S: Subscriber> Layer<S> for SocketLog {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut message = Message::default();
+        event.record(&mut message);
+        let level = *event.metadata().level();
+        let sent = if self.journald {
+            self.socket.send(&journal_entry(level, &message.0))
+        } else {
+            self.socket
+                .send(syslog_message(level, &message.0).as_bytes())
+        };
+        // With nowhere else to log it, don't lose it.
+        if sent.is_err() {
+            eprintln!("{} {}", level, message.0);
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: usize, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            max_size: max_size as u64,
+            keep,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    /// Move each old log up one, dropping the oldest, and start the log again.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// A file log shared by every event - each is written whole, so the log rotates between them.
+#[derive(Clone)]
+struct FileLog(Arc<Mutex<RotatingFile>>);
+
+impl Write for FileLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut log = self
+            .0
+            .lock()
+            .map_err(|_| io::Error::other("log file lock poisoned"))?;
+        if log.size > 0 && log.size + buf.len() as u64 > log.max_size {
+            log.rotate()?;
+        }
+        let written = log.file.write(buf)?;
+        log.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0.lock() {
+            Ok(mut log) => log.file.flush(),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl MakeWriter for FileLog {
+    type Writer = FileLog;
+
+    fn make_writer(&self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+type BoxLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+fn fmt_layer(format: OutputFormat, writer: BoxMakeWriter, ansi: bool) -> BoxLayer {
+    match format {
+        OutputFormat::Text => Box::new(
+            fmt::layer()
+                .with_target(false)
+                .with_ansi(ansi)
+                .with_writer(writer),
+        ),
+        OutputFormat::Json => Box::new(fmt::layer().json().with_target(false).with_writer(writer)),
+    }
+}
+
+fn socket(path: &str) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// The layer that writes the log where `opt` sends it, or why it can't.
+fn output(opt: &LogOpt) -> Result<BoxLayer, String> {
+    match opt.log_target.as_ref() {
+        None | Some(LogTarget::Stderr) => Ok(fmt_layer(
+            opt.log_format,
+            BoxMakeWriter::new(io::stderr),
+            io::stderr().is_terminal(),
+        )),
+        Some(LogTarget::File(path)) => RotatingFile::open(path, opt.log_max_size, opt.log_keep)
+            .map(|file| {
+                let writer = BoxMakeWriter::new(FileLog(Arc::new(Mutex::new(file))));
+                fmt_layer(opt.log_format, writer, false)
+            })
+            .map_err(|e| format!("unable to open the log file {:?} -> {:?}", path, e)),
+        Some(target) => {
+            let (path, journald) = match target {
+                LogTarget::Journald => (JOURNALD_SOCKET, true),
+                _ => (SYSLOG_SOCKET, false),
+            };
+            socket(path)
+                .map(|socket| Box::new(SocketLog { socket, journald }) as BoxLayer)
+                .map_err(|e| format!("unable to connect to {} -> {:?}", path, e))
+        }
+    }
+}
+
+/// Log the run as `opt` says, filtered by -v or -q, or else RUST_LOG (info by default), keeping
+/// its spans with `spans`. If the target can't be opened the log goes to stderr instead, so that
+/// the run isn't lost.
+pub(crate) fn init(opt: &LogOpt, spans: Option<telemetry::Spans>) {
+    let (output, failed) = match output(opt) {
+        Ok(output) => (output, None),
+        Err(e) => (
+            fmt_layer(
+                opt.log_format,
+                BoxMakeWriter::new(io::stderr),
+                io::stderr().is_terminal(),
+            ),
+            Some(e),
+        ),
+    };
+    let mut filter_layer = match opt.level() {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let spans_level = if spans.is_some() { "info" } else { "off" };
+    if let Ok(directive) = format!("{}={}", telemetry::SPANS, spans_level).parse() {
+        filter_layer = filter_layer.add_directive(directive);
+    }
+
+    tracing_subscriber::registry()
+        .with(output)
+        .with(filter_layer)
+        .with(email::ErrorLog)
+        .with(summary::WarningLog)
+        .with(spans)
+        .init();
+    if let Some(e) = failed {
+        tracing::error!("{} - logging to stderr", e);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_targets_parse() {
+        assert_eq!("journald".parse(), Ok(LogTarget::Journald));
+        assert_eq!(
+            "file:/var/log/znapper.log".parse(),
+            Ok(LogTarget::File(PathBuf::from("/var/log/znapper.log")))
+        );
+        assert!("file:".parse::<LogTarget>().is_err());
+        assert!("kafka".parse::<LogTarget>().is_err());
+
+        assert_eq!(
+            journal_entry(Level::WARN, "two\nlines"),
+            b"PRIORITY=4\nSYSLOG_IDENTIFIER=znapper\nMESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n"
+        );
+        assert!(syslog_message(Level::ERROR, "failed").starts_with("<27>znapper["));
+
+        let level = |args: &[&str]| LogOpt::from_iter(["znapper"].iter().chain(args)).level();
+        assert_eq!(level(&[]), None);
+        assert_eq!(level(&["-v"]), Some("debug"));
+        assert_eq!(level(&["-vv"]), Some("trace"));
+        assert_eq!(level(&["-q"]), Some("warn"));
+        assert_eq!(level(&["-q", "-q"]), Some("error"));
+    }
+
+    #[test]
+    fn file_logs_rotate_past_their_size() {
+        let dir = std::env::temp_dir().join(format!("znapper-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("znapper.log");
+
+        let mut log = FileLog(Arc::new(Mutex::new(
+            RotatingFile::open(&path, 10, 2).unwrap(),
+        )));
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("znapper.log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("znapper.log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!dir.join("znapper.log.3").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
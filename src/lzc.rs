@@ -0,0 +1,351 @@
+//! The libzfs_core backend - snapshots created and destroyed with the ioctls of libzfs_core,
+//! rather than a zfs process each.
+//!
+//! With `--zfs-backend libzfs_core`, and a znapper built with the `libzfs-core` feature (which
+//! links libzfs_core and libnvpair), the snapshots of `snapshot`, the destroys of cleanup,
+//! replication and the tui, and the holds and releases of its pins skip the exec of zfs, and a
+//! failure comes back as the errno of each snapshot rather than stderr to be parsed. Everything
+//! libzfs_core has no call for - lists, properties, sends and receives - still runs zfs. The
+//! replication anchors are kept in the anchor store rather than as holds, so there is nothing of
+//! theirs to route through it. If libzfs_core can't be opened, or znapper was built without it, zfs
+//! is run for everything, as without the option.
+
+use crate::listing;
+use crate::privilege::{self, Backend};
+use std::io;
+use std::sync::OnceLock;
+use tracing::{debug, warn};
+
+static ACTIVE: OnceLock<bool> = OnceLock::new();
+
+/// The snapshots that failed, with why.
+pub(crate) type Failures = Vec<(String, io::Error)>;
+
+/// Are snapshots to be created and destroyed through libzfs_core?
+pub(crate) fn active() -> bool {
+    *ACTIVE.get_or_init(|| {
+        if privilege::backend() != Backend::LibzfsCore {
+            return false;
+        }
+        match ffi::init() {
+            Ok(()) => {
+                debug!("using libzfs_core");
+                true
+            }
+            Err(e) => {
+                warn!("Unable to use libzfs_core, running zfs instead -> {}", e);
+                false
+            }
+        }
+    })
+}
+
+/// Create `snaps` at once (they must be of the same pool), with the user properties `props`.
+/// Either all are created, or none are.
+pub(crate) fn snapshot(snaps: &[String], props: &[(&str, &str)]) -> Result<(), Failures> {
+    let res = ffi::snapshot(snaps, props);
+    listing::changed();
+    res
+}
+
+/// The failures, as a line of the log.
+pub(crate) fn describe(failures: &Failures) -> String {
+    failures
+        .iter()
+        .map(|(snap, e)| format!("{} -> {}", snap, e))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Destroy `snaps`. Those that can be are, whichever can't.
+pub(crate) fn destroy(snaps: &[String]) -> Result<(), Failures> {
+    let res = ffi::destroy(snaps);
+    listing::changed();
+    res
+}
+
+/// Place the hold `tag` on `snaps` (they must be of the same pool). Either all are held, or none
+/// are.
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub(crate) fn hold(snaps: &[String], tag: &str) -> Result<(), Failures> {
+    ffi::hold(snaps, tag)
+}
+
+/// Release the hold `tag` from `snaps`. Either all are released, or none are.
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub(crate) fn release(snaps: &[String], tag: &str) -> Result<(), Failures> {
+    ffi::release(snaps, tag)
+}
+
+#[cfg(feature = "libzfs-core")]
+mod ffi {
+    use super::Failures;
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::os::raw::{c_char, c_int, c_uint};
+    use std::ptr;
+
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct nvlist_t {
+        _private: [u8; 0],
+    }
+
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct nvpair_t {
+        _private: [u8; 0],
+    }
+
+    const NV_UNIQUE_NAME: c_uint = 1;
+    const B_FALSE: c_int = 0;
+    /// No cleanup fd - a hold stays until it is released.
+    const NO_CLEANUP: c_int = -1;
+
+    #[link(name = "nvpair")]
+    extern "C" {
+        fn nvlist_alloc(nvlp: *mut *mut nvlist_t, nvflag: c_uint, kmflag: c_int) -> c_int;
+        fn nvlist_free(nvl: *mut nvlist_t);
+        fn nvlist_add_boolean(nvl: *mut nvlist_t, name: *const c_char) -> c_int;
+        fn nvlist_add_string(nvl: *mut nvlist_t, name: *const c_char, val: *const c_char) -> c_int;
+        fn nvlist_add_nvlist(nvl: *mut nvlist_t, name: *const c_char, val: *mut nvlist_t) -> c_int;
+        fn nvlist_next_nvpair(nvl: *mut nvlist_t, nvp: *mut nvpair_t) -> *mut nvpair_t;
+        fn nvpair_name(nvp: *mut nvpair_t) -> *const c_char;
+        fn nvpair_value_int32(nvp: *mut nvpair_t, val: *mut i32) -> c_int;
+    }
+
+    #[link(name = "zfs_core")]
+    extern "C" {
+        fn libzfs_core_init() -> c_int;
+        fn lzc_snapshot(
+            snaps: *mut nvlist_t,
+            props: *mut nvlist_t,
+            errlist: *mut *mut nvlist_t,
+        ) -> c_int;
+        fn lzc_destroy_snaps(
+            snaps: *mut nvlist_t,
+            defer: c_int,
+            errlist: *mut *mut nvlist_t,
+        ) -> c_int;
+        fn lzc_hold(holds: *mut nvlist_t, cleanup_fd: c_int, errlist: *mut *mut nvlist_t) -> c_int;
+        fn lzc_release(holds: *mut nvlist_t, errlist: *mut *mut nvlist_t) -> c_int;
+    }
+
+    /// An nvlist we own, freed when dropped.
+    struct NvList(*mut nvlist_t);
+
+    impl NvList {
+        fn new() -> io::Result<Self> {
+            let mut nvl = ptr::null_mut();
+            // SAFETY: nvlist_alloc writes a new list to nvl, or fails and leaves it null.
+            match unsafe { nvlist_alloc(&mut nvl, NV_UNIQUE_NAME, 0) } {
+                0 if !nvl.is_null() => Ok(NvList(nvl)),
+                errno => Err(io::Error::from_raw_os_error(errno)),
+            }
+        }
+
+        fn add_boolean(&mut self, name: &str) -> io::Result<()> {
+            let name = cstring(name)?;
+            // SAFETY: self.0 is a live list, and the name is copied into it.
+            match unsafe { nvlist_add_boolean(self.0, name.as_ptr()) } {
+                0 => Ok(()),
+                errno => Err(io::Error::from_raw_os_error(errno)),
+            }
+        }
+
+        fn add_string(&mut self, name: &str, value: &str) -> io::Result<()> {
+            let (name, value) = (cstring(name)?, cstring(value)?);
+            // SAFETY: self.0 is a live list, and the name and value are copied into it.
+            match unsafe { nvlist_add_string(self.0, name.as_ptr(), value.as_ptr()) } {
+                0 => Ok(()),
+                errno => Err(io::Error::from_raw_os_error(errno)),
+            }
+        }
+
+        fn add_nvlist(&mut self, name: &str, value: &NvList) -> io::Result<()> {
+            let name = cstring(name)?;
+            // SAFETY: both lists are live, and value is copied into self.0.
+            match unsafe { nvlist_add_nvlist(self.0, name.as_ptr(), value.0) } {
+                0 => Ok(()),
+                errno => Err(io::Error::from_raw_os_error(errno)),
+            }
+        }
+
+        fn of(names: &[String]) -> io::Result<Self> {
+            let mut nvl = NvList::new()?;
+            for name in names.iter() {
+                nvl.add_boolean(name)?;
+            }
+            Ok(nvl)
+        }
+
+        /// The snapshot name and errno of each pair of an error list.
+        fn errors(&self) -> Vec<(String, i32)> {
+            let mut errors = Vec::new();
+            // SAFETY: the pairs are walked, and read, only while self.0 is alive.
+            unsafe {
+                let mut pair = nvlist_next_nvpair(self.0, ptr::null_mut());
+                while !pair.is_null() {
+                    let name = CStr::from_ptr(nvpair_name(pair))
+                        .to_string_lossy()
+                        .into_owned();
+                    let mut errno = 0;
+                    if nvpair_value_int32(pair, &mut errno) == 0 {
+                        errors.push((name, errno));
+                    }
+                    pair = nvlist_next_nvpair(self.0, pair);
+                }
+            }
+            errors
+        }
+    }
+
+    impl Drop for NvList {
+        fn drop(&mut self) {
+            // SAFETY: the list is ours, and freed once.
+            unsafe { nvlist_free(self.0) }
+        }
+    }
+
+    fn cstring(s: &str) -> io::Result<CString> {
+        CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    pub(super) fn init() -> io::Result<()> {
+        // SAFETY: opens /dev/zfs, and may be called more than once.
+        match unsafe { libzfs_core_init() } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+
+    /// The failures of a call that returned `errno`, from its error list - or, if that names
+    /// none, `errno` for every one of `snaps`.
+    fn failures(snaps: &[String], errno: c_int, errlist: Option<&NvList>) -> Failures {
+        let mut failures: Failures = errlist
+            .map(NvList::errors)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, errno)| (name, io::Error::from_raw_os_error(errno)))
+            .collect();
+        if failures.is_empty() {
+            failures = snaps
+                .iter()
+                .map(|snap| (snap.clone(), io::Error::from_raw_os_error(errno)))
+                .collect();
+        }
+        failures
+    }
+
+    fn all_failed(snaps: &[String], e: &io::Error) -> Failures {
+        snaps
+            .iter()
+            .map(|snap| (snap.clone(), io::Error::new(e.kind(), e.to_string())))
+            .collect()
+    }
+
+    pub(super) fn snapshot(snaps: &[String], props: &[(&str, &str)]) -> Result<(), Failures> {
+        let build = || -> io::Result<(NvList, NvList)> {
+            let mut props_nvl = NvList::new()?;
+            for (name, value) in props.iter() {
+                props_nvl.add_string(name, value)?;
+            }
+            Ok((NvList::of(snaps)?, props_nvl))
+        };
+        let (snaps_nvl, props_nvl) = build().map_err(|e| all_failed(snaps, &e))?;
+        let mut errlist = ptr::null_mut();
+        // SAFETY: both lists are live for the call, and we take ownership of errlist.
+        let errno = unsafe { lzc_snapshot(snaps_nvl.0, props_nvl.0, &mut errlist) };
+        let errlist = (!errlist.is_null()).then_some(NvList(errlist));
+        match errno {
+            0 => Ok(()),
+            errno => Err(failures(snaps, errno, errlist.as_ref())),
+        }
+    }
+
+    pub(super) fn destroy(snaps: &[String]) -> Result<(), Failures> {
+        let snaps_nvl = NvList::of(snaps).map_err(|e| all_failed(snaps, &e))?;
+        let mut errlist = ptr::null_mut();
+        // SAFETY: the list is live for the call, and we take ownership of errlist.
+        let errno = unsafe { lzc_destroy_snaps(snaps_nvl.0, B_FALSE, &mut errlist) };
+        let errlist = (!errlist.is_null()).then_some(NvList(errlist));
+        match errno {
+            0 => Ok(()),
+            errno => Err(failures(snaps, errno, errlist.as_ref())),
+        }
+    }
+
+    pub(super) fn hold(snaps: &[String], tag: &str) -> Result<(), Failures> {
+        // Each snapshot names the tag of its hold.
+        let build = || -> io::Result<NvList> {
+            let mut holds = NvList::new()?;
+            for snap in snaps.iter() {
+                holds.add_string(snap, tag)?;
+            }
+            Ok(holds)
+        };
+        let holds = build().map_err(|e| all_failed(snaps, &e))?;
+        let mut errlist = ptr::null_mut();
+        // SAFETY: the list is live for the call, and we take ownership of errlist.
+        let errno = unsafe { lzc_hold(holds.0, NO_CLEANUP, &mut errlist) };
+        let errlist = (!errlist.is_null()).then_some(NvList(errlist));
+        match errno {
+            0 => Ok(()),
+            errno => Err(failures(snaps, errno, errlist.as_ref())),
+        }
+    }
+
+    pub(super) fn release(snaps: &[String], tag: &str) -> Result<(), Failures> {
+        // Each snapshot names a list of the tags to release.
+        let build = || -> io::Result<NvList> {
+            let tags = NvList::of(&[tag.to_string()])?;
+            let mut holds = NvList::new()?;
+            for snap in snaps.iter() {
+                holds.add_nvlist(snap, &tags)?;
+            }
+            Ok(holds)
+        };
+        let holds = build().map_err(|e| all_failed(snaps, &e))?;
+        let mut errlist = ptr::null_mut();
+        // SAFETY: the list is live for the call, and we take ownership of errlist.
+        let errno = unsafe { lzc_release(holds.0, &mut errlist) };
+        let errlist = (!errlist.is_null()).then_some(NvList(errlist));
+        match errno {
+            0 => Ok(()),
+            errno => Err(failures(snaps, errno, errlist.as_ref())),
+        }
+    }
+}
+
+#[cfg(not(feature = "libzfs-core"))]
+mod ffi {
+    use super::Failures;
+    use std::io;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "znapper was built without the libzfs-core feature",
+        )
+    }
+
+    pub(super) fn init() -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub(super) fn snapshot(snaps: &[String], _props: &[(&str, &str)]) -> Result<(), Failures> {
+        Err(snaps.iter().map(|s| (s.clone(), unsupported())).collect())
+    }
+
+    pub(super) fn destroy(snaps: &[String]) -> Result<(), Failures> {
+        Err(snaps.iter().map(|s| (s.clone(), unsupported())).collect())
+    }
+
+    pub(super) fn hold(snaps: &[String], _tag: &str) -> Result<(), Failures> {
+        Err(snaps.iter().map(|s| (s.clone(), unsupported())).collect())
+    }
+
+    pub(super) fn release(snaps: &[String], _tag: &str) -> Result<(), Failures> {
+        Err(snaps.iter().map(|s| (s.clone(), unsupported())).collect())
+    }
+}
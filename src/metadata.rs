@@ -0,0 +1,212 @@
+//! The metadata file of remote replication - what each destination was last sent, so that the
+//! next remote_repl knows where to send from.
+//!
+//! Since version 2 a file holds the metadata of every destination it is used with, keyed by the
+//! identity of the destination: the guid of the pool it receives into, or its host when that can't
+//! be had. So one job can rotate between two off-site disks, or two remote hosts, and each carries
+//! on from its own precursor, its anchor kept by cleanup while the other is away.
+//!
+//! Metadata that no destination has claimed yet - that of remote_init_archive, which doesn't know
+//! where the archive will be loaded, or the one destination of a version 1 file - is taken by the
+//! first destination replicated with the file.
+
+use crate::anchors::{AnchorStore, Owner};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::Path;
+use tracing::{debug, error, info};
+
+pub(crate) const VERSION: u32 = 2;
+
+/// The metadata of one destination.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RemoteMetadata {
+    /// The snapshot the last -R stream was sent up to. Empty with --per-dataset.
+    #[serde(default)]
+    pub precursor_snap: String,
+    /// With --per-dataset, the snapshot each dataset was last sent up to.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub datasets: BTreeMap<String, String>,
+}
+
+impl RemoteMetadata {
+    fn is_empty(&self) -> bool {
+        self.precursor_snap.is_empty() && self.datasets.is_empty()
+    }
+
+    /// The pool that the -R stream is sent from.
+    pub(crate) fn source(&self) -> Option<&str> {
+        self.precursor_snap
+            .split('@')
+            .next()
+            .filter(|pool| !pool.is_empty())
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct MetadataFile {
+    #[serde(default)]
+    version: u32,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    destinations: BTreeMap<String, RemoteMetadata>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unclaimed: Option<RemoteMetadata>,
+    /// Version 1 - the metadata of the one destination, read into unclaimed.
+    #[serde(default, skip_serializing)]
+    precursor_snap: String,
+    #[serde(default, skip_serializing)]
+    datasets: BTreeMap<String, String>,
+}
+
+/// The owner of the anchors of a destination of the metadata file at `path` - of its -R stream,
+/// or of `dataset` with --per-dataset. Unclaimed metadata is anchored as `path` alone.
+pub(crate) fn owner(path: &str, identity: Option<&str>, dataset: Option<&str>) -> Owner {
+    let mut destination = path.to_string();
+    if let Some(identity) = identity {
+        destination = format!("{}#{}", destination, identity);
+    }
+    if let Some(dataset) = dataset {
+        destination = format!("{}:{}", destination, dataset);
+    }
+    Owner::new("remote_repl", &destination)
+}
+
+impl MetadataFile {
+    fn parse(s: &str) -> Result<Self, serde_json::Error> {
+        let mut file: MetadataFile = serde_json::from_str(s)?;
+        if file.version < VERSION {
+            let legacy = RemoteMetadata {
+                precursor_snap: std::mem::take(&mut file.precursor_snap),
+                datasets: std::mem::take(&mut file.datasets),
+            };
+            if !legacy.is_empty() {
+                file.unclaimed = Some(legacy);
+            }
+            file.version = VERSION;
+        }
+        Ok(file)
+    }
+
+    /// The metadata file at `path`, treating a missing file as empty.
+    pub(crate) fn load(path: &str) -> Result<Self, ()> {
+        match fs::read_to_string(path) {
+            Ok(s) => Self::parse(&s).map_err(|e| {
+                error!("Failed to parse metadata file {} -> {}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(MetadataFile {
+                version: VERSION,
+                ..Default::default()
+            }),
+            Err(e) => {
+                error!("Failed to open metadata file {} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    /// Write then rename, so that a crash can't leave a truncated file behind.
+    pub(crate) fn save(&self, path: &str) -> Result<(), ()> {
+        let tmp = Path::new(path).with_extension("json.tmp");
+        let f = File::create(&tmp).map_err(|e| {
+            error!("failed to open file -> {:?}", e);
+        })?;
+        serde_json::to_writer(&f, self).map_err(|e| {
+            error!("failed to write metadata file -> {:?}", e);
+        })?;
+        fs::rename(&tmp, path).map_err(|e| {
+            error!("failed to replace metadata file {:?} -> {:?}", path, e);
+        })
+    }
+
+    /// The metadata of every destination, and that which is unclaimed (as an empty identity).
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&str, &RemoteMetadata)> {
+        self.destinations
+            .iter()
+            .map(|(identity, meta)| (identity.as_str(), meta))
+            .chain(self.unclaimed.iter().map(|meta| ("", meta)))
+    }
+
+    /// The metadata of the destination `identity`, claiming the unclaimed metadata (and moving
+    /// its anchors) if it has none of its own. The claim is only kept once the file is saved.
+    pub(crate) fn claim(
+        &mut self,
+        path: &str,
+        identity: &str,
+        dry: bool,
+    ) -> Option<RemoteMetadata> {
+        if let Some(meta) = self.destinations.get(identity) {
+            return Some(meta.clone());
+        }
+        let meta = self.unclaimed.take()?;
+        info!("{} takes the unclaimed metadata of {}", identity, path);
+        if !dry {
+            if let Ok(mut anchors) = AnchorStore::load() {
+                let mut owners = vec![(None, meta.precursor_snap.clone())];
+                owners.extend(
+                    meta.datasets
+                        .iter()
+                        .map(|(dataset, snap)| (Some(dataset.as_str()), snap.clone())),
+                );
+                for (dataset, snap) in owners.into_iter().filter(|(_, s)| !s.is_empty()) {
+                    anchors.remove(&owner(path, None, dataset));
+                    anchors.set(&owner(path, Some(identity), dataset), &snap);
+                }
+                let _ = anchors.save(false);
+            }
+        }
+        self.destinations.insert(identity.to_string(), meta.clone());
+        debug!(?self.destinations);
+        Some(meta)
+    }
+
+    /// Record `meta` as that of `identity` - or, with none, as unclaimed.
+    pub(crate) fn set(&mut self, identity: Option<&str>, meta: RemoteMetadata) {
+        self.version = VERSION;
+        match identity {
+            Some(identity) => {
+                self.destinations.insert(identity.to_string(), meta);
+            }
+            None => self.unclaimed = Some(meta),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_1_files_are_claimed_by_their_first_destination() {
+        let mut file =
+            MetadataFile::parse(r#"{"precursor_snap":"nvme@auto_2024-05-01T030000Z"}"#).unwrap();
+        let (identity, meta) = file.entries().next().unwrap();
+        assert_eq!((identity, meta.source()), ("", Some("nvme")));
+
+        let meta = file.claim("/tmp/nvme.json", "8312345", true).unwrap();
+        assert_eq!(meta.precursor_snap, "nvme@auto_2024-05-01T030000Z");
+        // The other disk has none of its own, and nothing is left to claim.
+        assert!(file.claim("/tmp/nvme.json", "1122334", true).is_none());
+
+        file.set(
+            Some("1122334"),
+            RemoteMetadata {
+                precursor_snap: "nvme@auto_2024-05-08T030000Z".to_string(),
+                ..Default::default()
+            },
+        );
+        let saved = serde_json::to_string(&file).unwrap();
+        assert_eq!(
+            saved,
+            r#"{"version":2,"destinations":{"1122334":{"precursor_snap":"nvme@auto_2024-05-08T030000Z"},"8312345":{"precursor_snap":"nvme@auto_2024-05-01T030000Z"}}}"#
+        );
+        let mut file = MetadataFile::parse(&saved).unwrap();
+        assert_eq!(
+            file.claim("/tmp/nvme.json", "8312345", true)
+                .unwrap()
+                .precursor_snap,
+            "nvme@auto_2024-05-01T030000Z"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! Prometheus metrics, in the text exposition format.
+//!
+//! With `textfile` under `[metrics]` in `znapper.toml`, every run that changes something rewrites
+//! that file for the node_exporter textfile collector, so alerts can fire when a replication
+//! falls behind or keeps failing. `znapper metrics` prints the same to stdout.
+//!
+//! The metrics come from the run records of status, the progress checkpoints of each send (and
+//! of its proxy, if it has one), the snapshot counts of each source, and how often serve found a
+//! job still running when it was due.
+
+use crate::config::Config;
+use crate::status::RunStore;
+use crate::{auto_snap_list, progress, repl_snap_list, serve};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use tracing::{debug, error};
+
+/// Escape a label value - backslash, double quote and newline.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Lines of one metric: its help, its type, then a sample per (labels, value).
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, String)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+pub(crate) fn render() -> Result<String, ()> {
+    let config = Config::load()?;
+    let runs = RunStore::load()?;
+    let checkpoints = progress::checkpoints()?;
+
+    let mut out = String::new();
+
+    let run_labels: Vec<_> = runs
+        .runs
+        .iter()
+        .map(|run| {
+            format!(
+                "flow=\"{}\",source=\"{}\",destination=\"{}\"",
+                escape(&run.owner.flow),
+                escape(&run.source),
+                escape(&run.owner.destination)
+            )
+        })
+        .collect();
+    let samples = |f: &dyn Fn(&crate::status::Run) -> Option<String>| -> Vec<(String, String)> {
+        runs.runs
+            .iter()
+            .zip(run_labels.iter())
+            .filter_map(|(run, labels)| f(run).map(|v| (labels.clone(), v)))
+            .collect()
+    };
+    metric(
+        &mut out,
+        "znapper_last_run_timestamp_seconds",
+        "gauge",
+        "When the replication last ran.",
+        &samples(&|run| Some(run.last_run.to_string())),
+    );
+    metric(
+        &mut out,
+        "znapper_last_success_timestamp_seconds",
+        "gauge",
+        "When the replication last succeeded.",
+        &samples(&|run| run.last_success.map(|ts| ts.to_string())),
+    );
+    metric(
+        &mut out,
+        "znapper_last_run_success",
+        "gauge",
+        "Whether the last run of the replication succeeded.",
+        &samples(&|run| Some(u8::from(run.succeeded).to_string())),
+    );
+    metric(
+        &mut out,
+        "znapper_failures_total",
+        "counter",
+        "Failed runs of the replication.",
+        &samples(&|run| Some(run.failures.to_string())),
+    );
+
+    let send_samples = |f: &dyn Fn(&progress::Checkpoint) -> String| -> Vec<(String, String)> {
+        checkpoints
+            .iter()
+            .map(|c| (format!("send=\"{}\"", escape(&c.label)), f(c)))
+            .collect()
+    };
+    metric(
+        &mut out,
+        "znapper_send_bytes",
+        "gauge",
+        "Bytes sent by the latest send.",
+        &send_samples(&|c| c.bytes_sent.to_string()),
+    );
+    metric(
+        &mut out,
+        "znapper_send_duration_seconds",
+        "gauge",
+        "How long the latest send ran for.",
+        &send_samples(&|c| (c.updated - c.started).to_string()),
+    );
+    let proxied_samples = |f: &dyn Fn(&progress::Proxied) -> u64| -> Vec<(String, String)> {
+        checkpoints
+            .iter()
+            .filter_map(|c| {
+                c.proxied
+                    .as_ref()
+                    .map(|p| (format!("send=\"{}\"", escape(&c.label)), f(p).to_string()))
+            })
+            .collect()
+    };
+    metric(
+        &mut out,
+        "znapper_send_proxied_bytes",
+        "gauge",
+        "Bytes znapper passed on to the receiver of the latest proxied send.",
+        &proxied_samples(&|p| p.bytes),
+    );
+    metric(
+        &mut out,
+        "znapper_send_proxied_bytes_per_second",
+        "gauge",
+        "Throughput of the latest proxied send, as of its last checkpoint.",
+        &proxied_samples(&|p| p.bytes_per_sec),
+    );
+    metric(
+        &mut out,
+        "znapper_send_success",
+        "gauge",
+        "Whether the latest send completed, 0 while running or after a failure.",
+        &send_samples(&|c| u8::from(c.status == "complete").to_string()),
+    );
+
+    let mut sources: Vec<&str> = config.job.values().map(|job| job.source.as_str()).collect();
+    sources.extend(runs.runs.iter().map(|run| run.source.as_str()));
+    sources.retain(|s| !s.is_empty());
+    sources.sort_unstable();
+    sources.dedup();
+    let mut counts = Vec::new();
+    for source in sources {
+        let labels = |class: &str| format!("dataset=\"{}\",class=\"{}\"", escape(source), class);
+        if let Ok(snaps) = auto_snap_list(source) {
+            counts.push((labels("auto"), snaps.len().to_string()));
+        }
+        if let Ok(snaps) = repl_snap_list(source) {
+            counts.push((labels("repl"), snaps.len().to_string()));
+        }
+    }
+    metric(
+        &mut out,
+        "znapper_snapshots",
+        "gauge",
+        "Snapshots of the source and its descendants, by class.",
+        &counts,
+    );
+
+    let overlaps = serve::overlaps();
+    let overlap_samples = |f: &dyn Fn(&serve::Overlaps) -> u64| -> Vec<(String, String)> {
+        overlaps
+            .iter()
+            .map(|(job, o)| (format!("job=\"{}\"", escape(job)), f(o).to_string()))
+            .collect()
+    };
+    metric(
+        &mut out,
+        "znapper_runs_skipped_total",
+        "counter",
+        "Times serve skipped a job that was due while a run of it was still going.",
+        &overlap_samples(&|o| o.skipped),
+    );
+    metric(
+        &mut out,
+        "znapper_runs_queued_total",
+        "counter",
+        "Times serve queued a job that was due while a run of it was still going.",
+        &overlap_samples(&|o| o.queued),
+    );
+    metric(
+        &mut out,
+        "znapper_runs_restarted_total",
+        "counter",
+        "Times serve restarted a job that was due while a run of it was still going.",
+        &overlap_samples(&|o| o.restarted),
+    );
+
+    Ok(out)
+}
+
+/// Rewrite the textfile, if one is configured. The file is replaced in one go, so the collector
+/// never reads half of it.
+pub(crate) fn write() {
+    let path = match Config::load().ok().and_then(|c| c.metrics.textfile) {
+        Some(path) => path,
+        None => return,
+    };
+    let out = match render() {
+        Ok(out) => out,
+        Err(_) => return,
+    };
+    let path = Path::new(&path);
+    let tmp = path.with_extension("prom.tmp");
+    let res = fs::write(&tmp, out).and_then(|_| fs::rename(&tmp, path));
+    match res {
+        Ok(()) => debug!("wrote metrics -> {:?}", path),
+        Err(e) => error!("Failed to write metrics {:?} -> {:?}", path, e),
+    }
+}
+
+pub(crate) fn do_metrics() {
+    if let Ok(out) = render() {
+        print!("{}", out);
+    }
+}
@@ -0,0 +1,434 @@
+//! Datasets and snapshots, parsed from their names and from what `zfs list` says of them.
+//!
+//! Comparing names as strings is easy to get wrong - `tank/nvme@repl_1` ends with `nvme@repl_1`,
+//! but so does `tank/xnvme@repl_1`. So snapshots are parsed once into their dataset, name, class
+//! and time, and compared by those.
+
+use crate::naming;
+use std::fmt;
+use time::OffsetDateTime;
+
+/// Why a name could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    name: String,
+    reason: &'static str,
+}
+
+impl ParseError {
+    fn new(name: &str, reason: &'static str) -> Self {
+        ParseError {
+            name: name.to_string(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is not valid - {}", self.name, self.reason)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A filesystem or volume, ie `nvme/home`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dataset {
+    name: String,
+}
+
+impl Dataset {
+    /// Parse a dataset name - a pool, then any children, separated by `/`.
+    pub fn parse(name: &str) -> Result<Self, ParseError> {
+        if name.is_empty() {
+            return Err(ParseError::new(name, "a dataset needs a name"));
+        }
+        if name.contains(['@', '#']) {
+            return Err(ParseError::new(name, "a dataset can not contain @ or #"));
+        }
+        if name.split('/').any(str::is_empty) {
+            return Err(ParseError::new(
+                name,
+                "a dataset can not have an empty component",
+            ));
+        }
+        if name.chars().any(char::is_whitespace) {
+            return Err(ParseError::new(
+                name,
+                "a dataset can not contain whitespace",
+            ));
+        }
+        Ok(Dataset {
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The pool the dataset is on.
+    pub fn pool(&self) -> &str {
+        self.name.split('/').next().unwrap_or(&self.name)
+    }
+
+    /// The parent dataset, if it is not the root of its pool.
+    pub fn parent(&self) -> Option<Dataset> {
+        self.name.rsplit_once('/').map(|(parent, _)| Dataset {
+            name: parent.to_string(),
+        })
+    }
+
+    /// How far below the root of its pool the dataset is - 0 for the pool.
+    pub fn depth(&self) -> usize {
+        self.name.matches('/').count()
+    }
+
+    /// The path of `self` under `root` - `""` for `root` itself, `"/home"` for `root/home`, and
+    /// None if it is not under `root`.
+    pub fn relative_to(&self, root: &Dataset) -> Option<&str> {
+        let rest = self.name.strip_prefix(root.name.as_str())?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    /// Is this `root`, or a descendant of it?
+    pub fn is_within(&self, root: &Dataset) -> bool {
+        self.relative_to(root).is_some()
+    }
+
+    /// Where `self`, under `from`, is once `from` is replicated to `to` - `nvme/home` from `nvme`
+    /// to `tank/nvme` is `tank/nvme/home`.
+    pub fn rebase(&self, from: &Dataset, to: &Dataset) -> Option<Dataset> {
+        self.relative_to(from).map(|rest| Dataset {
+            name: format!("{}{}", to.name, rest),
+        })
+    }
+}
+
+impl fmt::Display for Dataset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Which of znapper's snapshots - by the prefix of its name - a snapshot is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+    /// `auto_<time>`, taken by `znapper snapshot` and pruned by retention.
+    Auto,
+    /// `repl_<time>`, the anchor of a replication.
+    Repl,
+    /// `redact_<time>`, a redaction bookmark.
+    Redact,
+    /// Any other snapshot, which znapper leaves alone.
+    Other,
+}
+
+impl Class {
+    fn of(short_name: &str) -> Self {
+        if short_name.starts_with("auto_") {
+            Class::Auto
+        } else if short_name.starts_with("repl_") {
+            Class::Repl
+        } else if short_name.starts_with("redact_") {
+            Class::Redact
+        } else {
+            Class::Other
+        }
+    }
+
+    /// The prefix of the snapshots of the class.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Class::Auto => "auto_",
+            Class::Repl => "repl_",
+            Class::Redact => "redact_",
+            Class::Other => "",
+        }
+    }
+}
+
+/// A snapshot (`nvme/home@auto_2024_01_01_00_00_00`) or bookmark (`nvme#repl_...`).
+///
+/// Snapshots order, and compare, by their name and then what was listed of them.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Snapshot {
+    name: String,
+    /// Where the `@` or `#` is in `name`.
+    at: usize,
+    guid: Option<String>,
+    createtxg: Option<u64>,
+}
+
+impl Snapshot {
+    /// Parse a snapshot name, `<dataset>@<snapshot>`, or the name of a bookmark,
+    /// `<dataset>#<bookmark>`.
+    pub fn parse(name: &str) -> Result<Self, ParseError> {
+        let at = match name.find(['@', '#']) {
+            Some(at) => at,
+            None => return Err(ParseError::new(name, "a snapshot needs an @ or #")),
+        };
+        Dataset::parse(&name[..at])?;
+        let short = &name[at + 1..];
+        if short.is_empty() {
+            return Err(ParseError::new(name, "a snapshot needs a name"));
+        }
+        if short.contains(['@', '#', '/']) || short.chars().any(char::is_whitespace) {
+            return Err(ParseError::new(
+                name,
+                "a snapshot name can not contain @, #, / or whitespace",
+            ));
+        }
+        Ok(Snapshot {
+            name: name.to_string(),
+            at,
+            guid: None,
+            createtxg: None,
+        })
+    }
+
+    /// Parse a line of `zfs list -H -p -o name,guid,createtxg`. The guid and createtxg are
+    /// optional, as is `-` for either.
+    pub fn parse_listed(line: &str) -> Result<Self, ParseError> {
+        let mut columns = line.split('\t');
+        let mut snap = Snapshot::parse(columns.next().unwrap_or(line).trim())?;
+        snap.guid = columns
+            .next()
+            .map(str::trim)
+            .filter(|g| !g.is_empty() && *g != "-")
+            .map(str::to_string);
+        snap.createtxg = match columns.next().map(str::trim) {
+            None | Some("") | Some("-") => None,
+            Some(txg) => Some(
+                txg.parse()
+                    .map_err(|_| ParseError::new(line, "the createtxg is not a number"))?,
+            ),
+        };
+        Ok(snap)
+    }
+
+    /// The full name, `<dataset>@<snapshot>`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The name of the dataset it is a snapshot (or bookmark) of.
+    pub fn dataset_name(&self) -> &str {
+        &self.name[..self.at]
+    }
+
+    /// The dataset it is a snapshot (or bookmark) of.
+    pub fn dataset(&self) -> Dataset {
+        Dataset {
+            name: self.dataset_name().to_string(),
+        }
+    }
+
+    /// The name after the `@`, or the `#` of a bookmark.
+    pub fn short_name(&self) -> &str {
+        &self.name[self.at + 1..]
+    }
+
+    pub fn is_bookmark(&self) -> bool {
+        self.name[self.at..].starts_with('#')
+    }
+
+    pub fn class(&self) -> Class {
+        Class::of(self.short_name())
+    }
+
+    /// Is this one of the auto_ snapshots that `snapshot` takes, and retention prunes?
+    pub fn is_auto(&self) -> bool {
+        self.class() == Class::Auto
+    }
+
+    /// Is this a repl_ snapshot, the anchor of a replication?
+    pub fn is_repl(&self) -> bool {
+        self.class() == Class::Repl
+    }
+
+    /// The time in the name of an auto_, repl_ or redact_ snapshot, in UTC or local time as the
+    /// name has it.
+    pub fn timestamp(&self) -> Option<OffsetDateTime> {
+        let ts = self.short_name().strip_prefix(self.class().prefix())?;
+        match self.class() {
+            Class::Other => None,
+            _ => naming::parse(ts),
+        }
+    }
+
+    /// The guid, when it was listed with one. Snapshots with the same guid are the same snapshot,
+    /// on whichever pool they are.
+    pub fn guid(&self) -> Option<&str> {
+        self.guid.as_deref()
+    }
+
+    /// The transaction group the snapshot was created in, when it was listed with it.
+    pub fn createtxg(&self) -> Option<u64> {
+        self.createtxg
+    }
+
+    /// Is `other`, on another dataset, the same snapshot - the same name and, if both guids are
+    /// known, the same guid?
+    pub fn same_as(&self, other: &Snapshot) -> bool {
+        self.short_name() == other.short_name()
+            && match (self.guid(), other.guid()) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Parse the output of `zfs list -H -p -o name[,guid[,createtxg]]`, ignoring blank lines.
+pub(crate) fn parse_list(stdout: &str) -> Result<Vec<Snapshot>, ParseError> {
+    stdout
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(Snapshot::parse_listed)
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use time::UtcOffset;
+
+    fn snap(name: &str) -> Snapshot {
+        Snapshot::parse(name).unwrap()
+    }
+
+    fn dataset(name: &str) -> Dataset {
+        Dataset::parse(name).unwrap()
+    }
+
+    #[test]
+    fn parse_dataset() {
+        let home = dataset("nvme/home/william");
+        assert_eq!(home.pool(), "nvme");
+        assert_eq!(home.depth(), 2);
+        assert_eq!(home.parent(), Some(dataset("nvme/home")));
+        assert_eq!(dataset("nvme").parent(), None);
+
+        for bad in [
+            "",
+            "nvme/",
+            "/nvme",
+            "nvme//home",
+            "nvme@snap",
+            "nvme#b",
+            "nv me",
+        ] {
+            assert!(Dataset::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn dataset_relative_to() {
+        let nvme = dataset("nvme");
+        assert_eq!(dataset("nvme").relative_to(&nvme), Some(""));
+        assert_eq!(dataset("nvme/home").relative_to(&nvme), Some("/home"));
+        assert_eq!(dataset("nvmex").relative_to(&nvme), None);
+        assert_eq!(dataset("tank/nvme").relative_to(&nvme), None);
+        assert!(!dataset("nvmex/home").is_within(&nvme));
+    }
+
+    #[test]
+    fn dataset_rebase() {
+        let (nvme, tank) = (dataset("nvme"), dataset("tank/nvme"));
+        assert_eq!(
+            dataset("nvme/home").rebase(&nvme, &tank),
+            Some(dataset("tank/nvme/home"))
+        );
+        assert_eq!(dataset("nvme").rebase(&nvme, &tank), Some(tank.clone()));
+        assert_eq!(dataset("other/home").rebase(&nvme, &tank), None);
+    }
+
+    #[test]
+    fn parse_snapshot() {
+        let s = snap("nvme/home@auto_2024_01_02_03_04_05");
+        assert_eq!(s.dataset_name(), "nvme/home");
+        assert_eq!(s.dataset(), dataset("nvme/home"));
+        assert_eq!(s.short_name(), "auto_2024_01_02_03_04_05");
+        assert_eq!(s.class(), Class::Auto);
+        assert!(s.is_auto() && !s.is_repl() && !s.is_bookmark());
+        assert_eq!(s.to_string(), "nvme/home@auto_2024_01_02_03_04_05");
+
+        let b = snap("nvme#repl_2024_01_02_03_04_05");
+        assert!(b.is_bookmark() && b.is_repl());
+        assert_eq!(b.dataset_name(), "nvme");
+
+        assert_eq!(
+            snap("nvme@redact_2024_01_02_03_04_05").class(),
+            Class::Redact
+        );
+        assert_eq!(snap("nvme@manual").class(), Class::Other);
+
+        for bad in [
+            "nvme", "nvme@", "@auto_1", "nvme@a@b", "nvme@a/b", "nvme/@a",
+        ] {
+            assert!(Snapshot::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn snapshot_timestamp() {
+        let ts = snap("nvme@auto_2024-01-02T030405Z").timestamp().unwrap();
+        assert_eq!(ts.offset(), UtcOffset::UTC);
+        assert_eq!(ts.format(naming::UTC_FORMAT), "2024-01-02T030405Z");
+        // Named in local time by older znappers.
+        let ts = snap("nvme@auto_2024_01_02_03_04_05").timestamp().unwrap();
+        assert_eq!(
+            ts.to_offset(UtcOffset::local_offset_at(ts))
+                .format(naming::LOCAL_FORMAT),
+            "2024_01_02_03_04_05"
+        );
+        assert!(
+            snap("nvme@repl_2024-01-02T030405Z").timestamp()
+                < snap("nvme@repl_2024-01-02T030406Z").timestamp()
+        );
+        assert!(snap("nvme#repl_2024_01_02_03_04_05").timestamp().is_some());
+        assert_eq!(snap("nvme@auto_yesterday").timestamp(), None);
+        assert_eq!(snap("nvme@2024_01_02_03_04_05").timestamp(), None);
+        assert!(
+            snap("nvme@auto_2024_01_02_03_04_05").timestamp()
+                < snap("nvme@auto_2024_11_02_03_04_05").timestamp()
+        );
+    }
+
+    #[test]
+    fn parse_listed_snapshots() {
+        let snaps =
+            parse_list("nvme@repl_1\t1234\t56\n\nnvme/home@repl_1\t-\t-\nnvme@auto_1\n").unwrap();
+        assert_eq!(snaps.len(), 3);
+        assert_eq!(snaps[0].guid(), Some("1234"));
+        assert_eq!(snaps[0].createtxg(), Some(56));
+        assert_eq!(snaps[1].guid(), None);
+        assert_eq!(snaps[1].createtxg(), None);
+        assert_eq!(snaps[2].name(), "nvme@auto_1");
+
+        assert!(parse_list("nvme@repl_1\t1234\tsoon\n").is_err());
+        assert!(parse_list("not a snapshot\n").is_err());
+    }
+
+    #[test]
+    fn same_snapshot() {
+        let listed = |line: &str| Snapshot::parse_listed(line).unwrap();
+        let src = listed("nvme@repl_1\t1234");
+        assert!(src.same_as(&listed("tank/nvme@repl_1\t1234")));
+        assert!(src.same_as(&snap("tank/nvme@repl_1")));
+        assert!(!src.same_as(&listed("tank/nvme@repl_1\t999")));
+        assert!(!src.same_as(&snap("tank/nvme@repl_2")));
+    }
+}
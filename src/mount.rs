@@ -0,0 +1,272 @@
+//! `znapper mount` and `znapper unmount` - expose a snapshot read-only at a path, to copy files
+//! out of, without touching the dataset or the readonly and mountpoint of a replica.
+//!
+//! The snapshot of a mounted dataset is bind mounted from its `.zfs/snapshot` directory, which
+//! costs nothing. That of an unmounted one - such as a replica received with mountpoint=none - is
+//! cloned read-only at the path instead, and unmount destroys the clone. `mounts.json` in the
+//! state directory records which each mount is.
+
+use crate::anchors::state_dir;
+use crate::process::{Kind, Timed};
+use crate::{dataset_exists, get_property, privilege, short_name, AUTO_SNAPSHOT_PROPERTY};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::process::Command;
+use structopt::StructOpt;
+use tracing::{debug, error, info};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct MountOpt {
+    /// The snapshot to mount, ie tank/data@auto_2024-05-01T030000Z
+    pub snapshot: String,
+    /// Where to mount it, ie /mnt/recover
+    pub path: String,
+    /// Clone the snapshot even if its dataset is mounted, rather than bind mount .zfs/snapshot.
+    #[structopt(long = "clone")]
+    pub clone: bool,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct UnmountOpt {
+    /// The path a snapshot was mounted at with znapper mount.
+    path: String,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Mount {
+    path: String,
+    snapshot: String,
+    /// The clone mounted at the path, or none for a bind mount.
+    #[serde(default)]
+    clone: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MountStore {
+    mounts: Vec<Mount>,
+}
+
+impl MountStore {
+    fn path() -> PathBuf {
+        state_dir().join("mounts.json")
+    }
+
+    fn load() -> Result<Self, ()> {
+        let path = Self::path();
+        match File::open(&path) {
+            Ok(f) => serde_json::from_reader(f).map_err(|e| {
+                error!("Failed to parse {:?} -> {:?}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(MountStore::default()),
+            Err(e) => {
+                error!("Failed to open {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), ()> {
+        let path = Self::path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                error!("Failed to create state dir {:?} -> {:?}", parent, e);
+            })?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        let f = File::create(&tmp).map_err(|e| {
+            error!("Failed to create {:?} -> {:?}", tmp, e);
+        })?;
+        serde_json::to_writer_pretty(&f, self).map_err(|e| {
+            error!("Failed to write {:?} -> {:?}", tmp, e);
+        })?;
+        fs::rename(&tmp, &path).map_err(|e| {
+            error!("Failed to replace {:?} -> {:?}", path, e);
+        })
+    }
+}
+
+/// `path` without a trailing slash, so the same path is always recorded the same way.
+fn normalise(path: &str) -> String {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        "/".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// The clone for a mount at `path` - under the pool root, named for the path.
+fn clone_name(snapshot: &str, path: &str) -> String {
+    let pool = snapshot.split(['/', '@']).next().unwrap_or(snapshot);
+    let slug: String = path
+        .trim_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}/znapper_mount_{}", pool, slug)
+}
+
+fn run(dry: bool, mut cmd: Command, description: &str) -> Result<(), ()> {
+    if dry {
+        info!("dryrun: {}", description);
+        return Ok(());
+    }
+    info!("{}", description);
+    let output = cmd.run_output(Kind::Zfs).map_err(|e| {
+        error!("{} failed -> {:?}", description, e);
+    })?;
+    debug!(?output.status);
+    if output.status.success() {
+        Ok(())
+    } else {
+        error!(
+            "{} failed -> {}",
+            description,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Err(())
+    }
+}
+
+pub(crate) fn do_mount(opt: &MountOpt) {
+    debug!("do_mount");
+    let dataset = match opt.snapshot.split_once('@') {
+        Some((dataset, _)) => dataset,
+        None => {
+            error!("{} is not a snapshot", opt.snapshot);
+            return;
+        }
+    };
+    if !dataset_exists(&opt.snapshot) {
+        error!("{} does not exist", opt.snapshot);
+        return;
+    }
+    let path = normalise(&opt.path);
+    let mut store = match MountStore::load() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    if let Some(mount) = store.mounts.iter().find(|m| m.path == path) {
+        error!(
+            "{} is already mounted at {} - znapper unmount {} first",
+            mount.snapshot, path, path
+        );
+        return;
+    }
+
+    // A snapshot of a mounted filesystem is already there to bind.
+    let mounted = match get_property(dataset, "mounted") {
+        Ok(mounted) => mounted == "yes",
+        Err(_) => return,
+    };
+    let mount = if mounted && !opt.clone {
+        let mountpoint = match get_property(dataset, "mountpoint") {
+            Ok(m) => m,
+            Err(_) => return,
+        };
+        let source = format!(
+            "{}/.zfs/snapshot/{}",
+            mountpoint.trim_end_matches('/'),
+            short_name(&opt.snapshot)
+        );
+        if !opt.dryrun {
+            if let Err(e) = fs::create_dir_all(&path) {
+                error!("Failed to create {} -> {:?}", path, e);
+                return;
+            }
+        }
+        let mut cmd = privilege::escalated("mount");
+        cmd.arg("-o").arg("bind,ro").arg(&source).arg(&path);
+        let description = format!("mount -o bind,ro {} {}", source, path);
+        if run(opt.dryrun, cmd, &description).is_err() {
+            return;
+        }
+        Mount {
+            path: path.clone(),
+            snapshot: opt.snapshot.clone(),
+            clone: None,
+        }
+    } else {
+        let clone = clone_name(&opt.snapshot, &path);
+        // Left out of snapshots, so it isn't snapshotted (or replicated) for as long as it's up.
+        let mut cmd = privilege::zfs_escalated();
+        cmd.arg("clone")
+            .arg("-o")
+            .arg("readonly=on")
+            .arg("-o")
+            .arg(format!("mountpoint={}", path))
+            .arg("-o")
+            .arg(format!("{}=false", AUTO_SNAPSHOT_PROPERTY))
+            .arg(&opt.snapshot)
+            .arg(&clone);
+        let description = format!(
+            "zfs clone -o readonly=on -o mountpoint={} {} {}",
+            path, opt.snapshot, clone
+        );
+        if run(opt.dryrun, cmd, &description).is_err() {
+            return;
+        }
+        Mount {
+            path: path.clone(),
+            snapshot: opt.snapshot.clone(),
+            clone: Some(clone),
+        }
+    };
+
+    if opt.dryrun {
+        return;
+    }
+    store.mounts.push(mount);
+    if store.save().is_err() {
+        error!(
+            "{} is mounted at {}, but could not be recorded for znapper unmount",
+            opt.snapshot, path
+        );
+        return;
+    }
+    info!(
+        "{} is mounted read-only at {} - znapper unmount {} when done",
+        opt.snapshot, path, path
+    );
+}
+
+pub(crate) fn do_unmount(opt: &UnmountOpt) {
+    debug!("do_unmount");
+    let path = normalise(&opt.path);
+    let mut store = match MountStore::load() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let i = match store.mounts.iter().position(|m| m.path == path) {
+        Some(i) => i,
+        None => {
+            error!("Nothing was mounted at {} by znapper mount", path);
+            return;
+        }
+    };
+
+    let res = match store.mounts[i].clone {
+        Some(ref clone) => {
+            let mut cmd = privilege::zfs_escalated();
+            cmd.arg("destroy").arg(clone);
+            run(opt.dryrun, cmd, &format!("zfs destroy {}", clone))
+        }
+        None => {
+            let mut cmd = privilege::escalated("umount");
+            cmd.arg(&path);
+            run(opt.dryrun, cmd, &format!("umount {}", path))
+        }
+    };
+    if res.is_err() || opt.dryrun {
+        return;
+    }
+    let mount = store.mounts.remove(i);
+    if store.save().is_ok() {
+        info!("Unmounted {} from {}", mount.snapshot, path);
+    }
+}
@@ -0,0 +1,133 @@
+//! The time in the names of the auto_ and repl_ snapshots.
+//!
+//! New snapshots are named in UTC by default, as `auto_2024-05-01T030000Z`, so names sort in the
+//! order they were taken even across a DST change. `[naming]` in `znapper.toml` (or `--utc`,
+//! `--local-time` and `--name-template`) can name them in local time, or by another template.
+//! Whichever names new snapshots, the times of those named by the default, by the configured
+//! template, or in the local `2024_05_01_03_00_00` of older znappers are all understood, so
+//! cleanup and repl still recognise existing snapshots.
+
+use crate::config::Config;
+use std::sync::OnceLock;
+use structopt::StructOpt;
+use time::{Date, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+use tracing::error;
+
+/// ISO 8601 in UTC, the default.
+pub(crate) const UTC_FORMAT: &str = "%Y-%m-%dT%H%M%SZ";
+
+/// Local time, as snapshots were named before UTC.
+pub(crate) const LOCAL_FORMAT: &str = "%Y_%m_%d_%H_%M_%S";
+
+static NAMING: OnceLock<Naming> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub(crate) struct NamingOpt {
+    /// Name new snapshots in UTC, the default unless [naming] in znapper.toml says otherwise.
+    #[structopt(long = "utc", conflicts_with = "local-time")]
+    pub utc: bool,
+    /// Name new snapshots in local time.
+    #[structopt(long = "local-time")]
+    pub local_time: bool,
+    /// The time in the names of new snapshots, as a strftime template, ie %Y-%m-%dT%H%M%SZ.
+    #[structopt(long = "name-template")]
+    pub name_template: Option<String>,
+}
+
+#[derive(Debug)]
+struct Naming {
+    template: String,
+    utc: bool,
+}
+
+/// Name by `opt`, and then `[naming]`, rather than by `[naming]` alone.
+pub(crate) fn init(opt: &NamingOpt) {
+    let _ = NAMING.set(resolve(opt));
+}
+
+fn naming() -> &'static Naming {
+    NAMING.get_or_init(|| resolve(&NamingOpt::default()))
+}
+
+fn resolve(opt: &NamingOpt) -> Naming {
+    let config = Config::load().map(|c| c.naming).unwrap_or_default();
+    let utc = if opt.utc {
+        true
+    } else if opt.local_time {
+        false
+    } else {
+        config.utc.unwrap_or(true)
+    };
+    let default = if utc { UTC_FORMAT } else { LOCAL_FORMAT };
+    let template = opt
+        .name_template
+        .clone()
+        .or(config.template)
+        .unwrap_or_else(|| default.to_string());
+    match check(&template) {
+        Ok(()) => Naming { template, utc },
+        Err(reason) => {
+            error!(
+                "Invalid snapshot name template {} - {}, using {}",
+                template, reason, default
+            );
+            Naming {
+                template: default.to_string(),
+                utc,
+            }
+        }
+    }
+}
+
+/// A template must make a valid snapshot name, that can be parsed back to the same time.
+fn check(template: &str) -> Result<(), &'static str> {
+    let sample = Date::try_from_ymd(2001, 2, 3)
+        .and_then(|date| Ok(date.with_time(Time::try_from_hms(4, 5, 6)?)))
+        .map_err(|_| "unable to test it")?;
+    let formatted = sample.assume_utc().format(template);
+    if formatted.is_empty()
+        || !formatted
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c))
+    {
+        return Err("it has characters a snapshot name can't");
+    }
+    match PrimitiveDateTime::parse(&formatted, template) {
+        Ok(parsed) if parsed == sample => Ok(()),
+        _ => Err("it must have the year, month, day, hour, minute and second"),
+    }
+}
+
+/// The time for the name of a snapshot taken now.
+pub(crate) fn now() -> Result<String, ()> {
+    let naming = naming();
+    let now = if naming.utc {
+        OffsetDateTime::now_utc()
+    } else {
+        OffsetDateTime::try_now_local().map_err(|_| {
+            error!("Unable to determine time");
+        })?
+    };
+    Ok(now.format(naming.template.as_str()))
+}
+
+/// The time `ts`, the part of a snapshot name after its prefix, stands for.
+pub(crate) fn parse(ts: &str) -> Option<OffsetDateTime> {
+    let naming = naming();
+    [
+        (naming.template.as_str(), naming.utc),
+        (UTC_FORMAT, true),
+        (LOCAL_FORMAT, false),
+    ]
+    .iter()
+    .find_map(|&(template, utc)| {
+        let t = PrimitiveDateTime::parse(ts, template).ok()?;
+        Some(if utc { t.assume_utc() } else { assume_local(t) })
+    })
+}
+
+/// `t` as a local time, with the offset local time had then.
+pub(crate) fn assume_local(t: PrimitiveDateTime) -> OffsetDateTime {
+    let offset = UtcOffset::try_local_offset_at(t.assume_utc()).unwrap_or(UtcOffset::UTC);
+    t.assume_offset(offset)
+}
@@ -0,0 +1,144 @@
+//! Telling someone how a sync job went, as `[job.<name>.notify]` in `znapper.toml` asks.
+//!
+//! A healthchecks.io check is pinged when the job starts, and when it succeeds (or at /fail when
+//! it fails), so that a job that stops running at all is noticed too. A webhook is sent a json
+//! summary of the run, and a Slack incoming webhook a one line message.
+//!
+//! Requests are made with curl, with a short timeout, and a request that fails is only logged -
+//! the job itself is already over.
+
+use crate::config::Notify;
+use serde::Serialize;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use tracing::{debug, error, info};
+
+/// Give up on a notification after this many seconds.
+const TIMEOUT_SECS: &str = "10";
+
+/// The outcome of one stage of a job.
+#[derive(Debug, Serialize)]
+pub(crate) struct Stage {
+    pub stage: String,
+    pub result: String,
+}
+
+/// What the webhook is sent when a job finishes.
+#[derive(Debug, Serialize)]
+pub(crate) struct Report {
+    pub job: String,
+    pub action: &'static str,
+    /// ok or failed.
+    pub result: &'static str,
+    /// Bytes sent by every send of the run.
+    pub bytes: u64,
+    pub duration_seconds: i64,
+    pub stages: Vec<Stage>,
+    /// The errors logged during the run, with what the zfs and ssh commands that failed said.
+    pub errors: Vec<String>,
+}
+
+/// Request `url`, POSTing `body` as json if there is one.
+fn request(dry: bool, url: &str, body: Option<&str>) {
+    if dry {
+        info!("dryrun: notify -> {}", url);
+        return;
+    }
+    debug!("notify -> {}", url);
+    let mut cmd = Command::new("curl");
+    cmd.args([
+        "-fsS",
+        "-m",
+        TIMEOUT_SECS,
+        "--retry",
+        "3",
+        "-o",
+        "/dev/null",
+    ])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::null());
+    if body.is_some() {
+        cmd.args([
+            "-H",
+            "Content-Type: application/json",
+            "--data-binary",
+            "@-",
+        ]);
+    }
+    let mut child = match cmd.arg(url).spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Unable to run curl to notify {} -> {:?}", url, e);
+            return;
+        }
+    };
+    if let (Some(body), Some(mut stdin)) = (body, child.stdin.take()) {
+        if let Err(e) = stdin.write_all(body.as_bytes()) {
+            error!("Failed to send the notification to {} -> {:?}", url, e);
+        }
+    }
+    match child.wait() {
+        Ok(status) if status.success() => {}
+        Ok(status) => error!("Notifying {} failed -> {:?}", url, status.code()),
+        Err(e) => error!("Notifying {} failed -> {:?}", url, e),
+    }
+}
+
+/// The job has started.
+pub(crate) fn start(dry: bool, notify: &Notify) {
+    if let Some(url) = notify.healthcheck.as_deref() {
+        request(dry, &format!("{}/start", url.trim_end_matches('/')), None);
+    }
+}
+
+/// The job has finished, as `report` describes.
+pub(crate) fn finish(dry: bool, notify: &Notify, report: &Report) {
+    let ok = report.result == "ok";
+    if let Some(url) = notify.healthcheck.as_deref() {
+        let url = url.trim_end_matches('/');
+        if ok {
+            request(dry, url, None);
+        } else {
+            request(dry, &format!("{}/fail", url), None);
+        }
+    }
+
+    if ok && notify.only_failures {
+        return;
+    }
+    if let Some(url) = notify.webhook.as_deref() {
+        match serde_json::to_string(report) {
+            Ok(body) => request(dry, url, Some(&body)),
+            Err(e) => error!("failed to serialise notification -> {:?}", e),
+        }
+    }
+    if let Some(url) = notify.slack.as_deref() {
+        let failed: Vec<_> = report
+            .stages
+            .iter()
+            .filter(|s| s.result != "ok")
+            .map(|s| format!("{} {}", s.stage, s.result))
+            .collect();
+        let text = if ok {
+            format!(
+                "znapper {} {} ok - {} bytes in {}s",
+                report.action, report.job, report.bytes, report.duration_seconds
+            )
+        } else {
+            let mut text = format!(
+                "znapper {} {} FAILED - {}",
+                report.action,
+                report.job,
+                failed.join(", ")
+            );
+            if let Some(e) = report.errors.first() {
+                text.push_str(&format!("\n{}", e));
+            }
+            text
+        };
+        match serde_json::to_string(&serde_json::json!({ "text": text })) {
+            Ok(body) => request(dry, url, Some(&body)),
+            Err(e) => error!("failed to serialise notification -> {:?}", e),
+        }
+    }
+}
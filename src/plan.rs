@@ -0,0 +1,169 @@
+//! The plan of a dry run, as json.
+//!
+//! A dry run normally only logs each command it would run. With `--plan-format json` the same
+//! steps are also collected here as they are decided, and printed as one document once the run
+//! is over - the snapshots and bookmarks it would create and destroy, the datasets it would
+//! change, and the streams it would send, with their anchors and estimated sizes, on stdout. The
+//! snapshots an immutability window keeps from a destroy are listed with when it ends.
+
+use crate::summary;
+use serde::Serialize;
+use std::sync::Mutex;
+use tracing::error;
+
+/// The plan being collected, if this run is collecting one.
+static PLAN: Mutex<Option<Plan>> = Mutex::new(None);
+
+/// A snapshot to create.
+#[derive(Debug, Serialize)]
+pub(crate) struct Create {
+    pub name: String,
+    pub recursive: bool,
+}
+
+/// A bookmark to create from a snapshot.
+#[derive(Debug, Serialize)]
+pub(crate) struct Bookmark {
+    pub snapshot: String,
+    pub bookmark: String,
+}
+
+/// A stream to send and receive.
+#[derive(Debug, Serialize)]
+pub(crate) struct Transfer {
+    /// The root dataset the stream is sent from.
+    pub source: String,
+    /// The anchor the incremental is sent from, none for a full send.
+    pub from: Option<String>,
+    /// The snapshot the destination is brought up to.
+    pub to: String,
+    /// The dataset (or remote) that receives the stream.
+    pub destination: String,
+    /// The estimated size of the stream, if it could be estimated.
+    pub estimated_bytes: Option<u64>,
+    /// Resuming an interrupted receive, rather than sending from `from`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<String>,
+}
+
+/// A change to a dataset, other than its snapshots.
+#[derive(Debug, Serialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub(crate) enum DatasetChange {
+    Create { name: String },
+    Rename { from: String, to: String },
+    Clone { snapshot: String, clone: String },
+    Rollback { snapshot: String },
+}
+
+/// A snapshot a destroy or rollback would leave, as it is in an immutability window.
+#[derive(Debug, Serialize)]
+pub(crate) struct Immutable {
+    pub snapshot: String,
+    /// When the window ends.
+    pub until: String,
+}
+
+/// An operation that would be staged for approval rather than run.
+#[derive(Debug, Serialize)]
+pub(crate) struct Approval {
+    pub kind: String,
+    pub target: String,
+    pub description: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Plan {
+    create: Vec<Create>,
+    /// Snapshots and bookmarks.
+    destroy: Vec<String>,
+    bookmarks: Vec<Bookmark>,
+    transfers: Vec<Transfer>,
+    datasets: Vec<DatasetChange>,
+    immutable: Vec<Immutable>,
+    approvals: Vec<Approval>,
+}
+
+/// Collect the plan of this run.
+pub(crate) fn start() {
+    match PLAN.lock() {
+        Ok(mut plan) => *plan = Some(Plan::default()),
+        Err(_) => error!("Unable to collect the plan"),
+    }
+}
+
+fn with(f: impl FnOnce(&mut Plan)) {
+    if let Ok(mut plan) = PLAN.lock() {
+        if let Some(plan) = plan.as_mut() {
+            f(plan)
+        }
+    }
+}
+
+pub(crate) fn create(name: &str, recursive: bool) {
+    summary::would_create(name);
+    with(|plan| {
+        plan.create.push(Create {
+            name: name.to_string(),
+            recursive,
+        })
+    })
+}
+
+pub(crate) fn destroy(name: &str) {
+    summary::would_destroy(name);
+    with(|plan| plan.destroy.push(name.to_string()))
+}
+
+pub(crate) fn bookmark(snapshot: &str, bookmark: &str) {
+    with(|plan| {
+        plan.bookmarks.push(Bookmark {
+            snapshot: snapshot.to_string(),
+            bookmark: bookmark.to_string(),
+        })
+    })
+}
+
+pub(crate) fn transfer(transfer: Transfer) {
+    summary::would_send(
+        &format!("{} -> {}", transfer.source, transfer.destination),
+        transfer.estimated_bytes,
+    );
+    with(|plan| plan.transfers.push(transfer))
+}
+
+pub(crate) fn dataset(change: DatasetChange) {
+    with(|plan| plan.datasets.push(change))
+}
+
+pub(crate) fn immutable(snapshot: &str, until: &str) {
+    with(|plan| {
+        plan.immutable.push(Immutable {
+            snapshot: snapshot.to_string(),
+            until: until.to_string(),
+        })
+    })
+}
+
+pub(crate) fn approval(kind: &str, target: &str, description: &str) {
+    with(|plan| {
+        plan.approvals.push(Approval {
+            kind: kind.to_string(),
+            target: target.to_string(),
+            description: description.to_string(),
+        })
+    })
+}
+
+/// Print the plan collected since `start`.
+pub(crate) fn print() {
+    let plan = match PLAN.lock() {
+        Ok(mut plan) => plan.take(),
+        Err(_) => None,
+    };
+    match plan.map(|plan| serde_json::to_string_pretty(&plan)) {
+        Some(Ok(s)) => println!("{}", s),
+        Some(Err(e)) => error!("failed to serialise plan -> {:?}", e),
+        None => error!("No plan was collected"),
+    }
+}
@@ -0,0 +1,289 @@
+//! The CPU and I/O priority of the send and receive of a stream, from `[priority]` in
+//! `znapper.toml`.
+//!
+//! A nightly replication reads the whole of what changed as fast as the disks allow, which
+//! leaves the source host sluggish for whoever is using it. Each command of a stream - zfs send
+//! and recv, the ssh it goes over and the zstd that compresses it - can instead be started with a
+//! niceness, an I/O class (ionice on Linux, the idle priority class of idprio on FreeBSD) and, on
+//! Linux, in a systemd slice whose CPUWeight and IOWeight limit it further. The priority is set
+//! in the child before it execs, so it holds through sudo or doas and for whatever the command
+//! starts. A priority that can't be set doesn't stop the command from running.
+
+use crate::config;
+use serde::Deserialize;
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::os::raw::c_long;
+use std::os::raw::{c_char, c_int, c_uint};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use tracing::{debug, error, warn};
+
+extern "C" {
+    fn setpriority(which: c_int, who: c_uint, prio: c_int) -> c_int;
+    fn open(path: *const c_char, flags: c_int, ...) -> c_int;
+    fn write(fd: c_int, buf: *const u8, count: usize) -> isize;
+    fn close(fd: c_int) -> c_int;
+    #[cfg(target_os = "linux")]
+    fn syscall(number: c_long, ...) -> c_long;
+    #[cfg(target_os = "freebsd")]
+    fn rtprio(function: c_int, pid: c_int, rtp: *mut Rtprio) -> c_int;
+}
+
+const PRIO_PROCESS: c_int = 0;
+const O_WRONLY: c_int = 1;
+
+/// ioprio_set has no wrapper in glibc, and its number depends on the architecture.
+#[cfg(target_os = "linux")]
+const SYS_IOPRIO_SET: Option<c_long> = if cfg!(target_arch = "x86_64") {
+    Some(251)
+} else if cfg!(target_arch = "x86") {
+    Some(289)
+} else if cfg!(target_arch = "arm") {
+    Some(314)
+} else if cfg!(any(
+    target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "loongarch64"
+)) {
+    Some(30)
+} else {
+    None
+};
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: c_int = 1;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: c_int = 13;
+
+#[cfg(target_os = "freebsd")]
+#[repr(C)]
+struct Rtprio {
+    kind: u16,
+    prio: u16,
+}
+#[cfg(target_os = "freebsd")]
+const RTP_SET: c_int = 1;
+#[cfg(target_os = "freebsd")]
+const RTP_PRIO_IDLE: u16 = 4;
+/// The lowest of the idle priorities.
+#[cfg(target_os = "freebsd")]
+const RTP_PRIO_MAX: u16 = 31;
+
+/// Where the unified (v2) cgroup hierarchy is mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+static PRIORITY: OnceLock<Option<Arc<Priority>>> = OnceLock::new();
+
+/// The I/O scheduling class of a stream - `idle`, which only gets the disk when nothing else
+/// wants it, or `best-effort[:0-7]`, which shares it at a level from 0 (highest) to 7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum IoClass {
+    Idle,
+    BestEffort(u8),
+}
+
+impl TryFrom<String> for IoClass {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for IoClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "idle" => Ok(IoClass::Idle),
+            None if s == "best-effort" => Ok(IoClass::BestEffort(4)),
+            Some(("best-effort", level)) => level
+                .parse()
+                .ok()
+                .filter(|l| *l <= 7)
+                .map(IoClass::BestEffort)
+                .ok_or_else(|| format!("invalid best-effort level {} - use 0 to 7", level)),
+            _ => Err(format!(
+                "unknown io class {} - use idle or best-effort[:level]",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Priority {
+    nice: Option<c_int>,
+    io: Option<IoClass>,
+    /// The cgroup.procs of the slice, which the command joins by writing 0 to it.
+    cgroup: Option<CString>,
+}
+
+/// The cgroup.procs of a systemd slice - `a-b.slice` is nested in `a.slice`.
+fn slice_procs(slice: &str) -> Result<PathBuf, String> {
+    let stem = slice
+        .strip_suffix(".slice")
+        .filter(|s| {
+            !s.is_empty()
+                && !s.contains('/')
+                && !s.starts_with('-')
+                && !s.ends_with('-')
+                && !s.contains("--")
+        })
+        .ok_or_else(|| format!("invalid slice {} - use a name such as backup.slice", slice))?;
+    let mut path = PathBuf::from(CGROUP_ROOT);
+    let mut prefix = String::new();
+    for part in stem.split('-') {
+        if !prefix.is_empty() {
+            prefix.push('-');
+        }
+        prefix.push_str(part);
+        path.push(format!("{}.slice", prefix));
+    }
+    Ok(path.join("cgroup.procs"))
+}
+
+fn cgroup(slice: &str) -> Result<CString, String> {
+    if !cfg!(target_os = "linux") {
+        return Err("slices are only on Linux".to_string());
+    }
+    let procs = slice_procs(slice)?;
+    if !procs.exists() {
+        return Err(format!(
+            "{} is not running - start it with systemctl start {}",
+            slice, slice
+        ));
+    }
+    CString::new(procs.as_os_str().as_bytes()).map_err(|e| e.to_string())
+}
+
+fn io_supported(io: IoClass) -> Result<IoClass, String> {
+    #[cfg(target_os = "linux")]
+    let supported = SYS_IOPRIO_SET.is_some();
+    #[cfg(target_os = "freebsd")]
+    let supported = io == IoClass::Idle;
+    #[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+    let supported = false;
+    if supported {
+        Ok(io)
+    } else {
+        Err(format!("{:?} is not supported on this platform", io))
+    }
+}
+
+/// Read the priority of the commands of a stream.
+pub(crate) fn init(config: &config::Priority) {
+    let nice = config.nice.filter(|n| {
+        let valid = (-20..=19).contains(n);
+        if !valid {
+            error!("Ignoring nice {} in znapper.toml -> use -20 to 19", n);
+        }
+        valid
+    });
+    let io = config.io.and_then(|io| {
+        io_supported(io)
+            .map_err(|e| warn!("Ignoring the io class in znapper.toml -> {}", e))
+            .ok()
+    });
+    let cgroup = config.slice.as_deref().and_then(|slice| {
+        cgroup(slice)
+            .map_err(|e| warn!("Ignoring the slice in znapper.toml -> {}", e))
+            .ok()
+    });
+    let priority = Priority { nice, io, cgroup };
+    debug!(?priority);
+    let set = priority.nice.is_some() || priority.io.is_some() || priority.cgroup.is_some();
+    let _ = PRIORITY.set(set.then(|| Arc::new(priority)));
+}
+
+/// Start `cmd` at the priority of a stream, if `[priority]` gives one.
+pub(crate) fn apply(cmd: &mut Command) {
+    let Some(priority) = PRIORITY.get().cloned().flatten() else {
+        return;
+    };
+    // Only async signal safe calls are allowed between the fork and the exec.
+    let set = move || {
+        if let Some(nice) = priority.nice {
+            unsafe { setpriority(PRIO_PROCESS, 0, nice) };
+        }
+        if let Some(io) = priority.io {
+            set_io(io);
+        }
+        if let Some(procs) = &priority.cgroup {
+            join(procs);
+        }
+        Ok(())
+    };
+    unsafe { cmd.pre_exec(set) };
+}
+
+#[cfg(target_os = "linux")]
+fn set_io(io: IoClass) {
+    let ioprio = match io {
+        IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+        IoClass::BestEffort(level) => (2 << IOPRIO_CLASS_SHIFT) | c_int::from(level),
+    };
+    if let Some(number) = SYS_IOPRIO_SET {
+        unsafe { syscall(number, IOPRIO_WHO_PROCESS, 0 as c_int, ioprio) };
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+fn set_io(io: IoClass) {
+    if io == IoClass::Idle {
+        let mut rtp = Rtprio {
+            kind: RTP_PRIO_IDLE,
+            prio: RTP_PRIO_MAX,
+        };
+        unsafe { rtprio(RTP_SET, 0, &mut rtp) };
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+fn set_io(_io: IoClass) {}
+
+/// Move the calling process into the cgroup of `procs`.
+fn join(procs: &CString) {
+    let fd = unsafe { open(procs.as_ptr(), O_WRONLY) };
+    if fd >= 0 {
+        unsafe {
+            write(fd, b"0".as_ptr(), 1);
+            close(fd);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_class_is_idle_or_best_effort_at_a_level() {
+        assert_eq!("idle".parse(), Ok(IoClass::Idle));
+        assert_eq!("best-effort".parse(), Ok(IoClass::BestEffort(4)));
+        assert_eq!("best-effort:7".parse(), Ok(IoClass::BestEffort(7)));
+        assert!("best-effort:8".parse::<IoClass>().is_err());
+        assert!("realtime".parse::<IoClass>().is_err());
+    }
+
+    #[test]
+    fn slices_nest_by_their_dashes() {
+        assert_eq!(
+            slice_procs("backup.slice").unwrap(),
+            PathBuf::from("/sys/fs/cgroup/backup.slice/cgroup.procs")
+        );
+        assert_eq!(
+            slice_procs("system-backup.slice").unwrap(),
+            PathBuf::from("/sys/fs/cgroup/system.slice/system-backup.slice/cgroup.procs")
+        );
+        assert!(slice_procs("backup").is_err());
+        assert!(slice_procs("-.slice").is_err());
+        assert!(slice_procs("../x.slice").is_err());
+    }
+}
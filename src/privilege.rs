@@ -0,0 +1,154 @@
+//! Which zfs binary znapper runs, and how it escalates what ZFS delegation can't cover.
+//!
+//! With `zfs allow` an unprivileged user can snapshot, send, receive and destroy, but on Linux
+//! only root can mount - so the operations that mount or unmount a filesystem (clone, rename, and
+//! the clone that redaction works on) are run through sudo or doas with `--escalate`, and
+//! everything else runs as the user.
+
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use structopt::StructOpt;
+
+static ZFS: OnceLock<ZfsOpt> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Escalate {
+    #[default]
+    None,
+    Sudo,
+    Doas,
+}
+
+impl FromStr for Escalate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Escalate::None),
+            "sudo" => Ok(Escalate::Sudo),
+            "doas" => Ok(Escalate::Doas),
+            _ => Err(format!("Invalid escalation {} - use sudo, doas or none", s)),
+        }
+    }
+}
+
+/// How snapshots are created and destroyed - by running zfs, or through libzfs_core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Backend {
+    #[default]
+    Cli,
+    LibzfsCore,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cli" => Ok(Backend::Cli),
+            "libzfs_core" => Ok(Backend::LibzfsCore),
+            _ => Err(format!(
+                "Invalid zfs backend {} - use cli or libzfs_core",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub(crate) struct ZfsOpt {
+    /// The zfs binary to run, if it is not zfs on the PATH. Defaults to ZNAPPER_ZFS.
+    #[structopt(long = "zfs-path")]
+    pub zfs_path: Option<String>,
+    /// Run the zfs commands that mount or unmount (clone and rename) through sudo or doas, for a
+    /// znapper that otherwise runs unprivileged with delegated permissions.
+    #[structopt(long = "escalate", default_value = "none")]
+    pub escalate: Escalate,
+    /// Create and destroy snapshots through libzfs_core rather than by running zfs, with a
+    /// znapper built with the libzfs-core feature. Everything else still runs zfs.
+    #[structopt(long = "zfs-backend", default_value = "cli")]
+    pub backend: Backend,
+}
+
+/// Use the zfs binary and escalation of `opt`, rather than the defaults.
+pub(crate) fn init(opt: &ZfsOpt) {
+    let _ = ZFS.set(opt.clone());
+}
+
+fn opt() -> &'static ZfsOpt {
+    ZFS.get_or_init(ZfsOpt::default)
+}
+
+/// The zfs binary - `--zfs-path`, then `ZNAPPER_ZFS`, then zfs on the PATH.
+pub(crate) fn zfs_path() -> String {
+    opt()
+        .zfs_path
+        .clone()
+        .or_else(|| std::env::var("ZNAPPER_ZFS").ok())
+        .filter(|path| !path.is_empty())
+        .unwrap_or_else(|| "zfs".to_string())
+}
+
+/// The global options that give this run its zfs binary and escalation, to run znapper the same
+/// way from elsewhere.
+pub(crate) fn args() -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(path) = opt().zfs_path.as_ref() {
+        args.extend(["--zfs-path".to_string(), path.clone()]);
+    }
+    let escalate = match opt().escalate {
+        Escalate::None => None,
+        Escalate::Sudo => Some("sudo"),
+        Escalate::Doas => Some("doas"),
+    };
+    if let Some(escalate) = escalate {
+        args.extend(["--escalate".to_string(), escalate.to_string()]);
+    }
+    if opt().backend == Backend::LibzfsCore {
+        args.extend(["--zfs-backend".to_string(), "libzfs_core".to_string()]);
+    }
+    args
+}
+
+/// The backend that snapshots are created and destroyed with.
+pub(crate) fn backend() -> Backend {
+    opt().backend
+}
+
+/// Does this run escalate, through sudo or doas?
+pub(crate) fn escalates() -> bool {
+    opt().escalate != Escalate::None
+}
+
+/// A zfs command, run as the user.
+pub(crate) fn zfs() -> Command {
+    Command::new(zfs_path())
+}
+
+/// A zfs command that needs root for more than delegation gives, run through `--escalate`.
+pub(crate) fn zfs_escalated() -> Command {
+    escalated(&zfs_path())
+}
+
+/// A command running `bin` that needs root, such as mount, run through `--escalate`.
+pub(crate) fn escalated(bin: &str) -> Command {
+    let prefix = match opt().escalate {
+        Escalate::None => return Command::new(bin),
+        Escalate::Sudo => "sudo",
+        Escalate::Doas => "doas",
+    };
+    let mut cmd = Command::new(prefix);
+    // Never wait for a password that nobody is there to type.
+    cmd.arg("-n").arg(bin);
+    cmd
+}
+
+/// A command running `bin`, which is the configured zfs binary if it is zfs.
+pub(crate) fn command(bin: &str) -> Command {
+    if bin == "zfs" {
+        zfs()
+    } else {
+        Command::new(bin)
+    }
+}
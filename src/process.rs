@@ -0,0 +1,312 @@
+//! Timeouts and cancellation for the zfs and ssh commands znapper runs.
+//!
+//! A hung ssh or a stuck zfs recv would otherwise block a run forever. Each command is registered
+//! here while it runs, with the deadline `[timeouts]` in `znapper.toml` gives its kind, and a
+//! watchdog thread kills (with SIGTERM) any that is still running past its deadline. On SIGINT
+//! or SIGTERM the watchdog kills every command that is running at the time, so that the pipeline
+//! fails and its failure path cleans up - such as removing the repl snapshot that was just
+//! created - and znapper exits non-zero once it has. A second signal exits straight away.
+
+use crate::config::Config;
+use crate::listing;
+use crate::parse_duration;
+use crate::priority;
+use crate::runner;
+use std::io;
+use std::os::raw::{c_int, c_uint};
+use std::process::{Child, Command, ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, warn};
+
+const SIGINT: c_int = 2;
+const SIGTERM: c_int = 15;
+
+/// Why the watchdog killed a command.
+const KILLED_BY_SIGNAL: u8 = 1;
+const KILLED_BY_TIMEOUT: u8 = 2;
+
+/// How often the watchdog looks for commands to kill.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(200);
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    fn kill(pid: c_int, sig: c_int) -> c_int;
+    fn _exit(status: c_int) -> !;
+    fn setpriority(which: c_int, who: c_uint, prio: c_int) -> c_int;
+}
+
+const PRIO_PROCESS: c_int = 0;
+
+/// The niceness of a run that strains a pool. Without an I/O class of its own, Linux gives the
+/// I/O of a process a priority to match.
+const LOW_PRIORITY: c_int = 19;
+
+/// The signal that cancelled the run, or 0.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Whether the watchdog killed a command for running past its timeout.
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+/// Whether the run has lowered its priority.
+static LOWERED: AtomicBool = AtomicBool::new(false);
+
+static TIMEOUTS: OnceLock<Timeouts> = OnceLock::new();
+
+static RUNNING: Mutex<Vec<Running>> = Mutex::new(Vec::new());
+
+/// What a command is, for the timeout it is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Zfs,
+    Ssh,
+    /// The send and receive of a stream.
+    Transfer,
+}
+
+#[derive(Debug, Default)]
+struct Timeouts {
+    zfs: Option<Duration>,
+    ssh: Option<Duration>,
+    transfer: Option<Duration>,
+}
+
+impl Timeouts {
+    fn of(&self, kind: Kind) -> Option<Duration> {
+        match kind {
+            Kind::Zfs => self.zfs,
+            Kind::Ssh => self.ssh,
+            Kind::Transfer => self.transfer,
+        }
+    }
+}
+
+struct Running {
+    pid: u32,
+    program: String,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    killed: Arc<AtomicU8>,
+}
+
+/// A registered command, which stops being watched when this is dropped.
+pub(crate) struct Guard {
+    pid: u32,
+    timeout: Option<Duration>,
+    killed: Arc<AtomicU8>,
+}
+
+impl Guard {
+    /// Why the command was killed, if it was.
+    pub(crate) fn error(&self) -> Option<io::Error> {
+        match self.killed.load(Ordering::SeqCst) {
+            KILLED_BY_SIGNAL => Some(io::Error::new(io::ErrorKind::Interrupted, "cancelled")),
+            KILLED_BY_TIMEOUT => Some(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out after {:?}", self.timeout.unwrap_or_default()),
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Ok(mut running) = RUNNING.lock() {
+            running.retain(|r| r.pid != self.pid);
+        }
+    }
+}
+
+extern "C" fn on_signal(sig: c_int) {
+    // Only async signal safe calls are allowed here - the watchdog does the rest.
+    if SIGNAL.swap(sig, Ordering::SeqCst) != 0 {
+        unsafe { _exit(128 + sig) };
+    }
+}
+
+fn parse(kind: &str, value: Option<&str>) -> Option<Duration> {
+    let value = value?;
+    match parse_duration(value) {
+        Ok(d) => Some(d),
+        Err(e) => {
+            error!("Ignoring the {} timeout in znapper.toml -> {}", kind, e);
+            None
+        }
+    }
+}
+
+/// Read the timeouts, handle SIGINT and SIGTERM, and start the watchdog.
+pub(crate) fn init() {
+    let config = Config::load().ok();
+    let timeouts = config
+        .as_ref()
+        .map(|config| Timeouts {
+            zfs: parse("zfs", config.timeouts.zfs.as_deref()),
+            ssh: parse("ssh", config.timeouts.ssh.as_deref()),
+            transfer: parse("transfer", config.timeouts.transfer.as_deref()),
+        })
+        .unwrap_or_default();
+    debug!(?timeouts);
+    let _ = TIMEOUTS.set(timeouts);
+    priority::init(&config.map(|c| c.priority).unwrap_or_default());
+
+    unsafe {
+        signal(SIGINT, on_signal);
+        signal(SIGTERM, on_signal);
+    }
+
+    let spawned = thread::Builder::new()
+        .name("watchdog".to_string())
+        .spawn(watchdog);
+    if let Err(e) = spawned {
+        warn!(
+            "Unable to start the watchdog - commands will not time out -> {:?}",
+            e
+        );
+    }
+}
+
+fn watchdog() {
+    let mut noticed = false;
+    loop {
+        thread::sleep(WATCHDOG_INTERVAL);
+        let sig = SIGNAL.load(Ordering::SeqCst);
+        // Only what was running when the signal arrived is killed, not the cleanup after it.
+        let cancel = sig != 0 && !noticed;
+        if cancel {
+            noticed = true;
+            error!("Received signal {} - stopping", sig);
+        }
+        let now = Instant::now();
+        let running = match RUNNING.lock() {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        for r in running
+            .iter()
+            .filter(|r| r.killed.load(Ordering::SeqCst) == 0)
+        {
+            let expired = r.deadline.map(|d| now >= d).unwrap_or(false);
+            if !(cancel || expired) {
+                continue;
+            }
+            if expired {
+                r.killed.store(KILLED_BY_TIMEOUT, Ordering::SeqCst);
+                TIMED_OUT.store(true, Ordering::SeqCst);
+                error!(
+                    "{} (pid {}) timed out after {:?} - killing it",
+                    r.program,
+                    r.pid,
+                    r.timeout.unwrap_or_default()
+                );
+            } else {
+                r.killed.store(KILLED_BY_SIGNAL, Ordering::SeqCst);
+                debug!("killing {} (pid {})", r.program, r.pid);
+            }
+            unsafe { kill(r.pid as c_int, SIGTERM) };
+        }
+    }
+}
+
+/// Has the run been cancelled by a signal?
+pub(crate) fn cancelled() -> bool {
+    SIGNAL.load(Ordering::SeqCst) != 0
+}
+
+/// Ask `child` to stop with SIGTERM - another znapper then stops what it is running, and exits.
+pub(crate) fn terminate(child: &Child) {
+    unsafe { kill(child.id() as c_int, SIGTERM) };
+}
+
+/// Did a command of the run time out? The run then failed, whatever the action made of it.
+pub(crate) fn timed_out() -> bool {
+    TIMED_OUT.load(Ordering::SeqCst)
+}
+
+/// The exit code of a cancelled run - 128 plus the signal.
+pub(crate) fn exit_code() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(128 + sig),
+    }
+}
+
+/// Run the rest of this run, and the commands it starts, at the lowest priority.
+pub(crate) fn lower_priority() {
+    if LOWERED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if unsafe { setpriority(PRIO_PROCESS, 0, LOW_PRIORITY) } == 0 {
+        warn!("Running at a lower priority (nice {})", LOW_PRIORITY);
+    } else {
+        warn!(
+            "Unable to lower the priority of the run -> {:?}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Watch `child` until the guard is dropped, killing it after the timeout of `kind`.
+pub(crate) fn register(child: &Child, program: &str, kind: Kind) -> Guard {
+    let timeout = TIMEOUTS.get().and_then(|t| t.of(kind));
+    let killed = Arc::new(AtomicU8::new(0));
+    if let Ok(mut running) = RUNNING.lock() {
+        running.push(Running {
+            pid: child.id(),
+            program: program.to_string(),
+            timeout,
+            deadline: timeout.map(|t| Instant::now() + t),
+            killed: killed.clone(),
+        });
+    }
+    Guard {
+        pid: child.id(),
+        timeout,
+        killed,
+    }
+}
+
+/// `output` and `status` for the commands znapper runs, which go to the current runner. Unlike
+/// `Command::output`, `run_output` inherits stdin unless it was set.
+pub(crate) trait Timed {
+    fn run_output(&mut self, kind: Kind) -> io::Result<Output>;
+    fn run_status(&mut self, kind: Kind) -> io::Result<ExitStatus>;
+    /// Start one end of a stream, which the caller registers and waits for.
+    fn run_spawn(&mut self) -> io::Result<Child>;
+}
+
+impl Timed for Command {
+    fn run_output(&mut self, kind: Kind) -> io::Result<Output> {
+        listing::running(self, kind);
+        if kind == Kind::Transfer {
+            priority::apply(self);
+        }
+        runner::current().output(self, kind)
+    }
+
+    fn run_status(&mut self, kind: Kind) -> io::Result<ExitStatus> {
+        listing::running(self, kind);
+        if kind == Kind::Transfer {
+            priority::apply(self);
+        }
+        runner::current().status(self, kind)
+    }
+
+    fn run_spawn(&mut self) -> io::Result<Child> {
+        listing::running(self, Kind::Transfer);
+        priority::apply(self);
+        runner::current().spawn(self)
+    }
+}
+
+/// As `Child::wait`, for a child registered as `guard`.
+pub(crate) fn wait(child: &mut Child, guard: &Guard) -> io::Result<ExitStatus> {
+    let status = child.wait()?;
+    match guard.error() {
+        Some(e) if !status.success() => Err(e),
+        _ => Ok(status),
+    }
+}
@@ -0,0 +1,317 @@
+//! Progress checkpoints for long running sends.
+//!
+//! `zfs send -v -P` reports the estimated size of the stream and, every second, how much of the
+//! current snapshot has been sent. We parse that from the send's stderr and periodically persist
+//! it under the state directory, so that the progress (and an ETA) of a multi day seed can be seen
+//! with `znapper progress` from another shell, and is still there after a reconnect.
+
+use crate::anchors::state_dir;
+use crate::buffer::Meter;
+use crate::history;
+use crate::OutputFormat;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::ChildStderr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, warn};
+
+/// How often a running checkpoint is written.
+const CHECKPOINT_INTERVAL_SECS: i64 = 30;
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct ProgressOpt {
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    pub label: String,
+    pub status: String,
+    pub started: i64,
+    pub updated: i64,
+    /// The total estimated size of the stream, from the -P size line.
+    total_estimate: Option<u64>,
+    /// Bytes of the snapshots that have completed, plus those of the current one.
+    pub bytes_sent: u64,
+    current_snapshot: Option<String>,
+    bytes_per_sec: u64,
+    eta_seconds: Option<u64>,
+    /// The anchor the root of the stream is sent from, none for a full send.
+    #[serde(default)]
+    from: Option<String>,
+    /// The snapshot the root of the stream is sent up to.
+    #[serde(default)]
+    to: Option<String>,
+    /// What the send wrote to stderr besides its progress - why it failed, if it did.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stderr: Vec<String>,
+    /// What znapper passed on, when the stream is proxied through it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxied: Option<Proxied>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Proxied {
+    pub bytes: u64,
+    pub bytes_per_sec: u64,
+    /// The adler32 of the bytes, in hex.
+    pub adler32: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<u64>,
+}
+
+impl From<&Meter> for Proxied {
+    fn from(meter: &Meter) -> Self {
+        Proxied {
+            bytes: meter.bytes(),
+            bytes_per_sec: meter.bytes_per_sec(),
+            adler32: format!("{:08x}", meter.checksum()),
+            rate_limit: meter.rate_limit(),
+        }
+    }
+}
+
+pub(crate) struct Watch {
+    handle: JoinHandle<Checkpoint>,
+    meter: Option<Arc<Meter>>,
+}
+
+fn checkpoint_dir() -> PathBuf {
+    state_dir().join("checkpoints")
+}
+
+fn checkpoint_path(label: &str) -> PathBuf {
+    let name: String = label
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    checkpoint_dir().join(format!("{}.json", name))
+}
+
+impl Checkpoint {
+    fn save(&self) {
+        let dir = checkpoint_dir();
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!("Unable to create checkpoint dir {:?} -> {:?}", dir, e);
+            return;
+        }
+        let path = checkpoint_path(&self.label);
+        let tmp = path.with_extension("json.tmp");
+        let res = File::create(&tmp)
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|f| serde_json::to_writer_pretty(f, self).map_err(|e| format!("{:?}", e)))
+            .and_then(|_| fs::rename(&tmp, &path).map_err(|e| format!("{:?}", e)));
+        if let Err(e) = res {
+            warn!("Unable to write checkpoint {:?} -> {}", path, e);
+        }
+    }
+
+    fn update_rate(&mut self, now: i64) {
+        let elapsed = (now - self.started).max(1) as u64;
+        self.bytes_per_sec = self.bytes_sent / elapsed;
+        self.eta_seconds = match (self.total_estimate, self.bytes_per_sec) {
+            (Some(total), rate) if rate > 0 => Some(total.saturating_sub(self.bytes_sent) / rate),
+            _ => None,
+        };
+    }
+}
+
+/// Start following the stderr of a `zfs send -v -P`, identified by `label`, and the `meter` of
+/// the proxy it is sent through, if it is.
+pub(crate) fn watch(label: &str, stderr: ChildStderr, meter: Option<Arc<Meter>>) -> Watch {
+    let now = OffsetDateTime::now_utc().timestamp();
+    let mut checkpoint = Checkpoint {
+        label: label.to_string(),
+        status: "running".to_string(),
+        started: now,
+        updated: now,
+        ..Default::default()
+    };
+    checkpoint.save();
+
+    let thread_meter = meter.clone();
+    let handle = thread::spawn(move || {
+        // Bytes of the snapshots already finished, and of the one in flight.
+        let mut completed = 0;
+        let mut current = 0;
+        let mut last_save = now;
+        // The dataset the stream is sent from is the first one it estimates.
+        let mut root: Option<String> = None;
+
+        for line in BufReader::new(stderr).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+            debug!("send -> {}", line);
+
+            let fields: Vec<_> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["size", total] => checkpoint.total_estimate = total.parse().ok(),
+                // "full <snap> <size>" and "incremental <from> <snap> <size>" are estimates, but
+                // name the snapshots the stream is sent from and to.
+                ["full", snap, ..] | ["incremental", _, snap, ..] => {
+                    let dataset = snap.split('@').next().unwrap_or(snap);
+                    if root.is_none() {
+                        root = Some(dataset.to_string());
+                        if let ["incremental", from, ..] = fields.as_slice() {
+                            checkpoint.from = Some(from.to_string());
+                        }
+                    }
+                    if root.as_deref() == Some(dataset) {
+                        checkpoint.to = Some(snap.to_string());
+                    }
+                }
+                [time, bytes, snap] if time.contains(':') => {
+                    let bytes = bytes.parse().unwrap_or(0);
+                    if checkpoint.current_snapshot.as_deref() != Some(*snap) {
+                        completed += current;
+                        checkpoint.current_snapshot = Some(snap.to_string());
+                    }
+                    current = bytes;
+                    checkpoint.bytes_sent = completed + current;
+                }
+                _ => checkpoint.stderr.push(line),
+            }
+
+            let now = OffsetDateTime::now_utc().timestamp();
+            if now - last_save >= CHECKPOINT_INTERVAL_SECS {
+                checkpoint.updated = now;
+                checkpoint.update_rate(now);
+                checkpoint.proxied = thread_meter.as_deref().map(Proxied::from);
+                checkpoint.save();
+                last_save = now;
+            }
+        }
+        checkpoint
+    });
+
+    Watch { handle, meter }
+}
+
+impl Watch {
+    /// Record the final state of the send once it has exited, returning what it wrote to stderr
+    /// besides its progress.
+    pub(crate) fn finish(self, success: bool) -> Vec<String> {
+        match self.handle.join() {
+            Ok(mut checkpoint) => {
+                let now = OffsetDateTime::now_utc().timestamp();
+                checkpoint.updated = now;
+                checkpoint.update_rate(now);
+                checkpoint.status = if success { "complete" } else { "failed" }.to_string();
+                checkpoint.eta_seconds = None;
+                checkpoint.proxied = self.meter.as_deref().map(Proxied::from);
+                checkpoint.save();
+                // The span of the send, if it declared bytes.
+                tracing::Span::current().record("bytes", &checkpoint.bytes_sent);
+                history::record(
+                    history::Kind::Send {
+                        label: checkpoint.label,
+                        from: checkpoint.from,
+                        to: checkpoint.to,
+                        bytes: checkpoint.bytes_sent,
+                        duration_seconds: now - checkpoint.started,
+                    },
+                    success,
+                );
+                checkpoint.stderr
+            }
+            Err(_) => {
+                error!("send progress thread panicked");
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// Every checkpoint, oldest first.
+pub(crate) fn checkpoints() -> Result<Vec<Checkpoint>, ()> {
+    let dir = checkpoint_dir();
+    let entries = match fs::read_dir(&dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            error!("Unable to read checkpoint dir {:?} -> {:?}", dir, e);
+            return Err(());
+        }
+    };
+
+    let mut checkpoints: Vec<Checkpoint> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .path()
+                .extension()
+                .map(|e| e == "json")
+                .unwrap_or(false)
+        })
+        .filter_map(|entry| {
+            File::open(entry.path())
+                .ok()
+                .and_then(|f| serde_json::from_reader(f).ok())
+        })
+        .collect();
+    checkpoints.sort_by_key(|c| c.started);
+    Ok(checkpoints)
+}
+
+pub(crate) fn do_progress(opt: &ProgressOpt) {
+    let checkpoints = match checkpoints() {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&checkpoints) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise checkpoints -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for c in checkpoints {
+                let pct = c
+                    .total_estimate
+                    .filter(|t| *t > 0)
+                    .map(|t| format!("{}%", c.bytes_sent * 100 / t))
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{}\t{}\tsent={} of {} ({})\t{} B/s\teta={}\t{}",
+                    c.label,
+                    c.status,
+                    c.bytes_sent,
+                    c.total_estimate
+                        .map(|t| t.to_string())
+                        .unwrap_or_else(|| "?".to_string()),
+                    pct,
+                    c.bytes_per_sec,
+                    c.eta_seconds
+                        .map(|e| format!("{}s", e))
+                        .unwrap_or_else(|| "-".to_string()),
+                    c.current_snapshot.as_deref().unwrap_or("-"),
+                );
+                if let Some(p) = c.proxied.as_ref() {
+                    println!(
+                        "\tproxied={}\t{} B/s{}\tadler32={}",
+                        p.bytes,
+                        p.bytes_per_sec,
+                        p.rate_limit
+                            .map(|r| format!(" (limit {})", r))
+                            .unwrap_or_default(),
+                        p.adler32
+                    );
+                }
+                if c.status == "failed" {
+                    for line in c.stderr.iter() {
+                        println!("\t-> {}", line);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Pull replication - run on the backup host, which connects to the source and runs `zfs send`
+//! there, receiving locally. The backup host holds the credentials, and the source only needs to
+//! allow `send`, so a compromised source can't reach (or destroy) its backups.
+//!
+//! The source's auto snapshots are the anchors. The newest auto snapshot the destination holds
+//! with the same guid as the source is the incremental base, and every newer auto snapshot is sent
+//! with it.
+
+use crate::buffer::BufferOpt;
+use crate::lock::LockOpt;
+use crate::ssh::SshOpt;
+use crate::stream::{self, RecvPropsOpt};
+use crate::{
+    create_parents, dataset_exists, parse_guids, pipe_send_recv, resolve_remote_ssh,
+    snapshot_guid_list, ssh_output,
+};
+use structopt::StructOpt;
+use tracing::{debug, error, info};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct PullOpt {
+    /// user@host, or the name of a target in targets.toml, to pull from
+    pub remote_ssh: String,
+    #[structopt(flatten)]
+    pub ssh: SshOpt,
+    #[structopt(flatten)]
+    pub buffer: BufferOpt,
+    /// The dataset on the source to replicate.
+    pub from_pool: String,
+    /// The local dataset to receive into.
+    pub to_pool: String,
+    #[structopt(flatten)]
+    pub recv: RecvPropsOpt,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+    #[structopt(flatten)]
+    pub lock: LockOpt,
+}
+
+pub(crate) fn do_pull(opt: &PullOpt) -> Result<(), ()> {
+    debug!("do_pull");
+
+    let remote_ssh = match resolve_remote_ssh(opt.remote_ssh.as_str(), &opt.ssh) {
+        Ok((ssh, _)) => ssh,
+        Err(_) => return Err(()),
+    };
+
+    // Oldest first.
+    let remote_snaps: Vec<_> = match ssh_output(
+        &remote_ssh,
+        &[
+            "zfs",
+            "list",
+            "-H",
+            "-p",
+            "-t",
+            "snapshot",
+            "-o",
+            "name,guid",
+            "-s",
+            "createtxg",
+            "-d",
+            "1",
+            opt.from_pool.as_str(),
+        ],
+    ) {
+        Ok(stdout) => parse_guids(&stdout)
+            .into_iter()
+            .filter(|(name, _)| {
+                name.split_once('@')
+                    .map(|(_, short)| short.starts_with("auto_"))
+                    .unwrap_or(false)
+            })
+            .collect(),
+        Err(_) => return Err(()),
+    };
+    debug!(?remote_snaps);
+
+    let newest = match remote_snaps.last() {
+        Some((name, _)) => name.clone(),
+        None => {
+            error!(
+                "No auto snapshots of {} on {} to pull",
+                opt.from_pool, remote_ssh
+            );
+            return Err(());
+        }
+    };
+
+    let base = if dataset_exists(opt.to_pool.as_str()) {
+        let local_guids: Vec<_> = match snapshot_guid_list(opt.to_pool.as_str()) {
+            Ok(snaps) => snaps.into_iter().map(|(_, guid)| guid).collect(),
+            Err(_) => return Err(()),
+        };
+        match remote_snaps
+            .iter()
+            .rev()
+            .find(|(_, guid)| local_guids.contains(guid))
+        {
+            Some((name, _)) if *name == newest => {
+                info!("{} is up to date with {}", opt.to_pool, newest);
+                return Ok(());
+            }
+            Some((name, _)) => Some(name.clone()),
+            None => {
+                error!(
+                    "{} shares no auto snapshots with {} on {} - pull into a new dataset to start again",
+                    opt.to_pool, opt.from_pool, remote_ssh
+                );
+                return Err(());
+            }
+        }
+    } else {
+        if create_parents(opt.dryrun, opt.to_pool.as_str()).is_err() {
+            return Err(());
+        }
+        None
+    };
+
+    let mut remote_send = vec!["zfs", "send", "-v", "-P", "-R", "-w", "-L"];
+    if let Some(base) = base.as_deref() {
+        remote_send.extend(["-I", base]);
+    }
+    remote_send.push(newest.as_str());
+    let ssh_argv = remote_ssh.argv(&remote_send);
+    let send_cmd: Vec<&str> = ssh_argv.iter().map(String::as_str).collect();
+    let props = opt.recv.args(stream::REPLICA);
+    let recv_args: Vec<&str> = props.iter().map(String::as_str).collect();
+
+    if pipe_send_recv(
+        opt.dryrun,
+        &opt.buffer,
+        &send_cmd,
+        &recv_args,
+        opt.to_pool.as_str(),
+        &format!("pull to {}", opt.to_pool),
+    )
+    .is_err()
+    {
+        error!("Pull of {} from {} failed", opt.from_pool, remote_ssh);
+        return Err(());
+    }
+
+    info!(
+        "Pull of {} from {} success - now at {}",
+        opt.from_pool, remote_ssh, newest
+    );
+    Ok(())
+}
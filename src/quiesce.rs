@@ -0,0 +1,505 @@
+//! Quiescing libvirt guests and containers while the datasets holding their disks and volumes
+//! are snapshotted, so that a snapshot is consistent as they see it, rather than as a crash would
+//! leave it.
+//!
+//! Each guest is mapped to its datasets with a `[vm.<domain>]` in znapper.toml. When snapshot, or
+//! the repl snapshot of init_repl and repl, takes any of them, a running guest is frozen with
+//! `virsh domfsfreeze` first and thawed with `virsh domfsthaw` once the snapshots are taken - or,
+//! with `agent_socket`, by talking to its qemu-guest-agent directly. A guest that can't be frozen
+//! (it has no agent, or the agent doesn't answer) is warned of, and snapshotted as it is.
+//!
+//! Containers are mapped to the datasets bind-mounted into them with a `[container.<name>]`, and
+//! are paused with `docker pause` (or podman) and unpaused in the same way - or, given a
+//! `quiesce` command, have it exec'd in them instead, and `resume` exec'd after.
+//!
+//! Databases are mapped to datasets with a `[database.<name>]`. Postgres is put in backup mode with
+//! `pg_backup_start()` (forcing a checkpoint, so that the snapshot recovers quickly), and mysql has
+//! its tables flushed and locked with `FLUSH TABLES WITH READ LOCK`. Both only last as long as the
+//! session that asked for them, so psql or mysql is kept running until the snapshots are taken,
+//! then asked to `pg_backup_stop()` or `UNLOCK TABLES`.
+
+use crate::config::{Config, Engine, Runtime};
+use crate::{check, privilege};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Stdio};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// How long a guest agent has to answer - freezing waits for the guest to flush its writes.
+const AGENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A guest or container, and how it is quiesced and resumed.
+#[derive(Debug, Clone)]
+enum Target {
+    Virsh(String),
+    Agent(String, PathBuf),
+    Pause(Runtime, String),
+    Exec {
+        runtime: Runtime,
+        name: String,
+        quiesce: Vec<String>,
+        resume: Option<Vec<String>>,
+    },
+    Database {
+        engine: Engine,
+        name: String,
+        connection: String,
+    },
+}
+
+/// The line a session prints once the statements before it have completed.
+const READY: &str = "znapper_ready";
+
+/// A psql or mysql session, holding a database in backup mode or locked.
+struct Session {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+/// The arguments of mysql for `connection`, a `mysql://user@host:port/database` or an option
+/// file.
+fn mysql_args(connection: &str) -> Vec<String> {
+    let uri = match connection.strip_prefix("mysql://") {
+        Some(uri) => uri,
+        None => return vec![format!("--defaults-extra-file={}", connection)],
+    };
+    let mut args = Vec::new();
+    let (server, database) = match uri.split_once('/') {
+        Some((server, database)) => (server, Some(database)),
+        None => (uri, None),
+    };
+    let server = match server.split_once('@') {
+        Some((user, server)) => {
+            args.push(format!("--user={}", user));
+            server
+        }
+        None => server,
+    };
+    match server.split_once(':') {
+        Some((host, port)) => {
+            args.push(format!("--host={}", host));
+            args.push(format!("--port={}", port));
+        }
+        None if !server.is_empty() => args.push(format!("--host={}", server)),
+        None => {}
+    }
+    args.extend(database.filter(|d| !d.is_empty()).map(String::from));
+    args
+}
+
+impl Session {
+    fn open(engine: Engine, connection: &str) -> Result<Self, String> {
+        let mut command = match engine {
+            Engine::Postgres => {
+                let mut command = privilege::command("psql");
+                command
+                    .args(["-X", "-q", "-A", "-t", "-v", "ON_ERROR_STOP=1", "-d"])
+                    .arg(connection);
+                command
+            }
+            Engine::Mysql => {
+                let mut command = privilege::command("mysql");
+                command.args(mysql_args(connection)).args([
+                    "--batch",
+                    "--skip-column-names",
+                    "--unbuffered",
+                ]);
+                command
+            }
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("unable to run {:?} -> {:?}", engine, e))?;
+        match (child.stdin.take(), child.stdout.take()) {
+            (Some(stdin), Some(stdout)) => Ok(Session {
+                child,
+                stdin,
+                stdout: BufReader::new(stdout),
+            }),
+            _ => {
+                let _ = child.kill();
+                Err(format!("unable to talk to {:?}", engine))
+            }
+        }
+    }
+
+    /// Run `sql`, waiting for it to complete.
+    fn run(&mut self, sql: &str) -> Result<(), String> {
+        writeln!(self.stdin, "{}\nSELECT '{}';", sql, READY)
+            .and_then(|()| self.stdin.flush())
+            .map_err(|e| format!("unable to write to the session -> {:?}", e))?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.stdout.read_line(&mut line) {
+                Ok(0) | Err(_) => return Err(self.failed()),
+                Ok(_) if line.trim() == READY => return Ok(()),
+                Ok(_) => debug!("session -> {}", line.trim()),
+            }
+        }
+    }
+
+    /// Why the session ended.
+    fn failed(&mut self) -> String {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let mut stderr = String::new();
+        if let Some(mut err) = self.child.stderr.take() {
+            let _ = err.read_to_string(&mut stderr);
+        }
+        format!("the session ended -> {}", stderr.trim())
+    }
+
+    /// Run `sql`, and end the session.
+    fn close(mut self, sql: &str) -> Result<(), String> {
+        self.run(sql)?;
+        let Session {
+            mut child, stdin, ..
+        } = self;
+        drop(stdin);
+        match child.wait() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!("the session exited with {}", status)),
+            Err(e) => Err(format!("the session failed -> {:?}", e)),
+        }
+    }
+}
+
+/// Exec `command` in the container `name`.
+fn exec(runtime: Runtime, name: &str, command: &[String]) -> Result<(), String> {
+    let mut args = vec!["exec", name];
+    args.extend(command.iter().map(String::as_str));
+    check::run(runtime.bin(), &args).map(|_| ())
+}
+
+impl Target {
+    fn name(&self) -> &str {
+        match self {
+            Target::Virsh(name) | Target::Agent(name, _) | Target::Pause(_, name) => name,
+            Target::Exec { name, .. } | Target::Database { name, .. } => name,
+        }
+    }
+
+    /// What quiescing and resuming this is called, for the log.
+    fn verbs(&self) -> (&'static str, &'static str) {
+        match self {
+            Target::Virsh(_) | Target::Agent(..) => ("freeze", "thaw"),
+            Target::Pause(..) => ("pause", "unpause"),
+            Target::Exec { .. } => ("quiesce", "resume"),
+            Target::Database {
+                engine: Engine::Postgres,
+                ..
+            } => ("start the backup of", "stop the backup of"),
+            Target::Database {
+                engine: Engine::Mysql,
+                ..
+            } => ("lock", "unlock"),
+        }
+    }
+
+    /// Quiesce this, returning the session that holds it quiesced, if there is one.
+    fn quiesce(&self) -> Result<Option<Session>, String> {
+        match self {
+            Target::Virsh(name) => check::run("virsh", &["domfsfreeze", name]).map(|_| None),
+            Target::Agent(_, socket) => agent(socket, "guest-fsfreeze-freeze").map(|()| None),
+            Target::Pause(runtime, name) => {
+                check::run(runtime.bin(), &["pause", name]).map(|_| None)
+            }
+            Target::Exec {
+                runtime,
+                name,
+                quiesce,
+                ..
+            } => exec(*runtime, name, quiesce).map(|()| None),
+            Target::Database {
+                engine, connection, ..
+            } => {
+                let mut session = Session::open(*engine, connection)?;
+                session.run(match engine {
+                    Engine::Postgres => "SELECT pg_backup_start('znapper', true);",
+                    Engine::Mysql => "FLUSH TABLES WITH READ LOCK;",
+                })?;
+                Ok(Some(session))
+            }
+        }
+    }
+
+    fn resume(&self, session: Option<Session>) -> Result<(), String> {
+        match self {
+            Target::Virsh(name) => check::run("virsh", &["domfsthaw", name]).map(|_| ()),
+            Target::Agent(_, socket) => agent(socket, "guest-fsfreeze-thaw"),
+            Target::Pause(runtime, name) => {
+                check::run(runtime.bin(), &["unpause", name]).map(|_| ())
+            }
+            Target::Exec {
+                runtime,
+                name,
+                resume,
+                ..
+            } => match resume {
+                Some(resume) => exec(*runtime, name, resume),
+                None => Ok(()),
+            },
+            Target::Database { engine, .. } => match session {
+                Some(session) => session.close(match engine {
+                    Engine::Postgres => "SELECT lsn FROM pg_backup_stop(false);",
+                    Engine::Mysql => "UNLOCK TABLES;",
+                }),
+                None => Err("its session was lost".to_string()),
+            },
+        }
+    }
+
+    /// Is there anything to quiesce? A guest or container that isn't running has nothing in
+    /// flight.
+    fn running(&self) -> bool {
+        let state = match self {
+            Target::Virsh(name) => {
+                check::run("virsh", &["domstate", name]).map(|state| state.trim() == "running")
+            }
+            Target::Agent(name, socket) => {
+                let exists = socket.exists();
+                if !exists {
+                    debug!("{} has no agent socket {:?}", name, socket);
+                }
+                return exists;
+            }
+            // Whether it is up is only known by connecting to it.
+            Target::Database { .. } => return true,
+            Target::Pause(runtime, name) | Target::Exec { runtime, name, .. } => check::run(
+                runtime.bin(),
+                &["inspect", "-f", "{{.State.Running}}", name],
+            )
+            .map(|running| running.trim() == "true"),
+        };
+        state.unwrap_or_else(|e| {
+            warn!("Unable to get the state of {} -> {}", self.name(), e);
+            false
+        })
+    }
+}
+
+/// Run `command` on the qemu-guest-agent listening on `socket`.
+fn agent(socket: &Path, command: &str) -> Result<(), String> {
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|e| format!("unable to connect to {:?} -> {:?}", socket, e))?;
+    stream
+        .set_read_timeout(Some(AGENT_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(AGENT_TIMEOUT)))
+        .map_err(|e| format!("unable to set the timeout of {:?} -> {:?}", socket, e))?;
+    writeln!(stream, "{}", serde_json::json!({ "execute": command }))
+        .map_err(|e| format!("unable to write to {:?} -> {:?}", socket, e))?;
+
+    let mut reply = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut reply)
+        .map_err(|e| format!("no reply from {:?} -> {:?}", socket, e))?;
+    let reply: Value = serde_json::from_str(&reply)
+        .map_err(|e| format!("unable to parse the reply of {:?} -> {:?}", socket, e))?;
+    match reply.get("error") {
+        Some(error) => Err(error
+            .get("desc")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown error")
+            .to_string()),
+        None => Ok(()),
+    }
+}
+
+/// Is `dataset` one of `disks`, or would snapshotting it with its descendants take one of them?
+fn holds(dataset: &str, recursive: bool, disks: &[String]) -> bool {
+    disks.iter().any(|disk| {
+        disk == dataset
+            || (recursive
+                && disk
+                    .strip_prefix(dataset)
+                    .map(|rest| rest.starts_with('/'))
+                    .unwrap_or(false))
+    })
+}
+
+/// The guests, containers and databases of `config` with data in `datasets`, snapshotted alone
+/// or with their descendants.
+fn targets(config: &Config, datasets: &[String], recursive: bool) -> Vec<Target> {
+    let taken = |disks: &[String]| {
+        datasets
+            .iter()
+            .any(|dataset| holds(dataset, recursive, disks))
+    };
+    let vms = config
+        .vm
+        .iter()
+        .filter(|(_, vm)| taken(&vm.datasets))
+        .map(|(name, vm)| match vm.agent_socket.clone() {
+            Some(socket) => Target::Agent(name.clone(), socket),
+            None => Target::Virsh(name.clone()),
+        });
+    let containers = config
+        .container
+        .iter()
+        .filter(|(_, container)| taken(&container.datasets))
+        .map(|(name, container)| match container.quiesce.clone() {
+            Some(quiesce) => Target::Exec {
+                runtime: container.runtime,
+                name: name.clone(),
+                quiesce,
+                resume: container.resume.clone(),
+            },
+            None => Target::Pause(container.runtime, name.clone()),
+        });
+    let databases = config
+        .database
+        .iter()
+        .filter(|(_, database)| taken(&database.datasets))
+        .map(|(name, database)| Target::Database {
+            engine: database.engine,
+            name: name.clone(),
+            connection: database.connection.clone(),
+        });
+    vms.chain(containers).chain(databases).collect()
+}
+
+/// The guests, containers and databases quiesced for a snapshot, with the sessions holding them
+/// so - resumed when this is dropped.
+pub(crate) struct Frozen {
+    dry: bool,
+    targets: Vec<(Target, Option<Session>)>,
+}
+
+/// Quiesce the running guests, containers and databases with data in `datasets`, which are about
+/// to be snapshotted - alone, or with their descendants if `recursive`.
+pub(crate) fn freeze(dry: bool, datasets: &[String], recursive: bool) -> Frozen {
+    let config = Config::load().unwrap_or_default();
+    let mut frozen = Frozen {
+        dry,
+        targets: Vec::new(),
+    };
+    for target in targets(&config, datasets, recursive) {
+        let (quiesce, _) = target.verbs();
+        if dry {
+            info!("dryrun: {} {}", quiesce, target.name());
+            frozen.targets.push((target, None));
+            continue;
+        }
+        if !target.running() {
+            debug!("{} is not running - nothing to {}", target.name(), quiesce);
+            continue;
+        }
+        match target.quiesce() {
+            Ok(session) => {
+                info!("{} -> {}", quiesce, target.name());
+                frozen.targets.push((target, session));
+            }
+            Err(e) => warn!(
+                "Unable to {} {} - snapshotting it as it is -> {}",
+                quiesce,
+                target.name(),
+                e
+            ),
+        }
+    }
+    frozen
+}
+
+impl Drop for Frozen {
+    fn drop(&mut self) {
+        for (target, session) in self.targets.drain(..).rev() {
+            let (_, resume) = target.verbs();
+            if self.dry {
+                info!("dryrun: {} {}", resume, target.name());
+                continue;
+            }
+            match target.resume(session) {
+                Ok(()) => info!("{} -> {}", resume, target.name()),
+                Err(e) => warn!(
+                    "Unable to {} {} - it needs doing by hand -> {}",
+                    resume,
+                    target.name(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Container, Vm};
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn targets_are_quiesced_for_the_snapshots_of_their_disks() {
+        let mut config = Config::default();
+        let vm = |datasets: &[&str]| Vm {
+            datasets: names(datasets),
+            agent_socket: None,
+        };
+        config
+            .vm
+            .insert("db".to_string(), vm(&["nvme/vm/db", "nvme/vm/db-log"]));
+        config.vm.insert("web".to_string(), vm(&["tank/web"]));
+        config.container.insert(
+            "pg".to_string(),
+            Container {
+                datasets: names(&["tank/pg"]),
+                runtime: Runtime::Podman,
+                quiesce: Some(names(&["psql", "-c", "CHECKPOINT"])),
+                resume: None,
+            },
+        );
+
+        let taken = |datasets: &[&str], recursive: bool| {
+            targets(&config, &names(datasets), recursive)
+                .iter()
+                .map(|t| t.name().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(taken(&["nvme"], true), vec!["db"]);
+        assert!(taken(&["nvme"], false).is_empty());
+        assert_eq!(
+            taken(&["nvme/vm/db-log", "tank/web"], false),
+            vec!["db", "web"]
+        );
+        assert!(taken(&["nvme/vm/d"], true).is_empty());
+        assert_eq!(taken(&["tank"], true), vec!["web", "pg"]);
+        assert!(matches!(
+            targets(&config, &names(&["tank/pg"]), false).as_slice(),
+            [Target::Exec {
+                runtime: Runtime::Podman,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn mysql_connections_are_given_as_arguments() {
+        assert_eq!(
+            mysql_args("mysql://backup@db.example.com:3307/shop"),
+            names(&[
+                "--user=backup",
+                "--host=db.example.com",
+                "--port=3307",
+                "shop"
+            ])
+        );
+        assert_eq!(
+            mysql_args("mysql://localhost"),
+            names(&["--host=localhost"])
+        );
+        assert_eq!(
+            mysql_args("/etc/znapper/mysql.cnf"),
+            names(&["--defaults-extra-file=/etc/znapper/mysql.cnf"])
+        );
+    }
+}
@@ -0,0 +1,636 @@
+//! The receiving side of remote replication, used as the forced command of the replication key
+//! in the receiver's authorized_keys:
+//!
+//! ```text
+//! command="znapper recv --pool tank/remote",restrict ssh-ed25519 AAAA...
+//! ```
+//!
+//! Unlike a bare `zfs recv` forced command, this reports the outcome as json on stdout - what was
+//! received, with guids, and any errors - so that `remote_repl` only advances its metadata when the
+//! snapshot it sent is really on the receiver.
+//!
+//! If the sender's command is `snapshots` (`ssh backup@host snapshots`), nothing is received and
+//! the snapshots of the dataset are listed as json instead, so that the sender can find the
+//! latest snapshot the receiver really has. Likewise `partial` lists the resume tokens of
+//! interrupted receives - streams are received with -s, so a dropped connection can be resumed -
+//! and `space` reports the free space of the pool, so the sender can check the stream will fit.
+//!
+//! For `remote_repl --per-dataset`, `recv <dataset>` receives the stream into `<pool>/<dataset>`
+//! (creating its parents), and `snapshots <dataset>` lists that dataset's snapshots.
+//!
+//! When several machines replicate to one backup server, `--pool backups/%hostname%` keeps each
+//! under a parent of its own. The sender, with `remote_repl --per-host`, starts every command with
+//! `from <hostname>`, and names its pool (`recv tank`, `snapshots tank`, `partial tank`), so that
+//! its streams land in `backups/<hostname>/<pool>`. Commands other than `space` that name no host
+//! are refused, as there is no telling where they belong.
+//!
+//! `chunks`, `chunk` and `assemble` receive a stream sent in chunks, with remote_repl
+//! `--chunked` - see `chunked`.
+//!
+//! Any other command is refused with an error in the json, rather than received as though it was
+//! none - a `zfs recv -F` of `remote_repl --force-rollback` is not run, and its stream not received
+//! without the -F it asked for.
+//!
+//! A command (after any `from <hostname>`) that starts with `zstd`, as `remote_repl
+//! --transport-compress` sends, receives a zstd compressed stream, decompressing it with `zstd -d`
+//! on the way into zfs recv.
+
+use crate::chunked;
+use crate::compress;
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use crate::snapshot_guid_list;
+use crate::stream::{self, RecvPropsOpt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use structopt::StructOpt;
+use tracing::{debug, error};
+
+#[derive(Debug, Clone, StructOpt)]
+pub(crate) struct RecvOpt {
+    /// The dataset to receive into. Received datasets are readonly and not mounted, unless
+    /// --recv-set, --recv-inherit or --recv-keep say otherwise. %hostname% is replaced with the
+    /// host the sender names, ie backups/%hostname%.
+    #[structopt(long = "pool")]
+    pub pool: String,
+    /// The sender sends plain streams (--no-raw) - receive them with -x encryption, so that they
+    /// take the encryption of the pool.
+    #[structopt(long = "no-raw")]
+    no_raw: bool,
+    /// The most the spools of chunked transfers may take for the pool, ie 512G.
+    #[structopt(long = "spool-limit", default_value = "64G", parse(try_from_str = crate::buffer::parse_size))]
+    spool_limit: usize,
+    #[structopt(flatten)]
+    recv: RecvPropsOpt,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub name: String,
+    pub guid: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SnapshotList {
+    /// The snapshots of the dataset itself, oldest first.
+    pub snapshots: Vec<Snapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Partial {
+    pub name: String,
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PartialState {
+    /// The dataset streams are received into.
+    pub dataset: String,
+    /// The datasets under it holding an interrupted receive.
+    pub partial: Vec<Partial>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Space {
+    pub dataset: String,
+    /// The free bytes of the pool holding the dataset, if they could be read.
+    pub free: Option<u64>,
+    /// The guid of that pool, which tells the sender which of its destinations this is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guid: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct RecvResult {
+    pub success: bool,
+    pub received: Vec<Snapshot>,
+    pub errors: Vec<String>,
+    /// What zfs recv wrote to stderr when it succeeded, ie properties it could not set.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+/// Find the json reply in the output of `znapper recv`, if the receiver ran it.
+pub(crate) fn parse_result<T: serde::de::DeserializeOwned>(stdout: &str) -> Option<T> {
+    stdout
+        .lines()
+        .rev()
+        .find_map(|line| serde_json::from_str(line.trim()).ok())
+}
+
+/// Parse `zfs get -H -r -o name,value receive_resume_token`, skipping datasets with no token.
+pub(crate) fn parse_partial(stdout: &str) -> Vec<Partial> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (name, token) = line.split_once('\t')?;
+            let token = token.trim();
+            if token.is_empty() || token == "-" {
+                None
+            } else {
+                Some(Partial {
+                    name: name.to_string(),
+                    token: token.to_string(),
+                })
+            }
+        })
+        .collect()
+}
+
+/// name -> guid of every snapshot under `dataset`. A dataset that doesn't exist has none.
+fn guid_map(dataset: &str) -> BTreeMap<String, String> {
+    let output = privilege::zfs()
+        .args([
+            "list",
+            "-H",
+            "-p",
+            "-t",
+            "snapshot",
+            "-o",
+            "name,guid",
+            "-r",
+            dataset,
+        ])
+        .stdin(Stdio::null())
+        .run_output(Kind::Zfs);
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let (name, guid) = line.split_once('\t')?;
+                Some((name.to_string(), guid.to_string()))
+            })
+            .collect(),
+        _ => BTreeMap::new(),
+    }
+}
+
+/// `<pool>/<dataset>`, if `dataset` is a dataset name that stays beneath the pool.
+fn child(pool: &str, dataset: &str) -> Option<String> {
+    let valid = !dataset.is_empty()
+        && dataset.split('/').all(|component| {
+            !component.is_empty()
+                && component != "."
+                && component != ".."
+                && component
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c))
+        });
+    if valid {
+        Some(format!("{}/{}", pool, dataset))
+    } else {
+        None
+    }
+}
+
+/// The dataset `pool` stands for with the sender `host` (from `from <host>`), and the rest of
+/// `command`.
+fn root<'a>(pool: &str, command: &'a str) -> Result<(String, &'a str), String> {
+    let (host, rest) = match command.strip_prefix("from ") {
+        Some(rest) => {
+            let (host, rest) = rest
+                .trim_start()
+                .split_once(' ')
+                .unwrap_or((rest.trim(), ""));
+            (Some(host), rest.trim())
+        }
+        None => (None, command),
+    };
+    // The free space is that of the pool, whichever host asks.
+    if !pool.contains("%hostname%") || (host.is_none() && rest == "space") {
+        return Ok((pool.to_string(), rest));
+    }
+    let host = host.ok_or_else(|| {
+        format!(
+            "{} is kept per host - the sender must name its host, with remote_repl --per-host",
+            pool
+        )
+    })?;
+    let valid = !host.is_empty()
+        && host != "."
+        && host != ".."
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c));
+    if valid {
+        Ok((pool.replace("%hostname%", host), rest))
+    } else {
+        Err(format!("invalid host {:?}", host))
+    }
+}
+
+/// Create the missing parents of `dataset`, which is being received per dataset.
+fn create_parents(dataset: &str) -> Result<(), String> {
+    let parent = match dataset.rsplit_once('/') {
+        Some((parent, _)) => parent,
+        None => return Ok(()),
+    };
+    let exists = privilege::zfs()
+        .args(["list", "-H", "-o", "name", parent])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .run_status(Kind::Zfs)
+        .map(|status| status.success())
+        .unwrap_or(false);
+    if exists {
+        return Ok(());
+    }
+    let output = privilege::zfs()
+        .args(["create", "-p", "-o", "canmount=off", parent])
+        .stdin(Stdio::null())
+        .run_output(Kind::Zfs)
+        .map_err(|e| format!("zfs create failed -> {:?}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "unable to create {} -> {}",
+            parent,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Receive the stream on `stdin` into `pool`, decompressing it first if the sender compressed it
+/// with `zstd`.
+fn receive(pool: &str, opt: &RecvOpt, zstd: bool, stdin: Stdio) -> RecvResult {
+    let mut result = RecvResult::default();
+
+    let (stdin, mut decompressor) = if zstd {
+        match compress::decompress(stdin) {
+            Ok((child, out)) => (Stdio::from(out), Some(child)),
+            Err(e) => {
+                result.errors.push(e);
+                return result;
+            }
+        }
+    } else {
+        (stdin, None)
+    };
+    let exclude: &[&str] = if opt.no_raw {
+        &["-x", "encryption"]
+    } else {
+        &[]
+    };
+    let output = privilege::zfs()
+        .args(["recv", "-s", "-v"])
+        .args(exclude)
+        .args(opt.recv.args(stream::REPLICA))
+        .arg(pool)
+        .stdin(stdin)
+        .run_output(Kind::Transfer);
+    if let Some(child) = decompressor.take() {
+        match child.wait_with_output() {
+            Ok(out) if out.status.success() => {}
+            Ok(out) => result.errors.push(format!(
+                "zstd -d exited with {:?} -> {}",
+                out.status.code(),
+                String::from_utf8_lossy(&out.stderr).trim()
+            )),
+            Err(e) => result.errors.push(format!("zstd -d failed -> {:?}", e)),
+        }
+    }
+    let output = match output {
+        Ok(o) => o,
+        Err(e) => {
+            result.errors.push(format!("zfs recv failed -> {:?}", e));
+            return result;
+        }
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    debug!("{}", stdout);
+    // "receiving incremental stream of tank@auto_2 into tank/remote@auto_2"
+    let names: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.starts_with("receiving "))
+        .filter_map(|line| line.rsplit_once(" into ").map(|(_, name)| name.trim()))
+        .collect();
+
+    let stderr: Vec<String> = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+    if !output.status.success() {
+        result.errors.extend(stderr);
+        result
+            .errors
+            .push(format!("zfs recv exited with {:?}", output.status.code()));
+        return result;
+    }
+
+    result.warnings = stderr;
+
+    // Check everything the stream said it received is now really there.
+    let guids = guid_map(pool);
+    for name in names {
+        if !(name == pool
+            || name.starts_with(&format!("{}/", pool))
+            || name.starts_with(&format!("{}@", pool)))
+        {
+            result
+                .errors
+                .push(format!("{} was received outside of {}", name, pool));
+            continue;
+        }
+        match guids.get(name) {
+            Some(guid) => result.received.push(Snapshot {
+                name: name.to_string(),
+                guid: guid.clone(),
+            }),
+            None => result
+                .errors
+                .push(format!("{} is missing after the receive", name)),
+        }
+    }
+
+    result.success = result.errors.is_empty() && !result.received.is_empty();
+    if !result.success && result.errors.is_empty() {
+        result.errors.push("no snapshots were received".to_string());
+    }
+    result
+}
+
+fn partial(pool: &str) -> PartialState {
+    let output = privilege::zfs()
+        .args([
+            "get",
+            "-H",
+            "-r",
+            "-o",
+            "name,value",
+            "receive_resume_token",
+            pool,
+        ])
+        .stdin(Stdio::null())
+        .run_output(Kind::Zfs);
+    PartialState {
+        dataset: pool.to_string(),
+        partial: match output {
+            Ok(output) if output.status.success() => {
+                parse_partial(&String::from_utf8_lossy(&output.stdout))
+            }
+            _ => Vec::new(),
+        },
+    }
+}
+
+fn space(pool: &str) -> Space {
+    let root = pool.split('/').next().unwrap_or(pool);
+    let output = Command::new("zpool")
+        .args(["get", "-H", "-p", "-o", "property,value", "free,guid", root])
+        .stdin(Stdio::null())
+        .run_output(Kind::Zfs);
+    let stdout = match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).to_string()
+        }
+        _ => String::new(),
+    };
+    let value = |property: &str| {
+        stdout.lines().find_map(|line| {
+            line.split_once('\t')
+                .filter(|(p, _)| *p == property)
+                .map(|(_, value)| value.trim().to_string())
+        })
+    };
+    Space {
+        dataset: pool.to_string(),
+        free: value("free").and_then(|free| free.parse().ok()),
+        guid: value("guid"),
+    }
+}
+
+fn list(pool: &str) -> SnapshotList {
+    SnapshotList {
+        snapshots: snapshot_guid_list(pool)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, guid)| Snapshot { name, guid })
+            .collect(),
+    }
+}
+
+pub(crate) fn do_recv(opt: &RecvOpt) {
+    // Errors are reported in the reply only, as the sender reads stdout.
+    let command = std::env::var("SSH_ORIGINAL_COMMAND").unwrap_or_default();
+    reply(answer(opt, &command, Input::Stdin))
+}
+
+/// Where the sender's stream comes from.
+pub(crate) enum Input {
+    /// Our stdin, from ssh.
+    Stdin,
+    /// From serve-recv, which takes it off the connection.
+    Pipe(io::PipeReader),
+}
+
+impl Input {
+    fn stdio(self) -> Stdio {
+        match self {
+            Input::Stdin => Stdio::inherit(),
+            Input::Pipe(reader) => Stdio::from(reader),
+        }
+    }
+
+    fn reader(self) -> Box<dyn Read> {
+        match self {
+            Input::Stdin => Box::new(io::stdin()),
+            Input::Pipe(reader) => Box::new(reader),
+        }
+    }
+}
+
+/// Receive the `count` chunks of the spool `id`, into `dataset` under `pool` or `pool` itself.
+fn assemble(
+    pool: &str,
+    opt: &RecvOpt,
+    zstd: bool,
+    (id, count, dataset): (&str, usize, Option<&str>),
+) -> RecvResult {
+    let failed = |e: String| RecvResult {
+        errors: vec![e],
+        ..Default::default()
+    };
+    let target = match dataset.map(|dataset| (dataset, child(pool, dataset))) {
+        Some((_, Some(target))) => match create_parents(&target) {
+            Ok(()) => target,
+            Err(e) => return failed(e),
+        },
+        Some((dataset, None)) => return failed(format!("invalid dataset {:?}", dataset)),
+        None => pool.to_string(),
+    };
+    let (stream, feed) = match chunked::reassemble(pool, id, count) {
+        Ok(r) => r,
+        Err(e) => return failed(e),
+    };
+    let mut result = receive(&target, opt, zstd, stream);
+    match feed.join() {
+        Ok(Ok(())) => {}
+        // zfs recv stops reading when it fails, and that is the error.
+        Ok(Err(_)) if !result.success => {}
+        Ok(Err(e)) => result
+            .warnings
+            .push(format!("zfs recv did not read all of {} -> {:?}", id, e)),
+        Err(_) => result.errors.push("the chunk feed panicked".to_string()),
+    }
+    if result.success {
+        chunked::discard(pool, id);
+    }
+    result
+}
+
+/// The reply to a command of a chunked transfer, if `command` is one.
+fn answer_chunked(
+    pool: &str,
+    opt: &RecvOpt,
+    zstd: bool,
+    command: &str,
+    input: Input,
+) -> Result<serde_json::Result<String>, Input> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let reply = match words.as_slice() {
+        ["chunks", id] if chunked::valid_id(id) => {
+            return Ok(serde_json::to_string(&chunked::held(pool, id)))
+        }
+        ["chunk", id, index, size, adler32] if chunked::valid_id(id) => {
+            match (index.parse(), size.parse()) {
+                (Ok(index), Ok(size)) => chunked::store(
+                    pool,
+                    id,
+                    (index, size, adler32),
+                    opt.spool_limit as u64,
+                    input.reader(),
+                ),
+                _ => RecvResult {
+                    errors: vec![format!("invalid chunk {:?} of {:?} bytes", index, size)],
+                    ..Default::default()
+                },
+            }
+        }
+        ["assemble", id, count, dataset @ ..] if chunked::valid_id(id) && dataset.len() < 2 => {
+            match count.parse() {
+                Ok(count) => assemble(pool, opt, zstd, (id, count, dataset.first().copied())),
+                Err(_) => RecvResult {
+                    errors: vec![format!("invalid chunk count {:?}", count)],
+                    ..Default::default()
+                },
+            }
+        }
+        ["chunks" | "chunk" | "assemble", ..] => RecvResult {
+            errors: vec![format!("invalid command {:?}", command)],
+            ..Default::default()
+        },
+        _ => return Err(input),
+    };
+    Ok(serde_json::to_string(&reply))
+}
+
+/// The reply to the sender's `command`, receiving any stream from `input`.
+pub(crate) fn answer(opt: &RecvOpt, command: &str, input: Input) -> serde_json::Result<String> {
+    let (pool, command) = match root(&opt.pool, command.trim()) {
+        Ok(r) => r,
+        Err(e) => {
+            return serde_json::to_string(&RecvResult {
+                errors: vec![e],
+                ..Default::default()
+            });
+        }
+    };
+    // The sender compressed the stream, with remote_repl --transport-compress.
+    let (zstd, command) = match command.strip_prefix(compress::ZSTD) {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => (true, rest.trim_start()),
+        _ => (false, command),
+    };
+    let stdin = match answer_chunked(&pool, opt, zstd, command, input) {
+        Ok(reply) => return reply,
+        Err(input) => input.stdio(),
+    };
+    match command.split_once(' ') {
+        Some((verb @ ("recv" | "snapshots" | "partial"), dataset)) => {
+            match child(&pool, dataset.trim()) {
+                Some(target) if verb == "snapshots" => serde_json::to_string(&list(&target)),
+                Some(target) if verb == "partial" => serde_json::to_string(&partial(&target)),
+                Some(target) => match create_parents(&target) {
+                    Ok(()) => serde_json::to_string(&receive(&target, opt, zstd, stdin)),
+                    Err(e) => serde_json::to_string(&RecvResult {
+                        errors: vec![e],
+                        ..Default::default()
+                    }),
+                },
+                None => serde_json::to_string(&RecvResult {
+                    errors: vec![format!("invalid dataset {:?}", dataset)],
+                    ..Default::default()
+                }),
+            }
+        }
+        None if command == "snapshots" => serde_json::to_string(&list(&pool)),
+        None if command == "partial" => serde_json::to_string(&partial(&pool)),
+        None if command == "space" => serde_json::to_string(&space(&pool)),
+        None if command.is_empty() => serde_json::to_string(&receive(&pool, opt, zstd, stdin)),
+        _ => serde_json::to_string(&RecvResult {
+            errors: vec![unknown(command)],
+            ..Default::default()
+        }),
+    }
+}
+
+/// Why `command` is refused - nothing but the commands above is run, or received with.
+fn unknown(command: &str) -> String {
+    if command.starts_with("zfs ") {
+        format!(
+            "znapper recv does not run {:?} - --force-rollback needs a key without a forced command",
+            command
+        )
+    } else {
+        format!("unknown command {:?}", command)
+    }
+}
+
+fn reply(reply: serde_json::Result<String>) {
+    match reply {
+        Ok(s) => println!("{}", s),
+        Err(e) => error!("failed to serialise reply -> {:?}", e),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_outside_the_protocol_are_refused() {
+        let opt = RecvOpt::from_iter(["recv", "--pool", "tank/remote"]);
+        for command in ["zfs recv -F tank/remote", "destroy tank/remote", "recv"] {
+            let reply = answer(&opt, command, Input::Stdin).unwrap();
+            let result: RecvResult = serde_json::from_str(&reply).unwrap();
+            assert!(!result.success);
+            assert_eq!(result.errors.len(), 1, "{}", command);
+        }
+    }
+
+    #[test]
+    fn senders_are_kept_under_their_host() {
+        assert_eq!(
+            root("backups/%hostname%", "from laptop recv tank").unwrap(),
+            ("backups/laptop".to_string(), "recv tank")
+        );
+        assert_eq!(
+            root("backups/%hostname%", "from laptop space").unwrap(),
+            ("backups/laptop".to_string(), "space")
+        );
+        assert_eq!(
+            root("tank/remote", "from laptop").unwrap(),
+            ("tank/remote".to_string(), "")
+        );
+        assert!(root("backups/%hostname%", "snapshots").is_err());
+        assert!(root("backups/%hostname%", "space").is_ok());
+        assert!(root("backups/%hostname%", "from .. recv tank").is_err());
+        assert!(root("backups/%hostname%", "from a/b recv tank").is_err());
+    }
+}
@@ -0,0 +1,433 @@
+//! Redacted replication - excluding sensitive paths of a dataset from the streams sent to a less
+//! trusted destination.
+//!
+//! The paths to exclude are set per dataset in the `org.znapper:redact` property. For each repl
+//! the new repl_ snapshot is cloned, the paths are removed from the clone, and the snapshot of the
+//! clone is used to create a redaction bookmark `<dataset>#redact_<repl snapshot>`. The send is
+//! then `zfs send --redact` of that bookmark, so the removed blocks never leave the source. A
+//! path of `/` redacts the whole dataset - it and its descendants are left out of the streams.
+//!
+//! `znapper redact` makes the same bookmark from clones cleaned by hand instead, for sharing a
+//! sanitized copy of one snapshot - ie production data, given to developers - and with `--to`
+//! sends it there.
+
+use crate::buffer::BufferOpt;
+use crate::lock::LockOpt;
+use crate::model::Class;
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use crate::stream::{self, RecvPropsOpt};
+use crate::{bookmark_list, create_parents, dataset_exists, dataset_list, get_property};
+use crate::{pipe_send_recv, plan, remove_bookmark, short_name};
+use std::fs;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+use tracing::{debug, error, info};
+
+pub(crate) const REDACT_PROPERTY: &str = "org.znapper:redact";
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct RedactOpt {
+    /// The snapshot to redact, ie tank/prod@auto_2024-05-01T030000Z
+    pub snapshot: String,
+    /// Clones of the snapshot with the sensitive files removed, or snapshots of them. The blocks
+    /// of the snapshot that none of them hold are redacted.
+    #[structopt(required = true)]
+    clean: Vec<String>,
+    /// Send the redacted snapshot into this dataset, which must not exist yet.
+    #[structopt(long = "to")]
+    to: Option<String>,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+    #[structopt(flatten)]
+    recv: RecvPropsOpt,
+    #[structopt(flatten)]
+    buffer: BufferOpt,
+    #[structopt(flatten)]
+    pub lock: LockOpt,
+}
+
+/// The clone used to build redaction snapshots, created under (and skipped in) the pool root.
+const REDACT_CLONE: &str = "znapper_redact";
+
+pub(crate) fn clone_name(pool_name: &str) -> String {
+    let pool = pool_name.split('/').next().unwrap_or(pool_name);
+    format!("{}/{}", pool, REDACT_CLONE)
+}
+
+/// The paths to redact from `fs` - a comma separated list relative to the root of the dataset.
+/// Only a locally set property counts, so children do not redact the same paths by inheritance.
+pub(crate) fn redact_paths(fs: &str) -> Result<Vec<String>, ()> {
+    let output = privilege::zfs()
+        .arg("get")
+        .arg("-H")
+        .arg("-s")
+        .arg("local")
+        .arg("-o")
+        .arg("value")
+        .arg(REDACT_PROPERTY)
+        .arg(fs)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zfs get failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!("zfs get {} {} failed", REDACT_PROPERTY, fs);
+        return Err(());
+    }
+    let value = String::from_utf8_lossy(&output.stdout);
+    Ok(value
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+fn zfs(dry: bool, args: &[&str]) -> Result<(), ()> {
+    if dry {
+        info!("dryrun: zfs {}", args.join(" "));
+        return Ok(());
+    }
+    info!("zfs {}", args.join(" "));
+    // Mounting the clone, and unmounting it to destroy it, may need more than delegation gives.
+    let mut cmd = match args.first() {
+        Some(&"clone") | Some(&"destroy") => privilege::zfs_escalated(),
+        _ => privilege::zfs(),
+    };
+    let status = cmd.args(args).run_status(Kind::Zfs).map_err(|e| {
+        error!("zfs {} failed -> {:?}", args.join(" "), e);
+    })?;
+    debug!(?status);
+    if status.success() {
+        Ok(())
+    } else {
+        error!("zfs {} failed", args.join(" "));
+        Err(())
+    }
+}
+
+/// The datasets under `pool` that redact `/` - the whole of them - with their descendants, which
+/// redacted replication leaves out.
+pub(crate) fn left_out(pool: &str) -> Result<Vec<String>, ()> {
+    let output = privilege::zfs()
+        .args(["get", "-H", "-r", "-s", "local", "-o", "name,value"])
+        .arg(REDACT_PROPERTY)
+        .arg(pool)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zfs get failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!("zfs get {} {} failed", REDACT_PROPERTY, pool);
+        return Err(());
+    }
+    let whole: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(_, value)| value.split(',').any(|path| path.trim() == "/"))
+        .map(|(name, _)| name.to_string())
+        .collect();
+    if whole.is_empty() {
+        return Ok(whole);
+    }
+    Ok(dataset_list(pool)?
+        .into_iter()
+        .filter(|fs| {
+            whole.iter().any(|w| {
+                fs == w
+                    || fs
+                        .strip_prefix(w.as_str())
+                        .map(|rest| rest.starts_with('/'))
+                        .unwrap_or(false)
+            })
+        })
+        .collect())
+}
+
+/// Redacted streams can't be raw, so an encrypted dataset would arrive decrypted.
+fn check_redactable(fs: &str) -> Result<(), ()> {
+    if get_property(fs, "encryption")? != "off" {
+        error!("Refusing to redact {} - it is encrypted", fs);
+        return Err(());
+    }
+    if get_property(fs, "type")? != "filesystem" {
+        error!(
+            "Refusing to redact {} - only filesystems can be redacted",
+            fs
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Create the redaction bookmark of `snap_name`, excluding `paths`, and return its name.
+pub(crate) fn create_redaction(dry: bool, snap_name: &str, paths: &[String]) -> Result<String, ()> {
+    let (fs, short) = match snap_name.split_once('@') {
+        Some(split) => split,
+        None => {
+            error!("Invalid snapshot name -> {}", snap_name);
+            return Err(());
+        }
+    };
+
+    check_redactable(fs)?;
+    for path in paths {
+        if path.trim_start_matches('/').is_empty()
+            || !Path::new(path)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::RootDir))
+        {
+            error!("Invalid redact path {} on {}", path, fs);
+            return Err(());
+        }
+    }
+
+    let clone = clone_name(fs);
+    let mountpoint = mount_dir(dry)?;
+    let mountpoint_opt = format!("mountpoint={}", mountpoint.display());
+    let clone_snap = format!("{}@redact", clone);
+    let bookmark = format!("{}#redact_{}", fs, short);
+
+    let cloned = (|| {
+        if dataset_exists(&clone) {
+            info!("Removing leftover redaction clone {}", clone);
+            zfs(dry, &["destroy", "-r", &clone])?;
+        }
+        zfs(
+            dry,
+            &[
+                "clone",
+                "-o",
+                &mountpoint_opt,
+                "-o",
+                "readonly=off",
+                snap_name,
+                &clone,
+            ],
+        )
+    })();
+    if cloned.is_err() {
+        remove_mount_dir(dry, &mountpoint);
+        return Err(());
+    }
+
+    let res = (|| {
+        if !dry {
+            check_mounted(&clone, &mountpoint)?;
+        }
+        for path in paths {
+            let target = mountpoint.join(path.trim_start_matches('/'));
+            if dry {
+                info!("dryrun: redact -> {}", target.display());
+                continue;
+            }
+            if !within(&mountpoint, &target)? {
+                error!(
+                    "Refusing to redact {} - it is reached through a link out of the clone",
+                    target.display()
+                );
+                return Err(());
+            }
+            info!("redact -> {}", target.display());
+            let removed = if target.is_dir() {
+                fs::remove_dir_all(&target)
+            } else {
+                fs::remove_file(&target)
+            };
+            match removed {
+                Ok(()) => {}
+                // Nothing to redact in this snapshot.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    error!("Unable to redact {} -> {:?}", target.display(), e);
+                    return Err(());
+                }
+            }
+        }
+        zfs(dry, &["snapshot", &clone_snap])?;
+        zfs(dry, &["redact", snap_name, &bookmark, &clone_snap])
+    })();
+
+    // The redaction bookmark keeps its own record of the redacted blocks.
+    let _ = zfs(dry, &["destroy", "-r", &clone]);
+    remove_mount_dir(dry, &mountpoint);
+    res.map(|_| bookmark)
+}
+
+/// A directory of our own to mount the redaction clone at - new, and only ours to reach, so
+/// nothing else can have been put there. In a dry run only its name.
+fn mount_dir(dry: bool) -> Result<PathBuf, ()> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    let dir =
+        std::env::temp_dir().join(format!("{}-{}-{}", REDACT_CLONE, std::process::id(), nanos));
+    if !dry {
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .map_err(|e| {
+                error!("Unable to create {} -> {:?}", dir.display(), e);
+            })?;
+    }
+    Ok(dir)
+}
+
+fn remove_mount_dir(dry: bool, dir: &Path) {
+    if !dry {
+        if let Err(e) = fs::remove_dir(dir) {
+            debug!("Unable to remove {} -> {:?}", dir.display(), e);
+        }
+    }
+}
+
+/// Is `clone` mounted where we asked? Were it not, what is removed from under `mountpoint` would
+/// be removed from whatever is there instead.
+fn check_mounted(clone: &str, mountpoint: &Path) -> Result<(), ()> {
+    let mounted = get_property(clone, "mounted")?;
+    let at = get_property(clone, "mountpoint")?;
+    if mounted != "yes" || Path::new(&at) != mountpoint {
+        error!(
+            "Refusing to redact - {} is not mounted at {} (mounted {}, at {})",
+            clone,
+            mountpoint.display(),
+            mounted,
+            at
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Is `target` in the clone mounted at `mountpoint`, rather than reached through a symlink of
+/// the snapshot out of it? One whose parent doesn't exist is, as there is nothing to remove.
+fn within(mountpoint: &Path, target: &Path) -> Result<bool, ()> {
+    let root = fs::canonicalize(mountpoint).map_err(|e| {
+        error!("Unable to resolve {} -> {:?}", mountpoint.display(), e);
+    })?;
+    let parent = target.parent().unwrap_or(target);
+    match fs::canonicalize(parent) {
+        Ok(parent) => Ok(parent.starts_with(&root)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(e) => {
+            error!("Unable to resolve {} -> {:?}", parent.display(), e);
+            Err(())
+        }
+    }
+}
+
+/// Remove the redaction bookmarks of `fs`, apart from `keep`.
+pub(crate) fn cleanup_redactions(dry: bool, fs: &str, keep: &str) -> Result<(), ()> {
+    for bookmark in bookmark_list(fs)? {
+        if bookmark.dataset_name() == fs
+            && bookmark.class() == Class::Redact
+            && bookmark.name() != keep
+        {
+            remove_bookmark(dry, bookmark.name())?;
+        }
+    }
+    Ok(())
+}
+
+/// The snapshot of `clean` to redact `snap_name` with - `clean` itself if it is a snapshot,
+/// otherwise a new one of it. Either way it must be of a clone of `snap_name`.
+fn clean_snapshot(dry: bool, snap_name: &str, clean: &str) -> Result<String, ()> {
+    let (clone, short) = match clean.split_once('@') {
+        Some((clone, short)) => (clone, Some(short)),
+        None => (clean, None),
+    };
+    let origin = get_property(clone, "origin")?;
+    if origin != snap_name {
+        error!(
+            "{} is not a clone of {} - its origin is {}",
+            clone, snap_name, origin
+        );
+        return Err(());
+    }
+    match short {
+        Some(_) => Ok(clean.to_string()),
+        None => {
+            let clean_snap = format!("{}@redact_{}", clone, short_name(snap_name));
+            zfs(dry, &["snapshot", &clean_snap])?;
+            Ok(clean_snap)
+        }
+    }
+}
+
+pub(crate) fn do_redact(opt: &RedactOpt) {
+    let _ = redact(opt);
+}
+
+fn redact(opt: &RedactOpt) -> Result<(), ()> {
+    debug!("do_redact");
+    let (fs, short) = match opt.snapshot.split_once('@') {
+        Some(split) => split,
+        None => {
+            error!("{} is not a snapshot", opt.snapshot);
+            return Err(());
+        }
+    };
+    check_redactable(fs)?;
+    if let Some(to) = opt.to.as_deref() {
+        if dataset_exists(to) {
+            error!("{} already exists - a redacted send can only create it", to);
+            return Err(());
+        }
+    }
+
+    let clean_snaps = opt
+        .clean
+        .iter()
+        .map(|clean| clean_snapshot(opt.dryrun, &opt.snapshot, clean))
+        .collect::<Result<Vec<_>, _>>()?;
+    let bookmark = format!("{}#redact_{}", fs, short);
+    let mut args = vec!["redact", opt.snapshot.as_str(), bookmark.as_str()];
+    args.extend(clean_snaps.iter().map(String::as_str));
+    zfs(opt.dryrun, &args)?;
+    info!(
+        "Created {} - send it with zfs send --redact {} {}",
+        bookmark, bookmark, opt.snapshot
+    );
+
+    let to = match opt.to.as_deref() {
+        Some(to) => to,
+        None => return Ok(()),
+    };
+    create_parents(opt.dryrun, to)?;
+    let props = opt.recv.args(stream::REPLICA);
+    let recv: Vec<&str> = props.iter().map(String::as_str).collect();
+    pipe_send_recv(
+        opt.dryrun,
+        &opt.buffer,
+        &[
+            "zfs",
+            "send",
+            "-v",
+            "-P",
+            "-L",
+            "--redact",
+            bookmark.as_str(),
+            opt.snapshot.as_str(),
+        ],
+        &recv,
+        to,
+        &format!("redacted send to {}", to),
+    )?;
+    if opt.dryrun {
+        plan::transfer(plan::Transfer {
+            source: fs.to_string(),
+            from: None,
+            to: opt.snapshot.clone(),
+            destination: to.to_string(),
+            estimated_bytes: None,
+            resume_token: None,
+        });
+    } else {
+        info!("Redacted {} received into {}", opt.snapshot, to);
+    }
+    Ok(())
+}
@@ -0,0 +1,281 @@
+//! `znapper restore` - bring back a dataset as it was at one of its snapshots, either as a clone
+//! beside it or in its place.
+//!
+//! `zfs rollback -r` would destroy every newer snapshot, along with any safety snapshot taken
+//! first, so a rollback is instead a swap: the snapshot is cloned and promoted (taking the older
+//! snapshots with it), the dataset is renamed aside as `<dataset>_pre_restore_<time>` with its
+//! newer snapshots and the safety snapshot, and the clone is renamed into its place.
+
+use crate::lock::LockOpt;
+use crate::process::{Kind, Timed};
+use crate::{clone_snap, create_snap, dataset_exists, dataset_list, get_property, naming};
+use crate::{confirm, privilege};
+use crate::{rename_dataset, short_name};
+use structopt::StructOpt;
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct RestoreOpt {
+    /// The snapshot to restore, ie tank/data@auto_2024-05-01T030000Z
+    pub snapshot: String,
+    /// The dataset to clone the snapshot into. Defaults to <dataset>_restore_<time>.
+    #[structopt(long = "to", conflicts_with = "rollback")]
+    to: Option<String>,
+    /// Promote the clone, so that it no longer depends on the snapshot it was cloned from.
+    #[structopt(long = "promote", conflicts_with = "rollback")]
+    promote: bool,
+    /// Put the dataset itself back to the snapshot, first keeping its current state (and newer
+    /// snapshots) as <dataset>_pre_restore_<time>.
+    #[structopt(long = "rollback")]
+    rollback: bool,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+    #[structopt(flatten)]
+    pub lock: LockOpt,
+}
+
+/// Clone, promote, rename, mount and unmount may need more than delegation gives.
+pub(crate) fn zfs(dry: bool, args: &[&str]) -> Result<(), ()> {
+    if dry {
+        info!("dryrun: zfs {}", args.join(" "));
+        return Ok(());
+    }
+    info!("zfs {}", args.join(" "));
+    let mut cmd = match args.first() {
+        Some(&"set") | Some(&"inherit") => privilege::zfs(),
+        _ => privilege::zfs_escalated(),
+    };
+    let output = cmd.args(args).run_output(Kind::Zfs).map_err(|e| {
+        error!("zfs {} failed -> {:?}", args.join(" "), e);
+    })?;
+    debug!(?output.status);
+    if output.status.success() {
+        Ok(())
+    } else {
+        error!(
+            "zfs {} failed -> {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Err(())
+    }
+}
+
+/// The properties set locally on `dataset`, for the clone that replaces it to have too.
+fn local_properties(dataset: &str) -> Result<Vec<(String, String)>, ()> {
+    let output = privilege::zfs()
+        .arg("get")
+        .arg("-H")
+        .arg("-p")
+        .arg("-s")
+        .arg("local")
+        .arg("-o")
+        .arg("property,value")
+        .arg("all")
+        .arg(dataset)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zfs get failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!("zfs get all {} failed", dataset);
+        return Err(());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        // A clone takes its size and encryption from the snapshot, and canmount is kept off
+        // until the swap.
+        .filter(|(property, _)| {
+            ![
+                "canmount",
+                "volsize",
+                "keylocation",
+                "keyformat",
+                "pbkdf2iters",
+            ]
+            .contains(property)
+        })
+        .map(|(property, value)| (property.to_string(), value.to_string()))
+        .collect())
+}
+
+fn restore_clone(opt: &RestoreOpt, dataset: &str, short: &str) -> Result<(), ()> {
+    let clone = opt
+        .to
+        .clone()
+        .unwrap_or_else(|| format!("{}_restore_{}", dataset, short.trim_start_matches("auto_")));
+    clone_snap(opt.dryrun, &opt.snapshot, &clone)?;
+    if opt.promote {
+        zfs(opt.dryrun, &["promote", &clone])?;
+        warn!(
+            "{} is promoted - {} now depends on it, and the snapshots up to {} are its",
+            clone, dataset, short
+        );
+    }
+    info!("Restored {} as {}", opt.snapshot, clone);
+    Ok(())
+}
+
+fn restore_rollback(opt: &RestoreOpt, dataset: &str, short: &str) -> Result<(), ()> {
+    if dataset_list(dataset)?.len() > 1 {
+        error!(
+            "{} has child datasets, which would be set aside with it - restore into a clone \
+             with --to instead",
+            dataset
+        );
+        return Err(());
+    }
+    let now_ts = naming::now()?;
+    let aside = format!("{}_pre_restore_{}", dataset, now_ts);
+    let restoring = format!("{}_restoring", dataset);
+    if dataset_exists(&restoring) {
+        error!(
+            "{} exists, left by an earlier restore - destroy it first",
+            restoring
+        );
+        return Err(());
+    }
+
+    if !opt.dryrun
+        && !confirm::yes()
+        && !confirm::ask(&format!(
+            "Restore {} to {}? Its current state is kept as {}",
+            dataset, short, aside
+        ))
+    {
+        error!("Not restoring {}", dataset);
+        return Err(());
+    }
+
+    let filesystem = get_property(dataset, "type")? == "filesystem";
+    let canmount = if filesystem {
+        local_canmount(dataset)?
+    } else {
+        None
+    };
+    let properties = local_properties(dataset)?;
+
+    // The current state, which leaves with the dataset when it is set aside.
+    let safety = format!("{}@restore_safety_{}", dataset, now_ts);
+    let run_id = format!("{}_{}", now_ts, std::process::id());
+    create_snap(opt.dryrun, &safety, &run_id)?;
+
+    if filesystem {
+        zfs(opt.dryrun, &["set", "canmount=noauto", dataset])?;
+        if zfs(opt.dryrun, &["unmount", dataset]).is_err() {
+            error!("{} is busy - nothing was restored", dataset);
+            let _ = restore_canmount(opt.dryrun, dataset, canmount.as_deref());
+            return Err(());
+        }
+    }
+
+    let mut clone_args = vec!["clone".to_string()];
+    if filesystem {
+        clone_args.extend(["-o".to_string(), "canmount=noauto".to_string()]);
+    }
+    for (property, value) in properties.iter() {
+        clone_args.extend(["-o".to_string(), format!("{}={}", property, value)]);
+    }
+    clone_args.extend([opt.snapshot.clone(), restoring.clone()]);
+    let clone_args: Vec<_> = clone_args.iter().map(String::as_str).collect();
+
+    // However the swap ends, canmount goes back on whichever dataset is then at the mountpoint -
+    // the restored clone, or the previous state if that is still where it was.
+    let mut mounted = dataset.to_string();
+    let swapped = (|| {
+        if zfs(opt.dryrun, &clone_args).is_err() {
+            error!("Unable to clone {} - {} is unchanged", short, dataset);
+            return Err(());
+        }
+        if zfs(opt.dryrun, &["promote", &restoring]).is_err() {
+            error!("Unable to promote {} - {} is unchanged", restoring, dataset);
+            return Err(());
+        }
+        if rename_dataset(opt.dryrun, dataset, &aside).is_err() {
+            error!(
+                "{} is in {}, but {} could not be set aside",
+                short, restoring, dataset
+            );
+            return Err(());
+        }
+        mounted = aside.clone();
+        if rename_dataset(opt.dryrun, &restoring, dataset).is_err() {
+            error!(
+                "{} is in {}, but could not be renamed to {} - the previous state is {}",
+                short, restoring, dataset, aside
+            );
+            return Err(());
+        }
+        mounted = dataset.to_string();
+        Ok(())
+    })();
+    let remounted = if filesystem {
+        restore_canmount(opt.dryrun, &mounted, canmount.as_deref())
+    } else {
+        Ok(())
+    };
+    swapped?;
+    remounted?;
+
+    info!("Restored {} to {}", dataset, short);
+    warn!(
+        "The previous state of {} is kept as {} (with {}) - destroy it once you no longer need \
+         it, as until then {} can't be destroyed",
+        dataset, aside, safety, opt.snapshot
+    );
+    Ok(())
+}
+
+/// canmount, if it is set locally on `dataset`.
+fn local_canmount(dataset: &str) -> Result<Option<String>, ()> {
+    let output = privilege::zfs()
+        .arg("get")
+        .arg("-H")
+        .arg("-s")
+        .arg("local")
+        .arg("-o")
+        .arg("value")
+        .arg("canmount")
+        .arg(dataset)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zfs get failed -> {:?}", e);
+        })?;
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(Some(value).filter(|v| !v.is_empty()))
+}
+
+/// Put canmount back as it was, and mount the dataset if that allows.
+fn restore_canmount(dry: bool, dataset: &str, canmount: Option<&str>) -> Result<(), ()> {
+    match canmount {
+        Some(value) => zfs(dry, &["set", &format!("canmount={}", value), dataset])?,
+        None => zfs(dry, &["inherit", "canmount", dataset])?,
+    }
+    if canmount.unwrap_or("on") == "on" {
+        zfs(dry, &["mount", dataset])?;
+    }
+    Ok(())
+}
+
+pub(crate) fn do_restore(opt: &RestoreOpt) {
+    debug!("do_restore");
+    let (dataset, _) = match opt.snapshot.split_once('@') {
+        Some(split) => split,
+        None => {
+            error!("{} is not a snapshot", opt.snapshot);
+            return;
+        }
+    };
+    if !dataset_exists(&opt.snapshot) {
+        error!("{} does not exist", opt.snapshot);
+        return;
+    }
+    let short = short_name(&opt.snapshot);
+
+    let _ = if opt.rollback {
+        restore_rollback(opt, dataset, short)
+    } else {
+        restore_clone(opt, dataset, short)
+    };
+}
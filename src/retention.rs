@@ -0,0 +1,97 @@
+//! Retention a dataset declares for itself, with the `org.znapper:keep` and
+//! `org.znapper:keep-daily` user properties, in place of the policy snapshot_cleanup was given.
+//! Being properties they are inherited, so setting them on a parent covers its children too.
+
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use std::collections::BTreeMap;
+use tracing::{debug, error, warn};
+
+/// How long to keep the auto snapshots of the dataset for - hours, as `48h` (or a bare `48`), or
+/// days or weeks, as `7d` or `2w`.
+pub(crate) const KEEP_PROPERTY: &str = "org.znapper:keep";
+
+/// How many days to also keep the newest auto snapshot of.
+pub(crate) const KEEP_DAILY_PROPERTY: &str = "org.znapper:keep-daily";
+
+/// What a dataset set of its retention. What it leaves unset is up to the policy it was given.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Retention {
+    pub keep_hours: Option<u32>,
+    pub keep_daily: Option<u32>,
+}
+
+/// `48h`, `48`, `7d` or `2w` in hours.
+fn parse_keep(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let (n, hours) = match value.char_indices().last()? {
+        (i, 'h') => (&value[..i], 1),
+        (i, 'd') => (&value[..i], 24),
+        (i, 'w') => (&value[..i], 24 * 7),
+        _ => (value, 1),
+    };
+    n.parse::<u32>().ok()?.checked_mul(hours)
+}
+
+/// The retention of each dataset under `pool` that has (or inherits) either property.
+pub(crate) fn overrides(pool: &str) -> Result<BTreeMap<String, Retention>, ()> {
+    let output = privilege::zfs()
+        .arg("get")
+        .arg("-H")
+        .arg("-p")
+        .arg("-r")
+        .arg("-t")
+        .arg("filesystem,volume")
+        .arg("-s")
+        .arg("local,inherited")
+        .arg("-o")
+        .arg("name,property,value")
+        .arg(format!("{},{}", KEEP_PROPERTY, KEEP_DAILY_PROPERTY))
+        .arg(pool)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zfs get failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        error!(
+            "zfs get {},{} {} failed -> {}",
+            KEEP_PROPERTY,
+            KEEP_DAILY_PROPERTY,
+            pool,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(());
+    }
+
+    let mut retention: BTreeMap<String, Retention> = BTreeMap::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut fields = line.split('\t');
+        let (dataset, property, value) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(d), Some(p), Some(v)) => (d, p, v),
+            _ => continue,
+        };
+        let parsed = match property {
+            KEEP_PROPERTY => parse_keep(value),
+            KEEP_DAILY_PROPERTY => value.trim().parse::<u32>().ok(),
+            _ => continue,
+        };
+        let parsed = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                warn!(
+                    "Ignoring {}={} of {} - it is not a number of hours or days",
+                    property, value, dataset
+                );
+                continue;
+            }
+        };
+        let entry = retention.entry(dataset.to_string()).or_default();
+        if property == KEEP_PROPERTY {
+            entry.keep_hours = Some(parsed);
+        } else {
+            entry.keep_daily = Some(parsed);
+        }
+    }
+    debug!(?retention);
+    Ok(retention)
+}
@@ -0,0 +1,250 @@
+//! What runs the zfs and ssh commands.
+//!
+//! Every zfs and ssh command znapper runs goes through the current [`ZfsRunner`]. Normally that
+//! is [`SystemRunner`], which runs them with the timeouts of `[timeouts]` in `znapper.toml`. With
+//! [`set_runner`] they can instead go to a [`MockRunner`], which answers each with canned output
+//! and records what was run - so replication and cleanup can be tested without a pool.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use znapper::runner::{set_runner, MockRunner};
+//! use znapper::Zfs;
+//!
+//! let mock = Arc::new(MockRunner::new());
+//! mock.reply("zfs list -t all nvme", "nvme\nnvme@auto_2024_01_01_00_00_00\n");
+//! set_runner(mock.clone());
+//!
+//! let snaps = Zfs::new().auto_snapshots("nvme").unwrap();
+//! assert_eq!(snaps[0].name(), "nvme@auto_2024_01_01_00_00_00");
+//! assert_eq!(mock.ran("zfs list").len(), 1);
+//! ```
+
+use crate::process;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex, RwLock};
+
+pub use crate::process::Kind;
+
+static RUNNER: RwLock<Option<Arc<dyn ZfsRunner>>> = RwLock::new(None);
+
+/// Runs the zfs and ssh commands of znapper.
+pub trait ZfsRunner: Send + Sync {
+    /// Run `cmd` to its end, capturing stdout and stderr. Unlike `Command::output`, stdin is
+    /// inherited unless it was set.
+    fn output(&self, cmd: &mut Command, kind: Kind) -> io::Result<Output>;
+
+    /// Run `cmd` to its end.
+    fn status(&self, cmd: &mut Command, kind: Kind) -> io::Result<ExitStatus>;
+
+    /// Start one end of a stream - a zfs send, or the zfs recv of an archive. `cmd` has its
+    /// stdio set up already, and the caller waits for the child.
+    fn spawn(&self, cmd: &mut Command) -> io::Result<Child>;
+}
+
+/// Run the commands for real, killing them after the timeout of their kind, or on a signal.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl ZfsRunner for SystemRunner {
+    fn output(&self, cmd: &mut Command, kind: Kind) -> io::Result<Output> {
+        let child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        let guard = process::register(&child, &program(cmd), kind);
+        let output = child.wait_with_output()?;
+        match guard.error() {
+            Some(e) if !output.status.success() => Err(e),
+            _ => Ok(output),
+        }
+    }
+
+    fn status(&self, cmd: &mut Command, kind: Kind) -> io::Result<ExitStatus> {
+        let mut child = cmd.spawn()?;
+        let guard = process::register(&child, &program(cmd), kind);
+        process::wait(&mut child, &guard)
+    }
+
+    fn spawn(&self, cmd: &mut Command) -> io::Result<Child> {
+        cmd.spawn()
+    }
+}
+
+/// Run the commands with `runner` from now on, in place of [`SystemRunner`].
+pub fn set_runner(runner: Arc<dyn ZfsRunner>) {
+    if let Ok(mut current) = RUNNER.write() {
+        *current = Some(runner);
+    }
+}
+
+/// The runner to run commands with.
+pub(crate) fn current() -> Arc<dyn ZfsRunner> {
+    RUNNER
+        .read()
+        .ok()
+        .and_then(|current| current.clone())
+        .unwrap_or_else(|| Arc::new(SystemRunner))
+}
+
+fn program(cmd: &Command) -> String {
+    cmd.get_program().to_string_lossy().into_owned()
+}
+
+/// The program and arguments of `cmd`.
+fn words(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|w| w.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Does `words` have each word of `pattern`, in order? A word of the pattern ending in `*`
+/// matches any word it begins.
+fn matches(pattern: &[String], words: &[String]) -> bool {
+    let mut words = words.iter();
+    pattern.iter().all(|p| match p.strip_suffix('*') {
+        Some(prefix) => words.any(|w| w.starts_with(prefix)),
+        None => words.any(|w| w == p),
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+struct Reply {
+    pattern: Vec<String>,
+    code: i32,
+    stdout: String,
+    stderr: String,
+}
+
+/// Answer the commands with canned output, rather than running them, and record what was run.
+///
+/// A pattern is the words a command line must have, in order, so `zfs list -t snapshot nvme`
+/// matches `zfs list -H -o name -t snapshot -r nvme` but not the same list of `tank/nvme`, and
+/// `zfs get guid nvme@repl_*` the guid of any repl_ snapshot of nvme. The latest reply that
+/// matches a command answers it, and a command no reply matches succeeds with no output.
+#[derive(Debug, Default)]
+pub struct MockRunner {
+    replies: Mutex<Vec<Reply>>,
+    calls: Mutex<Vec<Vec<String>>>,
+}
+
+impl MockRunner {
+    pub fn new() -> Self {
+        MockRunner::default()
+    }
+
+    fn push(&self, reply: Reply) {
+        if let Ok(mut replies) = self.replies.lock() {
+            replies.push(reply);
+        }
+    }
+
+    /// Succeed the commands matching `pattern`, with `stdout`.
+    pub fn reply(&self, pattern: &str, stdout: &str) {
+        self.push(Reply {
+            pattern: pattern.split_whitespace().map(str::to_string).collect(),
+            stdout: stdout.to_string(),
+            ..Default::default()
+        });
+    }
+
+    /// Fail the commands matching `pattern` with exit `code`, and `stderr`.
+    pub fn fail(&self, pattern: &str, code: i32, stderr: &str) {
+        self.push(Reply {
+            pattern: pattern.split_whitespace().map(str::to_string).collect(),
+            code,
+            stderr: stderr.to_string(),
+            ..Default::default()
+        });
+    }
+
+    /// Every command line run so far, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls
+            .lock()
+            .map(|calls| calls.iter().map(|words| words.join(" ")).collect())
+            .unwrap_or_default()
+    }
+
+    /// The command lines run so far that match `pattern`.
+    pub fn ran(&self, pattern: &str) -> Vec<String> {
+        let pattern: Vec<_> = pattern.split_whitespace().map(str::to_string).collect();
+        self.calls
+            .lock()
+            .map(|calls| {
+                calls
+                    .iter()
+                    .filter(|words| matches(&pattern, words))
+                    .map(|words| words.join(" "))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Forget the commands run so far, keeping the replies.
+    pub fn clear(&self) {
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.clear();
+        }
+    }
+
+    fn answer(&self, cmd: &Command) -> Reply {
+        let words = words(cmd);
+        let reply = self
+            .replies
+            .lock()
+            .ok()
+            .and_then(|replies| {
+                replies
+                    .iter()
+                    .rev()
+                    .find(|reply| matches(&reply.pattern, &words))
+                    .cloned()
+            })
+            .unwrap_or_default();
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.push(words);
+        }
+        reply
+    }
+}
+
+impl ZfsRunner for MockRunner {
+    fn output(&self, cmd: &mut Command, _kind: Kind) -> io::Result<Output> {
+        let reply = self.answer(cmd);
+        Ok(Output {
+            status: ExitStatus::from_raw(reply.code << 8),
+            stdout: reply.stdout.into_bytes(),
+            stderr: reply.stderr.into_bytes(),
+        })
+    }
+
+    fn status(&self, cmd: &mut Command, _kind: Kind) -> io::Result<ExitStatus> {
+        Ok(ExitStatus::from_raw(self.answer(cmd).code << 8))
+    }
+
+    /// A stream end still has to be a child, so a shell stands in for it. A recv reads all of
+    /// its stdin first, and then both write the reply.
+    fn spawn(&self, cmd: &mut Command) -> io::Result<Child> {
+        let recv = words(cmd).iter().any(|w| w == "recv" || w == "receive");
+        let reply = self.answer(cmd);
+        let (script, stdin) = if recv {
+            ("cat >/dev/null; ", Stdio::piped())
+        } else {
+            ("", Stdio::null())
+        };
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "{}printf %s \"$1\"; printf %s \"$2\" >&2; exit \"$3\"",
+                script
+            ))
+            .arg("sh")
+            .arg(reply.stdout)
+            .arg(reply.stderr)
+            .arg(reply.code.to_string())
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+    }
+}
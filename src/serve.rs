@@ -0,0 +1,729 @@
+//! Daemon mode - a small http api, so that dashboards and automation can drive znapper without
+//! a shell on the box.
+//!
+//! `znapper serve` listens on `--listen` (localhost by default) and answers json:
+//!
+//! * `GET /jobs` - the jobs of `znapper.toml`,
+//! * `POST /jobs/<job>/run` - start `znapper sync <job>`, answering with the run,
+//! * `GET /status` and `GET /status/<job>` - the lag of each destination, as `znapper status`,
+//! * `GET /runs` and `GET /runs/<id>` - the runs started, whether they are still going, and how
+//!   they exited,
+//! * `GET /runs/<id>/log` - the log of a run, streamed until it ends.
+//!
+//! Every request must carry the token of `--token-file` as `Authorization: Bearer <token>`. The
+//! api speaks plain http, so anything but localhost belongs behind a proxy that adds TLS. Each run
+//! is a znapper process of its own, logging to a file under `api/` in the state directory, so a
+//! run takes its job's locks and sends its notifications as it would from a timer.
+//!
+//! A job with `every` is also run on that interval, with no timer needed. When a job is due (or
+//! asked to run by the api) while a run of it is still going, its `overlap` says what happens:
+//! `skip` leaves the run going and waits for the next time the job is due, `queue` runs it again
+//! once the run has ended - only once, however often it is due meanwhile - and `restart` stops
+//! the run and starts a new one once it has stopped. How often each happened is kept in
+//! `api/overlaps.json`, for the metrics.
+
+use crate::anchors::state_dir;
+use crate::config::{Config, Overlap};
+use crate::{parse_duration, process, status};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn};
+
+static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+
+/// The jobs to run again as soon as their run ends.
+static QUEUED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// How many finished runs are remembered.
+const KEEP_RUNS: usize = 100;
+
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest request line or header read, before the token has been checked.
+const MAX_LINE: u64 = 8192;
+
+/// The most headers a request may have.
+const MAX_HEADERS: usize = 100;
+
+/// How often a streamed log is checked for more.
+const POLL: Duration = Duration::from_millis(500);
+
+/// How often the jobs are looked at, to start the ones that are due.
+const TICK: Duration = Duration::from_secs(5);
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct ServeOpt {
+    /// The address to listen on.
+    #[structopt(long = "listen", default_value = "127.0.0.1:8127")]
+    listen: String,
+    /// A file holding the token that requests must carry, as Authorization: Bearer <token>.
+    #[structopt(long = "token-file")]
+    token_file: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    /// The bearer token of the Authorization header.
+    token: Option<String>,
+}
+
+struct Run {
+    id: String,
+    job: String,
+    started: i64,
+    log: PathBuf,
+    child: Child,
+    finished: Option<i64>,
+    exit_code: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+struct RunInfo {
+    id: String,
+    job: String,
+    started: i64,
+    finished: Option<i64>,
+    running: bool,
+    exit_code: Option<i32>,
+}
+
+impl Run {
+    /// Notice if the run has ended.
+    fn refresh(&mut self) {
+        if self.finished.is_some() {
+            return;
+        }
+        if let Ok(Some(status)) = self.child.try_wait() {
+            self.finished = Some(OffsetDateTime::now_utc().timestamp());
+            self.exit_code = status.code();
+            info!(
+                "Run {} of {} finished -> {:?}",
+                self.id, self.job, self.exit_code
+            );
+        }
+    }
+
+    fn info(&self) -> RunInfo {
+        RunInfo {
+            id: self.id.clone(),
+            job: self.job.clone(),
+            started: self.started,
+            finished: self.finished,
+            running: self.finished.is_none(),
+            exit_code: self.exit_code,
+        }
+    }
+}
+
+type Runs = Arc<Mutex<Vec<Run>>>;
+
+/// How often a job was due while a run of it was still going, by what was done about it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Overlaps {
+    pub skipped: u64,
+    pub queued: u64,
+    pub restarted: u64,
+}
+
+fn overlaps_path() -> PathBuf {
+    state_dir().join("api").join("overlaps.json")
+}
+
+/// The overlaps of each job that serve has run.
+pub(crate) fn overlaps() -> BTreeMap<String, Overlaps> {
+    let path = overlaps_path();
+    match File::open(&path) {
+        Ok(f) => serde_json::from_reader(f).unwrap_or_else(|e| {
+            warn!("Failed to parse {:?} -> {:?}", path, e);
+            BTreeMap::new()
+        }),
+        Err(_) => BTreeMap::new(),
+    }
+}
+
+fn record_overlap(job: &str, overlap: Overlap) {
+    let mut all = overlaps();
+    let counts = all.entry(job.to_string()).or_default();
+    match overlap {
+        Overlap::Skip => counts.skipped += 1,
+        Overlap::Queue => counts.queued += 1,
+        Overlap::Restart => counts.restarted += 1,
+    }
+    let path = overlaps_path();
+    let tmp = path.with_extension("json.tmp");
+    let res = fs::create_dir_all(state_dir().join("api"))
+        .and_then(|()| File::create(&tmp))
+        .and_then(|f| serde_json::to_writer_pretty(f, &all).map_err(io::Error::from))
+        .and_then(|()| fs::rename(&tmp, &path));
+    if let Err(e) = res {
+        warn!("Failed to write {:?} -> {:?}", path, e);
+    }
+}
+
+/// A line of the request, of at most MAX_LINE bytes - whoever is asking, the request is read before
+/// it is authorised.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>, String> {
+    let mut line = String::new();
+    let read = reader
+        .by_ref()
+        .take(MAX_LINE)
+        .read_line(&mut line)
+        .map_err(|e| format!("unable to read the request -> {:?}", e))?;
+    let whole = (read as u64) < MAX_LINE || line.ends_with('\n');
+    Ok(whole.then_some(line))
+}
+
+/// The request, or the status and why it was refused.
+fn read_request(reader: &mut impl BufRead) -> Result<Request, (u16, String)> {
+    let line = read_line(reader)
+        .map_err(|e| (400, e))?
+        .ok_or_else(|| (400, "the request line is too long".to_string()))?;
+    let mut words = line.split_whitespace();
+    let (method, path) = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err((400, format!("invalid request line {:?}", line.trim()))),
+    };
+
+    let mut token = None;
+    let mut length = 0;
+    for _ in 0..MAX_HEADERS {
+        let header = read_line(reader)
+            .map_err(|e| (400, e))?
+            .ok_or_else(|| (431, "a header is too long".to_string()))?;
+        let header = header.trim();
+        if header.is_empty() {
+            // A body is never needed, so only read it to keep the connection sane.
+            let _ = io::copy(&mut reader.take(length.min(65536)), &mut io::sink());
+            return Ok(Request {
+                method,
+                path,
+                token,
+            });
+        }
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => continue,
+        };
+        match name.as_str() {
+            "authorization" => {
+                token = value.strip_prefix("Bearer ").map(|t| t.trim().to_string());
+            }
+            "content-length" => length = value.parse().unwrap_or(0),
+            _ => (),
+        }
+    }
+    Err((431, "too many headers".to_string()))
+}
+
+/// Does `given` match `token`? Compared in full, so the time taken gives nothing away.
+fn authorised(token: &str, given: Option<&str>) -> bool {
+    let given = match given {
+        Some(g) => g.as_bytes(),
+        None => return false,
+    };
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        431 => "Request Header Fields Too Large",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &serde_json::Value) -> u16 {
+    let body = body.to_string();
+    let auth = if status == 401 {
+        "WWW-Authenticate: Bearer\r\n"
+    } else {
+        ""
+    };
+    let res = write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\
+         Connection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        auth,
+        body
+    );
+    if let Err(e) = res {
+        debug!("unable to answer -> {:?}", e);
+    }
+    status
+}
+
+fn error_body(message: &str) -> serde_json::Value {
+    json!({ "error": message })
+}
+
+fn jobs(stream: &mut TcpStream) -> u16 {
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(_) => return respond(stream, 500, &error_body("unable to load znapper.toml")),
+    };
+    let jobs: Vec<_> = config
+        .job
+        .iter()
+        .map(|(name, job)| {
+            json!({
+                "name": name,
+                "source": job.source,
+                "to": job.to,
+                "remotes": job.remote.iter().map(|r| r.target.clone()).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    respond(stream, 200, &json!(jobs))
+}
+
+/// Start a run of `job`.
+fn spawn(runs: &mut Vec<Run>, job: &str) -> Result<RunInfo, ()> {
+    let started = OffsetDateTime::now_utc().timestamp();
+    let id = format!("{}-{}", started, NEXT_RUN.fetch_add(1, Ordering::SeqCst));
+    let dir = state_dir().join("api");
+    let log = dir.join(format!("{}.log", id));
+    let spawned = fs::create_dir_all(&dir)
+        .and_then(|_| File::create(&log))
+        .and_then(|f| Ok((f.try_clone()?, f)))
+        .and_then(|(out, err)| {
+            Command::new(std::env::current_exe()?)
+                .args(["sync", job])
+                .stdin(Stdio::null())
+                .stdout(out)
+                .stderr(err)
+                .spawn()
+        });
+    let child = spawned.map_err(|e| {
+        error!("Unable to start a run of {} -> {:?}", job, e);
+    })?;
+    info!("Started run {} of {} (pid {})", id, job, child.id());
+    let run = Run {
+        id,
+        job: job.to_string(),
+        started,
+        log,
+        child,
+        finished: None,
+        exit_code: None,
+    };
+    let info = run.info();
+    runs.push(run);
+    while runs.len() > KEEP_RUNS {
+        match runs.iter().position(|r| r.finished.is_some()) {
+            Some(i) => {
+                runs.remove(i);
+            }
+            None => break,
+        }
+    }
+    Ok(info)
+}
+
+/// What came of a job being due.
+enum Triggered {
+    Started(RunInfo),
+    /// A run of it was still going, and this was done about it.
+    Overlapped(Overlap, RunInfo),
+    Failed,
+}
+
+/// Start a run of `job`, unless one is still going - then do what `overlap` says.
+fn trigger(runs: &mut Vec<Run>, job: &str, overlap: Overlap) -> Triggered {
+    runs.iter_mut().for_each(Run::refresh);
+    let running = match runs.iter().find(|r| r.job == job && r.finished.is_none()) {
+        Some(run) => run,
+        None => {
+            return match spawn(runs, job) {
+                Ok(info) => Triggered::Started(info),
+                Err(()) => Triggered::Failed,
+            }
+        }
+    };
+    match overlap {
+        Overlap::Skip => info!(
+            "{} is due, but run {} is still going - skipping",
+            job, running.id
+        ),
+        Overlap::Queue => info!(
+            "{} is due, but run {} is still going - queueing",
+            job, running.id
+        ),
+        Overlap::Restart => {
+            info!(
+                "{} is due, but run {} is still going - restarting",
+                job, running.id
+            );
+            process::terminate(&running.child);
+        }
+    }
+    if overlap != Overlap::Skip {
+        if let Ok(mut queued) = QUEUED.lock() {
+            queued.insert(job.to_string());
+        }
+    }
+    record_overlap(job, overlap);
+    Triggered::Overlapped(overlap, running.info())
+}
+
+fn start_run(stream: &mut TcpStream, runs: &Runs, job: &str) -> u16 {
+    let overlap = match Config::load() {
+        Ok(config) => match config.job.get(job) {
+            Some(j) => j.overlap,
+            None => return respond(stream, 404, &error_body(&format!("no job {}", job))),
+        },
+        Err(_) => return respond(stream, 500, &error_body("unable to load znapper.toml")),
+    };
+    let mut runs = match runs.lock() {
+        Ok(r) => r,
+        Err(_) => return respond(stream, 500, &error_body("the runs are unavailable")),
+    };
+    match trigger(&mut runs, job, overlap) {
+        Triggered::Started(info) => respond(stream, 202, &json!(info)),
+        Triggered::Overlapped(Overlap::Skip, run) => respond(
+            stream,
+            409,
+            &json!({ "error": format!("{} is already running", job), "run": run }),
+        ),
+        Triggered::Overlapped(overlap, run) => respond(
+            stream,
+            202,
+            &json!({ "queued": job, "restarting": overlap == Overlap::Restart, "run": run }),
+        ),
+        Triggered::Failed => respond(stream, 500, &error_body("unable to start the run")),
+    }
+}
+
+/// When each job with `every` is next due.
+#[derive(Default)]
+struct Schedule {
+    due: BTreeMap<String, Instant>,
+    checked: Option<Instant>,
+}
+
+impl Schedule {
+    /// Start the queued jobs whose run has ended, and the jobs that are due.
+    fn tick(&mut self, runs: &Runs) {
+        let now = Instant::now();
+        if self.checked.map(|c| now - c < TICK).unwrap_or(false) {
+            return;
+        }
+        self.checked = Some(now);
+        let config = match Config::load() {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+        let mut runs = match runs.lock() {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        runs.iter_mut().for_each(Run::refresh);
+
+        let ready: Vec<String> = QUEUED
+            .lock()
+            .map(|mut queued| {
+                let ready: Vec<_> = queued
+                    .iter()
+                    .filter(|job| !runs.iter().any(|r| &r.job == *job && r.finished.is_none()))
+                    .cloned()
+                    .collect();
+                ready.iter().for_each(|job| {
+                    queued.remove(job);
+                });
+                ready
+            })
+            .unwrap_or_default();
+        for job in ready.iter().filter(|job| config.job.contains_key(*job)) {
+            info!("Starting the queued run of {}", job);
+            let _ = spawn(&mut runs, job);
+        }
+
+        for (name, job) in config.job.iter() {
+            let every = match job.every.as_deref().map(parse_duration) {
+                Some(Ok(every)) => every,
+                _ => continue,
+            };
+            let due = self.due.entry(name.clone()).or_insert(now + every);
+            if now < *due {
+                continue;
+            }
+            *due = now + every;
+            trigger(&mut runs, name, job.overlap);
+        }
+    }
+}
+
+/// The run `id`, refreshed, as `f` of it.
+fn with_run<T>(runs: &Runs, id: &str, f: impl FnOnce(&Run) -> T) -> Option<T> {
+    let mut runs = runs.lock().ok()?;
+    let run = runs.iter_mut().find(|r| r.id == id)?;
+    run.refresh();
+    Some(f(run))
+}
+
+fn write_chunk(stream: &mut TcpStream, chunk: &[u8]) -> io::Result<()> {
+    write!(stream, "{:x}\r\n", chunk.len())?;
+    stream.write_all(chunk)?;
+    stream.write_all(b"\r\n")
+}
+
+/// Stream the log of run `id`, until the run has ended and all of it is sent.
+fn stream_log(stream: &mut TcpStream, runs: &Runs, id: &str) -> u16 {
+    let path = match with_run(runs, id, |run| run.log.clone()) {
+        Some(p) => p,
+        None => return respond(stream, 404, &error_body(&format!("no run {}", id))),
+    };
+    let mut log = match File::open(&path) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Failed to open {:?} -> {:?}", path, e);
+            return respond(stream, 500, &error_body("unable to read the log"));
+        }
+    };
+    let res = stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\
+          Connection: close\r\n\r\n",
+    );
+    if res.is_err() {
+        return 200;
+    }
+    let mut buf = [0; 8192];
+    loop {
+        // Whatever a finished run wrote is in the file, so read to the end once more.
+        let finished = with_run(runs, id, |run| run.finished.is_some()).unwrap_or(true);
+        let n = match log.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("Failed to read {:?} -> {:?}", path, e);
+                break;
+            }
+        };
+        if n > 0 {
+            if write_chunk(stream, &buf[..n]).is_err() {
+                // The client went away.
+                return 200;
+            }
+        } else if finished || process::cancelled() {
+            break;
+        } else {
+            thread::sleep(POLL);
+        }
+    }
+    let _ = stream.write_all(b"0\r\n\r\n");
+    200
+}
+
+fn route(stream: &mut TcpStream, runs: &Runs, request: &Request) -> u16 {
+    let path = request.path.split('?').next().unwrap_or("");
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["jobs"]) => jobs(stream),
+        ("POST", ["jobs", job, "run"]) => start_run(stream, runs, job),
+        ("GET", ["status"]) | ("GET", ["status", _]) => {
+            let job = segments.get(1).copied();
+            if let Some(job) = job {
+                match Config::load() {
+                    Ok(config) if config.job.contains_key(job) => (),
+                    _ => return respond(stream, 404, &error_body(&format!("no job {}", job))),
+                }
+            }
+            match status::report(job) {
+                Ok(report) => respond(stream, 200, &json!(report)),
+                Err(_) => respond(stream, 500, &error_body("unable to report the status")),
+            }
+        }
+        ("GET", ["runs"]) => {
+            let infos = runs.lock().ok().map(|mut runs| {
+                runs.iter_mut()
+                    .map(|run| {
+                        run.refresh();
+                        run.info()
+                    })
+                    .collect::<Vec<_>>()
+            });
+            match infos {
+                Some(infos) => respond(stream, 200, &json!(infos)),
+                None => respond(stream, 500, &error_body("the runs are unavailable")),
+            }
+        }
+        ("GET", ["runs", id]) => match with_run(runs, id, Run::info) {
+            Some(info) => respond(stream, 200, &json!(info)),
+            None => respond(stream, 404, &error_body(&format!("no run {}", id))),
+        },
+        ("GET", ["runs", id, "log"]) => stream_log(stream, runs, id),
+        (_, ["jobs"] | ["jobs", _, "run"] | ["status"] | ["status", _] | ["runs", ..]) => {
+            respond(stream, 405, &error_body("method not allowed"))
+        }
+        _ => respond(stream, 404, &error_body("not found")),
+    }
+}
+
+fn handle(mut stream: TcpStream, token: &str, runs: &Runs) {
+    let peer = stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_default();
+    if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        debug!("unable to set the read timeout -> {:?}", e);
+    }
+    let request = match stream
+        .try_clone()
+        .map_err(|e| (500, format!("{:?}", e)))
+        .and_then(|s| read_request(&mut BufReader::new(s)))
+    {
+        Ok(r) => r,
+        Err((status, e)) => {
+            warn!("{} -> {}", peer, e);
+            respond(&mut stream, status, &error_body(&e));
+            return;
+        }
+    };
+    let status = if authorised(token, request.token.as_deref()) {
+        route(&mut stream, runs, &request)
+    } else {
+        respond(&mut stream, 401, &error_body("unauthorised"))
+    };
+    info!("{} {} {} -> {}", peer, request.method, request.path, status);
+}
+
+pub(crate) fn do_serve(opt: &ServeOpt) {
+    debug!("do_serve");
+
+    let token = match fs::read_to_string(&opt.token_file) {
+        Ok(t) if !t.trim().is_empty() => Arc::new(t.trim().to_string()),
+        Ok(_) => {
+            error!("{:?} holds no token", opt.token_file);
+            return;
+        }
+        Err(e) => {
+            error!("Failed to read {:?} -> {:?}", opt.token_file, e);
+            return;
+        }
+    };
+    let listener = match TcpListener::bind(&opt.listen) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Unable to listen on {} -> {:?}", opt.listen, e);
+            return;
+        }
+    };
+    // Accept without blocking, so that a signal stops the daemon.
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("Unable to listen on {} -> {:?}", opt.listen, e);
+        return;
+    }
+    info!("Serving the api on {}", opt.listen);
+    if let Ok(config) = Config::load() {
+        for (name, job) in config.job.iter() {
+            match job.every.as_deref().map(parse_duration) {
+                Some(Ok(every)) => info!("Running {} every {:?}", name, every),
+                Some(Err(e)) => error!("Not running {} on a schedule -> {}", name, e),
+                None => (),
+            }
+        }
+    }
+
+    let runs: Runs = Arc::new(Mutex::new(Vec::new()));
+    let mut schedule = Schedule::default();
+    while !process::cancelled() {
+        schedule.tick(&runs);
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = stream.set_nonblocking(false) {
+                    warn!("Unable to serve a connection -> {:?}", e);
+                    continue;
+                }
+                let (token, runs) = (Arc::clone(&token), Arc::clone(&runs));
+                let spawned = thread::Builder::new()
+                    .name("api".to_string())
+                    .spawn(move || handle(stream, &token, &runs));
+                if let Err(e) = spawned {
+                    warn!("Unable to serve a connection -> {:?}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL / 5),
+            Err(e) => {
+                warn!("Unable to accept a connection -> {:?}", e);
+                thread::sleep(POLL);
+            }
+        }
+    }
+
+    let running = runs
+        .lock()
+        .map(|mut runs| {
+            runs.iter_mut().for_each(Run::refresh);
+            runs.iter().filter(|r| r.finished.is_none()).count()
+        })
+        .unwrap_or(0);
+    if running > 0 {
+        warn!("Stopping with {} runs still going", running);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_read_and_authorised() {
+        let raw = "POST /jobs/nvme/run HTTP/1.1\r\nHost: backup1\r\n\
+                   authorization: Bearer s3cret\r\nContent-Length: 2\r\n\r\n{}";
+        let request = read_request(&mut raw.as_bytes()).unwrap();
+        assert_eq!(
+            request,
+            Request {
+                method: "POST".to_string(),
+                path: "/jobs/nvme/run".to_string(),
+                token: Some("s3cret".to_string()),
+            }
+        );
+        assert!(authorised("s3cret", request.token.as_deref()));
+        assert!(!authorised("s3cret", Some("s3cre")));
+        assert!(!authorised("s3cret", Some("s3creT")));
+        assert!(!authorised("s3cret", None));
+        assert!(read_request(&mut "\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn requests_are_read_only_so_far() {
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE as usize));
+        assert_eq!(read_request(&mut long.as_bytes()).unwrap_err().0, 400);
+        let header = format!(
+            "GET / HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_LINE as usize)
+        );
+        assert_eq!(read_request(&mut header.as_bytes()).unwrap_err().0, 431);
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X: y\r\n".repeat(MAX_HEADERS));
+        assert_eq!(read_request(&mut many.as_bytes()).unwrap_err().0, 431);
+    }
+}
@@ -0,0 +1,507 @@
+//! Connecting to remote hosts over ssh.
+//!
+//! The connection options can be given as flags, or kept with a target in `targets.toml`, with
+//! flags taking precedence. Every ssh of one run shares a single connection through a
+//! ControlMaster socket in the state directory, which is closed when the run ends.
+//!
+//! This drives the ssh binary, so that the user's ssh configuration and agent keep working. The
+//! remote command is quoted for the remote shell here, a pinned host key replaces the known hosts
+//! files, and an exit code of 255 is reported as ssh failing rather than the remote command.
+//! With `--ssh-backend native`, and a znapper built with the `native-ssh` feature, a hidden
+//! `znapper ssh-connect` stands in for the ssh binary instead, connecting with libssh2 - see
+//! sshclient.
+
+use crate::anchors::state_dir;
+use crate::compress::{self, Compression};
+use crate::sshclient;
+use crate::transport::{TlsOpt, Transport};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use structopt::StructOpt;
+use tracing::{debug, error, warn};
+
+/// ssh exits with this when it fails itself, rather than the remote command.
+pub(crate) const SSH_FAILED: i32 = 255;
+
+/// The name the pinned host key is recorded under, whatever the host is called.
+const PINNED_ALIAS: &str = "znapper-pinned";
+
+/// What connects to remotes - the ssh binary, or the ssh client built into znapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum Backend {
+    Binary,
+    Native,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(Backend::Binary),
+            "native" => Ok(Backend::Native),
+            _ => Err(format!("Invalid ssh backend {} - use binary or native", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Backend {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Backend> for String {
+    fn from(backend: Backend) -> Self {
+        match backend {
+            Backend::Binary => "binary",
+            Backend::Native => "native",
+        }
+        .to_string()
+    }
+}
+
+#[derive(Debug, Clone, Default, StructOpt, Serialize, Deserialize)]
+pub(crate) struct SshOpt {
+    /// The port to connect to.
+    #[structopt(long = "ssh-port")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// The private key to authenticate with.
+    #[structopt(long = "ssh-identity")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    /// Connect through this jump host, as ssh -J.
+    #[structopt(long = "ssh-jump")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jump: Option<String>,
+    /// An additional ssh -o option, ie Compression=no. May be repeated.
+    #[structopt(long = "ssh-option")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+    /// How many seconds to wait for the connection to be established.
+    #[structopt(long = "ssh-timeout")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u32>,
+    /// Only accept this host key, ie "ssh-ed25519 AAAA...", ignoring the known hosts files.
+    #[structopt(long = "ssh-host-key")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_key: Option<String>,
+    /// Connect with the ssh binary, or with the client built into znapper (native), which
+    /// retries a connection that fails and needs the native-ssh feature.
+    #[structopt(long = "ssh-backend")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<Backend>,
+}
+
+impl SshOpt {
+    /// These options, with any that are unset taken from `fallback`.
+    pub(crate) fn or(&self, fallback: &SshOpt) -> SshOpt {
+        SshOpt {
+            port: self.port.or(fallback.port),
+            identity: self.identity.clone().or_else(|| fallback.identity.clone()),
+            jump: self.jump.clone().or_else(|| fallback.jump.clone()),
+            options: fallback
+                .options
+                .iter()
+                .chain(self.options.iter())
+                .cloned()
+                .collect(),
+            connect_timeout: self.connect_timeout.or(fallback.connect_timeout),
+            host_key: self.host_key.clone().or_else(|| fallback.host_key.clone()),
+            backend: self.backend.or(fallback.backend),
+        }
+    }
+
+    /// The options the native backend takes, as arguments of ssh-connect.
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(port) = self.port {
+            args.extend(["--ssh-port".to_string(), port.to_string()]);
+        }
+        if let Some(identity) = self.identity.as_ref() {
+            args.extend(["--ssh-identity".to_string(), identity.clone()]);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            args.extend(["--ssh-timeout".to_string(), timeout.to_string()]);
+        }
+        if let Some(key) = self.host_key.as_ref() {
+            args.extend(["--ssh-host-key".to_string(), key.clone()]);
+        }
+        args
+    }
+}
+
+/// A remote host, and the options to reach it with.
+#[derive(Debug)]
+pub(crate) struct Ssh {
+    host: String,
+    args: Vec<String>,
+    /// Is the connection shared through a ControlMaster socket?
+    shared: bool,
+    /// The known hosts file holding the pinned host key.
+    known_hosts: Option<PathBuf>,
+    /// With remote_repl --per-host, this host and the pool it sends, named to znapper recv.
+    from: Option<(String, Option<String>)>,
+    /// With remote_repl --transport-compress, how streams are compressed for znapper recv.
+    compression: Option<Compression>,
+    /// With remote_repl --chunked, the size of the chunks streams are sent in.
+    chunk_size: Option<u64>,
+    /// With remote_repl --transport, the serve-recv connected to instead of ssh.
+    transport: Option<Box<(Transport, TlsOpt)>>,
+    /// With --ssh-backend native, the options ssh-connect is run with instead of ssh.
+    native: Option<Box<SshOpt>>,
+}
+
+/// This znapper, to run ssh-connect and transport-connect with.
+fn znapper() -> PathBuf {
+    std::env::current_exe().unwrap_or_else(|_| PathBuf::from("znapper"))
+}
+
+/// Quote `arg` for the remote shell, which ssh passes the command to as a single string.
+pub(crate) fn quote(arg: &str) -> Cow<'_, str> {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@%+=:,./_-#".contains(c));
+    if safe {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', "'\\''")))
+    }
+}
+
+impl Ssh {
+    pub(crate) fn new(host: &str, opt: &SshOpt) -> Result<Self, ()> {
+        if opt.backend == Some(Backend::Native) {
+            // It never prompts, as with BatchMode.
+            let unsupported = opt.options.iter().any(|o| o != "BatchMode=yes");
+            if opt.jump.is_some() || unsupported {
+                error!("--ssh-jump and --ssh-option need --ssh-backend binary");
+                return Err(());
+            }
+            if sshclient::built() {
+                return Ok(Ssh {
+                    host: host.to_string(),
+                    args: Vec::new(),
+                    shared: false,
+                    known_hosts: None,
+                    from: None,
+                    compression: None,
+                    chunk_size: None,
+                    transport: None,
+                    native: Some(Box::new(opt.clone())),
+                });
+            }
+            warn!("znapper was built without the native-ssh feature, running ssh instead");
+        }
+
+        let mut args = Vec::new();
+        if let Some(port) = opt.port {
+            args.extend(["-p".to_string(), port.to_string()]);
+        }
+        if let Some(identity) = opt.identity.as_ref() {
+            args.extend(["-i".to_string(), identity.clone()]);
+        }
+        if let Some(jump) = opt.jump.as_ref() {
+            args.extend(["-J".to_string(), jump.clone()]);
+        }
+        if let Some(timeout) = opt.connect_timeout {
+            args.extend(["-o".to_string(), format!("ConnectTimeout={}", timeout)]);
+        }
+        for option in opt.options.iter() {
+            args.extend(["-o".to_string(), option.clone()]);
+        }
+
+        // Without a private directory for the socket, each ssh connects separately.
+        let dir = state_dir().join("ssh");
+        let created = fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir);
+
+        let known_hosts = match (opt.host_key.as_ref(), created.as_ref()) {
+            (Some(key), Ok(())) => {
+                static COUNT: AtomicUsize = AtomicUsize::new(0);
+                let path = dir.join(format!(
+                    "{}-{}.known_hosts",
+                    std::process::id(),
+                    COUNT.fetch_add(1, Ordering::Relaxed)
+                ));
+                fs::write(&path, format!("{} {}\n", PINNED_ALIAS, key.trim())).map_err(|e| {
+                    error!("Failed to write {:?} -> {:?}", path, e);
+                })?;
+                args.extend([
+                    "-o".to_string(),
+                    format!("HostKeyAlias={}", PINNED_ALIAS),
+                    "-o".to_string(),
+                    format!("UserKnownHostsFile={}", path.display()),
+                    "-o".to_string(),
+                    "GlobalKnownHostsFile=/dev/null".to_string(),
+                    "-o".to_string(),
+                    "StrictHostKeyChecking=yes".to_string(),
+                ]);
+                Some(path)
+            }
+            (Some(_), Err(e)) => {
+                error!(
+                    "Unable to create {:?} to pin the host key of {} -> {:?}",
+                    dir, host, e
+                );
+                return Err(());
+            }
+            (None, _) => None,
+        };
+
+        let shared = match created {
+            Ok(()) => {
+                let path = format!("{}/{}-%C", dir.display(), std::process::id());
+                args.extend([
+                    "-o".to_string(),
+                    "ControlMaster=auto".to_string(),
+                    "-o".to_string(),
+                    format!("ControlPath={}", path),
+                    "-o".to_string(),
+                    "ControlPersist=60".to_string(),
+                ]);
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to create {:?} for ssh connection sharing -> {:?}",
+                    dir, e
+                );
+                false
+            }
+        };
+
+        Ok(Ssh {
+            host: host.to_string(),
+            args,
+            shared,
+            known_hosts,
+            from: None,
+            compression: None,
+            chunk_size: None,
+            transport: None,
+            native: None,
+        })
+    }
+
+    /// Name `host` and its `pool` to the receiver's znapper recv, which keeps what each host
+    /// sends apart.
+    pub(crate) fn per_host(&mut self, host: &str, pool: Option<&str>) {
+        self.from = Some((host.to_string(), pool.map(str::to_string)));
+    }
+
+    /// Compress the streams this sends with `compression`, for znapper recv to decompress.
+    pub(crate) fn compress(&mut self, compression: Compression) {
+        self.compression = Some(compression);
+    }
+
+    /// Send streams in chunks of `size`, spooled locally, for znapper recv to reassemble.
+    pub(crate) fn chunked(&mut self, size: u64) {
+        self.chunk_size = Some(size);
+    }
+
+    /// The size of the chunks streams are sent in, if they are.
+    pub(crate) fn chunk_size(&self) -> Option<u64> {
+        self.chunk_size
+    }
+
+    /// Connect to the serve-recv at `transport` instead, with `tls` for a TLS one.
+    pub(crate) fn over(&mut self, transport: Transport, tls: TlsOpt) {
+        // There is no ssh connection to share, or close.
+        self.shared = false;
+        self.transport = Some(Box::new((transport, tls)));
+    }
+
+    /// How the streams this sends are compressed, if they are.
+    pub(crate) fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// `remote`, as znapper recv is asked for it with --per-host (and --transport-compress). Anything else, like a zfs
+    /// command, is run as it is.
+    fn remote<'a>(&'a self, remote: &[&'a str]) -> Vec<&'a str> {
+        // Only what is received is compressed.
+        let zstd = match (self.compression, remote) {
+            (Some(Compression::Zstd(_)), [] | ["recv", _] | ["assemble", ..]) => {
+                vec![compress::ZSTD]
+            }
+            _ => Vec::new(),
+        };
+        let (host, pool) = match self.from.as_ref() {
+            Some((host, pool)) => (host.as_str(), pool.as_deref()),
+            None if zstd.is_empty() => return remote.to_vec(),
+            None => return zstd.into_iter().chain(remote.iter().copied()).collect(),
+        };
+        let mut args = vec!["from", host];
+        args.extend(zstd);
+        match (remote, pool) {
+            ([], Some(pool)) => args.extend(["recv", pool]),
+            ([verb @ ("snapshots" | "partial")], Some(pool)) => args.extend([*verb, pool]),
+            ([], None) | (["space" | "snapshots" | "partial"], _) => args.extend(remote),
+            (["recv" | "snapshots" | "partial", _], _) => args.extend(remote),
+            (["assemble", id, count], Some(pool)) => args.extend(["assemble", id, count, pool]),
+            (["chunks" | "chunk" | "assemble", ..], _) => args.extend(remote),
+            _ => return remote.to_vec(),
+        }
+        args
+    }
+
+    /// `ssh <options> <host> <remote>`, with each arg of `remote` quoted. An empty `remote`
+    /// runs the forced command, if the key has one. With a transport, `znapper
+    /// transport-connect` sends `remote` to serve-recv instead.
+    pub(crate) fn command(&self, remote: &[&str]) -> Command {
+        if let Some((transport, tls)) = self.transport.as_deref() {
+            let mut cmd = Command::new(znapper());
+            cmd.arg("transport-connect")
+                .arg(transport.to_string())
+                .args(tls.args())
+                .arg("--")
+                .args(self.remote(remote));
+            return cmd;
+        }
+        if let Some(opt) = self.native.as_deref() {
+            let mut cmd = Command::new(znapper());
+            cmd.arg("ssh-connect")
+                .args(opt.args())
+                .arg(&self.host)
+                .arg("--")
+                .args(self.remote(remote));
+            return cmd;
+        }
+        let mut cmd = Command::new("ssh");
+        cmd.args(&self.args).arg(&self.host).args(
+            self.remote(remote)
+                .iter()
+                .map(|arg| quote(arg).into_owned()),
+        );
+        cmd
+    }
+
+    /// The same as `command`, as a list of args for a pipeline.
+    pub(crate) fn argv(&self, remote: &[&str]) -> Vec<String> {
+        if let Some(opt) = self.native.as_deref() {
+            return [znapper().display().to_string(), "ssh-connect".to_string()]
+                .into_iter()
+                .chain(opt.args())
+                .chain([self.host.clone(), "--".to_string()])
+                .chain(self.remote(remote).iter().map(|arg| arg.to_string()))
+                .collect();
+        }
+        std::iter::once("ssh".to_string())
+            .chain(self.args.iter().cloned())
+            .chain(std::iter::once(self.host.clone()))
+            .chain(
+                self.remote(remote)
+                    .iter()
+                    .map(|arg| quote(arg).into_owned()),
+            )
+            .collect()
+    }
+
+    /// Why the ssh exited with `status` - ssh itself failing, or the remote command.
+    pub(crate) fn describe_failure(&self, status: ExitStatus) -> String {
+        match status.code() {
+            Some(SSH_FAILED) => match self.transport.as_deref() {
+                Some((transport, _)) => format!("connecting to {} failed", transport),
+                None => format!("ssh to {} failed", self.host),
+            },
+            Some(code) => format!("exited with {} on {}", code, self.host),
+            None => format!("ssh to {} was killed", self.host),
+        }
+    }
+}
+
+impl fmt::Display for Ssh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.host)
+    }
+}
+
+impl Drop for Ssh {
+    fn drop(&mut self) {
+        if self.shared {
+            debug!("closing ssh connection to {}", self.host);
+            // The socket name is a hash of the connection options, so pass them all again. If
+            // no connection was ever made this fails, which is fine.
+            let _ = Command::new("ssh")
+                .args(&self.args)
+                .arg("-O")
+                .arg("exit")
+                .arg(&self.host)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        }
+        if let Some(path) = self.known_hosts.as_ref() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_native_backend_takes_only_what_it_supports() {
+        assert_eq!("native".parse(), Ok(Backend::Native));
+        assert!("openssh".parse::<Backend>().is_err());
+        let jump = SshOpt {
+            backend: Some(Backend::Native),
+            jump: Some("bastion".to_string()),
+            ..Default::default()
+        };
+        assert!(Ssh::new("backup1", &jump).is_err());
+        let opt = SshOpt {
+            port: Some(2222),
+            host_key: Some("ssh-ed25519 AAAA".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            opt.args(),
+            ["--ssh-port", "2222", "--ssh-host-key", "ssh-ed25519 AAAA"]
+        );
+    }
+
+    #[cfg(feature = "native-ssh")]
+    #[test]
+    fn the_native_backend_runs_ssh_connect_unquoted() {
+        let opt = SshOpt {
+            backend: Some(Backend::Native),
+            port: Some(2222),
+            ..Default::default()
+        };
+        let argv = Ssh::new("backup1", &opt)
+            .unwrap()
+            .argv(&["zfs", "list", "tank/a b"]);
+        assert_eq!(
+            argv[1..],
+            [
+                "ssh-connect",
+                "--ssh-port",
+                "2222",
+                "backup1",
+                "--",
+                "zfs",
+                "list",
+                "tank/a b"
+            ]
+        );
+    }
+}
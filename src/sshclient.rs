@@ -0,0 +1,409 @@
+//! The native ssh backend - remote commands run over a connection znapper makes itself with
+//! libssh2, rather than by the ssh binary.
+//!
+//! With `--ssh-backend native` (or `backend = "native"` in a target's connection), and a znapper
+//! built with the `native-ssh` feature, each remote command is run by a hidden `znapper
+//! ssh-connect`, which stands in for ssh as transport-connect does for serve-recv - everything
+//! ssh is used for runs unchanged. It connects to `--ssh-port`, within `--ssh-timeout`, and
+//! retries a connection that fails or is dropped before the command starts, backing off between
+//! attempts. The host key must be the pinned `--ssh-host-key`, or else in the known hosts files,
+//! and it authenticates with `--ssh-identity`, or else the agent and the default keys. The
+//! command's stdout and stderr are kept apart, and it exits with the command's exit code, or 255
+//! when the connection fails, as ssh does.
+//!
+//! There is no ssh configuration, ControlMaster or jump host - those need the ssh binary. A
+//! connection that drops once the command has started isn't retried here, as its stdin is gone;
+//! that is left to `--retries`.
+
+use crate::ssh::{SshOpt, SSH_FAILED};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[cfg_attr(not(feature = "native-ssh"), allow(dead_code))]
+pub(crate) struct SshConnectOpt {
+    #[structopt(flatten)]
+    ssh: SshOpt,
+    /// The host to connect to, as [user@]host.
+    host: String,
+    /// The remote command, unquoted.
+    command: Vec<String>,
+}
+
+/// Was znapper built with the native backend?
+pub(crate) fn built() -> bool {
+    cfg!(feature = "native-ssh")
+}
+
+/// `znapper ssh-connect`, run in place of ssh with `--ssh-backend native`. Returns the exit code.
+pub(crate) fn do_connect(opt: &SshConnectOpt) -> i32 {
+    match client::run(opt) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("znapper: ssh to {} failed -> {}", opt.host, e);
+            SSH_FAILED
+        }
+    }
+}
+
+#[cfg(feature = "native-ssh")]
+mod client {
+    use super::SshConnectOpt;
+    use crate::approval;
+    use crate::ssh::{quote, SSH_FAILED};
+    use ssh2::{CheckResult, KnownHostFileKind, Session};
+    use std::io::{self, Read, Write};
+    use std::net::{TcpStream, ToSocketAddrs};
+    use std::os::raw::{c_int, c_short, c_ulong};
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+    use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+    use std::thread;
+    use std::time::Duration;
+
+    extern "C" {
+        fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+    }
+
+    #[repr(C)]
+    struct PollFd {
+        fd: c_int,
+        events: c_short,
+        revents: c_short,
+    }
+
+    const POLLIN: c_short = 1;
+    const POLLOUT: c_short = 4;
+
+    /// How many times a connection is tried.
+    const ATTEMPTS: u32 = 4;
+
+    /// The wait before the second attempt, doubled for each after it.
+    const BACKOFF: Duration = Duration::from_secs(1);
+
+    /// How long to wait for the connection or stdin, when neither has anything.
+    const IDLE_MS: c_int = 10;
+
+    /// What is read at a time.
+    const READ: usize = 256 * 1024;
+
+    /// The default keys, in the order ssh tries them.
+    const KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+    /// Why a connection failed - Retry for what may work the next time, like a refused or
+    /// dropped connection, and Fatal for what won't, like a host key that doesn't match.
+    enum Failure {
+        Retry(String),
+        Fatal(String),
+    }
+
+    fn home() -> PathBuf {
+        std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/root"))
+    }
+
+    /// Check the host key of `session` against the pinned one, or the known hosts files.
+    fn check_host_key(
+        session: &Session,
+        host: &str,
+        port: u16,
+        pinned: Option<&str>,
+    ) -> Result<(), Failure> {
+        let fatal = |e: ssh2::Error| Failure::Fatal(e.to_string());
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| Failure::Fatal("the host sent no key".to_string()))?;
+        let mut known = session.known_hosts().map_err(fatal)?;
+        let check = match pinned {
+            Some(pinned) => {
+                known
+                    .read_str(
+                        &format!("{} {}", host, pinned.trim()),
+                        KnownHostFileKind::OpenSSH,
+                    )
+                    .map_err(fatal)?;
+                known.check(host, key)
+            }
+            None => {
+                for file in [
+                    home().join(".ssh/known_hosts"),
+                    PathBuf::from("/etc/ssh/ssh_known_hosts"),
+                ] {
+                    if file.exists() {
+                        let _ = known.read_file(&file, KnownHostFileKind::OpenSSH);
+                    }
+                }
+                known.check_port(host, port, key)
+            }
+        };
+        match (check, pinned) {
+            (CheckResult::Match, _) => Ok(()),
+            (CheckResult::Mismatch, Some(_)) | (CheckResult::NotFound, Some(_)) => Err(
+                Failure::Fatal(format!("the host key of {} is not the pinned key", host)),
+            ),
+            (CheckResult::Mismatch, None) => Err(Failure::Fatal(format!(
+                "the host key of {} does not match the known hosts",
+                host
+            ))),
+            (CheckResult::NotFound, None) => Err(Failure::Fatal(format!(
+                "{} is not in the known hosts - add it, or pin its key with --ssh-host-key",
+                host
+            ))),
+            (CheckResult::Failure, _) => Err(Failure::Fatal(format!(
+                "unable to check the host key of {}",
+                host
+            ))),
+        }
+    }
+
+    /// Authenticate as `user`, with `identity` or else the agent and the default keys.
+    fn authenticate(session: &Session, user: &str, identity: Option<&str>) -> Result<(), Failure> {
+        match identity {
+            Some(identity) => {
+                let _ = session.userauth_pubkey_file(user, None, identity.as_ref(), None);
+            }
+            None => {
+                if session.userauth_agent(user).is_err() {
+                    for key in KEYS {
+                        let path = home().join(".ssh").join(key);
+                        if path.exists()
+                            && session
+                                .userauth_pubkey_file(user, None, &path, None)
+                                .is_ok()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        if session.authenticated() {
+            Ok(())
+        } else {
+            Err(Failure::Fatal(format!(
+                "unable to authenticate as {}",
+                user
+            )))
+        }
+    }
+
+    /// One attempt at a connection, authenticated, and the fd of its socket.
+    fn connect(opt: &SshConnectOpt) -> Result<(Session, c_int), Failure> {
+        let (user, host) = match opt.host.split_once('@') {
+            Some((user, host)) => (user.to_string(), host),
+            None => (approval::login(), opt.host.as_str()),
+        };
+        let port = opt.ssh.port.unwrap_or(22);
+        let retry = |e: io::Error| Failure::Retry(e.to_string());
+        let addrs: Vec<_> = (host, port).to_socket_addrs().map_err(retry)?.collect();
+        let mut last = io::Error::other(format!("{} has no address", host));
+        let mut tcp = None;
+        for addr in addrs {
+            let connected = match opt.ssh.connect_timeout {
+                Some(secs) => {
+                    TcpStream::connect_timeout(&addr, Duration::from_secs(u64::from(secs)))
+                }
+                None => TcpStream::connect(addr),
+            };
+            match connected {
+                Ok(stream) => {
+                    tcp = Some(stream);
+                    break;
+                }
+                Err(e) => last = e,
+            }
+        }
+        let tcp = tcp.ok_or_else(|| retry(last))?;
+        let fd = tcp.as_raw_fd();
+
+        let mut session = Session::new().map_err(|e| Failure::Fatal(e.to_string()))?;
+        if let Some(secs) = opt.ssh.connect_timeout {
+            session.set_timeout(secs.saturating_mul(1000));
+        }
+        session.set_tcp_stream(tcp);
+        session
+            .handshake()
+            .map_err(|e| Failure::Retry(e.to_string()))?;
+        check_host_key(&session, host, port, opt.ssh.host_key.as_deref())?;
+        authenticate(&session, &user, opt.ssh.identity.as_deref())?;
+        session.set_timeout(0);
+        Ok((session, fd))
+    }
+
+    /// A connection, retrying those that fail with Retry.
+    fn connect_retrying(opt: &SshConnectOpt) -> Result<(Session, c_int), String> {
+        let mut backoff = BACKOFF;
+        for attempt in 1.. {
+            match connect(opt) {
+                Ok(connected) => return Ok(connected),
+                Err(Failure::Retry(e)) if attempt < ATTEMPTS => {
+                    eprintln!(
+                        "znapper: connecting to {} failed, retrying in {:?} -> {}",
+                        opt.host, backoff, e
+                    );
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(Failure::Retry(e) | Failure::Fatal(e)) => return Err(e),
+            }
+        }
+        Err(format!("no connection to {}", opt.host))
+    }
+
+    /// Wait for the socket `fd` to be ready for what `session` is waiting on.
+    fn wait(session: &Session, fd: c_int) {
+        let directions = session.block_directions();
+        let mut events = POLLIN;
+        if matches!(
+            directions,
+            ssh2::BlockDirections::Outbound | ssh2::BlockDirections::Both
+        ) {
+            events |= POLLOUT;
+        }
+        let mut fds = PollFd {
+            fd,
+            events,
+            revents: 0,
+        };
+        unsafe { poll(&mut fds, 1, IDLE_MS) };
+    }
+
+    fn would_block(e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::WouldBlock
+    }
+
+    /// Run the command, copying our stdin to it and its stdout and stderr to ours. Returns its
+    /// exit code.
+    pub(super) fn run(opt: &SshConnectOpt) -> Result<i32, String> {
+        let (session, fd) = connect_retrying(opt)?;
+        let command = opt
+            .command
+            .iter()
+            .map(|arg| quote(arg).into_owned())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut channel = session.channel_session().map_err(|e| e.to_string())?;
+        channel.exec(&command).map_err(|e| e.to_string())?;
+
+        // stdin blocks, so it is read in a thread of its own and handed over a few reads at a time.
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(8);
+        thread::spawn(move || {
+            let mut stdin = io::stdin().lock();
+            loop {
+                let mut buf = vec![0; READ];
+                match stdin.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        if tx.send(buf).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => return,
+                }
+            }
+        });
+
+        session.set_blocking(false);
+        let mut stdout = io::stdout().lock();
+        let mut stderr = io::stderr().lock();
+        let mut buf = vec![0; READ];
+        let mut pending: Option<(Vec<u8>, usize)> = None;
+        let mut input = true;
+        let mut eof = false;
+        loop {
+            let mut busy = false;
+            if pending.is_none() && input {
+                match rx.try_recv() {
+                    Ok(data) => pending = Some((data, 0)),
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => {
+                        input = false;
+                        eof = true;
+                    }
+                }
+            }
+            if let Some((data, sent)) = pending.as_mut() {
+                match channel.write(&data[*sent..]) {
+                    Ok(n) => {
+                        busy = true;
+                        *sent += n;
+                        if *sent == data.len() {
+                            pending = None;
+                        }
+                    }
+                    Err(e) if would_block(&e) => {}
+                    // The command stopped reading, and what it said about it follows.
+                    Err(_) => {
+                        pending = None;
+                        input = false;
+                    }
+                }
+            }
+            if eof && pending.is_none() {
+                match channel.send_eof().map_err(io::Error::from) {
+                    Err(e) if would_block(&e) => {}
+                    _ => eof = false,
+                }
+            }
+            match channel.read(&mut buf) {
+                Ok(0) => {}
+                Ok(n) => {
+                    busy = true;
+                    stdout.write_all(&buf[..n]).map_err(|e| e.to_string())?;
+                }
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e.to_string()),
+            }
+            match channel.stderr().read(&mut buf) {
+                Ok(0) => {}
+                Ok(n) => {
+                    busy = true;
+                    stderr.write_all(&buf[..n]).map_err(|e| e.to_string())?;
+                }
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e.to_string()),
+            }
+            if busy {
+                continue;
+            }
+            if channel.eof() {
+                break;
+            }
+            if pending.is_none() && input {
+                match rx.recv_timeout(Duration::from_millis(IDLE_MS as u64)) {
+                    Ok(data) => pending = Some((data, 0)),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        input = false;
+                        eof = true;
+                    }
+                }
+            } else {
+                wait(&session, fd);
+            }
+        }
+        stdout.flush().map_err(|e| e.to_string())?;
+
+        session.set_blocking(true);
+        channel.wait_close().map_err(|e| e.to_string())?;
+        let signalled = channel
+            .exit_signal()
+            .map(|s| s.exit_signal.is_some())
+            .unwrap_or(false);
+        if signalled {
+            return Ok(SSH_FAILED);
+        }
+        channel.exit_status().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(not(feature = "native-ssh"))]
+mod client {
+    use super::SshConnectOpt;
+
+    pub(super) fn run(_opt: &SshConnectOpt) -> Result<i32, String> {
+        Err("znapper was built without the native-ssh feature".to_string())
+    }
+}
@@ -0,0 +1,723 @@
+//! How far behind each replication is, and how its last run went.
+//!
+//! Every repl and remote_repl records its outcome per destination in `runs.json` in the state
+//! directory. `znapper status` combines that with what the datasets hold now - the newest
+//! snapshot the source and destination have in common, and how many auto snapshots the source
+//! has taken since - for each source and destination pair of the jobs in `znapper.toml`, and for
+//! any other destination that has run, with the health of the source pool.
+//!
+//! `znapper check-lag` reports the same for one job as a monitoring plugin would - one line of
+//! status on stdout, and the exit code of Nagios (0 ok, 1 warning, 2 critical, 3 unknown) - so
+//! Nagios, Icinga or Zabbix can watch znapper without a script of their own.
+//!
+//! On a backup server that several hosts replicate to, with `znapper recv --pool
+//! backups/%hostname%`, `znapper status --hosts backups` instead reports per host how long ago
+//! the newest snapshot it holds was taken, as the server has no runs of its own to go by.
+
+use crate::anchors::{state_dir, Owner};
+use crate::config::Config;
+use crate::metadata::MetadataFile;
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use crate::{expand_dest_path, parse_duration, repl_guid_list, short_name, snapshot_guid_list};
+use crate::{zed, OutputFormat};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, info};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct StatusOpt {
+    /// Only report this job.
+    job: Option<String>,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+    /// On the backup server, report the lag of each host received under this dataset, ie
+    /// backups for znapper recv --pool backups/%hostname%.
+    #[structopt(long = "hosts", conflicts_with = "job")]
+    hosts: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct CheckLagOpt {
+    /// The job of znapper.toml to check.
+    #[structopt(long = "job")]
+    job: String,
+    /// Warn when a destination is further behind than this, ie 26h or 2d.
+    #[structopt(long = "warn", parse(try_from_str = parse_duration))]
+    warn: Duration,
+    /// Be critical when a destination is further behind than this.
+    #[structopt(long = "crit", parse(try_from_str = parse_duration))]
+    crit: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Run {
+    #[serde(flatten)]
+    pub owner: Owner,
+    pub source: String,
+    pub last_run: i64,
+    pub succeeded: bool,
+    #[serde(default)]
+    pub last_success: Option<i64>,
+    /// How many runs have failed, ever.
+    #[serde(default)]
+    pub failures: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct RunStore {
+    pub runs: Vec<Run>,
+}
+
+impl RunStore {
+    fn path() -> PathBuf {
+        state_dir().join("runs.json")
+    }
+
+    pub(crate) fn load() -> Result<Self, ()> {
+        let path = Self::path();
+        match File::open(&path) {
+            Ok(f) => serde_json::from_reader(f).map_err(|e| {
+                error!("Failed to parse {:?} -> {:?}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(RunStore::default()),
+            Err(e) => {
+                error!("Failed to open {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), ()> {
+        let path = Self::path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                error!("Failed to create state dir {:?} -> {:?}", parent, e);
+            })?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        let f = File::create(&tmp).map_err(|e| {
+            error!("Failed to create {:?} -> {:?}", tmp, e);
+        })?;
+        serde_json::to_writer_pretty(&f, self).map_err(|e| {
+            error!("Failed to write {:?} -> {:?}", tmp, e);
+        })?;
+        fs::rename(&tmp, &path).map_err(|e| {
+            error!("Failed to replace {:?} -> {:?}", path, e);
+        })
+    }
+
+    fn get(&self, owner: &Owner) -> Option<&Run> {
+        self.runs.iter().find(|r| &r.owner == owner)
+    }
+}
+
+/// Record the outcome of a run of `owner`, replicating from `source`.
+pub(crate) fn record(dry: bool, owner: &Owner, source: &str, succeeded: bool) {
+    if dry {
+        info!(
+            "dryrun: record run of {} to {} -> {}",
+            owner.flow,
+            owner.destination,
+            if succeeded { "ok" } else { "failed" }
+        );
+        return;
+    }
+    let mut store = match RunStore::load() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let now = OffsetDateTime::now_utc().timestamp();
+    let previous = store.get(owner);
+    let last_success = if succeeded {
+        Some(now)
+    } else {
+        previous.and_then(|r| r.last_success)
+    };
+    let failures = previous.map(|r| r.failures).unwrap_or(0) + u64::from(!succeeded);
+    store.runs.retain(|r| &r.owner != owner);
+    store.runs.push(Run {
+        owner: owner.clone(),
+        source: source.to_string(),
+        last_run: now,
+        succeeded,
+        last_success,
+        failures,
+    });
+    let _ = store.save();
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PairStatus {
+    job: Option<String>,
+    flow: String,
+    source: String,
+    destination: String,
+    /// The newest snapshot both sides hold.
+    common: Option<String>,
+    /// Seconds since the last successful run.
+    lag: Option<i64>,
+    /// Auto snapshots on the source newer than the common snapshot.
+    pending: Option<usize>,
+    last_run: Option<String>,
+    last_result: Option<String>,
+    /// The health of the source pool, ie ONLINE or DEGRADED.
+    health: Option<String>,
+    /// Whether replication from the source is paused, as its pool is not healthy.
+    paused: bool,
+}
+
+/// The snapshots of `dataset` (not its children) as (name, creation).
+fn creation_list(dataset: &str) -> Result<Vec<(String, i64)>, ()> {
+    let output = privilege::zfs()
+        .args([
+            "list",
+            "-H",
+            "-p",
+            "-t",
+            "snapshot",
+            "-o",
+            "name,creation",
+            "-d",
+            "1",
+            dataset,
+        ])
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("snapshot list failed -> {:?}", e);
+        })?;
+    if !output.status.success() {
+        debug!("snapshot list of {} failed", dataset);
+        return Err(());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (name, creation) = line.split_once('\t')?;
+            Some((name.to_string(), creation.trim().parse().ok()?))
+        })
+        .collect())
+}
+
+/// How many auto snapshots of `source` are newer than its snapshot `short`.
+fn pending(source: &str, short: &str) -> Option<usize> {
+    let snaps = creation_list(source).ok()?;
+    let since = snaps
+        .iter()
+        .find(|(name, _)| short_name(name) == short)
+        .map(|(_, creation)| *creation)?;
+    Some(
+        snaps
+            .iter()
+            .filter(|(name, creation)| *creation > since && short_name(name).starts_with("auto_"))
+            .count(),
+    )
+}
+
+/// The newest snapshot of `destination` that `source` also has, as a snapshot or a repl_
+/// bookmark of the same guid.
+fn local_common(source: &str, destination: &str) -> Option<String> {
+    let mut source_guids: Vec<_> = snapshot_guid_list(source)
+        .ok()?
+        .into_iter()
+        .map(|(_, guid)| guid)
+        .collect();
+    if let Ok(bookmarks) = repl_guid_list(source, "bookmark") {
+        source_guids.extend(bookmarks.into_iter().map(|(_, guid)| guid));
+    }
+    snapshot_guid_list(destination)
+        .ok()?
+        .into_iter()
+        .rev()
+        .find(|(_, guid)| source_guids.contains(guid))
+        .map(|(name, _)| name)
+}
+
+/// The snapshot remote_repl last sent from, as recorded by its metadata - per dataset, that of
+/// `source` - and of the destinations of the file, the newest.
+fn remote_common(metadata: &str, source: &str) -> Option<String> {
+    let file = MetadataFile::load(metadata).ok()?;
+    file.entries()
+        .filter_map(|(_, meta)| {
+            if meta.precursor_snap.is_empty() {
+                meta.datasets.get(source).cloned()
+            } else {
+                Some(meta.precursor_snap.clone())
+            }
+        })
+        .max_by(|a, b| short_name(a).cmp(short_name(b)))
+}
+
+fn format_ts(ts: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(ts).format("%Y-%m-%dT%H:%M:%SZ")
+}
+
+/// `seconds` as ie 2d4h, 3h12m or 45s.
+pub(crate) fn format_lag(seconds: i64) -> String {
+    let (d, h, m) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    if d > 0 {
+        format!("{}d{}h", d, h)
+    } else if h > 0 {
+        format!("{}h{}m", h, m)
+    } else if m > 0 {
+        format!("{}m", m)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+fn pair_status(
+    job: Option<&str>,
+    owner: &Owner,
+    source: &str,
+    runs: &RunStore,
+    health: &BTreeMap<String, String>,
+) -> PairStatus {
+    let common = if owner.flow == "remote_repl" {
+        remote_common(&owner.destination, source)
+    } else {
+        local_common(source, &owner.destination)
+    };
+    let now = OffsetDateTime::now_utc().timestamp();
+    let run = runs.get(owner);
+    PairStatus {
+        job: job.map(str::to_string),
+        flow: owner.flow.clone(),
+        source: source.to_string(),
+        destination: owner.destination.clone(),
+        pending: common
+            .as_deref()
+            .and_then(|c| pending(source, short_name(c))),
+        common,
+        lag: run.and_then(|r| r.last_success).map(|ts| now - ts),
+        last_run: run.map(|r| format_ts(r.last_run)),
+        last_result: run.map(|r| if r.succeeded { "ok" } else { "failed" }.to_string()),
+        health: health
+            .get(source.split('/').next().unwrap_or(source))
+            .cloned(),
+        paused: zed::paused(source).is_some(),
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct HostStatus {
+    host: String,
+    /// The datasets received from the host, at least one snapshot each.
+    datasets: usize,
+    /// The newest snapshot received from the host.
+    newest: String,
+    /// Seconds since the newest snapshot was taken.
+    lag: i64,
+}
+
+/// Group `zfs list -H -p -o name,creation` of the snapshots under `root` by the host each is
+/// kept under, the first dataset beneath `root`.
+fn host_status(root: &str, stdout: &str, now: i64) -> Vec<HostStatus> {
+    let mut hosts: Vec<(HostStatus, Vec<&str>)> = Vec::new();
+    for line in stdout.lines() {
+        let (name, creation) = match line
+            .split_once('\t')
+            .and_then(|(name, creation)| Some((name, creation.trim().parse::<i64>().ok()?)))
+        {
+            Some(s) => s,
+            None => continue,
+        };
+        let dataset = name.split('@').next().unwrap_or(name);
+        let host = match dataset
+            .strip_prefix(root)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .and_then(|rest| rest.split('/').next())
+        {
+            Some(host) => host,
+            None => continue,
+        };
+        let i = match hosts.iter().position(|(h, _)| h.host == host) {
+            Some(i) => i,
+            None => {
+                hosts.push((
+                    HostStatus {
+                        host: host.to_string(),
+                        datasets: 0,
+                        newest: name.to_string(),
+                        lag: now - creation,
+                    },
+                    Vec::new(),
+                ));
+                hosts.len() - 1
+            }
+        };
+        let (status, datasets) = &mut hosts[i];
+        if !datasets.contains(&dataset) {
+            datasets.push(dataset);
+            status.datasets += 1;
+        }
+        if now - creation < status.lag {
+            status.lag = now - creation;
+            status.newest = name.to_string();
+        }
+    }
+    hosts.into_iter().map(|(status, _)| status).collect()
+}
+
+fn do_host_status(root: &str, format: OutputFormat) {
+    let output = privilege::zfs()
+        .args([
+            "list",
+            "-H",
+            "-p",
+            "-t",
+            "snapshot",
+            "-o",
+            "name,creation",
+            "-r",
+            root,
+        ])
+        .run_output(Kind::Zfs);
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            error!(
+                "snapshot list of {} failed -> {}",
+                root,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return;
+        }
+        Err(e) => {
+            error!("snapshot list failed -> {:?}", e);
+            return;
+        }
+    };
+    let report = host_status(
+        root,
+        &String::from_utf8_lossy(&output.stdout),
+        OffsetDateTime::now_utc().timestamp(),
+    );
+
+    match format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&report) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise status -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for host in report {
+                println!(
+                    "{}\tdatasets={}\tnewest={}\tlag={}",
+                    host.host,
+                    host.datasets,
+                    host.newest,
+                    format_lag(host.lag)
+                );
+            }
+        }
+    }
+}
+
+/// The status of each source and destination pair of the jobs (or the job `only`), and of any
+/// other destination that has run.
+pub(crate) fn report(only: Option<&str>) -> Result<Vec<PairStatus>, ()> {
+    let config = Config::load()?;
+    let runs = RunStore::load()?;
+
+    if let Some(job) = only {
+        if !config.job.contains_key(job) {
+            error!("No job {} in znapper.toml", job);
+            return Err(());
+        }
+    }
+
+    let mut pairs = Vec::new();
+    for (name, job) in config.job.iter() {
+        if only.map(|j| j != name).unwrap_or(false) {
+            continue;
+        }
+        for to in job.to.iter() {
+            let to = match expand_dest_path(to, &job.source) {
+                Ok(to) => to,
+                Err(_) => continue,
+            };
+            pairs.push((
+                Some(name.as_str()),
+                Owner::new("repl", &to),
+                job.source.clone(),
+            ));
+        }
+        for remote in job.remote.iter() {
+            pairs.push((
+                Some(name.as_str()),
+                Owner::new("remote_repl", &remote.metadata),
+                job.source.clone(),
+            ));
+        }
+    }
+    // Replications run by hand (or from cron) rather than as a job.
+    if only.is_none() {
+        for run in runs.runs.iter() {
+            if !pairs.iter().any(|(_, owner, _)| owner == &run.owner) {
+                pairs.push((None, run.owner.clone(), run.source.clone()));
+            }
+        }
+    }
+
+    let health = zed::pool_health();
+    Ok(pairs
+        .iter()
+        .map(|(job, owner, source)| pair_status(*job, owner, source, &runs, &health))
+        .collect())
+}
+
+pub(crate) fn do_status(opt: &StatusOpt) {
+    debug!("do_status");
+
+    if let Some(root) = opt.hosts.as_deref() {
+        return do_host_status(root, opt.format);
+    }
+
+    let report = match report(opt.job.as_deref()) {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&report) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise status -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for pair in report {
+                println!(
+                    "{}\t{} {} -> {}\tcommon={}\tlag={}\tpending={}\tlast={}\thealth={}{}",
+                    pair.job.as_deref().unwrap_or("-"),
+                    pair.flow,
+                    pair.source,
+                    pair.destination,
+                    pair.common.as_deref().unwrap_or("none"),
+                    pair.lag
+                        .map(format_lag)
+                        .unwrap_or_else(|| "never".to_string()),
+                    pair.pending
+                        .map(|p| p.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    match (pair.last_result.as_deref(), pair.last_run.as_deref()) {
+                        (Some(result), Some(at)) => format!("{} at {}", result, at),
+                        _ => "never".to_string(),
+                    },
+                    pair.health.as_deref().unwrap_or("-"),
+                    if pair.paused { " (paused)" } else { "" }
+                );
+            }
+        }
+    }
+}
+
+/// The states of a monitoring plugin, worst last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl Level {
+    fn code(self) -> i32 {
+        match self {
+            Level::Ok => 0,
+            Level::Warning => 1,
+            Level::Critical => 2,
+            Level::Unknown => 3,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Level::Ok => "OK",
+            Level::Warning => "WARNING",
+            Level::Critical => "CRITICAL",
+            Level::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+/// When `source` took its snapshot `short`.
+fn created(source: &str, short: &str) -> Option<i64> {
+    creation_list(source)
+        .ok()?
+        .into_iter()
+        .find(|(name, _)| short_name(name) == short)
+        .map(|(_, creation)| creation)
+}
+
+/// How far behind a destination is - the older of its last successful run, and the snapshot it
+/// has in common with the source, as either could be stale while the other isn't.
+fn lag(since_success: Option<i64>, common_age: Option<i64>) -> Option<i64> {
+    match (since_success, common_age) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn level(lag: Option<i64>, warn: i64, crit: i64) -> Level {
+    match lag {
+        None => Level::Unknown,
+        Some(lag) if lag > crit => Level::Critical,
+        Some(lag) if lag > warn => Level::Warning,
+        Some(_) => Level::Ok,
+    }
+}
+
+/// The status line of `job`, from the lag of each of its destinations, with the worst lag as
+/// perfdata.
+fn lag_line(job: &str, lags: &[(String, Option<i64>)], warn: i64, crit: i64) -> (Level, String) {
+    if lags.is_empty() {
+        return (
+            Level::Unknown,
+            format!("ZNAPPER UNKNOWN - job {} has no destinations", job),
+        );
+    }
+    let worst = lags
+        .iter()
+        .map(|(_, lag)| level(*lag, warn, crit))
+        .max()
+        .unwrap_or(Level::Unknown);
+    let behind: Vec<_> = lags
+        .iter()
+        .filter(|(_, lag)| level(*lag, warn, crit) != Level::Ok)
+        .map(|(destination, lag)| {
+            format!(
+                "{} {}",
+                destination,
+                lag.map(format_lag)
+                    .unwrap_or_else(|| "never replicated".to_string())
+            )
+        })
+        .collect();
+    let max_lag = lags.iter().filter_map(|(_, lag)| *lag).max();
+    let summary = if behind.is_empty() {
+        format!("{}: every destination within {}", job, format_lag(warn))
+    } else {
+        format!("{} behind: {}", job, behind.join(", "))
+    };
+    let perfdata = max_lag
+        .map(|lag| format!(" | lag={}s;{};{};0", lag, warn, crit))
+        .unwrap_or_default();
+    (
+        worst,
+        format!("ZNAPPER {} - {}{}", worst.label(), summary, perfdata),
+    )
+}
+
+/// Check the lag of the job, print its status line and return the exit code for it.
+pub(crate) fn do_check_lag(opt: &CheckLagOpt) -> i32 {
+    debug!("do_check_lag");
+    let (warn, crit) = (opt.warn.as_secs() as i64, opt.crit.as_secs() as i64);
+    let unknown = |why: &str| {
+        println!("ZNAPPER UNKNOWN - {}", why);
+        Level::Unknown.code()
+    };
+    if warn > crit {
+        return unknown("--warn must not be longer than --crit");
+    }
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(_) => return unknown("znapper.toml could not be loaded"),
+    };
+    let runs = match RunStore::load() {
+        Ok(r) => r,
+        Err(_) => return unknown("the run history could not be loaded"),
+    };
+    let job = match config.job.get(&opt.job) {
+        Some(job) => job,
+        None => return unknown(&format!("no job {} in znapper.toml", opt.job)),
+    };
+
+    let mut owners = Vec::new();
+    for to in job.to.iter() {
+        if let Ok(to) = expand_dest_path(to, &job.source) {
+            owners.push(Owner::new("repl", &to));
+        }
+    }
+    for remote in job.remote.iter() {
+        owners.push(Owner::new("remote_repl", &remote.metadata));
+    }
+
+    let now = OffsetDateTime::now_utc().timestamp();
+    let lags: Vec<_> = owners
+        .iter()
+        .map(|owner| {
+            let pair = pair_status(Some(&opt.job), owner, &job.source, &runs, &BTreeMap::new());
+            let common_age = pair
+                .common
+                .as_deref()
+                .and_then(|common| created(&job.source, short_name(common)))
+                .map(|ts| now - ts);
+            (owner.destination.clone(), lag(pair.lag, common_age))
+        })
+        .collect();
+    let (level, line) = lag_line(&opt.job, &lags, warn, crit);
+    println!("{}", line);
+    level.code()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_lag_reports_the_worst_destination() {
+        let (warn, crit) = (26 * 3600, 50 * 3600);
+        assert_eq!(lag(Some(60), Some(7200)), Some(7200));
+        assert_eq!(lag(None, Some(7200)), Some(7200));
+
+        let lags = [
+            ("tank/nvme".to_string(), Some(3600)),
+            ("/var/lib/znapper/nvme.json".to_string(), Some(30 * 3600)),
+        ];
+        assert_eq!(
+            lag_line("offsite", &lags, warn, crit),
+            (
+                Level::Warning,
+                "ZNAPPER WARNING - offsite behind: /var/lib/znapper/nvme.json 1d6h | \
+                 lag=108000s;93600;180000;0"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            lag_line("offsite", &lags[..1], warn, crit).1,
+            "ZNAPPER OK - offsite: every destination within 1d2h | lag=3600s;93600;180000;0"
+        );
+        let never = [("usb/nvme".to_string(), None)];
+        assert_eq!(lag_line("offsite", &never, warn, crit).0, Level::Unknown);
+    }
+
+    #[test]
+    fn host_status_groups_by_host() {
+        let stdout = "backups/laptop/tank@auto_1\t1000\n\
+                      backups/laptop/tank/home@auto_2\t5000\n\
+                      backups/nas/data@auto_1\t2000\n\
+                      backups@stray\t9000\n";
+        assert_eq!(
+            host_status("backups", stdout, 9000),
+            vec![
+                HostStatus {
+                    host: "laptop".to_string(),
+                    datasets: 2,
+                    newest: "backups/laptop/tank/home@auto_2".to_string(),
+                    lag: 4000,
+                },
+                HostStatus {
+                    host: "nas".to_string(),
+                    datasets: 1,
+                    newest: "backups/nas/data@auto_1".to_string(),
+                    lag: 7000,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,313 @@
+//! Whether streams are sent raw, with the properties of the source, and with which other flags.
+//!
+//! Streams are raw (`zfs send -w`) by default, so encrypted datasets are replicated still
+//! encrypted with the keys of the source, and the destination never sees them decrypted. A
+//! destination that manages its own encryption - or keeps its replicas unencrypted - needs plain
+//! streams instead, received with `-x encryption` so that they take the encryption of the dataset
+//! they are received under.
+//!
+//! Besides -w, streams are sent with -L (large blocks) and -p (properties). `--send-flags` names
+//! the flags to send in place of those two, from compressed (-c), embedded (-e), large-block (-L),
+//! holds (-h) and props (-p) - ie `compressed,embedded` for a faster plain stream to a pool with
+//! the same features but without large_blocks. A raw stream is sent as it is on disk, so -c and
+//! -e only change plain ones.
+//!
+//! Received datasets are readonly and not mounted, so that a replica is neither changed nor
+//! mounted over the destination's own datasets. `RecvPropsOpt` lets a destination set, inherit or
+//! keep the properties of the source instead. `--standby` receives a warm standby: the mountpoints
+//! and readonly of the source are kept, but nothing is mounted (canmount=noauto) until
+//! `znapper failover` takes it over.
+
+use serde::Deserialize;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// The properties of a replica, unless told otherwise - a value is set with `-o`, and none is
+/// inherited with `-x`.
+pub(crate) const REPLICA: &[(&str, Option<&str>)] =
+    &[("mountpoint", Some("none")), ("readonly", Some("on"))];
+
+/// The properties of a warm standby - ready to mount, but only when asked.
+const STANDBY: &[(&str, Option<&str>)] = &[("canmount", Some("noauto"))];
+
+/// An optional flag of zfs send, for --send-flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SendFlag {
+    Compressed,
+    Embedded,
+    LargeBlock,
+    Holds,
+    Props,
+}
+
+const SEND_FLAGS: &[(SendFlag, &str, &str)] = &[
+    (SendFlag::Compressed, "compressed", "-c"),
+    (SendFlag::Embedded, "embedded", "-e"),
+    (SendFlag::LargeBlock, "large-block", "-L"),
+    (SendFlag::Holds, "holds", "-h"),
+    (SendFlag::Props, "props", "-p"),
+];
+
+impl FromStr for SendFlag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SEND_FLAGS
+            .iter()
+            .find(|(_, name, _)| *name == s)
+            .map(|(flag, _, _)| *flag)
+            .ok_or_else(|| {
+                format!(
+                    "{} is not one of compressed, embedded, large-block, holds or props",
+                    s
+                )
+            })
+    }
+}
+
+/// The flags of --send-flags, comma separated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SendFlags(Vec<SendFlag>);
+
+fn parse_send_flags(s: &str) -> Result<SendFlags, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(SendFlag::from_str)
+        .collect::<Result<_, _>>()
+        .map(SendFlags)
+}
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub(crate) struct StreamOpt {
+    /// Send raw streams (-w), the default. Encrypted datasets stay encrypted with the keys of the
+    /// source.
+    #[structopt(long = "raw", overrides_with = "no_raw")]
+    raw: bool,
+    /// Send plain streams, received with -x encryption, so that the destination encrypts them
+    /// with its own key, or not at all. The keys of encrypted datasets must be loaded.
+    #[structopt(long = "no-raw", overrides_with = "raw")]
+    no_raw: bool,
+    /// Send the properties of each dataset with its stream (-p), the default.
+    #[structopt(long = "props", overrides_with = "no_props")]
+    props: bool,
+    /// Leave the properties of the source out of per-dataset streams, so that received datasets
+    /// inherit those of the destination. A replication (-R) stream always carries them.
+    #[structopt(long = "no-props", overrides_with = "props")]
+    no_props: bool,
+    /// Send these flags, comma separated, in place of the default large-block,props - any of
+    /// compressed (-c), embedded (-e), large-block (-L), holds (-h) and props (-p).
+    #[structopt(
+        long = "send-flags",
+        parse(try_from_str = parse_send_flags),
+        conflicts_with_all = &["props", "no-props"]
+    )]
+    send_flags: Option<SendFlags>,
+}
+
+impl StreamOpt {
+    pub(crate) fn new(raw: bool, props: bool) -> Self {
+        StreamOpt {
+            raw,
+            no_raw: !raw,
+            props,
+            no_props: !props,
+            send_flags: None,
+        }
+    }
+
+    /// Send `flags` in place of the default large-block and props, as --send-flags does.
+    pub(crate) fn with_send_flags(mut self, flags: Option<Vec<SendFlag>>) -> Self {
+        self.send_flags = flags.map(SendFlags);
+        self
+    }
+
+    /// Only one of each pair is set, the other overridden, so either tells.
+    pub(crate) fn raw(&self) -> bool {
+        self.raw || !self.no_raw
+    }
+
+    pub(crate) fn props(&self) -> bool {
+        match self.send_flags.as_ref() {
+            Some(flags) => flags.0.contains(&SendFlag::Props),
+            None => self.props || !self.no_props,
+        }
+    }
+
+    /// `args`, written for a raw send with large blocks and properties, less `-w`, `-L` and `-p`
+    /// if they are turned off, and with the other --send-flags. A resumed send (-t) takes its
+    /// flags from the token, so is left as it is.
+    pub(crate) fn send_args<'a>(&self, args: &[&'a str]) -> Vec<&'a str> {
+        if args.contains(&"-t") {
+            return args.to_vec();
+        }
+        let sent = |flag: SendFlag| match self.send_flags.as_ref() {
+            Some(flags) => flags.0.contains(&flag),
+            None => flag == SendFlag::LargeBlock || (flag == SendFlag::Props && self.props()),
+        };
+        let mut send: Vec<&'a str> = SEND_FLAGS
+            .iter()
+            .filter(|(flag, _, arg)| {
+                !matches!(flag, SendFlag::LargeBlock | SendFlag::Props)
+                    && sent(*flag)
+                    && !args.contains(arg)
+            })
+            .map(|(_, _, arg)| *arg)
+            .collect();
+        send.extend(args.iter().copied().filter(|arg| match *arg {
+            "-w" => self.raw(),
+            "-L" => sent(SendFlag::LargeBlock),
+            "-p" => sent(SendFlag::Props),
+            _ => true,
+        }));
+        send
+    }
+
+    /// What zfs recv needs to receive these streams.
+    pub(crate) fn recv_args(&self) -> &'static [&'static str] {
+        if self.raw() {
+            &[]
+        } else {
+            &["-x", "encryption"]
+        }
+    }
+}
+
+/// `key=value`, for --recv-set.
+fn parse_set(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("{} is not key=value", s)),
+    }
+}
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub(crate) struct RecvPropsOpt {
+    /// Receive a warm standby - keep the mountpoints and readonly of the source, but don't mount
+    /// the received datasets (canmount=noauto) until znapper failover.
+    #[structopt(long = "standby")]
+    pub standby: bool,
+    /// Set this property on the received datasets, ie canmount=noauto or compression=zstd, in
+    /// place of any default of it. May be repeated.
+    #[structopt(long = "recv-set", number_of_values = 1, parse(try_from_str = parse_set))]
+    pub set: Vec<(String, String)>,
+    /// Have the received datasets inherit this property from the destination (zfs recv -x). May
+    /// be repeated.
+    #[structopt(long = "recv-inherit", number_of_values = 1)]
+    pub inherit: Vec<String>,
+    /// Keep this property as the stream has it, rather than the default of it, ie mountpoint for
+    /// a warm standby. May be repeated.
+    #[structopt(long = "recv-keep", number_of_values = 1)]
+    pub keep: Vec<String>,
+}
+
+impl RecvPropsOpt {
+    /// Does this replace the default of `property`?
+    pub(crate) fn overrides(&self, property: &str) -> bool {
+        (self.standby && REPLICA.iter().any(|(p, _)| *p == property))
+            || self.set.iter().any(|(key, _)| key == property)
+            || self.inherit.iter().any(|key| key == property)
+            || self.keep.iter().any(|key| key == property)
+    }
+
+    /// The `-o` and `-x` options of zfs recv - the set and inherited properties, and those of
+    /// `defaults` (or those of a standby) they don't replace.
+    pub(crate) fn args(&self, defaults: &[(&str, Option<&str>)]) -> Vec<String> {
+        let defaults = if self.standby { STANDBY } else { defaults };
+        let mut args = Vec::new();
+        for (property, value) in defaults.iter().filter(|(p, _)| !self.overrides(p)) {
+            match value {
+                Some(value) => args.extend(["-o".to_string(), format!("{}={}", property, value)]),
+                None => args.extend(["-x".to_string(), property.to_string()]),
+            }
+        }
+        for property in self.inherit.iter() {
+            args.extend(["-x".to_string(), property.clone()]);
+        }
+        for (property, value) in self.set.iter() {
+            args.extend(["-o".to_string(), format!("{}={}", property, value)]);
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_streams_leave_out_raw_and_props() {
+        let args = ["-v", "-P", "-p", "-w", "-L", "tank@repl_1"];
+        assert_eq!(StreamOpt::default().send_args(&args), args);
+        assert!(StreamOpt::default().recv_args().is_empty());
+
+        let plain = StreamOpt::from_iter(["repl", "--no-raw", "--no-props"]);
+        assert_eq!(plain.send_args(&args), ["-v", "-P", "-L", "tank@repl_1"]);
+        assert_eq!(plain.recv_args(), ["-x", "encryption"]);
+
+        // The last of each pair wins.
+        let raw = StreamOpt::from_iter(["repl", "--no-raw", "--raw"]);
+        assert!(raw.raw() && raw.props());
+    }
+
+    #[test]
+    fn send_flags_replace_large_block_and_props() {
+        let args = ["-v", "-P", "-p", "-w", "-L", "tank@repl_1"];
+        let flags = |argv: &[&str]| StreamOpt::from_iter(argv).send_args(&args).join(" ");
+        assert_eq!(
+            flags(&["repl", "--send-flags", "compressed,embedded"]),
+            "-c -e -v -P -w tank@repl_1"
+        );
+        assert_eq!(
+            flags(&[
+                "repl",
+                "--no-raw",
+                "--send-flags",
+                "holds, large-block,props"
+            ]),
+            "-h -v -P -p -L tank@repl_1"
+        );
+        assert_eq!(flags(&["repl", "--send-flags", ""]), "-v -P -w tank@repl_1");
+        assert!(StreamOpt::from_iter_safe(["repl", "--send-flags", "dedup"]).is_err());
+        assert!(
+            StreamOpt::from_iter_safe(["repl", "--send-flags", "compressed", "--no-props"])
+                .is_err()
+        );
+
+        // A resumed send takes its flags from the token.
+        let resume = StreamOpt::from_iter(["repl", "--send-flags", "compressed"]);
+        assert_eq!(resume.send_args(&["-t", "1-abc"]), ["-t", "1-abc"]);
+    }
+
+    #[test]
+    fn recv_props_replace_the_defaults() {
+        let args = |argv: &[&str]| RecvPropsOpt::from_iter(argv).args(REPLICA).join(" ");
+        assert_eq!(args(&["repl"]), "-o mountpoint=none -o readonly=on");
+        assert_eq!(
+            args(&[
+                "repl",
+                "--recv-keep",
+                "mountpoint",
+                "--recv-set",
+                "canmount=noauto",
+                "--recv-inherit",
+                "compression"
+            ]),
+            "-o readonly=on -x compression -o canmount=noauto"
+        );
+        assert_eq!(
+            args(&["repl", "--recv-set", "readonly=off"]),
+            "-o mountpoint=none -o readonly=off"
+        );
+        assert!(RecvPropsOpt::from_iter_safe(["repl", "--recv-set", "readonly"]).is_err());
+
+        let standby = RecvPropsOpt::from_iter(["repl", "--standby"]);
+        assert!(standby.overrides("mountpoint") && standby.overrides("readonly"));
+        assert_eq!(args(&["repl", "--standby"]), "-o canmount=noauto");
+        assert_eq!(
+            args(&["repl", "--standby", "--recv-set", "canmount=off"]),
+            "-o canmount=off"
+        );
+    }
+}
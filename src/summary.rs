@@ -0,0 +1,280 @@
+//! The summary of a run, printed once it is over.
+//!
+//! Each action that changes something (or would, in a dry run) ends with what it did - the
+//! snapshots created and destroyed, the sends and the bytes they moved, how long it took, the
+//! warnings and errors it logged, and whether it succeeded - so that neither a person nor a
+//! wrapper script has to read it out of the log. It is logged as text, or printed to stdout as
+//! json with `--summary-format json`, and with `--summary-file` also written to a file.
+
+use crate::{email, history, OutputFormat};
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{error, info, Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+static RUN: Mutex<Option<Run>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub(crate) struct SummaryOpt {
+    /// Print the summary of the run as text (to the log, the default) or json (to stdout).
+    #[structopt(long = "summary-format")]
+    pub summary_format: Option<OutputFormat>,
+    /// Also write the summary to this file, in the --summary-format.
+    #[structopt(long = "summary-file")]
+    pub summary_file: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct Run {
+    started: Instant,
+    started_at: i64,
+    summary: Summary,
+}
+
+/// A send, as the history records it.
+#[derive(Debug, Clone, Serialize)]
+struct Send {
+    label: String,
+    bytes: u64,
+    succeeded: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct Summary {
+    action: String,
+    /// ok, failed or cancelled.
+    status: String,
+    dryrun: bool,
+    started: i64,
+    duration_seconds: f64,
+    snapshots_created: Vec<String>,
+    snapshots_destroyed: Vec<String>,
+    sends: Vec<Send>,
+    bytes_sent: u64,
+    warnings: Vec<String>,
+    errors: Vec<String>,
+}
+
+/// Keeps the warnings logged during the run, for the summary.
+pub(crate) struct WarningLog;
+
+impl<S: Subscriber> Layer<S> for WarningLog {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::WARN {
+            return;
+        }
+        let mut message = email::Message::default();
+        event.record(&mut message);
+        with(|summary| summary.warnings.push(message.0));
+    }
+}
+
+fn with(f: impl FnOnce(&mut Summary)) {
+    if let Ok(mut run) = RUN.lock() {
+        if let Some(run) = run.as_mut() {
+            f(&mut run.summary)
+        }
+    }
+}
+
+/// Summarise this run of `action`.
+pub(crate) fn start(action: &str, dry: bool) {
+    if let Ok(mut run) = RUN.lock() {
+        *run = Some(Run {
+            started: Instant::now(),
+            started_at: OffsetDateTime::now_utc().timestamp(),
+            summary: Summary {
+                action: action.to_string(),
+                dryrun: dry,
+                ..Summary::default()
+            },
+        });
+    }
+}
+
+/// What the history records of the run.
+pub(crate) fn record(kind: &history::Kind, succeeded: bool) {
+    with(|summary| match kind {
+        history::Kind::SnapshotCreate { snapshot, .. } if succeeded => {
+            summary.snapshots_created.push(snapshot.clone())
+        }
+        history::Kind::SnapshotDestroy { snapshot } if succeeded => {
+            summary.snapshots_destroyed.push(snapshot.clone())
+        }
+        history::Kind::Send { label, bytes, .. } => {
+            if succeeded {
+                summary.bytes_sent += bytes;
+            }
+            summary.sends.push(Send {
+                label: label.clone(),
+                bytes: *bytes,
+                succeeded,
+            })
+        }
+        _ => (),
+    })
+}
+
+/// What a dry run would create.
+pub(crate) fn would_create(name: &str) {
+    with(|summary| summary.snapshots_created.push(name.to_string()))
+}
+
+/// What a dry run would destroy.
+pub(crate) fn would_destroy(name: &str) {
+    with(|summary| summary.snapshots_destroyed.push(name.to_string()))
+}
+
+/// What a dry run would send, with the estimate of its size.
+pub(crate) fn would_send(label: &str, estimated_bytes: Option<u64>) {
+    with(|summary| {
+        let bytes = estimated_bytes.unwrap_or(0);
+        summary.bytes_sent += bytes;
+        summary.sends.push(Send {
+            label: label.to_string(),
+            bytes,
+            succeeded: true,
+        })
+    })
+}
+
+pub(crate) fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "K", "M", "G", "T", "P"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[0])
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}{} {} in {:.1}s",
+            if self.dryrun { "dryrun: " } else { "" },
+            self.action,
+            self.status,
+            self.duration_seconds
+        )?;
+        writeln!(
+            f,
+            "  {} snapshots created, {} destroyed",
+            self.snapshots_created.len(),
+            self.snapshots_destroyed.len()
+        )?;
+        for send in self.sends.iter() {
+            writeln!(
+                f,
+                "  {} {}{}",
+                send.label,
+                human_bytes(send.bytes),
+                if send.succeeded { "" } else { " failed" }
+            )?;
+        }
+        if !self.sends.is_empty() {
+            writeln!(f, "  {} sent", human_bytes(self.bytes_sent))?;
+        }
+        write!(
+            f,
+            "  {} warnings, {} errors",
+            self.warnings.len(),
+            self.errors.len()
+        )
+    }
+}
+
+/// The summary of the run so far, as its `status`.
+fn finish(cancelled: bool) -> Option<Summary> {
+    let run = RUN.lock().ok()?.take()?;
+    let mut summary = run.summary;
+    summary.started = run.started_at;
+    summary.duration_seconds = (run.started.elapsed().as_millis() as f64) / 1000.0;
+    summary.errors = email::logged();
+    summary.status = if cancelled {
+        "cancelled"
+    } else if summary.errors.is_empty() {
+        "ok"
+    } else {
+        "failed"
+    }
+    .to_string();
+    Some(summary)
+}
+
+/// Print (and write) the summary of the run started with `start`. `stdout` is whether it may
+/// go to stdout, which a json plan may have already taken.
+pub(crate) fn print(opt: &SummaryOpt, cancelled: bool, stdout: bool) {
+    let summary = match finish(cancelled) {
+        Some(s) => s,
+        None => return,
+    };
+    let out = match opt.summary_format.unwrap_or(OutputFormat::Text) {
+        OutputFormat::Json => match serde_json::to_string_pretty(&summary) {
+            Ok(json) => {
+                if stdout {
+                    println!("{}", json);
+                } else {
+                    eprintln!("{}", json);
+                }
+                json
+            }
+            Err(e) => {
+                error!("failed to serialise the summary -> {:?}", e);
+                return;
+            }
+        },
+        OutputFormat::Text => {
+            let text = summary.to_string();
+            for line in text.lines() {
+                info!("{}", line);
+            }
+            text
+        }
+    };
+    if let Some(path) = opt.summary_file.as_deref() {
+        if let Err(e) = fs::write(path, format!("{}\n", out)) {
+            error!("Failed to write the summary to {:?} -> {:?}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summaries_read_as_text() {
+        let summary = Summary {
+            action: "sync".to_string(),
+            status: "ok".to_string(),
+            duration_seconds: 2.5,
+            snapshots_created: vec!["nvme@auto_1".to_string()],
+            sends: vec![Send {
+                label: "repl to tank/nvme".to_string(),
+                bytes: 3 * 1024 * 1024,
+                succeeded: true,
+            }],
+            bytes_sent: 3 * 1024 * 1024,
+            ..Summary::default()
+        };
+        assert_eq!(
+            summary.to_string(),
+            "sync ok in 2.5s\n  1 snapshots created, 0 destroyed\n  repl to tank/nvme 3.0M\n  \
+             3.0M sent\n  0 warnings, 0 errors"
+        );
+        assert_eq!(human_bytes(512), "512B");
+    }
+}
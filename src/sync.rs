@@ -0,0 +1,441 @@
+//! A whole backup cycle of a job from `znapper.toml` in one run - snapshot the source, replicate
+//! it to each local and remote destination, then prune the source and destinations.
+//!
+//! The stages run in order, and a stage is skipped when one it depends on failed, so nothing is
+//! replicated from a failed snapshot, and the source is not pruned unless every destination
+//! received the new snapshots. The outcome of each stage is summarised at the end, and sent to
+//! the job's notifications.
+//!
+//! A job may come `after` others, which are synced first in the same run - in the order their own
+//! `after`s give - and a job is skipped if any of those it comes after did not sync. The run exits
+//! non-zero unless every job synced.
+
+use crate::buffer::BufferOpt;
+use crate::config::{Config, Job};
+use crate::lock::{self, LockOpt};
+use crate::ssh::SshOpt;
+use crate::stream::{RecvPropsOpt, StreamOpt};
+use crate::{audit, email, notify, process, progress, zed};
+use crate::{do_repl, do_repl_remote, do_snap, do_snap_cleanup, OutputFormat, Snapped};
+use crate::{CleanupOpt, Opt, ReplOpt, ReplRemoteOpt};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, info};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct SyncOpt {
+    /// The name of a [job.<name>] in znapper.toml, synced after the jobs it comes after
+    job: String,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+    /// With -n, print the plan as text (the log, the default) or json.
+    #[structopt(long = "plan-format", requires = "dryrun")]
+    pub plan_format: Option<OutputFormat>,
+    #[structopt(flatten)]
+    pub lock: LockOpt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Ok,
+    Failed,
+    Skipped,
+    /// A snapshot that was already there - taken by another run - which does as well as a new one.
+    Exists,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Ok => write!(f, "ok"),
+            Outcome::Failed => write!(f, "failed"),
+            Outcome::Skipped => write!(f, "skipped"),
+            Outcome::Exists => write!(f, "already exists"),
+        }
+    }
+}
+
+fn outcome(res: Result<(), ()>) -> Outcome {
+    match res {
+        Ok(()) => Outcome::Ok,
+        Err(()) => Outcome::Failed,
+    }
+}
+
+fn stream(job: &Job) -> StreamOpt {
+    StreamOpt::new(job.raw.unwrap_or(true), job.props.unwrap_or(true))
+        .with_send_flags(job.send_flags.clone())
+}
+
+fn recv_props(job: &Job) -> RecvPropsOpt {
+    RecvPropsOpt {
+        standby: job.standby,
+        set: job
+            .recv_set
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        inherit: job.recv_inherit.clone(),
+        keep: job.recv_keep.clone(),
+    }
+}
+
+pub(crate) fn repl_opt(
+    dryrun: bool,
+    plan_format: Option<OutputFormat>,
+    job: &Job,
+    to_pool: &str,
+    to: &[String],
+) -> ReplOpt {
+    ReplOpt {
+        from_pool: job.source.clone(),
+        to_pool: to_pool.to_string(),
+        to: to.to_vec(),
+        dryrun,
+        plan_format,
+        bookmarks: job.bookmarks,
+        keep_anchors: job.keep_anchors,
+        fallback_full: false,
+        force_rollback: false,
+        redact: false,
+        anomaly_factor: None,
+        pause_on_anomaly: false,
+        dest_keep_hours: job.dest_keep_hours,
+        dest_keep_daily: job.dest_keep_daily,
+        ignore_space: false,
+        skip_preflight: false,
+        require_healthy: job.require_healthy,
+        jobs: 1,
+        single_dataset: job.single_dataset,
+        stream: stream(job),
+        recv: recv_props(job),
+        buffer: BufferOpt::default(),
+        lock: LockOpt::default(),
+    }
+}
+
+/// The jobs a sync of `name` runs - those it comes after, with theirs, then `name` - in the order
+/// they run, each after every job it comes after.
+fn order(config: &Config, name: &str) -> Result<Vec<String>, String> {
+    fn visit(
+        config: &Config,
+        name: &str,
+        path: &mut Vec<String>,
+        order: &mut Vec<String>,
+    ) -> Result<(), String> {
+        if order.iter().any(|n| n == name) {
+            return Ok(());
+        }
+        if path.iter().any(|n| n == name) {
+            return Err(format!(
+                "jobs come after each other in a loop - {} -> {}",
+                path.join(" -> "),
+                name
+            ));
+        }
+        let job = config.job.get(name).ok_or_else(|| match path.last() {
+            Some(from) => format!("{} comes after {}, which is not a job", from, name),
+            None => format!("no job {} in znapper.toml", name),
+        })?;
+        path.push(name.to_string());
+        for after in job.after.iter() {
+            visit(config, after, path, order)?;
+        }
+        path.pop();
+        order.push(name.to_string());
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    visit(config, name, &mut Vec::new(), &mut order)?;
+    Ok(order)
+}
+
+/// The locks of a sync - each job it runs, and the pool of each source. The stages run within
+/// them.
+pub(crate) fn locks(opt: &SyncOpt) -> Vec<String> {
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(_) => return vec![lock::job(&opt.job)],
+    };
+    let names = order(&config, &opt.job).unwrap_or_else(|_| vec![opt.job.clone()]);
+    let mut locks: Vec<_> = names.iter().map(|name| lock::job(name)).collect();
+    locks.extend(
+        names
+            .iter()
+            .filter_map(|name| config.job.get(name))
+            .map(|job| lock::pool(&job.source)),
+    );
+    locks.sort_unstable();
+    locks.dedup();
+    locks
+}
+
+/// Sync the job of `opt`, after the jobs it comes after, returning the outcome of each in the
+/// order they ran.
+pub(crate) fn do_sync(opt: &SyncOpt) -> Result<Vec<(String, Outcome)>, ()> {
+    debug!("do_sync");
+
+    let config = Config::load()?;
+    let names = order(&config, &opt.job).map_err(|e| {
+        error!("Unable to sync {} - {}", opt.job, e);
+    })?;
+
+    let mut outcomes: BTreeMap<&str, Outcome> = BTreeMap::new();
+    for name in names.iter() {
+        let job = match config.job.get(name) {
+            Some(job) => job,
+            None => continue,
+        };
+        let unsynced: Vec<&str> = job
+            .after
+            .iter()
+            .map(String::as_str)
+            .filter(|after| !matches!(outcomes.get(after), Some(Outcome::Ok)))
+            .collect();
+        let o = if !unsynced.is_empty() {
+            error!(
+                "Skipping job {} - it comes after {}, which did not sync",
+                name,
+                unsynced.join(", ")
+            );
+            Outcome::Skipped
+        } else if process::cancelled() {
+            Outcome::Skipped
+        } else {
+            sync_job(opt, name, job)
+        };
+        outcomes.insert(name, o);
+    }
+    if names.len() > 1 {
+        for name in names.iter() {
+            if let Some(o) = outcomes.get(name.as_str()) {
+                info!("{}\tjob {}", o, name);
+            }
+        }
+    }
+    Ok(names
+        .iter()
+        .filter_map(|name| Some((name.clone(), *outcomes.get(name.as_str())?)))
+        .collect())
+}
+
+/// Did every job of a sync reach Ok? Anything less - a failed stage, or a job skipped for one it
+/// comes after - fails the run.
+pub(crate) fn synced(outcomes: &[(String, Outcome)]) -> bool {
+    outcomes.iter().all(|(_, o)| *o == Outcome::Ok)
+}
+
+/// Sync the job `name`, the outcome being Ok if every stage of it was.
+fn sync_job(opt: &SyncOpt, name: &str, job: &Job) -> Outcome {
+    info!("Syncing job {} from {}", name, job.source);
+    audit::job(name);
+    let started = OffsetDateTime::now_utc().timestamp();
+    notify::start(opt.dryrun, &job.notify);
+
+    let mut stages = Vec::new();
+
+    let snapshot = match do_snap(&Opt {
+        pools: vec![job.source.clone()],
+        group: false,
+        jobs: job.snapshot_jobs,
+        dryrun: opt.dryrun,
+        plan_format: opt.plan_format,
+        lock: LockOpt::default(),
+    }) {
+        Ok(Snapped::Created) => Outcome::Ok,
+        Ok(Snapped::AlreadyExists) => Outcome::Exists,
+        Err(()) => Outcome::Failed,
+    };
+    let snapshotted = matches!(snapshot, Outcome::Ok | Outcome::Exists);
+    stages.push((format!("snapshot {}", job.source), snapshot));
+
+    // Without the new snapshots there is nothing new to replicate, and so nothing to prune. Nor
+    // is anything sent from a pool ZED saw degrade.
+    let sendable = snapshotted && zed::check(&job.source).is_ok();
+    let mut replicated = sendable;
+    if let Some((to_pool, to)) = job.to.split_first() {
+        let repl = if sendable && !process::cancelled() {
+            outcome(do_repl(&repl_opt(
+                opt.dryrun,
+                opt.plan_format,
+                job,
+                to_pool,
+                to,
+            )))
+        } else {
+            Outcome::Skipped
+        };
+        replicated &= matches!(repl, Outcome::Ok);
+        stages.push((format!("repl to {}", job.to.join(", ")), repl));
+    }
+
+    for remote in job.remote.iter() {
+        let repl = if sendable && !process::cancelled() {
+            outcome(do_repl_remote(&ReplRemoteOpt {
+                remote_ssh: remote.target.clone(),
+                auto_snap_metadata: remote.metadata.clone(),
+                dryrun: opt.dryrun,
+                plan_format: opt.plan_format,
+                force_rollback: false,
+                retries: remote.retries,
+                retry_delay: Duration::from_secs(30),
+                ignore_space: false,
+                skip_preflight: false,
+                require_healthy: job.require_healthy,
+                per_dataset: remote.per_dataset,
+                single_dataset: job.single_dataset && !remote.per_dataset,
+                datasets: if remote.per_dataset {
+                    vec![job.source.clone()]
+                } else {
+                    Vec::new()
+                },
+                exclude: remote.exclude.clone(),
+                transport_compress: remote.transport_compress,
+                chunked: None,
+                transport: remote.transport.clone(),
+                tls: remote.tls.clone(),
+                per_host: remote.per_host,
+                stream: stream(job),
+                recv: RecvPropsOpt::default(),
+                ssh: SshOpt::default(),
+                buffer: BufferOpt {
+                    rate_limit: remote.rate_limit.clone(),
+                    ..BufferOpt::default()
+                },
+                lock: LockOpt::default(),
+            }))
+        } else {
+            Outcome::Skipped
+        };
+        replicated &= matches!(repl, Outcome::Ok);
+        stages.push((format!("remote_repl to {}", remote.target), repl));
+    }
+
+    // The destinations were pruned by repl as it finished with each of them.
+    if let Some(keep_hours) = job.keep_hours {
+        let cleanup = if replicated && !process::cancelled() {
+            outcome(do_snap_cleanup(&CleanupOpt {
+                pool: job.source.clone(),
+                keep_hours,
+                group: false,
+                empty: job.cleanup_empty,
+                defer: false,
+                grace: None,
+                dryrun: opt.dryrun,
+                plan_format: opt.plan_format,
+                lock: LockOpt::default(),
+            }))
+        } else {
+            Outcome::Skipped
+        };
+        stages.push((format!("snapshot_cleanup {}", job.source), cleanup));
+    }
+
+    let failed = stages
+        .iter()
+        .filter(|(_, o)| !matches!(o, Outcome::Ok | Outcome::Exists))
+        .count();
+    for (stage, o) in stages.iter() {
+        info!("{}\t{}", o, stage);
+    }
+    if failed == 0 {
+        info!("Job {} synced", name);
+    } else {
+        error!(
+            "Job {} did not sync - {} of {} stages failed or were skipped",
+            name,
+            failed,
+            stages.len()
+        );
+    }
+
+    // The sends of this run are the ones that started since it did.
+    let bytes = progress::checkpoints()
+        .unwrap_or_default()
+        .iter()
+        .filter(|c| c.started >= started)
+        .map(|c| c.bytes_sent)
+        .sum();
+    let report = notify::Report {
+        job: name.to_string(),
+        action: "sync",
+        result: if failed == 0 { "ok" } else { "failed" },
+        bytes,
+        duration_seconds: OffsetDateTime::now_utc().timestamp() - started,
+        stages: stages
+            .iter()
+            .map(|(stage, o)| notify::Stage {
+                stage: stage.clone(),
+                result: o.to_string(),
+            })
+            .collect(),
+        errors: email::logged(),
+    };
+    notify::finish(opt.dryrun, &job.notify, &report);
+    if failed == 0 {
+        Outcome::Ok
+    } else {
+        Outcome::Failed
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_run_after_the_jobs_they_come_after() {
+        let config: Config = toml::from_str(
+            r#"
+            [job.snapshot-all]
+            source = "tank"
+            [job.repl-local]
+            source = "tank"
+            after = ["snapshot-all"]
+            [job.repl-offsite]
+            source = "tank"
+            after = ["repl-local", "snapshot-all"]
+            [job.looped]
+            source = "nvme"
+            after = ["looping"]
+            [job.looping]
+            source = "nvme"
+            after = ["looped"]
+            [job.orphan]
+            source = "nvme"
+            after = ["gone"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            order(&config, "repl-offsite").unwrap(),
+            vec!["snapshot-all", "repl-local", "repl-offsite"]
+        );
+        assert_eq!(
+            order(&config, "snapshot-all").unwrap(),
+            vec!["snapshot-all"]
+        );
+        assert!(order(&config, "looped").unwrap_err().contains("loop"));
+        assert!(order(&config, "orphan").unwrap_err().contains("gone"));
+        assert!(order(&config, "missing").is_err());
+    }
+
+    #[test]
+    fn a_sync_succeeds_only_if_every_job_did() {
+        let ok = vec![
+            ("a".to_string(), Outcome::Ok),
+            ("b".to_string(), Outcome::Ok),
+        ];
+        assert!(synced(&ok));
+        let skipped = vec![
+            ("a".to_string(), Outcome::Failed),
+            ("b".to_string(), Outcome::Skipped),
+        ];
+        assert!(!synced(&skipped));
+    }
+}
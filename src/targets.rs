@@ -0,0 +1,284 @@
+//! The registry of remote replication targets, kept in `targets.toml`, so that commands can refer
+//! to a target by name instead of repeating its connection details.
+
+use crate::config::config_dir;
+use crate::process::{Kind, Timed};
+use crate::ssh::{Ssh, SshOpt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Stdio;
+use structopt::StructOpt;
+use tracing::{debug, error, info};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct TargetAddOpt {
+    name: String,
+    /// The user@host to ssh to.
+    #[structopt(long = "ssh")]
+    ssh: String,
+    /// The dataset on the remote that receives the replication.
+    #[structopt(long = "dataset")]
+    dataset: String,
+    #[structopt(flatten)]
+    connection: SshOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct TargetNameOpt {
+    name: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) enum TargetAction {
+    /// Add (or replace) a named target.
+    #[structopt(name = "add")]
+    Add(TargetAddOpt),
+    #[structopt(name = "remove")]
+    Remove(TargetNameOpt),
+    #[structopt(name = "list")]
+    List,
+    /// Check that a target is reachable and usable as a replication destination.
+    #[structopt(name = "test")]
+    Test(TargetNameOpt),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Target {
+    pub ssh: String,
+    pub dataset: String,
+    /// How to connect to `ssh`.
+    #[serde(flatten)]
+    pub connection: SshOpt,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Targets {
+    #[serde(default)]
+    target: BTreeMap<String, Target>,
+}
+
+impl Targets {
+    fn path() -> PathBuf {
+        config_dir().join("targets.toml")
+    }
+
+    /// Load the registry, treating a missing file as empty.
+    pub(crate) fn load() -> Result<Self, ()> {
+        let path = Self::path();
+        match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).map_err(|e| {
+                error!("Failed to parse {:?} -> {}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Targets::default()),
+            Err(e) => {
+                error!("Failed to read {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), ()> {
+        let path = Self::path();
+        let s = toml::to_string_pretty(self).map_err(|e| {
+            error!("Failed to serialise targets -> {:?}", e);
+        })?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                error!("Failed to create config dir {:?} -> {:?}", parent, e);
+            })?;
+        }
+        fs::write(&path, s).map_err(|e| {
+            error!("Failed to write {:?} -> {:?}", path, e);
+        })
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Target> {
+        self.target.get(name)
+    }
+}
+
+/// Run `args` on the target over ssh, returning stdout if it succeeded.
+fn ssh_output(ssh: &Ssh, args: &[&str]) -> Result<String, String> {
+    debug!("running -> ssh {} {}", ssh, args.join(" "));
+    let output = ssh
+        .command(args)
+        .stdin(Stdio::null())
+        .run_output(Kind::Ssh)
+        .map_err(|e| format!("ssh failed -> {:?}", e))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(format!(
+            "{} -> {}",
+            ssh.describe_failure(output.status),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+fn report(ok: bool, check: &str, detail: &str) -> bool {
+    println!(
+        "{}\t{}\t{}",
+        if ok { "PASS" } else { "FAIL" },
+        check,
+        detail
+    );
+    ok
+}
+
+fn test_target(name: &str, target: &Target) -> bool {
+    info!(
+        "Testing target {} -> {} {}",
+        name, target.ssh, target.dataset
+    );
+
+    let mut connection = target.connection.clone();
+    connection.options.push("BatchMode=yes".to_string());
+    let ssh = match Ssh::new(&target.ssh, &connection) {
+        Ok(ssh) => ssh,
+        Err(_) => {
+            report(false, "ssh", "unable to set up the connection");
+            return false;
+        }
+    };
+
+    if let Err(e) = ssh_output(&ssh, &["true"]) {
+        report(false, "ssh", &e);
+        return false;
+    }
+    let mut ok = report(true, "ssh", &target.ssh);
+
+    // The dataset may not exist until the first replication, so fall back to its parent.
+    let (existing, avail) = match ssh_output(
+        &ssh,
+        &["zfs", "list", "-H", "-p", "-o", "avail", &target.dataset],
+    ) {
+        Ok(avail) => (target.dataset.clone(), avail),
+        Err(_) => {
+            let parent = target
+                .dataset
+                .rsplit_once('/')
+                .map(|(p, _)| p)
+                .unwrap_or(&target.dataset)
+                .to_string();
+            match ssh_output(&ssh, &["zfs", "list", "-H", "-p", "-o", "avail", &parent]) {
+                Ok(avail) => (parent, avail),
+                Err(e) => {
+                    report(false, "dataset", &e);
+                    return false;
+                }
+            }
+        }
+    };
+    ok &= report(true, "dataset", &existing);
+
+    match avail.trim().parse::<u64>() {
+        Ok(bytes) => ok &= report(bytes > 0, "free space", &format!("{} bytes", bytes)),
+        Err(_) => ok &= report(false, "free space", avail.trim()),
+    }
+
+    // zfs allow lists delegations for every user - we only need the ones recv relies on.
+    match ssh_output(&ssh, &["zfs", "allow", &existing]) {
+        Ok(allow) => {
+            let missing: Vec<_> = ["create", "mount", "receive"]
+                .iter()
+                .filter(|perm| !allow.contains(*perm))
+                .copied()
+                .collect();
+            ok &= report(
+                missing.is_empty(),
+                "permissions",
+                &if missing.is_empty() {
+                    "create,mount,receive".to_string()
+                } else {
+                    format!("missing {}", missing.join(","))
+                },
+            );
+        }
+        Err(e) => ok &= report(false, "permissions", &e),
+    }
+
+    let pool = existing.split('/').next().unwrap_or(&existing);
+    match ssh_output(
+        &ssh,
+        &["zpool", "get", "-H", "-o", "property,value", "all", pool],
+    ) {
+        Ok(props) => {
+            let features: Vec<_> = props
+                .lines()
+                .filter_map(|line| {
+                    let mut lsplit = line.split('\t');
+                    match (lsplit.next(), lsplit.next()) {
+                        (Some(prop), Some("enabled")) | (Some(prop), Some("active")) => {
+                            prop.strip_prefix("feature@")
+                        }
+                        _ => None,
+                    }
+                })
+                .filter(|f| {
+                    [
+                        "bookmarks",
+                        "encryption",
+                        "large_blocks",
+                        "embedded_data",
+                        "lz4_compress",
+                        "zstd_compress",
+                        "redaction_bookmarks",
+                    ]
+                    .contains(f)
+                })
+                .collect();
+            ok &= report(true, "features", &features.join(","));
+        }
+        Err(e) => ok &= report(false, "features", &e),
+    }
+
+    ok
+}
+
+pub(crate) fn do_target(action: &TargetAction) {
+    let mut targets = match Targets::load() {
+        Ok(t) => t,
+        Err(_) => return,
+    };
+
+    match action {
+        TargetAction::Add(opt) => {
+            targets.target.insert(
+                opt.name.clone(),
+                Target {
+                    ssh: opt.ssh.clone(),
+                    dataset: opt.dataset.clone(),
+                    connection: opt.connection.clone(),
+                },
+            );
+            if targets.save().is_ok() {
+                info!("Added target {}", opt.name);
+            }
+        }
+        TargetAction::Remove(opt) => {
+            if targets.target.remove(&opt.name).is_none() {
+                error!("No such target {}", opt.name);
+            } else if targets.save().is_ok() {
+                info!("Removed target {}", opt.name);
+            }
+        }
+        TargetAction::List => {
+            for (name, target) in targets.target.iter() {
+                println!("{}\t{}\t{}", name, target.ssh, target.dataset);
+            }
+        }
+        TargetAction::Test(opt) => match targets.get(&opt.name) {
+            Some(target) => {
+                if test_target(&opt.name, target) {
+                    info!("Target {} is ready", opt.name);
+                } else {
+                    error!("Target {} failed one or more checks", opt.name);
+                }
+            }
+            None => error!("No such target {}", opt.name),
+        },
+    }
+}
@@ -0,0 +1,374 @@
+//! Traces of a run, exported to an OpenTelemetry collector over OTLP/HTTP.
+//!
+//! With `--otlp-endpoint` (or `OTEL_EXPORTER_OTLP_ENDPOINT`) every span of the run is kept - the
+//! run itself, and within it each snapshot, send, recv and cleanup, with the bytes a send wrote -
+//! and once the run is over they are POSTed as json to `<endpoint>/v1/traces`, with the headers of
+//! `OTEL_EXPORTER_OTLP_HEADERS`. A span in which an error was logged, and those around it, end
+//! with an error status. As with notifications, the request is made with curl and a failure is
+//! only logged.
+
+use crate::hostname;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{debug, error, Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// The target of the spans, which are only enabled when they are exported - the text log would
+/// otherwise carry them on every line.
+pub(crate) const SPANS: &str = "znapper::telemetry";
+
+/// Give up on the export after this many seconds.
+const TIMEOUT_SECS: &str = "10";
+
+/// The spans that have closed so far.
+static FINISHED: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, StructOpt)]
+pub(crate) struct TelemetryOpt {
+    /// Export the spans of the run to this OpenTelemetry collector, ie http://otel:4318. By
+    /// default OTEL_EXPORTER_OTLP_ENDPOINT, or no export.
+    #[structopt(long = "otlp-endpoint")]
+    otlp_endpoint: Option<String>,
+}
+
+impl TelemetryOpt {
+    fn endpoint(&self) -> Option<String> {
+        self.otlp_endpoint
+            .clone()
+            .or_else(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok())
+            .filter(|e| !e.is_empty())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum AttrValue {
+    Int(i64),
+    Bool(bool),
+    Str(String),
+}
+
+#[derive(Debug, Clone)]
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: &'static str,
+    start: u128,
+    end: u128,
+    attributes: Vec<(&'static str, AttrValue)>,
+    /// The first error logged within the span, or one within it.
+    error: Option<String>,
+}
+
+/// The fields of a span, as attributes.
+struct Attrs<'a>(&'a mut Vec<(&'static str, AttrValue)>);
+
+impl Attrs<'_> {
+    fn set(&mut self, field: &Field, value: AttrValue) {
+        self.0.retain(|(name, _)| *name != field.name());
+        self.0.push((field.name(), value));
+    }
+}
+
+impl Visit for Attrs<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, AttrValue::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, AttrValue::Int(value as i64));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, AttrValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, AttrValue::Str(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, AttrValue::Str(format!("{:?}", value)));
+    }
+}
+
+/// The message of an event.
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+/// A random id of `bytes` bytes, in hex - the ids of tracing are reused once a span closes.
+fn random_id(bytes: usize) -> String {
+    (0..bytes / 8)
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect()
+}
+
+/// Keeps the spans of the run, for `export`.
+pub(crate) struct Spans {
+    trace_id: String,
+}
+
+/// The layer that keeps the spans of the run, if they are to be exported.
+pub(crate) fn layer(opt: &TelemetryOpt) -> Option<Spans> {
+    opt.endpoint().map(|_| Spans {
+        trace_id: random_id(16),
+    })
+}
+
+impl<S> Layer<S> for Spans
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let parent_span_id = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| data.span_id.clone())
+        });
+        let mut data = SpanData {
+            trace_id: self.trace_id.clone(),
+            span_id: random_id(8),
+            parent_span_id,
+            name: attrs.metadata().name(),
+            start: now_nanos(),
+            end: 0,
+            attributes: Vec::new(),
+            error: None,
+        };
+        attrs.record(&mut Attrs(&mut data.attributes));
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut Attrs(&mut data.attributes));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        let mut message = Message::default();
+        event.record(&mut message);
+        let mut span = ctx.lookup_current();
+        while let Some(current) = span {
+            if let Some(data) = current.extensions_mut().get_mut::<SpanData>() {
+                data.error.get_or_insert_with(|| message.0.clone());
+            }
+            span = current.parent();
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(mut data) = span.extensions_mut().remove::<SpanData>() {
+                data.end = now_nanos();
+                if let Ok(mut finished) = FINISHED.lock() {
+                    finished.push(data);
+                }
+            }
+        }
+    }
+}
+
+fn attribute(key: &str, value: &AttrValue) -> Value {
+    // int64 is a string in the json encoding of OTLP.
+    let value = match value {
+        AttrValue::Int(i) => json!({ "intValue": i.to_string() }),
+        AttrValue::Bool(b) => json!({ "boolValue": b }),
+        AttrValue::Str(s) => json!({ "stringValue": s }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+/// An OTLP ExportTraceServiceRequest of `spans`.
+fn request(spans: &[SpanData], host: &str, command: &str) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let mut out = json!({
+                "traceId": span.trace_id,
+                "spanId": span.span_id,
+                "name": span.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": span.start.to_string(),
+                "endTimeUnixNano": span.end.to_string(),
+                "attributes": span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| attribute(key, value))
+                    .collect::<Vec<_>>(),
+                "status": match span.error.as_deref() {
+                    Some(message) => json!({ "code": 2, "message": message }),
+                    None => json!({ "code": 1 }),
+                },
+            });
+            if let Some(parent) = span.parent_span_id.as_deref() {
+                out["parentSpanId"] = json!(parent);
+            }
+            out
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", &AttrValue::Str("znapper".to_string())),
+                    attribute("host.name", &AttrValue::Str(host.to_string())),
+                    attribute("process.command_line", &AttrValue::Str(command.to_string())),
+                ]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "znapper", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }]
+        }]
+    })
+}
+
+/// The headers of OTEL_EXPORTER_OTLP_HEADERS, given as key=value,key=value.
+fn headers(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| format!("{}: {}", key.trim(), value.trim()))
+        .collect()
+}
+
+/// POST the spans of the run to the collector.
+pub(crate) fn export(opt: &TelemetryOpt) {
+    let endpoint = match opt.endpoint() {
+        Some(endpoint) => endpoint,
+        None => return,
+    };
+    let spans = match FINISHED.lock() {
+        Ok(mut finished) => std::mem::take(&mut *finished),
+        Err(_) => return,
+    };
+    if spans.is_empty() {
+        return;
+    }
+    let host = hostname().unwrap_or_default();
+    let command: Vec<_> = std::env::args().collect();
+    let body = request(&spans, &host, &command.join(" ")).to_string();
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    debug!("exporting {} spans -> {}", spans.len(), url);
+
+    let mut cmd = Command::new("curl");
+    cmd.args(["-fsS", "-m", TIMEOUT_SECS, "-o", "/dev/null"])
+        .args(["-H", "Content-Type: application/json"]);
+    for header in headers(&std::env::var("OTEL_EXPORTER_OTLP_HEADERS").unwrap_or_default()) {
+        cmd.arg("-H").arg(header);
+    }
+    let mut child = match cmd
+        .args(["--data-binary", "@-"])
+        .arg(&url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) => {
+            error!(
+                "Unable to run curl to export the trace to {} -> {:?}",
+                url, e
+            );
+            return;
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(e) = stdin.write_all(body.as_bytes()) {
+            error!("Failed to send the trace to {} -> {:?}", url, e);
+        }
+    }
+    match child.wait() {
+        Ok(status) if status.success() => {}
+        Ok(status) => error!(
+            "Exporting the trace to {} failed -> {:?}",
+            url,
+            status.code()
+        ),
+        Err(e) => error!("Exporting the trace to {} failed -> {:?}", url, e),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn spans_export_with_their_parents_and_errors() {
+        let spans = Spans {
+            trace_id: random_id(16),
+        };
+        let subscriber = tracing_subscriber::registry().with(spans);
+        tracing::subscriber::with_default(subscriber, || {
+            let send = tracing::info_span!("send", to = "tank/nvme", bytes = tracing::field::Empty);
+            let _send = send.enter();
+            {
+                let _recv = tracing::info_span!("recv").entered();
+                error!("recv into tank/nvme failed");
+            }
+            send.record("bytes", &4096u64);
+        });
+
+        let spans = std::mem::take(&mut *FINISHED.lock().unwrap());
+        let body = request(&spans, "backup1", "znapper repl nvme tank/nvme");
+        let spans = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        let (recv, send) = (&spans[0], &spans[1]);
+        assert_eq!(recv["name"], "recv");
+        assert_eq!(recv["parentSpanId"], send["spanId"]);
+        assert_eq!(recv["traceId"], send["traceId"]);
+        assert_eq!(send["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(send["spanId"].as_str().unwrap().len(), 16);
+        assert!(send.get("parentSpanId").is_none());
+        assert_eq!(
+            send["attributes"][1],
+            json!({ "key": "bytes", "value": { "intValue": "4096" } })
+        );
+        // The failed recv fails the send around it.
+        assert_eq!(send["status"]["code"], 2);
+        assert_eq!(recv["status"]["message"], "recv into tank/nvme failed");
+
+        assert_eq!(
+            headers("authorization=Bearer abc, x-scope=backups"),
+            ["authorization: Bearer abc", "x-scope: backups"]
+        );
+    }
+}
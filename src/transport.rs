@@ -0,0 +1,536 @@
+//! Replicating to a receiver that isn't reachable over ssh, over plain tcp or mutual TLS.
+//!
+//! `znapper serve-recv --listen :7722 --pool tank/remote` answers what `znapper recv` answers as
+//! a forced command, to remote_repl with `--transport tcp://host:7722`. With `--tls-cert`,
+//! `--tls-key` and `--tls-ca` on both sides the connection is TLS, and each side only accepts a
+//! certificate signed by the CA - the receiver authenticates the sender as ssh would by its key.
+//! Plain tcp authenticates nothing, so it is for links that are already secured, like a tunnel
+//! or a VPN.
+//!
+//! The sender's side of the connection is a `znapper transport-connect` that stands in for ssh,
+//! so everything remote_repl runs over ssh runs unchanged, as long as it is a recv command -
+//! a receiver can't be asked to run zfs itself. It sends the command as a line, then its stdin
+//! in frames (a big endian u32 length, then that many bytes), ending with an empty frame, and
+//! prints the reply the receiver sends back. TLS is made by socat, which must be installed on
+//! both hosts: the sender connects through `socat - OPENSSL:...`, and serve-recv listens with
+//! `socat OPENSSL-LISTEN:...`, which forwards each connection to serve-recv over a unix socket.
+//! The socket is only readable by the user serve-recv runs as, in a directory of its own under
+//! the state directory, so nothing on the receiver can get around the TLS by connecting to it.
+
+use crate::anchors::state_dir;
+use crate::recv::{self, RecvOpt, RecvResult};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+use tracing::{debug, error, info, warn};
+
+/// transport-connect exits with this when the connection fails, as ssh does.
+const CONNECT_FAILED: i32 = 255;
+
+/// The largest frame that is sent, or accepted.
+const FRAME: usize = 1024 * 1024;
+
+/// The longest command line that is accepted.
+const MAX_COMMAND: u64 = 4096;
+
+/// How long a sender may take to send its command.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the sender reaches the receiver, instead of ssh.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) enum Transport {
+    Tcp(String),
+    Tls(String),
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, addr) = s.split_once("://").ok_or_else(|| {
+            format!(
+                "invalid transport {} - use tcp://host:port or tls://host:port",
+                s
+            )
+        })?;
+        let valid = addr
+            .rsplit_once(':')
+            .map(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+            .unwrap_or(false);
+        if !valid {
+            return Err(format!("invalid address {} - use host:port", addr));
+        }
+        match scheme {
+            "tcp" => Ok(Transport::Tcp(addr.to_string())),
+            "tls" => Ok(Transport::Tls(addr.to_string())),
+            _ => Err(format!("unknown transport {} - use tcp or tls", scheme)),
+        }
+    }
+}
+
+impl TryFrom<String> for Transport {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Transport::Tls(addr) => write!(f, "tls://{}", addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, StructOpt, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsOpt {
+    /// The certificate to present over TLS, in pem.
+    #[structopt(long = "tls-cert")]
+    #[serde(default)]
+    pub cert: Option<PathBuf>,
+    /// The private key of --tls-cert, in pem.
+    #[structopt(long = "tls-key")]
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+    /// Only accept a peer certificate signed by this CA, in pem.
+    #[structopt(long = "tls-ca")]
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+}
+
+impl TlsOpt {
+    /// The socat options of an OPENSSL address, which needs all three for mutual TLS.
+    fn socat(&self) -> Result<String, String> {
+        match (self.cert.as_ref(), self.key.as_ref(), self.ca.as_ref()) {
+            (Some(cert), Some(key), Some(ca)) => Ok(format!(
+                "cert={},key={},cafile={},verify=1",
+                cert.display(),
+                key.display(),
+                ca.display()
+            )),
+            _ => Err("TLS needs --tls-cert, --tls-key and --tls-ca".to_string()),
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        self.cert.is_some() || self.key.is_some() || self.ca.is_some()
+    }
+
+    /// These options as arguments of transport-connect.
+    pub(crate) fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (flag, path) in [
+            ("--tls-cert", &self.cert),
+            ("--tls-key", &self.key),
+            ("--tls-ca", &self.ca),
+        ] {
+            if let Some(path) = path {
+                args.extend([flag.to_string(), path.display().to_string()]);
+            }
+        }
+        args
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct ConnectOpt {
+    /// tcp://host:port or tls://host:port
+    transport: Transport,
+    #[structopt(flatten)]
+    tls: TlsOpt,
+    /// The recv command, as it would be sent over ssh.
+    command: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct ServeRecvOpt {
+    /// The address to listen on, ie :7722 or 192.168.1.10:7722.
+    #[structopt(long = "listen", default_value = ":7722")]
+    listen: String,
+    #[structopt(flatten)]
+    tls: TlsOpt,
+    #[structopt(flatten)]
+    recv: RecvOpt,
+}
+
+/// Copy `from` to `to` in frames, ending with an empty one.
+fn write_frames(mut from: impl Read, to: &mut impl Write) -> io::Result<u64> {
+    let mut buf = vec![0; FRAME];
+    let mut total = 0;
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        to.write_all(&(n as u32).to_be_bytes())?;
+        if n == 0 {
+            to.flush()?;
+            return Ok(total);
+        }
+        to.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
+
+/// Copy the frames of `from` to `to`, until the empty one.
+fn read_frames(from: &mut impl Read, mut to: impl Write) -> io::Result<u64> {
+    let mut buf = vec![0; FRAME];
+    let mut total = 0;
+    loop {
+        let mut len = [0; 4];
+        from.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            to.flush()?;
+            return Ok(total);
+        }
+        if len > FRAME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes", len),
+            ));
+        }
+        from.read_exact(&mut buf[..len])?;
+        to.write_all(&buf[..len])?;
+        total += len as u64;
+    }
+}
+
+/// Is `command` one that znapper recv answers - after any `from <host>` and `zstd`, recv,
+/// snapshots, partial, space or the chunks of a chunked transfer, or nothing (to receive)?
+fn recv_command(command: &str) -> bool {
+    let mut words = command.split_whitespace().peekable();
+    if words.peek() == Some(&"from") {
+        words.next();
+        words.next();
+    }
+    if words.peek() == Some(&crate::compress::ZSTD) {
+        words.next();
+    }
+    matches!(
+        words.next(),
+        None | Some("recv" | "snapshots" | "partial" | "space" | "chunks" | "chunk" | "assemble")
+    )
+}
+
+/// Send our stdin to the receiver with `command`, and print its reply.
+fn send(
+    command: &str,
+    mut reader: impl Read + Send + 'static,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    writer.write_all(command.as_bytes())?;
+    writer.write_all(b"\n")?;
+    // The reply is read as the stream is sent, so that it isn't lost when the receiver fails
+    // part way and stops reading.
+    let replied = thread::spawn(move || {
+        let mut reply = Vec::new();
+        reader.read_to_end(&mut reply).map(|_| reply)
+    });
+    if let Err(e) = write_frames(io::stdin().lock(), &mut writer) {
+        // Whatever the receiver had to say about it is more use than the write failing.
+        debug!("sending the stream failed -> {:?}", e);
+    }
+    let reply = replied
+        .join()
+        .map_err(|_| io::Error::other("reply thread panicked"))??;
+    io::stdout().write_all(&reply)?;
+    io::stdout().flush()
+}
+
+/// Connect over `transport` and send `command`.
+fn connect(transport: &Transport, tls: &TlsOpt, command: &str) -> io::Result<()> {
+    match transport {
+        Transport::Tcp(addr) => {
+            let stream = TcpStream::connect(addr)?;
+            let res = send(command, stream.try_clone()?, &stream);
+            let _ = stream.shutdown(Shutdown::Both);
+            res
+        }
+        Transport::Tls(addr) => {
+            let options = tls.socat().map_err(io::Error::other)?;
+            let mut socat = Command::new("socat")
+                .arg("-")
+                .arg(format!("OPENSSL:{},{}", addr, options))
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()?;
+            let res = match (socat.stdin.take(), socat.stdout.take()) {
+                (Some(writer), Some(reader)) => send(command, reader, writer),
+                _ => Err(io::Error::other("failed to connect to socat")),
+            };
+            let _ = socat.kill();
+            let _ = socat.wait();
+            res
+        }
+    }
+}
+
+/// `znapper transport-connect`, run in place of ssh for a sender with `--transport`. Returns
+/// the exit code.
+pub(crate) fn do_connect(opt: &ConnectOpt) -> i32 {
+    let command = opt.command.join(" ");
+    if !recv_command(&command) {
+        eprintln!(
+            "znapper: {} can't be run over {} - only the commands of znapper recv",
+            command, opt.transport
+        );
+        return CONNECT_FAILED;
+    }
+    match connect(&opt.transport, &opt.tls, &command) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("znapper: connecting to {} failed -> {:?}", opt.transport, e);
+            CONNECT_FAILED
+        }
+    }
+}
+
+/// A connection from a sender - over tcp, or from socat over the unix socket.
+trait Conn: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Conn for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+impl Conn for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+/// Answer one sender on `conn`.
+fn serve(opt: &RecvOpt, mut conn: impl Conn) -> io::Result<()> {
+    conn.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut lines = BufReader::new(conn.try_clone()?);
+    let mut command = String::new();
+    (&mut lines).take(MAX_COMMAND).read_line(&mut command)?;
+    let command = command.trim().to_string();
+    // The stream may stall for as long as the send does.
+    conn.set_read_timeout(None)?;
+    debug!("serve-recv -> {:?}", command);
+
+    let reply = if recv_command(&command) {
+        let (reader, writer) = io::pipe()?;
+        let stream = thread::spawn(move || read_frames(&mut lines, writer));
+        let reply = recv::answer(opt, &command, recv::Input::Pipe(reader));
+        // If zfs recv gave up part way there is no one to read the rest.
+        let _ = conn.shutdown(Shutdown::Read);
+        match stream.join() {
+            Ok(Ok(bytes)) => debug!("received {} bytes", bytes),
+            Ok(Err(e)) => debug!("reading the stream ended -> {:?}", e),
+            Err(_) => error!("serve-recv stream thread panicked"),
+        }
+        reply
+    } else {
+        serde_json::to_string(&RecvResult {
+            errors: vec![format!("{:?} is not a recv command", command)],
+            ..Default::default()
+        })
+    };
+    let reply = reply.map_err(io::Error::other)?;
+    conn.write_all(reply.as_bytes())?;
+    conn.write_all(b"\n")?;
+    conn.shutdown(Shutdown::Write)
+}
+
+/// `:7722` listens on every address.
+fn listen_addr(listen: &str) -> String {
+    match listen.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => listen.to_string(),
+    }
+}
+
+/// Terminates the TLS of serve-recv, for as long as it runs, and the socket it forwards to.
+struct Socat {
+    child: Child,
+    socket: PathBuf,
+}
+
+impl Drop for Socat {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.socket);
+    }
+}
+
+/// The unix socket socat forwards the TLS connections to `listen` to, in `dir`, which only we can
+/// get into.
+fn socket(dir: &Path, listen: &str) -> io::Result<(UnixListener, PathBuf)> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    let path = dir.join(format!("{}.sock", listen.replace(':', "_")));
+    // Left behind by a serve-recv that was killed.
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    Ok((listener, path))
+}
+
+/// Answer each sender that connects, in a thread of its own.
+fn accept<C: Conn + fmt::Debug>(opt: &RecvOpt, incoming: impl Iterator<Item = io::Result<C>>) {
+    let opt = Arc::new(opt.clone());
+    for conn in incoming {
+        let conn = match conn {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("accept failed -> {:?}", e);
+                continue;
+            }
+        };
+        let peer = format!("{:?}", conn);
+        debug!("serve-recv connection {}", peer);
+        let opt = opt.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(&opt, conn) {
+                warn!("serve-recv connection {} failed -> {:?}", peer, e);
+            }
+        });
+    }
+}
+
+pub(crate) fn do_serve_recv(opt: &ServeRecvOpt) {
+    let listen = listen_addr(&opt.listen);
+    if !opt.tls.is_set() {
+        let listener = match TcpListener::bind(&listen) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Unable to listen on {} -> {:?}", listen, e);
+                return;
+            }
+        };
+        warn!(
+            "serve-recv listening on {} over plain tcp - anyone who can reach it can send to {}",
+            listen, opt.recv.pool
+        );
+        accept(&opt.recv, listener.incoming());
+        return;
+    }
+
+    // With TLS, socat listens and forwards each connection to us over a private unix socket.
+    let options = match opt.tls.socat() {
+        Ok(o) => o,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let (listener, path) = match socket(&state_dir().join("serve-recv"), &listen) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Unable to create the socket of serve-recv -> {:?}", e);
+            return;
+        }
+    };
+    let (host, port) = listen.rsplit_once(':').unwrap_or(("0.0.0.0", &listen));
+    let spawned = Command::new("socat")
+        .arg(format!(
+            "OPENSSL-LISTEN:{},bind={},reuseaddr,fork,{}",
+            port, host, options
+        ))
+        .arg(format!("UNIX-CONNECT:{}", path.display()))
+        .stdin(Stdio::null())
+        .spawn();
+    let _socat = match spawned {
+        Ok(child) => Socat {
+            child,
+            socket: path,
+        },
+        Err(e) => {
+            error!("socat failed -> {:?}", e);
+            let _ = fs::remove_file(&path);
+            return;
+        }
+    };
+    info!("serve-recv listening on {} over TLS", listen);
+    accept(&opt.recv, listener.incoming());
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_are_framed_over_the_transport() {
+        assert_eq!(
+            "tls://backup1:7722".parse(),
+            Ok(Transport::Tls("backup1:7722".to_string()))
+        );
+        assert_eq!(
+            "tcp://10.0.0.2:7722".parse(),
+            Ok(Transport::Tcp("10.0.0.2:7722".to_string()))
+        );
+        assert!("ssh://backup1:22".parse::<Transport>().is_err());
+        assert!("tls://backup1".parse::<Transport>().is_err());
+
+        let stream: Vec<u8> = (0..3 * FRAME as u32 + 7).map(|i| i as u8).collect();
+        let mut framed = Vec::new();
+        assert_eq!(
+            write_frames(stream.as_slice(), &mut framed).unwrap(),
+            stream.len() as u64
+        );
+        let mut out = Vec::new();
+        read_frames(&mut framed.as_slice(), &mut out).unwrap();
+        assert_eq!(out, stream);
+
+        assert!(recv_command("from web1 zstd recv tank"));
+        assert!(recv_command("snapshots") && recv_command(""));
+        assert!(!recv_command("zfs list -H tank"));
+        assert_eq!(listen_addr(":7722"), "0.0.0.0:7722");
+    }
+
+    #[test]
+    fn tls_is_forwarded_over_a_socket_only_we_can_reach() {
+        let dir = std::env::temp_dir().join(format!("znapper-transport-{}", std::process::id()));
+        let (_listener, path) = socket(&dir.join("serve-recv"), "0.0.0.0:7722").unwrap();
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
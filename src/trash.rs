@@ -0,0 +1,249 @@
+//! Deferred destroy - a safety net against retention that takes too much.
+//!
+//! `snapshot_cleanup --defer` renames the snapshots it would destroy to `trash_<name>` rather
+//! than destroying them, and records the batch in `trash.json` in the state directory. They still
+//! hold their space, but are no longer auto_ snapshots, so nothing else prunes or replicates from
+//! them. A later cleanup of the pool destroys the batches whose grace period (`--grace`, a day
+//! by default) has passed, and until then `znapper undo-cleanup <pool>` renames the most recent
+//! batch back.
+//!
+//! This is a rename rather than `zfs destroy -d`, which only defers the destroy of a snapshot
+//! that is held or cloned - any other is destroyed at once, with no way back.
+
+use crate::anchors::state_dir;
+use crate::lock::LockOpt;
+use crate::privilege;
+use crate::process::{self, Kind, Timed};
+use crate::{audit, immutable, plan, remove_snap_only};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn};
+
+/// The prefix of a snapshot set aside by a deferred cleanup.
+pub(crate) const PREFIX: &str = "trash_";
+
+/// How long a deferred cleanup can be undone, without `--grace`.
+pub(crate) const GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct UndoCleanupOpt {
+    /// The pool whose most recent deferred cleanup is put back.
+    pub pool: String,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+    #[structopt(flatten)]
+    pub lock: LockOpt,
+}
+
+/// The snapshots one deferred cleanup set aside.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Batch {
+    pool: String,
+    trashed_at: i64,
+    /// When the grace period ends, and the snapshots may be destroyed.
+    purge_after: i64,
+    /// Their names before they were set aside.
+    snapshots: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TrashStore {
+    batches: Vec<Batch>,
+}
+
+impl TrashStore {
+    fn path() -> PathBuf {
+        state_dir().join("trash.json")
+    }
+
+    fn load() -> Result<Self, ()> {
+        let path = Self::path();
+        match File::open(&path) {
+            Ok(f) => serde_json::from_reader(f).map_err(|e| {
+                error!("Failed to parse {:?} -> {:?}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TrashStore::default()),
+            Err(e) => {
+                error!("Failed to open {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), ()> {
+        let path = Self::path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                error!("Failed to create state dir {:?} -> {:?}", parent, e);
+            })?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        let f = File::create(&tmp).map_err(|e| {
+            error!("Failed to create {:?} -> {:?}", tmp, e);
+        })?;
+        serde_json::to_writer_pretty(&f, self).map_err(|e| {
+            error!("Failed to write {:?} -> {:?}", tmp, e);
+        })?;
+        fs::rename(&tmp, &path).map_err(|e| {
+            error!("Failed to replace {:?} -> {:?}", path, e);
+        })
+    }
+}
+
+/// The name `snap_name` is set aside as, ie nvme@trash_auto_2024-05-01T030000Z.
+pub(crate) fn trashed(snap_name: &str) -> String {
+    match snap_name.split_once('@') {
+        Some((dataset, short)) => format!("{}@{}{}", dataset, PREFIX, short),
+        None => format!("{}{}", PREFIX, snap_name),
+    }
+}
+
+fn rename_snap(dry: bool, from: &str, to: &str) -> Result<(), ()> {
+    if dry {
+        info!("dryrun: rename_snap -> {} {}", from, to);
+        plan::dataset(plan::DatasetChange::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+        return Ok(());
+    }
+    info!("rename_snap -> {} {}", from, to);
+    let status = privilege::zfs()
+        .arg("rename")
+        .arg(from)
+        .arg(to)
+        .run_status(Kind::Zfs)
+        .map_err(|e| {
+            error!("snapshot rename failed -> {:?}", e);
+        })?;
+    debug!(?status);
+    if status.success() {
+        Ok(())
+    } else {
+        error!("snapshot rename failed -> {} {}", from, to);
+        Err(())
+    }
+}
+
+/// Set `snaps` of `pool` aside rather than destroying them, until `grace` has passed.
+pub(crate) fn defer(dry: bool, pool: &str, snaps: &[String], grace: Duration) -> Result<(), ()> {
+    let mut res = Ok(());
+    let mut trashed_snaps = Vec::new();
+    for snap in snaps.iter() {
+        if process::cancelled() {
+            res = Err(());
+            break;
+        }
+        if immutable::check_destroy(snap).is_err()
+            || rename_snap(dry, snap, &trashed(snap)).is_err()
+        {
+            res = Err(());
+            continue;
+        }
+        trashed_snaps.push(snap.clone());
+    }
+    if dry || trashed_snaps.is_empty() {
+        return res;
+    }
+
+    let now = OffsetDateTime::now_utc().timestamp();
+    let mut store = TrashStore::load()?;
+    store.batches.push(Batch {
+        pool: pool.to_string(),
+        trashed_at: now,
+        purge_after: now.saturating_add(grace.as_secs() as i64),
+        snapshots: trashed_snaps,
+    });
+    info!(
+        "Set aside {} snapshots of {} - znapper undo-cleanup {} puts them back",
+        store.batches.last().map(|b| b.snapshots.len()).unwrap_or(0),
+        pool,
+        pool
+    );
+    store.save().and(res)
+}
+
+/// Destroy the set aside snapshots of `pool` whose grace period is over.
+pub(crate) fn purge(dry: bool, pool: &str) -> Result<(), ()> {
+    let mut store = TrashStore::load()?;
+    let now = OffsetDateTime::now_utc().timestamp();
+    let mut res = Ok(());
+    let mut changed = false;
+    for batch in store
+        .batches
+        .iter_mut()
+        .filter(|b| b.pool == pool && b.purge_after <= now)
+    {
+        let before = batch.snapshots.len();
+        batch.snapshots.retain(|snap| {
+            let destroyed = remove_snap_only(dry, &trashed(snap), audit::Reason::Purge).is_ok();
+            if !destroyed {
+                res = Err(());
+            }
+            !destroyed
+        });
+        changed |= batch.snapshots.len() != before;
+    }
+    if dry || !changed {
+        return res;
+    }
+    store.batches.retain(|b| !b.snapshots.is_empty());
+    store.save().and(res)
+}
+
+pub(crate) fn do_undo_cleanup(opt: &UndoCleanupOpt) {
+    debug!("do_undo_cleanup");
+
+    let mut store = match TrashStore::load() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let i = match store.batches.iter().rposition(|b| b.pool == opt.pool) {
+        Some(i) => i,
+        None => {
+            warn!("No deferred cleanup of {} to undo", opt.pool);
+            return;
+        }
+    };
+
+    let batch = &mut store.batches[i];
+    info!(
+        "Putting back the {} snapshots of {} set aside at {}",
+        batch.snapshots.len(),
+        opt.pool,
+        OffsetDateTime::from_unix_timestamp(batch.trashed_at).format("%Y-%m-%dT%H:%M:%SZ")
+    );
+    batch
+        .snapshots
+        .retain(|snap| rename_snap(opt.dryrun, &trashed(snap), snap).is_err());
+    if opt.dryrun {
+        return;
+    }
+    if batch.snapshots.is_empty() {
+        store.batches.remove(i);
+    } else {
+        error!(
+            "{} snapshots of {} could not be put back, and are still set aside",
+            batch.snapshots.len(),
+            opt.pool
+        );
+    }
+    let _ = store.save();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trashed_names_keep_the_dataset() {
+        assert_eq!(
+            trashed("nvme/home@auto_2024-05-01T030000Z"),
+            "nvme/home@trash_auto_2024-05-01T030000Z"
+        );
+    }
+}
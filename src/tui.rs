@@ -0,0 +1,763 @@
+//! A minimal interactive browser for pools, datasets and snapshots.
+//!
+//! Snapshots are listed with their size, age and the class of their prefix, and can be
+//! destroyed, pinned, rolled back to, cloned, diffed against the next newer snapshot (or the
+//! dataset, for the newest) and mounted with `znapper mount`.
+//!
+//! All zfs commands issued from here capture their output so that nothing is written over the
+//! terminal while the ui is active - errors are shown in the status line instead. Destroys and
+//! rollbacks are run as the rest of znapper runs them, audited, and the ui is drawn afresh over
+//! their log. As elsewhere, only snapshots znapper named are destroyed, unless given `--force`. A mount is the exception: the ui steps aside for it, so that its log can be read,
+//! and comes back on enter.
+
+use crate::model::{Class, Snapshot};
+use crate::mount::{self, MountOpt};
+use crate::summary::human_bytes;
+use crate::{audit, diff, email, lzc, privilege, trash};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::error;
+
+/// The hold tag used to "pin" a snapshot so that it can not be destroyed by cleanup.
+const PIN_TAG: &str = "znapper_pin";
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct TuiOpt {
+    #[structopt(short = "n")]
+    dryrun: bool,
+}
+
+struct DatasetRow {
+    name: String,
+    used: u64,
+    avail: u64,
+}
+
+struct SnapRow {
+    name: String,
+    used: u64,
+    creation: i64,
+    userrefs: u64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Pane {
+    Pools,
+    Datasets,
+    Snapshots,
+}
+
+enum SnapAction {
+    Destroy,
+    Pin,
+    Unpin,
+    Rollback,
+    Clone(String),
+    Mount(String),
+}
+
+enum Mode {
+    Browse,
+    Confirm(SnapAction),
+    CloneInput(String),
+    MountInput(String),
+    /// The changes since a snapshot, and how far they are scrolled.
+    Diff {
+        title: String,
+        lines: Vec<String>,
+        scroll: u16,
+    },
+}
+
+struct App {
+    dryrun: bool,
+    pane: Pane,
+    mode: Mode,
+    pools: Vec<String>,
+    pool_state: ListState,
+    datasets: Vec<DatasetRow>,
+    dataset_state: ListState,
+    snaps: Vec<SnapRow>,
+    snap_state: ListState,
+    status: String,
+    /// A snapshot to mount, and where, once the ui has stepped aside.
+    pending_mount: Option<(String, String)>,
+    /// Draw the ui afresh, over what was logged to the terminal.
+    redraw: bool,
+}
+
+fn zfs_output(bin: &str, args: &[&str]) -> Result<String, String> {
+    let output = privilege::command(bin)
+        .args(args)
+        .output()
+        .map_err(|e| format!("{} failed -> {:?}", bin, e))?;
+
+    if output.status.success() {
+        String::from_utf8(output.stdout).map_err(|e| format!("invalid utf8 -> {:?}", e))
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+fn list_pools() -> Result<Vec<String>, String> {
+    let stdout = zfs_output("zpool", &["list", "-H", "-o", "name"])?;
+    Ok(stdout
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+fn list_datasets(pool: &str) -> Result<Vec<DatasetRow>, String> {
+    let stdout = zfs_output(
+        "zfs",
+        &[
+            "list",
+            "-H",
+            "-p",
+            "-r",
+            "-t",
+            "filesystem,volume",
+            "-o",
+            "name,used,avail",
+            pool,
+        ],
+    )?;
+    Ok(stdout
+        .lines()
+        .filter_map(|line| {
+            let mut lsplit = line.split('\t');
+            match (lsplit.next(), lsplit.next(), lsplit.next()) {
+                (Some(name), Some(used), Some(avail)) => Some(DatasetRow {
+                    name: name.to_string(),
+                    used: used.parse().unwrap_or(0),
+                    avail: avail.parse().unwrap_or(0),
+                }),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+fn list_snaps(dataset: &str) -> Result<Vec<SnapRow>, String> {
+    let stdout = zfs_output(
+        "zfs",
+        &[
+            "list",
+            "-H",
+            "-p",
+            "-d",
+            "1",
+            "-t",
+            "snapshot",
+            "-o",
+            "name,used,creation,userrefs",
+            dataset,
+        ],
+    )?;
+    Ok(stdout
+        .lines()
+        .filter_map(|line| {
+            let mut lsplit = line.split('\t');
+            match (lsplit.next(), lsplit.next(), lsplit.next(), lsplit.next()) {
+                (Some(name), Some(used), Some(creation), Some(userrefs)) => Some(SnapRow {
+                    name: name.to_string(),
+                    used: used.parse().unwrap_or(0),
+                    creation: creation.parse().unwrap_or(0),
+                    userrefs: userrefs.parse().unwrap_or(0),
+                }),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+fn has_pin(snap_name: &str) -> bool {
+    zfs_output("zfs", &["holds", "-H", snap_name])
+        .map(|stdout| {
+            stdout
+                .lines()
+                .any(|line| line.split('\t').nth(1) == Some(PIN_TAG))
+        })
+        .unwrap_or(false)
+}
+
+/// The class of a snapshot's prefix, as the snapshots pane shows it.
+fn class_label(snap_name: &str) -> &'static str {
+    let short = snap_name.rsplit('@').next().unwrap_or(snap_name);
+    if short.starts_with(trash::PREFIX) {
+        return "trash";
+    }
+    match Snapshot::parse(snap_name).map(|s| s.class()) {
+        Ok(Class::Auto) => "auto",
+        Ok(Class::Repl) => "repl",
+        Ok(Class::Redact) => "redact",
+        _ => "other",
+    }
+}
+
+fn human_age(creation: i64, now: i64) -> String {
+    let secs = (now - creation).max(0);
+    let (days, hours, mins) = (secs / 86400, (secs % 86400) / 3600, (secs % 3600) / 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else {
+        format!("{}m", mins)
+    }
+}
+
+fn move_selection(state: &mut ListState, len: usize, down: bool) {
+    if len == 0 {
+        state.select(None);
+        return;
+    }
+    let next = match (state.selected(), down) {
+        (Some(i), true) => (i + 1).min(len - 1),
+        (Some(i), false) => i.saturating_sub(1),
+        (None, _) => 0,
+    };
+    state.select(Some(next));
+}
+
+impl App {
+    fn new(dryrun: bool) -> Self {
+        let mut app = App {
+            dryrun,
+            pane: Pane::Pools,
+            mode: Mode::Browse,
+            pools: Vec::new(),
+            pool_state: ListState::default(),
+            datasets: Vec::new(),
+            dataset_state: ListState::default(),
+            snaps: Vec::new(),
+            snap_state: ListState::default(),
+            pending_mount: None,
+            redraw: false,
+            status: if dryrun {
+                "dryrun: no changes will be made".to_string()
+            } else {
+                String::new()
+            },
+        };
+        app.reload_pools();
+        app
+    }
+
+    fn selected_pool(&self) -> Option<&str> {
+        self.pool_state
+            .selected()
+            .and_then(|i| self.pools.get(i))
+            .map(String::as_str)
+    }
+
+    fn selected_dataset(&self) -> Option<&str> {
+        self.dataset_state
+            .selected()
+            .and_then(|i| self.datasets.get(i))
+            .map(|d| d.name.as_str())
+    }
+
+    fn selected_snap(&self) -> Option<&SnapRow> {
+        self.snap_state.selected().and_then(|i| self.snaps.get(i))
+    }
+
+    fn reload_pools(&mut self) {
+        match list_pools() {
+            Ok(pools) => self.pools = pools,
+            Err(e) => self.status = e,
+        }
+        if self.pool_state.selected().map(|i| i >= self.pools.len()) != Some(false) {
+            self.pool_state
+                .select(if self.pools.is_empty() { None } else { Some(0) });
+        }
+        self.reload_datasets();
+    }
+
+    fn reload_datasets(&mut self) {
+        self.datasets = match self.selected_pool().map(list_datasets) {
+            Some(Ok(datasets)) => datasets,
+            Some(Err(e)) => {
+                self.status = e;
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+        if self
+            .dataset_state
+            .selected()
+            .map(|i| i >= self.datasets.len())
+            != Some(false)
+        {
+            self.dataset_state.select(if self.datasets.is_empty() {
+                None
+            } else {
+                Some(0)
+            });
+        }
+        self.reload_snaps();
+    }
+
+    fn reload_snaps(&mut self) {
+        self.snaps = match self.selected_dataset().map(list_snaps) {
+            Some(Ok(snaps)) => snaps,
+            Some(Err(e)) => {
+                self.status = e;
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+        if self.snap_state.selected().map(|i| i >= self.snaps.len()) != Some(false) {
+            self.snap_state
+                .select(if self.snaps.is_empty() { None } else { Some(0) });
+        }
+    }
+
+    fn move_cursor(&mut self, down: bool) {
+        match self.pane {
+            Pane::Pools => {
+                move_selection(&mut self.pool_state, self.pools.len(), down);
+                self.dataset_state.select(None);
+                self.reload_datasets();
+            }
+            Pane::Datasets => {
+                move_selection(&mut self.dataset_state, self.datasets.len(), down);
+                self.snap_state.select(None);
+                self.reload_snaps();
+            }
+            Pane::Snapshots => move_selection(&mut self.snap_state, self.snaps.len(), down),
+        }
+    }
+
+    fn describe(&self, action: &SnapAction) -> String {
+        let snap = self.selected_snap().map(|s| s.name.as_str()).unwrap_or("");
+        match action {
+            SnapAction::Destroy => format!("Destroy {} ?", snap),
+            SnapAction::Pin => format!("Pin (zfs hold {}) {} ?", PIN_TAG, snap),
+            SnapAction::Unpin => format!("Unpin (zfs release {}) {} ?", PIN_TAG, snap),
+            SnapAction::Rollback => format!(
+                "Rollback to {} ? This DESTROYS all newer snapshots of the dataset!",
+                snap
+            ),
+            SnapAction::Clone(target) => format!("Clone {} to {} ?", snap, target),
+            SnapAction::Mount(path) => format!("Mount {} read-only at {} ?", snap, path),
+        }
+    }
+
+    fn apply(&mut self, action: &SnapAction) {
+        let snap = match self.selected_snap() {
+            Some(s) => s.name.clone(),
+            None => return,
+        };
+        if let SnapAction::Mount(path) = action {
+            self.pending_mount = Some((snap, path.clone()));
+            return;
+        }
+
+        let args: Vec<&str> = match action {
+            SnapAction::Destroy => vec!["destroy", snap.as_str()],
+            SnapAction::Pin => vec!["hold", PIN_TAG, snap.as_str()],
+            SnapAction::Unpin => vec!["release", PIN_TAG, snap.as_str()],
+            SnapAction::Rollback => vec!["rollback", "-r", snap.as_str()],
+            SnapAction::Clone(target) => vec!["clone", snap.as_str(), target.as_str()],
+            SnapAction::Mount(_) => return,
+        };
+
+        // Destroys and rollbacks are run as the rest of znapper runs them - checked against
+        // immutability windows (and for a destroy, that znapper named the snapshot), through the
+        // zfs backend, and audited - and log why they failed.
+        let own = match action {
+            SnapAction::Destroy => Some(crate::remove_snap_only(
+                self.dryrun,
+                &snap,
+                audit::Reason::Requested,
+            )),
+            SnapAction::Rollback => Some(crate::rollback_snap(
+                self.dryrun,
+                &snap,
+                audit::Reason::Requested,
+            )),
+            _ => None,
+        };
+        if own.is_some() {
+            self.redraw = true;
+        }
+
+        self.status = if let Some(res) = own {
+            match (res, self.dryrun) {
+                (Ok(()), true) => format!("dryrun -> zfs {}", args.join(" ")),
+                (Ok(()), false) => format!("zfs {} -> success", args.join(" ")),
+                (Err(()), _) => format!(
+                    "zfs {} -> {}",
+                    args.join(" "),
+                    email::logged().last().map_or("failed", String::as_str)
+                ),
+            }
+        } else if self.dryrun {
+            format!("dryrun -> zfs {}", args.join(" "))
+        } else {
+            // Pins are holds, which libzfs_core places and releases itself.
+            let snaps = std::slice::from_ref(&snap);
+            let native = match action {
+                SnapAction::Pin if lzc::active() => Some(lzc::hold(snaps, PIN_TAG)),
+                SnapAction::Unpin if lzc::active() => Some(lzc::release(snaps, PIN_TAG)),
+                _ => None,
+            };
+            match native.map(|res| res.map_err(|failures| lzc::describe(&failures))) {
+                Some(Ok(())) => format!("zfs {} -> success", args.join(" ")),
+                Some(Err(e)) => format!("zfs {} -> {}", args.join(" "), e),
+                None => match zfs_output("zfs", &args) {
+                    Ok(_) => format!("zfs {} -> success", args.join(" ")),
+                    Err(e) => format!("zfs {} -> {}", args.join(" "), e),
+                },
+            }
+        };
+
+        if let SnapAction::Clone(_) = action {
+            self.reload_datasets();
+        } else {
+            self.reload_snaps();
+        }
+    }
+
+    /// The changes from the selected snapshot to the next newer one, or to the dataset as it is
+    /// now if it is the newest.
+    fn diff(&mut self) {
+        let (dataset, snap) = match (self.selected_dataset(), self.selected_snap()) {
+            (Some(d), Some(s)) => (d.to_string(), s),
+            _ => return,
+        };
+        let to = self
+            .snaps
+            .iter()
+            .filter(|s| s.creation > snap.creation)
+            .min_by_key(|s| s.creation)
+            .map(|s| s.name.clone())
+            .unwrap_or(dataset);
+        let from = snap.name.clone();
+        match diff::changes(&from, &to) {
+            Ok(records) => {
+                let mut lines: Vec<String> = records.iter().map(diff::Record::line).collect();
+                if lines.is_empty() {
+                    lines.push("no changes".to_string());
+                }
+                self.mode = Mode::Diff {
+                    title: format!("{} -> {}", from, to),
+                    lines,
+                    scroll: 0,
+                };
+            }
+            Err(e) => self.status = e,
+        }
+    }
+
+    /// Returns false when the ui should exit.
+    fn handle_key(&mut self, code: KeyCode) -> bool {
+        match std::mem::replace(&mut self.mode, Mode::Browse) {
+            Mode::Confirm(action) => {
+                if let KeyCode::Char('y') | KeyCode::Char('Y') = code {
+                    self.apply(&action);
+                } else {
+                    self.status = "cancelled".to_string();
+                }
+            }
+            Mode::CloneInput(mut target) => match code {
+                KeyCode::Enter if !target.is_empty() => {
+                    self.mode = Mode::Confirm(SnapAction::Clone(target));
+                }
+                KeyCode::Esc => self.status = "cancelled".to_string(),
+                KeyCode::Backspace => {
+                    target.pop();
+                    self.mode = Mode::CloneInput(target);
+                }
+                KeyCode::Char(c) => {
+                    target.push(c);
+                    self.mode = Mode::CloneInput(target);
+                }
+                _ => self.mode = Mode::CloneInput(target),
+            },
+            Mode::MountInput(mut path) => match code {
+                KeyCode::Enter if !path.is_empty() => {
+                    self.mode = Mode::Confirm(SnapAction::Mount(path));
+                }
+                KeyCode::Esc => self.status = "cancelled".to_string(),
+                KeyCode::Backspace => {
+                    path.pop();
+                    self.mode = Mode::MountInput(path);
+                }
+                KeyCode::Char(c) => {
+                    path.push(c);
+                    self.mode = Mode::MountInput(path);
+                }
+                _ => self.mode = Mode::MountInput(path),
+            },
+            Mode::Diff {
+                title,
+                lines,
+                scroll,
+            } => {
+                let last = lines.len().saturating_sub(1).min(u16::MAX as usize) as u16;
+                let scroll = match code {
+                    KeyCode::Char('q') | KeyCode::Esc => return true,
+                    KeyCode::Up | KeyCode::Char('k') => scroll.saturating_sub(1),
+                    KeyCode::Down | KeyCode::Char('j') => scroll.saturating_add(1).min(last),
+                    KeyCode::PageUp => scroll.saturating_sub(20),
+                    KeyCode::PageDown => scroll.saturating_add(20).min(last),
+                    _ => scroll,
+                };
+                self.mode = Mode::Diff {
+                    title,
+                    lines,
+                    scroll,
+                };
+            }
+            Mode::Browse => match code {
+                KeyCode::Char('q') | KeyCode::Esc => return false,
+                KeyCode::Up | KeyCode::Char('k') => self.move_cursor(false),
+                KeyCode::Down | KeyCode::Char('j') => self.move_cursor(true),
+                KeyCode::Left | KeyCode::Char('h') => {
+                    self.pane = match self.pane {
+                        Pane::Snapshots => Pane::Datasets,
+                        _ => Pane::Pools,
+                    }
+                }
+                KeyCode::Right | KeyCode::Char('l') | KeyCode::Tab | KeyCode::Enter => {
+                    self.pane = match self.pane {
+                        Pane::Pools => Pane::Datasets,
+                        _ => Pane::Snapshots,
+                    }
+                }
+                KeyCode::Char('R') => self.reload_pools(),
+                KeyCode::Char(c) if self.pane == Pane::Snapshots => {
+                    let snap = match self.selected_snap() {
+                        Some(s) => s.name.clone(),
+                        None => return true,
+                    };
+                    match c {
+                        'd' => self.mode = Mode::Confirm(SnapAction::Destroy),
+                        'p' => {
+                            self.mode = Mode::Confirm(if has_pin(&snap) {
+                                SnapAction::Unpin
+                            } else {
+                                SnapAction::Pin
+                            })
+                        }
+                        'r' => self.mode = Mode::Confirm(SnapAction::Rollback),
+                        'c' => {
+                            let target = snap.replace('@', "_");
+                            self.mode = Mode::CloneInput(target);
+                        }
+                        'f' => self.diff(),
+                        'm' => {
+                            let short = snap.rsplit('@').next().unwrap_or(snap.as_str());
+                            self.mode = Mode::MountInput(format!("/mnt/{}", short));
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            },
+        }
+        true
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, help, status] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [pools_area, datasets_area, snaps_area] = Layout::horizontal([
+            Constraint::Percentage(15),
+            Constraint::Percentage(35),
+            Constraint::Percentage(50),
+        ])
+        .areas(main);
+
+        let highlight = Style::default().add_modifier(Modifier::REVERSED);
+        let block = |title: &'static str, pane: Pane| {
+            let block = Block::default().borders(Borders::ALL).title(title);
+            if self.pane == pane {
+                block.border_style(Style::default().add_modifier(Modifier::BOLD))
+            } else {
+                block
+            }
+        };
+
+        let pools = List::new(self.pools.iter().map(|p| ListItem::new(p.as_str())))
+            .block(block("Pools", Pane::Pools))
+            .highlight_style(highlight);
+        frame.render_stateful_widget(pools, pools_area, &mut self.pool_state);
+
+        let datasets = List::new(self.datasets.iter().map(|d| {
+            ListItem::new(format!(
+                "{}  used {} avail {}",
+                d.name,
+                human_bytes(d.used),
+                human_bytes(d.avail)
+            ))
+        }))
+        .block(block("Datasets", Pane::Datasets))
+        .highlight_style(highlight);
+        frame.render_stateful_widget(datasets, datasets_area, &mut self.dataset_state);
+
+        let now = OffsetDateTime::now_utc().timestamp();
+        let snaps = List::new(self.snaps.iter().map(|s| {
+            let short = s.name.rsplit('@').next().unwrap_or(s.name.as_str());
+            ListItem::new(format!(
+                "{}{}  {}  {}  {} ago",
+                if s.userrefs > 0 { "* " } else { "  " },
+                short,
+                class_label(&s.name),
+                human_bytes(s.used),
+                human_age(s.creation, now)
+            ))
+        }))
+        .block(block("Snapshots", Pane::Snapshots))
+        .highlight_style(highlight);
+        frame.render_stateful_widget(snaps, snaps_area, &mut self.snap_state);
+
+        frame.render_widget(
+            Paragraph::new(
+                "q quit | arrows/hjkl move | R refresh | d destroy | p pin/unpin | r rollback | \
+                 c clone | f diff | m mount",
+            ),
+            help,
+        );
+        frame.render_widget(Paragraph::new(self.status.as_str()), status);
+
+        if let Mode::Diff {
+            title,
+            lines,
+            scroll,
+        } = &self.mode
+        {
+            let area = centered(main, 90, main.height.saturating_sub(2));
+            frame.render_widget(Clear, area);
+            frame.render_widget(
+                Paragraph::new(
+                    lines
+                        .iter()
+                        .map(|l| Line::from(l.as_str()))
+                        .collect::<Vec<_>>(),
+                )
+                .scroll((*scroll, 0))
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(format!("{}  (q closes)", title)),
+                ),
+                area,
+            );
+        }
+
+        let prompt = match &self.mode {
+            Mode::Browse | Mode::Diff { .. } => None,
+            Mode::Confirm(action) => Some(format!("{}  [y/N]", self.describe(action))),
+            Mode::CloneInput(target) => Some(format!("Clone to: {}_", target)),
+            Mode::MountInput(path) => Some(format!("Mount at: {}_", path)),
+        };
+        if let Some(prompt) = prompt {
+            let area = centered(main, 70, 3);
+            frame.render_widget(Clear, area);
+            frame.render_widget(
+                Paragraph::new(Line::from(prompt))
+                    .block(Block::default().borders(Borders::ALL).title("Confirm")),
+                area,
+            );
+        }
+    }
+}
+
+fn centered(area: Rect, width_pct: u16, height: u16) -> Rect {
+    let width = area.width * width_pct / 100;
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + area.height.saturating_sub(height) / 2,
+        width,
+        height: height.min(area.height),
+    }
+}
+
+/// Step aside from the ui to mount `snapshot` at `path`, so that its log can be read.
+fn mount(
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+    snapshot: String,
+    path: String,
+) -> std::io::Result<()> {
+    ratatui::restore();
+    let opt = MountOpt {
+        snapshot,
+        path,
+        clone: false,
+        dryrun: app.dryrun,
+    };
+    mount::do_mount(&opt);
+    println!("Press enter to return to the browser");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    *terminal = ratatui::try_init()?;
+    app.status = format!(
+        "mount of {} at {} done - znapper unmount {} when finished with it",
+        opt.snapshot, opt.path, opt.path
+    );
+    app.reload_datasets();
+    Ok(())
+}
+
+fn run(terminal: &mut DefaultTerminal, app: &mut App) -> std::io::Result<()> {
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press && !app.handle_key(key.code) {
+                return Ok(());
+            }
+        }
+        if let Some((snapshot, path)) = app.pending_mount.take() {
+            mount(terminal, app, snapshot, path)?;
+        }
+        if std::mem::take(&mut app.redraw) {
+            terminal.clear()?;
+        }
+    }
+}
+
+pub(crate) fn do_tui(opt: &TuiOpt) {
+    let mut app = App::new(opt.dryrun);
+    let mut terminal = match ratatui::try_init() {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Unable to initialise terminal -> {:?}", e);
+            return;
+        }
+    };
+    let res = run(&mut terminal, &mut app);
+    ratatui::restore();
+    if let Err(e) = res {
+        error!("tui failed -> {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_are_classed_by_prefix() {
+        assert_eq!(class_label("tank/data@auto_2024-05-01T030000Z"), "auto");
+        assert_eq!(
+            class_label("tank/data@trash_auto_2024-05-01T030000Z"),
+            "trash"
+        );
+        assert_eq!(class_label("tank/data@before-upgrade"), "other");
+    }
+}
@@ -0,0 +1,258 @@
+//! `znapper generate-units` - the systemd service and timer that run a job on a schedule.
+//!
+//! The service runs `znapper sync <job>` once, as the binary that generated it and with the same
+//! zfs binary, escalation and directories, so the units work as generated. It is hardened to
+//! write only what znapper writes - its state, locks, metadata and metrics - and to reach only
+//! the network ssh and the notifications need. With `--install` they are written to
+//! /etc/systemd/system (or `--dir`), ready for `systemctl enable --now znapper-<job>.timer`.
+
+use crate::anchors::state_dir;
+use crate::config::{config_dir, Config, Job, DEFAULT_CONFIG_DIR};
+use crate::lock::DEFAULT_LOCK_DIR;
+use crate::privilege;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use tracing::{debug, error, info};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct GenerateUnitsOpt {
+    /// The job of znapper.toml to run.
+    #[structopt(long = "job")]
+    job: String,
+    /// When to run it, as the OnCalendar= of the timer, ie hourly, daily or '*-*-* 18:00:00'.
+    #[structopt(long = "schedule", default_value = "daily")]
+    schedule: String,
+    /// Run it as this user, for a znapper running unprivileged with delegated permissions.
+    #[structopt(long = "user")]
+    user: Option<String>,
+    /// Write the units to --dir, rather than printing them.
+    #[structopt(long = "install")]
+    install: bool,
+    /// Where --install writes the units.
+    #[structopt(long = "dir", default_value = "/etc/systemd/system")]
+    dir: PathBuf,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+}
+
+/// How the service runs znapper - as what, with which options, and what it writes to.
+#[derive(Debug)]
+struct Exec {
+    znapper: String,
+    args: Vec<String>,
+    environment: Vec<(String, String)>,
+    user: Option<String>,
+    /// sudo and doas need to gain privileges, which the hardening would take away.
+    escalates: bool,
+    writes: BTreeSet<String>,
+    network: bool,
+}
+
+/// The directories a sync of `job` writes to, other than the datasets.
+fn writes(job: &Job, config: &Config) -> BTreeSet<String> {
+    let lock_dir = std::env::var("ZNAPPER_LOCK_DIR").unwrap_or_else(|_| DEFAULT_LOCK_DIR.into());
+    let mut writes: BTreeSet<String> = [state_dir().to_string_lossy().into_owned(), lock_dir]
+        .into_iter()
+        .collect();
+    let files = job
+        .remote
+        .iter()
+        .map(|remote| remote.metadata.as_str())
+        .chain(config.metrics.textfile.as_deref());
+    for file in files {
+        if let Some(parent) = Path::new(file)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+        {
+            writes.insert(parent.to_string_lossy().into_owned());
+        }
+    }
+    writes
+}
+
+fn service(job_name: &str, exec: &Exec) -> String {
+    let mut unit = format!("[Unit]\nDescription=znapper sync of job {}\n", job_name);
+    if exec.network {
+        unit.push_str("Wants=network-online.target\nAfter=zfs.target network-online.target\n");
+    } else {
+        unit.push_str("After=zfs.target\n");
+    }
+
+    unit.push_str("\n[Service]\nType=oneshot\n");
+    let mut command = vec![exec.znapper.clone()];
+    command.extend(exec.args.iter().cloned());
+    command.extend(["sync".to_string(), job_name.to_string()]);
+    unit.push_str(&format!("ExecStart={}\n", command.join(" ")));
+    if let Some(user) = exec.user.as_deref() {
+        unit.push_str(&format!("User={}\n", user));
+    }
+    for (key, value) in exec.environment.iter() {
+        unit.push_str(&format!("Environment={}={}\n", key, value));
+    }
+    unit.push_str("Nice=10\nIOSchedulingClass=idle\n");
+
+    // zfs reaches its datasets through /dev/zfs, so the filesystem can be read only.
+    unit.push_str(
+        "ProtectSystem=strict\nProtectHome=read-only\nPrivateTmp=yes\nProtectKernelTunables=yes\n\
+         ProtectKernelModules=yes\nProtectKernelLogs=yes\nProtectControlGroups=yes\n\
+         ProtectClock=yes\nProtectHostname=yes\nRestrictNamespaces=yes\nRestrictRealtime=yes\n\
+         LockPersonality=yes\nMemoryDenyWriteExecute=yes\nSystemCallArchitectures=native\n",
+    );
+    if !exec.escalates {
+        unit.push_str("NoNewPrivileges=yes\nRestrictSUIDSGID=yes\n");
+    }
+    if exec.network {
+        unit.push_str("RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6\n");
+    } else {
+        unit.push_str("RestrictAddressFamilies=AF_UNIX\n");
+    }
+    if !exec.writes.is_empty() {
+        let writes: Vec<_> = exec.writes.iter().map(|dir| format!("-{}", dir)).collect();
+        unit.push_str(&format!("ReadWritePaths={}\n", writes.join(" ")));
+    }
+    unit
+}
+
+fn timer(job_name: &str, schedule: &str) -> String {
+    format!(
+        "[Unit]\nDescription=Timer of the znapper sync of job {}\n\n[Timer]\nOnCalendar={}\n\
+         Persistent=true\n\n[Install]\nWantedBy=timers.target\n",
+        job_name, schedule
+    )
+}
+
+/// Write `contents` to `path`, as root would expect a unit to be.
+fn install(dry: bool, path: &Path, contents: &str) -> Result<(), ()> {
+    if dry {
+        info!("dryrun: write {:?}", path);
+        return Ok(());
+    }
+    fs::write(path, contents).map_err(|e| {
+        error!("Failed to write {:?} -> {:?}", path, e);
+    })?;
+    info!("Wrote {:?}", path);
+    Ok(())
+}
+
+pub(crate) fn do_generate_units(opt: &GenerateUnitsOpt) {
+    debug!("do_generate_units");
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+    let job = match config.job.get(&opt.job) {
+        Some(job) => job,
+        None => {
+            error!("No job {} in znapper.toml", opt.job);
+            return;
+        }
+    };
+    // The name ends up in the unit names and on the ExecStart line.
+    if opt.job.is_empty()
+        || !opt
+            .job
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        error!(
+            "Job {} can't be run from a unit - use letters, digits, -, _ and .",
+            opt.job
+        );
+        return;
+    }
+    if opt.schedule.trim().is_empty() || opt.schedule.contains('\n') {
+        error!("Invalid schedule {:?}", opt.schedule);
+        return;
+    }
+    let znapper = match std::env::current_exe() {
+        Ok(exe) => exe.to_string_lossy().into_owned(),
+        Err(e) => {
+            error!("Unable to find the znapper binary -> {:?}", e);
+            return;
+        }
+    };
+
+    let mut environment = Vec::new();
+    let config_dir = config_dir().to_string_lossy().into_owned();
+    if config_dir != DEFAULT_CONFIG_DIR {
+        environment.push(("ZNAPPER_CONFIG_DIR".to_string(), config_dir));
+    }
+    for var in ["ZNAPPER_STATE_DIR", "ZNAPPER_LOCK_DIR", "ZNAPPER_ZFS"] {
+        if let Ok(value) = std::env::var(var) {
+            environment.push((var.to_string(), value));
+        }
+    }
+    let exec = Exec {
+        znapper,
+        args: privilege::args(),
+        environment,
+        user: opt.user.clone(),
+        escalates: privilege::escalates(),
+        writes: writes(job, &config),
+        network: !job.remote.is_empty()
+            || job.notify.healthcheck.is_some()
+            || job.notify.webhook.is_some()
+            || job.notify.slack.is_some()
+            || config.email.is_some(),
+    };
+    debug!(?exec);
+
+    let units = [
+        (
+            format!("znapper-{}.service", opt.job),
+            service(&opt.job, &exec),
+        ),
+        (
+            format!("znapper-{}.timer", opt.job),
+            timer(&opt.job, opt.schedule.trim()),
+        ),
+    ];
+    if !opt.install {
+        for (name, contents) in units.iter() {
+            println!("# {}\n{}", name, contents);
+        }
+        return;
+    }
+    for (name, contents) in units.iter() {
+        if install(opt.dryrun, &opt.dir.join(name), contents).is_err() {
+            return;
+        }
+    }
+    info!(
+        "Run systemctl daemon-reload && systemctl enable --now znapper-{}.timer to start it",
+        opt.job
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units_run_the_sync_of_the_job() {
+        let exec = Exec {
+            znapper: "/usr/bin/znapper".to_string(),
+            args: vec!["--escalate".to_string(), "sudo".to_string()],
+            environment: vec![("ZNAPPER_STATE_DIR".to_string(), "/srv/znapper".to_string())],
+            user: Some("backup".to_string()),
+            escalates: true,
+            writes: ["/srv/znapper".to_string(), "/run/znapper".to_string()]
+                .into_iter()
+                .collect(),
+            network: true,
+        };
+        let service = service("nightly", &exec);
+        assert!(service.contains("ExecStart=/usr/bin/znapper --escalate sudo sync nightly\n"));
+        assert!(service.contains("User=backup\nEnvironment=ZNAPPER_STATE_DIR=/srv/znapper\n"));
+        assert!(service.contains("ReadWritePaths=-/run/znapper -/srv/znapper\n"));
+        assert!(service.contains("RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6\n"));
+        // sudo has to be able to gain root.
+        assert!(!service.contains("NoNewPrivileges"));
+
+        assert!(
+            timer("nightly", "hourly").contains("[Timer]\nOnCalendar=hourly\nPersistent=true\n")
+        );
+    }
+}
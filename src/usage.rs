@@ -0,0 +1,219 @@
+//! `znapper usage` - where the space of a pool goes, per dataset and per class of snapshot, and
+//! what snapshot_cleanup would give back.
+
+use crate::model::Class;
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use crate::{cleanup_expired, OutputFormat};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct UsageOpt {
+    pool: String,
+    /// Also report what snapshot_cleanup with this many hours would reclaim.
+    #[structopt(long = "keep-hours")]
+    keep_hours: Option<u32>,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+}
+
+/// How many snapshots of a class a dataset has, and the sum of what each alone holds.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+struct ClassUsage {
+    count: usize,
+    used: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct DatasetUsage {
+    name: String,
+    used: u64,
+    used_by_snapshots: u64,
+    used_by_dataset: u64,
+    auto: ClassUsage,
+    repl: ClassUsage,
+    other: ClassUsage,
+    /// What the snapshots snapshot_cleanup would destroy hold, with --keep-hours.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reclaimable: Option<ClassUsage>,
+}
+
+#[derive(Debug, Serialize)]
+struct Usage {
+    pool: String,
+    keep_hours: Option<u32>,
+    /// Most space held by snapshots first.
+    datasets: Vec<DatasetUsage>,
+}
+
+fn zfs_lines(args: &[&str]) -> Result<Vec<String>, ()> {
+    let output = privilege::zfs()
+        .args(args)
+        .run_output(Kind::Zfs)
+        .map_err(|e| {
+            error!("zfs {} failed -> {:?}", args.join(" "), e);
+        })?;
+    if !output.status.success() {
+        error!(
+            "zfs {} failed -> {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(());
+    }
+    let lines: Vec<_> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect();
+    debug!("{:?}", lines);
+    Ok(lines)
+}
+
+fn parse_u64(s: &str) -> u64 {
+    s.parse().unwrap_or(0)
+}
+
+fn usage(opt: &UsageOpt) -> Result<Usage, ()> {
+    let mut datasets: BTreeMap<String, DatasetUsage> = BTreeMap::new();
+    for line in zfs_lines(&[
+        "list",
+        "-H",
+        "-p",
+        "-r",
+        "-t",
+        "filesystem,volume",
+        "-o",
+        "name,used,usedbysnapshots,usedbydataset",
+        &opt.pool,
+    ])? {
+        let f: Vec<_> = line.split('\t').collect();
+        if f.len() < 4 {
+            continue;
+        }
+        datasets.insert(
+            f[0].to_string(),
+            DatasetUsage {
+                name: f[0].to_string(),
+                used: parse_u64(f[1]),
+                used_by_snapshots: parse_u64(f[2]),
+                used_by_dataset: parse_u64(f[3]),
+                ..Default::default()
+            },
+        );
+    }
+
+    let mut snapshot_used: BTreeMap<String, u64> = BTreeMap::new();
+    for line in zfs_lines(&[
+        "list",
+        "-H",
+        "-p",
+        "-r",
+        "-t",
+        "snapshot",
+        "-o",
+        "name,used",
+        &opt.pool,
+    ])? {
+        let (name, used) = match line.split_once('\t') {
+            Some((name, used)) => (name, parse_u64(used)),
+            None => continue,
+        };
+        let (dataset, short) = match name.split_once('@') {
+            Some(split) => split,
+            None => continue,
+        };
+        snapshot_used.insert(name.to_string(), used);
+        let ds = match datasets.get_mut(dataset) {
+            Some(ds) => ds,
+            None => continue,
+        };
+        let class = if short.starts_with(Class::Auto.prefix()) {
+            &mut ds.auto
+        } else if short.starts_with(Class::Repl.prefix()) {
+            &mut ds.repl
+        } else {
+            &mut ds.other
+        };
+        class.count += 1;
+        class.used += used;
+    }
+
+    if let Some(keep_hours) = opt.keep_hours {
+        let now = OffsetDateTime::try_now_local().map_err(|_| {
+            error!("Unable to determine time");
+        })?;
+        for snap in cleanup_expired(&opt.pool, keep_hours, false, now)? {
+            if let Some(ds) = datasets.get_mut(snap.dataset_name()) {
+                let reclaimable = ds.reclaimable.get_or_insert_with(Default::default);
+                reclaimable.count += 1;
+                reclaimable.used += snapshot_used.get(snap.name()).copied().unwrap_or(0);
+            }
+        }
+        for ds in datasets.values_mut() {
+            ds.reclaimable.get_or_insert_with(Default::default);
+        }
+    }
+
+    let mut datasets: Vec<_> = datasets.into_values().collect();
+    datasets.sort_by_key(|ds| Reverse(ds.used_by_snapshots));
+    Ok(Usage {
+        pool: opt.pool.clone(),
+        keep_hours: opt.keep_hours,
+        datasets,
+    })
+}
+
+pub(crate) fn do_usage(opt: &UsageOpt) {
+    let usage = match usage(opt) {
+        Ok(u) => u,
+        Err(_) => return,
+    };
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&usage) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise usage -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for ds in usage.datasets.iter() {
+                let reclaimable = ds
+                    .reclaimable
+                    .map(|r| format!("\treclaimable={} ({})", r.used, r.count))
+                    .unwrap_or_default();
+                println!(
+                    "{}\tused={} snapshots={} dataset={}\tauto={} ({}) repl={} ({}) other={} ({}){}",
+                    ds.name,
+                    ds.used,
+                    ds.used_by_snapshots,
+                    ds.used_by_dataset,
+                    ds.auto.used,
+                    ds.auto.count,
+                    ds.repl.used,
+                    ds.repl.count,
+                    ds.other.used,
+                    ds.other.count,
+                    reclaimable
+                );
+            }
+            if let Some(keep_hours) = usage.keep_hours {
+                let total: u64 = usage
+                    .datasets
+                    .iter()
+                    .filter_map(|ds| ds.reclaimable)
+                    .map(|r| r.used)
+                    .sum();
+                println!(
+                    "snapshot_cleanup {} {} would reclaim at least {}",
+                    usage.pool, keep_hours, total
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! `znapper usb-backup` - back a job up to whichever of its rotated removable disks is plugged in.
+//!
+//! The pools of the disks are listed by guid under `[job.<name>.usb]`, so that a disk is only
+//! written to if it is one of them, whatever else is plugged in. usb-backup waits for one to
+//! appear, imports it without mounting anything, replicates the source of the job to its
+//! destinations on it - init_repl for those the disk doesn't hold yet, repl for those it does -
+//! and exports it again, telling the notifications of the job when it is safe to unplug.
+
+use crate::config::{Config, Job};
+use crate::lock::{self, LockOpt};
+use crate::privilege;
+use crate::process::{self, Kind, Timed};
+use crate::sync::repl_opt;
+use crate::{dataset_exists, do_init, do_repl, expand_dest_path, OutputFormat};
+use crate::{email, notify, progress};
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, info};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct UsbBackupOpt {
+    /// The name of a [job.<name>] in znapper.toml, with a [job.<name>.usb]
+    job: String,
+    /// Give up if none of the disks has appeared after this many seconds. By default, wait until
+    /// one does.
+    #[structopt(long = "timeout")]
+    timeout: Option<u64>,
+    /// How often to look for the disks, in seconds.
+    #[structopt(long = "poll", default_value = "10")]
+    poll: u64,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+    /// With -n, print the plan as text (the log, the default) or json.
+    #[structopt(long = "plan-format", requires = "dryrun")]
+    pub plan_format: Option<OutputFormat>,
+    #[structopt(flatten)]
+    pub lock: LockOpt,
+}
+
+/// A pool of one of the disks, by name and guid.
+#[derive(Debug)]
+struct Disk {
+    name: String,
+    guid: String,
+    imported: bool,
+}
+
+fn zpool(args: &[&str]) -> Result<String, String> {
+    debug!("running -> zpool {}", args.join(" "));
+    let output = privilege::escalated("zpool")
+        .args(args)
+        .run_output(Kind::Zfs)
+        .map_err(|e| format!("zpool {} failed -> {:?}", args.join(" "), e))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(format!(
+            "zpool {} failed -> {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// The pools `zpool import` could import, as (name, guid).
+fn parse_importable(listing: &str) -> Vec<(String, String)> {
+    let mut pools = Vec::new();
+    let mut name = None;
+    for line in listing.lines().map(str::trim) {
+        if let Some(pool) = line.strip_prefix("pool:") {
+            name = Some(pool.trim().to_string());
+        } else if let Some(id) = line.strip_prefix("id:") {
+            if let Some(name) = name.take() {
+                pools.push((name, id.trim().to_string()));
+            }
+        }
+    }
+    pools
+}
+
+/// The first of `guids` that is imported, or could be.
+fn find_disk(guids: &[String]) -> Option<Disk> {
+    let imported = zpool(&["list", "-H", "-o", "name,guid"]).unwrap_or_default();
+    let imported = imported.lines().filter_map(|line| line.split_once('\t'));
+    // Nothing to import is an error to zpool.
+    let importable = zpool(&["import"])
+        .map(|l| parse_importable(&l))
+        .unwrap_or_else(|e| {
+            debug!("{}", e);
+            Vec::new()
+        });
+    imported
+        .map(|(name, guid)| (name.to_string(), guid.to_string(), true))
+        .chain(
+            importable
+                .into_iter()
+                .map(|(name, guid)| (name, guid, false)),
+        )
+        .find(|(_, guid, _)| guids.contains(guid))
+        .map(|(name, guid, imported)| Disk {
+            name,
+            guid,
+            imported,
+        })
+}
+
+fn wait_for_disk(opt: &UsbBackupOpt, guids: &[String]) -> Option<Disk> {
+    let started = Instant::now();
+    info!("Waiting for one of the disks of job {}", opt.job);
+    loop {
+        if let Some(disk) = find_disk(guids) {
+            return Some(disk);
+        }
+        if opt
+            .timeout
+            .map(|t| started.elapsed() >= Duration::from_secs(t))
+            .unwrap_or(false)
+        {
+            error!("None of the disks of job {} appeared", opt.job);
+            return None;
+        }
+        thread::sleep(Duration::from_secs(opt.poll.max(1)));
+        if process::cancelled() {
+            return None;
+        }
+    }
+}
+
+fn run_zpool(dry: bool, args: &[&str]) -> Result<(), ()> {
+    if dry {
+        info!("dryrun: zpool {}", args.join(" "));
+        return Ok(());
+    }
+    info!("zpool {}", args.join(" "));
+    zpool(args).map(|_| ()).map_err(|e| error!("{}", e))
+}
+
+/// Replicate `job` to its destinations on the disk, returning the stages it ran.
+fn backup(opt: &UsbBackupOpt, job: &Job, disk: &Disk) -> Vec<(String, bool)> {
+    let mut stages = Vec::new();
+    let dests: Result<Vec<_>, ()> = job
+        .to
+        .iter()
+        .map(|to| expand_dest_path(to, &job.source))
+        .collect();
+    let dests = match dests {
+        Ok(dests) => dests,
+        Err(_) => {
+            stages.push(("destinations".to_string(), false));
+            return stages;
+        }
+    };
+    if let Some(stray) = dests
+        .iter()
+        .find(|to| to.split('/').next() != Some(disk.name.as_str()))
+    {
+        error!(
+            "{} is not on {}, the pool of the disk - the destinations of job {} must be",
+            stray, disk.name, opt.job
+        );
+        stages.push((format!("repl to {}", stray), false));
+        return stages;
+    }
+
+    // Without the pool imported, a dry run can only plan the init of every destination.
+    let (existing, new): (Vec<_>, Vec<_>) = dests
+        .into_iter()
+        .partition(|to| disk.imported && dataset_exists(to));
+    for (dests, init) in [(new, true), (existing, false)] {
+        let (to_pool, to) = match dests.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        let repl = repl_opt(opt.dryrun, opt.plan_format, job, to_pool, to);
+        let (ok, stage) = if init {
+            (do_init(&repl).is_ok(), "init_repl")
+        } else {
+            (do_repl(&repl).is_ok(), "repl")
+        };
+        stages.push((format!("{} to {}", stage, dests.join(", ")), ok));
+    }
+    stages
+}
+
+/// The locks of a usb-backup - the job, and the pool of its source.
+pub(crate) fn locks(opt: &UsbBackupOpt) -> Vec<String> {
+    let mut locks = vec![lock::job(&opt.job)];
+    if let Some(job) = Config::load().ok().and_then(|mut c| c.job.remove(&opt.job)) {
+        locks.push(lock::pool(&job.source));
+    }
+    locks
+}
+
+pub(crate) fn do_usb_backup(opt: &UsbBackupOpt) {
+    debug!("do_usb_backup");
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+    let job = match config.job.get(&opt.job) {
+        Some(job) => job,
+        None => {
+            error!("No job {} in znapper.toml", opt.job);
+            return;
+        }
+    };
+    let guids = match job.usb.as_ref() {
+        Some(usb) if !usb.guids.is_empty() => &usb.guids,
+        _ => {
+            error!("Job {} has no [job.{}.usb] guids", opt.job, opt.job);
+            return;
+        }
+    };
+
+    let disk = match wait_for_disk(opt, guids) {
+        Some(disk) => disk,
+        None => return,
+    };
+    let started = OffsetDateTime::now_utc().timestamp();
+    notify::start(opt.dryrun, &job.notify);
+    info!("Found {} ({})", disk.name, disk.guid);
+
+    let mut stages = Vec::new();
+    let was_imported = disk.imported;
+    let imported = was_imported || run_zpool(opt.dryrun, &["import", "-N", &disk.guid]).is_ok();
+    if !was_imported {
+        stages.push((format!("import {}", disk.name), imported));
+    }
+    if imported {
+        let disk = Disk {
+            imported: was_imported || !opt.dryrun,
+            ..disk
+        };
+        stages.extend(backup(opt, job, &disk));
+        // Exported even if the backup failed, so that the disk can be unplugged either way.
+        let exported = run_zpool(opt.dryrun, &["export", &disk.name]).is_ok();
+        stages.push((format!("export {}", disk.name), exported));
+        if exported && !opt.dryrun {
+            info!("{} is exported - it is safe to unplug", disk.name);
+        }
+    }
+
+    let ok = stages.iter().all(|(_, ok)| *ok);
+    for (stage, ok) in stages.iter() {
+        info!("{}\t{}", if *ok { "ok" } else { "failed" }, stage);
+    }
+    let report = notify::Report {
+        job: opt.job.clone(),
+        action: "usb-backup",
+        result: if ok { "ok" } else { "failed" },
+        bytes: progress::checkpoints()
+            .unwrap_or_default()
+            .iter()
+            .filter(|c| c.started >= started)
+            .map(|c| c.bytes_sent)
+            .sum(),
+        duration_seconds: OffsetDateTime::now_utc().timestamp() - started,
+        stages: stages
+            .iter()
+            .map(|(stage, ok)| notify::Stage {
+                stage: stage.clone(),
+                result: if *ok { "ok" } else { "failed" }.to_string(),
+            })
+            .collect(),
+        errors: email::logged(),
+    };
+    notify::finish(opt.dryrun, &job.notify, &report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn importable_pools_are_found_by_guid() {
+        let listing = "   pool: offsite
+     id: 8312345
+  state: ONLINE
+ action: The pool can be imported using its name or numeric identifier.
+ config:
+
+        offsite     ONLINE
+          sdc       ONLINE
+
+   pool: scratch
+     id: 99
+  state: ONLINE
+";
+        assert_eq!(
+            parse_importable(listing),
+            [
+                ("offsite".to_string(), "8312345".to_string()),
+                ("scratch".to_string(), "99".to_string())
+            ]
+        );
+    }
+}
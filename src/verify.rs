@@ -0,0 +1,442 @@
+//! `znapper verify` - does a replica still match its source? The snapshots of each dataset are
+//! compared by guid, so a snapshot of the same name that was taken again on either side shows up,
+//! and optionally the streams up to the newest snapshots both have are compared by their estimated
+//! size, as a spot check of the data in them. A replica that diverged, or that couldn't be
+//! verified, fails the run. Of a replica here, the immutability window of the newest snapshot of
+//! each dataset both sides have is shown too.
+
+use crate::immutable;
+use crate::privilege;
+use crate::process::{Kind, Timed};
+use crate::ssh::{Ssh, SshOpt};
+use crate::{parse_guids, resolve_remote_ssh, OutputFormat};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::process::{Output, Stdio};
+use structopt::StructOpt;
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct VerifyOpt {
+    /// The dataset that was replicated, with its descendants.
+    #[structopt(long = "from")]
+    from: String,
+    /// The replica. With --remote, defaults to the dataset of the target.
+    #[structopt(long = "to")]
+    to: Option<String>,
+    /// user@host, or the name of a target in targets.toml, that holds the replica. The key must
+    /// allow running commands (not a forced command).
+    #[structopt(long = "remote")]
+    remote: Option<String>,
+    /// Also compare the estimated size of the stream up to each of the newest this many
+    /// snapshots both sides have (from the one before it) of each dataset.
+    #[structopt(long = "spot-check", default_value = "0")]
+    spot_check: usize,
+    /// text or json
+    #[structopt(long = "format", default_value = "text")]
+    format: OutputFormat,
+    #[structopt(flatten)]
+    ssh: SshOpt,
+}
+
+#[derive(Debug, Serialize)]
+struct SpotCheck {
+    snapshot: String,
+    source_size: Option<u64>,
+    destination_size: Option<u64>,
+    matches: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct DatasetReport {
+    source: String,
+    destination: String,
+    /// How many snapshots both sides have.
+    common: usize,
+    /// Snapshots of the source newer than the newest both have - not replicated yet.
+    behind: Vec<String>,
+    /// Snapshots of the same name on both sides that are not the same snapshot.
+    mismatched: Vec<String>,
+    spot_checks: Vec<SpotCheck>,
+    /// When the immutability window of the newest snapshot both have ends, if it is still in
+    /// one - for a replica here, as the windows are those of this host's znapper.toml.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    immutable_until: Option<String>,
+    /// The newest snapshot both have, as it is named on the destination.
+    #[serde(skip)]
+    newest_common: Option<String>,
+    ok: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    from: String,
+    to: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote: Option<String>,
+    datasets: Vec<DatasetReport>,
+    ok: bool,
+}
+
+/// Where the replica is - here, or over ssh.
+enum Side {
+    Local,
+    Remote(Ssh),
+}
+
+impl Side {
+    fn zfs(&self, args: &[&str]) -> Result<Output, ()> {
+        match self {
+            Side::Local => privilege::zfs().args(args).run_output(Kind::Zfs),
+            Side::Remote(ssh) => {
+                let mut remote = vec!["zfs"];
+                remote.extend(args);
+                ssh.command(&remote)
+                    .stdin(Stdio::null())
+                    .run_output(Kind::Ssh)
+            }
+        }
+        .map_err(|e| {
+            error!("zfs {} failed -> {:?}", args.join(" "), e);
+        })
+    }
+
+    /// The snapshots of `dataset` and its descendants as (name, guid), oldest first.
+    fn snapshots(&self, dataset: &str) -> Result<Vec<(String, String)>, ()> {
+        let output = self.zfs(&[
+            "list",
+            "-H",
+            "-p",
+            "-r",
+            "-t",
+            "snapshot",
+            "-o",
+            "name,guid",
+            "-s",
+            "createtxg",
+            dataset,
+        ])?;
+        if !output.status.success() {
+            error!(
+                "snapshot list of {} failed -> {}",
+                dataset,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return Err(());
+        }
+        Ok(parse_guids(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// The estimated size of the raw stream from `from` to `to`, of the same dataset.
+    fn stream_size(&self, from: &str, to: &str) -> Option<u64> {
+        let output = self.zfs(&["send", "-n", "-P", "-w", "-i", from, to]).ok()?;
+        if !output.status.success() {
+            debug!("No estimate for {} to {}", from, to);
+            return None;
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .chain(String::from_utf8_lossy(&output.stderr).lines())
+            .find_map(|line| {
+                line.strip_prefix("size\t")
+                    .and_then(|size| size.trim().parse::<u64>().ok())
+            })
+    }
+}
+
+/// The snapshots of each dataset under `root`, keyed by its path below `root`, as (short name,
+/// guid) oldest first.
+fn by_dataset(root: &str, snaps: Vec<(String, String)>) -> BTreeMap<String, Vec<(String, String)>> {
+    let mut datasets: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    for (name, guid) in snaps {
+        let (dataset, short) = match name.split_once('@') {
+            Some(split) => split,
+            None => continue,
+        };
+        let relative = match dataset.strip_prefix(root) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => continue,
+        };
+        datasets
+            .entry(relative.to_string())
+            .or_default()
+            .push((short.to_string(), guid));
+    }
+    datasets
+}
+
+fn compare(
+    opt: &VerifyOpt,
+    dest: &Side,
+    to: &str,
+    relative: &str,
+    source: &[(String, String)],
+    destination: &[(String, String)],
+) -> DatasetReport {
+    let source_ds = format!("{}{}", opt.from, relative);
+    let dest_ds = format!("{}{}", to, relative);
+
+    let common: Vec<_> = source
+        .iter()
+        .filter(|(_, guid)| destination.iter().any(|(_, g)| g == guid))
+        .collect();
+    let mismatched: Vec<_> = source
+        .iter()
+        .filter(|(short, guid)| destination.iter().any(|(s, g)| s == short && g != guid))
+        .map(|(short, _)| short.clone())
+        .collect();
+    let behind: Vec<_> = match common.last() {
+        Some((_, newest)) => source
+            .iter()
+            .skip_while(|(_, guid)| guid != newest)
+            .skip(1)
+            .map(|(short, _)| short.clone())
+            .collect(),
+        None => source.iter().map(|(short, _)| short.clone()).collect(),
+    };
+
+    // Each of the newest common snapshots, from the one before it that both have too.
+    let mut spot_checks = Vec::new();
+    let pairs: Vec<_> = common.windows(2).collect();
+    for pair in pairs.iter().rev().take(opt.spot_check).rev() {
+        let ((from_short, from_guid), (to_short, to_guid)) = (pair[0], pair[1]);
+        let dest_short = |guid: &str| {
+            destination
+                .iter()
+                .find(|(_, g)| g == guid)
+                .map(|(short, _)| short.clone())
+                .unwrap_or_default()
+        };
+        let source_size = Side::Local.stream_size(
+            &format!("{}@{}", source_ds, from_short),
+            &format!("{}@{}", source_ds, to_short),
+        );
+        let destination_size = dest.stream_size(
+            &format!("{}@{}", dest_ds, dest_short(from_guid)),
+            &format!("{}@{}", dest_ds, dest_short(to_guid)),
+        );
+        spot_checks.push(SpotCheck {
+            snapshot: to_short.clone(),
+            matches: source_size.is_some() && source_size == destination_size,
+            source_size,
+            destination_size,
+        });
+    }
+
+    let newest_common = common.last().and_then(|(_, newest)| {
+        destination
+            .iter()
+            .find(|(_, guid)| guid == newest)
+            .map(|(short, _)| format!("{}@{}", dest_ds, short))
+    });
+    let ok = !common.is_empty() && mismatched.is_empty() && spot_checks.iter().all(|c| c.matches);
+    DatasetReport {
+        source: source_ds,
+        destination: dest_ds,
+        common: common.len(),
+        behind,
+        mismatched,
+        spot_checks,
+        immutable_until: None,
+        newest_common,
+        ok,
+    }
+}
+
+fn verify(opt: &VerifyOpt) -> Result<Report, ()> {
+    let (dest, target_dataset) = match opt.remote.as_deref() {
+        Some(remote) => {
+            let (ssh, dataset) = resolve_remote_ssh(remote, &opt.ssh)?;
+            (Side::Remote(ssh), dataset)
+        }
+        None => (Side::Local, None),
+    };
+    let to = match opt.to.clone().or(target_dataset) {
+        Some(to) => to,
+        None => {
+            error!("--to is needed, unless --remote is a target with a dataset");
+            return Err(());
+        }
+    };
+
+    let source = by_dataset(&opt.from, Side::Local.snapshots(&opt.from)?);
+    let destination = by_dataset(&to, dest.snapshots(&to)?);
+
+    let mut datasets: Vec<_> = source
+        .iter()
+        .map(|(relative, snaps)| {
+            let dest_snaps = destination.get(relative).map(Vec::as_slice).unwrap_or(&[]);
+            compare(opt, &dest, &to, relative, snaps, dest_snaps)
+        })
+        .collect();
+    if let Side::Local = dest {
+        for d in datasets.iter_mut() {
+            d.immutable_until = d
+                .newest_common
+                .as_deref()
+                .and_then(|snap| immutable::locked_until(snap).ok().flatten())
+                .map(immutable::format_ts);
+        }
+    }
+    let ok = datasets.iter().all(|d| d.ok);
+    Ok(Report {
+        from: opt.from.clone(),
+        to,
+        remote: opt.remote.clone(),
+        datasets,
+        ok,
+    })
+}
+
+/// Verify the replica of `opt`, failing if any dataset of it diverged from the source or
+/// couldn't be verified.
+pub(crate) fn do_verify(opt: &VerifyOpt) -> Result<(), ()> {
+    debug!("do_verify");
+    let report = verify(opt)?;
+
+    match opt.format {
+        OutputFormat::Json => match serde_json::to_string_pretty(&report) {
+            Ok(s) => println!("{}", s),
+            Err(e) => error!("failed to serialise verify -> {:?}", e),
+        },
+        OutputFormat::Text => {
+            for d in report.datasets.iter() {
+                println!(
+                    "{}\t{}\t{}\tcommon={} behind={} mismatched={}",
+                    if d.ok { "OK" } else { "DIVERGED" },
+                    d.source,
+                    d.destination,
+                    d.common,
+                    d.behind.len(),
+                    d.mismatched.join(",")
+                );
+                if let Some(until) = d.immutable_until.as_deref() {
+                    println!("  immutable until {}", until);
+                }
+                for c in d.spot_checks.iter() {
+                    let size = |s: Option<u64>| s.map(|s| s.to_string()).unwrap_or("-".into());
+                    println!(
+                        "  {}\t{}\tsource={} destination={}",
+                        match (c.matches, unverified(c)) {
+                            (true, _) => "OK",
+                            (false, true) => "UNVERIFIED",
+                            (false, false) => "DIFFERS",
+                        },
+                        c.snapshot,
+                        size(c.source_size),
+                        size(c.destination_size)
+                    );
+                }
+            }
+        }
+    }
+
+    for d in report.datasets.iter() {
+        if d.common == 0 {
+            error!(
+                "{} has no snapshot in common with {}",
+                d.destination, d.source
+            );
+        }
+        for short in d.mismatched.iter() {
+            error!(
+                "{}@{} is not the same snapshot as {}@{}",
+                d.destination, short, d.source, short
+            );
+        }
+        for c in d.spot_checks.iter().filter(|c| !c.matches) {
+            if unverified(c) {
+                error!(
+                    "The stream up to {}@{} couldn't be estimated on {} or {} - it is unverified",
+                    d.source, c.snapshot, d.source, d.destination
+                );
+            } else {
+                error!(
+                    "The stream up to {}@{} differs between {} and {}",
+                    d.source, c.snapshot, d.source, d.destination
+                );
+            }
+        }
+        if d.common > 0 && !d.behind.is_empty() {
+            warn!(
+                "{} is {} snapshots behind {}",
+                d.destination,
+                d.behind.len(),
+                d.source
+            );
+        }
+    }
+    if report.ok {
+        info!("{} matches {}", report.to, report.from);
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// A spot check that couldn't compare, as one side (or both) couldn't estimate its stream.
+fn unverified(check: &SpotCheck) -> bool {
+    check.source_size.is_none() || check.destination_size.is_none()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn listed(names: &[(&str, &str)]) -> Vec<(String, String)> {
+        names
+            .iter()
+            .map(|(name, guid)| (name.to_string(), guid.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn replicas_are_compared_by_guid() {
+        let opt = VerifyOpt::from_iter(["verify", "--from", "nvme", "--to", "tank/nvme"]);
+        let source = by_dataset(
+            "nvme",
+            listed(&[
+                ("nvme@repl_1", "1"),
+                ("nvme@auto_2", "2"),
+                ("nvme@auto_3", "3"),
+                ("nvme/home@auto_2", "12"),
+            ]),
+        );
+        let destination = by_dataset(
+            "tank/nvme",
+            listed(&[
+                ("tank/nvme@repl_1", "1"),
+                ("tank/nvme@auto_2", "2"),
+                ("tank/nvme/home@auto_2", "99"),
+                ("tank/nvmex@auto_2", "2"),
+            ]),
+        );
+        assert_eq!(destination.len(), 2);
+
+        let root = compare(
+            &opt,
+            &Side::Local,
+            "tank/nvme",
+            "",
+            &source[""],
+            &destination[""],
+        );
+        assert!(root.ok);
+        assert_eq!(root.common, 2);
+        assert_eq!(root.behind, vec!["auto_3"]);
+        assert_eq!(root.newest_common.as_deref(), Some("tank/nvme@auto_2"));
+
+        let home = compare(
+            &opt,
+            &Side::Local,
+            "tank/nvme",
+            "/home",
+            &source["/home"],
+            &destination["/home"],
+        );
+        assert!(!home.ok);
+        assert_eq!(home.destination, "tank/nvme/home");
+        assert_eq!(home.mismatched, vec!["auto_2"]);
+    }
+}
@@ -0,0 +1,401 @@
+//! Reacting to the events of the ZFS Event Daemon.
+//!
+//! `znapper zedlet` handles one event as a zedlet - ZED runs it from `/etc/zfs/zed.d` with the
+//! event in `ZEVENT_*` variables - and `znapper zedlet --follow` follows `zpool events` itself,
+//! for a host without ZED. Either way, on an event that can change the health of a pool, the
+//! health is checked again:
+//!
+//! * a pool that is no longer ONLINE, ie DEGRADED, has replication from it paused - repl,
+//!   remote_repl and sync snapshot it as usual but skip its sends, rather than read every block
+//!   of a pool that is already struggling,
+//! * a pool that is ONLINE again has replication resumed,
+//! * and once a resilver finishes, the sync jobs of the pool run at once, rather than waiting for
+//!   their timers with the pool just repaired.
+//!
+//! Paused pools are kept in `zed.json` in the state directory, and `znapper status` shows the
+//! health of each source and whether its replication is paused.
+
+use crate::anchors::state_dir;
+use crate::check;
+use crate::config::Config;
+use crate::privilege;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use structopt::StructOpt;
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn};
+
+/// The events that can change the health of a pool, by their subclass.
+const HEALTH_EVENTS: &[&str] = &[
+    "statechange",
+    "vdev_remove",
+    "vdev_attach",
+    "vdev_clear",
+    "vdev_online",
+    "resilver_finish",
+    "pool_import",
+];
+
+#[derive(Debug, StructOpt)]
+pub(crate) struct ZedletOpt {
+    /// Follow zpool events rather than handle the one event ZED passes in ZEVENT_*.
+    #[structopt(long = "follow")]
+    follow: bool,
+    #[structopt(short = "n")]
+    pub dryrun: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Event {
+    /// ie sysevent.fs.zfs.resilver_finish
+    class: String,
+    pool: Option<String>,
+    /// When it happened, in seconds since the epoch.
+    time: Option<i64>,
+}
+
+impl Event {
+    /// The event ZED passes a zedlet.
+    fn from_env() -> Option<Self> {
+        let class = std::env::var("ZEVENT_CLASS").ok()?;
+        Some(Event {
+            class,
+            pool: std::env::var("ZEVENT_POOL")
+                .ok()
+                .filter(|pool| !pool.is_empty()),
+            time: None,
+        })
+    }
+
+    /// The last part of the class, ie resilver_finish.
+    fn subclass(&self) -> &str {
+        self.class.rsplit('.').next().unwrap_or(&self.class)
+    }
+}
+
+/// Reads the events of `zpool events -v -H`, each a line with its time and class, followed by
+/// its indented `name = value` pairs.
+#[derive(Debug, Default)]
+struct EventParser {
+    event: Option<Event>,
+}
+
+impl EventParser {
+    /// Read `line`, giving the event it ends, if any.
+    fn feed(&mut self, line: &str) -> Option<Event> {
+        if line.trim().is_empty() {
+            return self.event.take();
+        }
+        if !line.starts_with(char::is_whitespace) {
+            let ended = self.event.take();
+            self.event = Some(Event {
+                class: line.split_whitespace().last().unwrap_or("").to_string(),
+                ..Event::default()
+            });
+            return ended;
+        }
+        let event = self.event.as_mut()?;
+        let (name, value) = line.trim().split_once(" = ")?;
+        match name {
+            "pool" => event.pool = Some(value.trim_matches('"').to_string()),
+            "time" => {
+                event.time = value
+                    .split_whitespace()
+                    .next()
+                    .and_then(|secs| i64::from_str_radix(secs.trim_start_matches("0x"), 16).ok())
+            }
+            _ => (),
+        }
+        None
+    }
+}
+
+/// Replication from a pool paused until it is healthy again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Pause {
+    /// The health of the pool when it was paused, ie DEGRADED.
+    pub health: String,
+    pub since: i64,
+    /// The event it was paused on.
+    pub event: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ZedStore {
+    #[serde(default)]
+    paused: BTreeMap<String, Pause>,
+}
+
+impl ZedStore {
+    fn path() -> PathBuf {
+        state_dir().join("zed.json")
+    }
+
+    fn load() -> Result<Self, ()> {
+        let path = Self::path();
+        match File::open(&path) {
+            Ok(f) => serde_json::from_reader(f).map_err(|e| {
+                error!("Failed to parse {:?} -> {:?}", path, e);
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ZedStore::default()),
+            Err(e) => {
+                error!("Failed to open {:?} -> {:?}", path, e);
+                Err(())
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), ()> {
+        let path = Self::path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                error!("Failed to create state dir {:?} -> {:?}", parent, e);
+            })?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        let f = File::create(&tmp).map_err(|e| {
+            error!("Failed to create {:?} -> {:?}", tmp, e);
+        })?;
+        serde_json::to_writer_pretty(&f, self).map_err(|e| {
+            error!("Failed to write {:?} -> {:?}", tmp, e);
+        })?;
+        fs::rename(&tmp, &path).map_err(|e| {
+            error!("Failed to replace {:?} -> {:?}", path, e);
+        })
+    }
+}
+
+fn pool_of(dataset: &str) -> &str {
+    dataset.split(['/', '@', '#']).next().unwrap_or(dataset)
+}
+
+/// Why replication from the pool of `dataset` is paused, if it is.
+pub(crate) fn paused(dataset: &str) -> Option<Pause> {
+    ZedStore::load()
+        .ok()
+        .and_then(|mut store| store.paused.remove(pool_of(dataset)))
+}
+
+/// Refuse to replicate from the pool of `dataset` while it is paused.
+pub(crate) fn check(dataset: &str) -> Result<(), ()> {
+    match paused(dataset) {
+        Some(pause) => {
+            warn!(
+                "Replication from {} is paused, as the pool was {} at {} - it resumes once the \
+                 pool is ONLINE",
+                pool_of(dataset),
+                pause.health,
+                format_ts(pause.since)
+            );
+            Err(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// The health of every imported pool, ie ONLINE or DEGRADED.
+pub(crate) fn pool_health() -> BTreeMap<String, String> {
+    match check::run("zpool", &["list", "-H", "-o", "name,health"]) {
+        Ok(stdout) => stdout
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(pool, health)| (pool.to_string(), health.trim().to_string()))
+            .collect(),
+        Err(e) => {
+            debug!("zpool list failed -> {}", e);
+            BTreeMap::new()
+        }
+    }
+}
+
+fn format_ts(ts: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(ts).format("%Y-%m-%dT%H:%M:%SZ")
+}
+
+/// Start `znapper sync` of each job whose source is on `pool`, without waiting for them.
+fn sync_jobs(dry: bool, pool: &str) {
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+    for name in config
+        .job
+        .iter()
+        .filter(|(_, job)| pool_of(&job.source) == pool)
+        .map(|(name, _)| name)
+    {
+        if dry {
+            info!("dryrun: znapper sync {}", name);
+            continue;
+        }
+        let spawned = std::env::current_exe().and_then(|exe| {
+            Command::new(exe)
+                .args(["sync", name])
+                .stdin(Stdio::null())
+                .spawn()
+        });
+        match spawned {
+            Ok(mut child) => {
+                info!("Started znapper sync {} (pid {})", name, child.id());
+                // Reaped in the background, as a follower runs for good.
+                thread::spawn(move || child.wait());
+            }
+            Err(e) => error!("Unable to start znapper sync {} -> {:?}", name, e),
+        }
+    }
+}
+
+/// Pause or resume replication from the pool of `event` as its health now is, and run its jobs
+/// once it has resilvered.
+fn handle(dry: bool, event: &Event) -> Result<(), ()> {
+    let pool = match event.pool.as_deref() {
+        Some(pool) if HEALTH_EVENTS.contains(&event.subclass()) => pool,
+        _ => {
+            debug!("Ignoring zfs event {}", event.class);
+            return Ok(());
+        }
+    };
+    let health = match pool_health().remove(pool) {
+        Some(h) => h,
+        None => {
+            warn!("{} on {}, which is not imported", event.subclass(), pool);
+            return Ok(());
+        }
+    };
+    debug!("{} on {} -> {}", event.subclass(), pool, health);
+
+    let mut store = ZedStore::load()?;
+    let healthy = health == "ONLINE";
+    let changed = match (healthy, store.paused.contains_key(pool)) {
+        (false, false) => {
+            warn!(
+                "{} is {} after {} - pausing replication from it",
+                pool,
+                health,
+                event.subclass()
+            );
+            store.paused.insert(
+                pool.to_string(),
+                Pause {
+                    health: health.clone(),
+                    since: event
+                        .time
+                        .unwrap_or_else(|| OffsetDateTime::now_utc().timestamp()),
+                    event: event.class.clone(),
+                },
+            );
+            true
+        }
+        (true, true) => {
+            info!("{} is ONLINE again - resuming replication from it", pool);
+            store.paused.remove(pool);
+            true
+        }
+        _ => false,
+    };
+    let res = if !changed {
+        Ok(())
+    } else if dry {
+        info!("dryrun: not saving {:?}", ZedStore::path());
+        Ok(())
+    } else {
+        store.save()
+    };
+
+    if healthy && event.subclass() == "resilver_finish" {
+        info!("{} has resilvered - running its sync jobs", pool);
+        sync_jobs(dry, pool);
+    }
+    res
+}
+
+/// Handle each event of `zpool events -f` from when the follower started, until it ends.
+fn follow(dry: bool) {
+    let started = OffsetDateTime::now_utc().timestamp();
+    let mut child = match privilege::command("zpool")
+        .args(["events", "-f", "-v", "-H"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Unable to follow zpool events -> {:?}", e);
+            return;
+        }
+    };
+    let stdout = match child.stdout.take() {
+        Some(s) => s,
+        None => {
+            error!("Unable to read zpool events");
+            return;
+        }
+    };
+    info!("Following zpool events");
+
+    let mut parser = EventParser::default();
+    for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+        if let Some(event) = parser.feed(&line) {
+            // zpool events first lists the events it already holds, which are not news.
+            if event.time.map(|t| t >= started).unwrap_or(true) {
+                let _ = handle(dry, &event);
+            }
+        }
+    }
+    match child.wait() {
+        Ok(status) if status.success() => (),
+        Ok(status) => error!("zpool events exited -> {}", status),
+        Err(e) => error!("zpool events failed -> {:?}", e),
+    }
+}
+
+pub(crate) fn do_zedlet(opt: &ZedletOpt) {
+    debug!("do_zedlet");
+
+    if opt.follow {
+        return follow(opt.dryrun);
+    }
+    match Event::from_env() {
+        Some(event) => {
+            let _ = handle(opt.dryrun, &event);
+        }
+        None => error!("No ZEVENT_CLASS - run this from ZED, or with --follow"),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zpool_events_are_parsed() {
+        let out = "Oct 14 2026 10:00:00.123456789 sysevent.fs.zfs.resilver_finish\n\
+                   \tversion = 0x0\n\
+                   \tclass = \"sysevent.fs.zfs.resilver_finish\"\n\
+                   \tpool = \"tank\"\n\
+                   \ttime = 0x6523abcd 0x75bcd15\n\
+                   \n\
+                   Oct 14 2026 10:00:01.000000000 ereport.fs.zfs.checksum\n\
+                   \tpool = \"nvme\"\n";
+        let mut parser = EventParser::default();
+        let events: Vec<_> = out.lines().filter_map(|l| parser.feed(l)).collect();
+        assert_eq!(
+            events,
+            vec![Event {
+                class: "sysevent.fs.zfs.resilver_finish".to_string(),
+                pool: Some("tank".to_string()),
+                time: Some(0x6523abcd),
+            }]
+        );
+        assert_eq!(events[0].subclass(), "resilver_finish");
+        let last = parser.feed("").unwrap();
+        assert_eq!(last.pool.as_deref(), Some("nvme"));
+        assert_eq!(pool_of("tank/data@auto_1"), "tank");
+    }
+}
@@ -0,0 +1,127 @@
+//! The harness of the integration tests - a mock runner in place of zfs and ssh, and a state
+//! and config directory of each test's own.
+
+// Each test uses only some of the harness.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use znapper::runner::{set_runner, MockRunner};
+
+// The runner and the directories are process wide, so one test at a time.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+pub struct Harness {
+    pub zfs: Arc<MockRunner>,
+    pub state: PathBuf,
+    /// What `zfs list -t all -r <dataset>` lists - its datasets, snapshots and bookmarks.
+    listed: Mutex<BTreeMap<String, [Vec<String>; 3]>>,
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl Harness {
+    fn list(&self, dataset: &str, kind: usize, names: &[&str]) {
+        let mut listed = self.listed.lock().unwrap();
+        let entry = listed
+            .entry(dataset.to_string())
+            .or_insert_with(|| [vec![dataset.to_string()], Vec::new(), Vec::new()]);
+        entry[kind] = names.iter().map(|name| name.to_string()).collect();
+        let all: Vec<_> = entry.iter().flatten().map(String::as_str).collect();
+        self.zfs
+            .reply(&format!("zfs list -t all -r {}", dataset), &lines(&all));
+    }
+
+    /// List the datasets under (and including) `dataset`, one per line.
+    pub fn datasets(&self, dataset: &str, datasets: &[&str]) {
+        self.list(dataset, 0, datasets);
+    }
+
+    /// List `snapshots` (one per line, with their guid and createtxg if given) under `dataset`.
+    pub fn snapshots(&self, dataset: &str, snapshots: &[&str]) {
+        self.list(dataset, 1, snapshots);
+    }
+
+    /// List `bookmarks` (one per line) under `dataset`.
+    pub fn bookmarks(&self, dataset: &str, bookmarks: &[&str]) {
+        self.list(dataset, 2, bookmarks);
+    }
+
+    /// Record `anchor` as the anchor of another flow.
+    pub fn anchor(&self, flow: &str, destination: &str, anchor: &str) {
+        let anchors = format!(
+            r#"{{"anchors":[{{"flow":"{}","destination":"{}","anchor":"{}"}}]}}"#,
+            flow, destination, anchor
+        );
+        fs::write(self.state.join("anchors.json"), anchors).unwrap();
+    }
+
+    /// The snapshots taken so far.
+    pub fn created(&self) -> Vec<String> {
+        self.zfs
+            .calls()
+            .iter()
+            .filter_map(|call| call.strip_prefix("zfs snapshot "))
+            .filter_map(|args| args.rsplit(' ').next().map(str::to_string))
+            .collect()
+    }
+
+    /// The snapshots destroyed so far - with `-r`, those listed of the same name of the
+    /// descendants too, as zfs would.
+    pub fn destroyed(&self) -> Vec<String> {
+        let listed = self.listed.lock().unwrap();
+        let snapshots: Vec<_> = listed
+            .values()
+            .flat_map(|entry| entry[1].iter())
+            .filter_map(|line| line.split('\t').next())
+            .collect();
+        self.zfs
+            .ran("zfs destroy")
+            .iter()
+            .flat_map(|call| {
+                let name = call.rsplit(' ').next().unwrap_or_default();
+                let mut names = vec![name.to_string()];
+                if let Some((dataset, short)) = name.split_once('@') {
+                    if call.split(' ').any(|arg| arg == "-r") {
+                        let under = format!("{}/", dataset);
+                        names.extend(
+                            snapshots
+                                .iter()
+                                .filter(|snap| {
+                                    snap.split_once('@')
+                                        .is_some_and(|(d, s)| s == short && d.starts_with(&under))
+                                })
+                                .map(|snap| snap.to_string()),
+                        );
+                    }
+                }
+                names
+            })
+            .collect()
+    }
+}
+
+fn lines(names: &[&str]) -> String {
+    names.iter().map(|name| format!("{}\n", name)).collect()
+}
+
+pub fn harness(name: &str) -> Harness {
+    let serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let state = std::env::temp_dir().join(format!("znapper-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&state);
+    fs::create_dir_all(&state).unwrap();
+    std::env::set_var("ZNAPPER_STATE_DIR", &state);
+    std::env::set_var("ZNAPPER_CONFIG_DIR", &state);
+
+    let zfs = Arc::new(MockRunner::new());
+    // Every snapshot has the same guid, so what repl sends is confirmed on the destination.
+    zfs.reply("zfs get guid", "8312345\n");
+    set_runner(zfs.clone());
+    Harness {
+        zfs,
+        state,
+        listed: Mutex::new(BTreeMap::new()),
+        _serial: serial,
+    }
+}
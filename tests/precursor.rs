@@ -0,0 +1,161 @@
+mod common;
+
+use common::harness;
+use znapper::ReplicationJob;
+
+fn precursor() -> Option<String> {
+    ReplicationJob::new("nvme", "tank/nvme")
+        .precursor()
+        .unwrap()
+        .map(|snap| snap.name().to_string())
+}
+
+#[test]
+fn precursor_is_the_newest_common_snapshot() {
+    let h = harness("precursor_newest");
+    h.snapshots(
+        "nvme",
+        &[
+            "nvme@repl_2024_01_01_00_00_00",
+            "nvme@repl_2024_01_02_00_00_00",
+            "nvme@repl_2024_01_03_00_00_00",
+        ],
+    );
+    h.snapshots(
+        "tank/nvme",
+        &[
+            "tank/nvme@repl_2024_01_01_00_00_00",
+            "tank/nvme@repl_2024_01_02_00_00_00",
+        ],
+    );
+
+    assert_eq!(
+        precursor().as_deref(),
+        Some("nvme@repl_2024_01_02_00_00_00")
+    );
+}
+
+#[test]
+fn precursor_ignores_auto_snapshots() {
+    let h = harness("precursor_auto");
+    h.snapshots(
+        "nvme",
+        &[
+            "nvme@repl_2024_01_01_00_00_00",
+            "nvme@auto_2024_01_02_00_00_00",
+        ],
+    );
+    h.snapshots(
+        "tank/nvme",
+        &[
+            "tank/nvme@repl_2024_01_01_00_00_00",
+            "tank/nvme@auto_2024_01_02_00_00_00",
+        ],
+    );
+
+    assert_eq!(
+        precursor().as_deref(),
+        Some("nvme@repl_2024_01_01_00_00_00")
+    );
+}
+
+#[test]
+fn precursor_prefers_a_newer_bookmark() {
+    let h = harness("precursor_bookmark");
+    h.snapshots("nvme", &["nvme@repl_2024_01_01_00_00_00"]);
+    h.bookmarks("nvme", &["nvme#repl_2024_01_02_00_00_00"]);
+    h.snapshots(
+        "tank/nvme",
+        &[
+            "tank/nvme@repl_2024_01_01_00_00_00",
+            "tank/nvme@repl_2024_01_02_00_00_00",
+        ],
+    );
+
+    assert_eq!(
+        precursor().as_deref(),
+        Some("nvme#repl_2024_01_02_00_00_00")
+    );
+}
+
+#[test]
+fn precursor_of_a_new_destination_is_none() {
+    let h = harness("precursor_none");
+    h.snapshots("nvme", &["nvme@repl_2024_01_01_00_00_00"]);
+    h.snapshots("tank/nvme", &[]);
+
+    assert_eq!(precursor(), None);
+}
+
+#[test]
+fn precursor_of_a_destination_template() {
+    let h = harness("precursor_template");
+    h.snapshots("nvme", &["nvme@repl_2024_01_01_00_00_00"]);
+    h.snapshots("backup/nvme", &["backup/nvme@repl_2024_01_01_00_00_00"]);
+
+    let precursor = ReplicationJob::new("nvme", "backup/%dataset%")
+        .precursor()
+        .unwrap();
+    assert_eq!(
+        precursor.map(|snap| snap.name().to_string()).as_deref(),
+        Some("nvme@repl_2024_01_01_00_00_00")
+    );
+}
+
+#[test]
+fn precursor_is_an_anchor_of_the_root() {
+    let h = harness("precursor_root");
+    h.snapshots(
+        "nvme",
+        &[
+            "nvme@repl_2024_01_01_00_00_00",
+            "nvme/home@repl_2024_01_02_00_00_00",
+        ],
+    );
+    h.snapshots(
+        "tank/nvme",
+        &[
+            "tank/nvme@repl_2024_01_01_00_00_00",
+            "tank/nvme/home@repl_2024_01_02_00_00_00",
+        ],
+    );
+
+    assert_eq!(
+        precursor().as_deref(),
+        Some("nvme@repl_2024_01_01_00_00_00")
+    );
+}
+
+#[test]
+fn precursor_needs_the_same_guid() {
+    let h = harness("precursor_guid");
+    h.snapshots("nvme", &["nvme@repl_2024_01_01_00_00_00\t111\t10"]);
+    h.snapshots(
+        "tank/nvme",
+        &["tank/nvme@repl_2024_01_01_00_00_00\t222\t20"],
+    );
+
+    assert_eq!(precursor(), None);
+}
+
+#[test]
+fn precursor_is_the_newest_by_time_not_name() {
+    let h = harness("precursor_naming");
+    // The UTC name sorts before the local one, but was taken after it.
+    h.snapshots(
+        "nvme",
+        &[
+            "nvme@repl_2024-01-03T000000Z",
+            "nvme@repl_2024_01_02_00_00_00",
+        ],
+    );
+    h.snapshots(
+        "tank/nvme",
+        &[
+            "tank/nvme@repl_2024-01-03T000000Z",
+            "tank/nvme@repl_2024_01_02_00_00_00",
+        ],
+    );
+
+    assert_eq!(precursor().as_deref(), Some("nvme@repl_2024-01-03T000000Z"));
+}
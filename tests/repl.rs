@@ -0,0 +1,196 @@
+mod common;
+
+use common::harness;
+use znapper::ReplicationJob;
+
+const ANCHOR: &str = "nvme@repl_2024_01_01_00_00_00";
+const DEST_ANCHOR: &str = "tank/nvme@repl_2024_01_01_00_00_00";
+
+fn job() -> ReplicationJob {
+    ReplicationJob::new("nvme", "tank/nvme").skip_preflight(true)
+}
+
+#[test]
+fn repl_sends_incrementally_from_the_common_anchor() {
+    let h = harness("repl_incremental");
+    h.snapshots("nvme", &[ANCHOR]);
+    h.snapshots("tank/nvme", &[DEST_ANCHOR]);
+
+    job().run().unwrap();
+
+    let created = h.created();
+    assert_eq!(created.len(), 1);
+    let basesnap = &created[0];
+    assert!(basesnap.starts_with("nvme@repl_"));
+
+    let send = h.zfs.ran("zfs send -I");
+    assert_eq!(send.len(), 1);
+    assert!(send[0].ends_with(&format!("-I {} {}", ANCHOR, basesnap)));
+    assert_eq!(h.zfs.ran("zfs recv tank/nvme").len(), 1);
+
+    // The old anchor goes from both sides, and the new one is recorded.
+    assert_eq!(h.destroyed(), vec![ANCHOR, DEST_ANCHOR]);
+    let anchors = std::fs::read_to_string(h.state.join("anchors.json")).unwrap();
+    assert!(anchors.contains(basesnap.as_str()));
+}
+
+#[test]
+fn repl_without_a_common_anchor_sends_nothing() {
+    let h = harness("repl_no_anchor");
+    h.snapshots("nvme", &[ANCHOR]);
+    h.snapshots("tank/nvme", &["tank/nvme@repl_2023_01_01_00_00_00"]);
+
+    assert!(job().run().is_err());
+    assert!(h.created().is_empty());
+    assert!(h.zfs.ran("zfs send").is_empty());
+    assert!(h.destroyed().is_empty());
+}
+
+#[test]
+fn repl_failed_recv_removes_the_new_snapshot() {
+    let h = harness("repl_failed_recv");
+    h.snapshots("nvme", &[ANCHOR]);
+    h.snapshots("tank/nvme", &[DEST_ANCHOR]);
+    h.zfs
+        .fail("zfs recv", 1, "cannot receive: connection reset");
+
+    assert!(job().run().is_err());
+    // The anchors the next run needs are kept.
+    assert_eq!(h.destroyed(), h.created());
+}
+
+#[test]
+fn repl_to_several_destinations_keeps_going_past_a_failure() {
+    let h = harness("repl_several");
+    h.snapshots("nvme", &[ANCHOR]);
+    h.snapshots("tank/nvme", &[DEST_ANCHOR]);
+    h.snapshots("backup/nvme", &["backup/nvme@repl_2024_01_01_00_00_00"]);
+    h.zfs
+        .fail("zfs recv tank/nvme", 1, "cannot receive: out of space");
+
+    assert!(job().also_to("backup/nvme").run().is_err());
+    assert_eq!(h.zfs.ran("zfs recv backup/nvme").len(), 1);
+    // tank/nvme still needs the old anchor, so only backup/nvme's copy goes.
+    let anchors = std::fs::read_to_string(h.state.join("anchors.json")).unwrap();
+    assert!(anchors.contains("backup/nvme"));
+    assert!(!anchors.contains("\"tank/nvme\""));
+    assert!(h
+        .destroyed()
+        .contains(&"backup/nvme@repl_2024_01_01_00_00_00".to_string()));
+    assert!(!h.destroyed().contains(&DEST_ANCHOR.to_string()));
+}
+
+#[test]
+fn repl_dry_run_changes_nothing() {
+    let h = harness("repl_dry_run");
+    h.snapshots("nvme", &[ANCHOR]);
+    h.snapshots("tank/nvme", &[DEST_ANCHOR]);
+
+    job().dry_run(true).run().unwrap();
+    assert!(h.created().is_empty());
+    assert!(h.zfs.ran("zfs send -I").is_empty());
+    assert!(h.zfs.ran("zfs recv").is_empty());
+    assert!(h.destroyed().is_empty());
+}
+
+#[test]
+fn repl_keeps_the_anchors_until_the_destination_confirms() {
+    let h = harness("repl_unconfirmed");
+    h.snapshots("nvme", &[ANCHOR]);
+    h.snapshots("tank/nvme", &[DEST_ANCHOR]);
+    // The recv exited cleanly, but the new snapshot isn't there to be found.
+    h.zfs.fail("zfs get guid", 1, "dataset does not exist");
+
+    assert!(job().run().is_err());
+    assert_eq!(h.zfs.ran("zfs recv tank/nvme").len(), 1);
+    assert!(h.destroyed().is_empty());
+    assert!(!h.state.join("anchors.json").exists());
+}
+
+#[test]
+fn repl_keeps_the_newest_previous_anchors() {
+    let h = harness("repl_keep_anchors");
+    let older = "nvme@repl_2023_12_01_00_00_00";
+    h.snapshots("nvme", &[older, ANCHOR]);
+    h.snapshots(
+        "tank/nvme",
+        &["tank/nvme@repl_2023_12_01_00_00_00", DEST_ANCHOR],
+    );
+
+    job().keep_anchors(1).run().unwrap();
+    assert_eq!(
+        h.destroyed(),
+        vec![older, "tank/nvme@repl_2023_12_01_00_00_00"]
+    );
+}
+
+#[test]
+fn repl_partially_received_is_not_a_success() {
+    let h = harness("repl_partial");
+    h.snapshots("nvme", &[ANCHOR]);
+    h.snapshots("tank/nvme", &[DEST_ANCHOR]);
+    h.datasets("nvme", &["nvme", "nvme/home", "nvme/vm"]);
+    // nvme/vm arrived with another guid, and nvme/home not at all.
+    h.zfs.reply("zfs get guid tank/nvme/vm@repl_*", "4242\n");
+    h.zfs.fail(
+        "zfs get guid tank/nvme/home@repl_*",
+        1,
+        "dataset does not exist",
+    );
+
+    assert!(job().run().is_err());
+    assert!(h.destroyed().is_empty());
+    assert!(!h.state.join("anchors.json").exists());
+
+    // Once they match, it is.
+    h.zfs.reply("zfs get guid tank/nvme/*", "8312345\n");
+    job().run().unwrap();
+    assert!(h.destroyed().contains(&ANCHOR.to_string()));
+}
+
+#[test]
+fn repl_leaves_out_what_is_excluded() {
+    let h = harness("repl_excluded");
+    h.snapshots("nvme", &[ANCHOR, "nvme/home@repl_2024_01_01_00_00_00"]);
+    h.snapshots("tank/nvme", &[DEST_ANCHOR]);
+    h.datasets("nvme", &["nvme", "nvme/home", "nvme/scratch"]);
+    h.zfs.reply(
+        "zfs list name,org.znapper:replicate nvme",
+        "nvme\t-\nnvme/home\t-\nnvme/scratch\tfalse\n",
+    );
+
+    job().run().unwrap();
+    assert_eq!(h.zfs.ran("zfs send -R -I nvme/home@*").len(), 1);
+    assert_eq!(h.zfs.ran("zfs send -p -I nvme@*").len(), 1);
+    assert!(h.zfs.ran("zfs send -v nvme/scratch@*").is_empty());
+    // It was sent before it was left out, so the copy is pruned.
+    assert!(h.destroyed().contains(&"tank/nvme/scratch".to_string()));
+}
+
+#[test]
+fn repl_single_dataset_leaves_out_its_descendants() {
+    let h = harness("repl_single_dataset");
+    let anchor = "nvme/vm@repl_2024_01_01_00_00_00";
+    // The anchor of another flow, replicating nvme/vm/disk on its own.
+    let child = "nvme/vm/disk@repl_2023_06_01_00_00_00";
+    h.snapshots("nvme/vm", &[anchor, child]);
+    h.snapshots("tank/vm", &["tank/vm@repl_2024_01_01_00_00_00"]);
+    h.datasets("nvme/vm", &["nvme/vm", "nvme/vm/disk"]);
+
+    ReplicationJob::new("nvme/vm", "tank/vm")
+        .skip_preflight(true)
+        .single_dataset(true)
+        .run()
+        .unwrap();
+
+    let created = h.created();
+    assert_eq!(created.len(), 1);
+    assert!(h.zfs.ran("zfs snapshot -r").is_empty());
+    assert_eq!(h.zfs.ran("zfs send -p -I").len(), 1);
+    assert!(h.zfs.ran("zfs send -R").is_empty());
+    assert!(h.zfs.ran("zfs send -v nvme/vm/disk@*").is_empty());
+    assert_eq!(
+        h.destroyed(),
+        vec![anchor, "tank/vm@repl_2024_01_01_00_00_00"]
+    );
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use common::harness;
+use time::{Duration, OffsetDateTime};
+use znapper::Zfs;
+
+#[test]
+fn snapshot_counts_up_past_a_name_already_taken() {
+    let h = harness("snapshot_counter");
+    h.zfs
+        .reply("zfs list -r -t filesystem nvme", "nvme\t/nvme\n");
+    // Another run took the plain name of whichever second this one lands in.
+    let now = OffsetDateTime::now_utc();
+    let taken: String = (-1..=2)
+        .map(|s| {
+            format!(
+                "nvme@auto_{}\n",
+                (now + Duration::seconds(s)).format("%Y-%m-%dT%H%M%SZ")
+            )
+        })
+        .collect();
+    h.zfs.reply("zfs list -o name -t snapshot", &taken);
+
+    Zfs::new().snapshot(&["nvme"]).unwrap();
+
+    let created = h.created();
+    assert_eq!(created.len(), 1);
+    assert!(created[0].ends_with("Z_1"), "{:?}", created);
+    assert!(taken
+        .lines()
+        .all(|name| !created.contains(&name.to_string())));
+}
+
+#[test]
+fn snapshot_that_already_exists_is_not_a_failure() {
+    let h = harness("snapshot_exists");
+    h.zfs
+        .reply("zfs list -r -t filesystem nvme", "nvme\t/nvme\n");
+    h.zfs.fail(
+        "zfs snapshot -o",
+        1,
+        "cannot create snapshot 'nvme@auto_x': dataset already exists",
+    );
+    assert!(Zfs::new().snapshot(&["nvme"]).is_ok());
+
+    h.zfs.fail(
+        "zfs snapshot -o",
+        1,
+        "cannot create snapshot 'nvme@auto_x': out of space",
+    );
+    assert!(Zfs::new().snapshot(&["nvme"]).is_err());
+}
+
+#[test]
+fn snapshot_skips_datasets_opted_out_for_zfs_auto_snapshot() {
+    let h = harness("snapshot_opt_out");
+    h.zfs.reply(
+        "zfs list -r -t filesystem nvme",
+        "nvme\t/nvme\t-\nnvme/scratch\t/nvme/scratch\tfalse\nnvme/scratch/keep\t/nvme/scratch/keep\ttrue\n",
+    );
+
+    Zfs::new().snapshot(&["nvme"]).unwrap();
+
+    let datasets: Vec<_> = h
+        .created()
+        .iter()
+        .filter_map(|snap| snap.split_once('@').map(|(fs, _)| fs.to_string()))
+        .collect();
+    assert_eq!(datasets, vec!["nvme", "nvme/scratch/keep"]);
+}
+
+#[test]
+fn snapshot_jobs_create_every_snapshot_and_fail_together() {
+    let h = harness("snapshot_jobs");
+    h.zfs.reply(
+        "zfs list -r -t filesystem nvme",
+        "nvme\t/nvme\nnvme/a\t/nvme/a\nnvme/b\t/nvme/b\nnvme/c\t/nvme/c\n",
+    );
+    h.zfs.fail(
+        "zfs snapshot nvme/b@auto_*",
+        1,
+        "cannot create snapshot 'nvme/b@auto_x': out of space",
+    );
+
+    assert!(Zfs::new().snapshot_jobs(3).snapshot(&["nvme"]).is_err());
+
+    // One failure doesn't stop the others.
+    let mut datasets: Vec<_> = h
+        .created()
+        .iter()
+        .filter_map(|snap| snap.split_once('@').map(|(fs, _)| fs.to_string()))
+        .collect();
+    datasets.sort();
+    assert_eq!(datasets, vec!["nvme", "nvme/a", "nvme/b", "nvme/c"]);
+}
+
+#[test]
+fn snapshot_of_a_group_takes_its_datasets_together() {
+    let h = harness("snapshot_group");
+    std::fs::write(
+        h.state.join("znapper.toml"),
+        "[group.db]\ndatasets = [\"tank/db\", \"nvme/wal\"]\n",
+    )
+    .unwrap();
+    h.zfs.reply(
+        "zfs list -r -t filesystem tank/db nvme/wal",
+        "tank/db\t/srv/db\nnvme/wal\t/srv/db/wal\n",
+    );
+
+    Zfs::new().snapshot_groups(&["db"]).unwrap();
+
+    let created = h.created();
+    assert_eq!(created.len(), 2);
+    let names: Vec<_> = created
+        .iter()
+        .filter_map(|snap| snap.split_once('@').map(|(_, name)| name))
+        .collect();
+    assert_eq!(names[0], names[1]);
+    assert_eq!(h.zfs.ran("zfs snapshot org.znapper:group=db").len(), 2);
+}
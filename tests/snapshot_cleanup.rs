@@ -0,0 +1,297 @@
+mod common;
+
+use common::harness;
+use time::{Duration, OffsetDateTime};
+use znapper::{Snapshot, Zfs};
+
+const OLD: &str = "nvme@auto_2000_01_01_00_00_00";
+const OLD_HOME: &str = "nvme/home@auto_2000_01_01_00_00_00";
+const NEW: &str = "nvme@auto_2999_01_01_00_00_00";
+const REPL: &str = "nvme@repl_2000_01_01_00_00_00";
+
+#[test]
+fn cleanup_destroys_old_auto_snapshots() {
+    let h = harness("cleanup_old");
+    h.snapshots("nvme", &[OLD, OLD_HOME, NEW, REPL]);
+
+    Zfs::new().cleanup("nvme", 24).unwrap();
+    assert_eq!(h.destroyed(), vec![OLD_HOME, OLD]);
+    // The pool is listed once, for the snapshots and the anchors alike.
+    assert_eq!(h.zfs.ran("zfs list -t all").len(), 1);
+
+    // Each run lists it afresh.
+    Zfs::new().cleanup("nvme", 24).unwrap();
+    assert_eq!(h.zfs.ran("zfs list -t all").len(), 2);
+}
+
+#[test]
+fn cleanup_keeps_the_anchors_of_other_flows() {
+    let h = harness("cleanup_anchor");
+    h.snapshots("nvme", &[OLD, NEW]);
+    h.anchor("remote_repl", "/var/lib/znapper/remote.json", OLD);
+
+    Zfs::new().cleanup("nvme", 24).unwrap();
+    assert!(h.destroyed().is_empty());
+}
+
+#[test]
+fn cleanup_of_an_empty_pool_does_nothing() {
+    let h = harness("cleanup_empty");
+    h.snapshots("nvme", &[]);
+
+    Zfs::new().cleanup("nvme", 24).unwrap();
+    assert!(h.destroyed().is_empty());
+}
+
+#[test]
+fn cleanup_dry_run_destroys_nothing() {
+    let h = harness("cleanup_dry_run");
+    h.snapshots("nvme", &[OLD, NEW]);
+
+    Zfs::new().dry_run(true).cleanup("nvme", 24).unwrap();
+    assert!(h.destroyed().is_empty());
+}
+
+#[test]
+fn cleanup_keeps_the_last_keep_hours() {
+    let h = harness("cleanup_keep_hours");
+    let hour_ago = OffsetDateTime::try_now_local().unwrap() - Duration::hours(1);
+    let snap = format!("nvme@auto_{}", hour_ago.format("%Y_%m_%d_%H_%M_%S"));
+    h.snapshots("nvme", &[snap.as_str()]);
+
+    Zfs::new().cleanup("nvme", 2).unwrap();
+    assert!(h.destroyed().is_empty());
+    Zfs::new().cleanup("nvme", 0).unwrap();
+    assert_eq!(h.destroyed(), vec![snap]);
+}
+
+#[test]
+fn cleanup_understands_utc_and_local_names() {
+    let h = harness("cleanup_utc");
+    let hour_ago = OffsetDateTime::now_utc() - Duration::hours(1);
+    let recent = format!("nvme@auto_{}", hour_ago.format("%Y-%m-%dT%H%M%SZ"));
+    let old = "nvme@auto_2000-01-01T000000Z";
+    h.snapshots("nvme", &[OLD, old, recent.as_str()]);
+
+    Zfs::new().cleanup("nvme", 2).unwrap();
+    assert_eq!(h.destroyed(), vec![old, OLD]);
+}
+
+#[test]
+fn cleanup_applies_the_retention_a_dataset_sets_itself() {
+    let h = harness("cleanup_own_retention");
+    let day_ago = OffsetDateTime::now_utc() - Duration::hours(25);
+    let ts = day_ago.format("%Y-%m-%dT%H%M%SZ");
+    let pool = format!("nvme@auto_{}", ts);
+    let db = format!("nvme/db@auto_{}", ts);
+    let scratch = format!("nvme/scratch@auto_{}", ts);
+    let scratch_old = "nvme/scratch@auto_2000-01-01T000000Z";
+    h.snapshots(
+        "nvme",
+        &[pool.as_str(), db.as_str(), scratch_old, scratch.as_str()],
+    );
+    h.zfs.reply(
+        "zfs get org.znapper:keep,org.znapper:keep-daily nvme",
+        "nvme/db\torg.znapper:keep\t3d\nnvme/scratch\torg.znapper:keep-daily\t1\n",
+    );
+
+    Zfs::new().cleanup("nvme", 2).unwrap();
+    assert_eq!(h.destroyed(), vec![scratch_old, pool.as_str()]);
+    // Destroying the pool's own snapshot leaves those of the same name its children keep.
+    assert!(!h.destroyed().contains(&db));
+    assert!(!h.destroyed().contains(&scratch));
+}
+
+#[test]
+fn cleanup_empty_keeps_one_of_each_run_of_the_same_snapshots() {
+    let h = harness("cleanup_empty_runs");
+    let now = OffsetDateTime::try_now_local().unwrap();
+    let snap = |hours: i64| {
+        format!(
+            "nvme@auto_{}",
+            (now - Duration::hours(hours)).format("%Y_%m_%d_%H_%M_%S")
+        )
+    };
+    // The first is past retention, and the next two are the same as it.
+    let (old, kept, empty, changed) = (snap(30), snap(3), snap(2), snap(1));
+    h.snapshots("nvme", &[&old, &kept, &empty, &changed]);
+    h.zfs.reply(
+        "zfs list -o name,createtxg,used,written nvme",
+        &format!(
+            "{}\t1\t4096\t4096\n{}\t2\t0\t0\n{}\t3\t0\t0\n{}\t4\t0\t8192\n",
+            old, kept, empty, changed
+        ),
+    );
+
+    Zfs::new().cleanup_empty("nvme", 24).unwrap();
+    let mut destroyed = h.destroyed();
+    destroyed.sort();
+    assert_eq!(destroyed, vec![old, empty]);
+}
+
+#[test]
+fn cleanup_empty_keeps_the_snapshots_of_children_that_hold_data() {
+    let h = harness("cleanup_empty_children");
+    let now = OffsetDateTime::try_now_local().unwrap();
+    let snap = |dataset: &str, hours: i64| {
+        format!(
+            "{}@auto_{}",
+            dataset,
+            (now - Duration::hours(hours)).format("%Y_%m_%d_%H_%M_%S")
+        )
+    };
+    // nvme holds nothing new at the second, but nvme/db does.
+    let (first, empty, changed) = (snap("nvme", 3), snap("nvme", 2), snap("nvme", 1));
+    let (db_first, db_second, db_changed) =
+        (snap("nvme/db", 3), snap("nvme/db", 2), snap("nvme/db", 1));
+    h.snapshots(
+        "nvme",
+        &[&first, &empty, &changed, &db_first, &db_second, &db_changed],
+    );
+    h.zfs.reply(
+        "zfs list -o name,createtxg,used,written nvme",
+        &format!(
+            "{}\t1\t4096\t4096\n{}\t2\t0\t0\n{}\t3\t0\t8192\n\
+             {}\t1\t4096\t4096\n{}\t2\t4096\t8192\n{}\t3\t0\t8192\n",
+            first, empty, changed, db_first, db_second, db_changed
+        ),
+    );
+
+    Zfs::new().cleanup_empty("nvme", 24).unwrap();
+    assert_eq!(h.destroyed(), vec![empty]);
+}
+
+#[test]
+fn destroy_refuses_snapshots_znapper_did_not_name() {
+    let h = harness("destroy_unmanaged");
+    let manual = Snapshot::parse("nvme@before_upgrade").unwrap();
+
+    assert!(Zfs::new().destroy(&manual).is_err());
+    assert!(h.destroyed().is_empty());
+
+    Zfs::new().force(true).destroy(&manual).unwrap();
+    assert_eq!(h.destroyed(), vec!["nvme@before_upgrade"]);
+    Zfs::new().destroy(&Snapshot::parse(OLD).unwrap()).unwrap();
+    // What a deferred cleanup set aside is still znapper's.
+    let trashed = Snapshot::parse("nvme@trash_auto_2000_01_01_00_00_00").unwrap();
+    Zfs::new().destroy(&trashed).unwrap();
+}
+
+#[test]
+fn cleanup_audits_each_destroy() {
+    let h = harness("cleanup_audit");
+    h.snapshots("nvme", &[OLD, NEW]);
+
+    Zfs::new().dry_run(true).cleanup("nvme", 24).unwrap();
+    Zfs::new().cleanup("nvme", 24).unwrap();
+    let audit = std::fs::read_to_string(h.state.join("audit.jsonl")).unwrap();
+    let entries: Vec<_> = audit.lines().collect();
+    assert_eq!(entries.len(), 2);
+    for (entry, dryrun) in entries.iter().zip([true, false]) {
+        assert!(entry.contains(&format!(r#""target":"{}""#, OLD)));
+        assert!(entry.contains(r#""guid":"8312345""#));
+        assert!(entry.contains(r#""reason":"retention""#));
+        assert!(entry.contains(&format!(r#""dryrun":{}"#, dryrun)));
+    }
+}
+
+#[test]
+fn cleanup_of_a_group_destroys_a_name_from_every_dataset_or_none() {
+    let h = harness("cleanup_group");
+    std::fs::write(
+        h.state.join("znapper.toml"),
+        "[group.db]\ndatasets = [\"tank/db\", \"nvme/wal\"]\n",
+    )
+    .unwrap();
+    let day_ago = OffsetDateTime::now_utc() - Duration::hours(25);
+    let ts = day_ago.format("%Y-%m-%dT%H%M%SZ");
+    let db = format!("tank/db@auto_{}", ts);
+    let wal = format!("nvme/wal@auto_{}", ts);
+    let db_old = "tank/db@auto_2000-01-01T000000Z";
+    let wal_old = "nvme/wal@auto_2000-01-01T000000Z";
+    h.snapshots("tank/db", &[db_old, db.as_str()]);
+    h.snapshots("nvme/wal", &[wal_old, wal.as_str()]);
+    // The wal keeps three days, so the db keeps them with it.
+    h.zfs.reply(
+        "zfs get org.znapper:keep,org.znapper:keep-daily nvme/wal",
+        "nvme/wal\torg.znapper:keep\t3d\n",
+    );
+
+    Zfs::new().cleanup_group("db", 2).unwrap();
+    assert_eq!(h.destroyed(), vec![db_old, wal_old]);
+}
+
+#[test]
+fn a_failed_purge_keeps_the_snapshot_set_aside() {
+    let h = harness("cleanup_failed_purge");
+    h.snapshots("nvme", &[]);
+    let busy = "nvme@auto_2000_01_01_00_00_00";
+    let idle = "nvme@auto_2000_01_02_00_00_00";
+    std::fs::write(
+        h.state.join("trash.json"),
+        format!(
+            r#"{{"batches":[{{"pool":"nvme","trashed_at":0,"purge_after":0,"snapshots":["{}","{}"]}}]}}"#,
+            busy, idle
+        ),
+    )
+    .unwrap();
+    h.zfs.fail(
+        "zfs destroy nvme@trash_auto_2000_01_01_00_00_00",
+        1,
+        "cannot destroy snapshot: dataset is busy",
+    );
+
+    assert!(Zfs::new().cleanup("nvme", 24).is_err());
+    let trash = std::fs::read_to_string(h.state.join("trash.json")).unwrap();
+    assert!(trash.contains(busy));
+    assert!(!trash.contains(idle));
+
+    // The next cleanup tries it again.
+    assert!(Zfs::new().cleanup("nvme", 24).is_err());
+    assert_eq!(
+        h.destroyed(),
+        vec![
+            "nvme@trash_auto_2000_01_01_00_00_00",
+            "nvme@trash_auto_2000_01_02_00_00_00",
+            "nvme@trash_auto_2000_01_01_00_00_00",
+        ]
+    );
+}
+
+#[test]
+fn an_approved_mass_destroy_destroys_only_what_was_approved() {
+    let h = harness("cleanup_approval");
+    std::fs::write(
+        h.state.join("znapper.toml"),
+        "[approval]\nrequired = true\nmass_destroy = 1\n",
+    )
+    .unwrap();
+    let older = "nvme@auto_1999_01_01_00_00_00";
+    h.snapshots("nvme", &[OLD, OLD_HOME, NEW]);
+
+    // Staged for approval, and nothing destroyed.
+    assert!(Zfs::new().cleanup("nvme", 24).is_err());
+    assert!(h.destroyed().is_empty());
+    let path = h.state.join("approvals.json");
+    let staged = std::fs::read_to_string(&path).unwrap();
+    assert!(staged.contains(OLD) && staged.contains(OLD_HOME));
+
+    // Approved, but by the time the cleanup runs another snapshot has expired.
+    let now = OffsetDateTime::now_utc().timestamp();
+    let approved = staged
+        .replace("\"approved_by\": null", "\"approved_by\": \"approver\"")
+        .replace(
+            "\"approved_at\": null",
+            &format!("\"approved_at\": {}", now),
+        );
+    std::fs::write(&path, &approved).unwrap();
+    h.snapshots("nvme", &[older, OLD, OLD_HOME, NEW]);
+    Zfs::new().cleanup("nvme", 24).unwrap();
+    assert_eq!(h.destroyed(), vec![OLD_HOME, OLD]);
+
+    // An approval that has lapsed is dropped, and the cleanup staged again.
+    h.zfs.clear();
+    std::fs::write(&path, approved.replace(&now.to_string(), "0")).unwrap();
+    assert!(Zfs::new().cleanup("nvme", 24).is_err());
+    assert!(h.destroyed().is_empty());
+}